@@ -6,6 +6,7 @@ use std::{
     rc::Rc,
 };
 
+use aquatic_udp::config::SocketBackend;
 use clap::Parser;
 use indexmap::{indexmap, IndexMap};
 use indoc::writedoc;
@@ -260,7 +261,7 @@ impl UdpCommand {
 #[derive(Debug, Clone)]
 struct AquaticUdpRunner {
     socket_workers: usize,
-    use_io_uring: bool,
+    backend: SocketBackend,
     priority: Priority,
 }
 
@@ -271,7 +272,7 @@ impl AquaticUdpRunner {
     ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
         Rc::new(Self {
             socket_workers,
-            use_io_uring: false,
+            backend: SocketBackend::Mio,
             priority,
         })
     }
@@ -281,7 +282,7 @@ impl AquaticUdpRunner {
     ) -> Rc<dyn ProcessRunner<Command = UdpCommand>> {
         Rc::new(Self {
             socket_workers,
-            use_io_uring: true,
+            backend: SocketBackend::IoUring,
             priority,
         })
     }
@@ -301,7 +302,7 @@ impl ProcessRunner for AquaticUdpRunner {
 
         c.socket_workers = self.socket_workers;
         c.network.address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
-        c.network.use_io_uring = self.use_io_uring;
+        c.network.backend = self.backend;
         c.protocol.max_response_peers = 30;
 
         let c = toml::to_string_pretty(&c)?;
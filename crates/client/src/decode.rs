@@ -0,0 +1,115 @@
+//! Decoding of captured tracker packets, e.g., for debugging
+
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Http,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Self::Udp),
+            "http" => Ok(Self::Http),
+            _ => Err(anyhow::anyhow!(
+                "invalid protocol '{}', expected udp or http",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse hex dump, ignoring whitespace and colon separators as well as an
+/// optional `0x` prefix
+pub fn parse_hex(input: &str) -> anyhow::Result<Vec<u8>> {
+    let input = input.trim();
+    let input = input.strip_prefix("0x").unwrap_or(input);
+
+    let digits = input
+        .chars()
+        .filter(|c| !(c.is_whitespace() || *c == ':'))
+        .collect::<String>();
+
+    hex::decode(digits).with_context(|| "invalid hex")
+}
+
+/// Decode request sent to tracker and pretty-print it
+pub fn decode_request(protocol: Protocol, bytes: &[u8]) -> anyhow::Result<String> {
+    match protocol {
+        Protocol::Udp => aquatic_udp_protocol::Request::parse_bytes(bytes, usize::MAX)
+            .map(|request| pretty(&request))
+            .map_err(|err| anyhow::anyhow!("invalid udp request: {:?}", err)),
+        Protocol::Http => aquatic_http_protocol::request::Request::parse_bytes(bytes)
+            .with_context(|| "invalid http request")?
+            .map(|request| pretty(&request))
+            .with_context(|| "incomplete http request"),
+    }
+}
+
+/// Decode response sent by tracker and pretty-print it
+///
+/// For UDP, `ipv6` determines how announce response peers are parsed. For
+/// HTTP, either a full response or just the bencoded body can be passed.
+pub fn decode_response(protocol: Protocol, bytes: &[u8], ipv6: bool) -> anyhow::Result<String> {
+    match protocol {
+        Protocol::Udp => aquatic_udp_protocol::Response::parse_bytes(bytes, !ipv6)
+            .map(|response| pretty(&response))
+            .with_context(|| "invalid udp response"),
+        Protocol::Http => {
+            let body = if bytes.starts_with(b"HTTP/") {
+                let mut headers = [httparse::EMPTY_HEADER; 32];
+                let mut response = httparse::Response::new(&mut headers);
+
+                match response.parse(bytes)? {
+                    httparse::Status::Complete(header_len) => &bytes[header_len..],
+                    httparse::Status::Partial => {
+                        return Err(anyhow::anyhow!("incomplete http response headers"))
+                    }
+                }
+            } else {
+                bytes
+            };
+
+            aquatic_http_protocol::response::Response::parse_bytes(body)
+                .map(|response| pretty(&response))
+                .with_context(|| "invalid http response")
+        }
+    }
+}
+
+fn pretty<T: Debug>(value: &T) -> String {
+    format!("{:#?}", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // UDP connect request with transaction id 1
+        let bytes = parse_hex("0x00 00 04 17 27 10 19 80 00:00:00:00 00000001").unwrap();
+
+        assert!(decode_request(Protocol::Udp, &bytes)
+            .unwrap()
+            .contains("ConnectRequest"));
+        assert!(decode_response(Protocol::Udp, &bytes, false).is_err());
+
+        let bytes = b"HTTP/1.1 200 OK\r\nContent-Length: 25\r\n\r\nd14:failure reason4:teste";
+
+        assert!(decode_response(Protocol::Http, bytes, false)
+            .unwrap()
+            .contains("Failure"));
+        assert!(decode_response(Protocol::Http, &bytes[39..], false)
+            .unwrap()
+            .contains("Failure"));
+        assert!(decode_request(Protocol::Http, bytes).is_err());
+    }
+}
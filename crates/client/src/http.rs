@@ -0,0 +1,231 @@
+//! Plain HTTP tracker client
+//! ([BEP 003](https://www.bittorrent.org/beps/bep_0003.html),
+//! [BEP 048](https://www.bittorrent.org/beps/bep_0048.html))
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_http_protocol::common::{AnnounceEvent as HttpAnnounceEvent, InfoHash, PeerId};
+use aquatic_http_protocol::request::{AnnounceRequest, Request, ScrapeRequest};
+use aquatic_http_protocol::response::Response;
+
+use crate::{AnnounceEvent, AnnounceParams, AnnounceSummary, ScrapeSummary};
+
+/// Responses larger than this are rejected
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Headers written by [Request::write], which is mainly used for load testing
+const LOAD_TEST_HEADERS: &[u8] = b"Host: localhost\r\n\r\n";
+
+/// Client for one HTTP tracker. Each request uses a new connection.
+pub struct HttpClient {
+    tracker_addr: SocketAddr,
+    authority: String,
+    path_suffix: String,
+    timeout: Duration,
+}
+
+impl HttpClient {
+    /// `authority` is `host:port`, `path_suffix` is appended to `/announce`
+    /// and `/scrape`
+    pub fn new(authority: &str, path_suffix: &str, timeout: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            tracker_addr: crate::resolve(authority)?,
+            authority: authority.into(),
+            path_suffix: path_suffix.into(),
+            timeout,
+        })
+    }
+
+    /// Send announce request, returning full protocol response
+    pub fn announce_raw(&self, params: &AnnounceParams) -> anyhow::Result<Response> {
+        let event = match params.event {
+            AnnounceEvent::Started => HttpAnnounceEvent::Started,
+            AnnounceEvent::Stopped => HttpAnnounceEvent::Stopped,
+            AnnounceEvent::Completed => HttpAnnounceEvent::Completed,
+            AnnounceEvent::None => HttpAnnounceEvent::Empty,
+        };
+
+        self.request(&Request::Announce(AnnounceRequest {
+            info_hash: InfoHash(params.info_hash),
+            peer_id: PeerId(params.peer_id),
+            port: params.port,
+            bytes_uploaded: params.bytes_uploaded as usize,
+            bytes_downloaded: params.bytes_downloaded as usize,
+            bytes_left: params.bytes_left as usize,
+            event,
+            numwant: params.numwant,
+            key: Some(format!("{:08x}", params.key).into()),
+        }))
+    }
+
+    pub fn announce(&self, params: &AnnounceParams) -> anyhow::Result<AnnounceSummary> {
+        match self.announce_raw(params)? {
+            Response::Announce(response) => {
+                let peers_ipv4 = response
+                    .peers
+                    .0
+                    .iter()
+                    .map(|peer| SocketAddr::from((peer.ip_address, peer.port)));
+                let peers_ipv6 = response
+                    .peers6
+                    .0
+                    .iter()
+                    .map(|peer| SocketAddr::from((peer.ip_address, peer.port)));
+
+                Ok(AnnounceSummary {
+                    interval: response.announce_interval as u64,
+                    seeders: response.complete as u64,
+                    leechers: response.incomplete as u64,
+                    peers: peers_ipv4.chain(peers_ipv6).collect(),
+                    warning_message: response.warning_message,
+                })
+            }
+            Response::Failure(response) => Err(anyhow::anyhow!(
+                "tracker error: {}",
+                response.failure_reason
+            )),
+            response => Err(anyhow::anyhow!("not announce response: {:?}", response)),
+        }
+    }
+
+    /// Torrents missing from the tracker response are reported with zero
+    /// counts
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeSummary>> {
+        let response = self.request(&Request::Scrape(ScrapeRequest {
+            info_hashes: info_hashes.iter().copied().map(InfoHash).collect(),
+        }))?;
+
+        match response {
+            Response::Scrape(response) => Ok(info_hashes
+                .iter()
+                .map(|info_hash| {
+                    let opt_stats = response.files.get(&InfoHash(*info_hash));
+
+                    ScrapeSummary {
+                        info_hash: *info_hash,
+                        seeders: opt_stats.map(|s| s.complete as u64).unwrap_or(0),
+                        leechers: opt_stats.map(|s| s.incomplete as u64).unwrap_or(0),
+                        completed: opt_stats.map(|s| s.downloaded as u64).unwrap_or(0),
+                    }
+                })
+                .collect()),
+            Response::Failure(response) => Err(anyhow::anyhow!(
+                "tracker error: {}",
+                response.failure_reason
+            )),
+            response => Err(anyhow::anyhow!("not scrape response: {:?}", response)),
+        }
+    }
+
+    pub fn request(&self, request: &Request) -> anyhow::Result<Response> {
+        let mut stream = TcpStream::connect_timeout(&self.tracker_addr, self.timeout)
+            .with_context(|| format!("connect to {}", self.tracker_addr))?;
+
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream
+            .write_all(&self.request_bytes(request)?)
+            .with_context(|| "send request")?;
+
+        let body = read_response_body(&mut stream)?;
+
+        Response::parse_bytes(&body).with_context(|| "parse response")
+    }
+
+    fn request_bytes(&self, request: &Request) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+
+        request.write(&mut bytes, self.path_suffix.as_bytes())?;
+
+        if bytes.ends_with(LOAD_TEST_HEADERS) {
+            bytes.truncate(bytes.len() - LOAD_TEST_HEADERS.len());
+        }
+
+        write!(
+            &mut bytes,
+            "Host: {}\r\nUser-Agent: aquatic_client/{}\r\nConnection: close\r\n\r\n",
+            self.authority,
+            env!("CARGO_PKG_VERSION")
+        )?;
+
+        Ok(bytes)
+    }
+}
+
+fn read_response_body(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let (header_len, opt_content_len) = loop {
+        let bytes_read = stream.read(&mut chunk).with_context(|| "read response")?;
+
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("connection closed before end of headers"));
+        }
+
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+
+        if buffer.len() > MAX_RESPONSE_SIZE {
+            return Err(anyhow::anyhow!("response too large"));
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+
+        if let httparse::Status::Complete(header_len) = response.parse(&buffer)? {
+            if response.code != Some(200) {
+                return Err(anyhow::anyhow!(
+                    "unexpected status: {} {}",
+                    response.code.unwrap_or_default(),
+                    response.reason.unwrap_or_default()
+                ));
+            }
+
+            let mut opt_content_len = None;
+
+            for header in response.headers.iter() {
+                if header.name.eq_ignore_ascii_case("content-length") {
+                    let content_len = ::std::str::from_utf8(header.value)
+                        .ok()
+                        .and_then(|v| v.trim().parse::<usize>().ok())
+                        .with_context(|| "invalid content-length header")?;
+
+                    opt_content_len = Some(content_len);
+                } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                    return Err(anyhow::anyhow!("transfer encodings are not supported"));
+                }
+            }
+
+            break (header_len, opt_content_len);
+        }
+    };
+
+    let mut body = buffer.split_off(header_len);
+
+    // Without content-length, body ends when connection is closed
+    let content_len = opt_content_len.unwrap_or(MAX_RESPONSE_SIZE);
+
+    while body.len() < content_len {
+        let bytes_read = stream.read(&mut chunk).with_context(|| "read response")?;
+
+        if bytes_read == 0 {
+            if opt_content_len.is_some() {
+                return Err(anyhow::anyhow!("connection closed before end of body"));
+            }
+
+            break;
+        }
+
+        body.extend_from_slice(&chunk[..bytes_read]);
+    }
+
+    if body.len() > content_len {
+        return Err(anyhow::anyhow!("response too large"));
+    }
+
+    Ok(body)
+}
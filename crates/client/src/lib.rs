@@ -0,0 +1,284 @@
+//! BitTorrent tracker client
+//!
+//! Sends announce and scrape requests to UDP and (plain) HTTP trackers.
+//! [Tracker] dispatches protocol-independent requests based on tracker URL,
+//! while the [udp] and [http] modules can be used directly for access to
+//! protocol-specific requests and responses. Captured packets can be
+//! pretty-printed with the functions in [decode], and access list entries
+//! can be generated from .torrent files with [torrent].
+
+pub mod decode;
+pub mod http;
+pub mod torrent;
+pub mod udp;
+
+use std::fmt::Display;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+    None,
+}
+
+impl FromStr for AnnounceEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "started" => Ok(Self::Started),
+            "stopped" => Ok(Self::Stopped),
+            "completed" => Ok(Self::Completed),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow::anyhow!(
+                "invalid event '{}', expected started, stopped, completed or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Protocol-independent announce request
+#[derive(Clone, Debug)]
+pub struct AnnounceParams {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub bytes_left: u64,
+    pub event: AnnounceEvent,
+    /// Number of peers wanted. None means tracker default
+    pub numwant: Option<usize>,
+    pub key: u32,
+}
+
+impl AnnounceParams {
+    /// Parameters for a seeding peer with random peer id and key
+    pub fn new(info_hash: [u8; 20], port: u16) -> Self {
+        Self {
+            info_hash,
+            peer_id: rand::random(),
+            port,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left: 0,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: rand::random(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceSummary {
+    pub interval: u64,
+    pub seeders: u64,
+    pub leechers: u64,
+    pub peers: Vec<SocketAddr>,
+    pub warning_message: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrapeSummary {
+    pub info_hash: [u8; 20],
+    pub seeders: u64,
+    pub leechers: u64,
+    pub completed: u64,
+}
+
+/// Tracker address parsed from URL
+///
+/// Supported formats are `udp://host:port[/path]`, where the path is
+/// ignored, and `http://host[:port]/announce[suffix]`, where the suffix
+/// (e.g., a passkey) is kept for both announce and scrape requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tracker {
+    Udp {
+        authority: String,
+    },
+    Http {
+        authority: String,
+        /// Path after `/announce`
+        path_suffix: String,
+    },
+}
+
+impl FromStr for Tracker {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .with_context(|| format!("no scheme in tracker url {}", url))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+
+        if authority.is_empty() {
+            return Err(anyhow::anyhow!("no host in tracker url {}", url));
+        }
+
+        match scheme {
+            "udp" => {
+                if !has_port(authority) {
+                    return Err(anyhow::anyhow!("no port in udp tracker url {}", url));
+                }
+
+                Ok(Self::Udp {
+                    authority: authority.into(),
+                })
+            }
+            "http" => {
+                let path_suffix = path.strip_prefix("/announce").with_context(|| {
+                    format!("http tracker url path must start with /announce: {}", url)
+                })?;
+
+                let authority = if has_port(authority) {
+                    authority.into()
+                } else {
+                    format!("{}:80", authority)
+                };
+
+                Ok(Self::Http {
+                    authority,
+                    path_suffix: path_suffix.into(),
+                })
+            }
+            "https" => Err(anyhow::anyhow!("https trackers are not supported")),
+            _ => Err(anyhow::anyhow!("unsupported tracker url scheme {}", scheme)),
+        }
+    }
+}
+
+impl Display for Tracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Udp { authority } => write!(f, "udp://{}", authority),
+            Self::Http {
+                authority,
+                path_suffix,
+            } => write!(f, "http://{}/announce{}", authority, path_suffix),
+        }
+    }
+}
+
+impl Tracker {
+    pub fn announce(
+        &self,
+        params: &AnnounceParams,
+        timeout: Duration,
+    ) -> anyhow::Result<AnnounceSummary> {
+        match self {
+            Self::Udp { authority } => {
+                udp::UdpClient::new(resolve(authority)?, timeout)?.announce(params)
+            }
+            Self::Http {
+                authority,
+                path_suffix,
+            } => http::HttpClient::new(authority, path_suffix, timeout)?.announce(params),
+        }
+    }
+
+    pub fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<ScrapeSummary>> {
+        match self {
+            Self::Udp { authority } => {
+                udp::UdpClient::new(resolve(authority)?, timeout)?.scrape(info_hashes)
+            }
+            Self::Http {
+                authority,
+                path_suffix,
+            } => http::HttpClient::new(authority, path_suffix, timeout)?.scrape(info_hashes),
+        }
+    }
+}
+
+/// Resolve `host:port`, preferring IPv4 addresses
+pub fn resolve(authority: &str) -> anyhow::Result<SocketAddr> {
+    let addrs = authority
+        .to_socket_addrs()
+        .with_context(|| format!("resolve {}", authority))?
+        .collect::<Vec<_>>();
+
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+        .with_context(|| format!("no addresses found for {}", authority))
+}
+
+fn has_port(authority: &str) -> bool {
+    match authority.rsplit_once(':') {
+        // Don't mistake colons in bracketed IPv6 addresses for port separator
+        Some((host, port)) => {
+            !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (host.ends_with(']') || !host.contains(':'))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tracker_url() {
+        assert_eq!(
+            "udp://tracker.example.com:1337/announce"
+                .parse::<Tracker>()
+                .unwrap(),
+            Tracker::Udp {
+                authority: "tracker.example.com:1337".into()
+            }
+        );
+        assert_eq!(
+            "udp://[::1]:3000".parse::<Tracker>().unwrap(),
+            Tracker::Udp {
+                authority: "[::1]:3000".into()
+            }
+        );
+        assert_eq!(
+            "http://tracker.example.com/announce/abcdef"
+                .parse::<Tracker>()
+                .unwrap(),
+            Tracker::Http {
+                authority: "tracker.example.com:80".into(),
+                path_suffix: "/abcdef".into(),
+            }
+        );
+        assert_eq!(
+            "http://127.0.0.1:3000/announce"
+                .parse::<Tracker>()
+                .unwrap()
+                .to_string(),
+            "http://127.0.0.1:3000/announce"
+        );
+
+        for invalid in [
+            "tracker.example.com:1337",
+            "udp://tracker.example.com",
+            "udp://[::1]",
+            "udp://::1",
+            "http://tracker.example.com/scrape",
+            "https://tracker.example.com/announce",
+            "wss://tracker.example.com",
+        ] {
+            assert!(invalid.parse::<Tracker>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_client::decode::{decode_request, decode_response, parse_hex, Protocol};
+use aquatic_client::torrent::TorrentInfoHashes;
+use aquatic_client::udp::UdpClient;
+use aquatic_client::{resolve, AnnounceEvent, AnnounceParams, Tracker};
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send UDP connect request and print connection id and round-trip time
+    Connect(TrackerArgs),
+    /// Send announce request and print peers
+    Announce(AnnounceArgs),
+    /// Send scrape request and print torrent statistics
+    Scrape(ScrapeArgs),
+    /// Pretty-print request or response from hex dump
+    Decode(DecodeArgs),
+    /// Print access list entries for info hashes of .torrent files
+    AccessList(AccessListArgs),
+}
+
+#[derive(Args)]
+struct TrackerArgs {
+    /// Tracker URL, e.g., udp://127.0.0.1:3000 or http://127.0.0.1:3000/announce
+    #[arg(long)]
+    tracker: Tracker,
+    /// Response timeout in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    timeout_ms: u64,
+}
+
+#[derive(Args)]
+struct AnnounceArgs {
+    #[command(flatten)]
+    tracker: TrackerArgs,
+    /// Info hash as 40 hexadecimal characters
+    #[arg(long, value_parser = parse_20_bytes_hex)]
+    info_hash: [u8; 20],
+    /// Peer id as 40 hexadecimal characters. Random if not set
+    #[arg(long, value_parser = parse_20_bytes_hex)]
+    peer_id: Option<[u8; 20]>,
+    #[arg(long, default_value_t = 6881)]
+    port: u16,
+    #[arg(long, default_value_t = 0)]
+    uploaded: u64,
+    #[arg(long, default_value_t = 0)]
+    downloaded: u64,
+    /// Bytes left to download. 0 means seeding
+    #[arg(long, default_value_t = 0)]
+    left: u64,
+    /// started, stopped, completed or none
+    #[arg(long, default_value = "started")]
+    event: AnnounceEvent,
+    /// Number of peers wanted. Tracker default if not set
+    #[arg(long)]
+    numwant: Option<usize>,
+}
+
+#[derive(Args)]
+struct ScrapeArgs {
+    #[command(flatten)]
+    tracker: TrackerArgs,
+    /// Info hash as 40 hexadecimal characters. Can be passed multiple times
+    #[arg(long, required = true, value_parser = parse_20_bytes_hex)]
+    info_hash: Vec<[u8; 20]>,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    /// udp or http
+    #[arg(long, default_value = "udp")]
+    protocol: Protocol,
+    /// Decode response instead of request
+    #[arg(long)]
+    response: bool,
+    /// Parse peers in UDP announce responses as IPv6 addresses
+    #[arg(long)]
+    ipv6: bool,
+    /// Hex dump of packet, or HTTP request or response. Whitespace and colons
+    /// are ignored. Read from stdin if not set
+    hex: Option<String>,
+}
+
+#[derive(Args)]
+struct AccessListArgs {
+    /// Skip files that can't be read or parsed instead of exiting, printing
+    /// a warning to stderr
+    #[arg(long)]
+    skip_invalid: bool,
+    /// .torrent files, or directories to search recursively for files with
+    /// .torrent extension
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    match Cli::parse().command {
+        Command::Connect(args) => {
+            let tracker_addr = match &args.tracker {
+                Tracker::Udp { authority } => resolve(authority)?,
+                Tracker::Http { .. } => {
+                    return Err(anyhow::anyhow!(
+                        "connect is only supported for udp trackers"
+                    ))
+                }
+            };
+
+            let mut client = UdpClient::new(tracker_addr, Duration::from_millis(args.timeout_ms))?;
+
+            let start = Instant::now();
+
+            let connection_id = client
+                .connect()
+                .with_context(|| format!("connect to {}", args.tracker))?;
+
+            println!("tracker address: {}", tracker_addr);
+            println!("connection id: {}", connection_id.0.get());
+            println!(
+                "round-trip time: {:.2} ms",
+                start.elapsed().as_secs_f64() * 1000.0
+            );
+        }
+        Command::Announce(args) => {
+            let timeout = Duration::from_millis(args.tracker.timeout_ms);
+
+            let mut params = AnnounceParams::new(args.info_hash, args.port);
+
+            if let Some(peer_id) = args.peer_id {
+                params.peer_id = peer_id;
+            }
+
+            params.bytes_uploaded = args.uploaded;
+            params.bytes_downloaded = args.downloaded;
+            params.bytes_left = args.left;
+            params.event = args.event;
+            params.numwant = args.numwant;
+
+            let summary = args
+                .tracker
+                .tracker
+                .announce(&params, timeout)
+                .with_context(|| format!("announce to {}", args.tracker.tracker))?;
+
+            println!("interval: {}", summary.interval);
+            println!("seeders: {}", summary.seeders);
+            println!("leechers: {}", summary.leechers);
+
+            if let Some(warning_message) = summary.warning_message {
+                println!("warning: {}", warning_message);
+            }
+
+            println!("peers ({}):", summary.peers.len());
+
+            for peer in summary.peers {
+                println!("  {}", peer);
+            }
+        }
+        Command::Scrape(args) => {
+            let timeout = Duration::from_millis(args.tracker.timeout_ms);
+
+            let summaries = args
+                .tracker
+                .tracker
+                .scrape(&args.info_hash, timeout)
+                .with_context(|| format!("scrape {}", args.tracker.tracker))?;
+
+            for summary in summaries {
+                println!(
+                    "{}: seeders {}, leechers {}, completed {}",
+                    hex::encode(summary.info_hash),
+                    summary.seeders,
+                    summary.leechers,
+                    summary.completed
+                );
+            }
+        }
+        Command::Decode(args) => {
+            let hex = match args.hex {
+                Some(hex) => hex,
+                None => {
+                    let mut hex = String::new();
+
+                    ::std::io::stdin()
+                        .read_to_string(&mut hex)
+                        .with_context(|| "read stdin")?;
+
+                    hex
+                }
+            };
+
+            let bytes = parse_hex(&hex)?;
+
+            let output = if args.response {
+                decode_response(args.protocol, &bytes, args.ipv6)?
+            } else {
+                decode_request(args.protocol, &bytes)?
+            };
+
+            println!("{}", output);
+        }
+        Command::AccessList(args) => {
+            let mut files = Vec::new();
+
+            for path in args.paths {
+                if path.is_dir() {
+                    find_torrent_files(&path, &mut files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+
+            let mut printed = HashSet::new();
+
+            for file in files {
+                let result = ::std::fs::read(&file)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bytes| TorrentInfoHashes::from_torrent_file(&bytes))
+                    .with_context(|| format!("read {}", file.display()));
+
+                let info_hashes = match result {
+                    Ok(info_hashes) => info_hashes,
+                    Err(err) if args.skip_invalid => {
+                        eprintln!("warning: {:#}", err);
+
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                // Hybrid torrents produce both v1 and truncated v2 entries,
+                // since clients may announce with either
+                for info_hash in info_hashes.tracker_info_hashes() {
+                    if printed.insert(info_hash) {
+                        println!("{}", hex::encode(info_hash));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_20_bytes_hex(s: &str) -> anyhow::Result<[u8; 20]> {
+    let mut bytes = [0u8; 20];
+
+    hex::decode_to_slice(s, &mut bytes).with_context(|| "expected 40 hexadecimal characters")?;
+
+    Ok(bytes)
+}
+
+/// Collect files with .torrent extension, sorted for reproducible output
+fn find_torrent_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries = ::std::fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .with_context(|| format!("read directory {}", dir.display()))?;
+
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_torrent_files(&path, files)?;
+        } else if path.extension().is_some_and(|e| e == "torrent") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! Info hash extraction from .torrent files, e.g., for building access lists
+//!
+//! Only the byte range of the info dictionary is located; the rest of the
+//! file isn't interpreted, so that info hashes match exactly what clients
+//! calculate.
+
+use std::ops::Range;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// Protects against stack overflow on maliciously nested input
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TorrentInfoHashes {
+    /// SHA-1 of info dictionary, present for v1 and hybrid torrents
+    pub v1: Option<[u8; 20]>,
+    /// SHA-256 of info dictionary, present for v2 and hybrid torrents
+    pub v2: Option<[u8; 32]>,
+}
+
+impl TorrentInfoHashes {
+    pub fn from_torrent_file(bytes: &[u8]) -> anyhow::Result<Self> {
+        let top_level = dict_entries(bytes, 0..bytes.len(), 0).with_context(|| "parse torrent")?;
+
+        let info_range = top_level
+            .iter()
+            .find(|(key, _)| *key == b"info")
+            .map(|(_, range)| range.clone())
+            .with_context(|| "no info dictionary")?;
+
+        let info_entries =
+            dict_entries(bytes, info_range.clone(), 1).with_context(|| "parse info dictionary")?;
+
+        let has_key = |key: &[u8]| info_entries.iter().any(|(k, _)| *k == key);
+
+        let meta_version_2 = info_entries
+            .iter()
+            .any(|(key, range)| *key == b"meta version" && &bytes[range.clone()] == b"i2e");
+
+        let info = &bytes[info_range];
+
+        let v1 = has_key(b"pieces").then(|| Sha1::digest(info).into());
+        let v2 = (meta_version_2 && has_key(b"file tree")).then(|| Sha256::digest(info).into());
+
+        if v1.is_none() && v2.is_none() {
+            return Err(anyhow::anyhow!(
+                "info dictionary has neither pieces (v1) nor file tree (v2)"
+            ));
+        }
+
+        Ok(Self { v1, v2 })
+    }
+
+    /// Info hashes as sent by clients in tracker requests: the v1 hash and
+    /// the v2 hash truncated to 20 bytes
+    pub fn tracker_info_hashes(&self) -> Vec<[u8; 20]> {
+        let v2 = self.v2.map(|v2| {
+            let mut truncated = [0u8; 20];
+
+            truncated.copy_from_slice(&v2[..20]);
+
+            truncated
+        });
+
+        self.v1.into_iter().chain(v2).collect()
+    }
+}
+
+/// Parse dictionary at start of `range`, returning keys and value ranges
+fn dict_entries(
+    bytes: &[u8],
+    range: Range<usize>,
+    depth: usize,
+) -> anyhow::Result<Vec<(&[u8], Range<usize>)>> {
+    let bytes = &bytes[..range.end];
+    let mut pos = range.start;
+
+    if bytes.get(pos) != Some(&b'd') {
+        return Err(anyhow::anyhow!("expected dictionary at {}", pos));
+    }
+
+    pos += 1;
+
+    let mut entries = Vec::new();
+
+    while bytes.get(pos) != Some(&b'e') {
+        let key_end = skip_value(bytes, pos, depth + 1)?;
+        let key = string_contents(&bytes[pos..key_end])
+            .with_context(|| format!("expected string key at {}", pos))?;
+
+        let value_end = skip_value(bytes, key_end, depth + 1)?;
+
+        entries.push((key, key_end..value_end));
+
+        pos = value_end;
+    }
+
+    Ok(entries)
+}
+
+/// Return end position of value starting at `pos`
+fn skip_value(bytes: &[u8], mut pos: usize, depth: usize) -> anyhow::Result<usize> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow::anyhow!("nesting too deep"));
+    }
+
+    match bytes.get(pos) {
+        Some(b'i') => {
+            let len = bytes[pos..]
+                .iter()
+                .position(|b| *b == b'e')
+                .with_context(|| format!("unterminated integer at {}", pos))?;
+
+            Ok(pos + len + 1)
+        }
+        Some(b'l') | Some(b'd') => {
+            pos += 1;
+
+            while bytes.get(pos) != Some(&b'e') {
+                pos = skip_value(bytes, pos, depth + 1)?;
+            }
+
+            Ok(pos + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let colon = bytes[pos..]
+                .iter()
+                .position(|b| *b == b':')
+                .with_context(|| format!("unterminated string length at {}", pos))?;
+
+            let len = ::std::str::from_utf8(&bytes[pos..pos + colon])?
+                .parse::<usize>()
+                .with_context(|| format!("invalid string length at {}", pos))?;
+
+            let end = (pos + colon + 1)
+                .checked_add(len)
+                .filter(|end| *end <= bytes.len())
+                .with_context(|| format!("string at {} exceeds input", pos))?;
+
+            Ok(end)
+        }
+        Some(_) => Err(anyhow::anyhow!("invalid value at {}", pos)),
+        None => Err(anyhow::anyhow!("unexpected end of input")),
+    }
+}
+
+fn string_contents(value: &[u8]) -> Option<&[u8]> {
+    let colon = value.iter().position(|b| *b == b':')?;
+
+    value[0].is_ascii_digit().then(|| &value[colon + 1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent(info: &[u8]) -> Vec<u8> {
+        let mut bytes = b"d8:announce20:udp://127.0.0.1:30004:info".to_vec();
+
+        bytes.extend_from_slice(info);
+        bytes.extend_from_slice(b"8:url-listlee");
+
+        bytes
+    }
+
+    #[test]
+    fn test_torrent_info_hashes() {
+        let v1_info =
+            b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let v2_info =
+            b"d9:file treed1:ad0:d6:lengthi1e11:pieces root32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbeee12:meta versioni2e4:name1:a12:piece lengthi16384ee";
+        let hybrid_info = b"d9:file treed1:ad0:d6:lengthi1e11:pieces root32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbeee6:lengthi1e12:meta versioni2e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+        let v1 = TorrentInfoHashes::from_torrent_file(&torrent(v1_info)).unwrap();
+
+        assert_eq!(v1.v1, Some(Sha1::digest(v1_info).into()));
+        assert_eq!(v1.v2, None);
+        assert_eq!(v1.tracker_info_hashes().len(), 1);
+
+        let v2 = TorrentInfoHashes::from_torrent_file(&torrent(v2_info)).unwrap();
+        let v2_hash: [u8; 32] = Sha256::digest(v2_info).into();
+
+        assert_eq!(v2.v1, None);
+        assert_eq!(v2.v2, Some(v2_hash));
+        assert_eq!(v2.tracker_info_hashes()[0], v2_hash[..20]);
+
+        let hybrid = TorrentInfoHashes::from_torrent_file(&torrent(hybrid_info)).unwrap();
+
+        assert_eq!(hybrid.v1, Some(Sha1::digest(hybrid_info).into()));
+        assert_eq!(hybrid.v2, Some(Sha256::digest(hybrid_info).into()));
+        assert_eq!(hybrid.tracker_info_hashes().len(), 2);
+
+        for invalid in [
+            &b"d4:infod4:name1:aee"[..],
+            b"d4:info",
+            b"d4:infod4:name99:aee",
+            b"li1ee",
+            &b"l".repeat(1000),
+        ] {
+            assert!(
+                TorrentInfoHashes::from_torrent_file(invalid).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(invalid)
+            );
+        }
+    }
+}
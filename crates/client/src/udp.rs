@@ -0,0 +1,257 @@
+//! UDP tracker client ([BEP 015](https://www.bittorrent.org/beps/bep_0015.html))
+
+use std::io::Cursor;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::num::NonZeroU16;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_udp_protocol::{
+    common::PeerId, AnnounceRequest, ConnectRequest, ConnectionId, InfoHash, Ipv4AddrBytes,
+    NumberOfBytes, NumberOfPeers, PeerKey, Port, Request, Response, ScrapeRequest, TransactionId,
+};
+
+use crate::{AnnounceEvent, AnnounceParams, AnnounceSummary, ScrapeSummary};
+
+/// Large enough for any response to a request sent by this client
+const BUFFER_SIZE: usize = 8192;
+
+/// Connection ids are valid for at least this long according to BEP 015
+const CONNECTION_ID_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Send request and wait for one response
+///
+/// Transaction ids aren't checked, so this is mostly useful for sending
+/// requests with unusual content, e.g., in tests.
+pub fn send_request(
+    socket: &UdpSocket,
+    tracker_addr: SocketAddr,
+    request: Request,
+) -> anyhow::Result<Response> {
+    let mut buffer = [0u8; BUFFER_SIZE];
+
+    {
+        let mut buffer = Cursor::new(&mut buffer[..]);
+
+        request
+            .write_bytes(&mut buffer)
+            .with_context(|| "write request")?;
+
+        let bytes_written = buffer.position() as usize;
+
+        socket
+            .send_to(&(buffer.into_inner())[..bytes_written], tracker_addr)
+            .with_context(|| "send request")?;
+    }
+
+    {
+        let (bytes_read, _) = socket
+            .recv_from(&mut buffer)
+            .with_context(|| "recv response")?;
+
+        Response::parse_bytes(&buffer[..bytes_read], tracker_addr.is_ipv4())
+            .with_context(|| "parse response")
+    }
+}
+
+/// Client for one UDP tracker, reusing connection ids while valid
+pub struct UdpClient {
+    socket: UdpSocket,
+    tracker_addr: SocketAddr,
+    connection: Option<(ConnectionId, Instant)>,
+}
+
+impl UdpClient {
+    pub fn new(tracker_addr: SocketAddr, timeout: Duration) -> anyhow::Result<Self> {
+        let bind_addr = if tracker_addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        let socket = UdpSocket::bind(bind_addr).with_context(|| "bind socket")?;
+
+        socket
+            .set_read_timeout(Some(timeout))
+            .with_context(|| "set socket read timeout")?;
+
+        Ok(Self::from_socket(socket, tracker_addr))
+    }
+
+    /// Use existing socket, e.g., to control local port
+    pub fn from_socket(socket: UdpSocket, tracker_addr: SocketAddr) -> Self {
+        Self {
+            socket,
+            tracker_addr,
+            connection: None,
+        }
+    }
+
+    /// Get valid connection id, sending connect request if necessary
+    pub fn connect(&mut self) -> anyhow::Result<ConnectionId> {
+        if let Some((connection_id, created_at)) = self.connection {
+            if created_at.elapsed() < CONNECTION_ID_MAX_AGE {
+                return Ok(connection_id);
+            }
+        }
+
+        let created_at = Instant::now();
+
+        let response =
+            self.request(|transaction_id| Request::Connect(ConnectRequest { transaction_id }))?;
+
+        match response {
+            Response::Connect(response) => {
+                self.connection = Some((response.connection_id, created_at));
+
+                Ok(response.connection_id)
+            }
+            response => Err(anyhow::anyhow!("not connect response: {:?}", response)),
+        }
+    }
+
+    /// Send announce request, returning full protocol response
+    pub fn announce_raw(&mut self, params: &AnnounceParams) -> anyhow::Result<Response> {
+        let connection_id = self.connect()?;
+
+        let port = NonZeroU16::new(params.port).with_context(|| "port must not be zero")?;
+
+        let event = match params.event {
+            AnnounceEvent::Started => aquatic_udp_protocol::AnnounceEvent::Started,
+            AnnounceEvent::Stopped => aquatic_udp_protocol::AnnounceEvent::Stopped,
+            AnnounceEvent::Completed => aquatic_udp_protocol::AnnounceEvent::Completed,
+            AnnounceEvent::None => aquatic_udp_protocol::AnnounceEvent::None,
+        };
+
+        self.request(|transaction_id| {
+            Request::Announce(AnnounceRequest {
+                connection_id,
+                action_placeholder: Default::default(),
+                transaction_id,
+                info_hash: InfoHash(params.info_hash),
+                peer_id: PeerId(params.peer_id),
+                bytes_downloaded: NumberOfBytes::new(clamp_i64(params.bytes_downloaded)),
+                bytes_uploaded: NumberOfBytes::new(clamp_i64(params.bytes_uploaded)),
+                bytes_left: NumberOfBytes::new(clamp_i64(params.bytes_left)),
+                event: event.into(),
+                ip_address: Ipv4AddrBytes([0; 4]),
+                key: PeerKey::new(i32::from_ne_bytes(params.key.to_ne_bytes())),
+                peers_wanted: NumberOfPeers::new(
+                    params
+                        .numwant
+                        .map(|n| n.min(i32::MAX as usize) as i32)
+                        .unwrap_or(-1),
+                ),
+                port: Port::new(port),
+            })
+        })
+    }
+
+    pub fn announce(&mut self, params: &AnnounceParams) -> anyhow::Result<AnnounceSummary> {
+        match self.announce_raw(params)? {
+            Response::AnnounceIpv4(response) => Ok(AnnounceSummary {
+                interval: response.fixed.announce_interval.0.get().max(0) as u64,
+                seeders: response.fixed.seeders.0.get().max(0) as u64,
+                leechers: response.fixed.leechers.0.get().max(0) as u64,
+                peers: response
+                    .peers
+                    .iter()
+                    .map(|peer| {
+                        SocketAddr::from((Ipv4Addr::from(peer.ip_address), peer.port.0.get()))
+                    })
+                    .collect(),
+                warning_message: None,
+            }),
+            Response::AnnounceIpv6(response) => Ok(AnnounceSummary {
+                interval: response.fixed.announce_interval.0.get().max(0) as u64,
+                seeders: response.fixed.seeders.0.get().max(0) as u64,
+                leechers: response.fixed.leechers.0.get().max(0) as u64,
+                peers: response
+                    .peers
+                    .iter()
+                    .map(|peer| {
+                        SocketAddr::from((Ipv6Addr::from(peer.ip_address), peer.port.0.get()))
+                    })
+                    .collect(),
+                warning_message: None,
+            }),
+            response => Err(anyhow::anyhow!("not announce response: {:?}", response)),
+        }
+    }
+
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeSummary>> {
+        let connection_id = self.connect()?;
+
+        let response = self.request(|transaction_id| {
+            Request::Scrape(ScrapeRequest {
+                connection_id,
+                transaction_id,
+                info_hashes: info_hashes.iter().copied().map(InfoHash).collect(),
+            })
+        })?;
+
+        match response {
+            Response::Scrape(response) => {
+                if response.torrent_stats.len() != info_hashes.len() {
+                    return Err(anyhow::anyhow!(
+                        "scrape response contains {} torrents, expected {}",
+                        response.torrent_stats.len(),
+                        info_hashes.len()
+                    ));
+                }
+
+                Ok(info_hashes
+                    .iter()
+                    .zip(response.torrent_stats.iter())
+                    .map(|(info_hash, stats)| ScrapeSummary {
+                        info_hash: *info_hash,
+                        seeders: stats.seeders.0.get().max(0) as u64,
+                        leechers: stats.leechers.0.get().max(0) as u64,
+                        completed: stats.completed.0.get().max(0) as u64,
+                    })
+                    .collect())
+            }
+            response => Err(anyhow::anyhow!("not scrape response: {:?}", response)),
+        }
+    }
+
+    /// Send request with random transaction id and check that the response
+    /// carries the same one. Error responses are converted to errors.
+    fn request<F: FnOnce(TransactionId) -> Request>(
+        &mut self,
+        create_request: F,
+    ) -> anyhow::Result<Response> {
+        let transaction_id = TransactionId::new(rand::random());
+
+        let response = send_request(
+            &self.socket,
+            self.tracker_addr,
+            create_request(transaction_id),
+        )?;
+
+        let response_transaction_id = match &response {
+            Response::Connect(r) => r.transaction_id,
+            Response::AnnounceIpv4(r) => r.fixed.transaction_id,
+            Response::AnnounceIpv6(r) => r.fixed.transaction_id,
+            Response::Scrape(r) => r.transaction_id,
+            Response::Error(r) => r.transaction_id,
+        };
+
+        if response_transaction_id != transaction_id {
+            return Err(anyhow::anyhow!("response transaction id doesn't match"));
+        }
+
+        if let Response::Error(response) = response {
+            // Connection id might have expired
+            self.connection = None;
+
+            return Err(anyhow::anyhow!("tracker error: {}", response.message));
+        }
+
+        Ok(response)
+    }
+}
+
+fn clamp_i64(value: u64) -> i64 {
+    value.min(i64::MAX as u64) as i64
+}
@@ -7,6 +7,7 @@ use aquatic_ws::config::Config as WsConfig;
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 const APP_NAME: &str = "aquatic: BitTorrent tracker";
+const PROTOCOLS: [&str; 3] = ["udp", "http", "ws"];
 
 fn main() {
     ::std::process::exit(match run() {
@@ -33,13 +34,17 @@ fn run() -> Result<(), Option<String>> {
         return Err(None);
     };
 
-    let options = match Options::parse_args(arg_iter) {
+    let mut options = match Options::parse_args(arg_iter) {
         Ok(options) => options,
         Err(opt_err) => {
             return Err(opt_err);
         }
     };
 
+    if PROTOCOLS.contains(&protocol.as_str()) {
+        options.set_protocol(&protocol, &PROTOCOLS);
+    }
+
     match protocol.as_str() {
         "udp" => run_app_with_cli_and_config::<UdpConfig>(
             aquatic_udp::APP_NAME,
@@ -86,6 +91,9 @@ fn gen_info() -> String {
     info.push_str("\n    udp                   BitTorrent over UDP");
     info.push_str("\n    http                  BitTorrent over HTTP");
     info.push_str("\n    ws                    WebTorrent");
+    info.push_str("\n\nWith --env-config, AQUATIC_<PROTOCOL>__* environment variables (e.g.,");
+    info.push_str("\nAQUATIC_UDP__PROTOCOL__MAX_SCRAPE_TORRENTS) override shared ones for");
+    info.push_str("\nthat protocol.");
 
     info
 }
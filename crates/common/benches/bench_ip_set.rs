@@ -0,0 +1,73 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use aquatic_common::ip_set::IpSet;
+use aquatic_common::{SecondsSinceServerStart, ValidUntil};
+
+const NUM_IPS: u32 = 100_000;
+
+fn ips() -> Vec<IpAddr> {
+    (0..NUM_IPS)
+        .map(|i| {
+            if i % 2 == 0 {
+                IpAddr::V4(Ipv4Addr::from(i))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(u128::from(i) << 64))
+            }
+        })
+        .collect()
+}
+
+fn filled_set(ips: &[IpAddr]) -> IpSet {
+    let set = IpSet::new(64);
+
+    for (i, ip) in ips.iter().enumerate() {
+        set.insert(*ip, ValidUntil::from_seconds(i as u32 % 60));
+    }
+
+    set
+}
+
+pub fn bench(c: &mut Criterion) {
+    let ips = ips();
+    let set = filled_set(&ips);
+    let now = SecondsSinceServerStart::from_seconds(0);
+
+    c.bench_function("ip-set-insert", |b| {
+        let mut i = 0;
+
+        b.iter(|| {
+            i = (i + 1) % ips.len();
+
+            set.insert(black_box(ips[i]), ValidUntil::from_seconds(60));
+        })
+    });
+
+    c.bench_function("ip-set-contains", |b| {
+        let mut i = 0;
+
+        b.iter(|| {
+            i = (i + 1) % ips.len();
+
+            set.contains(black_box(ips[i]), now)
+        })
+    });
+
+    c.bench_function("ip-set-remove-expired-one-second", |b| {
+        b.iter_batched(
+            || filled_set(&ips),
+            |set| set.remove_expired(SecondsSinceServerStart::from_seconds(0)),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .measurement_time(Duration::from_secs(10));
+    targets = bench
+}
+criterion_main!(benches);
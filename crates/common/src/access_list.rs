@@ -1,7 +1,10 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
 use std::sync::Arc;
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_toml_config::TomlConfig;
@@ -9,6 +12,8 @@ use arc_swap::{ArcSwap, Cache};
 use hashbrown::HashSet;
 use serde::{Deserialize, Serialize};
 
+use crate::shutdown::ShutdownSignal;
+
 /// Access list mode. Available modes are allow, deny and off.
 #[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -35,6 +40,27 @@ pub struct AccessListConfig {
     ///
     /// If using chroot mode, path must be relative to new root.
     pub path: PathBuf,
+    /// Shell command printing access list to stdout in the same format as
+    /// the file. If set, it is run instead of reading the file.
+    ///
+    /// This can be used to fetch info hashes directly from a torrent index
+    /// database with its command line client, e.g.:
+    /// psql -At -c "SELECT encode(info_hash, 'hex') FROM torrents WHERE approved"
+    ///
+    /// There is no built-in database support, so connection settings and
+    /// credentials are handled by the client. The command is run with
+    /// `sh -c` (`cmd /C` on Windows), so it is not available in chroot mode.
+    pub command: String,
+    /// Kill access list command if it hasn't exited after this many seconds,
+    /// in which case the previous access list is kept
+    ///
+    /// 0 = no timeout
+    pub command_timeout: u64,
+    /// Update access list this often (seconds), in addition to on start and
+    /// when the program receives `SIGUSR1`
+    ///
+    /// 0 = disabled
+    pub update_interval: u64,
 }
 
 impl Default for AccessListConfig {
@@ -42,6 +68,9 @@ impl Default for AccessListConfig {
         Self {
             path: "./access-list.txt".into(),
             mode: AccessListMode::Off,
+            command: String::new(),
+            command_timeout: 60,
+            update_interval: 0,
         }
     }
 }
@@ -58,8 +87,73 @@ impl AccessList {
 
     pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
         let file = File::open(path)?;
-        let reader = BufReader::new(file);
 
+        Self::create_from_reader(BufReader::new(file))
+    }
+
+    /// Run shell command and parse its output. The command is killed if it
+    /// hasn't exited before timeout, if one is given.
+    pub fn create_from_command(command: &str, timeout: Option<Duration>) -> anyhow::Result<Self> {
+        #[cfg(not(windows))]
+        let mut shell = Command::new("sh");
+        #[cfg(not(windows))]
+        shell.arg("-c");
+        #[cfg(windows)]
+        let mut shell = Command::new("cmd");
+        #[cfg(windows)]
+        shell.arg("/C");
+
+        // Run command in its own process group, so that processes it spawns
+        // can be killed along with it
+        #[cfg(unix)]
+        ::std::os::unix::process::CommandExt::process_group(&mut shell, 0);
+
+        let mut child = shell
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("run access list command")?;
+
+        // Read output in separate threads, so that the command doesn't block
+        // on full pipes while its exit is waited for
+        let stdout = spawn_pipe_reader(child.stdout.take())?;
+        let stderr = spawn_pipe_reader(child.stderr.take())?;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let status = loop {
+            if let Some(status) = child.try_wait().context("wait for access list command")? {
+                break status;
+            }
+
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                kill(&mut child);
+
+                return Err(anyhow::anyhow!(
+                    "Access list command timed out after {} seconds",
+                    timeout.unwrap_or_default().as_secs()
+                ));
+            }
+
+            sleep(Duration::from_millis(10));
+        };
+
+        let stdout = join_pipe_reader(stdout)?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "Access list command failed ({}): {}",
+                status,
+                String::from_utf8_lossy(&join_pipe_reader(stderr)?).trim()
+            ));
+        }
+
+        Self::create_from_reader(&stdout[..])
+    }
+
+    fn create_from_reader(reader: impl BufRead) -> anyhow::Result<Self> {
         let mut new_list = Self::default();
 
         for line in reader.lines() {
@@ -102,7 +196,16 @@ pub type AccessListCache = Cache<Arc<AccessListArcSwap>, Arc<AccessList>>;
 
 impl AccessListQuery for AccessListArcSwap {
     fn update(&self, config: &AccessListConfig) -> anyhow::Result<()> {
-        self.store(Arc::new(AccessList::create_from_path(&config.path)?));
+        let new_list = if config.command.is_empty() {
+            AccessList::create_from_path(&config.path)?
+        } else {
+            let timeout =
+                (config.command_timeout != 0).then(|| Duration::from_secs(config.command_timeout));
+
+            AccessList::create_from_command(&config.command, timeout)?
+        };
+
+        self.store(Arc::new(new_list));
 
         Ok(())
     }
@@ -140,6 +243,66 @@ pub fn update_access_list(
     Ok(())
 }
 
+/// Spawn thread updating access list periodically, if enabled
+pub fn spawn_access_list_updater(
+    config: AccessListConfig,
+    access_list: Arc<AccessListArcSwap>,
+    shutdown: ShutdownSignal,
+) -> anyhow::Result<Option<JoinHandle<anyhow::Result<()>>>> {
+    if !config.mode.is_on() || config.update_interval == 0 {
+        return Ok(None);
+    }
+
+    let handle = Builder::new()
+        .name("access-list".into())
+        .spawn(move || {
+            while !shutdown.sleep(Duration::from_secs(config.update_interval)) {
+                let _ = update_access_list(&config, &access_list);
+            }
+
+            Ok(())
+        })
+        .context("spawn access list updater")?;
+
+    Ok(Some(handle))
+}
+
+type PipeReader = JoinHandle<::std::io::Result<Vec<u8>>>;
+
+fn spawn_pipe_reader(opt_pipe: Option<impl Read + Send + 'static>) -> anyhow::Result<PipeReader> {
+    let mut pipe = opt_pipe.context("access list command pipe missing")?;
+
+    Builder::new()
+        .name("access-list-cmd".into())
+        .spawn(move || {
+            let mut output = Vec::new();
+
+            pipe.read_to_end(&mut output)?;
+
+            Ok(output)
+        })
+        .context("spawn access list command pipe reader")
+}
+
+fn join_pipe_reader(reader: PipeReader) -> anyhow::Result<Vec<u8>> {
+    reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("access list command pipe reader panicked"))?
+        .context("read access list command output")
+}
+
+/// Kill command and any processes in its process group. Pipe readers finish
+/// once all processes holding the pipes have exited.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 fn parse_info_hash(line: &str) -> anyhow::Result<[u8; 20]> {
     let mut bytes = [0u8; 20];
 
@@ -162,6 +325,39 @@ mod tests {
         assert!(f("aaaabbbbccccddddeeeeaaaabbbbccccddddeeeö").is_err());
     }
 
+    #[test]
+    fn test_create_from_command() {
+        let access_list = AccessList::create_from_command(
+            "echo aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa; echo; echo bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(access_list.len(), 2);
+
+        assert!(AccessList::create_from_command("echo invalid", None).is_err());
+        assert!(AccessList::create_from_command("exit 1", None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_from_command_timeout() {
+        let start = Instant::now();
+
+        let result = AccessList::create_from_command(
+            "echo aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa; sleep 30",
+            Some(Duration::from_millis(200)),
+        );
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let access_list =
+            AccessList::create_from_command("echo", Some(Duration::from_secs(10))).unwrap();
+
+        assert_eq!(access_list.len(), 0);
+    }
+
     #[test]
     fn test_cache_allows() {
         let mut access_list = AccessList::default();
@@ -0,0 +1,170 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use socket2::{Domain, Socket, Type};
+
+/// Called with the bound address once all socket workers have bound their
+/// sockets
+pub type BoundCallback = Box<dyn FnOnce(SocketAddr) + Send>;
+
+/// Supports binding to port 0 (an ephemeral port chosen by the OS) and
+/// reports the bound address once all socket workers are ready
+///
+/// Socket workers bind to the same port with SO_REUSEPORT, so when port 0 is
+/// configured, a port is reserved by binding a socket to it before workers
+/// are started, and the configured address is updated to use it. The
+/// reservation is released once all workers have bound. For UDP, this
+/// happens before the callback is called, so no requests can be received
+/// by the reservation socket.
+#[derive(Clone, Default)]
+pub struct BindNotifier {
+    inner: Arc<Mutex<Option<BindNotifierInner>>>,
+}
+
+struct BindNotifierInner {
+    remaining_sockets: usize,
+    address: SocketAddr,
+    /// Only held until all workers have bound
+    opt_reservation: Option<Socket>,
+    opt_callback: Option<BoundCallback>,
+}
+
+impl BindNotifier {
+    /// Reserve a port if port of `address` is 0, updating `address` with
+    /// the chosen port. `socket_type` should be the type of worker sockets
+    /// (stream or datagram).
+    pub fn new(
+        address: &mut SocketAddr,
+        only_ipv6: bool,
+        socket_type: Type,
+        num_sockets: usize,
+        opt_callback: Option<BoundCallback>,
+    ) -> anyhow::Result<Self> {
+        let opt_reservation = if address.port() == 0 {
+            let socket = reserve_port(*address, only_ipv6, socket_type)
+                .with_context(|| format!("reserve port for address {}", address))?;
+
+            let port = socket
+                .local_addr()?
+                .as_socket()
+                .map(|addr| addr.port())
+                .with_context(|| "reservation socket has no address")?;
+
+            address.set_port(port);
+
+            ::log::info!("reserved ephemeral port {}", port);
+
+            // Without SO_REUSEPORT, workers can't bind while the reservation
+            // is held
+            if cfg!(unix) {
+                Some(socket)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Some(BindNotifierInner {
+                remaining_sockets: num_sockets,
+                address: *address,
+                opt_reservation,
+                opt_callback,
+            }))),
+        })
+    }
+
+    /// Call after a socket worker has bound its socket
+    pub fn socket_bound(&self) {
+        let mut guard = self.inner.lock().unwrap();
+
+        let done = match guard.as_mut() {
+            Some(inner) => {
+                inner.remaining_sockets = inner.remaining_sockets.saturating_sub(1);
+
+                inner.remaining_sockets == 0
+            }
+            None => false,
+        };
+
+        if done {
+            let BindNotifierInner {
+                address,
+                opt_reservation,
+                opt_callback,
+                ..
+            } = guard.take().unwrap();
+
+            drop(guard);
+            drop(opt_reservation);
+
+            ::log::info!("all socket workers bound to {}", address);
+
+            if let Some(callback) = opt_callback {
+                callback(address);
+            }
+        }
+    }
+}
+
+fn reserve_port(address: SocketAddr, only_ipv6: bool, socket_type: Type) -> anyhow::Result<Socket> {
+    let domain = if address.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+
+    let socket = Socket::new(domain, socket_type, None)?;
+
+    if only_ipv6 {
+        socket.set_only_v6(true)?;
+    }
+
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    socket.bind(&address.into())?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn test_bind_notifier() {
+        let mut address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let (sender, receiver) = channel();
+
+        let notifier = BindNotifier::new(
+            &mut address,
+            false,
+            Type::DGRAM,
+            2,
+            Some(Box::new(move |addr| sender.send(addr).unwrap())),
+        )
+        .unwrap();
+
+        assert_ne!(address.port(), 0);
+
+        notifier.socket_bound();
+
+        assert!(receiver.try_recv().is_err());
+
+        notifier.socket_bound();
+
+        assert_eq!(receiver.try_recv().unwrap(), address);
+
+        // Reservation has been released
+        UdpSocket::bind(address).unwrap();
+
+        // Further calls have no effect
+        notifier.socket_bound();
+    }
+}
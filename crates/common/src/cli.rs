@@ -1,10 +1,13 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
-use std::io::Read;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Stdout, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use aquatic_toml_config::TomlConfig;
 use git_testament::{git_testament, CommitKind};
-use log::LevelFilter;
+use log::{LevelFilter, Log, Metadata, Record};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode};
 
@@ -30,17 +33,102 @@ pub trait Config: Default + TomlConfig + DeserializeOwned + std::fmt::Debug {
     fn get_log_level(&self) -> Option<LogLevel> {
         None
     }
+    /// Cargo features the application was built with
+    fn enabled_features() -> Vec<&'static str> {
+        Vec::new()
+    }
+    /// Whether the application shuts down gracefully on SIGTERM and SIGINT
+    /// by itself. If not, it is exited on them when running with
+    /// environment config.
+    fn handles_termination_signals() -> bool {
+        false
+    }
 }
 
+/// Information on what is deployed, for printing, exporting as metric and
+/// serving on admin endpoints
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Short commit hash, empty if not available
+    pub commit: String,
+    pub features: Vec<&'static str>,
+    /// Hash of parsed configuration, for checking that instances run with
+    /// identical settings
+    pub config_hash: String,
+}
+
+impl BuildInfo {
+    pub fn new<T: Config>(crate_version: &str, config: &T) -> Self {
+        git_testament!(TESTAMENT);
+
+        let commit = match TESTAMENT.commit {
+            CommitKind::NoTags(hash, _) | CommitKind::FromTag(_, hash, _, _) => first_8_chars(hash),
+            _ => String::new(),
+        };
+
+        Self {
+            version: crate_version.to_string(),
+            commit,
+            features: T::enabled_features(),
+            config_hash: config_hash(config),
+        }
+    }
+}
+
+/// Hash Debug representation of configuration
+///
+/// Only stable for binaries built with the same Rust version.
+pub fn config_hash<T: std::fmt::Debug>(config: &T) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    format!("{:?}", config).hash(&mut hasher);
+
+    format!("{:016x}", hasher.finish())
+}
+
+/// Prefix of environment variables read in environment config mode
+///
+/// Nested fields are separated by double underscores, e.g.,
+/// `AQUATIC_NETWORK__ADDRESS` sets `address` in the `network` section.
+pub const ENV_CONFIG_PREFIX: &str = "AQUATIC_";
+
 #[derive(Debug, Default)]
 pub struct Options {
     config_file: Option<String>,
+    env_config: bool,
     print_config: bool,
     print_parsed_config: bool,
     print_version: bool,
+    verbose: bool,
+    opt_protocol: Option<ProtocolOptions>,
+}
+
+/// Protocol selected in the combined binary, see [`Options::set_protocol`]
+#[derive(Debug, Clone)]
+struct ProtocolOptions {
+    selected: String,
+    all: Vec<String>,
 }
 
 impl Options {
+    /// Set protocol selected in the combined binary
+    ///
+    /// In environment config mode, variables starting with
+    /// `AQUATIC_<PROTOCOL>__` then override shared ones for the selected
+    /// protocol, e.g., `AQUATIC_UDP__PROTOCOL__MAX_SCRAPE_TORRENTS` takes
+    /// precedence over `AQUATIC_PROTOCOL__MAX_SCRAPE_TORRENTS`. Variables
+    /// for other protocols in `all_protocols` are ignored.
+    pub fn set_protocol(&mut self, protocol: &str, all_protocols: &[&str]) {
+        self.opt_protocol = Some(ProtocolOptions {
+            selected: protocol.to_ascii_lowercase(),
+            all: all_protocols
+                .iter()
+                .map(|protocol| protocol.to_ascii_lowercase())
+                .collect(),
+        });
+    }
+
     pub fn parse_args<I>(mut arg_iter: I) -> Result<Options, Option<String>>
     where
         I: Iterator<Item = String>,
@@ -58,6 +146,9 @@ impl Options {
                             return Err(Some("No config file path given".to_string()));
                         }
                     }
+                    "-e" | "--env-config" => {
+                        options.env_config = true;
+                    }
                     "-p" | "--print-config" => {
                         options.print_config = true;
                     }
@@ -67,6 +158,9 @@ impl Options {
                     "-v" | "--version" => {
                         options.print_version = true;
                     }
+                    "--verbose" => {
+                        options.verbose = true;
+                    }
                     "-h" | "--help" => {
                         return Err(None);
                     }
@@ -80,6 +174,12 @@ impl Options {
             }
         }
 
+        if options.env_config && options.config_file.is_some() {
+            return Err(Some(
+                "Config file and environment config can't be combined".to_string(),
+            ));
+        }
+
         Ok(options)
     }
 }
@@ -138,20 +238,35 @@ where
 
         println!("{}{}", crate_version, commit_info);
 
+        if options.verbose {
+            let config: T = load_config(&options)?;
+
+            let build_info = BuildInfo::new(crate_version, &config);
+
+            println!("features: {}", build_info.features.join(", "));
+            println!("config hash: {}", build_info.config_hash);
+        }
+
         Ok(())
     } else if options.print_config {
         print!("{}", default_config_as_toml::<T>());
 
         Ok(())
     } else {
-        let config = if let Some(path) = options.config_file {
-            config_from_toml_file(path)?
-        } else {
-            T::default()
-        };
+        let config: T = load_config(&options)?;
 
         if let Some(log_level) = config.get_log_level() {
-            start_logger(log_level)?;
+            if options.env_config {
+                start_json_logger(log_level)?;
+            } else {
+                start_logger(log_level)?;
+            }
+        }
+
+        // Exit cleanly when container orchestrators stop the application
+        #[cfg(unix)]
+        if options.env_config && !T::handles_termination_signals() {
+            exit_on_termination_signals()?;
         }
 
         if options.print_parsed_config {
@@ -170,16 +285,107 @@ where
 
     println!("\nOptions:");
     println!("    -c, --config-file     Load config from this path");
+    println!(
+        "    -e, --env-config      Load config from {}* environment variables",
+        ENV_CONFIG_PREFIX
+    );
+    println!("                          on top of defaults and log to stdout as JSON");
     println!("    -h, --help            Print this help message");
     println!("    -p, --print-config    Print default config");
     println!("    -P                    Print parsed config");
     println!("    -v, --version         Print version information");
+    println!("    --verbose             With --version, also print enabled features and");
+    println!("                          hash of config (default or given with -c)");
 
     if let Some(error) = opt_error {
         println!("\nError: {}.", error);
     }
 }
 
+fn load_config<T: Config>(options: &Options) -> anyhow::Result<T> {
+    if let Some(path) = options.config_file.clone() {
+        config_from_toml_file(path)
+    } else if options.env_config {
+        config_from_env_vars(::std::env::vars(), options.opt_protocol.as_ref())
+    } else {
+        Ok(T::default())
+    }
+}
+
+/// Parse config from environment variables starting with [ENV_CONFIG_PREFIX]
+///
+/// Values are parsed as TOML values if possible (e.g., numbers, booleans
+/// and arrays) and used as strings otherwise. Fields not set fall back to
+/// defaults.
+fn config_from_env_vars<T, I>(vars: I, opt_protocol: Option<&ProtocolOptions>) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+    I: Iterator<Item = (String, String)>,
+{
+    let mut table = toml::value::Table::new();
+    let mut protocol_overrides = Vec::new();
+
+    for (key, value) in vars {
+        let key = if let Some(key) = key.strip_prefix(ENV_CONFIG_PREFIX) {
+            key.to_ascii_lowercase()
+        } else {
+            continue;
+        };
+
+        if let Some(protocol) = opt_protocol {
+            match key.split_once("__") {
+                Some((prefix, key)) if prefix == protocol.selected => {
+                    protocol_overrides.push((key.to_string(), value));
+
+                    continue;
+                }
+                Some((prefix, _)) if protocol.all.iter().any(|p| p == prefix) => {
+                    continue;
+                }
+                _ => (),
+            }
+        }
+
+        insert_env_var(&mut table, &key, &value)?;
+    }
+
+    // Apply after shared variables so that they take precedence
+    for (key, value) in protocol_overrides {
+        insert_env_var(&mut table, &key, &value)?;
+    }
+
+    toml::Value::Table(table)
+        .try_into()
+        .context("Couldn't parse config from environment variables")
+}
+
+fn insert_env_var(table: &mut toml::value::Table, key: &str, value: &str) -> anyhow::Result<()> {
+    let mut path = key.split("__").collect::<Vec<_>>();
+    let field = path.pop().unwrap();
+
+    let mut current = table;
+
+    for section in path {
+        current = current
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .with_context(|| format!("{} is not a config section", section))?;
+    }
+
+    current.insert(field.to_string(), parse_env_var_value(value));
+
+    Ok(())
+}
+
+fn parse_env_var_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|mut table| table.as_table_mut()?.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
 fn config_from_toml_file<T>(path: String) -> anyhow::Result<T>
 where
     T: DeserializeOwned,
@@ -202,6 +408,90 @@ where
     <T as TomlConfig>::default_to_string()
 }
 
+/// Log to stdout as JSON lines
+struct JsonLogger {
+    level_filter: LevelFilter,
+    stdout: Stdout,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_filter
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "thread": ::std::thread::current().name(),
+            "message": record.args().to_string(),
+        });
+
+        let _ = writeln!(self.stdout.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.stdout.lock().flush();
+    }
+}
+
+fn start_json_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
+    let level_filter = level_filter(log_level);
+
+    ::log::set_boxed_logger(Box::new(JsonLogger {
+        level_filter,
+        stdout: ::std::io::stdout(),
+    }))
+    .context("Couldn't initialize logger")?;
+
+    ::log::set_max_level(level_filter);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn exit_on_termination_signals() -> anyhow::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("register signal handlers")?;
+
+    ::std::thread::Builder::new()
+        .name("termination".into())
+        .spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                ::log::info!("Received signal {}, exiting", signal);
+                ::log::logger().flush();
+
+                ::std::process::exit(0);
+            }
+        })
+        .context("spawn termination signal thread")?;
+
+    Ok(())
+}
+
+fn level_filter(log_level: LogLevel) -> LevelFilter {
+    match log_level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
 fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
     let mut builder = simplelog::ConfigBuilder::new();
 
@@ -216,17 +506,8 @@ fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
         Err(builder) => builder.build(),
     };
 
-    let level_filter = match log_level {
-        LogLevel::Off => LevelFilter::Off,
-        LogLevel::Error => LevelFilter::Error,
-        LogLevel::Warn => LevelFilter::Warn,
-        LogLevel::Info => LevelFilter::Info,
-        LogLevel::Debug => LevelFilter::Debug,
-        LogLevel::Trace => LevelFilter::Trace,
-    };
-
     TermLogger::init(
-        level_filter,
+        level_filter(log_level),
         config,
         TerminalMode::Stderr,
         ColorChoice::Auto,
@@ -253,3 +534,85 @@ fn get_commit_info() -> String {
 fn first_8_chars(input: &str) -> String {
     input.chars().take(8).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct TestConfig {
+        workers: usize,
+        log_level: LogLevel,
+        network: TestNetworkConfig,
+    }
+
+    #[derive(Debug, Default, PartialEq, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct TestNetworkConfig {
+        address: String,
+        only_ipv6: bool,
+        ports: Vec<u16>,
+    }
+
+    #[test]
+    fn test_config_from_env_vars() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("AQUATIC_WORKERS", "4"),
+            ("AQUATIC_LOG_LEVEL", "info"),
+            ("AQUATIC_NETWORK__ADDRESS", "[::]:3000"),
+            ("AQUATIC_NETWORK__ONLY_IPV6", "true"),
+            ("AQUATIC_NETWORK__PORTS", "[1, 2]"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+
+        let config: TestConfig = config_from_env_vars(vars, None).unwrap();
+
+        assert_eq!(
+            config,
+            TestConfig {
+                workers: 4,
+                log_level: LogLevel::Info,
+                network: TestNetworkConfig {
+                    address: "[::]:3000".into(),
+                    only_ipv6: true,
+                    ports: vec![1, 2],
+                },
+            }
+        );
+
+        let vars = ::std::iter::once(("AQUATIC_UNKNOWN".to_string(), "1".to_string()));
+
+        assert!(config_from_env_vars::<TestConfig, _>(vars, None).is_err());
+    }
+
+    #[test]
+    fn test_config_from_env_vars_with_protocol() {
+        let vars = || {
+            [
+                ("AQUATIC_UDP__WORKERS", "8"),
+                ("AQUATIC_WORKERS", "4"),
+                ("AQUATIC_UDP__NETWORK__ONLY_IPV6", "true"),
+                ("AQUATIC_HTTP__NETWORK__ADDRESS", "[::]:3000"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        };
+
+        let mut options = Options::default();
+
+        options.set_protocol("udp", &["udp", "http", "ws"]);
+
+        let config: TestConfig =
+            config_from_env_vars(vars(), options.opt_protocol.as_ref()).unwrap();
+
+        assert_eq!(config.workers, 8);
+        assert!(config.network.only_ipv6);
+        assert_eq!(config.network.address, "");
+
+        // Without protocol, prefixed variables are unknown fields
+        assert!(config_from_env_vars::<TestConfig, _>(vars(), None).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+//! Connection table snapshots for diagnosing stuck connections and leaks
+//!
+//! Socket workers of the HTTP and WebSocket trackers periodically summarize
+//! their connection slabs into a [ConnectionTableSnapshot], which is served as
+//! JSON by an endpoint spawned with [spawn_connection_table_endpoint]. The
+//! endpoint also serves [BuildInfo].
+//!
+//! The UDP tracker keeps no per-connection state (connection IDs are
+//! validated statelessly), so there is nothing to export there.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::cli::BuildInfo;
+use crate::shutdown::ShutdownSignal;
+use crate::{canonical_ip, ip_prefix, SecondsSinceServerStart, ValidUntil};
+
+/// How often endpoint checks for new connections and shutdown
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Upper bounds (inclusive, in seconds) of distribution buckets. A final
+/// bucket without upper bound is always added.
+const BUCKET_UPPER_BOUNDS: &[u32] = &[10, 60, 300, 900, 3600];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// TLS or protocol handshake in progress
+    Handshake,
+    Open,
+}
+
+/// Diagnostic data kept in connection handles
+#[derive(Clone, Debug)]
+pub struct ConnectionTableEntry {
+    pub remote_ip: IpAddr,
+    pub opened_at: SecondsSinceServerStart,
+    /// Updated by connection task
+    pub state: Rc<Cell<ConnectionState>>,
+}
+
+impl ConnectionTableEntry {
+    /// IPv4-mapped addresses are converted to IPv4 addresses
+    pub fn new(remote_ip: IpAddr, opened_at: SecondsSinceServerStart) -> Self {
+        Self {
+            remote_ip: canonical_ip(remote_ip),
+            opened_at,
+            state: Rc::new(Cell::new(ConnectionState::Handshake)),
+        }
+    }
+
+    /// Create [ConnectionInfo], deriving idle time from connection expiry
+    /// time and maximum idle time
+    pub fn info(
+        &self,
+        now: SecondsSinceServerStart,
+        valid_until: ValidUntil,
+        max_idle_seconds: u32,
+    ) -> ConnectionInfo {
+        let seconds_left = valid_until.to_seconds().saturating_sub(now.0);
+
+        ConnectionInfo {
+            state: self.state.get(),
+            remote_ip: self.remote_ip,
+            age_seconds: now.0.saturating_sub(self.opened_at.0),
+            idle_seconds: max_idle_seconds.saturating_sub(seconds_left),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    pub state: ConnectionState,
+    pub remote_ip: IpAddr,
+    pub age_seconds: u32,
+    pub idle_seconds: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StateCounts {
+    pub handshake: usize,
+    pub open: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DistributionBucket {
+    /// None means no upper bound
+    pub max_seconds: Option<u32>,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Distribution {
+    pub max_seconds: u32,
+    pub buckets: Vec<DistributionBucket>,
+}
+
+impl Default for Distribution {
+    fn default() -> Self {
+        let buckets = BUCKET_UPPER_BOUNDS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain(::std::iter::once(None))
+            .map(|max_seconds| DistributionBucket {
+                max_seconds,
+                count: 0,
+            })
+            .collect();
+
+        Self {
+            max_seconds: 0,
+            buckets,
+        }
+    }
+}
+
+impl Distribution {
+    fn add(&mut self, seconds: u32) {
+        self.max_seconds = self.max_seconds.max(seconds);
+
+        for bucket in self.buckets.iter_mut() {
+            match bucket.max_seconds {
+                Some(max_seconds) if seconds > max_seconds => continue,
+                _ => {
+                    bucket.count += 1;
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SourceIpCount {
+    /// IPv4 address or IPv6 prefix (see [`crate::ip_prefix`])
+    pub ip: IpAddr,
+    pub connections: usize,
+}
+
+/// Summary of the connection slab of one socket worker
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionTableSnapshot {
+    /// Seconds since server start
+    pub updated_at: u32,
+    pub num_connections: usize,
+    pub states: StateCounts,
+    pub age: Distribution,
+    pub idle: Distribution,
+    /// Source IPs (IPv6: prefixes) with the most connections, in descending
+    /// order
+    pub top_source_ips: Vec<SourceIpCount>,
+}
+
+impl ConnectionTableSnapshot {
+    pub fn new(
+        now: SecondsSinceServerStart,
+        connections: impl Iterator<Item = ConnectionInfo>,
+        max_top_source_ips: usize,
+        ipv6_prefix_len: u8,
+    ) -> Self {
+        let mut snapshot = Self {
+            updated_at: now.0,
+            ..Default::default()
+        };
+        let mut source_ips: HashMap<IpAddr, usize> = HashMap::new();
+
+        for info in connections {
+            snapshot.num_connections += 1;
+
+            match info.state {
+                ConnectionState::Handshake => snapshot.states.handshake += 1,
+                ConnectionState::Open => snapshot.states.open += 1,
+            }
+
+            snapshot.age.add(info.age_seconds);
+            snapshot.idle.add(info.idle_seconds);
+
+            *source_ips
+                .entry(ip_prefix(info.remote_ip, ipv6_prefix_len))
+                .or_default() += 1;
+        }
+
+        let mut top_source_ips = source_ips
+            .into_iter()
+            .map(|(ip, connections)| SourceIpCount { ip, connections })
+            .collect::<Vec<_>>();
+
+        top_source_ips.sort_unstable_by(|a, b| {
+            b.connections
+                .cmp(&a.connections)
+                .then_with(|| a.ip.cmp(&b.ip))
+        });
+        top_source_ips.truncate(max_top_source_ips);
+
+        snapshot.top_source_ips = top_source_ips;
+
+        snapshot
+    }
+}
+
+#[derive(Serialize)]
+struct WorkerSnapshot<'a> {
+    worker_index: usize,
+    #[serde(flatten)]
+    snapshot: &'a ConnectionTableSnapshot,
+}
+
+/// Latest connection table snapshots of all socket workers
+pub struct ConnectionTables(Mutex<Vec<Option<ConnectionTableSnapshot>>>);
+
+impl ConnectionTables {
+    pub fn new(num_socket_workers: usize) -> Self {
+        Self(Mutex::new(vec![None; num_socket_workers]))
+    }
+
+    pub fn update(&self, worker_index: usize, snapshot: ConnectionTableSnapshot) {
+        if let Some(entry) = self.0.lock().unwrap().get_mut(worker_index) {
+            *entry = Some(snapshot);
+        }
+    }
+
+    /// JSON array of snapshots of workers that have reported one
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        let snapshots = self.0.lock().unwrap();
+
+        let snapshots = snapshots
+            .iter()
+            .enumerate()
+            .filter_map(|(worker_index, opt_snapshot)| {
+                opt_snapshot.as_ref().map(|snapshot| WorkerSnapshot {
+                    worker_index,
+                    snapshot,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_string_pretty(&snapshots).context("serialize connection tables")
+    }
+}
+
+/// Serve connection table snapshots as JSON on `GET /connections` and build
+/// information on `GET /build_info`
+pub fn spawn_connection_table_endpoint(
+    addr: SocketAddr,
+    tables: Arc<ConnectionTables>,
+    build_info: BuildInfo,
+    shutdown: ShutdownSignal,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("bind connection table endpoint to {}", addr))?;
+
+    // Poll for connections, so that shutdown is noticed
+    listener.set_nonblocking(true)?;
+
+    let handle = Builder::new()
+        .name("diagnostics".into())
+        .spawn(move || loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = handle_request(stream, &tables, &build_info) {
+                        ::log::warn!("connection table endpoint: {:#}", err);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if shutdown.sleep(ACCEPT_INTERVAL) {
+                        return Ok(());
+                    }
+                }
+                Err(err) => {
+                    ::log::warn!("connection table endpoint: accept connection: {:#}", err);
+                }
+            }
+        })
+        .context("spawn connection table endpoint")?;
+
+    Ok(handle)
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    tables: &ConnectionTables,
+    build_info: &BuildInfo,
+) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut buffer = [0u8; 1024];
+    let bytes_read = stream.read(&mut buffer)?;
+
+    let request = &buffer[..bytes_read];
+
+    let (status, body) = if request.starts_with(b"GET /connections ") {
+        ("200 OK", tables.to_json()?)
+    } else if request.starts_with(b"GET /build_info ") {
+        (
+            "200 OK",
+            serde_json::to_string_pretty(build_info).context("serialize build info")?,
+        )
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_connection_table_snapshot() {
+        let ip_a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let connections = [
+            (ConnectionState::Open, ip_a, 5, 1),
+            (ConnectionState::Open, ip_a, 100, 50),
+            (ConnectionState::Handshake, ip_b, 4000, 4000),
+        ]
+        .into_iter()
+        .map(
+            |(state, remote_ip, age_seconds, idle_seconds)| ConnectionInfo {
+                state,
+                remote_ip,
+                age_seconds,
+                idle_seconds,
+            },
+        );
+
+        let snapshot =
+            ConnectionTableSnapshot::new(SecondsSinceServerStart(4000), connections, 1, 64);
+
+        assert_eq!(snapshot.num_connections, 3);
+        assert_eq!(
+            snapshot.states,
+            StateCounts {
+                handshake: 1,
+                open: 2
+            }
+        );
+        assert_eq!(snapshot.age.max_seconds, 4000);
+        assert_eq!(
+            snapshot
+                .age
+                .buckets
+                .iter()
+                .map(|b| b.count)
+                .collect::<Vec<_>>(),
+            vec![1, 0, 1, 0, 0, 1]
+        );
+        assert_eq!(
+            snapshot
+                .idle
+                .buckets
+                .iter()
+                .map(|b| b.count)
+                .collect::<Vec<_>>(),
+            vec![1, 1, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            snapshot.top_source_ips,
+            vec![SourceIpCount {
+                ip: ip_a,
+                connections: 2
+            }]
+        );
+
+        let tables = ConnectionTables::new(2);
+
+        assert_eq!(tables.to_json().unwrap(), "[]");
+
+        tables.update(1, snapshot);
+
+        let json: serde_json::Value = serde_json::from_str(&tables.to_json().unwrap()).unwrap();
+
+        assert_eq!(json[0]["worker_index"], 1);
+        assert_eq!(json[0]["states"]["handshake"], 1);
+    }
+
+    #[test]
+    fn test_connection_table_snapshot_ipv6_prefix() {
+        let connections = ["2001:db8::1", "2001:db8::2", "2001:db8:0:1::1"]
+            .into_iter()
+            .map(|ip| ConnectionInfo {
+                state: ConnectionState::Open,
+                remote_ip: ip.parse().unwrap(),
+                age_seconds: 0,
+                idle_seconds: 0,
+            });
+
+        let snapshot =
+            ConnectionTableSnapshot::new(SecondsSinceServerStart(0), connections.clone(), 1, 64);
+
+        assert_eq!(
+            snapshot.top_source_ips,
+            vec![SourceIpCount {
+                ip: "2001:db8::".parse().unwrap(),
+                connections: 2
+            }]
+        );
+
+        let snapshot = ConnectionTableSnapshot::new(SecondsSinceServerStart(0), connections, 1, 48);
+
+        assert_eq!(snapshot.top_source_ips[0].connections, 3);
+    }
+
+    #[test]
+    fn test_build_info_endpoint() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let build_info = BuildInfo {
+            version: "0.9.0".into(),
+            commit: "abcdef12".into(),
+            features: vec!["prometheus"],
+            config_hash: "0123456789abcdef".into(),
+        };
+        let shutdown = ShutdownSignal::default();
+
+        let handle = spawn_connection_table_endpoint(
+            addr,
+            Arc::new(ConnectionTables::new(1)),
+            build_info,
+            shutdown.clone(),
+        )
+        .unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"GET /build_info HTTP/1.1\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(json["version"], "0.9.0");
+        assert_eq!(json["features"][0], "prometheus");
+        assert_eq!(json["config_hash"], "0123456789abcdef");
+
+        shutdown.trigger();
+
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -20,6 +20,10 @@ pub trait CpuPinningConfig {
     fn active(&self) -> bool;
     fn direction(&self) -> CpuPinningDirection;
     fn core_offset(&self) -> usize;
+    fn socket_worker_cores(&self) -> &[usize];
+    fn swarm_worker_cores(&self) -> &[usize];
+    fn util_cores(&self) -> &[usize];
+    fn isolate_hyperthreads(&self) -> bool;
 }
 
 // Do these shenanigans for compatibility with aquatic_toml_config
@@ -33,10 +37,25 @@ pub mod mod_name {
 
     /// Experimental cpu pinning
     #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+    #[serde(default, deny_unknown_fields)]
     pub struct struct_name {
         pub active: bool,
         pub direction: CpuPinningDirection,
         pub core_offset: usize,
+        /// Explicit list of core indices for socket workers
+        ///
+        /// Worker n is pinned to entry n modulo list length. If empty, cores
+        /// are chosen according to `direction` and `core_offset`.
+        pub socket_worker_cores: Vec<usize>,
+        /// Explicit list of core indices for swarm workers
+        pub swarm_worker_cores: Vec<usize>,
+        /// Explicit list of core indices for utility threads such as the
+        /// statistics worker. Only the first entry is currently used.
+        pub util_cores: Vec<usize>,
+        /// Pin each thread to a single hardware thread of its core, leaving
+        /// hyperthread siblings free, instead of to all of the core's
+        /// hardware threads
+        pub isolate_hyperthreads: bool,
     }
 
     impl Default for struct_name {
@@ -45,6 +64,10 @@ pub mod mod_name {
                 active: false,
                 direction: cpu_pinning_direction,
                 core_offset: 0,
+                socket_worker_cores: Vec::new(),
+                swarm_worker_cores: Vec::new(),
+                util_cores: Vec::new(),
+                isolate_hyperthreads: false,
             }
         }
     }
@@ -58,6 +81,18 @@ pub mod mod_name {
         fn core_offset(&self) -> usize {
             self.core_offset
         }
+        fn socket_worker_cores(&self) -> &[usize] {
+            &self.socket_worker_cores
+        }
+        fn swarm_worker_cores(&self) -> &[usize] {
+            &self.swarm_worker_cores
+        }
+        fn util_cores(&self) -> &[usize] {
+            &self.util_cores
+        }
+        fn isolate_hyperthreads(&self) -> bool {
+            self.isolate_hyperthreads
+        }
     }
 }
 
@@ -76,6 +111,18 @@ impl WorkerIndex {
         swarm_workers: usize,
         num_cores: usize,
     ) -> usize {
+        let (explicit_cores, index) = match self {
+            Self::SocketWorker(index) => (config.socket_worker_cores(), *index),
+            Self::SwarmWorker(index) => (config.swarm_worker_cores(), *index),
+            Self::Util => (config.util_cores(), 0),
+        };
+
+        // Explicitly configured cores are used as-is, so that invalid
+        // indices are reported when pinning
+        if !explicit_cores.is_empty() {
+            return explicit_cores[index % explicit_cores.len()];
+        }
+
         let ascending_index = match self {
             Self::SocketWorker(index) => config.core_offset() + index,
             Self::SwarmWorker(index) => config.core_offset() + socket_workers + index,
@@ -93,6 +140,43 @@ impl WorkerIndex {
     }
 }
 
+fn core_cpu_sets(topology: &hwloc::Topology) -> Vec<hwloc::CpuSet> {
+    topology
+        .objects_with_type(&hwloc::ObjectType::Core)
+        .expect("hwloc: list cores")
+        .into_iter()
+        .map(|core| core.allowed_cpuset().expect("hwloc: get core cpu set"))
+        .collect()
+}
+
+/// Log which core each worker will be pinned to
+///
+/// Requires hwloc (`apt-get install libhwloc-dev`)
+pub fn log_cpu_pinning_mapping<C: CpuPinningConfig>(
+    config: &C,
+    socket_workers: usize,
+    swarm_workers: usize,
+) {
+    if !config.active() {
+        return;
+    }
+
+    let num_cores = core_cpu_sets(&hwloc::Topology::new()).len();
+
+    let worker_indices = (0..socket_workers)
+        .map(WorkerIndex::SocketWorker)
+        .chain((0..swarm_workers).map(WorkerIndex::SwarmWorker))
+        .chain(::std::iter::once(WorkerIndex::Util));
+
+    for worker_index in worker_indices {
+        ::log::info!(
+            "CPU pinning: {:?} -> core {}",
+            worker_index,
+            worker_index.get_core_index(config, socket_workers, swarm_workers, num_cores)
+        );
+    }
+}
+
 /// Pin current thread to a suitable core
 ///
 /// Requires hwloc (`apt-get install libhwloc-dev`)
@@ -102,28 +186,28 @@ pub fn pin_current_if_configured_to<C: CpuPinningConfig>(
     swarm_workers: usize,
     worker_index: WorkerIndex,
 ) {
-    use hwloc::{CpuSet, ObjectType, Topology, CPUBIND_THREAD};
+    use hwloc::{Topology, CPUBIND_THREAD};
 
     if config.active() {
         let mut topology = Topology::new();
 
-        let core_cpu_sets: Vec<CpuSet> = topology
-            .objects_with_type(&ObjectType::Core)
-            .expect("hwloc: list cores")
-            .into_iter()
-            .map(|core| core.allowed_cpuset().expect("hwloc: get core cpu set"))
-            .collect();
+        let core_cpu_sets = core_cpu_sets(&topology);
 
         let num_cores = core_cpu_sets.len();
 
         let core_index =
             worker_index.get_core_index(config, socket_workers, swarm_workers, num_cores);
 
-        let cpu_set = core_cpu_sets
+        let mut cpu_set = core_cpu_sets
             .get(core_index)
             .unwrap_or_else(|| panic!("get cpu set for core {}", core_index))
             .to_owned();
 
+        if config.isolate_hyperthreads() {
+            // Keep only first hardware thread of core
+            cpu_set.singlify();
+        }
+
         topology
             .set_cpubind(cpu_set, CPUBIND_THREAD)
             .unwrap_or_else(|err| panic!("bind thread to core {}: {:?}", core_index, err));
@@ -0,0 +1,333 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use serde::{Deserialize, Serialize};
+
+/// IP blocklist configuration
+///
+/// Requests from addresses in the blocklist are ignored.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpBlocklistConfig {
+    pub enabled: bool,
+    /// Path to blocklist file
+    ///
+    /// Each line can contain an IP address, a CIDR range, an address range
+    /// in the form `start-end`, a range in P2P format (`description:start-end`)
+    /// or a range in DAT format (`start - end , level , description`). DAT
+    /// entries with a level above 127 are ignored. Empty lines and lines
+    /// starting with `#` are skipped.
+    ///
+    /// Reputation feeds can be downloaded to this path periodically by an
+    /// external program, e.g., a cron job.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+    /// Reload file this often (seconds). It is also reloaded when the
+    /// program receives `SIGUSR1`.
+    ///
+    /// 0 = only reload on `SIGUSR1`
+    pub update_interval: u64,
+}
+
+impl Default for IpBlocklistConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "./ip-blocklist.txt".into(),
+            update_interval: 60 * 60,
+        }
+    }
+}
+
+/// Sorted, non-overlapping inclusive IP address ranges
+#[derive(Default, Clone)]
+pub struct IpBlocklist {
+    ipv4: Vec<(u32, u32)>,
+    ipv6: Vec<(u128, u128)>,
+}
+
+impl IpBlocklist {
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let mut ipv4 = Vec::new();
+        let mut ipv6 = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_line(line)
+                .with_context(|| format!("Invalid line in IP blocklist: {}", line))?
+            {
+                Some((IpAddr::V4(start), IpAddr::V4(end))) => ipv4.push((start.into(), end.into())),
+                Some((IpAddr::V6(start), IpAddr::V6(end))) => ipv6.push((start.into(), end.into())),
+                Some(_) => {
+                    return Err(anyhow::anyhow!(
+                        "Mixed IP versions in IP blocklist range: {}",
+                        line
+                    ))
+                }
+                None => (),
+            }
+        }
+
+        Ok(Self {
+            ipv4: merge_ranges(ipv4, |ip| ip.saturating_add(1)),
+            ipv6: merge_ranges(ipv6, |ip| ip.saturating_add(1)),
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => ranges_contain(&self.ipv4, ip.into()),
+            IpAddr::V6(ip) => ranges_contain(&self.ipv6, ip.into()),
+        }
+    }
+
+    /// Number of ranges after merging
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
+    }
+}
+
+pub type IpBlocklistArcSwap = ArcSwap<IpBlocklist>;
+pub type IpBlocklistCache = Cache<Arc<IpBlocklistArcSwap>, Arc<IpBlocklist>>;
+
+pub fn create_ip_blocklist_cache(arc_swap: &Arc<IpBlocklistArcSwap>) -> IpBlocklistCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_ip_blocklist(
+    config: &IpBlocklistConfig,
+    ip_blocklist: &Arc<IpBlocklistArcSwap>,
+) -> anyhow::Result<()> {
+    if config.enabled {
+        match IpBlocklist::create_from_path(&config.path) {
+            Ok(new_list) => {
+                ::log::info!("IP blocklist updated ({} ranges)", new_list.len());
+
+                ip_blocklist.store(Arc::new(new_list));
+            }
+            Err(err) => {
+                ::log::error!("Updating IP blocklist failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns None for DAT entries with levels that don't indicate blocking
+fn parse_line(line: &str) -> anyhow::Result<Option<(IpAddr, IpAddr)>> {
+    if let Ok(range) = parse_range(line) {
+        return Ok(Some(range));
+    }
+
+    // P2P format. IPv6 addresses contain colons, so continue with DAT
+    // format if the part after the last colon is not a valid range
+    if let Some((_, range)) = line.rsplit_once(':') {
+        if let Ok(range) = parse_range(range) {
+            return Ok(Some(range));
+        }
+    }
+
+    // DAT format
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest
+            .split(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse::<u16>()
+            .unwrap_or(0);
+
+        if level > 127 {
+            return Ok(None);
+        }
+
+        return parse_range(range).map(Some);
+    }
+
+    parse_range(line).map(Some)
+}
+
+fn parse_range(range: &str) -> anyhow::Result<(IpAddr, IpAddr)> {
+    let range = range.trim();
+
+    if let Some((start, end)) = range.split_once('-') {
+        let start = parse_ip(start)?;
+        let end = parse_ip(end)?;
+
+        if start > end {
+            return Err(anyhow::anyhow!("Range start is after end"));
+        }
+
+        Ok((start, end))
+    } else if let Some((ip, prefix_len)) = range.split_once('/') {
+        let prefix_len: u32 = prefix_len.trim().parse().context("parse prefix length")?;
+
+        match parse_ip(ip)? {
+            IpAddr::V4(ip) if prefix_len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+
+                Ok((
+                    Ipv4Addr::from(start).into(),
+                    Ipv4Addr::from(start | !mask).into(),
+                ))
+            }
+            IpAddr::V6(ip) if prefix_len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+
+                Ok((
+                    Ipv6Addr::from(start).into(),
+                    Ipv6Addr::from(start | !mask).into(),
+                ))
+            }
+            _ => Err(anyhow::anyhow!("Invalid prefix length")),
+        }
+    } else {
+        let ip = parse_ip(range)?;
+
+        Ok((ip, ip))
+    }
+}
+
+/// Parse IP address, accepting zero-padded IPv4 octets as used in DAT files
+fn parse_ip(ip: &str) -> anyhow::Result<IpAddr> {
+    let ip = ip.trim();
+
+    if let Ok(ip) = ip.parse() {
+        return Ok(ip);
+    }
+
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+
+    for octet in octets.iter_mut() {
+        *octet = parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .with_context(|| format!("Invalid IP address: {}", ip))?;
+    }
+
+    if parts.next().is_some() {
+        return Err(anyhow::anyhow!("Invalid IP address: {}", ip));
+    }
+
+    Ok(Ipv4Addr::from(octets).into())
+}
+
+/// Sort ranges and merge overlapping and adjacent ones
+fn merge_ranges<T: Ord + Copy>(mut ranges: Vec<(T, T)>, next: fn(T) -> T) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= next(*last_end) => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged.shrink_to_fit();
+
+    merged
+}
+
+fn ranges_contain<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let index = ranges.partition_point(|(start, _)| *start <= ip);
+
+    index > 0 && ranges[index - 1].1 >= ip
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+
+        assert_eq!(
+            parse_line("Some, Inc:1.2.3.0-1.2.3.255").unwrap(),
+            Some((ip("1.2.3.0"), ip("1.2.3.255")))
+        );
+        assert_eq!(
+            parse_line("001.002.003.000 - 001.002.003.255 , 000 , Some range").unwrap(),
+            Some((ip("1.2.3.0"), ip("1.2.3.255")))
+        );
+        assert_eq!(
+            parse_line("001.002.003.000 - 001.002.003.255 , 200 , Allowed").unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_line("10.0.0.0/8").unwrap(),
+            Some((ip("10.0.0.0"), ip("10.255.255.255")))
+        );
+        assert_eq!(
+            parse_line("2001:db8::/32").unwrap(),
+            Some((
+                ip("2001:db8::"),
+                ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")
+            ))
+        );
+        assert_eq!(
+            parse_line("2001:db8::1-2001:db8::ff").unwrap(),
+            Some((ip("2001:db8::1"), ip("2001:db8::ff")))
+        );
+        assert_eq!(
+            parse_line("192.168.0.1").unwrap(),
+            Some((ip("192.168.0.1"), ip("192.168.0.1")))
+        );
+
+        assert!(parse_line("1.2.3.4-1.2.3.0").is_err());
+        assert!(parse_line("1.2.3").is_err());
+        assert!(parse_line("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_ip_blocklist() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+
+        writeln!(file, "# comment").unwrap();
+        writeln!(file, "a:1.0.0.0-1.0.0.9").unwrap();
+        writeln!(file, "b:1.0.0.10-1.0.0.20").unwrap();
+        writeln!(file, "c:1.0.0.15-1.0.0.16").unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, "2001:db8::/64").unwrap();
+
+        let blocklist = IpBlocklist::create_from_path(&file.path().into()).unwrap();
+
+        assert_eq!(blocklist.len(), 2);
+
+        assert!(blocklist.contains("1.0.0.0".parse().unwrap()));
+        assert!(blocklist.contains("1.0.0.20".parse().unwrap()));
+        assert!(!blocklist.contains("1.0.0.21".parse().unwrap()));
+        assert!(!blocklist.contains("0.255.255.255".parse().unwrap()));
+        assert!(blocklist.contains("2001:db8::1".parse().unwrap()));
+        assert!(!blocklist.contains("2001:db8:0:1::1".parse().unwrap()));
+    }
+}
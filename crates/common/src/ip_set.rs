@@ -0,0 +1,201 @@
+//! Concurrent set of IP addresses with per-entry expiry
+//!
+//! Meant for state such as bans, rate limiter state and anomaly detection,
+//! where entries should disappear after a while without every feature
+//! growing its own map of IPs.
+
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use ahash::RandomState;
+use hashbrown::HashMap;
+
+use crate::{ip_prefix, SecondsSinceServerStart, ValidUntil};
+
+const NUM_SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Shard {
+    /// Expiry time (seconds since server start) of each entry
+    entries: HashMap<IpAddr, u32>,
+    /// Entries by expiry time, for removing expired entries without
+    /// iterating over all of them
+    ///
+    /// Entries whose expiry time has since changed are left in place and
+    /// skipped when their time comes.
+    expiry_queue: BTreeMap<u32, Vec<IpAddr>>,
+}
+
+/// Set of IPv4 addresses and IPv6 prefixes with per-entry expiry
+///
+/// IPv6 addresses are aggregated by prefix of `ipv6_prefix_len` bits (see
+/// [`crate::ip_prefix`]). Entries are split into shards protected by
+/// separate mutexes, so threads rarely contend. Expired entries are no
+/// longer considered members, but only free memory once
+/// [`IpSet::remove_expired`] is called.
+pub struct IpSet {
+    ipv6_prefix_len: u8,
+    hasher_builder: RandomState,
+    shards: Box<[Mutex<Shard>]>,
+}
+
+impl IpSet {
+    pub fn new(ipv6_prefix_len: u8) -> Self {
+        Self {
+            ipv6_prefix_len,
+            hasher_builder: RandomState::new(),
+            shards: (0..NUM_SHARDS).map(|_| Default::default()).collect(),
+        }
+    }
+
+    /// Insert entry or update its expiry time
+    pub fn insert(&self, ip: IpAddr, valid_until: ValidUntil) {
+        let ip = ip_prefix(ip, self.ipv6_prefix_len);
+        let valid_until = valid_until.to_seconds();
+
+        let mut shard = self.shard(ip).lock().unwrap();
+
+        if shard.entries.insert(ip, valid_until) != Some(valid_until) {
+            shard.expiry_queue.entry(valid_until).or_default().push(ip);
+        }
+    }
+
+    /// Check if set contains non-expired entry for IP
+    pub fn contains(&self, ip: IpAddr, now: SecondsSinceServerStart) -> bool {
+        let ip = ip_prefix(ip, self.ipv6_prefix_len);
+
+        self.shard(ip)
+            .lock()
+            .unwrap()
+            .entries
+            .get(&ip)
+            .map(|valid_until| ValidUntil::from_seconds(*valid_until).valid(now))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if entry was present
+    pub fn remove(&self, ip: IpAddr) -> bool {
+        let ip = ip_prefix(ip, self.ipv6_prefix_len);
+
+        // Queue entry is skipped once its time comes
+        self.shard(ip).lock().unwrap().entries.remove(&ip).is_some()
+    }
+
+    /// Remove expired entries, returning how many were removed
+    ///
+    /// Only expired entries are visited, so this is cheap to call often.
+    pub fn remove_expired(&self, now: SecondsSinceServerStart) -> usize {
+        let mut num_removed = 0;
+
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let shard = &mut *shard;
+
+            // Entries are valid while valid_until > now
+            let not_expired = shard
+                .expiry_queue
+                .split_off(&(now.to_seconds().saturating_add(1)));
+            let expired = ::std::mem::replace(&mut shard.expiry_queue, not_expired);
+
+            for (valid_until, ips) in expired {
+                for ip in ips {
+                    if shard.entries.get(&ip) == Some(&valid_until) {
+                        shard.entries.remove(&ip);
+
+                        num_removed += 1;
+                    }
+                }
+            }
+
+            if shard.entries.capacity() > shard.entries.len() * 4 {
+                shard.entries.shrink_to_fit();
+            }
+        }
+
+        num_removed
+    }
+
+    /// Number of entries, including expired ones that haven't been removed
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, ip: IpAddr) -> &Mutex<Shard> {
+        let mut hasher = self.hasher_builder.build_hasher();
+
+        ip.hash(&mut hasher);
+
+        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_until(seconds: u32) -> ValidUntil {
+        ValidUntil::from_seconds(seconds)
+    }
+
+    fn now(seconds: u32) -> SecondsSinceServerStart {
+        SecondsSinceServerStart::from_seconds(seconds)
+    }
+
+    #[test]
+    fn test_ip_set() {
+        let set = IpSet::new(64);
+
+        let ipv4: IpAddr = "10.0.0.1".parse().unwrap();
+        let ipv6_a: IpAddr = "2001:db8::1".parse().unwrap();
+        let ipv6_b: IpAddr = "2001:db8::2".parse().unwrap();
+        let ipv6_c: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+
+        set.insert(ipv4, valid_until(10));
+        set.insert(ipv6_a, valid_until(20));
+
+        assert!(set.contains(ipv4, now(9)));
+        assert!(!set.contains(ipv4, now(10)));
+
+        // Addresses in same /64 prefix share entry
+        assert!(set.contains(ipv6_b, now(10)));
+        assert!(!set.contains(ipv6_c, now(10)));
+        assert_eq!(set.len(), 2);
+
+        assert_eq!(set.remove_expired(now(9)), 0);
+        assert_eq!(set.remove_expired(now(10)), 1);
+        assert_eq!(set.len(), 1);
+
+        // Extending expiry time keeps entry around
+        set.insert(ipv6_b, valid_until(30));
+
+        assert_eq!(set.remove_expired(now(20)), 0);
+        assert!(set.contains(ipv6_a, now(20)));
+        assert_eq!(set.remove_expired(now(30)), 1);
+        assert!(set.is_empty());
+
+        // Entries can be removed before they expire
+        set.insert(ipv4, valid_until(40));
+
+        assert!(set.remove(ipv4));
+        assert!(!set.remove(ipv4));
+        assert!(!set.contains(ipv4, now(0)));
+        assert_eq!(set.remove_expired(now(40)), 0);
+
+        // Reinserted entry isn't removed at expiry time of removed one
+        set.insert(ipv4, valid_until(50));
+        set.remove(ipv4);
+        set.insert(ipv4, valid_until(60));
+
+        assert_eq!(set.remove_expired(now(50)), 0);
+        assert!(set.contains(ipv4, now(50)));
+    }
+}
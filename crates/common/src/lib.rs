@@ -1,20 +1,101 @@
 use std::fmt::Display;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Instant;
 
 use ahash::RandomState;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
 
 pub mod access_list;
+pub mod bind;
 pub mod cli;
+pub mod connection_table;
 #[cfg(feature = "cpu-pinning")]
 pub mod cpu_pinning;
+pub mod ip_blocklist;
+pub mod ip_set;
+pub mod maintenance;
+pub mod observer;
+pub mod pending_scrape;
 pub mod privileges;
+pub mod routing;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+pub mod shutdown;
+pub mod user_agent;
 
 /// IndexMap using AHash hasher
 pub type IndexMap<K, V> = indexmap::IndexMap<K, V, RandomState>;
 
+/// How to handle announce requests with port 0. Available policies are
+/// reject and hide.
+///
+/// Peers announcing port 0 can't be connected to.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortZeroPolicy {
+    /// Respond with an error
+    Reject,
+    /// Respond normally, but never return peer to other peers
+    Hide,
+}
+
+/// Failure reason in responses to scrape requests with more info hashes
+/// than `protocol.max_scrape_torrents`. Shared by all protocols.
+pub const TOO_MANY_SCRAPE_TORRENTS: &str = "Too many info hashes in scrape request";
+
+/// Validate `protocol.max_scrape_torrents`, which is shared by all protocols
+pub fn validate_max_scrape_torrents(max_scrape_torrents: usize) -> anyhow::Result<()> {
+    if max_scrape_torrents == 0 {
+        Err(anyhow::anyhow!(
+            "configuration: protocol.max_scrape_torrents must be at least 1"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validate length of prefixes that IPv6 addresses are aggregated by
+pub fn validate_ipv6_prefix_len(ipv6_prefix_len: u8) -> anyhow::Result<()> {
+    if ipv6_prefix_len > 128 {
+        Err(anyhow::anyhow!(
+            "configuration: network.ipv6_prefix_len must be at most 128"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Address with all bits after the first `ipv6_prefix_len` set to zero if
+/// it is an IPv6 address
+///
+/// Hosts are commonly assigned a whole /64 (or even /48) network and can
+/// pick new addresses in it at will, e.g., with SLAAC, so per-IP accounting
+/// should operate on prefixes. IPv4 addresses are returned unchanged.
+pub fn ip_prefix(ip: IpAddr, ipv6_prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(ipv6_prefix_len.into()))
+                .unwrap_or(0);
+
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+/// IP address that is not an IPv6-mapped IPv4 address
+///
+/// Use this wherever addresses are compared or counted, so that a.b.c.d and
+/// ::ffff:a.b.c.d, as reported by dual-stack sockets, are treated the same.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
 /// Peer, connection or similar valid until this instant
 #[derive(Debug, Clone, Copy)]
 pub struct ValidUntil(SecondsSinceServerStart);
@@ -32,6 +113,58 @@ impl ValidUntil {
     pub fn valid(&self, now: SecondsSinceServerStart) -> bool {
         self.0 .0 > now.0
     }
+    /// Seconds since server start, for use in compact representations
+    pub fn to_seconds(self) -> u32 {
+        self.0 .0
+    }
+    pub fn from_seconds(seconds: u32) -> Self {
+        Self(SecondsSinceServerStart(seconds))
+    }
+}
+
+/// Decides whether peers have expired during cleaning
+///
+/// Peers are valid until `max_peer_age` seconds after they last announced.
+/// Seeders, which often announce less frequently, are instead kept for
+/// `max_seeder_age` seconds, unless it is 0.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerExpiry {
+    now: SecondsSinceServerStart,
+    now_for_seeders: SecondsSinceServerStart,
+}
+
+impl PeerExpiry {
+    pub fn new(now: SecondsSinceServerStart, max_peer_age: u32, max_seeder_age: u32) -> Self {
+        // Peers are valid until last announce + max_peer_age, so seeders
+        // should be kept while last announce + max_seeder_age > now, i.e.,
+        // while valid_until > now + max_peer_age - max_seeder_age
+        let now_for_seeders = if max_seeder_age == 0 {
+            now
+        } else {
+            SecondsSinceServerStart(
+                now.0
+                    .saturating_add(max_peer_age)
+                    .saturating_sub(max_seeder_age),
+            )
+        };
+
+        Self {
+            now,
+            now_for_seeders,
+        }
+    }
+
+    pub fn now(&self) -> SecondsSinceServerStart {
+        self.now
+    }
+
+    pub fn keep(&self, valid_until: ValidUntil, is_seeder: bool) -> bool {
+        if is_seeder {
+            valid_until.valid(self.now_for_seeders)
+        } else {
+            valid_until.valid(self.now)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -56,6 +189,16 @@ impl ServerStartInstant {
 #[derive(Debug, Clone, Copy)]
 pub struct SecondsSinceServerStart(u32);
 
+impl SecondsSinceServerStart {
+    /// For use in compact representations
+    pub fn to_seconds(self) -> u32 {
+        self.0
+    }
+    pub fn from_seconds(seconds: u32) -> Self {
+        Self(seconds)
+    }
+}
+
 /// SocketAddr that is not an IPv6-mapped IPv4 address
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct CanonicalSocketAddr(SocketAddr);
@@ -64,15 +207,10 @@ impl CanonicalSocketAddr {
     pub fn new(addr: SocketAddr) -> Self {
         match addr {
             addr @ SocketAddr::V4(_) => Self(addr),
-            SocketAddr::V6(addr) => {
-                match addr.ip().octets() {
-                    // Convert IPv4-mapped address (available in std but nightly-only)
-                    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Self(SocketAddr::V4(
-                        SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), addr.port()),
-                    )),
-                    _ => Self(addr.into()),
-                }
-            }
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => Self(SocketAddr::V4(SocketAddrV4::new(ip, addr.port()))),
+                None => Self(addr.into()),
+            },
         }
     }
 
@@ -101,13 +239,35 @@ impl CanonicalSocketAddr {
     pub fn is_ipv4(&self) -> bool {
         self.0.is_ipv4()
     }
+
+    /// IP address, aggregated by prefix if it is an IPv6 address
+    ///
+    /// See [`ip_prefix`].
+    pub fn ip_prefix(self, ipv6_prefix_len: u8) -> IpAddr {
+        ip_prefix(self.0.ip(), ipv6_prefix_len)
+    }
+
+    /// IPv6 /64 prefix (usually a single host), or IPv4 address
+    pub fn ip_prefix_64(self) -> IpAddr {
+        self.ip_prefix(64)
+    }
+
+    /// IPv6 /48 prefix (usually a single site), or IPv4 address
+    pub fn ip_prefix_48(self) -> IpAddr {
+        self.ip_prefix(48)
+    }
 }
 
+/// Spawn thread serving metrics over HTTP in prometheus format until
+/// shutdown is triggered
 #[cfg(feature = "prometheus")]
 pub fn spawn_prometheus_endpoint(
     addr: SocketAddr,
     timeout: Option<::std::time::Duration>,
     timeout_mask: Option<metrics_util::MetricKindMask>,
+    // Exported as labels of constant gauge aquatic_build_info if set
+    opt_build_info: Option<cli::BuildInfo>,
+    shutdown: shutdown::ShutdownSignal,
 ) -> anyhow::Result<::std::thread::JoinHandle<anyhow::Result<()>>> {
     use std::thread::Builder;
     use std::time::Duration;
@@ -144,13 +304,42 @@ pub fn spawn_prometheus_endpoint(
                     loop {
                         interval.tick().await;
 
+                        // Set on every tick so that gauge isn't considered
+                        // idle
+                        if let Some(build_info) = opt_build_info.as_ref() {
+                            ::metrics::gauge!(
+                                aquatic_metrics::names::BUILD_INFO,
+                                "version" => build_info.version.clone(),
+                                "commit" => build_info.commit.clone(),
+                                "features" => build_info.features.join(","),
+                                "config_hash" => build_info.config_hash.clone(),
+                            )
+                            .set(1.0);
+                        }
+
                         // Periodically render metrics to make sure
                         // idles are cleaned up
                         recorder_handle.render();
                     }
                 });
 
-                exporter.await.context("run prometheus exporter")
+                let exporter = ::tokio::spawn(exporter);
+
+                // Dropping the exporter closes its listener
+                while !shutdown.is_triggered() {
+                    if exporter.is_finished() {
+                        return exporter
+                            .await
+                            .context("join prometheus exporter")?
+                            .context("run prometheus exporter");
+                    }
+
+                    ::tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+
+                exporter.abort();
+
+                Ok(())
             })
         })
         .context("spawn prometheus endpoint")?;
@@ -164,6 +353,13 @@ pub enum WorkerType {
     Statistics,
     Signals,
     Cleaning,
+    ReachabilityProber,
+    IpBlocklistUpdater,
+    AccessListUpdater,
+    Diagnostics,
+    SnapshotEndpoint,
+    SnapshotFileWriter,
+    Maintenance,
     #[cfg(feature = "prometheus")]
     Prometheus,
 }
@@ -176,8 +372,81 @@ impl Display for WorkerType {
             Self::Statistics => f.write_str("Statistics worker"),
             Self::Signals => f.write_str("Signals worker"),
             Self::Cleaning => f.write_str("Cleaning worker"),
+            Self::ReachabilityProber => f.write_str("Reachability prober"),
+            Self::IpBlocklistUpdater => f.write_str("IP blocklist updater"),
+            Self::AccessListUpdater => f.write_str("Access list updater"),
+            Self::Diagnostics => f.write_str("Diagnostics endpoint"),
+            Self::SnapshotEndpoint => f.write_str("Snapshot endpoint"),
+            Self::SnapshotFileWriter => f.write_str("Snapshot file writer"),
+            Self::Maintenance => f.write_str("Maintenance worker"),
             #[cfg(feature = "prometheus")]
             Self::Prometheus => f.write_str("Prometheus worker"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_prefix() {
+        let addr =
+            CanonicalSocketAddr::new("[2001:db8:1:2:3:4:5:6]:1000".parse::<SocketAddr>().unwrap());
+
+        assert_eq!(
+            addr.ip_prefix_64(),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            addr.ip_prefix_48(),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(addr.ip_prefix(128), addr.get().ip());
+        assert_eq!(addr.ip_prefix(0), "::".parse::<IpAddr>().unwrap());
+
+        // IPv4 and IPv4-mapped addresses are not affected
+        let addr = CanonicalSocketAddr::new("[::ffff:10.0.0.1]:1000".parse().unwrap());
+
+        assert_eq!(addr.ip_prefix(48), "10.0.0.1".parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            canonical_ip("::ffff:10.0.0.1".parse().unwrap()),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            canonical_ip("2001:db8::1".parse().unwrap()),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        assert!(validate_ipv6_prefix_len(128).is_ok());
+        assert!(validate_ipv6_prefix_len(129).is_err());
+    }
+
+    #[test]
+    fn test_peer_expiry() {
+        let now = SecondsSinceServerStart(1000);
+
+        // Peers announced 100 seconds ago with max_peer_age 60 and 120
+        let expired = ValidUntil::new_with_now(SecondsSinceServerStart(900), 60);
+        let valid = ValidUntil::new_with_now(SecondsSinceServerStart(900), 120);
+
+        let expiry = PeerExpiry::new(now, 60, 0);
+
+        assert!(!expiry.keep(expired, false));
+        assert!(!expiry.keep(expired, true));
+        assert!(expiry.keep(valid, true));
+
+        // Seeders are kept for 150 seconds
+        let expiry = PeerExpiry::new(now, 60, 150);
+
+        assert!(!expiry.keep(expired, false));
+        assert!(expiry.keep(expired, true));
+
+        // Seeders are kept for 90 seconds
+        let expiry = PeerExpiry::new(now, 120, 90);
+
+        assert!(expiry.keep(valid, false));
+        assert!(!expiry.keep(valid, true));
+    }
+}
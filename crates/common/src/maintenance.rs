@@ -0,0 +1,270 @@
+//! Scheduling of maintenance tasks during low-traffic windows
+
+use std::str::FromStr;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::shutdown::ShutdownSignal;
+
+/// Cron-like schedule with minute granularity
+///
+/// Expressions consist of five whitespace-separated fields: minute (0-59),
+/// hour (0-23), day of month (1-31), month (1-12) and day of week (0-7,
+/// where both 0 and 7 mean Sunday). Each field is `*` or a comma-separated
+/// list of values (`5`) and ranges (`1-5`), each optionally followed by a
+/// step (`*/15`, `1-5/2`). Times are in UTC.
+///
+/// As in cron, if both day of month and day of week are restricted (don't
+/// start with `*`), days matching either field match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl Schedule {
+    /// Check if schedule matches the minute containing given time
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        let days = seconds / 86_400;
+        let seconds_of_day = seconds % 86_400;
+
+        let minute = seconds_of_day / 60 % 60;
+        let hour = seconds_of_day / 3600;
+        // 1970-01-01 was a Thursday
+        let day_of_week = (days + 4) % 7;
+        let (month, day_of_month) = month_and_day_from_days(days);
+
+        let day_matches = match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => {
+                bit_set(self.days_of_month, day_of_month) || bit_set(self.days_of_week, day_of_week)
+            }
+            _ => {
+                bit_set(self.days_of_month, day_of_month) && bit_set(self.days_of_week, day_of_week)
+            }
+        };
+
+        bit_set(self.minutes, minute)
+            && bit_set(self.hours, hour)
+            && bit_set(self.months, month)
+            && day_matches
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+
+        let (minutes, hours, days_of_month, months, days_of_week) = match fields[..] {
+            [minutes, hours, days_of_month, months, days_of_week] => {
+                (minutes, hours, days_of_month, months, days_of_week)
+            }
+            _ => return Err(anyhow::anyhow!("expected 5 fields, got {}", fields.len())),
+        };
+
+        let mut days_of_week_bits = parse_field(days_of_week, 0, 7).context("day of week")?;
+
+        // 7 means Sunday
+        if bit_set(days_of_week_bits, 7) {
+            days_of_week_bits = (days_of_week_bits | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).context("minute")?,
+            hours: parse_field(hours, 0, 23).context("hour")?,
+            days_of_month: parse_field(days_of_month, 1, 31).context("day of month")?,
+            months: parse_field(months, 1, 12).context("month")?,
+            days_of_week: days_of_week_bits,
+            days_of_month_restricted: !days_of_month.starts_with('*'),
+            days_of_week_restricted: !days_of_week.starts_with('*'),
+        })
+    }
+}
+
+/// Run `run_tasks` whenever `schedule` matches the current minute, until
+/// shutdown is triggered
+pub fn spawn_maintenance_worker(
+    schedule: Schedule,
+    shutdown: ShutdownSignal,
+    mut run_tasks: impl FnMut() + Send + 'static,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let handle = Builder::new()
+        .name("maintenance".into())
+        .spawn(move || {
+            let mut last_checked_minute = None;
+
+            loop {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let until_next_minute = Duration::from_secs(60)
+                    - Duration::from_nanos((now.as_nanos() % 60_000_000_000) as u64);
+
+                if shutdown.sleep(until_next_minute) {
+                    return Ok(());
+                }
+
+                let now = SystemTime::now();
+                let minute = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+
+                // Don't run twice in the same minute if woken up early
+                if last_checked_minute == Some(minute) {
+                    continue;
+                }
+
+                last_checked_minute = Some(minute);
+
+                if schedule.matches(now) {
+                    ::log::info!("running scheduled maintenance");
+
+                    run_tasks();
+                }
+            }
+        })
+        .context("spawn maintenance worker")?;
+
+    Ok(handle)
+}
+
+/// Parse field into bitmask of allowed values
+fn parse_field(field: &str, min: u64, max: u64) -> anyhow::Result<u64> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().context("parse step")?),
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            return Err(anyhow::anyhow!("step must be greater than 0"));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse::<u64>().context("parse range start")?,
+                end.parse::<u64>().context("parse range end")?,
+            )
+        } else {
+            let value = range.parse::<u64>().context("parse value")?;
+
+            // As in cron, `5/10` means from 5 to maximum value in steps of 10
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(anyhow::anyhow!(
+                "{} is not a valid range within {}-{}",
+                range,
+                min,
+                max
+            ));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+fn bit_set(bits: u64, value: u64) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// Convert days since Unix epoch to month (1-12) and day of month (1-31)
+///
+/// Based on the `civil_from_days` algorithm by Howard Hinnant.
+fn month_and_day_from_days(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(unix_seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(unix_seconds)
+    }
+
+    #[test]
+    fn test_month_and_day_from_days() {
+        assert_eq!(month_and_day_from_days(0), (1, 1));
+        // 2024-02-29
+        assert_eq!(month_and_day_from_days(19_782), (2, 29));
+        // 2024-12-31
+        assert_eq!(month_and_day_from_days(20_088), (12, 31));
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert!("* * * * *".parse::<Schedule>().is_ok());
+        assert!("*/15 1-5/2 1,15 * 1-7".parse::<Schedule>().is_ok());
+
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("* * 0 * *".parse::<Schedule>().is_err());
+        assert!("* * * * 8".parse::<Schedule>().is_err());
+        assert!("5-1 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("a * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_schedule_matches() {
+        // Monday 2024-01-01 03:30:15 UTC
+        let t = time(1_704_079_815);
+
+        let matches = |s: &str| s.parse::<Schedule>().unwrap().matches(t);
+
+        assert!(matches("* * * * *"));
+        assert!(matches("30 3 * * *"));
+        assert!(matches("*/15 2-4 1 1 1"));
+        assert!(matches("0-59/10 3 * * 1"));
+        assert!(!matches("31 3 * * *"));
+        assert!(!matches("30 4 * * *"));
+        assert!(!matches("30 3 * 2 *"));
+        assert!(!matches("30 3 * * 0"));
+        // Sunday as 7
+        assert!(matches("30 3 * * 1,7"));
+        assert!(!matches("30 3 * * 7"));
+
+        // Either day of month or day of week must match if both are
+        // restricted
+        assert!(matches("30 3 2 * 1"));
+        assert!(matches("30 3 1 * 2"));
+        assert!(!matches("30 3 2 * 2"));
+        // Both must match if either is unrestricted
+        assert!(!matches("30 3 2 * *"));
+        assert!(!matches("30 3 * * 2"));
+    }
+}
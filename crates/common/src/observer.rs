@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// Announce event as sent by peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceKind {
+    Started,
+    Stopped,
+    Completed,
+    /// Regular announce without event
+    Update,
+}
+
+/// Announce request handled by a swarm worker
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceEvent {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// Address that peer can be reached at, if known
+    pub peer_addr: Option<SocketAddr>,
+    pub kind: AnnounceKind,
+    /// Bytes left to download, if sent by peer
+    pub bytes_left: Option<u64>,
+}
+
+/// Channels of subscribers to announce events
+///
+/// Swarm workers never block on subscribers: events are dropped for
+/// subscribers whose channels are full. Channels with dropped receivers are
+/// removed.
+#[derive(Clone, Default)]
+pub struct AnnounceSubscribers {
+    inner: Arc<AnnounceSubscribersInner>,
+}
+
+#[derive(Default)]
+struct AnnounceSubscribersInner {
+    /// Set if there are any senders, to avoid locking when there are none
+    active: AtomicBool,
+    senders: Mutex<Vec<SyncSender<AnnounceEvent>>>,
+}
+
+impl AnnounceSubscribers {
+    /// Subscribe to events, buffering at most `capacity` of them
+    pub fn subscribe(&self, capacity: usize) -> Receiver<AnnounceEvent> {
+        let (sender, receiver) = sync_channel(capacity);
+
+        let mut senders = self.inner.senders.lock().unwrap();
+
+        senders.push(sender);
+
+        self.inner.active.store(true, Ordering::Release);
+
+        receiver
+    }
+
+    /// Send event to subscribers. Since `create_event` is only called if
+    /// there are any, this is cheap when there are none.
+    #[inline]
+    pub fn publish(&self, create_event: impl FnOnce() -> AnnounceEvent) {
+        if self.inner.active.load(Ordering::Acquire) {
+            self.publish_inner(create_event());
+        }
+    }
+
+    fn publish_inner(&self, event: AnnounceEvent) {
+        let mut senders = self.inner.senders.lock().unwrap();
+
+        senders.retain(|sender| match sender.try_send(event.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+
+        self.inner
+            .active
+            .store(!senders.is_empty(), Ordering::Release);
+    }
+}
+
+/// Number of torrents and peers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SwarmSummary {
+    pub torrents: usize,
+    pub seeders: usize,
+    pub leechers: usize,
+}
+
+/// Latest swarm summaries of all swarm workers
+pub struct SwarmSummaries {
+    summaries: Mutex<Vec<SwarmSummary>>,
+}
+
+impl SwarmSummaries {
+    pub fn new(num_swarm_workers: usize) -> Self {
+        Self {
+            summaries: Mutex::new(vec![Default::default(); num_swarm_workers]),
+        }
+    }
+
+    pub fn update(&self, worker_index: usize, summary: SwarmSummary) {
+        if let Some(entry) = self.summaries.lock().unwrap().get_mut(worker_index) {
+            *entry = summary;
+        }
+    }
+
+    /// Sum of latest summaries
+    pub fn total(&self) -> SwarmSummary {
+        self.summaries
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(SwarmSummary::default(), |total, summary| SwarmSummary {
+                torrents: total.torrents + summary.torrents,
+                seeders: total.seeders + summary.seeders,
+                leechers: total.leechers + summary.leechers,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: AnnounceKind) -> AnnounceEvent {
+        AnnounceEvent {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            peer_addr: None,
+            kind,
+            bytes_left: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_announce_subscribers() {
+        let subscribers = AnnounceSubscribers::default();
+
+        subscribers.publish(|| unreachable!("no subscribers"));
+
+        let receiver_a = subscribers.subscribe(1);
+        let receiver_b = subscribers.subscribe(2);
+
+        subscribers.publish(|| event(AnnounceKind::Started));
+        subscribers.publish(|| event(AnnounceKind::Completed));
+
+        // Second event is dropped for subscriber with full channel
+        assert_eq!(receiver_a.try_recv().unwrap().kind, AnnounceKind::Started);
+        assert!(receiver_a.try_recv().is_err());
+
+        assert_eq!(receiver_b.try_recv().unwrap().kind, AnnounceKind::Started);
+        assert_eq!(receiver_b.try_recv().unwrap().kind, AnnounceKind::Completed);
+
+        drop(receiver_a);
+        drop(receiver_b);
+
+        subscribers.publish(|| event(AnnounceKind::Stopped));
+        subscribers.publish(|| unreachable!("subscribers were removed"));
+    }
+
+    #[test]
+    fn test_swarm_summaries() {
+        let summaries = SwarmSummaries::new(2);
+
+        let summary = SwarmSummary {
+            torrents: 1,
+            seeders: 2,
+            leechers: 3,
+        };
+
+        summaries.update(0, summary);
+        summaries.update(1, summary);
+        summaries.update(2, summary);
+
+        assert_eq!(
+            summaries.total(),
+            SwarmSummary {
+                torrents: 2,
+                seeders: 4,
+                leechers: 6,
+            }
+        );
+    }
+}
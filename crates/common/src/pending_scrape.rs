@@ -0,0 +1,357 @@
+//! Assembly of scrape responses from partial responses by swarm workers
+//!
+//! Scrape requests are split up by the swarm worker responsible for each
+//! info hash (see [crate::routing]). The partial responses need to be merged
+//! before a full response can be sent back.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use slab::Slab;
+
+use crate::{SecondsSinceServerStart, ValidUntil};
+
+/// Only reclaim memory from [PendingScrapeResponses] when slab capacity is at
+/// least this many times its length, to avoid reallocating on every scrape
+const SHRINK_FACTOR: usize = 4;
+/// Never shrink [PendingScrapeResponses] slab below this capacity
+const SHRINK_MIN_CAPACITY: usize = 16;
+
+/// Scrape response being assembled from partial responses
+#[derive(Debug)]
+pub struct PendingScrapeResponse<T> {
+    pending_parts: usize,
+    response: T,
+}
+
+impl<T> PendingScrapeResponse<T> {
+    /// Start with `response` (usually empty), expecting `num_parts` partial
+    /// responses
+    pub fn new(num_parts: usize, response: T) -> Self {
+        Self {
+            pending_parts: num_parts,
+            response,
+        }
+    }
+
+    /// Merge partial response using `merge`, returning true if all parts
+    /// have now been received
+    pub fn merge_part<F: FnOnce(&mut T)>(&mut self, merge: F) -> bool {
+        merge(&mut self.response);
+
+        self.pending_parts = self.pending_parts.saturating_sub(1);
+
+        self.is_finished()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending_parts == 0
+    }
+
+    pub fn into_response(self) -> T {
+        self.response
+    }
+}
+
+impl<T: Default> PendingScrapeResponse<T> {
+    /// Start with empty response, expecting one partial response per group
+    /// of info hashes (see [crate::routing::group_by_swarm_worker])
+    pub fn for_groups<I>(groups: &BTreeMap<usize, Vec<I>>) -> Self {
+        Self::new(groups.len(), Default::default())
+    }
+}
+
+/// Key of a pending scrape response
+///
+/// Indices are reused once responses are finished or expired. The generation
+/// tells apart responses stored at the same index, so that late partial
+/// responses to an expired scrape are not merged into a newer one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingScrapeKey {
+    pub index: usize,
+    pub generation: u32,
+}
+
+/// Pending scrape responses keyed by small, reused indices
+///
+/// Useful when partial responses arrive through a channel shared with other
+/// messages and need to be matched with the request they belong to.
+#[derive(Debug)]
+pub struct PendingScrapeResponses<T> {
+    slab: Slab<PendingScrapeEntry<T>>,
+    next_generation: u32,
+}
+
+#[derive(Debug)]
+struct PendingScrapeEntry<T> {
+    generation: u32,
+    valid_until: ValidUntil,
+    pending: PendingScrapeResponse<T>,
+}
+
+impl<T> Default for PendingScrapeResponses<T> {
+    fn default() -> Self {
+        Self {
+            slab: Slab::new(),
+            next_generation: 0,
+        }
+    }
+}
+
+impl<T> PendingScrapeResponses<T> {
+    /// Store pending response, returning its key
+    pub fn insert(
+        &mut self,
+        pending: PendingScrapeResponse<T>,
+        valid_until: ValidUntil,
+    ) -> PendingScrapeKey {
+        let generation = self.next_generation;
+
+        self.next_generation = self.next_generation.wrapping_add(1);
+
+        let index = self.slab.insert(PendingScrapeEntry {
+            generation,
+            valid_until,
+            pending,
+        });
+
+        PendingScrapeKey { index, generation }
+    }
+
+    /// Merge partial response into pending response with given key
+    ///
+    /// Returns the full response if all parts have now been received. Fails
+    /// if there is no pending response with the key, e.g., because it has
+    /// expired.
+    pub fn merge_part<F: FnOnce(&mut T)>(
+        &mut self,
+        key: PendingScrapeKey,
+        merge: F,
+    ) -> anyhow::Result<Option<T>> {
+        let entry = self
+            .slab
+            .get_mut(key.index)
+            .filter(|entry| entry.generation == key.generation)
+            .ok_or_else(|| anyhow::anyhow!("pending scrape response {:?} not found", key))?;
+
+        if entry.pending.merge_part(merge) {
+            let entry = self.slab.remove(key.index);
+
+            if self.slab.capacity() > SHRINK_MIN_CAPACITY
+                && self.slab.capacity() >= self.slab.len().saturating_mul(SHRINK_FACTOR)
+            {
+                self.slab.shrink_to_fit();
+            }
+
+            Ok(Some(entry.pending.into_response()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn remove(&mut self, key: PendingScrapeKey) -> Option<PendingScrapeResponse<T>> {
+        match self.slab.get(key.index) {
+            Some(entry) if entry.generation == key.generation => {
+                Some(self.slab.remove(key.index).pending)
+            }
+            _ => None,
+        }
+    }
+
+    /// Remove expired pending responses, returning number removed
+    pub fn clean(&mut self, now: SecondsSinceServerStart) -> usize {
+        let len_before = self.slab.len();
+
+        self.slab.retain(|_, entry| entry.valid_until.valid(now));
+
+        len_before - self.slab.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slab.is_empty()
+    }
+}
+
+/// Torrent statistics collected out of order and returned in the order of
+/// the info hashes in the original request
+///
+/// Partial responses arrive in swarm worker order, with each swarm worker
+/// only handling some of the info hashes. Statistics for info hashes that
+/// weren't requested are ignored. Info hashes requested more than once are
+/// only returned at their first position.
+#[derive(Debug)]
+pub struct OrderedScrapeStatistics<K, S> {
+    info_hashes: Vec<K>,
+    stats: HashMap<K, Option<S>>,
+}
+
+impl<K: Copy + Eq + Hash, S> OrderedScrapeStatistics<K, S> {
+    /// Prepare for info hashes in original request order
+    pub fn new(info_hashes: Vec<K>) -> Self {
+        let stats = info_hashes
+            .iter()
+            .map(|info_hash| (*info_hash, None))
+            .collect();
+
+        Self { info_hashes, stats }
+    }
+
+    pub fn insert(&mut self, info_hash: K, stats: S) {
+        if let Some(entry) = self.stats.get_mut(&info_hash) {
+            *entry = Some(stats);
+        }
+    }
+
+    /// Return statistics in original order, skipping info hashes that
+    /// no statistics were inserted for
+    pub fn into_ordered(mut self) -> impl Iterator<Item = (K, S)> {
+        self.info_hashes.into_iter().filter_map(move |info_hash| {
+            self.stats
+                .remove(&info_hash)
+                .flatten()
+                .map(|stats| (info_hash, stats))
+        })
+    }
+}
+
+impl<K: Copy + Eq + Hash, S> Extend<(K, S)> for OrderedScrapeStatistics<K, S> {
+    fn extend<T: IntoIterator<Item = (K, S)>>(&mut self, iter: T) {
+        for (info_hash, stats) in iter {
+            self.insert(info_hash, stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ServerStartInstant;
+
+    use super::*;
+
+    #[test]
+    fn test_pending_scrape_responses() {
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 60);
+
+        let mut pending_responses: PendingScrapeResponses<Vec<u8>> = Default::default();
+
+        let key = pending_responses.insert(PendingScrapeResponse::new(2, Vec::new()), valid_until);
+
+        assert_eq!(
+            pending_responses
+                .merge_part(key, |response| response.push(1))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            pending_responses
+                .merge_part(key, |response| response.push(2))
+                .unwrap(),
+            Some(vec![1, 2])
+        );
+        assert!(pending_responses.is_empty());
+        assert!(pending_responses.merge_part(key, |_| ()).is_err());
+
+        let expired = ValidUntil::new(server_start_instant, 0);
+
+        pending_responses.insert(PendingScrapeResponse::new(1, Vec::new()), expired);
+        pending_responses.insert(PendingScrapeResponse::new(1, Vec::new()), valid_until);
+
+        assert_eq!(
+            pending_responses.clean(server_start_instant.seconds_elapsed()),
+            1
+        );
+        assert_eq!(pending_responses.len(), 1);
+    }
+
+    #[test]
+    fn test_pending_scrape_responses_reused_index() {
+        let server_start_instant = ServerStartInstant::new();
+
+        let mut pending_responses: PendingScrapeResponses<Vec<u8>> = Default::default();
+
+        let old_key = pending_responses.insert(
+            PendingScrapeResponse::new(2, Vec::new()),
+            ValidUntil::new(server_start_instant, 0),
+        );
+
+        pending_responses.clean(server_start_instant.seconds_elapsed());
+
+        let new_key = pending_responses.insert(
+            PendingScrapeResponse::new(1, Vec::new()),
+            ValidUntil::new(server_start_instant, 60),
+        );
+
+        assert_eq!(old_key.index, new_key.index);
+
+        // Late partial response to expired scrape must not be merged
+        assert!(pending_responses
+            .merge_part(old_key, |response| response.push(1))
+            .is_err());
+        assert!(pending_responses.remove(old_key).is_none());
+
+        assert_eq!(
+            pending_responses
+                .merge_part(new_key, |response| response.push(2))
+                .unwrap(),
+            Some(vec![2])
+        );
+    }
+
+    #[test]
+    fn test_pending_scrape_response_for_groups() {
+        let groups = crate::routing::group_by_swarm_worker([[0u8; 20], [1; 20], [2; 20]], 2, |h| h);
+
+        let mut pending: PendingScrapeResponse<Vec<u8>> =
+            PendingScrapeResponse::for_groups(&groups);
+
+        assert!(!pending.merge_part(|response| response.push(0)));
+        assert!(pending.merge_part(|response| response.push(1)));
+        assert_eq!(pending.into_response(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_pending_scrape_responses_shrink() {
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 60);
+
+        let mut pending_responses: PendingScrapeResponses<()> = Default::default();
+
+        let keys = (0..64)
+            .map(|_| pending_responses.insert(PendingScrapeResponse::new(1, ()), valid_until))
+            .collect::<Vec<_>>();
+
+        let capacity = pending_responses.slab.capacity();
+
+        // Capacity is kept while most of it is in use
+        for key in keys[32..].iter().rev() {
+            pending_responses.merge_part(*key, |_| ()).unwrap();
+
+            assert_eq!(pending_responses.slab.capacity(), capacity);
+        }
+
+        for key in keys[..32].iter().rev() {
+            pending_responses.merge_part(*key, |_| ()).unwrap();
+        }
+
+        assert!(pending_responses.slab.capacity() <= SHRINK_MIN_CAPACITY);
+    }
+
+    #[test]
+    fn test_ordered_scrape_statistics() {
+        let mut stats = OrderedScrapeStatistics::new(vec![3u8, 1, 2, 1, 4]);
+
+        // Partial responses arrive grouped by swarm worker
+        stats.extend([(1, "b"), (3, "a")]);
+        stats.extend([(2, "c"), (5, "not requested")]);
+
+        assert_eq!(
+            stats.into_ordered().collect::<Vec<_>>(),
+            vec![(3, "a"), (1, "b"), (2, "c")]
+        );
+    }
+}
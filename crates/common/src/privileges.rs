@@ -3,12 +3,16 @@ use std::{
     sync::{Arc, Barrier},
 };
 
+#[cfg(unix)]
 use anyhow::Context;
+#[cfg(unix)]
 use privdrop::PrivDrop;
 use serde::{Deserialize, Serialize};
 
 use aquatic_toml_config::TomlConfig;
 
+use crate::bind::BindNotifier;
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct PrivilegeConfig {
@@ -35,8 +39,10 @@ impl Default for PrivilegeConfig {
 
 #[derive(Clone)]
 pub struct PrivilegeDropper {
+    #[cfg_attr(not(unix), allow(dead_code))]
     barrier: Arc<Barrier>,
     config: Arc<PrivilegeConfig>,
+    bind_notifier: BindNotifier,
 }
 
 impl PrivilegeDropper {
@@ -44,9 +50,18 @@ impl PrivilegeDropper {
         Self {
             barrier: Arc::new(Barrier::new(num_sockets)),
             config: Arc::new(config),
+            bind_notifier: Default::default(),
         }
     }
 
+    /// Notify `bind_notifier` in `after_socket_creation`
+    pub fn with_bind_notifier(mut self, bind_notifier: BindNotifier) -> Self {
+        self.bind_notifier = bind_notifier;
+
+        self
+    }
+
+    #[cfg(unix)]
     pub fn after_socket_creation(self) -> anyhow::Result<()> {
         if self.config.drop_privileges && self.barrier.wait().is_leader() {
             PrivDrop::default()
@@ -57,6 +72,22 @@ impl PrivilegeDropper {
                 .with_context(|| "couldn't drop privileges after socket creation")?;
         }
 
+        self.bind_notifier.socket_bound();
+
         Ok(())
     }
+
+    /// Dropping privileges is only supported on unix-like platforms
+    #[cfg(not(unix))]
+    pub fn after_socket_creation(self) -> anyhow::Result<()> {
+        if self.config.drop_privileges {
+            Err(anyhow::anyhow!(
+                "dropping privileges is not supported on this platform"
+            ))
+        } else {
+            self.bind_notifier.socket_bound();
+
+            Ok(())
+        }
+    }
 }
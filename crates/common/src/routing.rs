@@ -0,0 +1,145 @@
+//! Routing of requests from socket workers to swarm workers
+//!
+//! Torrents are partitioned between swarm workers by the first byte of their
+//! info hash, so that all requests concerning a torrent are handled by the
+//! same worker.
+//!
+//! With the `glommio` or `flume` features, [WorkerSenders] provides channels
+//! from one worker to each worker of another kind, e.g., from a socket worker
+//! to all swarm workers.
+
+use std::collections::BTreeMap;
+#[cfg(any(feature = "glommio", feature = "flume"))]
+use std::rc::Rc;
+
+/// Index of swarm worker responsible for torrent with given info hash
+#[inline]
+pub fn swarm_worker_index(info_hash: &[u8; 20], num_swarm_workers: usize) -> usize {
+    (info_hash[0] as usize) % num_swarm_workers
+}
+
+/// Group items (e.g., info hashes of a scrape request) by responsible swarm
+/// worker, preserving item order within each group
+pub fn group_by_swarm_worker<T, F>(
+    items: impl IntoIterator<Item = T>,
+    num_swarm_workers: usize,
+    info_hash: F,
+) -> BTreeMap<usize, Vec<T>>
+where
+    F: Fn(&T) -> &[u8; 20],
+{
+    let mut groups: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+
+    for item in items {
+        let index = swarm_worker_index(info_hash(&item), num_swarm_workers);
+
+        groups.entry(index).or_default().push(item);
+    }
+
+    groups
+}
+
+/// Channels from one worker to each worker of another kind
+///
+/// All channels share the same backpressure policy: when the channel to a
+/// worker is full, sending waits until there is room, so messages are never
+/// dropped. Sending only fails when the receiving worker has stopped.
+#[cfg(any(feature = "glommio", feature = "flume"))]
+pub enum WorkerSenders<T: Send + 'static> {
+    #[cfg(feature = "glommio")]
+    Mesh(Rc<glommio::channels::channel_mesh::Senders<T>>),
+    /// Indexed by worker index
+    #[cfg(feature = "flume")]
+    Flume(Rc<[flume::Sender<T>]>),
+}
+
+#[cfg(any(feature = "glommio", feature = "flume"))]
+impl<T: Send + 'static> Clone for WorkerSenders<T> {
+    fn clone(&self) -> Self {
+        match self {
+            #[cfg(feature = "glommio")]
+            Self::Mesh(senders) => Self::Mesh(senders.clone()),
+            #[cfg(feature = "flume")]
+            Self::Flume(senders) => Self::Flume(senders.clone()),
+        }
+    }
+}
+
+#[cfg(any(feature = "glommio", feature = "flume"))]
+impl<T: Send + 'static> WorkerSenders<T> {
+    /// Join channel mesh as producer
+    #[cfg(feature = "glommio")]
+    pub async fn join_mesh(
+        mesh_builder: glommio::channels::channel_mesh::MeshBuilder<
+            T,
+            glommio::channels::channel_mesh::Partial,
+        >,
+        mesh_name: &str,
+    ) -> anyhow::Result<Self> {
+        let (senders, _) = mesh_builder
+            .join(glommio::channels::channel_mesh::Role::Producer)
+            .await
+            .map_err(|err| anyhow::anyhow!("join {} mesh: {:#}", mesh_name, err))?;
+
+        Ok(Self::Mesh(Rc::new(senders)))
+    }
+
+    /// Send message to worker with given index, waiting if its channel is
+    /// full
+    ///
+    /// Only fails when the receiving worker has stopped.
+    pub async fn send_to(&self, worker_index: usize, message: T) -> anyhow::Result<()> {
+        let result = match self {
+            #[cfg(feature = "glommio")]
+            Self::Mesh(senders) => senders.send_to(worker_index, message).await.map_err(|_| ()),
+            #[cfg(feature = "flume")]
+            Self::Flume(senders) => senders[worker_index]
+                .send_async(message)
+                .await
+                .map_err(|_| ()),
+        };
+
+        result.map_err(|_| anyhow::anyhow!("channel to worker {} is closed", worker_index))
+    }
+
+    /// Send one message per group, e.g., per group of scrape request info
+    /// hashes as returned by [group_by_swarm_worker]
+    ///
+    /// A pending response for the groups can be created with
+    /// [crate::pending_scrape::PendingScrapeResponse::for_groups].
+    pub async fn scatter<I, F>(
+        &self,
+        groups: BTreeMap<usize, Vec<I>>,
+        mut make_message: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Vec<I>) -> T,
+    {
+        for (worker_index, items) in groups {
+            self.send_to(worker_index, make_message(items)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_by_swarm_worker() {
+        let info_hashes = [[0u8; 20], [1; 20], [2; 20], [3; 20], [5; 20]];
+
+        let groups = group_by_swarm_worker(info_hashes, 2, |info_hash| info_hash);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&0], vec![[0; 20], [2; 20]]);
+        assert_eq!(groups[&1], vec![[1; 20], [3; 20], [5; 20]]);
+
+        let groups = group_by_swarm_worker(info_hashes, 1, |info_hash| info_hash);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[&0].len(), info_hashes.len());
+    }
+}
@@ -1,12 +1,23 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
 
 use anyhow::Context;
+use rustls::server::{NoServerSessionStorage, ServerSessionMemoryCache};
 
 pub type RustlsConfig = rustls::ServerConfig;
 
+/// TLS session resumption settings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionResumption {
+    /// Number of sessions to cache for stateful resumption (0 = disabled)
+    pub cache_size: usize,
+    /// Issue stateless session tickets
+    pub tickets: bool,
+}
+
 pub fn create_rustls_config(
     tls_certificate_path: &Path,
     tls_private_key_path: &Path,
+    session_resumption: SessionResumption,
 ) -> anyhow::Result<RustlsConfig> {
     let certs = {
         let f = File::open(tls_certificate_path).with_context(|| {
@@ -50,10 +61,24 @@ pub fn create_rustls_config(
         key
     };
 
-    let tls_config = rustls::ServerConfig::builder()
+    let mut tls_config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(certs, rustls::pki_types::PrivateKeyDer::Pkcs8(private_key))
         .with_context(|| "create rustls config")?;
 
+    if session_resumption.cache_size == 0 {
+        tls_config.session_storage = Arc::new(NoServerSessionStorage {});
+    } else {
+        tls_config.session_storage = ServerSessionMemoryCache::new(session_resumption.cache_size);
+    }
+
+    if session_resumption.tickets {
+        tls_config.ticketer = rustls::crypto::aws_lc_rs::Ticketer::new()
+            .with_context(|| "create tls session ticketer")?;
+    } else if session_resumption.cache_size == 0 {
+        // Tickets would be useless
+        tls_config.send_tls13_tickets = 0;
+    }
+
     Ok(tls_config)
 }
@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::WorkerType;
+
+/// How long to wait for workers to stop after shutdown has been triggered
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells worker threads to stop, e.g., when a tracker embedded in another
+/// application is shut down
+///
+/// Workers are expected to check it regularly. Waiting on it with
+/// [`ShutdownSignal::sleep`] returns early when it is triggered.
+#[derive(Clone, Default)]
+pub struct ShutdownSignal {
+    inner: Arc<ShutdownSignalInner>,
+}
+
+#[derive(Default)]
+struct ShutdownSignalInner {
+    triggered: AtomicBool,
+    mutex: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl ShutdownSignal {
+    pub fn trigger(&self) {
+        let _guard = self.inner.mutex.lock().unwrap();
+
+        self.inner.triggered.store(true, Ordering::Release);
+        self.inner.condvar.notify_all();
+    }
+
+    #[inline]
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::Acquire)
+    }
+
+    /// Sleep for `duration` or until shutdown is triggered. Returns true if
+    /// it has been triggered.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let guard = self.inner.mutex.lock().unwrap();
+
+        let _ = self
+            .inner
+            .condvar
+            .wait_timeout_while(guard, duration, |_| !self.is_triggered())
+            .unwrap();
+
+        self.is_triggered()
+    }
+}
+
+/// Wait for workers to stop after shutdown has been triggered
+///
+/// Returns the first error of any worker, or an error if not all of them
+/// stopped within `timeout`. Workers still running at that point are left
+/// detached.
+pub fn join_workers(
+    mut join_handles: Vec<(WorkerType, JoinHandle<anyhow::Result<()>>)>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let deadline = Instant::now() + timeout;
+
+    let mut result = Ok(());
+
+    while !join_handles.is_empty() {
+        if Instant::now() >= deadline {
+            let worker_types = join_handles
+                .iter()
+                .map(|(worker_type, _)| worker_type.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(anyhow::anyhow!(
+                "workers didn't stop within {} seconds: {}",
+                timeout.as_secs(),
+                worker_types
+            ));
+        }
+
+        let mut i = 0;
+
+        while i < join_handles.len() {
+            if !join_handles[i].1.is_finished() {
+                i += 1;
+
+                continue;
+            }
+
+            let (worker_type, handle) = join_handles.swap_remove(i);
+
+            let worker_result = match handle.join() {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(err)) => Err(err.context(format!("{} stopped", worker_type))),
+                Err(_) => Err(anyhow::anyhow!("{} panicked", worker_type)),
+            };
+
+            if result.is_ok() {
+                result = worker_result;
+            }
+        }
+
+        sleep(Duration::from_millis(10));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_shutdown_signal() {
+        let signal = ShutdownSignal::default();
+
+        assert!(!signal.is_triggered());
+        assert!(!signal.sleep(Duration::from_millis(1)));
+
+        let handle = thread::spawn({
+            let signal = signal.clone();
+
+            move || {
+                let start = Instant::now();
+                let triggered = signal.sleep(Duration::from_secs(60));
+
+                (triggered, start.elapsed())
+            }
+        });
+
+        thread::sleep(Duration::from_millis(10));
+
+        signal.trigger();
+
+        let (triggered, elapsed) = handle.join().unwrap();
+
+        assert!(triggered);
+        assert!(elapsed < Duration::from_secs(60));
+        assert!(signal.is_triggered());
+        assert!(signal.sleep(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_join_workers() {
+        let signal = ShutdownSignal::default();
+
+        let spawn_worker = |signal: ShutdownSignal| {
+            thread::spawn(move || {
+                while !signal.sleep(Duration::from_secs(60)) {}
+
+                Ok(())
+            })
+        };
+
+        let join_handles = vec![
+            (WorkerType::Swarm(0), spawn_worker(signal.clone())),
+            (WorkerType::Socket(0), spawn_worker(signal.clone())),
+        ];
+
+        signal.trigger();
+
+        assert!(join_workers(join_handles, Duration::from_secs(60)).is_ok());
+
+        // Worker that ignores shutdown
+        let join_handles = vec![(
+            WorkerType::Cleaning,
+            thread::spawn(|| {
+                thread::sleep(Duration::from_secs(60));
+
+                Ok(())
+            }),
+        )];
+
+        assert!(join_workers(join_handles, Duration::from_millis(50)).is_err());
+    }
+}
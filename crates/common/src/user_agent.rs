@@ -0,0 +1,239 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_metrics::{Counter, Labels, Registry};
+use aquatic_toml_config::TomlConfig;
+use hashbrown::HashMap;
+use regex::bytes::RegexSet;
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of product names used as statistics labels
+const MAX_PRODUCT_NAME_LEN: usize = 32;
+
+/// User-Agent header filtering and statistics configuration
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserAgentConfig {
+    /// Regular expressions matched against the User-Agent header. Requests
+    /// with a header matching any of them are rejected. Requests without
+    /// the header are never rejected.
+    ///
+    /// Example: ["^Transmission/2\\.", "(?i)bad-client"]
+    pub deny: Vec<String>,
+    /// Count requests per user agent in statistics
+    ///
+    /// Requests are labelled with the product name, i.e., the part of the
+    /// header before the first slash or space, e.g., "qBittorrent" for
+    /// "qBittorrent/4.6.2". Requests without the header are labelled
+    /// "none".
+    pub statistics: bool,
+    /// Maximum number of distinct product names counted per worker. Once
+    /// it is reached, requests from further user agents are labelled
+    /// "other", which protects against unbounded statistics growth.
+    pub max_statistics_agents: usize,
+}
+
+impl Default for UserAgentConfig {
+    fn default() -> Self {
+        Self {
+            deny: Vec::new(),
+            statistics: false,
+            max_statistics_agents: 32,
+        }
+    }
+}
+
+/// Compiled deny rules
+#[derive(Clone, Debug)]
+pub struct UserAgentFilter {
+    deny: RegexSet,
+}
+
+impl UserAgentFilter {
+    pub fn new(config: &UserAgentConfig) -> anyhow::Result<Self> {
+        let deny = RegexSet::new(&config.deny).with_context(|| "compile user_agent.deny")?;
+
+        Ok(Self { deny })
+    }
+
+    pub fn is_denied(&self, user_agent: &[u8]) -> bool {
+        !self.deny.is_empty() && self.deny.is_match(user_agent)
+    }
+}
+
+/// Per-user-agent request counters of one worker
+///
+/// Counters are registered in the worker's registry on first use.
+pub struct UserAgentStatistics {
+    enabled: bool,
+    max_agents: usize,
+    metric_name: &'static str,
+    registry: Registry,
+    counters: RefCell<HashMap<String, Arc<Counter>>>,
+    none: Arc<Counter>,
+    other: Arc<Counter>,
+}
+
+impl UserAgentStatistics {
+    pub fn new(config: &UserAgentConfig, registry: &Registry, metric_name: &'static str) -> Self {
+        let with_user_agent = |a: &str| Labels::new().with("user_agent", a);
+
+        // Don't register any counters when disabled, so that they aren't
+        // reported
+        let (none, other) = if config.statistics {
+            (
+                registry.counter(metric_name, with_user_agent("none")),
+                registry.counter(metric_name, with_user_agent("other")),
+            )
+        } else {
+            Default::default()
+        };
+
+        Self {
+            enabled: config.statistics,
+            max_agents: config.max_statistics_agents,
+            metric_name,
+            registry: registry.clone(),
+            counters: Default::default(),
+            none,
+            other,
+        }
+    }
+
+    pub fn record(&self, opt_user_agent: Option<&[u8]>) {
+        if !self.enabled {
+            return;
+        }
+
+        let product_name = match opt_user_agent {
+            Some(user_agent) => product_name(user_agent),
+            None => {
+                self.none.increment(1);
+
+                return;
+            }
+        };
+
+        let mut counters = self.counters.borrow_mut();
+
+        if let Some(counter) = counters.get(product_name.as_str()) {
+            counter.increment(1);
+        } else if counters.len() < self.max_agents {
+            let counter = self.registry.counter(
+                self.metric_name,
+                Labels::new().with("user_agent", &product_name),
+            );
+
+            counter.increment(1);
+
+            counters.insert(product_name, counter);
+        } else {
+            self.other.increment(1);
+        }
+    }
+}
+
+/// Extract product name from User-Agent header, keeping only characters
+/// safe to use in statistics labels
+pub fn product_name(user_agent: &[u8]) -> String {
+    let name: String = user_agent
+        .iter()
+        .take_while(|b| !matches!(b, b'/' | b' ' | b'('))
+        .filter(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+        .take(MAX_PRODUCT_NAME_LEN)
+        .map(|b| *b as char)
+        .collect();
+
+    if name.is_empty() {
+        "unknown".into()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_product_name() {
+        assert_eq!(product_name(b"qBittorrent/4.6.2"), "qBittorrent");
+        assert_eq!(product_name(b"Transmission 4.0"), "Transmission");
+        assert_eq!(product_name(b"Mozilla/5.0 (X11; Linux x86_64)"), "Mozilla");
+        assert_eq!(product_name(b"a\"b\n}c/1"), "abc");
+        assert_eq!(product_name(b"/1.0"), "unknown");
+        assert_eq!(product_name(&[b'a'; 100]).len(), MAX_PRODUCT_NAME_LEN);
+    }
+
+    #[test]
+    fn test_user_agent_filter() {
+        let config = UserAgentConfig {
+            deny: vec!["^Transmission/2\\.".into(), "(?i)bad".into()],
+            ..Default::default()
+        };
+        let filter = UserAgentFilter::new(&config).unwrap();
+
+        assert!(filter.is_denied(b"Transmission/2.94"));
+        assert!(filter.is_denied(b"BAD-client/1.0"));
+        assert!(!filter.is_denied(b"Transmission/4.0.5"));
+        assert!(!UserAgentFilter::new(&Default::default())
+            .unwrap()
+            .is_denied(b"Transmission/2.94"));
+
+        let config = UserAgentConfig {
+            deny: vec!["(".into()],
+            ..Default::default()
+        };
+
+        assert!(UserAgentFilter::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_user_agent_statistics() {
+        const NAME: &str = "requests_by_user_agent";
+
+        let count = |registry: &Registry, user_agent: &str| {
+            registry
+                .collect()
+                .into_iter()
+                .find(|sample| {
+                    sample
+                        .labels
+                        .iter()
+                        .any(|(k, v)| k == "user_agent" && v == user_agent)
+                })
+                .map(|sample| sample.value)
+        };
+
+        let registry = Registry::default();
+        let statistics = UserAgentStatistics::new(&Default::default(), &registry, NAME);
+
+        statistics.record(Some(b"qBittorrent/4.6.2"));
+
+        assert!(registry.collect().is_empty());
+
+        let config = UserAgentConfig {
+            statistics: true,
+            max_statistics_agents: 2,
+            ..Default::default()
+        };
+        let registry = Registry::default();
+        let statistics = UserAgentStatistics::new(&config, &registry, NAME);
+
+        statistics.record(Some(b"qBittorrent/4.6.2"));
+        statistics.record(Some(b"qBittorrent/4.5.0"));
+        statistics.record(Some(b"Transmission/4.0.5"));
+        statistics.record(Some(b"Deluge/2.1.1"));
+        statistics.record(Some(b"Deluge/2.1.1"));
+        statistics.record(None);
+
+        let counter = |n| Some(aquatic_metrics::SampleValue::Counter(n));
+
+        assert_eq!(count(&registry, "qBittorrent"), counter(2));
+        assert_eq!(count(&registry, "Transmission"), counter(1));
+        assert_eq!(count(&registry, "Deluge"), None);
+        assert_eq!(count(&registry, "other"), counter(2));
+        assert_eq!(count(&registry, "none"), counter(1));
+    }
+}
@@ -1,7 +1,14 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use aquatic_common::access_list::AccessListArcSwap;
+use anyhow::Context;
+use aquatic_common::access_list::{update_access_list, AccessListArcSwap};
+use aquatic_common::connection_table::ConnectionTables;
+use aquatic_common::observer::{AnnounceSubscribers, SwarmSummaries};
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::user_agent::{UserAgentFilter, UserAgentStatistics};
 use aquatic_common::CanonicalSocketAddr;
+use aquatic_metrics::{names, Counter, Gauge, HyperLogLog, Labels, Registry};
 
 pub use aquatic_common::ValidUntil;
 
@@ -12,6 +19,8 @@ use aquatic_http_protocol::{
 use glommio::channels::shared_channel::SharedSender;
 use slotmap::new_key_type;
 
+use crate::config::Config;
+
 #[derive(Copy, Clone, Debug)]
 pub struct ConsumerId(pub usize);
 
@@ -24,16 +33,329 @@ pub enum ChannelRequest {
     Announce {
         request: AnnounceRequest,
         peer_addr: CanonicalSocketAddr,
-        response_sender: SharedSender<AnnounceResponse>,
+        tracker: TrackerIndex,
+        /// When socket worker received request
+        received_at: Instant,
+        response_sender: ResponseSender<AnnounceResponse>,
     },
     Scrape {
         request: ScrapeRequest,
         peer_addr: CanonicalSocketAddr,
-        response_sender: SharedSender<ScrapeResponse>,
+        tracker: TrackerIndex,
+        /// When socket worker received request
+        received_at: Instant,
+        response_sender: ResponseSender<ScrapeResponse>,
     },
 }
 
-#[derive(Default, Clone)]
+impl ChannelRequest {
+    /// Returns true if request was received longer than `max_age` ago
+    pub fn expired(&self, now: Instant, max_age: Duration) -> bool {
+        let received_at = match self {
+            Self::Announce { received_at, .. } | Self::Scrape { received_at, .. } => *received_at,
+        };
+
+        now.saturating_duration_since(received_at) > max_age
+    }
+}
+
+/// Channel for sending swarm worker response back to socket worker
+#[derive(Debug)]
+pub enum ResponseSender<T: Send> {
+    Glommio(SharedSender<T>),
+    #[cfg(feature = "tokio")]
+    Oneshot(futures::channel::oneshot::Sender<T>),
+}
+
+impl<T: Send + ::std::fmt::Debug + 'static> ResponseSender<T> {
+    pub async fn send(self, response: T) -> anyhow::Result<()> {
+        match self {
+            Self::Glommio(sender) => sender
+                .connect()
+                .await
+                .send(response)
+                .await
+                .map_err(|err| anyhow::anyhow!("{:#}", err)),
+            #[cfg(feature = "tokio")]
+            Self::Oneshot(sender) => sender
+                .send(response)
+                .map_err(|_| anyhow::anyhow!("receiver closed")),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct State {
-    pub access_list: Arc<AccessListArcSwap>,
+    /// Access lists indexed by tracker index
+    pub access_lists: Arc<[Arc<AccessListArcSwap>]>,
+    /// Latest connection table snapshots of socket workers, for diagnostics
+    pub connection_tables: Arc<ConnectionTables>,
+    pub static_pages: Arc<StaticPages>,
+    pub user_agent_filter: Arc<UserAgentFilter>,
+    /// Updated by swarm workers after cleaning torrents
+    pub swarm_summaries: Arc<SwarmSummaries>,
+    pub announce_subscribers: AnnounceSubscribers,
+    pub shutdown: ShutdownSignal,
+}
+
+impl State {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            access_lists: (0..=config.virtual_trackers.len())
+                .map(|_| Default::default())
+                .collect(),
+            connection_tables: Arc::new(ConnectionTables::new(config.socket_workers)),
+            static_pages: Arc::new(StaticPages::new(config)?),
+            user_agent_filter: Arc::new(UserAgentFilter::new(&config.user_agent)?),
+            swarm_summaries: Arc::new(SwarmSummaries::new(config.swarm_workers)),
+            announce_subscribers: Default::default(),
+            shutdown: Default::default(),
+        })
+    }
+
+    /// Update access lists of all trackers, returning last error if any
+    /// update failed
+    pub fn update_access_lists(&self, config: &Config) -> anyhow::Result<()> {
+        let mut result = Ok(());
+
+        for (i, access_list) in self.access_lists.iter().enumerate() {
+            if let Err(err) =
+                update_access_list(config.access_list_config(TrackerIndex(i)), access_list)
+            {
+                result = Err(err);
+            }
+        }
+
+        result
+    }
+}
+
+/// Tracker that request was routed to
+///
+/// 0 is the default tracker, followed by virtual trackers in configuration
+/// order.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TrackerIndex(pub usize);
+
+impl TrackerIndex {
+    pub fn from_virtual_tracker_index(index: usize) -> Self {
+        Self(index + 1)
+    }
+
+    pub fn virtual_tracker_index(self) -> Option<usize> {
+        self.0.checked_sub(1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaticPage {
+    Landing,
+    RobotsTxt,
+}
+
+/// Response that doesn't depend on request contents and can be sent without
+/// involving swarm workers. Connections are kept alive afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaticResponse {
+    /// Static page, or only its headers for HEAD requests
+    Page { page: StaticPage, head: bool },
+    /// Headers only, for HEAD requests to announce and scrape paths. Since
+    /// announce and scrape requests are not handled, content length is
+    /// omitted.
+    TrackerHead,
+    /// Response to OPTIONS request, listing allowed methods
+    Options,
+}
+
+/// Full HTTP response and length of its headers
+struct PageResponse {
+    bytes: Vec<u8>,
+    headers_len: usize,
+}
+
+/// Responses for static pages, created on start
+pub struct StaticPages {
+    landing: Option<PageResponse>,
+    robots_txt: Option<PageResponse>,
+}
+
+impl StaticPages {
+    const ROBOTS_TXT: &'static [u8] = b"User-agent: *\nDisallow: /\n";
+    const TRACKER_HEAD_RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\n\r\n";
+    const OPTIONS_RESPONSE: &'static [u8] =
+        b"HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, OPTIONS\r\n\r\n";
+
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let path = &config.static_pages.landing_page_path;
+
+        let landing = if path.as_os_str().is_empty() {
+            None
+        } else {
+            let body = ::std::fs::read(path)
+                .with_context(|| format!("read landing page {}", path.display()))?;
+
+            Some(PageResponse::new("text/html; charset=utf-8", &body))
+        };
+
+        let robots_txt = config
+            .static_pages
+            .robots_txt
+            .then(|| PageResponse::new("text/plain; charset=utf-8", Self::ROBOTS_TXT));
+
+        Ok(Self {
+            landing,
+            robots_txt,
+        })
+    }
+
+    /// Find static page served on path, if any
+    pub fn route(&self, path: &str) -> Option<StaticPage> {
+        let location = path.split_once('?').map_or(path, |(location, _)| location);
+
+        match location {
+            "/" if self.landing.is_some() => Some(StaticPage::Landing),
+            "/robots.txt" if self.robots_txt.is_some() => Some(StaticPage::RobotsTxt),
+            _ => None,
+        }
+    }
+
+    pub fn response_bytes(&self, response: StaticResponse) -> &[u8] {
+        match response {
+            StaticResponse::Page { page, head } => {
+                let opt_response = match page {
+                    StaticPage::Landing => &self.landing,
+                    StaticPage::RobotsTxt => &self.robots_txt,
+                };
+                let response = opt_response
+                    .as_ref()
+                    .expect("static page must be enabled if routed to");
+
+                if head {
+                    &response.bytes[..response.headers_len]
+                } else {
+                    &response.bytes
+                }
+            }
+            StaticResponse::TrackerHead => Self::TRACKER_HEAD_RESPONSE,
+            StaticResponse::Options => Self::OPTIONS_RESPONSE,
+        }
+    }
+}
+
+impl PageResponse {
+    fn new(content_type: &str, body: &[u8]) -> Self {
+        let mut bytes = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            content_type,
+            body.len()
+        )
+        .into_bytes();
+
+        let headers_len = bytes.len();
+
+        bytes.extend_from_slice(body);
+
+        Self { bytes, headers_len }
+    }
+}
+
+/// Statistics of one socket worker
+///
+/// Counters are registered in the worker's registry, from which they are
+/// collected by the statistics reporter. Estimates of distinct values are
+/// registered in a registry shared by all socket workers.
+pub struct SocketWorkerStatistics {
+    pub requests_announce: Arc<Counter>,
+    pub requests_scrape: Arc<Counter>,
+    pub responses_announce: Arc<Counter>,
+    pub responses_scrape: Arc<Counter>,
+    pub responses_error: Arc<Counter>,
+    /// Static pages and responses to HEAD and OPTIONS requests
+    pub responses_static: Arc<Counter>,
+    pub request_parse_failures: Arc<Counter>,
+    pub requests_too_large: Arc<Counter>,
+    /// Requests for paths other than announce, scrape and static page paths
+    pub requests_not_found: Arc<Counter>,
+    pub tls_handshake_failures: Arc<Counter>,
+    pub bytes_received: Arc<Counter>,
+    pub bytes_sent: Arc<Counter>,
+    pub active_connections: Arc<Gauge>,
+    /// Requests that were the first on their connection
+    pub requests_fresh_connection: Arc<Counter>,
+    /// Requests on kept-alive connections
+    pub requests_reused_connection: Arc<Counter>,
+    pub connections_closed_max_requests: Arc<Counter>,
+    pub connections_closed_max_lifetime: Arc<Counter>,
+    /// Announce and scrape requests rejected due to `user_agent.deny`
+    pub requests_user_agent_denied: Arc<Counter>,
+    /// Peer addresses of announce and scrape requests (IPv6: prefixes of
+    /// `network.ipv6_prefix_len` bits)
+    pub unique_ips: Arc<HyperLogLog>,
+    pub user_agents: UserAgentStatistics,
+}
+
+impl SocketWorkerStatistics {
+    pub fn new(config: &Config, registry: &Registry, shared_registry: &Registry) -> Self {
+        let with_type = |t: &str| Labels::new().with("type", t);
+        let with_connection = |c: &str| Labels::new().with("connection", c);
+        let with_reason = |r: &str| Labels::new().with("reason", r);
+
+        Self {
+            requests_announce: registry.counter(names::REQUESTS_TOTAL, with_type("announce")),
+            requests_scrape: registry.counter(names::REQUESTS_TOTAL, with_type("scrape")),
+            responses_announce: registry.counter(names::RESPONSES_TOTAL, with_type("announce")),
+            responses_scrape: registry.counter(names::RESPONSES_TOTAL, with_type("scrape")),
+            responses_error: registry.counter(names::RESPONSES_TOTAL, with_type("error")),
+            responses_static: registry.counter(names::RESPONSES_TOTAL, with_type("static")),
+            request_parse_failures: registry
+                .counter(names::REQUEST_PARSE_FAILURES_TOTAL, Labels::new()),
+            requests_too_large: registry.counter(names::REQUESTS_TOO_LARGE_TOTAL, Labels::new()),
+            requests_not_found: registry.counter(names::REQUESTS_NOT_FOUND_TOTAL, Labels::new()),
+            tls_handshake_failures: registry
+                .counter(names::TLS_HANDSHAKE_FAILURES_TOTAL, Labels::new()),
+            bytes_received: registry.counter(names::RX_BYTES, Labels::new()),
+            bytes_sent: registry.counter(names::TX_BYTES, Labels::new()),
+            active_connections: registry.gauge(names::ACTIVE_CONNECTIONS, Labels::new()),
+            requests_fresh_connection: registry
+                .counter(names::CONNECTION_REQUESTS_TOTAL, with_connection("fresh")),
+            requests_reused_connection: registry
+                .counter(names::CONNECTION_REQUESTS_TOTAL, with_connection("reused")),
+            connections_closed_max_requests: registry
+                .counter(names::CONNECTIONS_CLOSED_TOTAL, with_reason("max_requests")),
+            connections_closed_max_lifetime: registry
+                .counter(names::CONNECTIONS_CLOSED_TOTAL, with_reason("max_lifetime")),
+            requests_user_agent_denied: registry
+                .counter(names::REQUESTS_USER_AGENT_DENIED_TOTAL, Labels::new()),
+            unique_ips: shared_registry.hyperloglog(names::UNIQUE_IPS, Labels::new()),
+            user_agents: UserAgentStatistics::new(
+                &config.user_agent,
+                registry,
+                names::REQUESTS_BY_USER_AGENT_TOTAL,
+            ),
+        }
+    }
+}
+
+/// Statistics of one swarm worker
+pub struct SwarmWorkerStatistics {
+    pub queued_announce_requests: Arc<Gauge>,
+    pub queued_scrape_requests: Arc<Gauge>,
+    /// Requests dropped because they exceeded `scheduling.request_deadline_ms`
+    pub expired_announce_requests: Arc<Counter>,
+    pub expired_scrape_requests: Arc<Counter>,
+}
+
+impl SwarmWorkerStatistics {
+    pub fn new(registry: &Registry) -> Self {
+        let with_type = |t: &str| Labels::new().with("type", t);
+
+        Self {
+            queued_announce_requests: registry.gauge(names::QUEUED_REQUESTS, with_type("announce")),
+            queued_scrape_requests: registry.gauge(names::QUEUED_REQUESTS, with_type("scrape")),
+            expired_announce_requests: registry
+                .counter(names::REQUESTS_EXPIRED_TOTAL, with_type("announce")),
+            expired_scrape_requests: registry
+                .counter(names::REQUESTS_EXPIRED_TOTAL, with_type("scrape")),
+        }
+    }
 }
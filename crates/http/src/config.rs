@@ -1,7 +1,12 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, privileges::PrivilegeConfig, rustls_config::SessionResumption,
+    user_agent::UserAgentConfig, PortZeroPolicy,
+};
 use aquatic_toml_config::TomlConfig;
+
+use crate::common::TrackerIndex;
 use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
@@ -13,6 +18,39 @@ pub enum ReverseProxyPeerIpHeaderFormat {
     LastAddress,
 }
 
+/// How to handle announce request parameters that are sent more than once
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TomlConfig, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateParameterPolicy {
+    /// Treat request as invalid
+    #[default]
+    Reject,
+    /// Use first value
+    First,
+    /// Use last value
+    Last,
+}
+
+impl From<DuplicateParameterPolicy> for aquatic_http_protocol::common::DuplicateParameterPolicy {
+    fn from(policy: DuplicateParameterPolicy) -> Self {
+        match policy {
+            DuplicateParameterPolicy::Reject => Self::Reject,
+            DuplicateParameterPolicy::First => Self::First,
+            DuplicateParameterPolicy::Last => Self::Last,
+        }
+    }
+}
+
+/// Async runtime used by socket workers
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TomlConfig, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketWorkerRuntime {
+    #[default]
+    Glommio,
+    Tokio,
+}
+
 /// aquatic_http configuration
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -28,10 +66,29 @@ pub struct Config {
     /// Swarm workers receive a number of requests from socket workers,
     /// generate responses and send them back to the socket workers.
     pub swarm_workers: usize,
+    /// Run socket workers on glommio or tokio
+    ///
+    /// Swarm workers always run on glommio. Tokio doesn't depend on io_uring,
+    /// so it works with older kernels and in restricted environments where
+    /// glommio socket workers fail to start, at some performance cost.
+    #[cfg(feature = "tokio")]
+    pub socket_worker_runtime: SocketWorkerRuntime,
     pub log_level: LogLevel,
+    /// Virtual trackers sharing socket and swarm workers
+    ///
+    /// Requests are routed to the first virtual tracker with matching host
+    /// and path prefix, e.g., `/staging/announce` for path prefix `/staging`.
+    /// Other requests are handled by the default tracker, which uses the
+    /// top-level access list.
+    ///
+    /// Example:
+    /// virtual_trackers = [{ name = "staging", host = "", path_prefix = "/staging", access_list = { mode = "allow", path = "./staging-access-list.txt" } }]
+    pub virtual_trackers: Vec<VirtualTrackerConfig>,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
+    pub static_pages: StaticPagesConfig,
     pub cleaning: CleaningConfig,
+    pub scheduling: SchedulingConfig,
     pub privileges: PrivilegeConfig,
     /// Access list configuration
     ///
@@ -40,6 +97,12 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// User-Agent header filtering and per-agent request statistics. Only
+    /// applies to announce and scrape requests.
+    pub user_agent: UserAgentConfig,
+    pub statistics: StatisticsConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub maintenance: MaintenanceConfig,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
 }
@@ -49,31 +112,137 @@ impl Default for Config {
         Self {
             socket_workers: 1,
             swarm_workers: 1,
+            #[cfg(feature = "tokio")]
+            socket_worker_runtime: SocketWorkerRuntime::default(),
             log_level: LogLevel::default(),
+            virtual_trackers: Vec::new(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
+            static_pages: StaticPagesConfig::default(),
             cleaning: CleaningConfig::default(),
+            scheduling: SchedulingConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            user_agent: UserAgentConfig::default(),
+            statistics: StatisticsConfig::default(),
+            diagnostics: DiagnosticsConfig::default(),
+            maintenance: MaintenanceConfig::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
         }
     }
 }
 
+impl Config {
+    pub fn access_list_config(&self, tracker: TrackerIndex) -> &AccessListConfig {
+        match tracker.virtual_tracker_index() {
+            Some(i) => &self.virtual_trackers[i].access_list,
+            None => &self.access_list,
+        }
+    }
+
+    /// Name used in metrics
+    pub fn tracker_name(&self, tracker: TrackerIndex) -> &str {
+        match tracker.virtual_tracker_index() {
+            Some(i) => &self.virtual_trackers[i].name,
+            None => "default",
+        }
+    }
+
+    /// Find tracker to route request to and strip its path prefix from path
+    pub fn route_request<'a>(&self, host: Option<&str>, path: &'a str) -> (TrackerIndex, &'a str) {
+        // Ignore port, taking care not to strip part of IPv6 address
+        let host = host.map(|host| match host.rfind(':') {
+            Some(i) if !host[i..].contains(']') => &host[..i],
+            _ => host,
+        });
+
+        for (i, tracker) in self.virtual_trackers.iter().enumerate() {
+            if !tracker.host.is_empty()
+                && !host
+                    .map(|host| host.eq_ignore_ascii_case(&tracker.host))
+                    .unwrap_or(false)
+            {
+                continue;
+            }
+
+            let prefix = tracker.path_prefix.trim_end_matches('/');
+
+            if let Some(stripped_path) = path.strip_prefix(prefix) {
+                if stripped_path.starts_with('/') {
+                    return (TrackerIndex::from_virtual_tracker_index(i), stripped_path);
+                }
+            }
+        }
+
+        (TrackerIndex::default(), path)
+    }
+}
+
 impl aquatic_common::cli::Config for Config {
     fn get_log_level(&self) -> Option<LogLevel> {
         Some(self.log_level)
     }
+
+    fn enabled_features() -> Vec<&'static str> {
+        let mut features = Vec::new();
+
+        if cfg!(feature = "metrics") {
+            features.push("metrics");
+        }
+        if cfg!(feature = "prometheus") {
+            features.push("prometheus");
+        }
+        if cfg!(feature = "mimalloc") {
+            features.push("mimalloc");
+        }
+        if cfg!(feature = "tokio") {
+            features.push("tokio");
+        }
+
+        features
+    }
+
+    fn handles_termination_signals() -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtualTrackerConfig {
+    /// Name used in metrics
+    pub name: String,
+    /// Only route requests with this Host header value to tracker (port is
+    /// ignored). Empty = any host
+    pub host: String,
+    /// Only route requests with paths starting with this prefix to tracker
+    pub path_prefix: String,
+    pub access_list: AccessListConfig,
 }
 
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
     /// Bind to this address
+    ///
+    /// With port 0, the operating system chooses a free port, which is
+    /// logged on startup
     pub address: SocketAddr,
     /// Only allow access over IPv6
     pub only_ipv6: bool,
+    /// Bind sockets to this network interface (SO_BINDTODEVICE), e.g.,
+    /// "eth1", so that only traffic over it is handled, regardless of
+    /// routing. Linux only. Requires CAP_NET_RAW on kernels older than 5.7.
+    ///
+    /// Leave empty to not bind to an interface.
+    pub bind_device: String,
+    /// Length of prefixes that IPv6 addresses are aggregated by in per-IP
+    /// accounting, i.e., when counting connections per source IP in the
+    /// connection table endpoint
+    ///
+    /// Hosts can often pick new addresses in a whole /64 network at will.
+    pub ipv6_prefix_len: u8,
     /// Maximum number of pending TCP connections
     pub tcp_backlog: i32,
     /// Enable TLS
@@ -88,8 +257,40 @@ pub struct NetworkConfig {
     pub tls_certificate_path: PathBuf,
     /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
     pub tls_private_key_path: PathBuf,
+    /// Number of TLS sessions to cache for resumption (0 = disable cache)
+    ///
+    /// Resuming sessions lets returning clients skip the expensive part of
+    /// the handshake. The cache is cleared when TLS files are reloaded.
+    pub tls_session_cache_size: usize,
+    /// Issue stateless TLS session tickets, so that clients can resume
+    /// sessions without taking up cache entries
+    ///
+    /// Ticket keys are rotated periodically and regenerated when TLS files
+    /// are reloaded. TLS 1.3 early data (0-RTT) is not accepted either way.
+    pub tls_session_tickets: bool,
     /// Keep connections alive after sending a response
     pub keep_alive: bool,
+    /// Close kept-alive connections after this many requests (0 = no limit)
+    pub max_requests_per_connection: usize,
+    /// Close kept-alive connections after a response once they have been
+    /// open for this long (seconds, 0 = no limit)
+    pub max_connection_lifetime: u32,
+    /// Maximum size of a request, including headers (bytes)
+    ///
+    /// Requests exceeding this size are answered with a
+    /// `413 Payload Too Large` failure response, after which the connection
+    /// is closed. Request buffers start out small and only grow up to this
+    /// size when needed. The default is enough for scrape requests with the
+    /// default maximum number of info hashes.
+    pub max_request_size: usize,
+    /// Maximum size of a response, including headers (bytes)
+    ///
+    /// Response buffers start out small and only grow up to this size when
+    /// needed. Connections are closed instead of sending larger responses.
+    /// Make sure it is large enough for announce responses with
+    /// `protocol.max_peers` peers and scrape responses with
+    /// `protocol.max_scrape_torrents` info hashes.
+    pub max_response_size: usize,
     /// Does tracker run behind reverse proxy?
     ///
     /// MUST be set to false if not running behind reverse proxy.
@@ -112,6 +313,15 @@ pub struct NetworkConfig {
     pub reverse_proxy_ip_header_format: ReverseProxyPeerIpHeaderFormat,
 }
 
+impl NetworkConfig {
+    pub fn tls_session_resumption(&self) -> SessionResumption {
+        SessionResumption {
+            cache_size: self.tls_session_cache_size,
+            tickets: self.tls_session_tickets,
+        }
+    }
+}
+
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
@@ -119,9 +329,17 @@ impl Default for NetworkConfig {
             enable_tls: false,
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_session_cache_size: 256,
+            tls_session_tickets: false,
             only_ipv6: false,
+            bind_device: String::new(),
+            ipv6_prefix_len: 64,
             tcp_backlog: 1024,
             keep_alive: true,
+            max_requests_per_connection: 0,
+            max_connection_lifetime: 0,
+            max_request_size: 8192,
+            max_response_size: 65536,
             runs_behind_reverse_proxy: false,
             reverse_proxy_ip_header_name: "X-Forwarded-For".into(),
             reverse_proxy_ip_header_format: Default::default(),
@@ -132,20 +350,81 @@ impl Default for NetworkConfig {
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ProtocolConfig {
+    /// URL path of announce endpoint
+    ///
+    /// Requests to paths other than the announce and scrape paths (and
+    /// static pages, see `static_pages`) are answered with an empty
+    /// `404 Not Found` response, after which the connection is closed.
+    /// Setting hard-to-guess paths reduces noise from scanners. For virtual
+    /// trackers, the path prefix comes before this path.
+    pub announce_path: String,
+    /// URL path of scrape endpoint
+    pub scrape_path: String,
     /// Maximum number of torrents to accept in scrape request
+    ///
+    /// Requests with more torrents get an error response.
     pub max_scrape_torrents: usize,
     /// Maximum number of requested peers to accept in announce request
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// How to handle announce requests with port 0
+    pub port_zero_policy: PortZeroPolicy,
+    /// How to handle announce requests with parameters such as `info_hash`,
+    /// `peer_id` or `port` sent more than once: reject, first or last
+    ///
+    /// Rejecting removes ambiguity that could otherwise be exploited when
+    /// other software in front of the tracker picks a different value.
+    /// Multiple `info_hash` parameters in scrape requests are always allowed.
+    pub duplicate_parameter_policy: DuplicateParameterPolicy,
+    /// Include non-standard `ipv4` and `ipv6` dictionaries with per-IP-version
+    /// seeder and leecher counts in each scrape response entry
+    ///
+    /// Useful for debugging swarms that are only healthy for one IP version.
+    pub scrape_ip_version_breakdown: bool,
+    /// Return combined IPv4 and IPv6 seeder and leecher counts in scrape
+    /// responses instead of only counts for the IP version of the client
+    pub scrape_merge_ip_versions: bool,
 }
 
 impl Default for ProtocolConfig {
     fn default() -> Self {
         Self {
+            announce_path: "/announce".into(),
+            scrape_path: "/scrape".into(),
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            port_zero_policy: PortZeroPolicy::Reject,
+            duplicate_parameter_policy: DuplicateParameterPolicy::default(),
+            scrape_ip_version_breakdown: false,
+            scrape_merge_ip_versions: false,
+        }
+    }
+}
+
+/// Responses to GET requests for paths other than announce and scrape paths
+///
+/// HEAD requests are answered with the headers of the page. Requests with
+/// methods other than GET, HEAD and OPTIONS are answered with
+/// `405 Method Not Allowed`.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticPagesConfig {
+    /// Serve contents of this HTML file on `/`. Leave empty to answer with
+    /// `404 Not Found` instead
+    ///
+    /// The file is read on start.
+    pub landing_page_path: PathBuf,
+    /// Serve `/robots.txt` asking crawlers not to visit any paths
+    pub robots_txt: bool,
+}
+
+impl Default for StaticPagesConfig {
+    fn default() -> Self {
+        Self {
+            landing_page_path: PathBuf::new(),
+            robots_txt: true,
         }
     }
 }
@@ -159,6 +438,10 @@ pub struct CleaningConfig {
     pub connection_cleaning_interval: u64,
     /// Remove peers that have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Remove seeders that have not announced for this long (seconds)
+    /// instead, since they often announce less frequently. 0 = use
+    /// `max_peer_age` for seeders too.
+    pub max_seeder_age: u32,
     /// Remove connections that haven't seen valid requests for this long (seconds)
     pub max_connection_idle: u32,
 }
@@ -169,11 +452,144 @@ impl Default for CleaningConfig {
             torrent_cleaning_interval: 30,
             connection_cleaning_interval: 60,
             max_peer_age: 1800,
+            max_seeder_age: 0,
             max_connection_idle: 180,
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulingConfig {
+    /// Number of announce requests swarm workers handle before turning to
+    /// scrape requests, if any are queued
+    ///
+    /// Together with `scrape_batch_size`, this sets the ratio in which
+    /// announce and scrape requests are handled when both kinds are queued,
+    /// so that bursts of scrape requests can't starve announce handling (or
+    /// the other way around). Values below 1 are treated as 1.
+    pub announce_batch_size: usize,
+    /// Number of scrape requests swarm workers handle before turning to
+    /// announce requests, if any are queued
+    pub scrape_batch_size: usize,
+    /// Maximum number of requests each swarm worker takes off channels from
+    /// a socket worker and queues locally
+    pub max_queued_requests: usize,
+    /// Drop requests that were received by socket workers longer than this
+    /// many milliseconds before swarm workers get to them (0 = never drop)
+    ///
+    /// During overload, clients have likely given up on such requests and
+    /// retried, so handling them would only make queues build up further.
+    /// The connection of a dropped request is closed.
+    pub request_deadline_ms: u64,
+}
+
+impl Default for SchedulingConfig {
+    fn default() -> Self {
+        Self {
+            announce_batch_size: 8,
+            scrape_batch_size: 2,
+            max_queued_requests: 64,
+            request_deadline_ms: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Collect and print/write statistics this often (seconds)
+    ///
+    /// Reported counts are totals since the tracker was started. Request and
+    /// response counts are also exported over the prometheus endpoint if it
+    /// is enabled. Queued request counts of swarm workers are reported as
+    /// of collection time.
+    pub interval: u64,
+    /// Print statistics to standard output
+    pub print_to_stdout: bool,
+    /// Append statistics as a JSON object per line to a file
+    pub write_json_to_file: bool,
+    /// Path to append JSON lines to
+    pub json_file_path: PathBuf,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        self.interval != 0 && (self.print_to_stdout || self.write_json_to_file)
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            print_to_stdout: false,
+            write_json_to_file: false,
+            json_file_path: "tmp/statistics.jsonl".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    /// Serve summaries of socket worker connection tables as JSON on
+    /// `GET /connections`
+    ///
+    /// Summaries include connection counts by state, age and idle time
+    /// distributions and the source IPs with the most connections. They are
+    /// updated when connections are cleaned (see
+    /// `cleaning.connection_cleaning_interval`).
+    ///
+    /// Version, commit, enabled cargo features and configuration hash (as
+    /// printed with `--version --verbose`) are served on `GET /build_info`.
+    pub run_connection_table_endpoint: bool,
+    /// Address to run connection table endpoint on. Since source IPs are
+    /// exposed, it should not be reachable from the public internet.
+    pub connection_table_endpoint_address: SocketAddr,
+    /// Number of source IPs with the most connections to include
+    pub top_source_ips: usize,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self {
+            run_connection_table_endpoint: false,
+            connection_table_endpoint_address: SocketAddr::from(([127, 0, 0, 1], 9100)),
+            top_source_ips: 10,
+        }
+    }
+}
+
+/// Heavy maintenance tasks run on a schedule, e.g., during low-traffic hours
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Cron-like schedule in UTC with fields for minute, hour, day of month,
+    /// month and day of week, e.g. "30 3 * * *" for 03:30 every day or
+    /// "0 2-5 * * 6,7" for every hour from 02:00 to 05:00 on weekends
+    ///
+    /// Empty = disabled
+    pub schedule: String,
+    /// Reload access lists of all trackers
+    pub reload_access_list: bool,
+}
+
+impl MaintenanceConfig {
+    pub fn active(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            schedule: String::new(),
+            reload_access_list: true,
+        }
+    }
+}
+
 #[cfg(feature = "metrics")]
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -199,7 +615,47 @@ impl Default for MetricsConfig {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::*;
 
     ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+
+    #[test]
+    fn test_route_request() {
+        let config = Config {
+            virtual_trackers: vec![
+                VirtualTrackerConfig {
+                    name: "a".into(),
+                    host: "a.example.com".into(),
+                    path_prefix: "/x/".into(),
+                    ..Default::default()
+                },
+                VirtualTrackerConfig {
+                    name: "b".into(),
+                    path_prefix: "/x".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.route_request(Some("A.example.com:3000"), "/x/announce?a=b"),
+            (TrackerIndex(1), "/announce?a=b")
+        );
+        assert_eq!(
+            config.route_request(Some("b.example.com"), "/x/announce?a=b"),
+            (TrackerIndex(2), "/announce?a=b")
+        );
+        assert_eq!(
+            config.route_request(None, "/xy/announce"),
+            (TrackerIndex(0), "/xy/announce")
+        );
+        assert_eq!(
+            config.route_request(Some("[::1]"), "/announce"),
+            (TrackerIndex(0), "/announce")
+        );
+
+        assert_eq!(config.tracker_name(TrackerIndex(2)), "b");
+        assert_eq!(config.tracker_name(TrackerIndex(0)), "default");
+    }
 }
@@ -0,0 +1,200 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::observer::{AnnounceEvent, SwarmSummary};
+use aquatic_common::rustls_config::{create_rustls_config, RustlsConfig};
+use aquatic_common::shutdown::{join_workers, SHUTDOWN_TIMEOUT};
+use aquatic_common::WorkerType;
+use arc_swap::ArcSwap;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+
+use crate::common::State;
+use crate::config::Config;
+
+/// Handle to tracker started with [`crate::run_with_handle`]
+///
+/// Dropping the handle tells all workers to stop without waiting for them.
+pub struct TrackerHandle {
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    local_addr: SocketAddr,
+    join_handles: Vec<(WorkerType, JoinHandle<anyhow::Result<()>>)>,
+}
+
+impl TrackerHandle {
+    pub(crate) fn new(
+        config: Config,
+        state: State,
+        opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    ) -> Self {
+        Self {
+            local_addr: config.network.address,
+            config,
+            state,
+            opt_tls_config,
+            join_handles: Vec::new(),
+        }
+    }
+
+    /// Address that socket workers are bound to. If port 0 was configured,
+    /// this contains the port chosen by the operating system.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Receive announce requests handled by swarm workers, buffering at
+    /// most `capacity` events. Events are dropped while the buffer is full.
+    pub fn subscribe_announces(&self, capacity: usize) -> Receiver<AnnounceEvent> {
+        self.state.announce_subscribers.subscribe(capacity)
+    }
+
+    /// Torrent and peer counts of all trackers, as of the latest torrent
+    /// cleaning of each swarm worker
+    pub fn stats(&self) -> SwarmSummary {
+        self.state.swarm_summaries.total()
+    }
+
+    /// Block until shutdown is triggered by a termination signal, in which
+    /// case workers are waited for as in [`Self::shutdown`], or until any
+    /// worker stops or panics, returning an error describing what happened
+    pub fn wait(mut self) -> anyhow::Result<()> {
+        loop {
+            if self.state.shutdown.sleep(Duration::from_secs(5)) {
+                return self.shutdown();
+            }
+
+            if let Err(err) = self.check_workers() {
+                // Worker might have stopped because shutdown was triggered
+                // after sleeping
+                if self.state.shutdown.is_triggered() {
+                    return self.shutdown();
+                }
+
+                return Err(err);
+            }
+        }
+    }
+
+    /// Tell workers to stop and wait for them to do so, for at most ten
+    /// seconds
+    ///
+    /// Socket workers stop accepting connections and close existing ones,
+    /// after which swarm workers stop once they have handled remaining
+    /// requests. The statistics thread exports metrics a final time before
+    /// stopping.
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.state.shutdown.trigger();
+
+        join_workers(::std::mem::take(&mut self.join_handles), SHUTDOWN_TIMEOUT)
+    }
+
+    pub(crate) fn push_worker(
+        &mut self,
+        worker_type: WorkerType,
+        handle: JoinHandle<anyhow::Result<()>>,
+    ) {
+        self.join_handles.push((worker_type, handle));
+    }
+
+    /// Reload access lists and TLS config when SIGUSR1 is received and
+    /// trigger shutdown when SIGTERM or SIGINT is
+    pub(crate) fn spawn_signal_handler(&mut self, mut signals: Signals) -> anyhow::Result<()> {
+        let config = self.config.clone();
+        let state = self.state.clone();
+        let opt_tls_config = self.opt_tls_config.clone();
+
+        let handle = Builder::new()
+            .name("signals".into())
+            .spawn(move || {
+                for signal in &mut signals {
+                    match signal {
+                        SIGUSR1 => {
+                            let _ = state.update_access_lists(&config);
+
+                            if let Some(tls_config) = opt_tls_config.as_ref() {
+                                match create_rustls_config(
+                                    &config.network.tls_certificate_path,
+                                    &config.network.tls_private_key_path,
+                                    config.network.tls_session_resumption(),
+                                ) {
+                                    Ok(config) => {
+                                        tls_config.store(Arc::new(config));
+
+                                        ::log::info!("successfully updated tls config");
+                                    }
+                                    Err(err) => {
+                                        ::log::error!("could not update tls config: {:#}", err)
+                                    }
+                                }
+                            }
+                        }
+                        SIGTERM | SIGINT => {
+                            ::log::info!("received signal {}, shutting down", signal);
+
+                            state.shutdown.trigger();
+
+                            break;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                Ok(())
+            })
+            .context("spawn signal worker")?;
+
+        self.push_worker(WorkerType::Signals, handle);
+
+        Ok(())
+    }
+
+    pub(crate) fn wait_until_bound(
+        &mut self,
+        receiver: mpsc::Receiver<SocketAddr>,
+    ) -> anyhow::Result<()> {
+        loop {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(addr) => {
+                    self.local_addr = addr;
+
+                    return Ok(());
+                }
+                Err(_) => self.check_workers()?,
+            }
+        }
+    }
+
+    /// Return error if any worker has stopped
+    fn check_workers(&mut self) -> anyhow::Result<()> {
+        let opt_index = self
+            .join_handles
+            .iter()
+            .position(|(_, handle)| handle.is_finished());
+
+        if let Some(index) = opt_index {
+            let (worker_type, handle) = self.join_handles.remove(index);
+
+            return match handle.join() {
+                Ok(Ok(())) => Err(anyhow::anyhow!("{} stopped", worker_type)),
+                Ok(Err(err)) => Err(err.context(format!("{} stopped", worker_type))),
+                Err(_) => Err(anyhow::anyhow!("{} panicked", worker_type)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TrackerHandle {
+    fn drop(&mut self) {
+        self.state.shutdown.trigger();
+    }
+}
@@ -1,62 +1,187 @@
 use anyhow::Context;
 use aquatic_common::{
-    access_list::update_access_list, privileges::PrivilegeDropper,
-    rustls_config::create_rustls_config, ServerStartInstant, WorkerType,
+    access_list::spawn_access_list_updater,
+    bind::{BindNotifier, BoundCallback},
+    connection_table::spawn_connection_table_endpoint,
+    maintenance::{spawn_maintenance_worker, Schedule},
+    privileges::PrivilegeDropper,
+    rustls_config::create_rustls_config,
+    validate_ipv6_prefix_len, validate_max_scrape_torrents, ServerStartInstant, WorkerType,
 };
+use aquatic_metrics::export::{run_reporter, Exporter, JsonFileExporter, StdoutExporter};
+use aquatic_metrics::{Labels, Registry};
 use arc_swap::ArcSwap;
-use common::State;
+use common::{SocketWorkerStatistics, State, SwarmWorkerStatistics, TrackerIndex};
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
-use signal_hook::{consts::SIGUSR1, iterator::Signals};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
 use std::{
-    sync::Arc,
-    thread::{sleep, Builder, JoinHandle},
+    net::SocketAddr,
+    sync::{mpsc::sync_channel, Arc},
+    thread::Builder,
     time::Duration,
 };
 
 use crate::config::Config;
+use crate::workers::swarm::RequestReceiver;
 
 mod common;
 pub mod config;
+mod handle;
 mod workers;
 
+pub use handle::TrackerHandle;
+
 pub const APP_NAME: &str = "aquatic_http: HTTP BitTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const SHARED_CHANNEL_SIZE: usize = 1024;
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
-    let mut signals = Signals::new([SIGUSR1])?;
+    run_inner(config, None)
+}
+
+/// Run tracker, calling `callback` with the bound address once all socket
+/// workers are ready to accept connections
+///
+/// This is useful together with port 0 in `network.address`, which makes
+/// the operating system choose a free port, e.g., in tests.
+pub fn run_with_bound_callback<F>(config: Config, callback: F) -> ::anyhow::Result<()>
+where
+    F: FnOnce(SocketAddr) + Send + 'static,
+{
+    run_inner(config, Some(Box::new(callback)))
+}
+
+fn run_inner(config: Config, opt_callback: Option<BoundCallback>) -> ::anyhow::Result<()> {
+    let signals = Signals::new([SIGUSR1, SIGTERM, SIGINT])?;
+
+    let mut tracker = run_with_handle(config)?;
+
+    if let Some(callback) = opt_callback {
+        callback(tracker.local_addr());
+    }
 
-    let state = State::default();
+    tracker.spawn_signal_handler(signals)?;
 
-    update_access_list(&config.access_list, &state.access_list)?;
+    tracker.wait()
+}
+
+/// Start tracker and return once all socket workers are ready to accept
+/// connections
+///
+/// The returned handle can be used to subscribe to announce events and to
+/// query swarm statistics. No signal handlers are installed.
+pub fn run_with_handle(mut config: Config) -> ::anyhow::Result<TrackerHandle> {
+    for path in [&config.protocol.announce_path, &config.protocol.scrape_path] {
+        if !path.starts_with('/') || path.contains('?') {
+            return Err(anyhow::anyhow!(
+                "announce and scrape paths must start with '/' and not contain '?': {}",
+                path
+            ));
+        }
+    }
+    if config.protocol.announce_path == config.protocol.scrape_path {
+        return Err(anyhow::anyhow!("announce and scrape paths must differ"));
+    }
+
+    validate_max_scrape_torrents(config.protocol.max_scrape_torrents)?;
+    validate_ipv6_prefix_len(config.network.ipv6_prefix_len)?;
+
+    let state = State::new(&config)?;
+    let opt_maintenance_schedule: Option<Schedule> = config
+        .maintenance
+        .active()
+        .then(|| config.maintenance.schedule.parse())
+        .transpose()
+        .context("parse maintenance.schedule")?;
+
+    state.update_access_lists(&config)?;
 
     let request_mesh_builder = MeshBuilder::partial(
         config.socket_workers + config.swarm_workers,
         SHARED_CHANNEL_SIZE,
     );
-    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
+    let (bound_sender, bound_receiver) = sync_channel(1);
+    let bind_notifier = BindNotifier::new(
+        &mut config.network.address,
+        config.network.only_ipv6,
+        socket2::Type::STREAM,
+        config.socket_workers,
+        Some(Box::new(move |addr| {
+            let _ = bound_sender.send(addr);
+        })),
+    )?;
+    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers)
+        .with_bind_notifier(bind_notifier);
 
     let opt_tls_config = if config.network.enable_tls {
         Some(Arc::new(ArcSwap::from_pointee(create_rustls_config(
             &config.network.tls_certificate_path,
             &config.network.tls_private_key_path,
+            config.network.tls_session_resumption(),
         )?)))
     } else {
         None
     };
 
+    // Socket workers running on tokio send requests to swarm workers over
+    // flume channels instead of the glommio channel mesh
+    #[cfg(feature = "tokio")]
+    let (opt_flume_request_senders, opt_flume_request_receivers) =
+        if config.socket_worker_runtime == config::SocketWorkerRuntime::Tokio {
+            let (senders, receivers): (Vec<_>, Vec<_>) = (0..config.swarm_workers)
+                .map(|_| flume::bounded(SHARED_CHANNEL_SIZE))
+                .unzip();
+
+            (Some(senders), Some(receivers))
+        } else {
+            (None, None)
+        };
+
     let server_start_instant = ServerStartInstant::new();
 
-    let mut join_handles = Vec::new();
+    let mut tracker = TrackerHandle::new(config.clone(), state.clone(), opt_tls_config.clone());
+    let shared_registry = Registry::new(Labels::new());
+    let mut registries = vec![shared_registry.clone()];
 
     for i in 0..(config.socket_workers) {
+        let registry = Registry::new(Labels::new().with("worker_index", i));
+        let statistics = SocketWorkerStatistics::new(&config, &registry, &shared_registry);
+
+        registries.push(registry);
+
         let config = config.clone();
         let state = state.clone();
         let opt_tls_config = opt_tls_config.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let priv_dropper = priv_dropper.clone();
 
+        #[cfg(feature = "tokio")]
+        if let Some(request_senders) = opt_flume_request_senders.clone() {
+            let handle = Builder::new()
+                .name(format!("socket-{:02}", i + 1))
+                .spawn(move || {
+                    workers::socket::run_socket_worker_on_tokio(
+                        config,
+                        state,
+                        opt_tls_config,
+                        request_senders,
+                        priv_dropper,
+                        statistics,
+                        server_start_instant,
+                        i,
+                    )
+                })
+                .context("spawn socket worker")?;
+
+            tracker.push_worker(WorkerType::Socket(i), handle);
+
+            continue;
+        }
+
         let handle = Builder::new()
             .name(format!("socket-{:02}", i + 1))
             .spawn(move || {
@@ -69,19 +194,36 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                         opt_tls_config,
                         request_mesh_builder,
                         priv_dropper,
+                        statistics,
                         server_start_instant,
                         i,
                     ))
             })
             .context("spawn socket worker")?;
 
-        join_handles.push((WorkerType::Socket(i), handle));
+        tracker.push_worker(WorkerType::Socket(i), handle);
     }
 
     for i in 0..(config.swarm_workers) {
+        let registry = Registry::new(
+            Labels::new()
+                .with("worker_type", "swarm")
+                .with("worker_index", i),
+        );
+        let statistics = SwarmWorkerStatistics::new(&registry);
+
+        registries.push(registry);
+
         let config = config.clone();
         let state = state.clone();
-        let request_mesh_builder = request_mesh_builder.clone();
+
+        let request_receiver = RequestReceiver::Mesh(request_mesh_builder.clone());
+
+        #[cfg(feature = "tokio")]
+        let request_receiver = match opt_flume_request_receivers.as_ref() {
+            Some(receivers) => RequestReceiver::Flume(receivers[i].clone()),
+            None => request_receiver,
+        };
 
         let handle = Builder::new()
             .name(format!("swarm-{:02}", i + 1))
@@ -92,14 +234,75 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     .run(workers::swarm::run_swarm_worker(
                         config,
                         state,
-                        request_mesh_builder,
+                        request_receiver,
+                        statistics,
                         server_start_instant,
                         i,
                     ))
             })
             .context("spawn swarm worker")?;
 
-        join_handles.push((WorkerType::Swarm(i), handle));
+        tracker.push_worker(WorkerType::Swarm(i), handle);
+    }
+
+    for (i, access_list) in state.access_lists.iter().enumerate() {
+        let access_list_config = config.access_list_config(TrackerIndex(i)).clone();
+
+        if let Some(handle) = spawn_access_list_updater(
+            access_list_config,
+            access_list.clone(),
+            state.shutdown.clone(),
+        )? {
+            tracker.push_worker(WorkerType::AccessListUpdater, handle);
+        }
+    }
+
+    if let Some(schedule) = opt_maintenance_schedule {
+        let state = state.clone();
+        let config = config.clone();
+
+        let handle = spawn_maintenance_worker(schedule, state.shutdown.clone(), move || {
+            if config.maintenance.reload_access_list {
+                let _ = state.update_access_lists(&config);
+            }
+        })?;
+
+        tracker.push_worker(WorkerType::Maintenance, handle);
+    }
+
+    if config.statistics.active() {
+        let mut exporters: Vec<Box<dyn Exporter>> = Vec::new();
+
+        if config.statistics.print_to_stdout {
+            exporters.push(Box::new(StdoutExporter));
+        }
+        if config.statistics.write_json_to_file {
+            exporters.push(Box::new(JsonFileExporter::new(
+                &config.statistics.json_file_path,
+            )?));
+        }
+
+        let shutdown = state.shutdown.clone();
+
+        let handle = run_reporter(
+            Duration::from_secs(config.statistics.interval),
+            registries,
+            exporters,
+            move |duration| shutdown.sleep(duration),
+        )?;
+
+        tracker.push_worker(WorkerType::Statistics, handle);
+    }
+
+    if config.diagnostics.run_connection_table_endpoint {
+        let handle = spawn_connection_table_endpoint(
+            config.diagnostics.connection_table_endpoint_address,
+            state.connection_tables.clone(),
+            aquatic_common::cli::BuildInfo::new(APP_VERSION, &config),
+            state.shutdown.clone(),
+        )?;
+
+        tracker.push_worker(WorkerType::Diagnostics, handle);
     }
 
     #[cfg(feature = "prometheus")]
@@ -115,67 +318,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             config.metrics.prometheus_endpoint_address,
             Some(Duration::from_secs(idle_timeout)),
             Some(metrics_util::MetricKindMask::GAUGE),
+            Some(aquatic_common::cli::BuildInfo::new(APP_VERSION, &config)),
+            state.shutdown.clone(),
         )?;
 
-        join_handles.push((WorkerType::Prometheus, handle));
+        tracker.push_worker(WorkerType::Prometheus, handle);
     }
 
-    // Spawn signal handler thread
-    {
-        let handle: JoinHandle<anyhow::Result<()>> = Builder::new()
-            .name("signals".into())
-            .spawn(move || {
-                for signal in &mut signals {
-                    match signal {
-                        SIGUSR1 => {
-                            let _ = update_access_list(&config.access_list, &state.access_list);
-
-                            if let Some(tls_config) = opt_tls_config.as_ref() {
-                                match create_rustls_config(
-                                    &config.network.tls_certificate_path,
-                                    &config.network.tls_private_key_path,
-                                ) {
-                                    Ok(config) => {
-                                        tls_config.store(Arc::new(config));
-
-                                        ::log::info!("successfully updated tls config");
-                                    }
-                                    Err(err) => {
-                                        ::log::error!("could not update tls config: {:#}", err)
-                                    }
-                                }
-                            }
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-
-                Ok(())
-            })
-            .context("spawn signal worker")?;
-
-        join_handles.push((WorkerType::Signals, handle));
-    }
+    tracker.wait_until_bound(bound_receiver)?;
 
-    loop {
-        for (i, (_, handle)) in join_handles.iter().enumerate() {
-            if handle.is_finished() {
-                let (worker_type, handle) = join_handles.remove(i);
-
-                match handle.join() {
-                    Ok(Ok(())) => {
-                        return Err(anyhow::anyhow!("{} stopped", worker_type));
-                    }
-                    Ok(Err(err)) => {
-                        return Err(err.context(format!("{} stopped", worker_type)));
-                    }
-                    Err(_) => {
-                        return Err(anyhow::anyhow!("{} panicked", worker_type));
-                    }
-                }
-            }
-        }
-
-        sleep(Duration::from_secs(5));
-    }
+    Ok(tracker)
 }
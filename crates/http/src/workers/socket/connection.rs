@@ -1,13 +1,20 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::connection_table::ConnectionState;
+use aquatic_common::pending_scrape::PendingScrapeResponse;
+use aquatic_common::routing::{group_by_swarm_worker, swarm_worker_index, WorkerSenders};
 use aquatic_common::rustls_config::RustlsConfig;
-use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
+use aquatic_common::user_agent::UserAgentFilter;
+use aquatic_common::{
+    CanonicalSocketAddr, PortZeroPolicy, ServerStartInstant, TOO_MANY_SCRAPE_TORRENTS,
+};
 use aquatic_http_protocol::common::InfoHash;
 use aquatic_http_protocol::request::{Request, ScrapeRequest};
 use aquatic_http_protocol::response::{
@@ -17,9 +24,7 @@ use arc_swap::ArcSwap;
 use futures::stream::FuturesUnordered;
 use futures_lite::{AsyncReadExt, AsyncWriteExt, StreamExt};
 use futures_rustls::TlsAcceptor;
-use glommio::channels::channel_mesh::Senders;
 use glommio::channels::shared_channel::{self, SharedReceiver};
-use glommio::net::TcpStream;
 use once_cell::sync::Lazy;
 
 use crate::common::*;
@@ -27,10 +32,14 @@ use crate::config::Config;
 
 #[cfg(feature = "metrics")]
 use super::peer_addr_to_ip_version_str;
-use super::request::{parse_request, RequestParseError};
+use super::request::{headers_complete, parse_request, ParsedRequest, RequestParseError};
 
-const REQUEST_BUFFER_SIZE: usize = 2048;
-const RESPONSE_BUFFER_SIZE: usize = 4096;
+/// Request buffers start out at this size and grow up to
+/// `network.max_request_size` when needed
+const INITIAL_REQUEST_BUFFER_SIZE: usize = 2048;
+/// Response buffers start out at this size and grow up to
+/// `network.max_response_size` when needed
+const INITIAL_RESPONSE_BUFFER_SIZE: usize = 4096;
 
 const RESPONSE_HEADER_A: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: ";
 const RESPONSE_HEADER_B: &[u8] = b"        ";
@@ -39,9 +48,83 @@ const RESPONSE_HEADER_C: &[u8] = b"\r\n\r\n";
 static RESPONSE_HEADER: Lazy<Vec<u8>> =
     Lazy::new(|| [RESPONSE_HEADER_A, RESPONSE_HEADER_B, RESPONSE_HEADER_C].concat());
 
-struct PendingScrapeResponse {
-    pending_worker_responses: usize,
-    stats: BTreeMap<InfoHash, ScrapeStatistics>,
+/// Full response sent when request exceeds `network.max_request_size`
+static REQUEST_TOO_LARGE_RESPONSE: Lazy<Vec<u8>> = Lazy::new(|| {
+    let mut body = Vec::new();
+
+    FailureResponse::new("Request too large")
+        .write_bytes(&mut body)
+        .expect("write to vec");
+
+    let mut response = format!(
+        "HTTP/1.1 413 Payload Too Large\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+
+    response.extend_from_slice(&body);
+
+    response
+});
+
+/// Full response sent when request path is not announce or scrape path.
+/// Body is empty so that it doesn't reveal that this is a tracker.
+const NOT_FOUND_RESPONSE: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Full response sent for requests with methods other than GET, HEAD and
+/// OPTIONS
+const METHOD_NOT_ALLOWED_RESPONSE: &[u8] =
+    b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD, OPTIONS\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Channels for sending requests to swarm workers
+#[derive(Clone)]
+pub struct RequestSenders(pub WorkerSenders<ChannelRequest>);
+
+impl RequestSenders {
+    async fn send_to(&self, consumer_index: usize, request: ChannelRequest) {
+        // Only fails when receiver is closed
+        self.0.send_to(consumer_index, request).await.unwrap()
+    }
+
+    fn response_channel<T: Send + 'static>(&self) -> (ResponseSender<T>, ResponseReceiver<T>) {
+        match self.0 {
+            WorkerSenders::Mesh(_) => {
+                let (sender, receiver) = shared_channel::new_bounded(1);
+
+                (
+                    ResponseSender::Glommio(sender),
+                    ResponseReceiver::Glommio(receiver),
+                )
+            }
+            #[cfg(feature = "tokio")]
+            WorkerSenders::Flume(_) => {
+                let (sender, receiver) = futures::channel::oneshot::channel();
+
+                (
+                    ResponseSender::Oneshot(sender),
+                    ResponseReceiver::Oneshot(receiver),
+                )
+            }
+        }
+    }
+}
+
+enum ResponseReceiver<T: Send> {
+    Glommio(SharedReceiver<T>),
+    #[cfg(feature = "tokio")]
+    Oneshot(futures::channel::oneshot::Receiver<T>),
+}
+
+impl<T: Send + 'static> ResponseReceiver<T> {
+    /// Returns None if sender was closed
+    async fn recv(self) -> Option<T> {
+        match self {
+            Self::Glommio(receiver) => receiver.connect().await.recv().await,
+            #[cfg(feature = "tokio")]
+            Self::Oneshot(receiver) => receiver.await.ok(),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -50,8 +133,12 @@ pub enum ConnectionError {
     Inactive,
     #[error("socket peer addr extraction failed")]
     NoSocketPeerAddr(String),
-    #[error("request buffer full")]
-    RequestBufferFull,
+    #[error("request exceeds max size")]
+    RequestTooLarge,
+    #[error("request path not found")]
+    NotFound,
+    #[error("request method not allowed")]
+    MethodNotAllowed,
     #[error("response buffer full")]
     ResponseBufferFull,
     #[error("response buffer write error: {0}")]
@@ -67,26 +154,31 @@ pub enum ConnectionError {
 }
 
 #[allow(clippy::too_many_arguments)]
-pub(super) async fn run_connection(
+pub(super) async fn run_connection<S>(
     config: Rc<Config>,
-    access_list: Arc<AccessListArcSwap>,
-    request_senders: Rc<Senders<ChannelRequest>>,
+    access_lists: Arc<[Arc<AccessListArcSwap>]>,
+    static_pages: Arc<StaticPages>,
+    user_agent_filter: Arc<UserAgentFilter>,
+    request_senders: RequestSenders,
+    statistics: Rc<SocketWorkerStatistics>,
     server_start_instant: ServerStartInstant,
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     valid_until: Rc<RefCell<ValidUntil>>,
-    stream: TcpStream,
+    connection_state: Rc<Cell<ConnectionState>>,
+    stream: S,
+    remote_addr: SocketAddr,
     worker_index: usize,
-) -> Result<(), ConnectionError> {
-    let access_list_cache = create_access_list_cache(&access_list);
-    let request_buffer = Box::new([0u8; REQUEST_BUFFER_SIZE]);
-
-    let mut response_buffer = Box::new([0; RESPONSE_BUFFER_SIZE]);
+) -> Result<(), ConnectionError>
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static,
+{
+    let access_list_caches = access_lists.iter().map(create_access_list_cache).collect();
+    let request_buffer =
+        vec![0u8; INITIAL_REQUEST_BUFFER_SIZE.min(config.network.max_request_size)];
 
-    response_buffer[..RESPONSE_HEADER.len()].copy_from_slice(&RESPONSE_HEADER);
+    let mut response_buffer = Vec::with_capacity(INITIAL_RESPONSE_BUFFER_SIZE);
 
-    let remote_addr = stream
-        .peer_addr()
-        .map_err(|err| ConnectionError::NoSocketPeerAddr(err.to_string()))?;
+    response_buffer.extend_from_slice(&RESPONSE_HEADER);
 
     let opt_peer_addr = if config.network.runs_behind_reverse_proxy {
         None
@@ -98,15 +190,26 @@ pub(super) async fn run_connection(
 
     if let Some(tls_config) = opt_tls_config {
         let tls_acceptor: TlsAcceptor = tls_config.load_full().into();
-        let stream = tls_acceptor
-            .accept(stream)
-            .await
-            .with_context(|| "tls accept")?;
+        let stream = match tls_acceptor.accept(stream).await {
+            Ok(stream) => {
+                connection_state.set(ConnectionState::Open);
+
+                stream
+            }
+            Err(err) => {
+                statistics.tls_handshake_failures.increment(1);
+
+                return Err(anyhow::Error::from(err).context("tls accept").into());
+            }
+        };
 
         let mut conn = Connection {
             config,
-            access_list_cache,
+            access_list_caches,
+            static_pages,
+            user_agent_filter,
             request_senders,
+            statistics,
             valid_until,
             server_start_instant,
             peer_port,
@@ -119,10 +222,15 @@ pub(super) async fn run_connection(
 
         conn.run(opt_peer_addr).await
     } else {
+        connection_state.set(ConnectionState::Open);
+
         let mut conn = Connection {
             config,
-            access_list_cache,
+            access_list_caches,
+            static_pages,
+            user_agent_filter,
             request_senders,
+            statistics,
             valid_until,
             server_start_instant,
             peer_port,
@@ -137,16 +245,33 @@ pub(super) async fn run_connection(
     }
 }
 
+enum ReadRequest {
+    Tracker {
+        request: Request,
+        opt_peer_addr: Option<CanonicalSocketAddr>,
+        tracker: TrackerIndex,
+        /// User-Agent header matched `user_agent.deny`
+        user_agent_denied: bool,
+    },
+    /// Static response has been sent
+    StaticResponseSent,
+}
+
 struct Connection<S> {
     config: Rc<Config>,
-    access_list_cache: AccessListCache,
-    request_senders: Rc<Senders<ChannelRequest>>,
+    /// Indexed by tracker index
+    access_list_caches: Vec<AccessListCache>,
+    static_pages: Arc<StaticPages>,
+    user_agent_filter: Arc<UserAgentFilter>,
+    request_senders: RequestSenders,
+    statistics: Rc<SocketWorkerStatistics>,
     valid_until: Rc<RefCell<ValidUntil>>,
     server_start_instant: ServerStartInstant,
     peer_port: u16,
-    request_buffer: Box<[u8; REQUEST_BUFFER_SIZE]>,
+    request_buffer: Vec<u8>,
     request_buffer_position: usize,
-    response_buffer: Box<[u8; RESPONSE_BUFFER_SIZE]>,
+    /// Always starts with response header
+    response_buffer: Vec<u8>,
     stream: S,
     worker_index_string: String,
 }
@@ -160,33 +285,104 @@ where
         // Set unless running behind reverse proxy
         opt_stable_peer_addr: Option<CanonicalSocketAddr>,
     ) -> Result<(), ConnectionError> {
+        let max_requests = self.config.network.max_requests_per_connection;
+        let opt_lifetime_valid_until =
+            (self.config.network.max_connection_lifetime != 0).then(|| {
+                ValidUntil::new(
+                    self.server_start_instant,
+                    self.config.network.max_connection_lifetime,
+                )
+            });
+
+        let mut num_requests = 0usize;
+
         loop {
-            let (request, opt_peer_addr) = self.read_request().await?;
+            let (request, opt_peer_addr, tracker, user_agent_denied) =
+                match self.read_request().await? {
+                    ReadRequest::Tracker {
+                        request,
+                        opt_peer_addr,
+                        tracker,
+                        user_agent_denied,
+                    } => (request, opt_peer_addr, tracker, user_agent_denied),
+                    ReadRequest::StaticResponseSent => {
+                        if !self.config.network.keep_alive {
+                            break;
+                        }
+
+                        continue;
+                    }
+                };
+
+            if num_requests == 0 {
+                self.statistics.requests_fresh_connection.increment(1);
+            } else {
+                self.statistics.requests_reused_connection.increment(1);
+            }
+
+            num_requests += 1;
 
             let peer_addr = opt_stable_peer_addr
                 .or(opt_peer_addr)
                 .ok_or(anyhow::anyhow!("Could not extract peer addr"))?;
 
-            let response = self.handle_request(request, peer_addr).await?;
+            self.statistics
+                .unique_ips
+                .insert(&peer_addr.ip_prefix(self.config.network.ipv6_prefix_len));
+
+            let response = if user_agent_denied {
+                self.statistics.requests_user_agent_denied.increment(1);
+
+                Response::Failure(FailureResponse {
+                    failure_reason: "Client not allowed".into(),
+                })
+            } else {
+                self.handle_request(request, peer_addr, tracker).await?
+            };
 
-            self.write_response(&response, peer_addr).await?;
+            self.write_response(&response, peer_addr, tracker).await?;
 
             if !self.config.network.keep_alive {
                 break;
             }
+            if max_requests != 0 && num_requests >= max_requests {
+                self.statistics.connections_closed_max_requests.increment(1);
+
+                break;
+            }
+            if let Some(valid_until) = opt_lifetime_valid_until {
+                if !valid_until.valid(self.server_start_instant.seconds_elapsed()) {
+                    self.statistics.connections_closed_max_lifetime.increment(1);
+
+                    break;
+                }
+            }
         }
 
         Ok(())
     }
 
-    async fn read_request(
-        &mut self,
-    ) -> Result<(Request, Option<CanonicalSocketAddr>), ConnectionError> {
+    async fn read_request(&mut self) -> Result<ReadRequest, ConnectionError> {
         self.request_buffer_position = 0;
 
+        // Release memory used for previous large request
+        if self.request_buffer.len() > INITIAL_REQUEST_BUFFER_SIZE {
+            self.request_buffer.truncate(INITIAL_REQUEST_BUFFER_SIZE);
+            self.request_buffer.shrink_to_fit();
+        }
+
         loop {
             if self.request_buffer_position == self.request_buffer.len() {
-                return Err(ConnectionError::RequestBufferFull);
+                if self.request_buffer.len() >= self.config.network.max_request_size {
+                    self.reject_too_large_request().await?;
+
+                    return Err(ConnectionError::RequestTooLarge);
+                }
+
+                let new_len =
+                    (self.request_buffer.len() * 2).min(self.config.network.max_request_size);
+
+                self.request_buffer.resize(new_len, 0);
             }
 
             let bytes_read = self
@@ -199,12 +395,27 @@ where
                 return Err(ConnectionError::PeerClosed);
             }
 
+            self.statistics.bytes_received.increment(bytes_read);
+
+            let new_data_start = self.request_buffer_position;
+
             self.request_buffer_position += bytes_read;
 
             let buffer_slice = &self.request_buffer[..self.request_buffer_position];
 
-            match parse_request(&self.config, buffer_slice) {
-                Ok((request, opt_peer_ip)) => {
+            // Avoid parsing the whole buffer again until headers are
+            // complete. Long scrape requests may arrive in many reads.
+            if !headers_complete(buffer_slice, new_data_start) {
+                continue;
+            }
+
+            match parse_request(&self.config, &self.static_pages, buffer_slice) {
+                Ok(ParsedRequest {
+                    request,
+                    opt_peer_ip,
+                    tracker,
+                    opt_user_agent,
+                }) => {
                     let opt_peer_addr = if self.config.network.runs_behind_reverse_proxy {
                         let peer_ip = opt_peer_ip
                             .expect("logic error: peer ip must have been extracted at this point");
@@ -217,19 +428,115 @@ where
                         None
                     };
 
-                    return Ok((request, opt_peer_addr));
+                    self.statistics.user_agents.record(opt_user_agent);
+
+                    let user_agent_denied = opt_user_agent.map_or(false, |user_agent| {
+                        self.user_agent_filter.is_denied(user_agent)
+                    });
+
+                    return Ok(ReadRequest::Tracker {
+                        request,
+                        opt_peer_addr,
+                        tracker,
+                        user_agent_denied,
+                    });
                 }
                 Err(RequestParseError::MoreDataNeeded) => continue,
+                Err(RequestParseError::StaticResponse(response)) => {
+                    self.write_static_response(response).await?;
+
+                    return Ok(ReadRequest::StaticResponseSent);
+                }
+                Err(RequestParseError::NotFound) => {
+                    self.reject_not_found_request().await?;
+
+                    return Err(ConnectionError::NotFound);
+                }
+                Err(RequestParseError::MethodNotAllowed) => {
+                    self.write_error_response(METHOD_NOT_ALLOWED_RESPONSE)
+                        .await?;
+
+                    return Err(ConnectionError::MethodNotAllowed);
+                }
                 Err(RequestParseError::RequiredPeerIpHeaderMissing(err)) => {
                     panic!("Tracker configured as running behind reverse proxy, but no corresponding IP header set in request. Please check your reverse proxy setup as well as your aquatic configuration. Error: {:#}", err);
                 }
                 Err(RequestParseError::Other(err)) => {
+                    self.statistics.request_parse_failures.increment(1);
+
                     ::log::debug!("Failed parsing request: {:#}", err);
                 }
             }
         }
     }
 
+    /// Send `413 Payload Too Large` response. Connection is closed afterwards,
+    /// since the rest of the request can't be skipped reliably.
+    async fn reject_too_large_request(&mut self) -> Result<(), ConnectionError> {
+        self.statistics.requests_too_large.increment(1);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!(
+            aquatic_metrics::names::REQUESTS_TOO_LARGE_TOTAL,
+            "worker_index" => self.worker_index_string.clone(),
+        )
+        .increment(1);
+
+        self.write_error_response(&REQUEST_TOO_LARGE_RESPONSE).await
+    }
+
+    /// Send `404 Not Found` response. Connection is closed afterwards, so
+    /// that scanners can't probe further paths cheaply.
+    async fn reject_not_found_request(&mut self) -> Result<(), ConnectionError> {
+        self.statistics.requests_not_found.increment(1);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!(
+            aquatic_metrics::names::REQUESTS_NOT_FOUND_TOTAL,
+            "worker_index" => self.worker_index_string.clone(),
+        )
+        .increment(1);
+
+        self.write_error_response(NOT_FOUND_RESPONSE).await
+    }
+
+    async fn write_static_response(
+        &mut self,
+        response: StaticResponse,
+    ) -> Result<(), ConnectionError> {
+        *self.valid_until.borrow_mut() = ValidUntil::new(
+            self.server_start_instant,
+            self.config.cleaning.max_connection_idle,
+        );
+
+        let static_pages = self.static_pages.clone();
+        let response = static_pages.response_bytes(response);
+
+        self.stream
+            .write_all(response)
+            .await
+            .with_context(|| "write")?;
+        self.stream.flush().await.with_context(|| "flush")?;
+
+        self.statistics.bytes_sent.increment(response.len());
+        self.statistics.responses_static.increment(1);
+
+        Ok(())
+    }
+
+    async fn write_error_response(&mut self, response: &[u8]) -> Result<(), ConnectionError> {
+        self.stream
+            .write_all(response)
+            .await
+            .with_context(|| "write")?;
+        self.stream.flush().await.with_context(|| "flush")?;
+
+        self.statistics.bytes_sent.increment(response.len());
+        self.statistics.responses_error.increment(1);
+
+        Ok(())
+    }
+
     /// Take a request and:
     /// - Update connection ValidUntil
     /// - Return error response if request is not allowed
@@ -241,7 +548,10 @@ where
         &mut self,
         request: Request,
         peer_addr: CanonicalSocketAddr,
+        tracker: TrackerIndex,
     ) -> Result<Response, ConnectionError> {
+        let received_at = Instant::now();
+
         *self.valid_until.borrow_mut() = ValidUntil::new(
             self.server_start_instant,
             self.config.cleaning.max_connection_idle,
@@ -249,41 +559,58 @@ where
 
         match request {
             Request::Announce(request) => {
+                self.statistics.requests_announce.increment(1);
+
                 #[cfg(feature = "metrics")]
                 ::metrics::counter!(
-                    "aquatic_requests_total",
+                    aquatic_metrics::names::REQUESTS_TOTAL,
                     "type" => "announce",
                     "ip_version" => peer_addr_to_ip_version_str(&peer_addr),
                     "worker_index" => self.worker_index_string.clone(),
+                    "tracker" => self.config.tracker_name(tracker).to_string(),
                 )
                 .increment(1);
 
+                if request.port == 0 {
+                    #[cfg(feature = "metrics")]
+                    ::metrics::counter!(
+                        aquatic_metrics::names::ANNOUNCE_REQUESTS_PORT_ZERO_TOTAL,
+                        "ip_version" => peer_addr_to_ip_version_str(&peer_addr),
+                        "worker_index" => self.worker_index_string.clone(),
+                        "tracker" => self.config.tracker_name(tracker).to_string(),
+                    )
+                    .increment(1);
+
+                    if self.config.protocol.port_zero_policy == PortZeroPolicy::Reject {
+                        return Ok(Response::Failure(FailureResponse {
+                            failure_reason: "Port can't be 0".into(),
+                        }));
+                    }
+                }
+
                 let info_hash = request.info_hash;
 
-                if self
-                    .access_list_cache
+                if self.access_list_caches[tracker.0]
                     .load()
-                    .allows(self.config.access_list.mode, &info_hash.0)
+                    .allows(self.config.access_list_config(tracker).mode, &info_hash.0)
                 {
-                    let (response_sender, response_receiver) = shared_channel::new_bounded(1);
+                    let (response_sender, response_receiver) =
+                        self.request_senders.response_channel();
 
                     let request = ChannelRequest::Announce {
                         request,
                         peer_addr,
+                        tracker,
+                        received_at,
                         response_sender,
                     };
 
-                    let consumer_index = calculate_request_consumer_index(&self.config, info_hash);
+                    let consumer_index =
+                        swarm_worker_index(&info_hash.0, self.config.swarm_workers);
 
-                    // Only fails when receiver is closed
-                    self.request_senders
-                        .send_to(consumer_index, request)
-                        .await
-                        .unwrap();
+                    self.request_senders.send_to(consumer_index, request).await;
 
                     response_receiver
-                        .connect()
-                        .await
                         .recv()
                         .await
                         .ok_or(ConnectionError::ResponseSenderClosed)
@@ -297,50 +624,52 @@ where
                 }
             }
             Request::Scrape(ScrapeRequest { info_hashes }) => {
+                self.statistics.requests_scrape.increment(1);
+
                 #[cfg(feature = "metrics")]
                 ::metrics::counter!(
-                    "aquatic_requests_total",
+                    aquatic_metrics::names::REQUESTS_TOTAL,
                     "type" => "scrape",
                     "ip_version" => peer_addr_to_ip_version_str(&peer_addr),
                     "worker_index" => self.worker_index_string.clone(),
+                    "tracker" => self.config.tracker_name(tracker).to_string(),
                 )
                 .increment(1);
 
-                let mut info_hashes_by_worker: BTreeMap<usize, Vec<InfoHash>> = BTreeMap::new();
-
-                for info_hash in info_hashes.into_iter() {
-                    let info_hashes = info_hashes_by_worker
-                        .entry(calculate_request_consumer_index(&self.config, info_hash))
-                        .or_default();
-
-                    info_hashes.push(info_hash);
+                if info_hashes.len() > self.config.protocol.max_scrape_torrents {
+                    return Ok(Response::Failure(FailureResponse {
+                        failure_reason: TOO_MANY_SCRAPE_TORRENTS.into(),
+                    }));
                 }
 
-                let pending_worker_responses = info_hashes_by_worker.len();
-                let mut response_receivers = Vec::with_capacity(pending_worker_responses);
-
-                for (consumer_index, info_hashes) in info_hashes_by_worker {
-                    let (response_sender, response_receiver) = shared_channel::new_bounded(1);
-
-                    response_receivers.push(response_receiver);
-
-                    let request = ChannelRequest::Scrape {
-                        request: ScrapeRequest { info_hashes },
-                        peer_addr,
-                        response_sender,
-                    };
-
-                    // Only fails when receiver is closed
-                    self.request_senders
-                        .send_to(consumer_index, request)
-                        .await
-                        .unwrap();
-                }
+                let info_hashes_by_worker =
+                    group_by_swarm_worker(info_hashes, self.config.swarm_workers, |info_hash| {
+                        &info_hash.0
+                    });
 
-                let pending_scrape_response = PendingScrapeResponse {
-                    pending_worker_responses,
-                    stats: Default::default(),
-                };
+                let pending_scrape_response =
+                    PendingScrapeResponse::for_groups(&info_hashes_by_worker);
+                let mut response_receivers = Vec::with_capacity(info_hashes_by_worker.len());
+
+                // Only fails when receiver is closed
+                self.request_senders
+                    .0
+                    .scatter(info_hashes_by_worker, |info_hashes| {
+                        let (response_sender, response_receiver) =
+                            self.request_senders.response_channel();
+
+                        response_receivers.push(response_receiver);
+
+                        ChannelRequest::Scrape {
+                            request: ScrapeRequest { info_hashes },
+                            peer_addr,
+                            tracker,
+                            received_at,
+                            response_sender,
+                        }
+                    })
+                    .await
+                    .unwrap();
 
                 self.wait_for_scrape_responses(response_receivers, pending_scrape_response)
                     .await
@@ -352,12 +681,12 @@ where
     /// return full response
     async fn wait_for_scrape_responses(
         &self,
-        response_receivers: Vec<SharedReceiver<ScrapeResponse>>,
-        mut pending: PendingScrapeResponse,
+        response_receivers: Vec<ResponseReceiver<ScrapeResponse>>,
+        mut pending: PendingScrapeResponse<BTreeMap<InfoHash, ScrapeStatistics>>,
     ) -> Result<Response, ConnectionError> {
         let mut responses = response_receivers
             .into_iter()
-            .map(|receiver| async { receiver.connect().await.recv().await })
+            .map(|receiver| receiver.recv())
             .collect::<FuturesUnordered<_>>();
 
         loop {
@@ -371,12 +700,9 @@ where
                 })?
                 .ok_or_else(|| ConnectionError::ScrapeChannelError("sender is closed"))?;
 
-            pending.stats.extend(response.files);
-            pending.pending_worker_responses -= 1;
-
-            if pending.pending_worker_responses == 0 {
+            if pending.merge_part(|files| files.extend(response.files)) {
                 let response = Response::Scrape(ScrapeResponse {
-                    files: pending.stats,
+                    files: pending.into_response(),
                 });
 
                 break Ok(response);
@@ -384,60 +710,41 @@ where
         }
     }
 
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
     async fn write_response(
         &mut self,
         response: &Response,
         peer_addr: CanonicalSocketAddr,
+        tracker: TrackerIndex,
     ) -> Result<(), ConnectionError> {
-        // Write body and final newline to response buffer
-
-        let mut position = RESPONSE_HEADER.len();
-
-        let body_len = response
-            .write_bytes(&mut &mut self.response_buffer[position..])
-            .map_err(ConnectionError::ResponseBufferWrite)?;
-
-        position += body_len;
-
-        if position + 2 > self.response_buffer.len() {
-            return Err(ConnectionError::ResponseBufferFull);
-        }
-
-        self.response_buffer[position..position + 2].copy_from_slice(b"\r\n");
-
-        position += 2;
-
-        let content_len = body_len + 2;
-
-        // Clear content-len header value
-
-        {
-            let start = RESPONSE_HEADER_A.len();
-            let end = start + RESPONSE_HEADER_B.len();
-
-            self.response_buffer[start..end].copy_from_slice(RESPONSE_HEADER_B);
-        }
-
-        // Set content-len header value
-
-        {
-            let mut buf = ::itoa::Buffer::new();
-            let content_len_bytes = buf.format(content_len).as_bytes();
-
-            let start = RESPONSE_HEADER_A.len();
-            let end = start + content_len_bytes.len();
-
-            self.response_buffer[start..end].copy_from_slice(content_len_bytes);
-        }
+        let position = write_response_to_buffer(
+            &mut self.response_buffer,
+            response,
+            self.config.network.max_response_size,
+        )?;
 
         // Write buffer to stream
 
         self.stream
-            .write(&self.response_buffer[..position])
+            .write_all(&self.response_buffer[..position])
             .await
             .with_context(|| "write")?;
         self.stream.flush().await.with_context(|| "flush")?;
 
+        // Release memory used for large response
+        if self.response_buffer.capacity() > INITIAL_RESPONSE_BUFFER_SIZE {
+            self.response_buffer.truncate(RESPONSE_HEADER.len());
+            self.response_buffer.shrink_to(INITIAL_RESPONSE_BUFFER_SIZE);
+        }
+
+        self.statistics.bytes_sent.increment(position);
+
+        match response {
+            Response::Announce(_) => self.statistics.responses_announce.increment(1),
+            Response::Scrape(_) => self.statistics.responses_scrape.increment(1),
+            Response::Failure(_) => self.statistics.responses_error.increment(1),
+        }
+
         #[cfg(feature = "metrics")]
         {
             let response_type = match response {
@@ -449,10 +756,11 @@ where
             let ip_version_str = peer_addr_to_ip_version_str(&peer_addr);
 
             ::metrics::counter!(
-                "aquatic_responses_total",
+                aquatic_metrics::names::RESPONSES_TOTAL,
                 "type" => response_type,
                 "ip_version" => ip_version_str,
                 "worker_index" => self.worker_index_string.clone(),
+                "tracker" => self.config.tracker_name(tracker).to_string(),
             )
             .increment(1);
         }
@@ -461,6 +769,138 @@ where
     }
 }
 
-fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
-    (info_hash.0[0] as usize) % config.swarm_workers
+/// Write response body and final newline to buffer (which must start with
+/// [RESPONSE_HEADER]) and set content length header value, growing buffer as
+/// needed. Returns length of full response.
+fn write_response_to_buffer(
+    buffer: &mut Vec<u8>,
+    response: &Response,
+    max_response_size: usize,
+) -> Result<usize, ConnectionError> {
+    buffer.truncate(RESPONSE_HEADER.len());
+
+    let body_len = response
+        .write_bytes(buffer)
+        .map_err(ConnectionError::ResponseBufferWrite)?;
+
+    buffer.extend_from_slice(b"\r\n");
+
+    if buffer.len() > max_response_size {
+        return Err(ConnectionError::ResponseBufferFull);
+    }
+
+    let content_len = body_len + 2;
+
+    // Clear content-len header value
+
+    {
+        let start = RESPONSE_HEADER_A.len();
+        let end = start + RESPONSE_HEADER_B.len();
+
+        buffer[start..end].copy_from_slice(RESPONSE_HEADER_B);
+    }
+
+    // Set content-len header value
+
+    {
+        let mut buf = ::itoa::Buffer::new();
+        let content_len_bytes = buf.format(content_len).as_bytes();
+
+        let start = RESPONSE_HEADER_A.len();
+        let end = start + content_len_bytes.len();
+
+        buffer[start..end].copy_from_slice(content_len_bytes);
+    }
+
+    Ok(buffer.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use aquatic_http_protocol::response::{
+        AnnounceResponse, ResponsePeer, ResponsePeerListV4, ResponsePeerListV6,
+    };
+
+    use super::*;
+
+    fn check_response(config: &Config, response: Response) {
+        let mut buffer = RESPONSE_HEADER.clone();
+
+        let len =
+            write_response_to_buffer(&mut buffer, &response, config.network.max_response_size)
+                .unwrap();
+
+        assert!(len > INITIAL_RESPONSE_BUFFER_SIZE);
+
+        let mut headers = [httparse::EMPTY_HEADER; 4];
+        let mut parsed = httparse::Response::new(&mut headers);
+
+        let body_start = match parsed.parse(&buffer[..len]).unwrap() {
+            httparse::Status::Complete(body_start) => body_start,
+            httparse::Status::Partial => panic!("partial response"),
+        };
+
+        let content_len: usize = ::std::str::from_utf8(parsed.headers[0].value)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        assert_eq!(body_start + content_len, len);
+
+        // Response exceeding limit is rejected
+        assert!(matches!(
+            write_response_to_buffer(&mut buffer, &response, len - 1),
+            Err(ConnectionError::ResponseBufferFull)
+        ));
+    }
+
+    #[test]
+    fn test_write_max_peers_announce_response() {
+        let mut config = Config::default();
+
+        config.protocol.max_peers = 500;
+
+        let peers6 = (0..config.protocol.max_peers)
+            .map(|i| ResponsePeer {
+                ip_address: Ipv6Addr::from(i as u128),
+                port: i as u16,
+            })
+            .collect();
+
+        check_response(
+            &config,
+            Response::Announce(AnnounceResponse {
+                announce_interval: config.protocol.peer_announce_interval,
+                complete: 1,
+                incomplete: 2,
+                peers: ResponsePeerListV4(Vec::new()),
+                peers6: ResponsePeerListV6(peers6),
+                warning_message: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_write_max_info_hashes_scrape_response() {
+        let config = Config::default();
+
+        let files = (0..config.protocol.max_scrape_torrents)
+            .map(|i| {
+                let statistics = ScrapeStatistics {
+                    complete: i,
+                    incomplete: i,
+                    downloaded: 0,
+                    ipv4: None,
+                    ipv6: None,
+                };
+
+                (InfoHash([i as u8; 20]), statistics)
+            })
+            .collect();
+
+        check_response(&config, Response::Scrape(ScrapeResponse { files }));
+    }
 }
@@ -1,20 +1,27 @@
 mod connection;
 mod request;
+#[cfg(feature = "tokio")]
+mod tokio_runtime;
 
 use std::cell::RefCell;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
+use aquatic_common::connection_table::{
+    ConnectionTableEntry, ConnectionTableSnapshot, ConnectionTables,
+};
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::routing::WorkerSenders;
 use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::shutdown::ShutdownSignal;
 use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
 use arc_swap::ArcSwap;
 use futures_lite::future::race;
 use futures_lite::StreamExt;
-use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
+use glommio::channels::channel_mesh::{MeshBuilder, Partial};
 use glommio::channels::local_channel::{new_bounded, LocalSender};
 use glommio::net::TcpListener;
 use glommio::timer::TimerActionRepeat;
@@ -23,11 +30,20 @@ use slotmap::HopSlotMap;
 
 use crate::common::*;
 use crate::config::Config;
-use crate::workers::socket::connection::{run_connection, ConnectionError};
+use crate::workers::socket::connection::{run_connection, ConnectionError, RequestSenders};
+
+#[cfg(feature = "tokio")]
+pub use self::tokio_runtime::run_socket_worker_on_tokio;
+
+/// How often to check if shutdown has been triggered
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for connections to close on shutdown
+const CONNECTION_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 struct ConnectionHandle {
     close_conn_sender: LocalSender<()>,
     valid_until: Rc<RefCell<ValidUntil>>,
+    table_entry: ConnectionTableEntry,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -37,19 +53,22 @@ pub async fn run_socket_worker(
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     priv_dropper: PrivilegeDropper,
+    statistics: SocketWorkerStatistics,
     server_start_instant: ServerStartInstant,
     worker_index: usize,
 ) -> anyhow::Result<()> {
     let config = Rc::new(config);
-    let access_list = state.access_list;
+    let access_lists = state.access_lists;
+    let static_pages = state.static_pages;
+    let user_agent_filter = state.user_agent_filter;
+    let connection_tables = state.connection_tables;
+    let shutdown = state.shutdown;
+    let statistics = Rc::new(statistics);
 
     let listener = create_tcp_listener(&config, priv_dropper).context("create tcp listener")?;
 
-    let (request_senders, _) = request_mesh_builder
-        .join(Role::Producer)
-        .await
-        .map_err(|err| anyhow::anyhow!("join request mesh: {:#}", err))?;
-    let request_senders = Rc::new(request_senders);
+    let request_senders =
+        RequestSenders(WorkerSenders::join_mesh(request_mesh_builder, "request").await?);
 
     let connection_handles = Rc::new(RefCell::new(HopSlotMap::with_key()));
 
@@ -57,104 +76,149 @@ pub async fn run_socket_worker(
         clean_connections(
             config.clone(),
             connection_handles.clone(),
+            connection_tables.clone(),
             server_start_instant,
+            worker_index,
         )
     }));
 
     let mut incoming = listener.incoming();
 
-    while let Some(stream) = incoming.next().await {
-        match stream {
-            Ok(stream) => {
-                let (close_conn_sender, close_conn_receiver) = new_bounded(1);
-
-                let valid_until = Rc::new(RefCell::new(ValidUntil::new(
-                    server_start_instant,
-                    config.cleaning.max_connection_idle,
-                )));
-
-                let connection_id = connection_handles.borrow_mut().insert(ConnectionHandle {
-                    close_conn_sender,
-                    valid_until: valid_until.clone(),
-                });
-
-                spawn_local(enclose!(
-                    (
-                        config,
-                        access_list,
-                        request_senders,
-                        opt_tls_config,
-                        connection_handles,
-                        valid_until,
-                    )
-                    async move {
-                        #[cfg(feature = "metrics")]
-                        let active_connections_gauge = ::metrics::gauge!(
-                            "aquatic_active_connections",
-                            "worker_index" => worker_index.to_string(),
-                        );
-
-                        #[cfg(feature = "metrics")]
-                        active_connections_gauge.increment(1.0);
-
-                        let f1 = async { run_connection(
+    let accept_connections = async {
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    let remote_addr = match stream.peer_addr() {
+                        Ok(remote_addr) => remote_addr,
+                        Err(err) => {
+                            log_connection_result(Err(ConnectionError::NoSocketPeerAddr(
+                                err.to_string(),
+                            )));
+
+                            continue;
+                        }
+                    };
+
+                    let (close_conn_sender, close_conn_receiver) = new_bounded(1);
+
+                    let valid_until = Rc::new(RefCell::new(ValidUntil::new(
+                        server_start_instant,
+                        config.cleaning.max_connection_idle,
+                    )));
+                    let table_entry = ConnectionTableEntry::new(
+                        CanonicalSocketAddr::new(remote_addr).get().ip(),
+                        server_start_instant.seconds_elapsed(),
+                    );
+                    let connection_state = table_entry.state.clone();
+
+                    let connection_id = connection_handles.borrow_mut().insert(ConnectionHandle {
+                        close_conn_sender,
+                        valid_until: valid_until.clone(),
+                        table_entry,
+                    });
+
+                    spawn_local(enclose!(
+                        (
+                            config,
+                            access_lists,
+                            static_pages,
+                            user_agent_filter,
+                            request_senders,
+                            opt_tls_config,
+                            connection_handles,
+                            valid_until,
+                            statistics,
+                        )
+                        async move {
+                            #[cfg(feature = "metrics")]
+                            let active_connections_gauge = ::metrics::gauge!(
+                                aquatic_metrics::names::ACTIVE_CONNECTIONS,
+                                "worker_index" => worker_index.to_string(),
+                            );
+
+                            #[cfg(feature = "metrics")]
+                            active_connections_gauge.increment(1.0);
+
+                            statistics.active_connections.increment(1);
+
+                            let f1 = run_connection(
                                 config,
-                                access_list,
+                                access_lists,
+                                static_pages,
+                                user_agent_filter,
                                 request_senders,
+                                statistics.clone(),
                                 server_start_instant,
                                 opt_tls_config,
                                 valid_until.clone(),
+                                connection_state,
                                 stream,
+                                remote_addr,
                                 worker_index,
-                            ).await
-                        };
-                        let f2 = async {
-                            close_conn_receiver.recv().await;
-
-                            Err(ConnectionError::Inactive)
-                        };
-
-                        let result = race(f1, f2).await;
-
-                        #[cfg(feature = "metrics")]
-                        active_connections_gauge.decrement(1.0);
-
-                        match result {
-                            Ok(()) => (),
-                            Err(err@(
-                                ConnectionError::ResponseBufferWrite(_) |
-                                ConnectionError::ResponseBufferFull |
-                                ConnectionError::ScrapeChannelError(_) |
-                                ConnectionError::ResponseSenderClosed
-                            )) => {
-                                ::log::error!("connection closed: {:#}", err);
-                            }
-                            Err(err@ConnectionError::RequestBufferFull) => {
-                                ::log::info!("connection closed: {:#}", err);
-                            }
-                            Err(err) => {
-                                ::log::debug!("connection closed: {:#}", err);
-                            }
-                        }
+                            );
+                            let f2 = async {
+                                close_conn_receiver.recv().await;
 
-                        connection_handles.borrow_mut().remove(connection_id);
-                    }
-                ))
-                .detach();
-            }
-            Err(err) => {
-                ::log::error!("accept connection: {:?}", err);
+                                Err(ConnectionError::Inactive)
+                            };
+
+                            let result = race(f1, f2).await;
+
+                            #[cfg(feature = "metrics")]
+                            active_connections_gauge.decrement(1.0);
+
+                            statistics.active_connections.decrement(1);
+
+                            log_connection_result(result);
+
+                            connection_handles.borrow_mut().remove(connection_id);
+                        }
+                    ))
+                    .detach();
+                }
+                Err(err) => {
+                    ::log::error!("accept connection: {:?}", err);
+                }
             }
         }
-    }
+    };
+
+    race(accept_connections, wait_for_shutdown(&shutdown)).await;
+
+    // Connections hold request senders, which need to be dropped for swarm
+    // workers to notice that request streams have ended
+    close_connections(&connection_handles).await;
 
     Ok(())
 }
 
+async fn wait_for_shutdown(shutdown: &ShutdownSignal) {
+    while !shutdown.is_triggered() {
+        glommio::timer::sleep(SHUTDOWN_CHECK_INTERVAL).await;
+    }
+}
+
+async fn close_connections(
+    connection_handles: &RefCell<HopSlotMap<ConnectionId, ConnectionHandle>>,
+) {
+    for handle in connection_handles.borrow().values() {
+        let _ = handle.close_conn_sender.try_send(());
+    }
+
+    let deadline = Instant::now() + CONNECTION_CLOSE_TIMEOUT;
+
+    // Connection tasks remove their handles when they finish
+    while !connection_handles.borrow().is_empty() && Instant::now() < deadline {
+        glommio::timer::sleep(Duration::from_millis(10)).await;
+    }
+}
+
 async fn clean_connections(
     config: Rc<Config>,
     connection_slab: Rc<RefCell<HopSlotMap<ConnectionId, ConnectionHandle>>>,
+    connection_tables: Arc<ConnectionTables>,
     server_start_instant: ServerStartInstant,
+    worker_index: usize,
 ) -> Option<Duration> {
     let now = server_start_instant.seconds_elapsed();
 
@@ -168,15 +232,80 @@ async fn clean_connections(
         }
     });
 
+    if config.diagnostics.run_connection_table_endpoint {
+        update_connection_table(
+            &config,
+            &connection_tables,
+            server_start_instant,
+            worker_index,
+            connection_slab
+                .borrow()
+                .values()
+                .map(|handle| (&handle.table_entry, *handle.valid_until.borrow())),
+        );
+    }
+
     Some(Duration::from_secs(
         config.cleaning.connection_cleaning_interval,
     ))
 }
 
+/// Store summary of connections (with their current expiry times) in
+/// connection tables
+fn update_connection_table<'a>(
+    config: &Config,
+    connection_tables: &ConnectionTables,
+    server_start_instant: ServerStartInstant,
+    worker_index: usize,
+    connections: impl Iterator<Item = (&'a ConnectionTableEntry, ValidUntil)>,
+) {
+    let now = server_start_instant.seconds_elapsed();
+
+    let snapshot = ConnectionTableSnapshot::new(
+        now,
+        connections.map(|(entry, valid_until)| {
+            entry.info(now, valid_until, config.cleaning.max_connection_idle)
+        }),
+        config.diagnostics.top_source_ips,
+        config.network.ipv6_prefix_len,
+    );
+
+    connection_tables.update(worker_index, snapshot);
+}
+
+fn log_connection_result(result: Result<(), ConnectionError>) {
+    match result {
+        Ok(()) => (),
+        Err(
+            err @ (ConnectionError::ResponseBufferWrite(_)
+            | ConnectionError::ResponseBufferFull
+            | ConnectionError::ScrapeChannelError(_)
+            | ConnectionError::ResponseSenderClosed),
+        ) => {
+            ::log::error!("connection closed: {:#}", err);
+        }
+        Err(err @ ConnectionError::RequestTooLarge) => {
+            ::log::info!("connection closed: {:#}", err);
+        }
+        Err(err) => {
+            ::log::debug!("connection closed: {:#}", err);
+        }
+    }
+}
+
 fn create_tcp_listener(
     config: &Config,
     priv_dropper: PrivilegeDropper,
 ) -> anyhow::Result<TcpListener> {
+    let socket = create_tcp_listener_socket(config, priv_dropper)?;
+
+    Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
+}
+
+fn create_tcp_listener_socket(
+    config: &Config,
+    priv_dropper: PrivilegeDropper,
+) -> anyhow::Result<socket2::Socket> {
     let domain = if config.network.address.is_ipv4() {
         socket2::Domain::IPV4
     } else {
@@ -195,6 +324,18 @@ fn create_tcp_listener(
         .set_reuse_port(true)
         .with_context(|| "socket: set reuse port")?;
 
+    if !config.network.bind_device.is_empty() {
+        #[cfg(target_os = "linux")]
+        socket
+            .bind_device(Some(config.network.bind_device.as_bytes()))
+            .with_context(|| format!("socket: bind to device {}", config.network.bind_device))?;
+
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "network.bind_device is only supported on Linux"
+        ));
+    }
+
     socket
         .bind(&config.network.address.into())
         .with_context(|| format!("socket: bind to {}", config.network.address))?;
@@ -205,7 +346,7 @@ fn create_tcp_listener(
 
     priv_dropper.after_socket_creation()?;
 
-    Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
+    Ok(socket)
 }
 
 #[cfg(feature = "metrics")]
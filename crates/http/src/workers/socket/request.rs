@@ -1,8 +1,9 @@
 use std::net::IpAddr;
 
 use anyhow::Context;
-use aquatic_http_protocol::request::Request;
+use aquatic_http_protocol::request::{AnnounceRequest, Request, ScrapeRequest};
 
+use crate::common::{StaticPages, StaticResponse, TrackerIndex};
 use crate::config::{Config, ReverseProxyPeerIpHeaderFormat};
 
 #[derive(Debug, thiserror::Error)]
@@ -11,21 +12,88 @@ pub enum RequestParseError {
     RequiredPeerIpHeaderMissing(anyhow::Error),
     #[error("more data needed")]
     MoreDataNeeded,
+    #[error("path not found")]
+    NotFound,
+    #[error("method not allowed")]
+    MethodNotAllowed,
+    /// Request can be answered with static response instead of being
+    /// handled by tracker
+    #[error("static response requested")]
+    StaticResponse(StaticResponse),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
-pub fn parse_request(
+#[derive(Debug)]
+pub struct ParsedRequest<'a> {
+    pub request: Request,
+    /// Set if running behind reverse proxy
+    pub opt_peer_ip: Option<IpAddr>,
+    pub tracker: TrackerIndex,
+    /// User-Agent header value
+    pub opt_user_agent: Option<&'a [u8]>,
+}
+
+pub fn parse_request<'a>(
     config: &Config,
-    buffer: &[u8],
-) -> Result<(Request, Option<IpAddr>), RequestParseError> {
+    static_pages: &StaticPages,
+    buffer: &'a [u8],
+) -> Result<ParsedRequest<'a>, RequestParseError> {
     let mut headers = [httparse::EMPTY_HEADER; 16];
     let mut http_request = httparse::Request::new(&mut headers);
 
     match http_request.parse(buffer).with_context(|| "httparse")? {
         httparse::Status::Complete(_) => {
+            let head = match http_request.method {
+                Some("GET") => false,
+                Some("HEAD") => true,
+                Some("OPTIONS") => {
+                    return Err(RequestParseError::StaticResponse(StaticResponse::Options))
+                }
+                _ => return Err(RequestParseError::MethodNotAllowed),
+            };
+
             let path = http_request.path.ok_or(anyhow::anyhow!("no http path"))?;
-            let request = Request::parse_http_get_path(path)?;
+
+            let opt_host = http_request
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("host"))
+                .and_then(|header| ::std::str::from_utf8(header.value).ok());
+
+            let full_path = path;
+            let (tracker, path) = config.route_request(opt_host, path);
+
+            ::log::debug!("request GET path: {}", path);
+
+            let (location, query_string) = path.split_once('?').unwrap_or((path, ""));
+
+            let is_tracker_path = location == config.protocol.announce_path
+                || location == config.protocol.scrape_path;
+
+            // Monitoring systems often probe with HEAD requests. These must
+            // not have side effects, so announce requests are not handled.
+            if head && is_tracker_path {
+                return Err(RequestParseError::StaticResponse(
+                    StaticResponse::TrackerHead,
+                ));
+            }
+
+            let request = if location == config.protocol.announce_path {
+                Request::Announce(AnnounceRequest::parse_query_string_with_policy(
+                    query_string,
+                    config.protocol.duplicate_parameter_policy.into(),
+                )?)
+            } else if location == config.protocol.scrape_path {
+                Request::Scrape(ScrapeRequest::parse_query_string(query_string)?)
+            } else if let Some(page) = static_pages.route(full_path) {
+                return Err(RequestParseError::StaticResponse(StaticResponse::Page {
+                    page,
+                    head,
+                }));
+            } else {
+                return Err(RequestParseError::NotFound);
+            };
 
             let opt_peer_ip = if config.network.runs_behind_reverse_proxy {
                 let header_name = &config.network.reverse_proxy_ip_header_name;
@@ -41,12 +109,36 @@ pub fn parse_request(
                 None
             };
 
-            Ok((request, opt_peer_ip))
+            let opt_user_agent = http_request
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("user-agent"))
+                .map(|header| header.value);
+
+            Ok(ParsedRequest {
+                request,
+                opt_peer_ip,
+                tracker,
+                opt_user_agent,
+            })
         }
         httparse::Status::Partial => Err(RequestParseError::MoreDataNeeded),
     }
 }
 
+/// Check if buffer contains the end of the request headers, only searching
+/// data starting at `new_data_start` (and the bytes preceding it that could
+/// be part of the terminator)
+pub fn headers_complete(buffer: &[u8], new_data_start: usize) -> bool {
+    const TERMINATOR: &[u8] = b"\r\n\r\n";
+
+    let search_start = new_data_start.saturating_sub(TERMINATOR.len() - 1);
+
+    buffer[search_start..]
+        .windows(TERMINATOR.len())
+        .any(|window| window == TERMINATOR)
+}
+
 fn parse_forwarded_header(
     header_name: &str,
     header_format: ReverseProxyPeerIpHeaderFormat,
@@ -73,8 +165,282 @@ fn parse_forwarded_header(
 
 #[cfg(test)]
 mod tests {
+    use crate::common::StaticPage;
+    use crate::config::DuplicateParameterPolicy;
+
     use super::*;
 
+    #[test]
+    fn test_headers_complete() {
+        let request = format!("{}\r\n", REQUEST_START);
+        let request = request.as_bytes();
+
+        assert!(headers_complete(request, 0));
+        assert!(!headers_complete(&request[..request.len() - 1], 0));
+
+        // Terminator split over two reads
+        assert!(headers_complete(request, request.len() - 2));
+
+        // Terminator only in previously searched data
+        let request_with_extra = [request, b"abcd"].concat();
+
+        assert!(!headers_complete(&request_with_extra, request.len() + 1));
+    }
+
+    #[test]
+    fn test_parse_long_scrape_request() {
+        let config = Config::default();
+
+        let mut request = "GET /scrape?".to_string();
+
+        for i in 0..config.protocol.max_scrape_torrents {
+            if i != 0 {
+                request.push('&');
+            }
+
+            request.push_str("info_hash=");
+
+            for _ in 0..20 {
+                request.push_str(&format!("%{:02x}", i));
+            }
+        }
+
+        request.push_str(" HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        assert!(request.len() > 2048);
+        assert!(request.len() <= config.network.max_request_size);
+
+        match parse_request(&config, &static_pages(&config), request.as_bytes())
+            .unwrap()
+            .request
+        {
+            Request::Scrape(request) => {
+                assert_eq!(
+                    request.info_hashes.len(),
+                    config.protocol.max_scrape_torrents
+                )
+            }
+            _ => panic!("not a scrape request"),
+        }
+    }
+
+    #[test]
+    fn test_parse_duplicate_parameters() {
+        let mut config = Config::default();
+
+        let request = REQUEST_START.replace("&port=12345", "&port=12345&port=1");
+        let request = format!("{}\r\n", request);
+
+        assert!(matches!(
+            parse_request(&config, &static_pages(&config), request.as_bytes()),
+            Err(RequestParseError::Other(_))
+        ));
+
+        for (policy, expected_port) in [
+            (DuplicateParameterPolicy::First, 12345),
+            (DuplicateParameterPolicy::Last, 1),
+        ] {
+            config.protocol.duplicate_parameter_policy = policy;
+
+            match parse_request(&config, &static_pages(&config), request.as_bytes())
+                .unwrap()
+                .request
+            {
+                Request::Announce(request) => assert_eq!(request.port, expected_port),
+                _ => panic!("not an announce request"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_custom_paths() {
+        let mut config = Config::default();
+
+        config.protocol.announce_path = "/a".into();
+        config.protocol.scrape_path = "/s".into();
+
+        let request = format!("{}\r\n", REQUEST_START);
+
+        assert!(matches!(
+            parse_request(&config, &static_pages(&config), request.as_bytes()),
+            Err(RequestParseError::NotFound)
+        ));
+
+        let request = request.replacen("/announce?", "/a?", 1);
+
+        assert!(matches!(
+            parse_request(&config, &static_pages(&config), request.as_bytes())
+                .unwrap()
+                .request,
+            Request::Announce(_)
+        ));
+
+        let request = "GET /s?info_hash=%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00 HTTP/1.1\r\n\r\n";
+
+        assert!(matches!(
+            parse_request(&config, &static_pages(&config), request.as_bytes())
+                .unwrap()
+                .request,
+            Request::Scrape(_)
+        ));
+
+        // Only exact matches are accepted
+        for path in ["/", "/a/", "/ab?", "/announce", "/favicon.ico"] {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            assert!(matches!(
+                parse_request(&config, &static_pages(&config), request.as_bytes()),
+                Err(RequestParseError::NotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_static_pages_and_methods() {
+        let mut config = Config::default();
+
+        let landing_page_path = ::std::env::temp_dir().join(format!(
+            "aquatic_http_test_landing_page_{}.html",
+            ::std::process::id()
+        ));
+
+        ::std::fs::write(&landing_page_path, "<h1>Tracker</h1>").unwrap();
+
+        config.static_pages.landing_page_path = landing_page_path.clone();
+
+        let static_pages = static_pages(&config);
+
+        ::std::fs::remove_file(landing_page_path).unwrap();
+
+        for (path, expected_page) in [
+            ("/", StaticPage::Landing),
+            ("/?a=b", StaticPage::Landing),
+            ("/robots.txt", StaticPage::RobotsTxt),
+        ] {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            match parse_request(&config, &static_pages, request.as_bytes()) {
+                Err(RequestParseError::StaticResponse(response)) => {
+                    assert_eq!(
+                        response,
+                        StaticResponse::Page {
+                            page: expected_page,
+                            head: false
+                        }
+                    );
+                    assert!(static_pages
+                        .response_bytes(response)
+                        .starts_with(b"HTTP/1.1 200 OK\r\n"));
+                }
+                _ => panic!("static page not routed to: {}", path),
+            }
+        }
+
+        for method in ["POST", "PUT", "DELETE"] {
+            let request = format!("{} /announce HTTP/1.1\r\n\r\n", method);
+
+            assert!(matches!(
+                parse_request(&config, &static_pages, request.as_bytes()),
+                Err(RequestParseError::MethodNotAllowed)
+            ));
+        }
+
+        // Disabled pages are not found
+        let static_pages = self::static_pages(&Config::default());
+
+        for path in ["/", "/index.html"] {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            assert!(matches!(
+                parse_request(&config, &static_pages, request.as_bytes()),
+                Err(RequestParseError::NotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_user_agent() {
+        let config = Config::default();
+        let static_pages = static_pages(&config);
+
+        let request = format!("{}user-agent: qBittorrent/4.6.2\r\n\r\n", REQUEST_START);
+
+        assert_eq!(
+            parse_request(&config, &static_pages, request.as_bytes())
+                .unwrap()
+                .opt_user_agent,
+            Some(&b"qBittorrent/4.6.2"[..])
+        );
+
+        let request = format!("{}\r\n", REQUEST_START);
+
+        assert_eq!(
+            parse_request(&config, &static_pages, request.as_bytes())
+                .unwrap()
+                .opt_user_agent,
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_head_and_options() {
+        let config = Config::default();
+        let static_pages = static_pages(&config);
+
+        let parse = |request: &str| {
+            parse_request(&config, &static_pages, request.as_bytes()).map(|parsed| parsed.request)
+        };
+
+        // Announce requests are not handled, even if valid
+        let request = format!("{}\r\n", REQUEST_START.replacen("GET", "HEAD", 1));
+
+        assert!(matches!(
+            parse(&request),
+            Err(RequestParseError::StaticResponse(
+                StaticResponse::TrackerHead
+            ))
+        ));
+        assert!(matches!(
+            parse("HEAD /scrape HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::StaticResponse(
+                StaticResponse::TrackerHead
+            ))
+        ));
+
+        match parse("HEAD /robots.txt HTTP/1.1\r\n\r\n") {
+            Err(RequestParseError::StaticResponse(response)) => {
+                let head_bytes = static_pages.response_bytes(response);
+                let get_bytes = static_pages.response_bytes(StaticResponse::Page {
+                    page: StaticPage::RobotsTxt,
+                    head: false,
+                });
+
+                assert!(head_bytes.ends_with(b"\r\n\r\n"));
+                assert!(get_bytes.len() > head_bytes.len());
+                assert!(get_bytes.starts_with(head_bytes));
+            }
+            _ => panic!("static page not routed to"),
+        }
+
+        assert!(matches!(
+            parse("HEAD /other HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::NotFound)
+        ));
+
+        for path in ["*", "/announce"] {
+            let request = format!("OPTIONS {} HTTP/1.1\r\n\r\n", path);
+
+            assert!(matches!(
+                parse(&request),
+                Err(RequestParseError::StaticResponse(StaticResponse::Options))
+            ));
+        }
+    }
+
+    fn static_pages(config: &Config) -> StaticPages {
+        StaticPages::new(config).unwrap()
+    }
+
     const REQUEST_START: &str = "GET /announce?info_hash=%04%0bkV%3f%5cr%14%a6%b7%98%adC%c3%c9.%40%24%00%b9&peer_id=-ABC940-5ert69muw5t8&port=12345&uploaded=1&downloaded=2&left=3&numwant=0&key=4ab4b877&compact=1&supportcrypto=1&event=started HTTP/1.1\r\nHost: example.com\r\n";
 
     #[test]
@@ -94,9 +460,9 @@ mod tests {
         let expected_ip = IpAddr::from([9, 10, 11, 12]);
 
         assert_eq!(
-            parse_request(&config, request.as_bytes())
+            parse_request(&config, &static_pages(&config), request.as_bytes())
                 .unwrap()
-                .1
+                .opt_peer_ip
                 .unwrap(),
             expected_ip
         )
@@ -119,9 +485,9 @@ mod tests {
         let expected_ip = IpAddr::from([200, 0, 0, 1]);
 
         assert_eq!(
-            parse_request(&config, request.as_bytes())
+            parse_request(&config, &static_pages(&config), request.as_bytes())
                 .unwrap()
-                .1
+                .opt_peer_ip
                 .unwrap(),
             expected_ip
         )
@@ -137,7 +503,7 @@ mod tests {
 
         request.push_str("\r\n");
 
-        let res = parse_request(&config, request.as_bytes());
+        let res = parse_request(&config, &static_pages(&config), request.as_bytes());
 
         assert!(matches!(
             res,
@@ -0,0 +1,231 @@
+//! Socket worker running on tokio instead of glommio
+//!
+//! Requests are passed to the swarm workers over flume channels, while
+//! connection handling is shared with the glommio socket worker.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::connection_table::{ConnectionTableEntry, ConnectionTables};
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::routing::WorkerSenders;
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
+use arc_swap::ArcSwap;
+use futures::channel::oneshot;
+use futures_lite::future::race;
+use slotmap::HopSlotMap;
+use tokio::net::TcpListener;
+use tokio::task::{spawn_local, LocalSet};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::connection::{run_connection, ConnectionError, RequestSenders};
+use super::{
+    create_tcp_listener_socket, log_connection_result, update_connection_table,
+    SHUTDOWN_CHECK_INTERVAL,
+};
+
+struct ConnectionHandle {
+    close_conn_sender: Option<oneshot::Sender<()>>,
+    valid_until: Rc<RefCell<ValidUntil>>,
+    table_entry: ConnectionTableEntry,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_socket_worker_on_tokio(
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    request_senders: Vec<flume::Sender<ChannelRequest>>,
+    priv_dropper: PrivilegeDropper,
+    statistics: SocketWorkerStatistics,
+    server_start_instant: ServerStartInstant,
+    worker_index: usize,
+) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("build tokio runtime")?;
+
+    let config = Rc::new(config);
+
+    let socket =
+        create_tcp_listener_socket(&config, priv_dropper).context("create tcp listener")?;
+
+    socket
+        .set_nonblocking(true)
+        .context("socket: set nonblocking")?;
+
+    let request_senders = RequestSenders(WorkerSenders::Flume(request_senders.into()));
+    let access_lists = state.access_lists;
+    let static_pages = state.static_pages;
+    let user_agent_filter = state.user_agent_filter;
+    let connection_tables = state.connection_tables;
+    let shutdown = state.shutdown;
+    let statistics = Rc::new(statistics);
+
+    LocalSet::new().block_on(&runtime, async move {
+        let listener =
+            TcpListener::from_std(socket.into()).context("register tcp listener with tokio")?;
+
+        let connection_handles = Rc::new(RefCell::new(HopSlotMap::with_key()));
+
+        spawn_local(clean_connections(
+            config.clone(),
+            connection_handles.clone(),
+            connection_tables,
+            server_start_instant,
+            worker_index,
+        ));
+
+        loop {
+            let accept = async { Some(listener.accept().await) };
+            let check_shutdown = async {
+                wait_for_shutdown(&shutdown).await;
+
+                None
+            };
+
+            // Connection tasks, which hold request senders, are dropped
+            // along with the LocalSet on return
+            let (stream, remote_addr) = match race(accept, check_shutdown).await {
+                Some(Ok(stream_and_addr)) => stream_and_addr,
+                Some(Err(err)) => {
+                    ::log::error!("accept connection: {:?}", err);
+
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            let (close_conn_sender, close_conn_receiver) = oneshot::channel();
+
+            let valid_until = Rc::new(RefCell::new(ValidUntil::new(
+                server_start_instant,
+                config.cleaning.max_connection_idle,
+            )));
+
+            let table_entry = ConnectionTableEntry::new(
+                CanonicalSocketAddr::new(remote_addr).get().ip(),
+                server_start_instant.seconds_elapsed(),
+            );
+            let connection_state = table_entry.state.clone();
+
+            let connection_id = connection_handles.borrow_mut().insert(ConnectionHandle {
+                close_conn_sender: Some(close_conn_sender),
+                valid_until: valid_until.clone(),
+                table_entry,
+            });
+
+            let config = config.clone();
+            let access_lists = access_lists.clone();
+            let static_pages = static_pages.clone();
+            let user_agent_filter = user_agent_filter.clone();
+            let request_senders = request_senders.clone();
+            let opt_tls_config = opt_tls_config.clone();
+            let connection_handles = connection_handles.clone();
+            let statistics = statistics.clone();
+
+            spawn_local(async move {
+                #[cfg(feature = "metrics")]
+                let active_connections_gauge = ::metrics::gauge!(
+                    aquatic_metrics::names::ACTIVE_CONNECTIONS,
+                    "worker_index" => worker_index.to_string(),
+                );
+
+                #[cfg(feature = "metrics")]
+                active_connections_gauge.increment(1.0);
+
+                statistics.active_connections.increment(1);
+
+                let f1 = run_connection(
+                    config,
+                    access_lists,
+                    static_pages,
+                    user_agent_filter,
+                    request_senders,
+                    statistics.clone(),
+                    server_start_instant,
+                    opt_tls_config,
+                    valid_until,
+                    connection_state,
+                    stream.compat(),
+                    remote_addr,
+                    worker_index,
+                );
+                let f2 = async {
+                    let _ = close_conn_receiver.await;
+
+                    Err(ConnectionError::Inactive)
+                };
+
+                let result = race(f1, f2).await;
+
+                #[cfg(feature = "metrics")]
+                active_connections_gauge.decrement(1.0);
+
+                statistics.active_connections.decrement(1);
+
+                log_connection_result(result);
+
+                connection_handles.borrow_mut().remove(connection_id);
+            });
+        }
+    })
+}
+
+async fn wait_for_shutdown(shutdown: &ShutdownSignal) {
+    while !shutdown.is_triggered() {
+        tokio::time::sleep(SHUTDOWN_CHECK_INTERVAL).await;
+    }
+}
+
+async fn clean_connections(
+    config: Rc<Config>,
+    connection_handles: Rc<RefCell<HopSlotMap<ConnectionId, ConnectionHandle>>>,
+    connection_tables: Arc<ConnectionTables>,
+    server_start_instant: ServerStartInstant,
+    worker_index: usize,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.cleaning.connection_cleaning_interval.max(1),
+    ));
+
+    loop {
+        interval.tick().await;
+
+        let now = server_start_instant.seconds_elapsed();
+
+        connection_handles.borrow_mut().retain(|_, handle| {
+            if handle.valid_until.borrow().valid(now) {
+                true
+            } else {
+                if let Some(sender) = handle.close_conn_sender.take() {
+                    let _ = sender.send(());
+                }
+
+                false
+            }
+        });
+
+        if config.diagnostics.run_connection_table_endpoint {
+            update_connection_table(
+                &config,
+                &connection_tables,
+                server_start_instant,
+                worker_index,
+                connection_handles
+                    .borrow()
+                    .values()
+                    .map(|handle| (&handle.table_entry, *handle.valid_until.borrow())),
+            );
+        }
+    }
+}
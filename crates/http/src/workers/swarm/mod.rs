@@ -1,9 +1,12 @@
+mod scheduling;
 mod storage;
 
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use futures_lite::future::poll_once;
 use futures_lite::{Stream, StreamExt};
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
 use glommio::timer::TimerActionRepeat;
@@ -11,36 +14,52 @@ use glommio::{enclose, prelude::*};
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
 
-use aquatic_common::{ServerStartInstant, ValidUntil};
+use aquatic_common::observer::{AnnounceEvent, AnnounceKind, AnnounceSubscribers};
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant, ValidUntil};
+use aquatic_http_protocol::common::AnnounceEvent as RequestEvent;
+use aquatic_http_protocol::request::AnnounceRequest;
 
 use crate::common::*;
 use crate::config::Config;
 
+use self::scheduling::RequestQueues;
 use self::storage::TorrentMaps;
 
+/// Source of requests from socket workers
+pub enum RequestReceiver {
+    Mesh(MeshBuilder<ChannelRequest, Partial>),
+    /// Shared by all socket workers running on tokio
+    #[cfg(feature = "tokio")]
+    Flume(flume::Receiver<ChannelRequest>),
+}
+
 pub async fn run_swarm_worker(
     config: Config,
     state: State,
-    request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
+    request_receiver: RequestReceiver,
+    statistics: SwarmWorkerStatistics,
     server_start_instant: ServerStartInstant,
     worker_index: usize,
 ) -> anyhow::Result<()> {
-    let (_, mut request_receivers) = request_mesh_builder
-        .join(Role::Consumer)
-        .await
-        .map_err(|err| anyhow::anyhow!("join request mesh: {:#}", err))?;
-
-    let torrents = Rc::new(RefCell::new(TorrentMaps::new(worker_index)));
-    let access_list = state.access_list;
+    let torrents = Rc::new(RefCell::new(TorrentMaps::new(&config, worker_index)));
+    let access_lists = state.access_lists;
+    let swarm_summaries = state.swarm_summaries;
+    let announce_subscribers = state.announce_subscribers;
 
     // Periodically clean torrents
-    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
-        enclose!((config, torrents, access_list) move || async move {
-            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+    TimerActionRepeat::repeat(
+        enclose!((config, torrents, access_lists, swarm_summaries) move || {
+            enclose!((config, torrents, access_lists, swarm_summaries) move || async move {
+                let mut torrents = torrents.borrow_mut();
 
-            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
-        })()
-    }));
+                torrents.clean(&config, &access_lists, server_start_instant);
+
+                swarm_summaries.update(worker_index, torrents.summary());
+
+                Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            })()
+        }),
+    );
 
     let max_peer_age = config.cleaning.max_peer_age;
     let peer_valid_until = Rc::new(RefCell::new(ValidUntil::new(
@@ -67,18 +86,45 @@ pub async fn run_swarm_worker(
         })()
     }));
 
+    let statistics = Rc::new(statistics);
+
     let mut handles = Vec::new();
 
-    for (_, receiver) in request_receivers.streams() {
-        let handle = spawn_local(handle_request_stream(
-            config.clone(),
-            torrents.clone(),
-            peer_valid_until.clone(),
-            receiver,
-        ))
-        .detach();
+    match request_receiver {
+        RequestReceiver::Mesh(request_mesh_builder) => {
+            let (_, mut request_receivers) = request_mesh_builder
+                .join(Role::Consumer)
+                .await
+                .map_err(|err| anyhow::anyhow!("join request mesh: {:#}", err))?;
 
-        handles.push(handle);
+            for (_, receiver) in request_receivers.streams() {
+                let handle = spawn_local(handle_request_stream(
+                    config.clone(),
+                    torrents.clone(),
+                    peer_valid_until.clone(),
+                    statistics.clone(),
+                    announce_subscribers.clone(),
+                    receiver,
+                ))
+                .detach();
+
+                handles.push(handle);
+            }
+        }
+        #[cfg(feature = "tokio")]
+        RequestReceiver::Flume(receiver) => {
+            let handle = spawn_local(handle_request_stream(
+                config.clone(),
+                torrents.clone(),
+                peer_valid_until.clone(),
+                statistics.clone(),
+                announce_subscribers.clone(),
+                receiver.into_stream(),
+            ))
+            .detach();
+
+            handles.push(handle);
+        }
     }
 
     for handle in handles {
@@ -88,48 +134,153 @@ pub async fn run_swarm_worker(
     Ok(())
 }
 
+/// Handle requests from one stream
+///
+/// Available requests are moved to local queues, from which announce and
+/// scrape requests are handled in batches of configured sizes, so that
+/// bursts of one request type can't starve the other.
 async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
     peer_valid_until: Rc<RefCell<ValidUntil>>,
+    statistics: Rc<SwarmWorkerStatistics>,
+    announce_subscribers: AnnounceSubscribers,
     mut stream: S,
 ) where
     S: Stream<Item = ChannelRequest> + ::std::marker::Unpin,
 {
     let mut rng = SmallRng::from_entropy();
+    let mut queues = RequestQueues::new(
+        &config.scheduling,
+        statistics.queued_announce_requests.clone(),
+        statistics.queued_scrape_requests.clone(),
+    );
+    let max_queued_requests = config.scheduling.max_queued_requests.max(1);
+    let request_deadline = Some(config.scheduling.request_deadline_ms)
+        .filter(|ms| *ms != 0)
+        .map(Duration::from_millis);
+    let mut stream_ended = false;
 
-    while let Some(channel_request) = stream.next().await {
-        match channel_request {
-            ChannelRequest::Announce {
-                request,
-                peer_addr,
-                response_sender,
-            } => {
-                let response = torrents.borrow_mut().handle_announce_request(
-                    &config,
-                    &mut rng,
-                    peer_valid_until.borrow().to_owned(),
-                    peer_addr,
-                    request,
-                );
-
-                if let Err(err) = response_sender.connect().await.send(response).await {
-                    ::log::error!("swarm worker could not send announce response: {:#}", err);
+    loop {
+        if queues.is_empty() {
+            if stream_ended {
+                break;
+            }
+
+            match stream.next().await {
+                Some(channel_request) => enqueue_request(&mut queues, channel_request),
+                None => break,
+            }
+        }
+
+        while !stream_ended && queues.len() < max_queued_requests {
+            match poll_once(stream.next()).await {
+                Some(Some(channel_request)) => enqueue_request(&mut queues, channel_request),
+                Some(None) => stream_ended = true,
+                None => break,
+            }
+        }
+
+        if let Some(channel_request) = queues.pop() {
+            if let Some(max_age) = request_deadline {
+                if channel_request.expired(Instant::now(), max_age) {
+                    match channel_request {
+                        ChannelRequest::Announce { .. } => {
+                            statistics.expired_announce_requests.increment(1)
+                        }
+                        ChannelRequest::Scrape { .. } => {
+                            statistics.expired_scrape_requests.increment(1)
+                        }
+                    }
+
+                    // Dropping response sender makes socket worker close
+                    // connection
+                    continue;
                 }
             }
-            ChannelRequest::Scrape {
-                request,
+
+            handle_request(
+                &config,
+                &torrents,
+                &peer_valid_until,
+                &announce_subscribers,
+                &mut rng,
+                channel_request,
+            )
+            .await;
+        }
+    }
+}
+
+fn enqueue_request(queues: &mut RequestQueues<ChannelRequest>, channel_request: ChannelRequest) {
+    match channel_request {
+        ChannelRequest::Announce { .. } => queues.push_announce(channel_request),
+        ChannelRequest::Scrape { .. } => queues.push_scrape(channel_request),
+    }
+}
+
+async fn handle_request(
+    config: &Config,
+    torrents: &RefCell<TorrentMaps>,
+    peer_valid_until: &RefCell<ValidUntil>,
+    announce_subscribers: &AnnounceSubscribers,
+    rng: &mut SmallRng,
+    channel_request: ChannelRequest,
+) {
+    match channel_request {
+        ChannelRequest::Announce {
+            request,
+            peer_addr,
+            tracker,
+            received_at: _,
+            response_sender,
+        } => {
+            announce_subscribers.publish(|| announce_event(&request, peer_addr));
+
+            let response = torrents.borrow_mut().handle_announce_request(
+                config,
+                rng,
+                peer_valid_until.borrow().to_owned(),
                 peer_addr,
-                response_sender,
-            } => {
-                let response = torrents
-                    .borrow_mut()
-                    .handle_scrape_request(&config, peer_addr, request);
-
-                if let Err(err) = response_sender.connect().await.send(response).await {
-                    ::log::error!("swarm worker could not send scrape response: {:#}", err);
-                }
+                tracker,
+                request,
+            );
+
+            if let Err(err) = response_sender.send(response).await {
+                ::log::error!("swarm worker could not send announce response: {:#}", err);
             }
-        };
+        }
+        ChannelRequest::Scrape {
+            request,
+            peer_addr,
+            tracker,
+            received_at: _,
+            response_sender,
+        } => {
+            let response = torrents
+                .borrow_mut()
+                .handle_scrape_request(config, peer_addr, tracker, request);
+
+            if let Err(err) = response_sender.send(response).await {
+                ::log::error!("swarm worker could not send scrape response: {:#}", err);
+            }
+        }
+    };
+}
+
+fn announce_event(request: &AnnounceRequest, peer_addr: CanonicalSocketAddr) -> AnnounceEvent {
+    let kind = match request.event {
+        RequestEvent::Started => AnnounceKind::Started,
+        RequestEvent::Stopped => AnnounceKind::Stopped,
+        RequestEvent::Completed => AnnounceKind::Completed,
+        RequestEvent::Empty => AnnounceKind::Update,
+    };
+
+    AnnounceEvent {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        peer_addr: Some(SocketAddr::new(peer_addr.get().ip(), request.port)),
+        kind,
+        bytes_left: Some(request.bytes_left as u64),
     }
 }
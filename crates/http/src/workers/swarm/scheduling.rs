@@ -0,0 +1,139 @@
+//! Type-aware scheduling of requests in swarm workers
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use aquatic_metrics::Gauge;
+
+use crate::config::SchedulingConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Turn {
+    Announce,
+    Scrape,
+}
+
+/// Local queues of announce and scrape requests, popped from in turns of
+/// configurable size
+pub struct RequestQueues<T> {
+    announce: VecDeque<T>,
+    scrape: VecDeque<T>,
+    announce_batch_size: usize,
+    scrape_batch_size: usize,
+    turn: Turn,
+    taken_in_turn: usize,
+    announce_gauge: Arc<Gauge>,
+    scrape_gauge: Arc<Gauge>,
+}
+
+impl<T> RequestQueues<T> {
+    pub fn new(
+        config: &SchedulingConfig,
+        announce_gauge: Arc<Gauge>,
+        scrape_gauge: Arc<Gauge>,
+    ) -> Self {
+        Self {
+            announce: Default::default(),
+            scrape: Default::default(),
+            announce_batch_size: config.announce_batch_size.max(1),
+            scrape_batch_size: config.scrape_batch_size.max(1),
+            turn: Turn::Announce,
+            taken_in_turn: 0,
+            announce_gauge,
+            scrape_gauge,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.announce.len() + self.scrape.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push_announce(&mut self, request: T) {
+        self.announce.push_back(request);
+        self.announce_gauge.increment(1);
+    }
+
+    pub fn push_scrape(&mut self, request: T) {
+        self.scrape.push_back(request);
+        self.scrape_gauge.increment(1);
+    }
+
+    /// Pop request of current turn, switching turns when current turn is
+    /// used up or there are no requests of its type
+    pub fn pop(&mut self) -> Option<T> {
+        // At most: finish exhausted turn, skip empty queue, take from other
+        for _ in 0..3 {
+            let (queue, gauge, batch_size) = match self.turn {
+                Turn::Announce => (
+                    &mut self.announce,
+                    &self.announce_gauge,
+                    self.announce_batch_size,
+                ),
+                Turn::Scrape => (&mut self.scrape, &self.scrape_gauge, self.scrape_batch_size),
+            };
+
+            if self.taken_in_turn < batch_size {
+                if let Some(request) = queue.pop_front() {
+                    self.taken_in_turn += 1;
+                    gauge.decrement(1);
+
+                    return Some(request);
+                }
+            }
+
+            self.turn = match self.turn {
+                Turn::Announce => Turn::Scrape,
+                Turn::Scrape => Turn::Announce,
+            };
+            self.taken_in_turn = 0;
+        }
+
+        None
+    }
+}
+
+impl<T> Drop for RequestQueues<T> {
+    fn drop(&mut self) {
+        self.announce_gauge.decrement(self.announce.len());
+        self.scrape_gauge.decrement(self.scrape.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_queues() {
+        let config = SchedulingConfig {
+            announce_batch_size: 3,
+            scrape_batch_size: 1,
+            ..Default::default()
+        };
+
+        let announce_gauge = Arc::new(Gauge::default());
+        let scrape_gauge = Arc::new(Gauge::default());
+
+        let mut queues = RequestQueues::new(&config, announce_gauge.clone(), scrape_gauge.clone());
+
+        for i in 0..5 {
+            queues.push_scrape(100 + i);
+        }
+        for i in 0..5 {
+            queues.push_announce(i);
+        }
+
+        assert_eq!(announce_gauge.get(), 5);
+        assert_eq!(scrape_gauge.get(), 5);
+
+        let popped = ::std::iter::from_fn(|| queues.pop()).collect::<Vec<_>>();
+
+        assert_eq!(popped, vec![0, 1, 2, 100, 3, 4, 101, 102, 103, 104]);
+        assert_eq!(announce_gauge.get(), 0);
+        assert_eq!(scrape_gauge.get(), 0);
+    }
+}
@@ -6,14 +6,14 @@ use arrayvec::ArrayVec;
 use rand::Rng;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
-use aquatic_common::{
-    CanonicalSocketAddr, IndexMap, SecondsSinceServerStart, ServerStartInstant, ValidUntil,
-};
+use aquatic_common::observer::SwarmSummary;
+use aquatic_common::{CanonicalSocketAddr, IndexMap, PeerExpiry, ServerStartInstant, ValidUntil};
 use aquatic_http_protocol::common::*;
 use aquatic_http_protocol::request::*;
 use aquatic_http_protocol::response::ResponsePeer;
 use aquatic_http_protocol::response::*;
 
+use crate::common::TrackerIndex;
 use crate::config::Config;
 
 const SMALL_PEER_MAP_CAPACITY: usize = 4;
@@ -29,10 +29,10 @@ pub struct TorrentMaps {
 }
 
 impl TorrentMaps {
-    pub fn new(worker_index: usize) -> Self {
+    pub fn new(config: &Config, worker_index: usize) -> Self {
         Self {
-            ipv4: TorrentMap::new(worker_index, true),
-            ipv6: TorrentMap::new(worker_index, false),
+            ipv4: TorrentMap::new(config, worker_index, true),
+            ipv6: TorrentMap::new(config, worker_index, false),
         }
     }
 
@@ -42,6 +42,7 @@ impl TorrentMaps {
         rng: &mut impl Rng,
         valid_until: ValidUntil,
         peer_addr: CanonicalSocketAddr,
+        tracker: TrackerIndex,
         request: AnnounceRequest,
     ) -> AnnounceResponse {
         match peer_addr.get().ip() {
@@ -52,6 +53,7 @@ impl TorrentMaps {
                         rng,
                         valid_until,
                         peer_ip_address,
+                        tracker,
                         request,
                     );
 
@@ -71,6 +73,7 @@ impl TorrentMaps {
                         rng,
                         valid_until,
                         peer_ip_address,
+                        tracker,
                         request,
                     );
 
@@ -90,81 +93,129 @@ impl TorrentMaps {
         &mut self,
         config: &Config,
         peer_addr: CanonicalSocketAddr,
+        tracker: TrackerIndex,
         request: ScrapeRequest,
     ) -> ScrapeResponse {
-        if peer_addr.get().ip().is_ipv4() {
-            self.ipv4.handle_scrape_request(config, request)
+        let mut response = if peer_addr.get().ip().is_ipv4() {
+            self.ipv4.handle_scrape_request(config, tracker, request)
         } else {
-            self.ipv6.handle_scrape_request(config, request)
+            self.ipv6.handle_scrape_request(config, tracker, request)
+        };
+
+        if config.protocol.scrape_merge_ip_versions {
+            for (info_hash, stats) in response.files.iter_mut() {
+                let other = if peer_addr.get().ip().is_ipv4() {
+                    self.ipv6.ip_version_scrape_statistics(tracker, info_hash)
+                } else {
+                    self.ipv4.ip_version_scrape_statistics(tracker, info_hash)
+                };
+
+                stats.complete += other.complete;
+                stats.incomplete += other.incomplete;
+            }
+        }
+
+        if config.protocol.scrape_ip_version_breakdown {
+            for (info_hash, stats) in response.files.iter_mut() {
+                stats.ipv4 = Some(self.ipv4.ip_version_scrape_statistics(tracker, info_hash));
+                stats.ipv6 = Some(self.ipv6.ip_version_scrape_statistics(tracker, info_hash));
+            }
         }
+
+        response
     }
 
     #[cfg(feature = "metrics")]
     pub fn update_torrent_metrics(&self) {
-        self.ipv4.torrent_gauge.set(self.ipv4.torrents.len() as f64);
-        self.ipv6.torrent_gauge.set(self.ipv6.torrents.len() as f64);
+        self.ipv4.update_torrent_metrics();
+        self.ipv6.update_torrent_metrics();
+    }
+
+    /// Count torrents and peers of all trackers
+    pub fn summary(&self) -> SwarmSummary {
+        let mut summary = SwarmSummary::default();
+
+        self.ipv4.add_to_summary(&mut summary);
+        self.ipv6.add_to_summary(&mut summary);
+
+        summary
     }
 
     pub fn clean(
         &mut self,
         config: &Config,
-        access_list: &Arc<AccessListArcSwap>,
+        access_lists: &[Arc<AccessListArcSwap>],
         server_start_instant: ServerStartInstant,
     ) {
-        let mut access_list_cache = create_access_list_cache(access_list);
-
-        let now = server_start_instant.seconds_elapsed();
-
-        self.ipv4.clean(config, &mut access_list_cache, now);
-        self.ipv6.clean(config, &mut access_list_cache, now);
+        let mut access_list_caches = access_lists
+            .iter()
+            .map(create_access_list_cache)
+            .collect::<Vec<_>>();
+
+        let expiry = PeerExpiry::new(
+            server_start_instant.seconds_elapsed(),
+            config.cleaning.max_peer_age,
+            config.cleaning.max_seeder_age,
+        );
+
+        self.ipv4.clean(config, &mut access_list_caches, expiry);
+        self.ipv6.clean(config, &mut access_list_caches, expiry);
     }
 }
 
+/// Torrents keyed by tracker and info hash, so that peers announcing to
+/// different virtual trackers are never mixed
 pub struct TorrentMap<I: Ip> {
-    torrents: IndexMap<InfoHash, TorrentData<I>>,
+    torrents: IndexMap<(TrackerIndex, InfoHash), TorrentData<I>>,
+    /// Indexed by tracker index
     #[cfg(feature = "metrics")]
-    peer_gauge: ::metrics::Gauge,
+    peer_gauges: Vec<::metrics::Gauge>,
+    /// Indexed by tracker index
     #[cfg(feature = "metrics")]
-    torrent_gauge: ::metrics::Gauge,
+    torrent_gauges: Vec<::metrics::Gauge>,
 }
 
 impl<I: Ip> TorrentMap<I> {
-    fn new(worker_index: usize, ipv4: bool) -> Self {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn new(config: &Config, worker_index: usize, ipv4: bool) -> Self {
         #[cfg(feature = "metrics")]
-        let peer_gauge = if ipv4 {
-            ::metrics::gauge!(
-                "aquatic_peers",
-                "ip_version" => "4",
-                "worker_index" => worker_index.to_string(),
-            )
-        } else {
-            ::metrics::gauge!(
-                "aquatic_peers",
-                "ip_version" => "6",
-                "worker_index" => worker_index.to_string(),
-            )
-        };
+        let ip_version = if ipv4 { "4" } else { "6" };
         #[cfg(feature = "metrics")]
-        let torrent_gauge = if ipv4 {
-            ::metrics::gauge!(
-                "aquatic_torrents",
-                "ip_version" => "4",
-                "worker_index" => worker_index.to_string(),
-            )
-        } else {
-            ::metrics::gauge!(
-                "aquatic_torrents",
-                "ip_version" => "6",
-                "worker_index" => worker_index.to_string(),
-            )
-        };
+        let trackers = (0..=config.virtual_trackers.len())
+            .map(|i| config.tracker_name(TrackerIndex(i)).to_string())
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "metrics")]
+        let peer_gauges = trackers
+            .iter()
+            .map(|tracker| {
+                ::metrics::gauge!(
+                    aquatic_metrics::names::PEERS,
+                    "ip_version" => ip_version,
+                    "worker_index" => worker_index.to_string(),
+                    "tracker" => tracker.clone(),
+                )
+            })
+            .collect();
+        #[cfg(feature = "metrics")]
+        let torrent_gauges = trackers
+            .iter()
+            .map(|tracker| {
+                ::metrics::gauge!(
+                    aquatic_metrics::names::TORRENTS,
+                    "ip_version" => ip_version,
+                    "worker_index" => worker_index.to_string(),
+                    "tracker" => tracker.clone(),
+                )
+            })
+            .collect();
 
         Self {
             torrents: Default::default(),
             #[cfg(feature = "metrics")]
-            peer_gauge,
+            peer_gauges,
             #[cfg(feature = "metrics")]
-            torrent_gauge,
+            torrent_gauges,
         }
     }
 
@@ -174,10 +225,11 @@ impl<I: Ip> TorrentMap<I> {
         rng: &mut impl Rng,
         valid_until: ValidUntil,
         peer_ip_address: I,
+        tracker: TrackerIndex,
         request: AnnounceRequest,
     ) -> (usize, usize, Vec<ResponsePeer<I>>) {
         self.torrents
-            .entry(request.info_hash)
+            .entry((tracker, request.info_hash))
             .or_default()
             .upsert_peer_and_get_response_peers(
                 config,
@@ -186,11 +238,27 @@ impl<I: Ip> TorrentMap<I> {
                 peer_ip_address,
                 valid_until,
                 #[cfg(feature = "metrics")]
-                &self.peer_gauge,
+                &self.peer_gauges[tracker.0],
             )
     }
 
-    fn handle_scrape_request(&mut self, config: &Config, request: ScrapeRequest) -> ScrapeResponse {
+    fn add_to_summary(&self, summary: &mut SwarmSummary) {
+        summary.torrents += self.torrents.len();
+
+        for torrent_data in self.torrents.values() {
+            let stats = torrent_data.scrape_statistics();
+
+            summary.seeders += stats.complete;
+            summary.leechers += stats.incomplete;
+        }
+    }
+
+    fn handle_scrape_request(
+        &mut self,
+        config: &Config,
+        tracker: TrackerIndex,
+        request: ScrapeRequest,
+    ) -> ScrapeResponse {
         let num_to_take = request
             .info_hashes
             .len()
@@ -203,12 +271,14 @@ impl<I: Ip> TorrentMap<I> {
         for info_hash in request.info_hashes.into_iter().take(num_to_take) {
             let stats = self
                 .torrents
-                .get(&info_hash)
+                .get(&(tracker, info_hash))
                 .map(|torrent_data| torrent_data.scrape_statistics())
                 .unwrap_or(ScrapeStatistics {
                     complete: 0,
                     incomplete: 0,
                     downloaded: 0,
+                    ipv4: None,
+                    ipv6: None,
                 });
 
             response.files.insert(info_hash, stats);
@@ -217,28 +287,63 @@ impl<I: Ip> TorrentMap<I> {
         response
     }
 
+    fn ip_version_scrape_statistics(
+        &self,
+        tracker: TrackerIndex,
+        info_hash: &InfoHash,
+    ) -> IpVersionScrapeStatistics {
+        self.torrents
+            .get(&(tracker, *info_hash))
+            .map(|torrent_data| {
+                let stats = torrent_data.scrape_statistics();
+
+                IpVersionScrapeStatistics {
+                    complete: stats.complete,
+                    incomplete: stats.incomplete,
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn update_torrent_metrics(&self) {
+        let mut num_torrents = vec![0usize; self.torrent_gauges.len()];
+
+        for (tracker, _) in self.torrents.keys() {
+            num_torrents[tracker.0] += 1;
+        }
+
+        for (gauge, n) in self.torrent_gauges.iter().zip(num_torrents) {
+            gauge.set(n as f64);
+        }
+    }
+
     fn clean(
         &mut self,
         config: &Config,
-        access_list_cache: &mut AccessListCache,
-        now: SecondsSinceServerStart,
+        access_list_caches: &mut [AccessListCache],
+        expiry: PeerExpiry,
     ) {
-        let mut total_num_peers = 0;
+        #[cfg(feature = "metrics")]
+        let mut num_peers_by_tracker = vec![0u64; self.peer_gauges.len()];
 
-        self.torrents.retain(|info_hash, torrent_data| {
-            if !access_list_cache
+        self.torrents.retain(|(tracker, info_hash), torrent_data| {
+            if !access_list_caches[tracker.0]
                 .load()
-                .allows(config.access_list.mode, &info_hash.0)
+                .allows(config.access_list_config(*tracker).mode, &info_hash.0)
             {
                 return false;
             }
 
             let num_peers = match torrent_data {
-                TorrentData::Small(t) => t.clean_and_get_num_peers(now),
-                TorrentData::Large(t) => t.clean_and_get_num_peers(now),
+                TorrentData::Small(t) => t.clean_and_get_num_peers(expiry),
+                TorrentData::Large(t) => t.clean_and_get_num_peers(expiry),
             };
 
-            total_num_peers += num_peers as u64;
+            #[cfg(feature = "metrics")]
+            {
+                num_peers_by_tracker[tracker.0] += num_peers as u64;
+            }
 
             num_peers > 0
         });
@@ -246,7 +351,9 @@ impl<I: Ip> TorrentMap<I> {
         self.torrents.shrink_to_fit();
 
         #[cfg(feature = "metrics")]
-        self.peer_gauge.set(total_num_peers as f64);
+        for (gauge, n) in self.peer_gauges.iter().zip(num_peers_by_tracker) {
+            gauge.set(n as f64);
+        }
     }
 }
 
@@ -270,7 +377,13 @@ impl<I: Ip> TorrentData<I> {
             Some(numwant) => numwant.min(config.protocol.max_peers),
         };
 
-        let status = PeerStatus::from_event_and_bytes_left(request.event, request.bytes_left);
+        let status = if request.port == 0 {
+            // Treating peer as stopped means that it will not be inserted
+            // into peer map, so it will never be returned to other peers
+            PeerStatus::Stopped
+        } else {
+            PeerStatus::from_event_and_bytes_left(request.event, request.bytes_left)
+        };
 
         let peer_map_key = ResponsePeer {
             ip_address,
@@ -353,6 +466,8 @@ impl<I: Ip> TorrentData<I> {
             complete: seeders,
             incomplete: leechers,
             downloaded: 0,
+            ipv4: None,
+            ipv6: None,
         }
     }
 }
@@ -399,8 +514,9 @@ impl<I: Ip> SmallPeerMap<I> {
         Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
     }
 
-    fn clean_and_get_num_peers(&mut self, now: SecondsSinceServerStart) -> usize {
-        self.0.retain(|(_, peer)| peer.valid_until.valid(now));
+    fn clean_and_get_num_peers(&mut self, expiry: PeerExpiry) -> usize {
+        self.0
+            .retain(|(_, peer)| expiry.keep(peer.valid_until, peer.is_seeder));
 
         self.0.len()
     }
@@ -492,9 +608,9 @@ impl<I: Ip> LargePeerMap<I> {
         }
     }
 
-    fn clean_and_get_num_peers(&mut self, now: SecondsSinceServerStart) -> usize {
+    fn clean_and_get_num_peers(&mut self, expiry: PeerExpiry) -> usize {
         self.peers.retain(|_, peer| {
-            let keep = peer.valid_until.valid(now);
+            let keep = expiry.keep(peer.valid_until, peer.is_seeder);
 
             if (!keep) & peer.is_seeder {
                 self.num_seeders -= 1;
@@ -541,3 +657,150 @@ impl PeerStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::config::VirtualTrackerConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_trackers_do_not_share_swarms() {
+        let config = Config {
+            virtual_trackers: vec![VirtualTrackerConfig::default()],
+            ..Default::default()
+        };
+
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 60);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_maps = TorrentMaps::new(&config, 0);
+
+        let info_hash = InfoHash([1; 20]);
+
+        let announce = |torrent_maps: &mut TorrentMaps, rng: &mut SmallRng, tracker, i: u8| {
+            let request = AnnounceRequest {
+                info_hash,
+                peer_id: PeerId([i; 20]),
+                port: 1000,
+                bytes_uploaded: 0,
+                bytes_downloaded: 0,
+                bytes_left: 1,
+                event: AnnounceEvent::Started,
+                numwant: None,
+                key: None,
+            };
+            let peer_addr = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, i], 1000)));
+
+            torrent_maps.handle_announce_request(
+                &config,
+                rng,
+                valid_until,
+                peer_addr,
+                tracker,
+                request,
+            )
+        };
+
+        announce(&mut torrent_maps, &mut rng, TrackerIndex(0), 1);
+
+        let response = announce(&mut torrent_maps, &mut rng, TrackerIndex(1), 2);
+
+        assert_eq!(response.incomplete, 0);
+        assert!(response.peers.0.is_empty());
+
+        let response = announce(&mut torrent_maps, &mut rng, TrackerIndex(0), 3);
+
+        assert_eq!(response.incomplete, 1);
+        assert_eq!(response.peers.0.len(), 1);
+    }
+
+    #[test]
+    fn test_scrape_ip_versions() {
+        let mut config = Config::default();
+
+        config.protocol.scrape_ip_version_breakdown = true;
+
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 60);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_maps = TorrentMaps::new(&config, 0);
+
+        let info_hash = InfoHash([1; 20]);
+
+        let ipv4_addr = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+        let ipv6_addr = CanonicalSocketAddr::new("[2001:db8::1]:1000".parse().unwrap());
+
+        for (i, (peer_addr, bytes_left)) in [(ipv4_addr, 0), (ipv6_addr, 1)].into_iter().enumerate()
+        {
+            let request = AnnounceRequest {
+                info_hash,
+                peer_id: PeerId([i as u8; 20]),
+                port: 1000,
+                bytes_uploaded: 0,
+                bytes_downloaded: 0,
+                bytes_left,
+                event: AnnounceEvent::Started,
+                numwant: None,
+                key: None,
+            };
+
+            torrent_maps.handle_announce_request(
+                &config,
+                &mut rng,
+                valid_until,
+                peer_addr,
+                TrackerIndex(0),
+                request,
+            );
+        }
+
+        let response = torrent_maps.handle_scrape_request(
+            &config,
+            ipv6_addr,
+            TrackerIndex(0),
+            ScrapeRequest {
+                info_hashes: vec![info_hash],
+            },
+        );
+
+        let stats = response.files.get(&info_hash).unwrap();
+
+        assert_eq!(stats.complete, 0);
+        assert_eq!(stats.incomplete, 1);
+        assert_eq!(
+            stats.ipv4,
+            Some(IpVersionScrapeStatistics {
+                complete: 1,
+                incomplete: 0
+            })
+        );
+        assert_eq!(
+            stats.ipv6,
+            Some(IpVersionScrapeStatistics {
+                complete: 0,
+                incomplete: 1
+            })
+        );
+
+        config.protocol.scrape_merge_ip_versions = true;
+
+        let response = torrent_maps.handle_scrape_request(
+            &config,
+            ipv6_addr,
+            TrackerIndex(0),
+            ScrapeRequest {
+                info_hashes: vec![info_hash],
+            },
+        );
+
+        let stats = response.files.get(&info_hash).unwrap();
+
+        assert_eq!(stats.complete, 1);
+        assert_eq!(stats.incomplete, 1);
+    }
+}
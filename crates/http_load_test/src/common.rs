@@ -1,4 +1,7 @@
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize},
+    Arc,
+};
 
 use rand_distr::Gamma;
 
@@ -22,6 +25,11 @@ pub struct Statistics {
     pub responses_failure: AtomicUsize,
     pub bytes_sent: AtomicUsize,
     pub bytes_received: AtomicUsize,
+    pub connections_opened: AtomicUsize,
+    /// Time spent on TCP connect and TLS handshake
+    pub connection_setup_micros: AtomicUsize,
+    /// Time spent sending requests and reading responses
+    pub request_micros: AtomicUsize,
 }
 
 #[derive(Clone)]
@@ -29,6 +37,9 @@ pub struct LoadTestState {
     pub info_hashes: Arc<Vec<InfoHash>>,
     pub statistics: Arc<Statistics>,
     pub gamma: Arc<Gamma<f64>>,
+    /// Keep connections alive after receiving responses. Changed during
+    /// run in keep-alive comparison mode
+    pub keep_alive: Arc<AtomicBool>,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -22,7 +22,19 @@ pub struct Config {
     pub url_suffix: String,
     pub duration: usize,
     pub keep_alive: bool,
+    /// Run first half of test with keep-alive enabled and second half with
+    /// it disabled, then report the difference in connection setup cost
+    /// (TCP connect and TLS handshake) per response
+    ///
+    /// Requires a non-zero duration. Overrides `keep_alive`.
+    pub compare_keep_alive: bool,
     pub enable_tls: bool,
+    /// Resume TLS sessions when opening new connections, if the server
+    /// supports it
+    pub tls_session_resumption: bool,
+    /// Send first request as TLS 1.3 early data (0-RTT) when resuming
+    /// sessions, if the server accepts it
+    pub tls_early_data: bool,
     pub torrents: TorrentConfig,
 }
 
@@ -43,7 +55,10 @@ impl Default for Config {
             url_suffix: "".into(),
             duration: 0,
             keep_alive: true,
+            compare_keep_alive: false,
             enable_tls: true,
+            tls_session_resumption: true,
+            tls_early_data: false,
             torrents: TorrentConfig::default(),
         }
     }
@@ -1,4 +1,7 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -35,6 +38,10 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         panic!("Error: at least one weight must be larger than zero.");
     }
 
+    if config.compare_keep_alive && config.duration == 0 {
+        panic!("Error: compare_keep_alive requires a non-zero duration.");
+    }
+
     println!("Starting client with config: {:#?}", config);
 
     let mut info_hashes = Vec::with_capacity(config.torrents.number_of_torrents);
@@ -55,10 +62,13 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         info_hashes: Arc::new(info_hashes),
         statistics: Arc::new(Statistics::default()),
         gamma: Arc::new(gamma),
+        keep_alive: Arc::new(AtomicBool::new(
+            config.keep_alive || config.compare_keep_alive,
+        )),
     };
 
     let opt_tls_config = if config.enable_tls {
-        Some(create_tls_config().unwrap())
+        Some(create_tls_config(&config).unwrap())
     } else {
         None
     };
@@ -83,9 +93,49 @@ fn run(config: Config) -> ::anyhow::Result<()> {
     Ok(())
 }
 
+/// Totals for one half of a keep-alive comparison run
+#[derive(Default)]
+struct PhaseTotals {
+    seconds: f64,
+    responses: f64,
+    connections_opened: f64,
+    connection_setup_micros: f64,
+    request_micros: f64,
+}
+
+impl PhaseTotals {
+    fn print(&self, label: &str) {
+        let responses = self.responses.max(1.0);
+
+        println!("  - {}:", label);
+        println!(
+            "    - Responses per second:            {:.2}",
+            self.responses / self.seconds.max(1.0)
+        );
+        println!(
+            "    - Connections per response:        {:.2}",
+            self.connections_opened / responses
+        );
+        println!(
+            "    - Connection setup ms per response: {:.3}",
+            self.connection_setup_micros / responses / 1000.0
+        );
+        println!(
+            "    - Request ms per response:          {:.3}",
+            self.request_micros / responses / 1000.0
+        );
+    }
+
+    fn setup_ms_per_response(&self) -> f64 {
+        self.connection_setup_micros / self.responses.max(1.0) / 1000.0
+    }
+}
+
 fn monitor_statistics(state: LoadTestState, config: &Config) {
     let start_time = Instant::now();
     let mut report_avg_response_vec: Vec<f64> = Vec::new();
+    // Index 0 is keep-alive phase, index 1 is non-keep-alive phase
+    let mut phases = [PhaseTotals::default(), PhaseTotals::default()];
 
     let interval = 5;
     let interval_f64 = interval as f64;
@@ -113,6 +163,14 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
         let bytes_received_per_second =
             statistics.bytes_received.fetch_and(0, Ordering::Relaxed) as f64 / interval_f64;
 
+        let connections_opened = statistics
+            .connections_opened
+            .fetch_and(0, Ordering::Relaxed) as f64;
+        let connection_setup_micros = statistics
+            .connection_setup_micros
+            .fetch_and(0, Ordering::Relaxed) as f64;
+        let request_micros = statistics.request_micros.fetch_and(0, Ordering::Relaxed) as f64;
+
         let responses_announce_per_second = responses_announce / interval_f64;
 
         let responses_per_second = responses_announce_per_second
@@ -121,6 +179,17 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
 
         report_avg_response_vec.push(responses_per_second);
 
+        if config.compare_keep_alive {
+            let keep_alive = state.keep_alive.load(Ordering::Relaxed);
+            let phase = &mut phases[if keep_alive { 0 } else { 1 }];
+
+            phase.seconds += interval_f64;
+            phase.responses += responses_per_second * interval_f64;
+            phase.connections_opened += connections_opened;
+            phase.connection_setup_micros += connection_setup_micros;
+            phase.request_micros += request_micros;
+        }
+
         println!();
         println!("Requests out: {:.2}/second", requests_per_second);
         println!("Responses in: {:.2}/second", responses_per_second);
@@ -142,10 +211,23 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
             "Bandwidth in:  {:.2}Mbit/s",
             bytes_received_per_second * MBITS_FACTOR
         );
+        println!(
+            "Connections opened: {:.2}/second, average setup time {:.3} ms",
+            connections_opened / interval_f64,
+            connection_setup_micros / connections_opened.max(1.0) / 1000.0
+        );
 
         let time_elapsed = start_time.elapsed();
         let duration = Duration::from_secs(config.duration as u64);
 
+        if config.compare_keep_alive
+            && time_elapsed >= duration / 2
+            && state.keep_alive.swap(false, Ordering::Relaxed)
+        {
+            println!();
+            println!("Disabling keep-alive");
+        }
+
         if config.duration != 0 && time_elapsed >= duration {
             let report_len = report_avg_response_vec.len() as f64;
             let report_sum: f64 = report_avg_response_vec.into_iter().sum();
@@ -162,6 +244,19 @@ fn monitor_statistics(state: LoadTestState, config: &Config) {
                 config
             );
 
+            if config.compare_keep_alive {
+                println!("Keep-alive comparison:");
+
+                phases[0].print("With keep-alive");
+                phases[1].print("Without keep-alive");
+
+                println!(
+                    "  - Connection setup overhead without keep-alive: {:.3} ms per response",
+                    phases[1].setup_ms_per_response() - phases[0].setup_ms_per_response()
+                );
+                println!();
+            }
+
             break;
         }
     }
@@ -212,14 +307,20 @@ impl rustls::client::danger::ServerCertVerifier for FakeCertificateVerifier {
     }
 }
 
-fn create_tls_config() -> anyhow::Result<Arc<rustls::ClientConfig>> {
-    let mut config = rustls::ClientConfig::builder()
+fn create_tls_config(config: &Config) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let mut tls_config = rustls::ClientConfig::builder()
         .with_root_certificates(rustls::RootCertStore::empty())
         .with_no_client_auth();
 
-    config
+    tls_config
         .dangerous()
         .set_certificate_verifier(Arc::new(FakeCertificateVerifier));
 
-    Ok(Arc::new(config))
+    if !config.tls_session_resumption {
+        tls_config.resumption = rustls::client::Resumption::disabled();
+    }
+
+    tls_config.enable_early_data = config.tls_early_data;
+
+    Ok(Arc::new(tls_config))
 }
@@ -4,7 +4,7 @@ use std::{
     io::Cursor,
     rc::Rc,
     sync::{atomic::Ordering, Arc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use aquatic_http_protocol::response::Response;
@@ -98,6 +98,8 @@ async fn run_connection(
     num_active_connections: Rc<RefCell<usize>>,
     rng: Rc<RefCell<SmallRng>>,
 ) -> anyhow::Result<()> {
+    let setup_start = Instant::now();
+
     let stream = TcpStream::connect(config.server_address)
         .await
         .map_err(|err| anyhow::anyhow!("connect: {:?}", err))?;
@@ -107,6 +109,8 @@ async fn run_connection(
             .connect("example.com".try_into().unwrap(), stream)
             .await?;
 
+        record_connection_setup(&load_test_state, setup_start);
+
         let mut connection = Connection {
             config,
             load_test_state,
@@ -117,6 +121,8 @@ async fn run_connection(
 
         connection.run(num_active_connections).await?;
     } else {
+        record_connection_setup(&load_test_state, setup_start);
+
         let mut connection = Connection {
             config,
             load_test_state,
@@ -131,6 +137,18 @@ async fn run_connection(
     Ok(())
 }
 
+fn record_connection_setup(load_test_state: &LoadTestState, setup_start: Instant) {
+    let statistics = &load_test_state.statistics;
+
+    statistics
+        .connections_opened
+        .fetch_add(1, Ordering::Relaxed);
+    statistics.connection_setup_micros.fetch_add(
+        setup_start.elapsed().as_micros() as usize,
+        Ordering::Relaxed,
+    );
+}
+
 struct Connection<S> {
     config: Rc<Config>,
     load_test_state: LoadTestState,
@@ -159,10 +177,17 @@ where
 
     async fn run_connection_loop(&mut self) -> anyhow::Result<()> {
         loop {
+            let request_start = Instant::now();
+
             self.send_request().await?;
             self.read_response().await?;
 
-            if !self.config.keep_alive {
+            self.load_test_state.statistics.request_micros.fetch_add(
+                request_start.elapsed().as_micros() as usize,
+                Ordering::Relaxed,
+            );
+
+            if !self.load_test_state.keep_alive.load(Ordering::Relaxed) {
                 break Ok(());
             }
         }
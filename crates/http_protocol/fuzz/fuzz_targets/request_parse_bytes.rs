@@ -0,0 +1,13 @@
+#![no_main]
+
+use aquatic_http_protocol::request::Request;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some(request)) = Request::parse_bytes(data) {
+        // Successfully parsed requests are written back by the load tester
+        let mut output = Vec::new();
+
+        request.write(&mut output, b"").unwrap();
+    }
+});
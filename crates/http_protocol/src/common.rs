@@ -63,6 +63,25 @@ impl AnnounceEvent {
     }
 }
 
+/// How to handle announce request parameters that are sent more than once
+///
+/// Trackers and clients differ in whether the first or the last value wins,
+/// which some abusive clients exploit, e.g., to have a proxy and the tracker
+/// see different info hashes. Multiple `info_hash` parameters in scrape
+/// requests are always allowed.
+///
+/// Defaults to using the last value, as earlier versions did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateParameterPolicy {
+    /// Fail parsing
+    Reject,
+    /// Use first value and ignore later ones
+    First,
+    /// Use last value
+    #[default]
+    Last,
+}
+
 #[cfg(test)]
 impl quickcheck::Arbitrary for InfoHash {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
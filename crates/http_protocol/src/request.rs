@@ -68,6 +68,13 @@ impl AnnounceRequest {
     }
 
     pub fn parse_query_string(query_string: &str) -> anyhow::Result<Self> {
+        Self::parse_query_string_with_policy(query_string, DuplicateParameterPolicy::default())
+    }
+
+    pub fn parse_query_string_with_policy(
+        query_string: &str,
+        duplicate_policy: DuplicateParameterPolicy,
+    ) -> anyhow::Result<Self> {
         // -- Parse key-value pairs
 
         let mut opt_info_hash = None;
@@ -76,7 +83,8 @@ impl AnnounceRequest {
         let mut opt_bytes_left = None;
         let mut opt_bytes_uploaded = None;
         let mut opt_bytes_downloaded = None;
-        let mut event = AnnounceEvent::default();
+        let mut opt_event = None;
+        let mut opt_compact = None;
         let mut opt_numwant = None;
         let mut opt_key = None;
 
@@ -98,49 +106,56 @@ impl AnnounceRequest {
                 })?;
 
             match key {
-                "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
-
-                    opt_info_hash = Some(InfoHash(value));
-                }
-                "peer_id" => {
-                    let value = urldecode_20_bytes(value)?;
-
-                    opt_peer_id = Some(PeerId(value));
-                }
-                "port" => {
-                    opt_port = Some(value.parse::<u16>().with_context(|| "parse port")?);
-                }
-                "left" => {
-                    opt_bytes_left = Some(value.parse::<usize>().with_context(|| "parse left")?);
-                }
+                "info_hash" => set_parameter(&mut opt_info_hash, key, duplicate_policy, || {
+                    Ok(InfoHash(
+                        urldecode_20_bytes(value).with_context(|| "parse info_hash")?,
+                    ))
+                })?,
+                "peer_id" => set_parameter(&mut opt_peer_id, key, duplicate_policy, || {
+                    Ok(PeerId(
+                        urldecode_20_bytes(value).with_context(|| "parse peer_id")?,
+                    ))
+                })?,
+                "port" => set_parameter(&mut opt_port, key, duplicate_policy, || {
+                    value.parse::<u16>().with_context(|| "parse port")
+                })?,
+                "left" => set_parameter(&mut opt_bytes_left, key, duplicate_policy, || {
+                    value.parse::<usize>().with_context(|| "parse left")
+                })?,
                 "uploaded" => {
-                    opt_bytes_uploaded =
-                        Some(value.parse::<usize>().with_context(|| "parse uploaded")?);
+                    set_parameter(&mut opt_bytes_uploaded, key, duplicate_policy, || {
+                        value.parse::<usize>().with_context(|| "parse uploaded")
+                    })?
                 }
                 "downloaded" => {
-                    opt_bytes_downloaded =
-                        Some(value.parse::<usize>().with_context(|| "parse downloaded")?);
+                    set_parameter(&mut opt_bytes_downloaded, key, duplicate_policy, || {
+                        value.parse::<usize>().with_context(|| "parse downloaded")
+                    })?
                 }
-                "event" => {
-                    event = value
+                "event" => set_parameter(&mut opt_event, key, duplicate_policy, || {
+                    value
                         .parse::<AnnounceEvent>()
-                        .map_err(|err| anyhow::anyhow!("invalid event: {}", err))?;
-                }
-                "compact" => {
+                        .map_err(|err| anyhow::anyhow!("invalid event: {}", err))
+                })?,
+                "compact" => set_parameter(&mut opt_compact, key, duplicate_policy, || {
                     if value != "1" {
                         return Err(anyhow::anyhow!("compact set, but not to 1"));
                     }
-                }
-                "numwant" => {
-                    opt_numwant = Some(value.parse::<usize>().with_context(|| "parse numwant")?);
-                }
-                "key" => {
+
+                    Ok(())
+                })?,
+                "numwant" => set_parameter(&mut opt_numwant, key, duplicate_policy, || {
+                    value.parse::<usize>().with_context(|| "parse numwant")
+                })?,
+                "key" => set_parameter(&mut opt_key, key, duplicate_policy, || {
                     if value.len() > 100 {
                         return Err(anyhow::anyhow!("'key' is too long"));
                     }
-                    opt_key = Some(::urlencoding::decode(value)?.into());
-                }
+
+                    Ok(::urlencoding::decode(value)
+                        .with_context(|| "parse key")?
+                        .into())
+                })?,
                 k => {
                     ::log::debug!("ignored unrecognized key: {}", k)
                 }
@@ -160,13 +175,38 @@ impl AnnounceRequest {
             bytes_uploaded: opt_bytes_uploaded.with_context(|| "no uploaded")?,
             bytes_downloaded: opt_bytes_downloaded.with_context(|| "no downloaded")?,
             bytes_left: opt_bytes_left.with_context(|| "no left")?,
-            event,
+            event: opt_event.unwrap_or_default(),
             numwant: opt_numwant,
             key: opt_key,
         })
     }
 }
 
+/// Parse parameter value and store it in `slot` if allowed by policy
+fn set_parameter<T, F>(
+    slot: &mut Option<T>,
+    key: &str,
+    policy: DuplicateParameterPolicy,
+    parse: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<T>,
+{
+    if slot.is_some() {
+        match policy {
+            DuplicateParameterPolicy::Reject => {
+                return Err(anyhow::anyhow!("duplicate parameter '{}'", key));
+            }
+            DuplicateParameterPolicy::First => return Ok(()),
+            DuplicateParameterPolicy::Last => (),
+        }
+    }
+
+    *slot = Some(parse()?);
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ScrapeRequest {
     pub info_hashes: Vec<InfoHash>,
@@ -220,7 +260,9 @@ impl ScrapeRequest {
 
             match key {
                 "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = urldecode_20_bytes(value).with_context(|| {
+                        format!("parse info_hash number {}", info_hashes.len() + 1)
+                    })?;
 
                     info_hashes.push(InfoHash(value));
                 }
@@ -278,11 +320,20 @@ impl Request {
     /// peer_id.
     ///
     /// The info hashes and peer id's that are received are url-encoded byte
-    /// by byte, e.g., %fa for byte 0xfa. However, they need to be parsed as
-    /// UTF-8 string, meaning that non-ascii bytes are invalid characters.
-    /// Therefore, these bytes must be converted to their equivalent multi-byte
-    /// UTF-8 encodings.
+    /// by byte, e.g., %fa for byte 0xfa. Bytes outside of printable ASCII
+    /// must be percent-encoded: raw non-ASCII, whitespace and control
+    /// characters are rejected, since they can't be mapped to single bytes
+    /// unambiguously.
+    ///
+    /// For duplicate announce parameters, the last value is used.
     pub fn parse_http_get_path(path: &str) -> anyhow::Result<Self> {
+        Self::parse_http_get_path_with_policy(path, DuplicateParameterPolicy::default())
+    }
+
+    pub fn parse_http_get_path_with_policy(
+        path: &str,
+        duplicate_policy: DuplicateParameterPolicy,
+    ) -> anyhow::Result<Self> {
         ::log::debug!("request GET path: {}", path);
 
         let mut split_parts = path.splitn(2, '?');
@@ -291,9 +342,9 @@ impl Request {
         let query_string = split_parts.next().with_context(|| "no query string")?;
 
         if location == "/announce" {
-            Ok(Request::Announce(AnnounceRequest::parse_query_string(
-                query_string,
-            )?))
+            Ok(Request::Announce(
+                AnnounceRequest::parse_query_string_with_policy(query_string, duplicate_policy)?,
+            ))
         } else if location == "/scrape" {
             Ok(Request::Scrape(ScrapeRequest::parse_query_string(
                 query_string,
@@ -373,6 +424,59 @@ mod tests {
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_announce_request_duplicate_parameters() {
+        let path = format!("{}&port=1&event=stopped", ANNOUNCE_REQUEST_PATH);
+
+        let err = Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::Reject)
+            .unwrap_err();
+
+        assert!(err.to_string().contains("duplicate parameter 'port'"));
+
+        // Last value is used by default
+        assert_eq!(
+            Request::parse_http_get_path(&path).unwrap(),
+            Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::Last)
+                .unwrap()
+        );
+
+        let first =
+            Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::First)
+                .unwrap();
+
+        assert_eq!(first, get_reference_announce_request());
+
+        match Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::Last)
+            .unwrap()
+        {
+            Request::Announce(request) => {
+                assert_eq!(request.port, 1);
+                assert_eq!(request.event, AnnounceEvent::Stopped);
+            }
+            Request::Scrape(_) => panic!("not an announce request"),
+        }
+
+        // Later invalid values are ignored with first-value policy only
+        let path = format!("{}&port=abc", ANNOUNCE_REQUEST_PATH);
+
+        assert!(
+            Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::First)
+                .is_ok()
+        );
+        assert!(
+            Request::parse_http_get_path_with_policy(&path, DuplicateParameterPolicy::Last)
+                .is_err()
+        );
+
+        // Repeated info hashes are how multiple torrents are scraped
+        let path = format!("{}&{}", SCRAPE_REQUEST_PATH, &SCRAPE_REQUEST_PATH[8..]);
+
+        match Request::parse_http_get_path(&path).unwrap() {
+            Request::Scrape(request) => assert_eq!(request.info_hashes.len(), 2),
+            Request::Announce(_) => panic!("not a scrape request"),
+        }
+    }
+
     impl Arbitrary for AnnounceRequest {
         fn arbitrary(g: &mut Gen) -> Self {
             let key: Option<String> = Arbitrary::arbitrary(g);
@@ -39,6 +39,42 @@ pub struct ScrapeStatistics {
     pub complete: usize,
     pub incomplete: usize,
     pub downloaded: usize,
+    /// Non-standard breakdown of IPv4 peers
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_ip_version_scrape_statistics",
+        deserialize_with = "deserialize_optional_ip_version_scrape_statistics"
+    )]
+    pub ipv4: Option<IpVersionScrapeStatistics>,
+    /// Non-standard breakdown of IPv6 peers
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_ip_version_scrape_statistics",
+        deserialize_with = "deserialize_optional_ip_version_scrape_statistics"
+    )]
+    pub ipv6: Option<IpVersionScrapeStatistics>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpVersionScrapeStatistics {
+    pub complete: usize,
+    pub incomplete: usize,
+}
+
+impl IpVersionScrapeStatistics {
+    fn write_bytes<W: Write>(&self, output: &mut W) -> ::std::io::Result<usize> {
+        let mut bytes_written = 0usize;
+
+        bytes_written += output.write(b"d8:completei")?;
+        bytes_written += output.write(itoa::Buffer::new().format(self.complete).as_bytes())?;
+        bytes_written += output.write(b"e10:incompletei")?;
+        bytes_written += output.write(itoa::Buffer::new().format(self.incomplete).as_bytes())?;
+        bytes_written += output.write(b"ee")?;
+
+        Ok(bytes_written)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -138,7 +174,18 @@ impl ScrapeResponse {
             bytes_written += output.write(b"e10:downloadedi0e10:incompletei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.incomplete).as_bytes())?;
-            bytes_written += output.write(b"ee")?;
+            bytes_written += output.write(b"e")?;
+
+            if let Some(ipv4) = statistics.ipv4.as_ref() {
+                bytes_written += output.write(b"4:ipv4")?;
+                bytes_written += ipv4.write_bytes(output)?;
+            }
+            if let Some(ipv6) = statistics.ipv6.as_ref() {
+                bytes_written += output.write(b"4:ipv6")?;
+                bytes_written += ipv6.write_bytes(output)?;
+            }
+
+            bytes_written += output.write(b"e")?;
         }
 
         bytes_written += output.write(b"ee")?;
@@ -237,6 +284,18 @@ impl quickcheck::Arbitrary for ScrapeStatistics {
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
             downloaded: 0,
+            ipv4: Option::arbitrary(g),
+            ipv6: Option::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for IpVersionScrapeStatistics {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        Self {
+            complete: usize::arbitrary(g),
+            incomplete: usize::arbitrary(g),
         }
     }
 }
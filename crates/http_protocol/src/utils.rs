@@ -2,9 +2,9 @@ use std::io::Write;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use anyhow::Context;
-use serde::{de::Visitor, Deserializer, Serializer};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::response::ResponsePeer;
+use super::response::{IpVersionScrapeStatistics, ResponsePeer};
 
 pub fn urlencode_20_bytes(input: [u8; 20], output: &mut impl Write) -> ::std::io::Result<()> {
     let mut tmp = [b'%'; 60];
@@ -18,40 +18,75 @@ pub fn urlencode_20_bytes(input: [u8; 20], output: &mut impl Write) -> ::std::io
     Ok(())
 }
 
+/// Decode url-encoded 20 byte value, e.g., an info hash or a peer id
+///
+/// Bytes may be percent-encoded (with upper- or lowercase hex digits) or
+/// sent as printable ASCII characters. As in form encoding, `+` is decoded
+/// to a space, so a literal `+` must be sent as `%2B`. Raw non-ASCII
+/// characters are rejected, since they can't be mapped to single bytes
+/// unambiguously.
 pub fn urldecode_20_bytes(value: &str) -> anyhow::Result<[u8; 20]> {
     let mut out_arr = [0u8; 20];
 
-    let mut chars = value.chars();
+    let bytes = value.as_bytes();
+    let mut position = 0usize;
 
-    for i in 0..20 {
-        let c = chars.next().with_context(|| "less than 20 chars")?;
+    for (num_decoded, out) in out_arr.iter_mut().enumerate() {
+        let byte = *bytes
+            .get(position)
+            .with_context(|| format!("expected 20 bytes, got {}", num_decoded))?;
 
-        if c as u32 > 255 {
-            return Err(anyhow::anyhow!(
-                "character not in single byte range: {:#?}",
-                c
-            ));
-        }
+        *out = match byte {
+            b'%' => {
+                let pair = bytes.get(position + 1..position + 3).with_context(|| {
+                    format!("truncated percent-encoding at position {}", position)
+                })?;
 
-        if c == '%' {
-            let first = chars
-                .next()
-                .with_context(|| "missing first urldecode char in pair")?;
-            let second = chars
-                .next()
-                .with_context(|| "missing second urldecode char in pair")?;
+                let mut decoded = [0u8; 1];
 
-            let hex = [first as u8, second as u8];
+                // Non-ASCII bytes are never valid hex digits, so multi-byte
+                // characters can't be truncated into valid ones
+                hex::decode_to_slice(pair, &mut decoded).map_err(|_| {
+                    anyhow::anyhow!(
+                        "invalid percent-encoding {:?} at position {}",
+                        String::from_utf8_lossy(&bytes[position..(position + 3)]),
+                        position
+                    )
+                })?;
 
-            hex::decode_to_slice(hex, &mut out_arr[i..i + 1])
-                .map_err(|err| anyhow::anyhow!("hex decode error: {:?}", err))?;
-        } else {
-            out_arr[i] = c as u8;
-        }
+                position += 3;
+
+                decoded[0]
+            }
+            b'+' => {
+                position += 1;
+
+                b' '
+            }
+            byte if byte.is_ascii_graphic() => {
+                position += 1;
+
+                byte
+            }
+            _ => {
+                // Position is at char boundary, since only ASCII characters
+                // have been consumed
+                let c = value[position..].chars().next().unwrap_or_default();
+
+                return Err(anyhow::anyhow!(
+                    "invalid character {:?} at position {}",
+                    c,
+                    position
+                ));
+            }
+        };
     }
 
-    if chars.next().is_some() {
-        return Err(anyhow::anyhow!("more than 20 chars"));
+    if position != bytes.len() {
+        return Err(anyhow::anyhow!(
+            "more than 20 bytes, trailing data at position {}",
+            position
+        ));
     }
 
     Ok(out_arr)
@@ -68,6 +103,32 @@ where
     }
 }
 
+/// Serialize per-IP-version scrape statistics as a bare dictionary, matching
+/// the hand-written encoder, instead of as a serde option
+#[inline]
+pub fn serialize_optional_ip_version_scrape_statistics<S>(
+    v: &Option<IpVersionScrapeStatistics>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match v {
+        Some(statistics) => statistics.serialize(serializer),
+        None => Err(serde::ser::Error::custom("use skip_serializing_if")),
+    }
+}
+
+#[inline]
+pub fn deserialize_optional_ip_version_scrape_statistics<'de, D>(
+    deserializer: D,
+) -> Result<Option<IpVersionScrapeStatistics>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    IpVersionScrapeStatistics::deserialize(deserializer).map(Some)
+}
+
 #[inline]
 pub fn serialize_20_bytes<S>(bytes: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -307,6 +368,97 @@ mod tests {
         input == decoded
     }
 
+    #[test]
+    fn test_urldecode_20_bytes_all_bytes() {
+        for byte in 0..=u8::MAX {
+            let expected = [byte; 20];
+
+            let lowercase = format!("%{:02x}", byte).repeat(20);
+            let uppercase = format!("%{:02X}", byte).repeat(20);
+
+            assert_eq!(urldecode_20_bytes(&lowercase).unwrap(), expected);
+            assert_eq!(urldecode_20_bytes(&uppercase).unwrap(), expected);
+
+            let raw = (byte as char).to_string().repeat(20);
+
+            match byte {
+                b'+' => assert_eq!(urldecode_20_bytes(&raw).unwrap(), [b' '; 20]),
+                b'%' => assert!(urldecode_20_bytes(&raw).is_err()),
+                byte if byte.is_ascii_graphic() => {
+                    assert_eq!(urldecode_20_bytes(&raw).unwrap(), expected)
+                }
+                // Control characters, space and raw non-ASCII characters,
+                // including the multi-byte UTF-8 encodings of U+0080 to U+00FF
+                _ => assert!(urldecode_20_bytes(&raw).is_err(), "{:?}", raw),
+            }
+        }
+    }
+
+    #[test]
+    fn test_urldecode_20_bytes_reserved_characters() {
+        // Reserved characters other than '&', '=' and '+' (which have
+        // special meaning in query strings) decode to themselves
+        let input = format!("{}{}", "!#$'()*,/:;?@[]%26%3d%2B", "%00".repeat(2));
+
+        assert_eq!(
+            &urldecode_20_bytes(&input).unwrap(),
+            b"!#$'()*,/:;?@[]&=+\0\0"
+        );
+
+        // '+' is a space, as is "%20"
+        assert_eq!(urldecode_20_bytes(&"+%20".repeat(10)).unwrap(), [b' '; 20]);
+    }
+
+    #[test]
+    fn test_urldecode_20_bytes_invalid() {
+        let valid = "%00".repeat(20);
+
+        assert_eq!(urldecode_20_bytes(&valid).unwrap(), [0; 20]);
+
+        for (invalid, expected_error) in [
+            ("%00".repeat(19), "expected 20 bytes, got 19"),
+            ("%00".repeat(21), "trailing data at position 60"),
+            (
+                "%00".repeat(19) + "%",
+                "truncated percent-encoding at position 57",
+            ),
+            (
+                "%00".repeat(19) + "%0",
+                "truncated percent-encoding at position 57",
+            ),
+            (
+                "%00".repeat(19) + "%g0",
+                "invalid percent-encoding \"%g0\" at position 57",
+            ),
+            // U+0131 would be truncated to b'1' if cast to u8
+            ("%00".repeat(19) + "%\u{131}1", "invalid percent-encoding"),
+            ("%00".repeat(19) + "%1\u{131}", "invalid percent-encoding"),
+            (
+                "%00".repeat(19) + "\u{e9}",
+                "invalid character 'é' at position 57",
+            ),
+            ("%00".repeat(19) + "\u{100}", "invalid character"),
+            (
+                "%00".repeat(19) + " ",
+                "invalid character ' ' at position 57",
+            ),
+        ] {
+            let err = urldecode_20_bytes(&invalid).unwrap_err().to_string();
+
+            assert!(
+                err.contains(expected_error),
+                "input: {:?}, error: {}",
+                invalid,
+                err
+            );
+        }
+
+        // Truncation at every position of a valid value
+        for i in 0..valid.len() {
+            assert!(urldecode_20_bytes(&valid[..i]).is_err());
+        }
+    }
+
     #[quickcheck]
     fn test_serde_response_peers_ipv4(peers: Vec<ResponsePeer<Ipv4Addr>>) -> bool {
         let serialized = bendy::serde::to_bytes(&peers).unwrap();
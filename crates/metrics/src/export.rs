@@ -0,0 +1,294 @@
+//! Output of collected samples
+//!
+//! Exporters are driven by [run_reporter], which periodically collects
+//! samples from all registered workers.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{Registry, Sample, SampleValue};
+
+pub trait Exporter: Send {
+    fn export(&mut self, samples: &[Sample]) -> anyhow::Result<()>;
+}
+
+/// Print samples in prometheus text format
+#[derive(Default)]
+pub struct StdoutExporter;
+
+impl Exporter for StdoutExporter {
+    fn export(&mut self, samples: &[Sample]) -> anyhow::Result<()> {
+        let mut stdout = ::std::io::stdout().lock();
+
+        write_samples(&mut stdout, samples)?;
+
+        writeln!(stdout)?;
+
+        Ok(())
+    }
+}
+
+/// Write samples in prometheus text format, with samples of the same metric
+/// grouped together below a TYPE line
+fn write_samples(output: &mut impl Write, samples: &[Sample]) -> ::std::io::Result<()> {
+    let mut samples = samples.iter().collect::<Vec<_>>();
+
+    // Stable sort keeps order of samples from different registries
+    samples.sort_by_key(|sample| sample.name);
+
+    let mut previous_name = None;
+
+    for sample in samples {
+        if previous_name != Some(sample.name) {
+            let sample_type = match sample.value {
+                SampleValue::Counter(_) => "counter",
+                SampleValue::Gauge(_) => "gauge",
+                SampleValue::Histogram(_) => "histogram",
+            };
+
+            writeln!(output, "# TYPE {} {}", sample.name, sample_type)?;
+
+            previous_name = Some(sample.name);
+        }
+
+        write_sample(output, sample)?;
+    }
+
+    Ok(())
+}
+
+fn write_sample(output: &mut impl Write, sample: &Sample) -> ::std::io::Result<()> {
+    let labels = sample
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    match &sample.value {
+        SampleValue::Counter(value) | SampleValue::Gauge(value) => {
+            writeln!(output, "{}{{{}}} {}", sample.name, labels, value)
+        }
+        SampleValue::Histogram(histogram) => {
+            let separator = if labels.is_empty() { "" } else { "," };
+
+            for (bound, count) in histogram.buckets.iter() {
+                writeln!(
+                    output,
+                    "{}_bucket{{{}{}le=\"{}\"}} {}",
+                    sample.name, labels, separator, bound, count
+                )?;
+            }
+
+            // Values above the largest bound are only counted here
+            writeln!(
+                output,
+                "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                sample.name, labels, separator, histogram.count
+            )?;
+            writeln!(
+                output,
+                "{}_sum{{{}}} {}",
+                sample.name, labels, histogram.sum
+            )?;
+            writeln!(
+                output,
+                "{}_count{{{}}} {}",
+                sample.name, labels, histogram.count
+            )
+        }
+    }
+}
+
+/// Append one JSON object per export to a file
+pub struct JsonFileExporter {
+    file: File,
+}
+
+impl JsonFileExporter {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open metrics json file {}", path.to_string_lossy()))?;
+
+        Ok(Self { file })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: u64,
+    samples: &'a [Sample],
+}
+
+impl Exporter for JsonFileExporter {
+    fn export(&mut self, samples: &[Sample]) -> anyhow::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        serde_json::to_writer(&mut self.file, &JsonLine { timestamp, samples })
+            .context("serialize samples")?;
+
+        self.file.write_all(b"\n").context("write newline")?;
+
+        Ok(())
+    }
+}
+
+/// Collect samples from all registries and pass them to all exporters
+pub fn export_once(registries: &[Registry], exporters: &mut [Box<dyn Exporter>]) {
+    let samples = registries
+        .iter()
+        .flat_map(|registry| registry.collect())
+        .collect::<Vec<_>>();
+
+    for exporter in exporters.iter_mut() {
+        if let Err(err) = exporter.export(&samples) {
+            ::log::error!("Couldn't export metrics: {:#}", err);
+        }
+    }
+}
+
+/// Spawn thread that exports samples every `interval`
+///
+/// The thread waits between exports by calling `sleep`, which should return
+/// true if the reporter is to stop, e.g., because the tracker is shutting
+/// down. Samples are exported once more before stopping.
+pub fn run_reporter<F>(
+    interval: Duration,
+    registries: Vec<Registry>,
+    mut exporters: Vec<Box<dyn Exporter>>,
+    mut sleep: F,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>>
+where
+    F: FnMut(Duration) -> bool + Send + 'static,
+{
+    Builder::new()
+        .name("metrics-reporter".into())
+        .spawn(move || loop {
+            let start = Instant::now();
+
+            export_once(&registries, &mut exporters);
+
+            let remaining = interval.saturating_sub(start.elapsed());
+
+            if sleep(remaining) {
+                export_once(&registries, &mut exporters);
+
+                return Ok(());
+            }
+        })
+        .context("spawn metrics reporter")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{names, Labels};
+
+    use super::*;
+
+    #[test]
+    fn test_json_file_exporter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.jsonl");
+
+        let registry = Registry::new(Labels::new().with("worker_index", 1));
+
+        registry
+            .counter(
+                names::RESPONSES_TOTAL,
+                Labels::new().with("type", "announce"),
+            )
+            .increment(7);
+
+        let mut exporters: Vec<Box<dyn Exporter>> =
+            vec![Box::new(JsonFileExporter::new(&path).unwrap())];
+
+        export_once(::std::slice::from_ref(&registry), &mut exporters);
+        export_once(&[registry], &mut exporters);
+
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        let sample = &line["samples"][0];
+
+        assert_eq!(sample["name"], names::RESPONSES_TOTAL);
+        assert_eq!(sample["labels"]["worker_index"], "1");
+        assert_eq!(sample["labels"]["type"], "announce");
+        assert_eq!(sample["value"]["counter"], 7);
+    }
+
+    #[test]
+    fn test_run_reporter_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.jsonl");
+
+        let exporters: Vec<Box<dyn Exporter>> =
+            vec![Box::new(JsonFileExporter::new(&path).unwrap())];
+
+        let mut num_sleeps = 0;
+
+        let handle = run_reporter(
+            Duration::from_secs(60),
+            vec![Registry::new(Labels::new())],
+            exporters,
+            move |_| {
+                num_sleeps += 1;
+
+                num_sleeps == 2
+            },
+        )
+        .unwrap();
+
+        handle.join().unwrap().unwrap();
+
+        // Once per interval and once more when stopping
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(contents.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_write_samples() {
+        let registry = Registry::new(Labels::new().with("worker_index", 1));
+
+        let histogram = registry.histogram("latency", Labels::new(), vec![1, 10]);
+
+        histogram.record(1);
+        histogram.record(5);
+        histogram.record(100);
+
+        registry.gauge("connections", Labels::new()).set(3);
+
+        let mut output = Vec::new();
+
+        write_samples(&mut output, &registry.collect()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "# TYPE connections gauge\n",
+                "connections{worker_index=\"1\"} 3\n",
+                "# TYPE latency histogram\n",
+                "latency_bucket{worker_index=\"1\",le=\"1\"} 1\n",
+                "latency_bucket{worker_index=\"1\",le=\"10\"} 2\n",
+                "latency_bucket{worker_index=\"1\",le=\"+Inf\"} 3\n",
+                "latency_sum{worker_index=\"1\"} 106\n",
+                "latency_count{worker_index=\"1\"} 3\n",
+            )
+        );
+    }
+}
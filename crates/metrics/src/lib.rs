@@ -0,0 +1,544 @@
+//! Statistics types shared by the aquatic trackers
+//!
+//! Workers register counters, gauges and histograms in a [Registry] and
+//! update them through cheap atomic operations. A reporting task
+//! periodically collects [Sample]s from all registries and passes them to
+//! one or more [exporters](export::Exporter).
+//!
+//! Metric names that all trackers have in common are found in [names].
+
+pub mod export;
+pub mod names;
+
+// Synchronization primitives are swapped for loom's when building with
+// `--cfg loom`, so that tests in `tests/loom.rs` can explore interleavings
+#[cfg(loom)]
+use loom::sync::{
+    atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+#[cfg(not(loom))]
+use std::sync::{
+    atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+/// Monotonically increasing count
+#[derive(Debug, Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    #[inline]
+    pub fn increment(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Return count and reset it to zero, for per-interval reporting
+    pub fn take(&self) -> usize {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down, e.g., number of active connections
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: usize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn increment(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Decrease value, stopping at zero
+    #[inline]
+    pub fn decrement(&self, n: usize) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                Some(value.saturating_sub(n))
+            });
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Histogram with fixed bucket upper bounds
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Box<[u64]>,
+    /// One count per bound plus one for values above the largest bound
+    buckets: Box<[AtomicU64]>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// Create histogram with given (inclusive) bucket upper bounds
+    pub fn new(mut bounds: Vec<u64>) -> Self {
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let buckets = ::std::iter::repeat_with(AtomicU64::default)
+            .take(bounds.len() + 1)
+            .collect();
+
+        Self {
+            bounds: bounds.into(),
+            buckets,
+            sum: Default::default(),
+            count: Default::default(),
+        }
+    }
+
+    /// Create histogram with bounds 1, 2, 4, .. 2^(num_buckets - 1)
+    pub fn exponential(num_buckets: u32) -> Self {
+        Self::new((0..num_buckets.min(64)).map(|i| 1 << i).collect())
+    }
+
+    #[inline]
+    pub fn record(&self, value: u64) {
+        let index = self.bounds.partition_point(|bound| *bound < value);
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+
+                (*bound, cumulative)
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            sum: self.sum.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Histogram state with cumulative bucket counts, like in the prometheus
+/// exposition format
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HistogramSnapshot {
+    /// Upper bound and number of values less than or equal to it
+    pub buckets: Vec<(u64, u64)>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+/// Number of index bits of [HyperLogLog] hashes
+const HYPERLOGLOG_PRECISION: u32 = 12;
+const HYPERLOGLOG_NUM_REGISTERS: usize = 1 << HYPERLOGLOG_PRECISION;
+
+/// Estimate of number of distinct values inserted, e.g., client IPs
+///
+/// Uses 4 KiB of memory regardless of the number of values and has a
+/// standard error of around 1.6%. Values are hashed with fixed keys, so
+/// [snapshots](HyperLogLogSnapshot) of different sketches can be merged to
+/// estimate the number of distinct values inserted into any of them.
+#[derive(Debug)]
+pub struct HyperLogLog {
+    registers: Box<[AtomicU8]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: ::std::iter::repeat_with(AtomicU8::default)
+                .take(HYPERLOGLOG_NUM_REGISTERS)
+                .collect(),
+        }
+    }
+}
+
+impl HyperLogLog {
+    #[inline]
+    pub fn insert<T: Hash + ?Sized>(&self, value: &T) {
+        let (index, rank) = hyperloglog_index_and_rank(value);
+        let register = &self.registers[index];
+
+        // Most insertions don't change the register, so avoid writing to
+        // cache lines shared with other threads when possible
+        if register.load(Ordering::Relaxed) < rank {
+            register.fetch_max(rank, Ordering::Relaxed);
+        }
+    }
+
+    /// Return current state and reset it, for per-interval reporting
+    pub fn take(&self) -> HyperLogLogSnapshot {
+        HyperLogLogSnapshot {
+            registers: self
+                .registers
+                .iter()
+                .map(|register| register.swap(0, Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// State of a [HyperLogLog] at time of collection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLogSnapshot {
+    registers: Box<[u8]>,
+}
+
+impl Default for HyperLogLogSnapshot {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; HYPERLOGLOG_NUM_REGISTERS].into_boxed_slice(),
+        }
+    }
+}
+
+impl HyperLogLogSnapshot {
+    /// Merge with other snapshot, as if all values had been inserted into
+    /// the same sketch
+    pub fn merge(&mut self, other: &Self) {
+        for (a, b) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *a = (*a).max(*b);
+        }
+    }
+
+    /// Estimated number of distinct values
+    pub fn estimate(&self) -> usize {
+        let m = HYPERLOGLOG_NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2.0f64.powi(-i32::from(*register)))
+            .sum();
+        let num_zero = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+
+        let estimate = alpha * m * m / sum;
+
+        // Use linear counting for small cardinalities, where the raw
+        // estimate is biased
+        if estimate <= 2.5 * m && num_zero != 0 {
+            (m * (m / num_zero as f64).ln()).round() as usize
+        } else {
+            estimate.round() as usize
+        }
+    }
+}
+
+#[inline]
+fn hyperloglog_index_and_rank<T: Hash + ?Sized>(value: &T) -> (usize, u8) {
+    let mut hasher = DefaultHasher::new();
+
+    value.hash(&mut hasher);
+
+    let hash = hasher.finish();
+
+    let index = (hash >> (64 - HYPERLOGLOG_PRECISION)) as usize;
+    // Position of first set bit among remaining bits, or one past the
+    // last of them if none are set
+    let rank = ((hash << HYPERLOGLOG_PRECISION).leading_zeros() + 1)
+        .min(64 - HYPERLOGLOG_PRECISION + 1) as u8;
+
+    (index, rank)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleValue {
+    Counter(usize),
+    Gauge(usize),
+    Histogram(HistogramSnapshot),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Labels(pub Vec<(&'static str, String)>);
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.0.push((key, value.to_string()));
+
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.0.iter().map(|(k, v)| (*k, v.as_str()))
+    }
+
+    fn merged(&self, other: &Labels) -> Self {
+        Self(self.0.iter().chain(other.0.iter()).cloned().collect())
+    }
+}
+
+impl Serialize for Labels {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+
+        for (k, v) in self.0.iter() {
+            map.serialize_entry(k, v)?;
+        }
+
+        map.end()
+    }
+}
+
+/// Metric value at time of collection
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Labels,
+    pub value: SampleValue,
+}
+
+#[derive(Clone, Debug)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+    HyperLogLog(Arc<HyperLogLog>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: &'static str,
+    labels: Labels,
+    metric: Metric,
+}
+
+/// Collection of metrics, typically one per worker
+///
+/// Cloning returns a handle to the same registry. Registering is relatively
+/// expensive and should be done at worker startup. Registering a metric
+/// that already exists (same name and labels) returns the existing one.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    /// Added to labels of all metrics in registry
+    common_labels: Labels,
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Registry {
+    pub fn new(common_labels: Labels) -> Self {
+        Self {
+            common_labels,
+            entries: Default::default(),
+        }
+    }
+
+    pub fn counter(&self, name: &'static str, labels: Labels) -> Arc<Counter> {
+        match self.get_or_insert(name, labels, || Metric::Counter(Default::default())) {
+            Metric::Counter(counter) => counter,
+            _ => panic!("metric {} already registered with different type", name),
+        }
+    }
+
+    pub fn gauge(&self, name: &'static str, labels: Labels) -> Arc<Gauge> {
+        match self.get_or_insert(name, labels, || Metric::Gauge(Default::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => panic!("metric {} already registered with different type", name),
+        }
+    }
+
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        labels: Labels,
+        bounds: Vec<u64>,
+    ) -> Arc<Histogram> {
+        match self.get_or_insert(name, labels, || {
+            Metric::Histogram(Arc::new(Histogram::new(bounds)))
+        }) {
+            Metric::Histogram(histogram) => histogram,
+            _ => panic!("metric {} already registered with different type", name),
+        }
+    }
+
+    /// Register [HyperLogLog], reported as a gauge with its estimate
+    ///
+    /// Registering the sketch in a registry shared by several workers
+    /// makes them all insert into the same one.
+    pub fn hyperloglog(&self, name: &'static str, labels: Labels) -> Arc<HyperLogLog> {
+        match self.get_or_insert(name, labels, || Metric::HyperLogLog(Default::default())) {
+            Metric::HyperLogLog(hyperloglog) => hyperloglog,
+            _ => panic!("metric {} already registered with different type", name),
+        }
+    }
+
+    /// Collect current values of all metrics
+    ///
+    /// Counters are reported as totals since registration. HyperLogLog
+    /// sketches are reset, so their estimates cover the time since the
+    /// previous collection.
+    pub fn collect(&self) -> Vec<Sample> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .iter()
+            .map(|entry| Sample {
+                name: entry.name,
+                labels: entry.labels.clone(),
+                value: match &entry.metric {
+                    Metric::Counter(counter) => SampleValue::Counter(counter.get()),
+                    Metric::Gauge(gauge) => SampleValue::Gauge(gauge.get()),
+                    Metric::Histogram(histogram) => SampleValue::Histogram(histogram.snapshot()),
+                    Metric::HyperLogLog(hyperloglog) => {
+                        SampleValue::Gauge(hyperloglog.take().estimate())
+                    }
+                },
+            })
+            .collect()
+    }
+
+    fn get_or_insert<F: FnOnce() -> Metric>(
+        &self,
+        name: &'static str,
+        labels: Labels,
+        create: F,
+    ) -> Metric {
+        let labels = self.common_labels.merged(&labels);
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.name == name && entry.labels == labels)
+        {
+            return entry.metric.clone();
+        }
+
+        let metric = create();
+
+        entries.push(Entry {
+            name,
+            labels,
+            metric: metric.clone(),
+        });
+
+        metric
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new(Labels::new().with("worker_index", 0));
+
+        let requests = registry.counter(names::REQUESTS_TOTAL, Labels::new());
+        let connections = registry.gauge(names::ACTIVE_CONNECTIONS, Labels::new());
+
+        requests.increment(3);
+        registry
+            .counter(names::REQUESTS_TOTAL, Labels::new())
+            .increment(1);
+        connections.increment(2);
+        connections.decrement(5);
+
+        let samples = registry.collect();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].value, SampleValue::Counter(4));
+        assert_eq!(samples[0].labels.iter().next(), Some(("worker_index", "0")));
+        assert_eq!(samples[1].value, SampleValue::Gauge(0));
+        assert_eq!(requests.take(), 4);
+        assert_eq!(requests.get(), 0);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(vec![10, 1, 100]);
+
+        for value in [0, 1, 5, 50, 1000] {
+            histogram.record(value);
+        }
+
+        assert_eq!(
+            histogram.snapshot(),
+            HistogramSnapshot {
+                buckets: vec![(1, 2), (10, 3), (100, 4)],
+                sum: 1056,
+                count: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let a = HyperLogLog::default();
+        let b = HyperLogLog::default();
+
+        assert_eq!(a.take().estimate(), 0);
+
+        for i in 0..50_000u32 {
+            a.insert(&i);
+            a.insert(&i);
+        }
+        for i in 25_000..100_000u32 {
+            b.insert(&i);
+        }
+
+        let within =
+            |estimate: usize, expected: f64| (estimate as f64 - expected).abs() / expected < 0.05;
+
+        let mut snapshot = a.take();
+
+        assert!(within(snapshot.estimate(), 50_000.0));
+
+        snapshot.merge(&b.take());
+
+        assert!(within(snapshot.estimate(), 100_000.0));
+
+        // Small cardinalities are estimated almost exactly
+        for i in 0..10u32 {
+            a.insert(&i);
+        }
+
+        assert_eq!(a.take().estimate(), 10);
+        assert_eq!(a.take(), HyperLogLogSnapshot::default());
+
+        let registry = Registry::new(Labels::new());
+
+        registry
+            .hyperloglog(names::UNIQUE_IPS, Labels::new())
+            .insert("10.0.0.1");
+        registry
+            .hyperloglog(names::UNIQUE_IPS, Labels::new())
+            .insert("10.0.0.2");
+
+        assert_eq!(registry.collect()[0].value, SampleValue::Gauge(2));
+        assert_eq!(registry.collect()[0].value, SampleValue::Gauge(0));
+    }
+}
@@ -0,0 +1,49 @@
+//! Metric names reported by all trackers where applicable
+//!
+//! Labels in common use are `ip_version` ("4" or "6"), `worker_index` and
+//! `type` (request or response type). Closed connections, removed peers and
+//! dropped answers are labelled by `reason`.
+
+pub const REQUESTS_TOTAL: &str = "aquatic_requests_total";
+pub const RESPONSES_TOTAL: &str = "aquatic_responses_total";
+pub const REQUESTS_BLOCKED_TOTAL: &str = "aquatic_requests_blocked_total";
+pub const REQUESTS_INVALID_LENGTH_TOTAL: &str = "aquatic_requests_invalid_length_total";
+pub const RESPONSES_AMPLIFICATION_DROPPED_TOTAL: &str =
+    "aquatic_responses_amplification_dropped_total";
+pub const CONNECT_REQUESTS_THROTTLED_TOTAL: &str = "aquatic_connect_requests_throttled_total";
+pub const ANNOUNCE_REQUESTS_PORT_ZERO_TOTAL: &str = "aquatic_announce_requests_port_zero_total";
+pub const ANNOUNCE_REQUESTS_BY_COUNTRY_TOTAL: &str = "aquatic_announce_requests_by_country_total";
+pub const RX_BYTES: &str = "aquatic_rx_bytes";
+pub const TX_BYTES: &str = "aquatic_tx_bytes";
+pub const REQUEST_PARSE_FAILURES_TOTAL: &str = "aquatic_request_parse_failures_total";
+pub const REQUESTS_TOO_LARGE_TOTAL: &str = "aquatic_requests_too_large_total";
+pub const REQUESTS_NOT_FOUND_TOTAL: &str = "aquatic_requests_not_found_total";
+pub const REQUESTS_BY_USER_AGENT_TOTAL: &str = "aquatic_requests_by_user_agent_total";
+pub const REQUESTS_USER_AGENT_DENIED_TOTAL: &str = "aquatic_requests_user_agent_denied_total";
+pub const REQUESTS_EXPIRED_TOTAL: &str = "aquatic_requests_expired_total";
+pub const TLS_HANDSHAKE_FAILURES_TOTAL: &str = "aquatic_tls_handshake_failures_total";
+pub const TLS_HANDSHAKE_SUCCESSES_TOTAL: &str = "aquatic_tls_handshake_successes_total";
+pub const WS_HANDSHAKE_FAILURES_TOTAL: &str = "aquatic_ws_handshake_failures_total";
+pub const WS_HANDSHAKE_ORIGIN_REJECTIONS_TOTAL: &str =
+    "aquatic_ws_handshake_origin_rejections_total";
+pub const WS_HANDSHAKE_USER_AGENT_REJECTIONS_TOTAL: &str =
+    "aquatic_ws_handshake_user_agent_rejections_total";
+pub const WS_HANDSHAKES_BY_USER_AGENT_TOTAL: &str = "aquatic_ws_handshakes_by_user_agent_total";
+pub const WS_HANDSHAKE_SUCCESSES_TOTAL: &str = "aquatic_ws_handshake_successes_total";
+pub const TCP_ACCEPTS_TOTAL: &str = "aquatic_tcp_accepts_total";
+pub const CONNECTION_REQUESTS_TOTAL: &str = "aquatic_connection_requests_total";
+pub const CONNECTIONS_CLOSED_TOTAL: &str = "aquatic_connections_closed_total";
+pub const ACTIVE_CONNECTIONS: &str = "aquatic_active_connections";
+pub const QUEUED_REQUESTS: &str = "aquatic_queued_requests";
+pub const TORRENTS: &str = "aquatic_torrents";
+pub const PEERS: &str = "aquatic_peers";
+pub const PEERS_ADDED_TOTAL: &str = "aquatic_peers_added_total";
+pub const PEERS_REMOVED_TOTAL: &str = "aquatic_peers_removed_total";
+pub const ANSWERS_DROPPED_TOTAL: &str = "aquatic_answers_dropped_total";
+pub const PEERS_PER_TORRENT: &str = "aquatic_peers_per_torrent";
+pub const PEER_CLIENTS: &str = "aquatic_peer_clients";
+pub const PEER_ID_PREFIXES: &str = "aquatic_peer_id_prefixes";
+pub const UNIQUE_IPS: &str = "aquatic_unique_ips";
+pub const UNIQUE_ASNS: &str = "aquatic_unique_asns";
+pub const CLEANING_RECLAIMED_BYTES: &str = "aquatic_cleaning_reclaimed_bytes";
+pub const BUILD_INFO: &str = "aquatic_build_info";
@@ -0,0 +1,93 @@
+//! Concurrency tests run with [loom](https://docs.rs/loom), which explores
+//! possible thread interleavings:
+//!
+//! ```sh
+//! RUSTFLAGS="--cfg loom" cargo test --release -p aquatic_metrics --test loom
+//! ```
+#![cfg(loom)]
+
+use aquatic_metrics::{names, Labels, Registry, SampleValue};
+use loom::thread;
+
+/// Counts must not be lost when a reporter takes them while workers
+/// increment them
+#[test]
+fn loom_counter_take() {
+    loom::model(|| {
+        let registry = Registry::new(Labels::new());
+        let counter = registry.counter(names::REQUESTS_TOTAL, Labels::new());
+
+        let handles = (0..2)
+            .map(|_| {
+                let counter = counter.clone();
+
+                thread::spawn(move || counter.increment(1))
+            })
+            .collect::<Vec<_>>();
+
+        let taken = counter.take();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(taken + counter.take(), 2);
+    });
+}
+
+/// Gauges never wrap around below zero, even when decrements race with
+/// increments
+#[test]
+fn loom_gauge_decrement() {
+    loom::model(|| {
+        let registry = Registry::new(Labels::new());
+        let gauge = registry.gauge(names::ACTIVE_CONNECTIONS, Labels::new());
+
+        gauge.set(1);
+
+        let decrementer = {
+            let gauge = gauge.clone();
+
+            thread::spawn(move || {
+                gauge.decrement(1);
+                gauge.decrement(1);
+            })
+        };
+
+        gauge.increment(1);
+
+        decrementer.join().unwrap();
+
+        assert!(gauge.get() <= 1);
+    });
+}
+
+/// Workers registering the same metric concurrently must get the same
+/// instance, so that no updates go unreported
+#[test]
+fn loom_registry_get_or_insert() {
+    loom::model(|| {
+        let registry = Registry::new(Labels::new().with("worker_index", 0));
+
+        let handle = {
+            let registry = registry.clone();
+
+            thread::spawn(move || {
+                registry
+                    .counter(names::REQUESTS_TOTAL, Labels::new())
+                    .increment(1)
+            })
+        };
+
+        registry
+            .counter(names::REQUESTS_TOTAL, Labels::new())
+            .increment(1);
+
+        handle.join().unwrap();
+
+        let samples = registry.collect();
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value, SampleValue::Counter(2));
+    });
+}
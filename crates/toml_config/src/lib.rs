@@ -123,4 +123,33 @@ pub mod __private {
 
     impl_trait!(PathBuf);
     impl_trait!(SocketAddr);
+
+    /// Fields that are `None` by default are left out, e.g., deprecated
+    /// settings that are only kept for compatibility
+    impl<T: Private> Private for Option<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            match self {
+                Some(value) => value.__to_string(comment, field_name),
+                None => String::new(),
+            }
+        }
+    }
+
+    /// Arrays are written inline, so fields containing them must come before
+    /// fields containing structs
+    impl<T: serde::Serialize> Private for Vec<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            let mut output = String::new();
+
+            if let Some(comment) = comment {
+                output.push_str(&comment);
+            }
+
+            let value = crate::toml::Value::try_from(self).unwrap();
+
+            output.push_str(&format!("{} = {}\n", field_name, value));
+
+            output
+        }
+    }
 }
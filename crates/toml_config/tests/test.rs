@@ -28,6 +28,8 @@ struct TestConfig {
     /// Comment for b
     b: usize,
     c: bool,
+    /// Comment for d
+    d: Vec<String>,
     /// Comment for TestConfigInnerA
     inner_a: TestConfigInnerA,
 }
@@ -38,6 +40,7 @@ impl Default for TestConfig {
             a: "Hello, world!".into(),
             b: 100,
             c: true,
+            d: vec!["Hello".into(), "world".into()],
             inner_a: Default::default(),
         }
     }
@@ -0,0 +1,122 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroU16;
+use std::time::Duration;
+
+use aquatic_common::{CanonicalSocketAddr, ValidUntil};
+use aquatic_udp::common::{IpVersionStatistics, SocketWorkerStatistics, SwarmRng};
+use aquatic_udp::config::Config;
+use aquatic_udp::swarm::{AnnounceResponseCache, TorrentMaps};
+use aquatic_udp_protocol::*;
+
+/// Announce request by peer `i`, which is a seeder if `i` is even
+fn announce_request(info_hash: InfoHash, i: usize) -> (AnnounceRequest, CanonicalSocketAddr) {
+    let mut peer_id = PeerId([0; 20]);
+
+    peer_id.0[..8].copy_from_slice(&(i as u64).to_be_bytes());
+
+    let request = AnnounceRequest {
+        connection_id: ConnectionId::new(0),
+        action_placeholder: Default::default(),
+        transaction_id: TransactionId::new(0),
+        info_hash,
+        peer_id,
+        bytes_downloaded: NumberOfBytes::new(0),
+        bytes_uploaded: NumberOfBytes::new(0),
+        bytes_left: NumberOfBytes::new((i % 2) as i64),
+        event: AnnounceEvent::None.into(),
+        ip_address: Ipv4AddrBytes([0; 4]),
+        key: PeerKey::new(0),
+        peers_wanted: NumberOfPeers::new(-1),
+        port: Port::new(NonZeroU16::new(6881).unwrap()),
+    };
+
+    let src = CanonicalSocketAddr::new(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32)),
+        6881,
+    ));
+
+    (request, src)
+}
+
+fn info_hash(i: usize) -> InfoHash {
+    let mut info_hash = InfoHash([0; 20]);
+
+    info_hash.0[..8].copy_from_slice(&(i as u64).to_be_bytes());
+
+    info_hash
+}
+
+/// Announce `peers_per_torrent` peers to each of `num_torrents` torrents,
+/// then benchmark re-announces by the same peers, cycling through torrents
+///
+/// Swarms with many torrents don't fit in CPU caches, so the size of peer
+/// map entries affects announce performance.
+fn bench_reannounce(c: &mut Criterion, name: &str, num_torrents: usize, peers_per_torrent: usize) {
+    let config = Config::default();
+    let statistics = IpVersionStatistics::<SocketWorkerStatistics>::default();
+    let (statistics_sender, statistics_receiver) = crossbeam_channel::unbounded();
+    let mut rng = SwarmRng::seed_from_u64(&config, 0);
+    let mut cache = AnnounceResponseCache::new(&config);
+    let torrent_maps = TorrentMaps::default();
+    let valid_until = ValidUntil::from_seconds(u32::MAX >> 2);
+
+    let requests = (0..peers_per_torrent)
+        .flat_map(|peer| {
+            (0..num_torrents).map(move |torrent| announce_request(info_hash(torrent), peer))
+        })
+        .collect::<Vec<_>>();
+
+    for (request, src) in requests.iter() {
+        torrent_maps.announce(
+            &config,
+            &statistics,
+            &statistics_sender,
+            &mut rng,
+            request,
+            *src,
+            valid_until,
+            &mut cache,
+        );
+    }
+
+    statistics_receiver.try_iter().for_each(drop);
+
+    let mut i = 0;
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let (request, src) = &requests[i % requests.len()];
+
+            i += 1;
+
+            black_box(torrent_maps.announce(
+                &config,
+                &statistics,
+                &statistics_sender,
+                &mut rng,
+                black_box(request),
+                *src,
+                valid_until,
+                &mut cache,
+            ));
+        })
+    });
+}
+
+pub fn bench_announce_large_swarm(c: &mut Criterion) {
+    bench_reannounce(c, "handle-announce-request-large-swarm", 1, 10_000);
+}
+
+pub fn bench_announce_many_torrents(c: &mut Criterion) {
+    bench_reannounce(c, "handle-announce-request-many-torrents", 100_000, 20);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .measurement_time(Duration::from_secs(30))
+        .significance_level(0.01);
+    targets = bench_announce_large_swarm, bench_announce_many_torrents
+}
+criterion_main!(benches);
@@ -1,19 +1,26 @@
 use std::iter::repeat_with;
-use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
-use aquatic_common::ServerStartInstant;
+use aquatic_common::ip_blocklist::IpBlocklistArcSwap;
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
+use aquatic_metrics::{Counter, Gauge, HyperLogLog};
 use aquatic_udp_protocol::*;
+use crossbeam_channel::Sender;
 use crossbeam_utils::CachePadded;
 use hdrhistogram::Histogram;
+use parking_lot::Mutex;
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
 
-use crate::config::Config;
-use crate::swarm::TorrentMaps;
+use crate::config::{Config, PeerSelectionRng};
+use crate::geoip::{AsnLookup, CountryLookup};
+use crate::swarm::{TorrentHealth, TorrentMaps};
 
 pub const BUFFER_SIZE: usize = 8192;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum IpVersion {
     V4,
     V6,
@@ -63,21 +70,42 @@ impl<T> IpVersionStatistics<T> {
 
 #[derive(Default)]
 pub struct SocketWorkerStatistics {
-    pub requests: AtomicUsize,
-    pub responses_connect: AtomicUsize,
-    pub responses_announce: AtomicUsize,
-    pub responses_scrape: AtomicUsize,
-    pub responses_error: AtomicUsize,
-    pub bytes_received: AtomicUsize,
-    pub bytes_sent: AtomicUsize,
+    pub requests: Counter,
+    pub responses_connect: Counter,
+    pub responses_announce: Counter,
+    pub responses_scrape: Counter,
+    pub responses_error: Counter,
+    pub connect_requests_throttled: Counter,
+    /// Connect and error responses dropped due to
+    /// `protocol.max_amplification_factor`
+    pub responses_amplification_dropped: Counter,
+    pub announce_requests_port_zero: Counter,
+    pub requests_blocked: Counter,
+    /// Datagrams ignored since they are too short or long for their action
+    pub requests_invalid_length: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    /// Peers inserted into a swarm they were not already in
+    pub peers_added: Counter,
+    /// Peers removed from a swarm due to a stopped event
+    pub peers_stopped: Counter,
+    /// Sources of parsed requests (IPv6: prefixes of
+    /// `network.ipv6_prefix_len` bits)
+    pub unique_ips: HyperLogLog,
+    /// Autonomous systems of sources of parsed requests, if an ASN database
+    /// is configured
+    pub unique_asns: HyperLogLog,
 }
 
 pub type CachePaddedArc<T> = CachePadded<Arc<CachePadded<T>>>;
 
 #[derive(Default)]
 pub struct SwarmWorkerStatistics {
-    pub torrents: AtomicUsize,
-    pub peers: AtomicUsize,
+    pub torrents: Gauge,
+    pub peers: Gauge,
+    pub reclaimed_bytes: Gauge,
+    /// Peers removed by cleaning since they stopped announcing
+    pub peers_expired: Counter,
 }
 
 pub enum StatisticsMessage {
@@ -90,18 +118,97 @@ pub enum StatisticsMessage {
 #[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_blocklist: Arc<IpBlocklistArcSwap>,
     pub torrent_maps: TorrentMaps,
     pub server_start_instant: ServerStartInstant,
+    /// Set if reachability probing is enabled
+    pub reachability_probe_sender: Option<Sender<ReachabilityProbeRequest>>,
+    /// Set if GeoIP database is configured
+    pub country_lookup: Option<Arc<CountryLookup>>,
+    /// Set if ASN database is configured
+    pub asn_lookup: Option<Arc<AsnLookup>>,
+    /// Least healthy popular torrents, updated after each cleaning pass if
+    /// `snapshot.under_seeded_report_len` is set
+    pub under_seeded_torrents: Arc<Mutex<Vec<TorrentHealth>>>,
+    pub shutdown: ShutdownSignal,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
+            ip_blocklist: Arc::new(IpBlocklistArcSwap::default()),
             torrent_maps: TorrentMaps::default(),
             server_start_instant: ServerStartInstant::new(),
+            reachability_probe_sender: None,
+            country_lookup: None,
+            asn_lookup: None,
+            under_seeded_torrents: Default::default(),
+            shutdown: ShutdownSignal::default(),
+        }
+    }
+}
+
+/// Random number generator for peer selection, see
+/// [`crate::config::ProtocolConfig::peer_selection_rng`]
+pub enum SwarmRng {
+    Small(SmallRng),
+    Wyrand(fastrand::Rng),
+}
+
+impl SwarmRng {
+    pub fn from_entropy(config: &Config) -> Self {
+        match config.protocol.peer_selection_rng {
+            PeerSelectionRng::Small => Self::Small(SmallRng::from_entropy()),
+            PeerSelectionRng::Wyrand => Self::Wyrand(fastrand::Rng::new()),
+        }
+    }
+
+    /// Deterministic generator, e.g., for tests and single-threaded mode
+    pub fn seed_from_u64(config: &Config, seed: u64) -> Self {
+        match config.protocol.peer_selection_rng {
+            PeerSelectionRng::Small => Self::Small(SmallRng::seed_from_u64(seed)),
+            PeerSelectionRng::Wyrand => Self::Wyrand(fastrand::Rng::with_seed(seed)),
+        }
+    }
+}
+
+impl RngCore for SwarmRng {
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Self::Small(rng) => rng.next_u32(),
+            Self::Wyrand(rng) => rng.u32(..),
+        }
+    }
+
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            Self::Small(rng) => rng.next_u64(),
+            Self::Wyrand(rng) => rng.u64(..),
         }
     }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            Self::Small(rng) => rng.fill_bytes(dest),
+            Self::Wyrand(rng) => rng.fill(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+
+        Ok(())
+    }
+}
+
+/// Announced peer address to probe
+#[derive(Clone, Copy, Debug)]
+pub struct ReachabilityProbeRequest {
+    pub info_hash: InfoHash,
+    pub addr: CanonicalSocketAddr,
 }
 
 #[cfg(test)]
@@ -145,4 +252,25 @@ mod tests {
 
         assert!(buf.len() <= BUFFER_SIZE);
     }
+
+    #[test]
+    fn test_swarm_rng_seeded() {
+        use rand::Rng;
+
+        for kind in [PeerSelectionRng::Small, PeerSelectionRng::Wyrand] {
+            let mut config = Config::default();
+
+            config.protocol.peer_selection_rng = kind;
+
+            let mut a = SwarmRng::seed_from_u64(&config, 1);
+            let mut b = SwarmRng::seed_from_u64(&config, 1);
+
+            for _ in 0..100 {
+                let value = a.gen_range(10..20);
+
+                assert!((10..20).contains(&value));
+                assert_eq!(value, b.gen_range(10..20));
+            }
+        }
+    }
 }
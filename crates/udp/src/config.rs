@@ -1,11 +1,15 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, ip_blocklist::IpBlocklistConfig, privileges::PrivilegeConfig,
+    PortZeroPolicy,
+};
 use cfg_if::cfg_if;
 use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
+use aquatic_udp_protocol::{AnnounceResponse, Ip, ScrapeResponse};
 
 /// aquatic_udp configuration
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
@@ -20,6 +24,8 @@ pub struct Config {
     pub protocol: ProtocolConfig,
     pub statistics: StatisticsConfig,
     pub cleaning: CleaningConfig,
+    pub reachability: ReachabilityConfig,
+    pub geoip: GeoIpConfig,
     pub privileges: PrivilegeConfig,
     /// Access list configuration
     ///
@@ -28,6 +34,20 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// IP blocklist configuration
+    ///
+    /// Requests from blocked addresses are ignored. If initial parsing of the
+    /// file fails, the program exits. Later failures result in emitting of
+    /// an error-level log message.
+    pub ip_blocklist: IpBlocklistConfig,
+    pub snapshot: SnapshotConfig,
+    pub import: ImportConfig,
+    pub maintenance: MaintenanceConfig,
+    pub debug: DebugConfig,
+    #[cfg(feature = "cpu-pinning")]
+    pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
+    #[cfg(feature = "fault-injection")]
+    pub fault_injection: FaultInjectionConfig,
 }
 
 impl Default for Config {
@@ -39,8 +59,19 @@ impl Default for Config {
             protocol: ProtocolConfig::default(),
             statistics: StatisticsConfig::default(),
             cleaning: CleaningConfig::default(),
+            reachability: ReachabilityConfig::default(),
+            geoip: GeoIpConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_blocklist: IpBlocklistConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            import: ImportConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            debug: DebugConfig::default(),
+            #[cfg(feature = "cpu-pinning")]
+            cpu_pinning: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            fault_injection: Default::default(),
         }
     }
 }
@@ -49,15 +80,59 @@ impl aquatic_common::cli::Config for Config {
     fn get_log_level(&self) -> Option<LogLevel> {
         Some(self.log_level)
     }
+
+    fn enabled_features() -> Vec<&'static str> {
+        let mut features = Vec::new();
+
+        if cfg!(feature = "prometheus") {
+            features.push("prometheus");
+        }
+        if cfg!(feature = "io-uring") {
+            features.push("io-uring");
+        }
+        if cfg!(feature = "af-xdp") {
+            features.push("af-xdp");
+        }
+        if cfg!(feature = "mimalloc") {
+            features.push("mimalloc");
+        }
+        if cfg!(feature = "cpu-pinning") {
+            features.push("cpu-pinning");
+        }
+        if cfg!(feature = "fault-injection") {
+            features.push("fault-injection");
+        }
+
+        features
+    }
+
+    fn handles_termination_signals() -> bool {
+        true
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
     /// Bind to this address
+    ///
+    /// With port 0, the operating system chooses a free port, which is
+    /// logged on startup
     pub address: SocketAddr,
     /// Only allow access over IPv6
     pub only_ipv6: bool,
+    /// Bind sockets to this network interface (SO_BINDTODEVICE), e.g.,
+    /// "eth1", so that only traffic over it is handled, regardless of
+    /// routing. Linux only. Requires CAP_NET_RAW on kernels older than 5.7.
+    ///
+    /// Leave empty to not bind to an interface.
+    pub bind_device: String,
+    /// Length of prefixes that IPv6 addresses are aggregated by in per-IP
+    /// accounting, i.e., in `protocol.max_connects_per_ip_per_second` and
+    /// `protocol.max_amplification_factor`
+    ///
+    /// Hosts can often pick new addresses in a whole /64 network at will.
+    pub ipv6_prefix_len: u8,
     /// Size of socket recv buffer. Use 0 for OS default.
     ///
     /// This setting can have a big impact on dropped packages. It might
@@ -71,8 +146,55 @@ pub struct NetworkConfig {
     /// $ sudo sysctl -w net.core.rmem_max=8000000
     /// $ sudo sysctl -w net.core.rmem_default=8000000
     pub socket_recv_buffer_size: usize,
+    /// Size of socket send buffer. Use 0 for OS default.
+    ///
+    /// A larger send buffer makes it less likely that responses can't be
+    /// sent during bursts. On Linux, values above net.core.wmem_max require
+    /// changing that system setting. The effective recv and send buffer
+    /// sizes granted by the kernel are logged on startup.
+    pub socket_send_buffer_size: usize,
     /// Poll timeout in milliseconds (mio backend only)
     pub poll_timeout_ms: u64,
+    /// After receiving packets, keep polling without timeout for this many
+    /// microseconds before falling back to waiting for `poll_timeout_ms`
+    /// (mio and AF_XDP backends only)
+    ///
+    /// Reduces latency at high packet rates at the cost of CPU usage, while
+    /// still idling cheaply when there is little traffic. 0 = disabled
+    pub busy_poll_us: u64,
+    /// Set SO_BUSY_POLL socket option to this many microseconds (Linux only)
+    ///
+    /// Makes the kernel busy poll the network device queue on receive
+    /// instead of waiting for interrupts. Requires a driver that supports
+    /// it and possibly CAP_NET_ADMIN. 0 = don't set option
+    pub socket_busy_poll_us: u32,
+    /// Set traffic class of sent packets to this value (Linux only)
+    ///
+    /// Sets IPV6_TCLASS on IPv6 sockets and IP_TOS for IPv4 traffic. 0 =
+    /// don't set options
+    pub socket_traffic_class: u8,
+    /// Path MTU discovery mode of socket (Linux only)
+    ///
+    /// Sets IP_MTU_DISCOVER and, on IPv6 sockets, IPV6_MTU_DISCOVER. Setting
+    /// the don't-fragment flag makes the kernel refuse to send responses that
+    /// would need to be fragmented instead of fragmenting them, which is
+    /// useful together with `protocol.max_response_packet_bytes`.
+    pub socket_mtu_discovery: MtuDiscovery,
+    /// Don't compute UDP checksums of sent datagrams (Linux only)
+    ///
+    /// Sets SO_NO_CHECK for IPv4 traffic and UDP_NO_CHECK6_TX on IPv6
+    /// sockets. Saves some CPU time when the network device doesn't offload
+    /// checksum calculation. Zero checksums are optional in IPv4, but only
+    /// allowed for tunneling protocols in IPv6, so clients may drop IPv6
+    /// responses sent with this setting.
+    pub socket_skip_send_checksum: bool,
+    /// Receive errors caused by sent datagrams, such as ICMP port
+    /// unreachable messages, and log them at debug level (mio backend on
+    /// Linux only)
+    ///
+    /// Sets IP_RECVERR and, on IPv6 sockets, IPV6_RECVERR. The socket error
+    /// queue is drained whenever the kernel reports errors.
+    pub socket_recv_errors: bool,
     /// Store this many responses at most for retrying (once) on send failure
     /// (mio backend only)
     ///
@@ -80,13 +202,96 @@ pub struct NetworkConfig {
     /// such as FreeBSD. Setting the value to zero disables resending
     /// functionality.
     pub resend_buffer_max_len: usize,
+    /// Use UDP generic segmentation offload (GSO) when sending responses
+    /// from the resend buffer (mio backend on Linux only)
+    ///
+    /// Responses to the same address are then passed to the kernel in a
+    /// single system call. Support is checked on startup. If it is missing,
+    /// or if the network device rejects segmented sends, responses are sent
+    /// one by one instead.
+    pub use_gso: bool,
+    /// Send each response from the local address that its request was sent
+    /// to (mio backend on Linux only)
+    ///
+    /// Useful when binding to an unspecified address such as 0.0.0.0 on a
+    /// host with multiple addresses, since the kernel otherwise picks source
+    /// addresses by routing and some clients drop responses from addresses
+    /// they didn't send requests to. Local addresses are received with
+    /// IP_PKTINFO or IPV6_PKTINFO. Disables recvmmsg/sendmmsg batching.
+    /// Responses sent later, i.e., by sender threads, from the resend buffer
+    /// or after being deferred by the egress budget, still get source
+    /// addresses picked by the kernel.
+    pub pin_source_address: bool,
+    /// Egress bandwidth budget per socket worker in Mbit/s
+    ///
+    /// When the budget is exceeded, the number of peers returned in announce
+    /// responses is temporarily reduced and scrape responses are delayed.
+    /// Useful when running on a host with a capped uplink. 0 = unlimited
+    pub egress_budget_mbits: u64,
+    /// Send responses from a dedicated thread paired with each socket worker
+    /// (mio and AF_XDP backends only)
+    ///
+    /// Decouples receiving requests from sending responses, so that bursts
+    /// of large responses don't delay reception of new packets. Each sender
+    /// thread uses a duplicate handle of its worker's socket rather than a
+    /// separate socket bound to the same port, since the kernel would
+    /// otherwise distribute incoming packets to it too. Responses are
+    /// retried when the send buffer is full. GSO is not used.
+    pub use_sender_threads: bool,
+    /// Maximum number of responses waiting to be sent by each sender thread
+    pub sender_channel_capacity: usize,
+    /// Set SO_REUSEPORT_LB on sockets, so that incoming packets are
+    /// distributed among socket workers (FreeBSD only)
+    #[cfg(target_os = "freebsd")]
+    pub use_reuse_port_lb: bool,
+    /// Socket backend: "mio" or "io_uring" (Linux 6.0 or later, requires
+    /// io-uring feature)
+    ///
+    /// Support for io_uring is checked on startup, and the program exits if
+    /// it is missing. Settings marked as mio backend only don't apply to the
+    /// io_uring backend. Ignored if `use_af_xdp` is set.
+    pub backend: SocketBackend,
+    /// Deprecated, use `backend` instead. true = "io_uring", false = "mio"
     #[cfg(feature = "io-uring")]
-    pub use_io_uring: bool,
+    pub use_io_uring: Option<bool>,
     /// Number of ring entries (io_uring backend only)
     ///
     /// Will be rounded to next power of two if not already one.
     #[cfg(feature = "io-uring")]
     pub ring_size: u16,
+    /// Maximum number of datagrams to receive or send with a single
+    /// recvmmsg or sendmmsg system call (mio backend only)
+    ///
+    /// Responses are collected while handling a batch of requests and sent
+    /// together afterwards. Must be at most 1024. 0 or 1 = receive and send
+    /// datagrams one by one
+    #[cfg(feature = "mmsg")]
+    pub mmsg_batch_size: usize,
+    /// Receive requests with AF_XDP sockets (experimental)
+    ///
+    /// Each socket worker binds an AF_XDP socket to one queue of the network
+    /// interface, starting at `af_xdp_first_queue_id`, and registers it in
+    /// the XSKMAP pinned at `af_xdp_xsks_map_path`. An XDP program that
+    /// redirects tracker traffic into that map needs to be attached to the
+    /// interface separately. Responses are sent with regular UDP sockets.
+    ///
+    /// Requires running with CAP_NET_ADMIN and CAP_NET_RAW or as root, since
+    /// the sockets are created before privileges are dropped.
+    #[cfg(feature = "af-xdp")]
+    pub use_af_xdp: bool,
+    /// Network interface to bind AF_XDP sockets to
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_interface: String,
+    /// Queue id for first socket worker. Following workers use following
+    /// queues.
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_first_queue_id: u32,
+    /// Number of AF_XDP rx ring entries. Must be a power of two.
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_rx_ring_size: u32,
+    /// Path of pinned XSKMAP used by the XDP program
+    #[cfg(feature = "af-xdp")]
+    pub af_xdp_xsks_map_path: PathBuf,
 }
 
 impl NetworkConfig {
@@ -103,26 +308,137 @@ impl Default for NetworkConfig {
         Self {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
             only_ipv6: false,
+            bind_device: String::new(),
+            ipv6_prefix_len: 64,
             socket_recv_buffer_size: 8_000_000,
+            socket_send_buffer_size: 0,
             poll_timeout_ms: 50,
+            busy_poll_us: 0,
+            socket_busy_poll_us: 0,
+            socket_traffic_class: 0,
+            socket_mtu_discovery: MtuDiscovery::default(),
+            socket_skip_send_checksum: false,
+            socket_recv_errors: false,
             resend_buffer_max_len: 0,
+            use_gso: false,
+            pin_source_address: false,
+            egress_budget_mbits: 0,
+            use_sender_threads: false,
+            sender_channel_capacity: 4096,
+            #[cfg(target_os = "freebsd")]
+            use_reuse_port_lb: true,
+            backend: SocketBackend::default(),
             #[cfg(feature = "io-uring")]
-            use_io_uring: true,
+            use_io_uring: None,
             #[cfg(feature = "io-uring")]
             ring_size: 128,
+            #[cfg(feature = "mmsg")]
+            mmsg_batch_size: 32,
+            #[cfg(feature = "af-xdp")]
+            use_af_xdp: false,
+            #[cfg(feature = "af-xdp")]
+            af_xdp_interface: "eth0".into(),
+            #[cfg(feature = "af-xdp")]
+            af_xdp_first_queue_id: 0,
+            #[cfg(feature = "af-xdp")]
+            af_xdp_rx_ring_size: 2048,
+            #[cfg(feature = "af-xdp")]
+            af_xdp_xsks_map_path: "/sys/fs/bpf/aquatic_udp_xsks_map".into(),
         }
     }
 }
 
+/// Socket backend
+///
+/// io_uring is the default when support for it is compiled in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TomlConfig, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketBackend {
+    #[cfg_attr(not(feature = "io-uring"), default)]
+    Mio,
+    #[cfg(feature = "io-uring")]
+    #[default]
+    IoUring,
+}
+
+/// Path MTU discovery mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TomlConfig, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MtuDiscovery {
+    /// Don't set socket options, keeping OS defaults
+    #[default]
+    OsDefault,
+    /// Set don't-fragment flag and respect discovered path MTU
+    Do,
+    /// Set don't-fragment flag, but ignore discovered path MTU
+    Probe,
+    /// Never set don't-fragment flag
+    Dont,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ProtocolConfig {
     /// Maximum number of torrents to allow in scrape request
-    pub max_scrape_torrents: u8,
+    ///
+    /// Requests with more torrents get an error response.
+    pub max_scrape_torrents: usize,
     /// Maximum number of peers to return in announce response
     pub max_response_peers: usize,
+    /// Maximum size of responses in bytes, excluding IP and UDP headers
+    /// (0 = no limit other than the 8192 byte buffer size)
+    ///
+    /// Announce responses are capped to the number of peers and scrape
+    /// requests to the number of torrents that fit. Fragmented UDP responses
+    /// are dropped by many networks, so setting this avoids IP
+    /// fragmentation. 1200 is a conservative value.
+    pub max_response_packet_bytes: usize,
+    /// Never return any peers in announce responses
+    ///
+    /// Swarms and statistics are still updated. Useful for trackers that
+    /// only collect statistics.
+    pub announce_only: bool,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: i32,
+    /// Reuse peers selected for announce responses to large swarms for this
+    /// many milliseconds
+    ///
+    /// Reduces work during announce storms on popular torrents. Each socket
+    /// worker keeps its own cache. 0 = disabled
+    pub announce_response_cache_ms: u64,
+    /// Random number generator for selecting peers for announce responses
+    pub peer_selection_rng: PeerSelectionRng,
+    /// Ignore connect requests in datagrams smaller than this many bytes
+    ///
+    /// Connect requests are 16 bytes long according to the specification.
+    /// Requiring clients to pad them makes spoofed connect requests less
+    /// useful for reflection attacks. Longer connect requests are otherwise
+    /// ignored. 0 = disabled
+    pub min_connect_request_size: usize,
+    /// Maximum number of connect requests to respond to per second and source
+    /// IP (prefix of `network.ipv6_prefix_len` bits for IPv6) in each socket
+    /// worker
+    ///
+    /// 0 = unlimited
+    pub max_connects_per_ip_per_second: u16,
+    /// Maximum ratio of bytes sent to bytes received per source IP (prefix
+    /// of `network.ipv6_prefix_len` bits for IPv6) in each socket worker
+    /// before connect and error responses to it are dropped
+    ///
+    /// Sources that haven't presented a valid connection id may be spoofed,
+    /// so this limits how much traffic the tracker can be made to reflect.
+    /// All responses are counted, but announce and scrape responses are never
+    /// dropped. Byte counts decay with a half-life of one second. Since
+    /// announce responses are often larger than requests, values below 4 or
+    /// so may cause connect requests of regular clients to go unanswered.
+    ///
+    /// 0 = unlimited
+    pub max_amplification_factor: u16,
+    /// How to handle announce requests with port 0
+    pub port_zero_policy: PortZeroPolicy,
+    /// Return combined IPv4 and IPv6 seeder and leecher counts in scrape
+    /// responses instead of only counts for the IP version of the client
+    pub scrape_merge_ip_versions: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -130,16 +446,73 @@ impl Default for ProtocolConfig {
         Self {
             max_scrape_torrents: 70,
             max_response_peers: 30,
+            max_response_packet_bytes: 0,
+            announce_only: false,
             peer_announce_interval: 60 * 15,
+            announce_response_cache_ms: 0,
+            peer_selection_rng: PeerSelectionRng::default(),
+            min_connect_request_size: 0,
+            max_connects_per_ip_per_second: 0,
+            max_amplification_factor: 0,
+            port_zero_policy: PortZeroPolicy::Reject,
+            scrape_merge_ip_versions: false,
         }
     }
 }
 
+impl ProtocolConfig {
+    /// Maximum number of peers in announce responses with peers of type `I`,
+    /// taking `max_response_packet_bytes` into account
+    pub fn response_peers_limit<I: Ip>(&self) -> usize {
+        if self.max_response_packet_bytes == 0 {
+            self.max_response_peers
+        } else {
+            self.max_response_peers
+                .min(AnnounceResponse::<I>::max_peers_for_len(
+                    self.max_response_packet_bytes,
+                ))
+        }
+    }
+
+    /// Maximum number of torrents in scrape requests, taking
+    /// `max_response_packet_bytes` into account
+    pub fn scrape_torrents_limit(&self) -> usize {
+        if self.max_response_packet_bytes == 0 {
+            self.max_scrape_torrents
+        } else {
+            let max = ScrapeResponse::max_torrents_for_len(self.max_response_packet_bytes);
+
+            self.max_scrape_torrents.min(max)
+        }
+    }
+}
+
+/// Random number generator for peer selection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TomlConfig, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerSelectionRng {
+    /// Xoshiro256++ (SmallRng of the rand crate)
+    #[default]
+    Small,
+    /// WyRand, which is cheaper to run at high announce rates but has a
+    /// smaller state
+    Wyrand,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct StatisticsConfig {
     /// Collect and print/write statistics this often (seconds)
     pub interval: u64,
+    /// Update torrent and peer counts at most this often (seconds)
+    ///
+    /// Counts are computed during torrent cleaning, so they are updated at
+    /// most every `cleaning.torrent_cleaning_interval` seconds. Setting this
+    /// to a higher value reduces the cost of cleaning passes on instances
+    /// with many torrents, especially if `torrent_peer_histograms` is
+    /// enabled. Request and bandwidth statistics are still collected every
+    /// `interval` seconds.
+    pub torrent_count_update_interval: u64,
     /// Collect statistics on number of peers per torrent
     ///
     /// Will increase time taken for torrent cleaning.
@@ -157,6 +530,38 @@ pub struct StatisticsConfig {
     pub write_html_to_file: bool,
     /// Path to save HTML file to
     pub html_file_path: PathBuf,
+    /// Append statistics as a JSON object per line to a file
+    pub write_json_to_file: bool,
+    /// Path to append JSON lines to
+    pub json_file_path: PathBuf,
+    /// Append changes in seeder, leecher and completed counts of each
+    /// torrent since previous interval as JSON lines to a file
+    ///
+    /// Only torrents with changes are included, so that downstream
+    /// databases can be updated incrementally. Removed torrents are
+    /// included with negative seeder and leecher changes. Requires
+    /// iterating over all torrents every `interval` seconds and keeping
+    /// their counts in memory.
+    pub write_swarm_deltas_to_file: bool,
+    /// Path to append swarm delta JSON lines to
+    pub swarm_deltas_file_path: PathBuf,
+    /// Push statistics to a StatsD server over UDP
+    ///
+    /// Request, response and byte counts are sent as counters, the rest as
+    /// gauges.
+    pub push_to_statsd: bool,
+    /// Address of StatsD server
+    pub statsd_address: SocketAddr,
+    /// Push statistics to a Graphite server using the plaintext protocol
+    /// over TCP
+    pub push_to_graphite: bool,
+    /// Address of Graphite server
+    pub graphite_address: SocketAddr,
+    /// Prefix of metric names pushed to StatsD and Graphite
+    ///
+    /// IP version and metric name are appended, e.g.,
+    /// `aquatic_udp.ipv4.requests`
+    pub push_prefix: String,
     /// Run a prometheus endpoint
     #[cfg(feature = "prometheus")]
     pub run_prometheus_endpoint: bool,
@@ -174,15 +579,35 @@ pub struct StatisticsConfig {
 }
 
 impl StatisticsConfig {
+    /// Approximate interval between torrent and peer count updates (seconds)
+    pub fn torrent_count_interval(&self, cleaning: &CleaningConfig) -> u64 {
+        if self.torrent_count_update_interval <= cleaning.torrent_cleaning_interval {
+            cleaning.torrent_cleaning_interval
+        } else {
+            // Rounded up to multiple of cleaning interval
+            let cleaning_interval = cleaning.torrent_cleaning_interval.max(1);
+
+            ((self.torrent_count_update_interval + cleaning_interval - 1) / cleaning_interval)
+                * cleaning_interval
+        }
+    }
+
+    fn machine_readable_output_active(&self) -> bool {
+        self.write_json_to_file
+            | self.write_swarm_deltas_to_file
+            | self.push_to_statsd
+            | self.push_to_graphite
+    }
+
     cfg_if! {
         if #[cfg(feature = "prometheus")] {
             pub fn active(&self) -> bool {
                 (self.interval != 0) &
-                    (self.print_to_stdout | self.write_html_to_file | self.run_prometheus_endpoint)
+                    (self.print_to_stdout | self.write_html_to_file | self.run_prometheus_endpoint | self.machine_readable_output_active())
             }
         } else {
             pub fn active(&self) -> bool {
-                (self.interval != 0) & (self.print_to_stdout | self.write_html_to_file)
+                (self.interval != 0) & (self.print_to_stdout | self.write_html_to_file | self.machine_readable_output_active())
             }
         }
     }
@@ -192,11 +617,21 @@ impl Default for StatisticsConfig {
     fn default() -> Self {
         Self {
             interval: 5,
+            torrent_count_update_interval: 0,
             torrent_peer_histograms: false,
             peer_clients: false,
             print_to_stdout: false,
             write_html_to_file: false,
             html_file_path: "tmp/statistics.html".into(),
+            write_json_to_file: false,
+            json_file_path: "tmp/statistics.jsonl".into(),
+            write_swarm_deltas_to_file: false,
+            swarm_deltas_file_path: "tmp/swarm-deltas.jsonl".into(),
+            push_to_statsd: false,
+            statsd_address: SocketAddr::from(([127, 0, 0, 1], 8125)),
+            push_to_graphite: false,
+            graphite_address: SocketAddr::from(([127, 0, 0, 1], 2003)),
+            push_prefix: "aquatic_udp".into(),
             #[cfg(feature = "prometheus")]
             run_prometheus_endpoint: false,
             #[cfg(feature = "prometheus")]
@@ -216,6 +651,20 @@ pub struct CleaningConfig {
     pub max_connection_age: u32,
     /// Remove peers who have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Remove seeders who have not announced for this long (seconds)
+    /// instead, since they often announce less frequently. 0 = use
+    /// `max_peer_age` for seeders too.
+    pub max_seeder_age: u32,
+    /// Remove torrents that have not received announce requests for this
+    /// long (seconds), including any peers that haven't expired yet. 0 =
+    /// disabled
+    pub max_torrent_idle: u32,
+    /// Only reclaim memory from torrent and peer maps during cleaning when
+    /// their capacity is at least this many times their number of entries
+    ///
+    /// Shrinking maps that will soon grow again causes needless
+    /// reallocations. 1.0 = always shrink, 0.0 = never shrink
+    pub shrink_threshold: f64,
 }
 
 impl Default for CleaningConfig {
@@ -224,6 +673,369 @@ impl Default for CleaningConfig {
             torrent_cleaning_interval: 60 * 2,
             max_connection_age: 60 * 2,
             max_peer_age: 60 * 20,
+            max_seeder_age: 0,
+            max_torrent_idle: 0,
+            shrink_threshold: 2.0,
+        }
+    }
+}
+
+/// Optional probing of peer reachability
+///
+/// A sample of peers sending announce requests with the started event is
+/// probed by attempting a TCP connection to the announced address. Peers
+/// that can't be connected to are returned after all other peers in
+/// announce responses until they announce again.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReachabilityConfig {
+    /// Maximum number of peers to probe per second
+    ///
+    /// 0 = disabled
+    pub probes_per_second: u32,
+    /// Maximum number of concurrent probes
+    pub max_probes_in_flight: usize,
+    /// Consider peer unreachable if connection attempt has not succeeded
+    /// after this many milliseconds
+    pub timeout_ms: u64,
+}
+
+impl ReachabilityConfig {
+    pub fn active(&self) -> bool {
+        self.probes_per_second != 0
+    }
+}
+
+impl Default for ReachabilityConfig {
+    fn default() -> Self {
+        Self {
+            probes_per_second: 0,
+            max_probes_in_flight: 256,
+            timeout_ms: 2_000,
+        }
+    }
+}
+
+/// Country-based announce policy and ASN statistics
+///
+/// Databases are read on start only.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// Path to country database in CSV format
+    ///
+    /// Each line must contain the first address of a range, the last address
+    /// of the range and an ISO 3166-1 alpha-2 country code, separated by
+    /// commas, e.g. `1.0.0.0,1.0.0.255,AU`. This is the format of the DB-IP
+    /// IP to Country Lite database. Lines starting with `#` are skipped.
+    ///
+    /// Empty = disabled
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub database_path: PathBuf,
+    /// Which announce requests to respond to with an error. Available
+    /// policies are allow (only serve listed countries), deny (don't serve
+    /// listed countries) and off.
+    ///
+    /// Addresses not found in the database are treated as not being in any
+    /// listed country.
+    pub country_policy: CountryPolicy,
+    /// Comma-separated ISO 3166-1 alpha-2 country codes, e.g. "SE,NO"
+    pub countries: String,
+    /// Error message sent in response to denied announce requests
+    pub denied_message: String,
+    /// Count announce requests by country (prometheus endpoint only)
+    pub count_requests_by_country: bool,
+    /// Path to autonomous system number (ASN) database in CSV format
+    ///
+    /// Each line must contain the first address of a range, the last address
+    /// of the range and an AS number, with or without "AS" prefix, separated
+    /// by commas, e.g. `1.0.0.0,1.0.0.255,13335`. Further fields, such as the
+    /// organization name in the DB-IP IP to ASN Lite database, are ignored.
+    ///
+    /// When set, the number of distinct ASNs that requests were received
+    /// from is estimated in statistics.
+    ///
+    /// Empty = disabled
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub asn_database_path: PathBuf,
+}
+
+impl GeoIpConfig {
+    pub fn active(&self) -> bool {
+        !self.database_path.as_os_str().is_empty()
+    }
+
+    pub fn asn_active(&self) -> bool {
+        !self.asn_database_path.as_os_str().is_empty()
+    }
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        Self {
+            database_path: PathBuf::new(),
+            country_policy: CountryPolicy::Off,
+            countries: String::new(),
+            denied_message: "Tracker not available in your country".into(),
+            count_requests_by_country: false,
+            asn_database_path: PathBuf::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountryPolicy {
+    /// Only serve announce requests from listed countries
+    Allow,
+    /// Don't serve announce requests from listed countries
+    Deny,
+    /// Serve all announce requests
+    Off,
+}
+
+/// Snapshots of all torrents for inventories
+///
+/// Snapshots contain info hash, IP version, seeder count, leecher count,
+/// completed count (announce requests with the completed event since the
+/// torrent was added) and seconds since last announce request of each
+/// torrent. Torrent map shards are copied one at a time, so snapshots are not
+/// exact under load.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Serve snapshots on `GET /torrents.ndjson` (newline-delimited JSON),
+    /// `GET /torrents.csv` and `GET /torrents.txt` (plain format)
+    ///
+    /// Version, commit, enabled cargo features and configuration hash (as
+    /// printed with `--version --verbose`) are served as JSON on
+    /// `GET /build_info`.
+    pub run_endpoint: bool,
+    /// Address to run snapshot endpoint on. It should not be reachable from
+    /// the public internet.
+    pub endpoint_address: SocketAddr,
+    /// Write snapshot to `file_path` when the program receives `SIGUSR2`
+    pub write_file_on_signal: bool,
+    /// Write snapshot to `file_path` every this many seconds (0 = off)
+    pub file_interval: u64,
+    pub file_path: PathBuf,
+    pub file_format: SnapshotFormat,
+    /// Pause this many milliseconds after copying each torrent map shard,
+    /// limiting the impact of snapshots on request handling
+    pub shard_pause_ms: u64,
+    /// Serve report of this many under-seeded torrents on
+    /// `GET /under_seeded.ndjson` (0 = off). Requires `run_endpoint`.
+    ///
+    /// Health of each torrent is computed during cleaning as (seeders +
+    /// announce requests with the completed event since previous cleaning
+    /// pass) / leechers. The report lists the torrents with the lowest
+    /// scores, least healthy first, and is updated after each cleaning
+    /// pass.
+    pub under_seeded_report_len: usize,
+    /// Only include torrents with at least this many leechers in
+    /// under-seeded torrent report, so that it is limited to popular ones
+    pub under_seeded_min_leechers: usize,
+}
+
+impl SnapshotConfig {
+    pub fn under_seeded_report_active(&self) -> bool {
+        self.run_endpoint && self.under_seeded_report_len != 0
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            run_endpoint: false,
+            endpoint_address: SocketAddr::from(([127, 0, 0, 1], 9101)),
+            write_file_on_signal: false,
+            file_interval: 0,
+            file_path: "./torrents.ndjson".into(),
+            file_format: SnapshotFormat::default(),
+            shard_pause_ms: 1,
+            under_seeded_report_len: 0,
+            under_seeded_min_leechers: 10,
+        }
+    }
+}
+
+/// Format of snapshots and of imported peer dumps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TomlConfig, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    /// Newline-delimited JSON objects
+    #[default]
+    Ndjson,
+    /// Comma-separated values with header row
+    Csv,
+    /// Lines of info hash, seeder count, leecher count and completed count
+    /// separated by spaces, as expected by common scrape aggregation tools.
+    /// Counts of IPv4 and IPv6 peers are combined. Not supported for
+    /// imports.
+    Plain,
+}
+
+/// Import of peers on start, e.g., when migrating from other tracker
+/// software, so that announce responses contain peers before they have
+/// reannounced
+///
+/// Imported peers are removed like other peers if they don't announce within
+/// `cleaning.max_peer_age` seconds. Torrents not allowed by the access list
+/// and peers with port 0 are skipped. If reading the file fails, the program
+/// exits.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportConfig {
+    /// Path to peer dump
+    ///
+    /// Each entry contains an info hash (40 hex characters), an optional
+    /// peer id (40 hex characters), an IP address, a port and whether the
+    /// peer is a seeder. In ndjson format, each line is an object such as
+    /// `{"info_hash":"...","peer_id":"...","ip":"1.2.3.4","port":6881,"seeder":true}`.
+    /// In csv format, each line contains the fields in the same order, e.g.
+    /// `...,,1.2.3.4,6881,true`, and a header row starting with `info_hash`
+    /// is skipped.
+    ///
+    /// Dumps from other tracker software need to be converted to one of
+    /// these formats. Dumps with only seeder and leecher counts per torrent,
+    /// such as snapshots, can't be imported since they contain no peers.
+    ///
+    /// Empty = disabled
+    pub file_path: PathBuf,
+    pub file_format: SnapshotFormat,
+}
+
+impl ImportConfig {
+    pub fn active(&self) -> bool {
+        !self.file_path.as_os_str().is_empty()
+    }
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            file_path: PathBuf::new(),
+            file_format: SnapshotFormat::default(),
+        }
+    }
+}
+
+/// Heavy maintenance tasks run on a schedule, e.g., during low-traffic hours
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Cron-like schedule in UTC with fields for minute, hour, day of month,
+    /// month and day of week, e.g. "30 3 * * *" for 03:30 every day or
+    /// "0 2-5 * * 6,7" for every hour from 02:00 to 05:00 on weekends
+    ///
+    /// Empty = disabled
+    pub schedule: String,
+    /// Reload access list and IP blocklist
+    pub reload_lists: bool,
+    /// Write snapshot to `snapshot.file_path`
+    pub write_snapshot_file: bool,
+    /// Shrink all torrent and peer maps to fit their contents, regardless of
+    /// `cleaning.shrink_threshold`
+    pub shrink_torrent_maps: bool,
+}
+
+impl MaintenanceConfig {
+    pub fn active(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            schedule: String::new(),
+            reload_lists: true,
+            write_snapshot_file: false,
+            shrink_torrent_maps: true,
+        }
+    }
+}
+
+/// Options for reproducing bugs. Not meant for production use.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// Handle requests and clean torrents interleaved on a single thread,
+    /// with peer selection using a random number generator seeded with
+    /// `rng_seed`
+    ///
+    /// Requests are handled in the order they are received. Implies one
+    /// socket worker using the mio backend without sender threads.
+    pub single_threaded: bool,
+    /// Seed for peer selection in single-threaded mode
+    pub rng_seed: u64,
+    /// Append peers returned in every Nth announce response handled by each
+    /// socket worker as JSON lines to `peer_selection_audit_file_path` (0 =
+    /// disabled)
+    ///
+    /// Lines contain the info hash and identifiers of the announcing peer
+    /// and of the returned peers, for checking fairness and diversity of
+    /// peer selection. Identifiers are the first 8 bytes (hex) of the BLAKE3
+    /// hash of `peer_selection_audit_salt`, IP address octets and port (big
+    /// endian), so that operators can look up specific peers.
+    pub peer_selection_audit_interval: u64,
+    pub peer_selection_audit_file_path: PathBuf,
+    pub peer_selection_audit_salt: String,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            single_threaded: false,
+            rng_seed: 0,
+            peer_selection_audit_interval: 0,
+            peer_selection_audit_file_path: "tmp/peer-selection-audit.jsonl".into(),
+            peer_selection_audit_salt: String::new(),
+        }
+    }
+}
+
+/// Artificial delays and failures for testing resilience of the tracker
+/// and its clients. Never enable this in production.
+///
+/// Faults are injected when responses are passed on to sender threads or
+/// sent from the socket (mio backend only).
+#[cfg(feature = "fault-injection")]
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultInjectionConfig {
+    /// Sleep this many milliseconds before passing on or sending each
+    /// response
+    ///
+    /// Since socket workers handle requests one by one, this also delays
+    /// processing of subsequent requests.
+    pub response_delay_ms: u64,
+    /// Probability (0.0 - 1.0) of silently dropping a response
+    pub response_drop_probability: f64,
+    /// Probability (0.0 - 1.0) of treating a socket send as having failed
+    /// because the send buffer is full, which causes the response to be
+    /// added to the resend buffer if it is enabled
+    pub send_would_block_probability: f64,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultInjectionConfig {
+    pub fn active(&self) -> bool {
+        self.response_delay_ms != 0
+            || self.response_drop_probability > 0.0
+            || self.send_would_block_probability > 0.0
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl Default for FaultInjectionConfig {
+    fn default() -> Self {
+        Self {
+            response_delay_ms: 0,
+            response_drop_probability: 0.0,
+            send_would_block_probability: 0.0,
         }
     }
 }
@@ -233,4 +1045,76 @@ mod tests {
     use super::Config;
 
     ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+
+    #[test]
+    fn test_torrent_count_interval() {
+        let mut config = Config::default();
+
+        config.cleaning.torrent_cleaning_interval = 60;
+
+        config.statistics.torrent_count_update_interval = 0;
+        assert_eq!(
+            config.statistics.torrent_count_interval(&config.cleaning),
+            60
+        );
+
+        config.statistics.torrent_count_update_interval = 60;
+        assert_eq!(
+            config.statistics.torrent_count_interval(&config.cleaning),
+            60
+        );
+
+        config.statistics.torrent_count_update_interval = 61;
+        assert_eq!(
+            config.statistics.torrent_count_interval(&config.cleaning),
+            120
+        );
+    }
+
+    #[test]
+    fn test_scrape_torrents_limit() {
+        let mut config = Config::default();
+
+        config.protocol.max_scrape_torrents = 70;
+        assert_eq!(config.protocol.scrape_torrents_limit(), 70);
+
+        config.protocol.max_response_packet_bytes = 1200;
+        assert_eq!(config.protocol.scrape_torrents_limit(), 70);
+
+        config.protocol.max_response_packet_bytes = 500;
+        assert_eq!(config.protocol.scrape_torrents_limit(), 41);
+    }
+
+    #[test]
+    fn test_network_backend() {
+        use super::SocketBackend;
+
+        let config: Config =
+            ::aquatic_toml_config::toml::from_str("[network]\nbackend = \"mio\"\n").unwrap();
+
+        assert_eq!(config.network.backend, SocketBackend::Mio);
+
+        #[cfg(feature = "io-uring")]
+        {
+            use aquatic_toml_config::TomlConfig;
+
+            let config: Config =
+                ::aquatic_toml_config::toml::from_str("[network]\nuse_io_uring = false\n").unwrap();
+
+            assert_eq!(config.network.backend, SocketBackend::IoUring);
+            assert_eq!(config.network.use_io_uring, Some(false));
+
+            // Deprecated setting is left out of default config
+            assert!(!Config::default_to_string().contains("use_io_uring"));
+        }
+    }
+
+    #[test]
+    fn test_too_many_scrape_torrents_message() {
+        // Failure reason is the same in all protocols
+        assert_eq!(
+            aquatic_udp_protocol::TOO_MANY_SCRAPE_TORRENTS,
+            aquatic_common::TOO_MANY_SCRAPE_TORRENTS
+        );
+    }
 }
@@ -0,0 +1,305 @@
+//! Country lookup for country-based announce policy and autonomous system
+//! number (ASN) lookup for statistics
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::config::{Config, CountryPolicy};
+
+/// ISO 3166-1 alpha-2 country code in upper case
+pub type CountryCode = [u8; 2];
+
+pub type Asn = u32;
+
+type Ranges<T> = (Vec<(u32, u32, T)>, Vec<(u128, u128, T)>);
+
+pub struct CountryLookup {
+    /// Sorted by range start
+    ipv4: Vec<(u32, u32, CountryCode)>,
+    /// Sorted by range start
+    ipv6: Vec<(u128, u128, CountryCode)>,
+    policy: CountryPolicy,
+    countries: HashSet<CountryCode>,
+}
+
+impl CountryLookup {
+    /// Returns None if country database is not configured
+    pub fn create(config: &Config) -> anyhow::Result<Option<Self>> {
+        if !config.geoip.active() {
+            return Ok(None);
+        }
+
+        let (ipv4, ipv6) = read_database(&config.geoip.database_path, parse_country_code)
+            .with_context(|| {
+                format!(
+                    "read country database {}",
+                    config.geoip.database_path.to_string_lossy()
+                )
+            })?;
+
+        ::log::info!("Read country database ({} ranges)", ipv4.len() + ipv6.len());
+
+        Ok(Some(Self {
+            ipv4,
+            ipv6,
+            policy: config.geoip.country_policy,
+            countries: parse_countries(&config.geoip.countries)?,
+        }))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<CountryCode> {
+        match ip {
+            IpAddr::V4(ip) => lookup_range(&self.ipv4, ip.into()),
+            IpAddr::V6(ip) => lookup_range(&self.ipv6, ip.into()),
+        }
+    }
+
+    pub fn allows(&self, country: Option<CountryCode>) -> bool {
+        policy_allows(self.policy, &self.countries, country)
+    }
+}
+
+/// Lookup of autonomous system numbers, for estimating the number of
+/// distinct networks that clients connect from
+pub struct AsnLookup {
+    /// Sorted by range start
+    ipv4: Vec<(u32, u32, Asn)>,
+    /// Sorted by range start
+    ipv6: Vec<(u128, u128, Asn)>,
+}
+
+impl AsnLookup {
+    /// Returns None if ASN database is not configured
+    pub fn create(config: &Config) -> anyhow::Result<Option<Self>> {
+        if !config.geoip.asn_active() {
+            return Ok(None);
+        }
+
+        let path = &config.geoip.asn_database_path;
+
+        let (ipv4, ipv6) = read_database(path, parse_asn)
+            .with_context(|| format!("read ASN database {}", path.to_string_lossy()))?;
+
+        ::log::info!("Read ASN database ({} ranges)", ipv4.len() + ipv6.len());
+
+        Ok(Some(Self { ipv4, ipv6 }))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Asn> {
+        match ip {
+            IpAddr::V4(ip) => lookup_range(&self.ipv4, ip.into()),
+            IpAddr::V6(ip) => lookup_range(&self.ipv6, ip.into()),
+        }
+    }
+}
+
+/// Read CSV file with lines starting with first address of a range, last
+/// address of the range and a value. Additional fields are ignored, as are
+/// ranges for which `parse_value` returns None.
+fn read_database<T>(path: &Path, parse_value: fn(&str) -> Option<T>) -> anyhow::Result<Ranges<T>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut ipv4 = Vec::new();
+    let mut ipv6 = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split(',').map(|field| field.trim().trim_matches('"'));
+
+        let (start, end, value) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(end), Some(value)) => (start, end, value),
+            _ => return Err(anyhow::anyhow!("Invalid line in database: {}", line)),
+        };
+
+        let start: IpAddr = start
+            .parse()
+            .with_context(|| format!("Invalid line in database: {}", line))?;
+        let end: IpAddr = end
+            .parse()
+            .with_context(|| format!("Invalid line in database: {}", line))?;
+
+        // Skip entries such as "ZZ" for unassigned ranges
+        let value = match parse_value(value) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => ipv4.push((start.into(), end.into(), value)),
+            (IpAddr::V6(start), IpAddr::V6(end)) => ipv6.push((start.into(), end.into(), value)),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Mixed IP versions in database range: {}",
+                    line
+                ))
+            }
+        }
+    }
+
+    ipv4.sort_unstable_by_key(|(start, _, _)| *start);
+    ipv6.sort_unstable_by_key(|(start, _, _)| *start);
+
+    ipv4.shrink_to_fit();
+    ipv6.shrink_to_fit();
+
+    Ok((ipv4, ipv6))
+}
+
+fn lookup_range<T: Ord + Copy, V: Copy>(ranges: &[(T, T, V)], ip: T) -> Option<V> {
+    let index = ranges.partition_point(|(start, _, _)| *start <= ip);
+
+    match ranges.get(index.checked_sub(1)?) {
+        Some((_, end, value)) if *end >= ip => Some(*value),
+        _ => None,
+    }
+}
+
+fn policy_allows(
+    policy: CountryPolicy,
+    countries: &HashSet<CountryCode>,
+    country: Option<CountryCode>,
+) -> bool {
+    let listed = country
+        .map(|country| countries.contains(&country))
+        .unwrap_or(false);
+
+    match policy {
+        CountryPolicy::Allow => listed,
+        CountryPolicy::Deny => !listed,
+        CountryPolicy::Off => true,
+    }
+}
+
+fn parse_countries(countries: &str) -> anyhow::Result<HashSet<CountryCode>> {
+    countries
+        .split(',')
+        .map(str::trim)
+        .filter(|country| !country.is_empty())
+        .map(|country| {
+            parse_country_code(country)
+                .with_context(|| format!("Invalid country code: {}", country))
+        })
+        .collect()
+}
+
+fn parse_country_code(country: &str) -> Option<CountryCode> {
+    match *country.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            let country = [a.to_ascii_uppercase(), b.to_ascii_uppercase()];
+
+            // "ZZ" denotes unknown country
+            (&country != b"ZZ").then_some(country)
+        }
+        _ => None,
+    }
+}
+
+/// Parse AS number with or without "AS" prefix, skipping 0 (unassigned)
+fn parse_asn(asn: &str) -> Option<Asn> {
+    let asn = asn
+        .strip_prefix("AS")
+        .or_else(|| asn.strip_prefix("as"))
+        .unwrap_or(asn);
+
+    asn.parse().ok().filter(|asn| *asn != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_country_lookup() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+
+        writeln!(file, "1.0.4.0,1.0.7.255,AU").unwrap();
+        writeln!(file, "1.0.0.0,1.0.0.255,AU").unwrap();
+        writeln!(file, "\"1.0.1.0\",\"1.0.3.255\",\"CN\"").unwrap();
+        writeln!(file, "2001:db8::,2001:db8::ffff,SE").unwrap();
+        writeln!(file, "10.0.0.0,10.255.255.255,ZZ").unwrap();
+
+        let mut config = Config::default();
+
+        config.geoip.database_path = file.path().into();
+        config.geoip.country_policy = CountryPolicy::Deny;
+        config.geoip.countries = "cn".into();
+
+        let lookup = CountryLookup::create(&config).unwrap().unwrap();
+
+        assert_eq!(lookup.lookup("1.0.0.1".parse().unwrap()), Some(*b"AU"));
+        assert_eq!(lookup.lookup("1.0.2.0".parse().unwrap()), Some(*b"CN"));
+        assert_eq!(lookup.lookup("1.0.8.0".parse().unwrap()), None);
+        assert_eq!(lookup.lookup("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(lookup.lookup("0.0.0.1".parse().unwrap()), None);
+        assert_eq!(lookup.lookup("2001:db8::1".parse().unwrap()), Some(*b"SE"));
+
+        assert!(lookup.allows(Some(*b"AU")));
+        assert!(!lookup.allows(Some(*b"CN")));
+        assert!(lookup.allows(None));
+    }
+
+    #[test]
+    fn test_asn_lookup() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+
+        writeln!(file, "1.0.0.0,1.0.0.255,13335,\"Cloudflare, Inc.\"").unwrap();
+        writeln!(file, "1.0.4.0,1.0.7.255,AS38803").unwrap();
+        writeln!(file, "2001:db8::,2001:db8::ffff,64496").unwrap();
+        writeln!(file, "10.0.0.0,10.255.255.255,0").unwrap();
+
+        let mut config = Config::default();
+
+        assert!(AsnLookup::create(&config).unwrap().is_none());
+
+        config.geoip.asn_database_path = file.path().into();
+
+        let lookup = AsnLookup::create(&config).unwrap().unwrap();
+
+        assert_eq!(lookup.lookup("1.0.0.1".parse().unwrap()), Some(13335));
+        assert_eq!(lookup.lookup("1.0.5.0".parse().unwrap()), Some(38803));
+        assert_eq!(lookup.lookup("1.0.1.0".parse().unwrap()), None);
+        assert_eq!(lookup.lookup("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(lookup.lookup("2001:db8::1".parse().unwrap()), Some(64496));
+    }
+
+    #[test]
+    fn test_policy_allows() {
+        let countries = parse_countries(" se,,").unwrap();
+
+        assert!(policy_allows(
+            CountryPolicy::Allow,
+            &countries,
+            Some(*b"SE")
+        ));
+        assert!(!policy_allows(
+            CountryPolicy::Allow,
+            &countries,
+            Some(*b"NO")
+        ));
+        assert!(!policy_allows(CountryPolicy::Allow, &countries, None));
+
+        assert!(!policy_allows(
+            CountryPolicy::Deny,
+            &countries,
+            Some(*b"SE")
+        ));
+        assert!(policy_allows(CountryPolicy::Deny, &countries, None));
+
+        assert!(policy_allows(CountryPolicy::Off, &countries, Some(*b"SE")));
+
+        assert!(parse_countries("SWE").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! Import of peers from dumps on start
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use aquatic_common::{CanonicalSocketAddr, ValidUntil};
+use aquatic_udp_protocol::{InfoHash, PeerId};
+use serde::Deserialize;
+
+use crate::common::State;
+use crate::config::{Config, SnapshotFormat};
+
+/// Peer dump entry, see [`crate::config::ImportConfig`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ImportedPeer {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    addr: SocketAddr,
+    is_seeder: bool,
+}
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    info_hash: String,
+    #[serde(default)]
+    peer_id: Option<String>,
+    ip: IpAddr,
+    port: u16,
+    seeder: bool,
+}
+
+/// Insert peers from configured dump into torrent maps, if any
+pub fn import_peers(config: &Config, state: &State) -> anyhow::Result<()> {
+    if !config.import.active() {
+        return Ok(());
+    }
+
+    if let SnapshotFormat::Plain = config.import.file_format {
+        return Err(anyhow::anyhow!(
+            "peer dump in plain format can't be imported since it contains no peers"
+        ));
+    }
+
+    let path = &config.import.file_path;
+
+    let file = File::open(path).with_context(|| format!("open peer dump {:?}", path))?;
+
+    let access_list = state.access_list.load();
+    // Seeders are given the same expiry time as leechers, just like when
+    // they announce: cleaning keeps them for max_seeder_age instead, see
+    // aquatic_common::PeerExpiry
+    let valid_until = ValidUntil::new(state.server_start_instant, config.cleaning.max_peer_age);
+
+    let mut num_imported = 0usize;
+    let mut num_skipped = 0usize;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("read peer dump {:?}", path))?;
+
+        let peer = match parse_line(config.import.file_format, &line)
+            .with_context(|| format!("invalid entry on line {} of peer dump", i + 1))?
+        {
+            Some(peer) => peer,
+            None => continue,
+        };
+
+        if peer.addr.port() == 0 || !access_list.allows(config.access_list.mode, &peer.info_hash.0)
+        {
+            num_skipped += 1;
+
+            continue;
+        }
+
+        state.torrent_maps.insert_peer(
+            peer.info_hash,
+            peer.peer_id,
+            CanonicalSocketAddr::new(peer.addr),
+            peer.is_seeder,
+            valid_until,
+        );
+
+        num_imported += 1;
+    }
+
+    ::log::info!(
+        "Imported {} peers from peer dump, skipped {}",
+        num_imported,
+        num_skipped
+    );
+
+    Ok(())
+}
+
+/// Returns None for empty lines and CSV header rows
+fn parse_line(format: SnapshotFormat, line: &str) -> anyhow::Result<Option<ImportedPeer>> {
+    let line = line.trim();
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let (info_hash, opt_peer_id, ip, port, is_seeder) = match format {
+        SnapshotFormat::Ndjson => {
+            let entry: JsonEntry = serde_json::from_str(line)?;
+
+            (
+                entry.info_hash,
+                entry.peer_id,
+                entry.ip,
+                entry.port,
+                entry.seeder,
+            )
+        }
+        SnapshotFormat::Plain => {
+            return Err(anyhow::anyhow!("plain format is not supported for imports"));
+        }
+        SnapshotFormat::Csv => {
+            if line.starts_with("info_hash") {
+                return Ok(None);
+            }
+
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+
+            let (info_hash, peer_id, ip, port, seeder) = match fields[..] {
+                [info_hash, peer_id, ip, port, seeder] => (info_hash, peer_id, ip, port, seeder),
+                _ => return Err(anyhow::anyhow!("expected 5 fields, got {}", fields.len())),
+            };
+
+            (
+                info_hash.to_owned(),
+                Some(peer_id.to_owned()).filter(|peer_id| !peer_id.is_empty()),
+                ip.parse().context("parse ip")?,
+                port.parse().context("parse port")?,
+                seeder.parse().context("parse seeder")?,
+            )
+        }
+    };
+
+    let peer_id = match opt_peer_id {
+        Some(peer_id) => PeerId(decode_hex_20(&peer_id).context("parse peer_id")?),
+        None => PeerId([0; 20]),
+    };
+
+    Ok(Some(ImportedPeer {
+        info_hash: InfoHash(decode_hex_20(&info_hash).context("parse info_hash")?),
+        peer_id,
+        addr: SocketAddr::new(ip, port),
+        is_seeder,
+    }))
+}
+
+fn decode_hex_20(s: &str) -> anyhow::Result<[u8; 20]> {
+    let mut bytes = [0u8; 20];
+
+    hex::decode_to_slice(s, &mut bytes)?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let info_hash = "ab".repeat(20);
+        let peer_id = "cd".repeat(20);
+
+        let expected = ImportedPeer {
+            info_hash: InfoHash([0xab; 20]),
+            peer_id: PeerId([0xcd; 20]),
+            addr: "[::1]:6881".parse().unwrap(),
+            is_seeder: true,
+        };
+
+        let json = format!(
+            r#"{{"info_hash":"{}","peer_id":"{}","ip":"::1","port":6881,"seeder":true}}"#,
+            info_hash, peer_id
+        );
+        let csv = format!("{},{},::1,6881,true", info_hash, peer_id);
+
+        assert_eq!(
+            parse_line(SnapshotFormat::Ndjson, &json).unwrap(),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_line(SnapshotFormat::Csv, &csv).unwrap(),
+            Some(expected)
+        );
+
+        // Peer id is optional
+        let csv = format!("{},,127.0.0.1,6881,false", info_hash);
+
+        assert_eq!(
+            parse_line(SnapshotFormat::Csv, &csv)
+                .unwrap()
+                .unwrap()
+                .peer_id,
+            PeerId([0; 20])
+        );
+
+        assert_eq!(
+            parse_line(SnapshotFormat::Csv, "info_hash,peer_id,ip,port,seeder").unwrap(),
+            None
+        );
+        assert_eq!(parse_line(SnapshotFormat::Ndjson, "").unwrap(), None);
+
+        assert!(parse_line(SnapshotFormat::Csv, "ab,,127.0.0.1,6881,true").is_err());
+        assert!(parse_line(SnapshotFormat::Csv, &info_hash).is_err());
+    }
+}
@@ -1,17 +1,21 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
 use std::iter::repeat_with;
+use std::mem::size_of;
 use std::net::IpAddr;
-use std::ops::DerefMut;
-use std::sync::atomic::AtomicBool;
+use std::ops::{Deref, DerefMut};
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use aquatic_common::SecondsSinceServerStart;
-use aquatic_common::ServerStartInstant;
+use aquatic_common::PeerExpiry;
 use aquatic_common::{
     access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache, AccessListMode},
     ValidUntil,
 };
 use aquatic_common::{CanonicalSocketAddr, IndexMap};
+use aquatic_common::{SecondsSinceServerStart, ServerStartInstant};
 
 use aquatic_udp_protocol::*;
 use arrayvec::ArrayVec;
@@ -19,7 +23,6 @@ use crossbeam_channel::Sender;
 use hashbrown::HashMap;
 use hdrhistogram::Histogram;
 use parking_lot::RwLockUpgradableReadGuard;
-use rand::prelude::SmallRng;
 use rand::Rng;
 
 use crate::common::*;
@@ -27,6 +30,10 @@ use crate::config::Config;
 
 const SMALL_PEER_MAP_CAPACITY: usize = 2;
 
+/// Number of announce response cache entries above which expired entries
+/// are removed
+const ANNOUNCE_RESPONSE_CACHE_PURGE_LEN: usize = 4096;
+
 use aquatic_udp_protocol::InfoHash;
 use parking_lot::RwLock;
 
@@ -48,44 +55,184 @@ impl Default for TorrentMaps {
 }
 
 impl TorrentMaps {
+    #[allow(clippy::too_many_arguments)]
     pub fn announce(
         &self,
         config: &Config,
+        statistics: &IpVersionStatistics<SocketWorkerStatistics>,
         statistics_sender: &Sender<StatisticsMessage>,
-        rng: &mut SmallRng,
+        rng: &mut SwarmRng,
         request: &AnnounceRequest,
         src: CanonicalSocketAddr,
         valid_until: ValidUntil,
+        cache: &mut AnnounceResponseCache,
     ) -> Response {
         match src.get().ip() {
             IpAddr::V4(ip_address) => Response::AnnounceIpv4(self.ipv4.announce(
                 config,
+                &statistics.ipv4,
                 statistics_sender,
                 rng,
                 request,
                 ip_address.into(),
                 valid_until,
+                &mut cache.ipv4,
             )),
             IpAddr::V6(ip_address) => Response::AnnounceIpv6(self.ipv6.announce(
                 config,
+                &statistics.ipv6,
                 statistics_sender,
                 rng,
                 request,
                 ip_address.into(),
                 valid_until,
+                &mut cache.ipv6,
             )),
         }
     }
 
-    pub fn scrape(&self, request: ScrapeRequest, src: CanonicalSocketAddr) -> ScrapeResponse {
-        if src.is_ipv4() {
-            self.ipv4.scrape(request)
+    /// Mark peer as unreachable until it announces again
+    pub fn mark_peer_unreachable(&self, info_hash: InfoHash, addr: CanonicalSocketAddr) {
+        let port = Port(addr.get().port().into());
+
+        match addr.get().ip() {
+            IpAddr::V4(ip_address) => self.ipv4.mark_peer_unreachable(
+                info_hash,
+                ResponsePeer {
+                    ip_address: ip_address.into(),
+                    port,
+                },
+            ),
+            IpAddr::V6(ip_address) => self.ipv6.mark_peer_unreachable(
+                info_hash,
+                ResponsePeer {
+                    ip_address: ip_address.into(),
+                    port,
+                },
+            ),
+        }
+    }
+
+    /// Insert peer without handling an announce request, e.g., when
+    /// importing peers on start. Any peer with the same address is replaced.
+    pub fn insert_peer(
+        &self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        addr: CanonicalSocketAddr,
+        is_seeder: bool,
+        valid_until: ValidUntil,
+    ) {
+        let port = Port(addr.get().port().into());
+        let peer = Peer::new(peer_id, is_seeder, valid_until);
+
+        match addr.get().ip() {
+            IpAddr::V4(ip_address) => self.ipv4.insert_peer(
+                info_hash,
+                ResponsePeer {
+                    ip_address: ip_address.into(),
+                    port,
+                },
+                peer,
+            ),
+            IpAddr::V6(ip_address) => self.ipv6.insert_peer(
+                info_hash,
+                ResponsePeer {
+                    ip_address: ip_address.into(),
+                    port,
+                },
+                peer,
+            ),
+        }
+    }
+
+    pub fn scrape(
+        &self,
+        config: &Config,
+        request: ScrapeRequest,
+        src: CanonicalSocketAddr,
+    ) -> ScrapeResponse {
+        if config.protocol.scrape_merge_ip_versions {
+            let mut response = self.ipv4.scrape(&request);
+
+            let ipv6_torrent_stats = self.ipv6.scrape(&request).torrent_stats;
+
+            for (stats, ipv6_stats) in response.torrent_stats.iter_mut().zip(ipv6_torrent_stats) {
+                stats.seeders = NumberOfPeers::new(
+                    stats
+                        .seeders
+                        .0
+                        .get()
+                        .saturating_add(ipv6_stats.seeders.0.get()),
+                );
+                stats.leechers = NumberOfPeers::new(
+                    stats
+                        .leechers
+                        .0
+                        .get()
+                        .saturating_add(ipv6_stats.leechers.0.get()),
+                );
+                stats.completed = NumberOfDownloads::new(
+                    stats
+                        .completed
+                        .0
+                        .get()
+                        .saturating_add(ipv6_stats.completed.0.get()),
+                );
+            }
+
+            response
+        } else if src.is_ipv4() {
+            self.ipv4.scrape(&request)
         } else {
-            self.ipv6.scrape(request)
+            self.ipv6.scrape(&request)
         }
     }
 
+    /// Count torrents and peers of given IP version
+    ///
+    /// Shards are locked one at a time, so the result is not an exact
+    /// snapshot under load. Expired peers that haven't been cleaned yet are
+    /// included.
+    pub fn summary(&self, ip_version: IpVersion) -> SwarmSummary {
+        match ip_version {
+            IpVersion::V4 => self.ipv4.summary(),
+            IpVersion::V6 => self.ipv6.summary(),
+        }
+    }
+
+    /// Call `f` with seeder and leecher counts of the torrents of one shard
+    /// at a time, first for IPv4 and then for IPv6, pausing for `pause`
+    /// after each shard
+    ///
+    /// Counts are copied before calling `f`, so no locks are held while it
+    /// runs.
+    pub fn for_each_shard_counts<E>(
+        &self,
+        now: SecondsSinceServerStart,
+        pause: Duration,
+        mut f: impl FnMut(&[TorrentCounts]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut buffer = Vec::new();
+
+        self.ipv4
+            .for_each_shard_counts(IpVersion::V4, now, pause, &mut buffer, &mut f)?;
+        self.ipv6
+            .for_each_shard_counts(IpVersion::V6, now, pause, &mut buffer, &mut f)
+    }
+
+    /// Shrink all torrent and peer maps to fit their contents regardless of
+    /// `cleaning.shrink_threshold`. Returns approximate number of bytes
+    /// reclaimed.
+    pub fn shrink_to_fit(&self) -> usize {
+        self.ipv4.shrink_to_fit() + self.ipv6.shrink_to_fit()
+    }
+
     /// Remove forbidden or inactive torrents, reclaim space and update statistics
+    ///
+    /// Torrent and peer counts (and peer histograms) are only updated if
+    /// `update_torrent_counts` is true. Returns under-seeded torrent report
+    /// if it is enabled.
     pub fn clean_and_update_statistics(
         &self,
         config: &Config,
@@ -93,47 +240,245 @@ impl TorrentMaps {
         statistics_sender: &Sender<StatisticsMessage>,
         access_list: &Arc<AccessListArcSwap>,
         server_start_instant: ServerStartInstant,
-    ) {
+        update_torrent_counts: bool,
+    ) -> Option<Vec<TorrentHealth>> {
         let mut cache = create_access_list_cache(access_list);
         let mode = config.access_list.mode;
-        let now = server_start_instant.seconds_elapsed();
+        let expiry = PeerExpiry::new(
+            server_start_instant.seconds_elapsed(),
+            config.cleaning.max_peer_age,
+            config.cleaning.max_seeder_age,
+        );
 
         let mut statistics_messages = Vec::new();
+        let mut opt_under_seeded = config
+            .snapshot
+            .under_seeded_report_active()
+            .then(|| UnderSeededTorrents::new(config));
 
         let ipv4 = self.ipv4.clean_and_get_statistics(
             config,
             &mut statistics_messages,
             &mut cache,
             mode,
-            now,
+            expiry,
+            update_torrent_counts,
+            IpVersion::V4,
+            opt_under_seeded.as_mut(),
         );
         let ipv6 = self.ipv6.clean_and_get_statistics(
             config,
             &mut statistics_messages,
             &mut cache,
             mode,
-            now,
+            expiry,
+            update_torrent_counts,
+            IpVersion::V6,
+            opt_under_seeded.as_mut(),
         );
 
-        if config.statistics.active() {
-            statistics.ipv4.torrents.store(ipv4.0, Ordering::Relaxed);
-            statistics.ipv6.torrents.store(ipv6.0, Ordering::Relaxed);
-            statistics.ipv4.peers.store(ipv4.1, Ordering::Relaxed);
-            statistics.ipv6.peers.store(ipv6.1, Ordering::Relaxed);
+        if update_torrent_counts {
+            statistics.ipv4.torrents.set(ipv4.0);
+            statistics.ipv6.torrents.set(ipv6.0);
+            statistics.ipv4.peers.set(ipv4.1);
+            statistics.ipv6.peers.set(ipv6.1);
+            statistics.ipv4.reclaimed_bytes.set(ipv4.2);
+            statistics.ipv6.reclaimed_bytes.set(ipv6.2);
 
-            if let Some(message) = ipv4.2 {
+            if let Some(message) = ipv4.4 {
                 statistics_messages.push(StatisticsMessage::Ipv4PeerHistogram(message));
             }
-            if let Some(message) = ipv6.2 {
+            if let Some(message) = ipv6.4 {
                 statistics_messages.push(StatisticsMessage::Ipv6PeerHistogram(message));
             }
+        }
+
+        statistics.ipv4.peers_expired.increment(ipv4.3);
+        statistics.ipv6.peers_expired.increment(ipv6.3);
 
+        if config.statistics.active() {
             for message in statistics_messages {
                 if let Err(err) = statistics_sender.try_send(message) {
                     ::log::error!("couldn't send statistics message: {:#}", err);
                 }
             }
         }
+
+        opt_under_seeded.map(UnderSeededTorrents::into_sorted_vec)
+    }
+}
+
+/// Number of torrents and peers, see [`TorrentMaps::summary`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SwarmSummary {
+    pub torrents: usize,
+    pub seeders: usize,
+    pub leechers: usize,
+}
+
+/// Seeder and leecher counts of a torrent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TorrentCounts {
+    pub info_hash: InfoHash,
+    pub ip_version: IpVersion,
+    pub seeders: usize,
+    pub leechers: usize,
+    /// Number of announce requests with the completed event since torrent
+    /// was added
+    pub completed: u32,
+    /// Seconds since last announce request
+    pub idle_seconds: u32,
+}
+
+/// Health of a torrent, computed during cleaning
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TorrentHealth {
+    pub info_hash: InfoHash,
+    pub ip_version: IpVersion,
+    pub seeders: usize,
+    pub leechers: usize,
+    /// Number of announce requests with the completed event since previous
+    /// cleaning pass
+    pub recent_completions: u32,
+    /// (seeders + recent completions) / leechers. Lower is worse.
+    pub score: f64,
+}
+
+impl TorrentHealth {
+    fn new(
+        info_hash: InfoHash,
+        ip_version: IpVersion,
+        seeders: usize,
+        leechers: usize,
+        recent_completions: u32,
+    ) -> Self {
+        let score = (seeders as f64 + f64::from(recent_completions)) / leechers.max(1) as f64;
+
+        Self {
+            info_hash,
+            ip_version,
+            seeders,
+            leechers,
+            recent_completions,
+            score,
+        }
+    }
+}
+
+/// Keeps the `snapshot.under_seeded_report_len` torrents with the lowest
+/// health scores among those with at least
+/// `snapshot.under_seeded_min_leechers` leechers
+struct UnderSeededTorrents {
+    max_len: usize,
+    min_leechers: usize,
+    /// Healthiest torrent is on top, so it can be replaced cheaply
+    heap: BinaryHeap<ByScore>,
+}
+
+impl UnderSeededTorrents {
+    fn new(config: &Config) -> Self {
+        Self {
+            max_len: config.snapshot.under_seeded_report_len,
+            min_leechers: config.snapshot.under_seeded_min_leechers,
+            heap: BinaryHeap::with_capacity(config.snapshot.under_seeded_report_len + 1),
+        }
+    }
+
+    fn push(&mut self, health: TorrentHealth) {
+        if health.leechers < self.min_leechers.max(1) {
+            return;
+        }
+
+        self.heap.push(ByScore(health));
+
+        if self.heap.len() > self.max_len {
+            self.heap.pop();
+        }
+    }
+
+    /// Least healthy torrent first
+    fn into_sorted_vec(self) -> Vec<TorrentHealth> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|entry| entry.0)
+            .collect()
+    }
+}
+
+/// Orders by health score, preferring torrents with more leechers on ties
+struct ByScore(TorrentHealth);
+
+impl Ord for ByScore {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.0
+            .score
+            .total_cmp(&other.0.score)
+            .then_with(|| other.0.leechers.cmp(&self.0.leechers))
+    }
+}
+
+impl PartialOrd for ByScore {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ByScore {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for ByScore {}
+
+/// Runs torrent cleaning and decides when to update torrent counts
+pub struct TorrentCleaner {
+    statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+    statistics_sender: Sender<StatisticsMessage>,
+    torrent_count_update_interval: Duration,
+    last_torrent_count_update: Option<Instant>,
+}
+
+impl TorrentCleaner {
+    pub fn new(
+        config: &Config,
+        statistics: CachePaddedArc<IpVersionStatistics<SwarmWorkerStatistics>>,
+        statistics_sender: Sender<StatisticsMessage>,
+    ) -> Self {
+        Self {
+            statistics,
+            statistics_sender,
+            torrent_count_update_interval: Duration::from_secs(
+                config.statistics.torrent_count_update_interval,
+            ),
+            last_torrent_count_update: None,
+        }
+    }
+
+    pub fn clean(&mut self, config: &Config, state: &State) {
+        let update_torrent_counts = config.statistics.active()
+            && self
+                .last_torrent_count_update
+                .map(|instant| instant.elapsed() >= self.torrent_count_update_interval)
+                .unwrap_or(true);
+
+        if update_torrent_counts {
+            self.last_torrent_count_update = Some(Instant::now());
+        }
+
+        let opt_under_seeded = state.torrent_maps.clean_and_update_statistics(
+            config,
+            &self.statistics,
+            &self.statistics_sender,
+            &state.access_list,
+            state.server_start_instant,
+            update_torrent_counts,
+        );
+
+        if let Some(under_seeded) = opt_under_seeded {
+            *state.under_seeded_torrents.lock() = under_seeded;
+        }
     }
 }
 
@@ -151,14 +496,17 @@ impl<I: Ip> TorrentMapShards<I> {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn announce(
         &self,
         config: &Config,
+        statistics: &SocketWorkerStatistics,
         statistics_sender: &Sender<StatisticsMessage>,
-        rng: &mut SmallRng,
+        rng: &mut SwarmRng,
         request: &AnnounceRequest,
         ip_address: I,
         valid_until: ValidUntil,
+        cache: &mut ResponsePeerCache<I>,
     ) -> AnnounceResponse<I> {
         let torrent_data = {
             let torrent_map_shard = self.get_shard(&request.info_hash).upgradable_read();
@@ -175,28 +523,61 @@ impl<I: Ip> TorrentMapShards<I> {
             }
         };
 
+        // Peers are valid until max_peer_age seconds after the request
+        torrent_data.last_announce.store(
+            valid_until
+                .to_seconds()
+                .saturating_sub(config.cleaning.max_peer_age),
+            Ordering::Relaxed,
+        );
+
+        if let AnnounceEvent::Completed = request.event.into() {
+            torrent_data.completed.fetch_add(1, Ordering::Relaxed);
+        }
+
         let mut peer_map = torrent_data.peer_map.write();
 
         peer_map.announce(
             config,
+            statistics,
             statistics_sender,
             rng,
             request,
             ip_address,
             valid_until,
+            cache,
         )
     }
 
-    fn scrape(&self, request: ScrapeRequest) -> ScrapeResponse {
+    fn mark_peer_unreachable(&self, info_hash: InfoHash, key: ResponsePeer<I>) {
+        let opt_torrent_data = self.get_shard(&info_hash).read().get(&info_hash).cloned();
+
+        if let Some(torrent_data) = opt_torrent_data {
+            torrent_data.peer_map.write().mark_peer_unreachable(&key);
+        }
+    }
+
+    fn insert_peer(&self, info_hash: InfoHash, key: ResponsePeer<I>, peer: Peer) {
+        let torrent_data = self
+            .get_shard(&info_hash)
+            .write()
+            .entry(info_hash)
+            .or_default()
+            .clone();
+
+        torrent_data.peer_map.write().insert(key, peer);
+    }
+
+    fn scrape(&self, request: &ScrapeRequest) -> ScrapeResponse {
         let mut response = ScrapeResponse {
             transaction_id: request.transaction_id,
             torrent_stats: Vec::with_capacity(request.info_hashes.len()),
         };
 
-        for info_hash in request.info_hashes {
-            let torrent_map_shard = self.get_shard(&info_hash);
+        for info_hash in request.info_hashes.iter() {
+            let torrent_map_shard = self.get_shard(info_hash);
 
-            let statistics = if let Some(torrent_data) = torrent_map_shard.read().get(&info_hash) {
+            let statistics = if let Some(torrent_data) = torrent_map_shard.read().get(info_hash) {
                 torrent_data.peer_map.read().scrape_statistics()
             } else {
                 TorrentScrapeStatistics {
@@ -212,35 +593,171 @@ impl<I: Ip> TorrentMapShards<I> {
         response
     }
 
+    fn summary(&self) -> SwarmSummary {
+        let mut summary = SwarmSummary::default();
+
+        for torrent_map_shard in self.0.iter() {
+            let torrent_map_shard = torrent_map_shard.read();
+
+            summary.torrents += torrent_map_shard.len();
+
+            for torrent_data in torrent_map_shard.values() {
+                let (seeders, leechers) = match torrent_data.peer_map.read().deref() {
+                    PeerMap::Small(peer_map) => peer_map.num_seeders_leechers(),
+                    PeerMap::Large(peer_map) => peer_map.num_seeders_leechers(),
+                };
+
+                summary.seeders += seeders;
+                summary.leechers += leechers;
+            }
+        }
+
+        summary
+    }
+
+    fn for_each_shard_counts<E>(
+        &self,
+        ip_version: IpVersion,
+        now: SecondsSinceServerStart,
+        pause: Duration,
+        buffer: &mut Vec<TorrentCounts>,
+        f: &mut impl FnMut(&[TorrentCounts]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for torrent_map_shard in self.0.iter() {
+            buffer.clear();
+
+            buffer.extend(
+                torrent_map_shard
+                    .read()
+                    .iter()
+                    .map(|(info_hash, torrent_data)| {
+                        let (seeders, leechers) = match torrent_data.peer_map.read().deref() {
+                            PeerMap::Small(peer_map) => peer_map.num_seeders_leechers(),
+                            PeerMap::Large(peer_map) => peer_map.num_seeders_leechers(),
+                        };
+
+                        TorrentCounts {
+                            info_hash: *info_hash,
+                            ip_version,
+                            seeders,
+                            leechers,
+                            completed: torrent_data.completed.load(Ordering::Relaxed),
+                            idle_seconds: now
+                                .to_seconds()
+                                .saturating_sub(torrent_data.last_announce.load(Ordering::Relaxed)),
+                        }
+                    }),
+            );
+
+            f(buffer)?;
+
+            if !pause.is_zero() {
+                ::std::thread::sleep(pause);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn shrink_to_fit(&self) -> usize {
+        let mut reclaimed_bytes = 0;
+
+        for torrent_map_shard in self.0.iter() {
+            for torrent_data in torrent_map_shard.read().values() {
+                let mut peer_map = torrent_data.peer_map.write();
+
+                if let PeerMap::Large(large_peer_map) = peer_map.deref_mut() {
+                    let capacity_before = large_peer_map.peers.capacity();
+
+                    if let Some(small_peer_map) = large_peer_map.try_shrink() {
+                        *peer_map = PeerMap::Small(small_peer_map);
+
+                        reclaimed_bytes += capacity_before * size_of::<(ResponsePeer<I>, Peer)>();
+                    } else {
+                        large_peer_map.peers.shrink_to_fit();
+
+                        reclaimed_bytes += (capacity_before - large_peer_map.peers.capacity())
+                            * size_of::<(ResponsePeer<I>, Peer)>();
+                    }
+                }
+            }
+
+            let mut torrent_map_shard = torrent_map_shard.write();
+
+            let capacity_before = torrent_map_shard.capacity();
+
+            torrent_map_shard.shrink_to_fit();
+
+            reclaimed_bytes += (capacity_before - torrent_map_shard.capacity())
+                * size_of::<(InfoHash, Arc<TorrentData<I>>)>();
+        }
+
+        reclaimed_bytes
+    }
+
+    /// Clean shards one at a time and return torrent count, peer count,
+    /// reclaimed bytes, number of expired peers and optionally a
+    /// peers-per-torrent histogram
+    ///
+    /// The histogram is built while iterating over the torrents for cleaning,
+    /// so the statistics worker never needs to access the torrent maps. The
+    /// same goes for torrent health, which is passed to `opt_under_seeded`.
+    #[allow(clippy::too_many_arguments)]
     fn clean_and_get_statistics(
         &self,
         config: &Config,
         statistics_messages: &mut Vec<StatisticsMessage>,
         access_list_cache: &mut AccessListCache,
         access_list_mode: AccessListMode,
-        now: SecondsSinceServerStart,
-    ) -> (usize, usize, Option<Histogram<u64>>) {
+        expiry: PeerExpiry,
+        update_torrent_counts: bool,
+        ip_version: IpVersion,
+        mut opt_under_seeded: Option<&mut UnderSeededTorrents>,
+    ) -> (usize, usize, usize, usize, Option<Histogram<u64>>) {
         let mut total_num_torrents = 0;
         let mut total_num_peers = 0;
+        let mut reclaimed_bytes = 0;
+        let mut peers_expired = 0;
 
-        let mut opt_histogram: Option<Histogram<u64>> = config
-            .statistics
-            .torrent_peer_histograms
+        let mut opt_histogram: Option<Histogram<u64>> = (update_torrent_counts
+            && config.statistics.torrent_peer_histograms)
             .then(|| Histogram::new(3).expect("create peer histogram"));
 
         for torrent_map_shard in self.0.iter() {
-            for torrent_data in torrent_map_shard.read().values() {
+            for (info_hash, torrent_data) in torrent_map_shard.read().iter() {
                 let mut peer_map = torrent_data.peer_map.write();
 
+                let idle = config.cleaning.max_torrent_idle != 0
+                    && expiry
+                        .now()
+                        .to_seconds()
+                        .saturating_sub(torrent_data.last_announce.load(Ordering::Relaxed))
+                        >= config.cleaning.max_torrent_idle;
+
                 let num_peers = match peer_map.deref_mut() {
-                    PeerMap::Small(small_peer_map) => {
-                        small_peer_map.clean_and_get_num_peers(config, statistics_messages, now)
+                    peer_map if idle => {
+                        peer_map.remove_all(
+                            config,
+                            statistics_messages,
+                            &mut reclaimed_bytes,
+                            &mut peers_expired,
+                        );
+
+                        0
                     }
+                    PeerMap::Small(small_peer_map) => small_peer_map.clean_and_get_num_peers(
+                        config,
+                        statistics_messages,
+                        expiry,
+                        &mut peers_expired,
+                    ),
                     PeerMap::Large(large_peer_map) => {
                         let num_peers = large_peer_map.clean_and_get_num_peers(
                             config,
                             statistics_messages,
-                            now,
+                            expiry,
+                            &mut reclaimed_bytes,
+                            &mut peers_expired,
                         );
 
                         if let Some(small_peer_map) = large_peer_map.try_shrink() {
@@ -251,6 +768,27 @@ impl<I: Ip> TorrentMapShards<I> {
                     }
                 };
 
+                if let Some(under_seeded) = opt_under_seeded.as_mut() {
+                    let completed = torrent_data.completed.load(Ordering::Relaxed);
+                    let recent_completions = completed.wrapping_sub(
+                        torrent_data
+                            .completed_at_last_cleaning
+                            .swap(completed, Ordering::Relaxed),
+                    );
+
+                    if num_peers > 0 {
+                        let (seeders, leechers) = peer_map.num_seeders_leechers();
+
+                        under_seeded.push(TorrentHealth::new(
+                            *info_hash,
+                            ip_version,
+                            seeders,
+                            leechers,
+                            recent_completions,
+                        ));
+                    }
+                }
+
                 drop(peer_map);
 
                 match opt_histogram.as_mut() {
@@ -294,12 +832,29 @@ impl<I: Ip> TorrentMapShards<I> {
                 true
             });
 
-            torrent_map_shard.shrink_to_fit();
+            if should_shrink(
+                config,
+                torrent_map_shard.capacity(),
+                torrent_map_shard.len(),
+            ) {
+                let capacity_before = torrent_map_shard.capacity();
+
+                torrent_map_shard.shrink_to_fit();
+
+                reclaimed_bytes += (capacity_before - torrent_map_shard.capacity())
+                    * size_of::<(InfoHash, Arc<TorrentData<I>>)>();
+            }
 
             total_num_torrents += torrent_map_shard.len();
         }
 
-        (total_num_torrents, total_num_peers, opt_histogram)
+        (
+            total_num_torrents,
+            total_num_peers,
+            reclaimed_bytes,
+            peers_expired,
+            opt_histogram,
+        )
     }
 
     fn get_shard(&self, info_hash: &InfoHash) -> &RwLock<TorrentMapShard<I>> {
@@ -307,12 +862,112 @@ impl<I: Ip> TorrentMapShards<I> {
     }
 }
 
+/// Per socket worker cache of announce response peers
+pub struct AnnounceResponseCache {
+    ipv4: ResponsePeerCache<Ipv4AddrBytes>,
+    ipv6: ResponsePeerCache<Ipv6AddrBytes>,
+}
+
+impl AnnounceResponseCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ipv4: ResponsePeerCache::new(config),
+            ipv6: ResponsePeerCache::new(config),
+        }
+    }
+}
+
+/// Cache of response peers for large swarms, keyed by info hash and number
+/// of peers wanted (rounded up to next power of two)
+///
+/// During announce storms on popular torrents, this avoids repeatedly
+/// selecting random peers from the peer map. Since the peers are only
+/// cached for a short duration, any peers that have left the swarm will
+/// soon stop being returned.
+pub struct ResponsePeerCache<I: Ip> {
+    max_age: Option<Duration>,
+    max_response_peers: usize,
+    map: HashMap<(InfoHash, usize), CachedResponsePeers<I>>,
+}
+
+type CachedResponsePeers<I> = (Instant, Vec<ResponsePeer<I>>);
+
+impl<I: Ip> ResponsePeerCache<I> {
+    fn new(config: &Config) -> Self {
+        let max_age = config.protocol.announce_response_cache_ms;
+
+        Self {
+            max_age: (max_age != 0).then(|| Duration::from_millis(max_age)),
+            max_response_peers: config.protocol.max_response_peers,
+            map: Default::default(),
+        }
+    }
+
+    fn get_or_extract<F>(
+        &mut self,
+        info_hash: InfoHash,
+        num_peers: usize,
+        announcing_peer: ResponsePeer<I>,
+        extract: F,
+    ) -> Vec<ResponsePeer<I>>
+    where
+        F: FnOnce(usize) -> Vec<ResponsePeer<I>>,
+    {
+        let max_age = if let Some(max_age) = self.max_age {
+            max_age
+        } else {
+            return extract(num_peers);
+        };
+
+        let bucket = num_peers
+            .checked_next_power_of_two()
+            .unwrap_or(usize::MAX)
+            .min(self.max_response_peers);
+        let now = Instant::now();
+
+        if let Some((created, peers)) = self.map.get(&(info_hash, bucket)) {
+            if now.duration_since(*created) < max_age {
+                return peers
+                    .iter()
+                    .filter(|peer| **peer != announcing_peer)
+                    .take(num_peers)
+                    .copied()
+                    .collect();
+            }
+        }
+
+        // Announcing peer has already been removed from peer map, so it
+        // doesn't need to be filtered out here. Extract one extra peer so
+        // that enough remain when later announcers are filtered out of the
+        // cached peers.
+        let peers = extract(bucket.saturating_add(1));
+        let response_peers = peers.iter().take(num_peers).copied().collect();
+
+        if self.map.len() >= ANNOUNCE_RESPONSE_CACHE_PURGE_LEN {
+            self.map
+                .retain(|_, (created, _)| now.duration_since(*created) < max_age);
+        }
+
+        self.map.insert((info_hash, bucket), (now, peers));
+
+        response_peers
+    }
+}
+
 /// Use HashMap instead of IndexMap for better lookup performance
 type TorrentMapShard<T> = HashMap<InfoHash, Arc<TorrentData<T>>>;
 
 pub struct TorrentData<T: Ip> {
     peer_map: RwLock<PeerMap<T>>,
     pending_removal: AtomicBool,
+    /// Seconds since server start of last announce request. Imported peers
+    /// are inserted on start, so the default of 0 is correct for them too.
+    last_announce: AtomicU32,
+    /// Number of announce requests with the completed event
+    completed: AtomicU32,
+    /// Value of `completed` during previous cleaning pass, for computing
+    /// torrent health
+    completed_at_last_cleaning: AtomicU32,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -320,6 +975,9 @@ impl<I: Ip> Default for TorrentData<I> {
         Self {
             peer_map: Default::default(),
             pending_removal: Default::default(),
+            last_announce: Default::default(),
+            completed: Default::default(),
+            completed_at_last_cleaning: Default::default(),
         }
     }
 }
@@ -330,27 +988,28 @@ pub enum PeerMap<I: Ip> {
 }
 
 impl<I: Ip> PeerMap<I> {
+    #[allow(clippy::too_many_arguments)]
     fn announce(
         &mut self,
         config: &Config,
+        statistics: &SocketWorkerStatistics,
         statistics_sender: &Sender<StatisticsMessage>,
-        rng: &mut SmallRng,
+        rng: &mut SwarmRng,
         request: &AnnounceRequest,
         ip_address: I,
         valid_until: ValidUntil,
+        cache: &mut ResponsePeerCache<I>,
     ) -> AnnounceResponse<I> {
-        let max_num_peers_to_take: usize = if request.peers_wanted.0.get() <= 0 {
-            config.protocol.max_response_peers
+        let max_num_peers_to_take = num_peers_to_take::<I>(config, request.peers_wanted);
+
+        let status = if request.port.0.get() == 0 {
+            // Treating peer as stopped means that it will not be inserted
+            // into peer map, so it will never be returned to other peers
+            PeerStatus::Stopped
         } else {
-            ::std::cmp::min(
-                config.protocol.max_response_peers,
-                request.peers_wanted.0.get().try_into().unwrap(),
-            )
+            PeerStatus::from_event_and_bytes_left(request.event.into(), request.bytes_left)
         };
 
-        let status =
-            PeerStatus::from_event_and_bytes_left(request.event.into(), request.bytes_left);
-
         let peer_map_key = ResponsePeer {
             ip_address,
             port: request.port,
@@ -374,7 +1033,10 @@ impl<I: Ip> PeerMap<I> {
                         leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
                         seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
                     },
-                    peers: peer_map.extract_response_peers(max_num_peers_to_take),
+                    peers: peer_map.extract_response_peers(
+                        max_num_peers_to_take,
+                        config.reachability.active(),
+                    ),
                 };
 
                 // Convert peer map to large variant if it is full and
@@ -400,7 +1062,22 @@ impl<I: Ip> PeerMap<I> {
                         leechers: NumberOfPeers::new(leechers.try_into().unwrap_or(i32::MAX)),
                         seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
                     },
-                    peers: peer_map.extract_response_peers(rng, max_num_peers_to_take),
+                    peers: if max_num_peers_to_take == 0 {
+                        Vec::new()
+                    } else {
+                        cache.get_or_extract(
+                            request.info_hash,
+                            max_num_peers_to_take,
+                            peer_map_key,
+                            |n| {
+                                peer_map.extract_response_peers(
+                                    rng,
+                                    n,
+                                    config.reachability.active(),
+                                )
+                            },
+                        )
+                    },
                 };
 
                 // Try shrinking the map if announcing peer is stopped and
@@ -417,17 +1094,17 @@ impl<I: Ip> PeerMap<I> {
 
         match status {
             PeerStatus::Leeching | PeerStatus::Seeding => {
-                let peer = Peer {
-                    peer_id: request.peer_id,
-                    is_seeder: status == PeerStatus::Seeding,
-                    valid_until,
-                };
+                let peer = Peer::new(request.peer_id, status == PeerStatus::Seeding, valid_until);
 
                 match self {
                     Self::Small(peer_map) => peer_map.insert(peer_map_key, peer),
                     Self::Large(peer_map) => peer_map.insert(peer_map_key, peer),
                 }
 
+                if opt_removed_peer.is_none() {
+                    statistics.peers_added.increment(1);
+                }
+
                 if config.statistics.peer_clients && opt_removed_peer.is_none() {
                     statistics_sender
                         .try_send(StatisticsMessage::PeerAdded(request.peer_id))
@@ -435,6 +1112,10 @@ impl<I: Ip> PeerMap<I> {
                 }
             }
             PeerStatus::Stopped => {
+                if opt_removed_peer.is_some() {
+                    statistics.peers_stopped.increment(1);
+                }
+
                 if config.statistics.peer_clients && opt_removed_peer.is_some() {
                     statistics_sender
                         .try_send(StatisticsMessage::PeerRemoved(request.peer_id))
@@ -446,11 +1127,94 @@ impl<I: Ip> PeerMap<I> {
         response
     }
 
-    fn scrape_statistics(&self) -> TorrentScrapeStatistics {
-        let (seeders, leechers) = match self {
+    /// Insert peer, replacing any peer with the same key
+    fn insert(&mut self, key: ResponsePeer<I>, peer: Peer) {
+        match self {
+            Self::Small(peer_map) => {
+                peer_map.remove(&key);
+
+                if peer_map.is_full() {
+                    let mut large_peer_map = peer_map.to_large();
+
+                    large_peer_map.insert(key, peer);
+
+                    *self = Self::Large(large_peer_map);
+                } else {
+                    peer_map.insert(key, peer);
+                }
+            }
+            Self::Large(peer_map) => {
+                peer_map.remove_peer(&key);
+                peer_map.insert(key, peer);
+            }
+        }
+    }
+
+    /// Remove all peers, e.g., when torrent has been idle for too long
+    fn remove_all(
+        &mut self,
+        config: &Config,
+        statistics_messages: &mut Vec<StatisticsMessage>,
+        reclaimed_bytes: &mut usize,
+        peers_removed: &mut usize,
+    ) {
+        match self {
+            Self::Small(peer_map) => {
+                *peers_removed += peer_map.0.len();
+
+                if config.statistics.peer_clients {
+                    statistics_messages.extend(
+                        peer_map
+                            .0
+                            .iter()
+                            .map(|(_, peer)| StatisticsMessage::PeerRemoved(peer.peer_id)),
+                    );
+                }
+
+                peer_map.0.clear();
+            }
+            Self::Large(peer_map) => {
+                *peers_removed += peer_map.peers.len();
+                *reclaimed_bytes +=
+                    peer_map.peers.capacity() * size_of::<(ResponsePeer<I>, Peer)>();
+
+                if config.statistics.peer_clients {
+                    statistics_messages.extend(
+                        peer_map
+                            .peers
+                            .values()
+                            .map(|peer| StatisticsMessage::PeerRemoved(peer.peer_id)),
+                    );
+                }
+
+                *self = Self::Small(SmallPeerMap(ArrayVec::default()));
+            }
+        }
+    }
+
+    fn mark_peer_unreachable(&mut self, key: &ResponsePeer<I>) {
+        let opt_peer = match self {
+            Self::Small(peer_map) => peer_map
+                .0
+                .iter_mut()
+                .find_map(|(k, peer)| (k == key).then_some(peer)),
+            Self::Large(peer_map) => peer_map.peers.get_mut(key),
+        };
+
+        if let Some(peer) = opt_peer {
+            peer.set_unreachable();
+        }
+    }
+
+    fn num_seeders_leechers(&self) -> (usize, usize) {
+        match self {
             Self::Small(peer_map) => peer_map.num_seeders_leechers(),
             Self::Large(peer_map) => peer_map.num_seeders_leechers(),
-        };
+        }
+    }
+
+    fn scrape_statistics(&self) -> TorrentScrapeStatistics {
+        let (seeders, leechers) = self.num_seeders_leechers();
 
         TorrentScrapeStatistics {
             seeders: NumberOfPeers::new(seeders.try_into().unwrap_or(i32::MAX)),
@@ -485,7 +1249,7 @@ impl<I: Ip> SmallPeerMap<I> {
     }
 
     fn num_seeders_leechers(&self) -> (usize, usize) {
-        let seeders = self.0.iter().filter(|(_, p)| p.is_seeder).count();
+        let seeders = self.0.iter().filter(|(_, p)| p.is_seeder()).count();
         let leechers = self.0.len() - seeders;
 
         (seeders, leechers)
@@ -505,21 +1269,37 @@ impl<I: Ip> SmallPeerMap<I> {
         None
     }
 
-    fn extract_response_peers(&self, max_num_peers_to_take: usize) -> Vec<ResponsePeer<I>> {
-        Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+    fn extract_response_peers(
+        &self,
+        max_num_peers_to_take: usize,
+        prioritize_reachable: bool,
+    ) -> Vec<ResponsePeer<I>> {
+        if prioritize_reachable {
+            extract_prioritizing_reachable(
+                self.0.iter().map(|(k, peer)| (k, peer)),
+                max_num_peers_to_take,
+            )
+        } else {
+            Vec::from_iter(self.0.iter().take(max_num_peers_to_take).map(|(k, _)| *k))
+        }
     }
 
     fn clean_and_get_num_peers(
         &mut self,
         config: &Config,
         statistics_messages: &mut Vec<StatisticsMessage>,
-        now: SecondsSinceServerStart,
+        expiry: PeerExpiry,
+        peers_expired: &mut usize,
     ) -> usize {
         self.0.retain(|(_, peer)| {
-            let keep = peer.valid_until.valid(now);
+            let keep = expiry.keep(peer.valid_until(), peer.is_seeder());
+
+            if !keep {
+                *peers_expired += 1;
 
-            if !keep && config.statistics.peer_clients {
-                statistics_messages.push(StatisticsMessage::PeerRemoved(peer.peer_id));
+                if config.statistics.peer_clients {
+                    statistics_messages.push(StatisticsMessage::PeerRemoved(peer.peer_id));
+                }
             }
 
             keep
@@ -548,7 +1328,7 @@ impl<I: Ip> LargePeerMap<I> {
     }
 
     fn insert(&mut self, key: ResponsePeer<I>, peer: Peer) {
-        if peer.is_seeder {
+        if peer.is_seeder() {
             self.num_seeders += 1;
         }
 
@@ -558,10 +1338,7 @@ impl<I: Ip> LargePeerMap<I> {
     fn remove_peer(&mut self, key: &ResponsePeer<I>) -> Option<Peer> {
         let opt_removed_peer = self.peers.swap_remove(key);
 
-        if let Some(Peer {
-            is_seeder: true, ..
-        }) = opt_removed_peer
-        {
+        if opt_removed_peer.map_or(false, |peer| peer.is_seeder()) {
             self.num_seeders -= 1;
         }
 
@@ -574,13 +1351,21 @@ impl<I: Ip> LargePeerMap<I> {
     /// random selection of peers from first and second halves of map in
     /// order to avoid returning too homogeneous peers. This is a lot more
     /// cache-friendly than doing a fully random selection.
+    ///
+    /// If `prioritize_reachable` is true, peers marked as unreachable are
+    /// placed after the other selected peers.
     fn extract_response_peers(
         &self,
         rng: &mut impl Rng,
         max_num_peers_to_take: usize,
+        prioritize_reachable: bool,
     ) -> Vec<ResponsePeer<I>> {
         if self.peers.len() <= max_num_peers_to_take {
-            self.peers.keys().copied().collect()
+            if prioritize_reachable {
+                extract_prioritizing_reachable(self.peers.iter(), max_num_peers_to_take)
+            } else {
+                self.peers.keys().copied().collect()
+            }
         } else {
             let middle_index = self.peers.len() / 2;
             let num_to_take_per_half = max_num_peers_to_take / 2;
@@ -601,6 +1386,24 @@ impl<I: Ip> LargePeerMap<I> {
             let end_half_one = offset_half_one + num_to_take_per_half;
             let end_half_two = offset_half_two + num_to_take_per_half;
 
+            if prioritize_reachable {
+                let half_one = self
+                    .peers
+                    .get_range(offset_half_one..end_half_one)
+                    .into_iter()
+                    .flat_map(|slice| slice.iter());
+                let half_two = self
+                    .peers
+                    .get_range(offset_half_two..end_half_two)
+                    .into_iter()
+                    .flat_map(|slice| slice.iter());
+
+                return extract_prioritizing_reachable(
+                    half_one.chain(half_two),
+                    max_num_peers_to_take,
+                );
+            }
+
             let mut peers = Vec::with_capacity(max_num_peers_to_take);
 
             if let Some(slice) = self.peers.get_range(offset_half_one..end_half_one) {
@@ -618,13 +1421,17 @@ impl<I: Ip> LargePeerMap<I> {
         &mut self,
         config: &Config,
         statistics_messages: &mut Vec<StatisticsMessage>,
-        now: SecondsSinceServerStart,
+        expiry: PeerExpiry,
+        reclaimed_bytes: &mut usize,
+        peers_expired: &mut usize,
     ) -> usize {
         self.peers.retain(|_, peer| {
-            let keep = peer.valid_until.valid(now);
+            let keep = expiry.keep(peer.valid_until(), peer.is_seeder());
 
             if !keep {
-                if peer.is_seeder {
+                *peers_expired += 1;
+
+                if peer.is_seeder() {
                     self.num_seeders -= 1;
                 }
                 if config.statistics.peer_clients {
@@ -635,8 +1442,14 @@ impl<I: Ip> LargePeerMap<I> {
             keep
         });
 
-        if !self.peers.is_empty() {
+        if !self.peers.is_empty() && should_shrink(config, self.peers.capacity(), self.peers.len())
+        {
+            let capacity_before = self.peers.capacity();
+
             self.peers.shrink_to_fit();
+
+            *reclaimed_bytes +=
+                (capacity_before - self.peers.capacity()) * size_of::<(ResponsePeer<I>, Peer)>();
         }
 
         self.peers.len()
@@ -651,11 +1464,89 @@ impl<I: Ip> LargePeerMap<I> {
     }
 }
 
+/// Collect peer keys, placing peers marked as unreachable last
+fn extract_prioritizing_reachable<'a, I: Ip + 'a>(
+    peers: impl Iterator<Item = (&'a ResponsePeer<I>, &'a Peer)> + Clone,
+    max_num_peers_to_take: usize,
+) -> Vec<ResponsePeer<I>> {
+    let reachable = peers.clone().filter(|(_, peer)| !peer.is_unreachable());
+    let unreachable = peers.filter(|(_, peer)| peer.is_unreachable());
+
+    reachable
+        .chain(unreachable)
+        .take(max_num_peers_to_take)
+        .map(|(k, _)| *k)
+        .collect()
+}
+
+/// Number of peers to return in announce response
+///
+/// Negative values of `peers_wanted` mean that the client wants the default
+/// number of peers, while zero means that it doesn't want any.
+fn num_peers_to_take<I: Ip>(config: &Config, peers_wanted: NumberOfPeers) -> usize {
+    if config.protocol.announce_only {
+        return 0;
+    }
+
+    let max_response_peers = config.protocol.response_peers_limit::<I>();
+
+    match usize::try_from(peers_wanted.0.get()) {
+        Ok(peers_wanted) => peers_wanted.min(max_response_peers),
+        Err(_) => max_response_peers,
+    }
+}
+
+/// Check if map should be shrunk according to `cleaning.shrink_threshold`
+fn should_shrink(config: &Config, capacity: usize, len: usize) -> bool {
+    let threshold = config.cleaning.shrink_threshold;
+
+    threshold > 0.0 && capacity > len && capacity as f64 >= len as f64 * threshold
+}
+
+/// Peer map value
+///
+/// Seeding and reachability status are stored in the two most significant
+/// bits of the expiry time (seconds since server start), which keeps the
+/// struct at 24 bytes. The address and port are stored in the map key.
 #[derive(Clone, Copy, Debug)]
 struct Peer {
     peer_id: PeerId,
-    is_seeder: bool,
-    valid_until: ValidUntil,
+    valid_until_and_flags: u32,
+}
+
+impl Peer {
+    const SEEDER_BIT: u32 = 1 << 31;
+    const UNREACHABLE_BIT: u32 = 1 << 30;
+    const FLAG_MASK: u32 = Self::SEEDER_BIT | Self::UNREACHABLE_BIT;
+
+    fn new(peer_id: PeerId, is_seeder: bool, valid_until: ValidUntil) -> Self {
+        let mut valid_until_and_flags = valid_until.to_seconds() & !Self::FLAG_MASK;
+
+        if is_seeder {
+            valid_until_and_flags |= Self::SEEDER_BIT;
+        }
+
+        Self {
+            peer_id,
+            valid_until_and_flags,
+        }
+    }
+
+    fn is_seeder(&self) -> bool {
+        self.valid_until_and_flags & Self::SEEDER_BIT != 0
+    }
+
+    fn is_unreachable(&self) -> bool {
+        self.valid_until_and_flags & Self::UNREACHABLE_BIT != 0
+    }
+
+    fn set_unreachable(&mut self) {
+        self.valid_until_and_flags |= Self::UNREACHABLE_BIT;
+    }
+
+    fn valid_until(&self) -> ValidUntil {
+        ValidUntil::from_seconds(self.valid_until_and_flags & !Self::FLAG_MASK)
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
@@ -703,4 +1594,576 @@ mod tests {
         assert_eq!(Seeding, f(AnnounceEvent::None, NumberOfBytes::new(0)));
         assert_eq!(Leeching, f(AnnounceEvent::None, NumberOfBytes::new(1)));
     }
+
+    #[test]
+    fn test_peer() {
+        assert_eq!(size_of::<Peer>(), 24);
+
+        let peer_id = PeerId([1; 20]);
+
+        for is_seeder in [false, true] {
+            let mut peer = Peer::new(peer_id, is_seeder, ValidUntil::from_seconds(1000));
+
+            assert_eq!(peer.peer_id, peer_id);
+            assert_eq!(peer.is_seeder(), is_seeder);
+            assert!(!peer.is_unreachable());
+            assert_eq!(peer.valid_until().to_seconds(), 1000);
+
+            peer.set_unreachable();
+
+            assert_eq!(peer.is_seeder(), is_seeder);
+            assert!(peer.is_unreachable());
+            assert_eq!(peer.valid_until().to_seconds(), 1000);
+        }
+    }
+
+    #[test]
+    fn test_peer_map_insert() {
+        let mut peer_map = PeerMap::<Ipv4AddrBytes>::default();
+
+        let key = |i: u8| ResponsePeer {
+            ip_address: Ipv4AddrBytes([127, 0, 0, i]),
+            port: Port::new(::std::num::NonZeroU16::new(6881).unwrap()),
+        };
+        let peer = |is_seeder| Peer::new(PeerId([0; 20]), is_seeder, ValidUntil::from_seconds(1));
+
+        let num_seeders_leechers = |peer_map: &PeerMap<Ipv4AddrBytes>| match peer_map {
+            PeerMap::Small(peer_map) => peer_map.num_seeders_leechers(),
+            PeerMap::Large(peer_map) => peer_map.num_seeders_leechers(),
+        };
+
+        for i in 0..SMALL_PEER_MAP_CAPACITY as u8 {
+            peer_map.insert(key(i), peer(true));
+        }
+
+        // Replacing peer in full small map doesn't convert it
+        peer_map.insert(key(0), peer(false));
+
+        assert!(matches!(peer_map, PeerMap::Small(_)));
+        assert_eq!(
+            num_seeders_leechers(&peer_map),
+            (SMALL_PEER_MAP_CAPACITY - 1, 1)
+        );
+
+        peer_map.insert(key(u8::MAX), peer(false));
+
+        assert!(matches!(peer_map, PeerMap::Large(_)));
+        assert_eq!(
+            num_seeders_leechers(&peer_map),
+            (SMALL_PEER_MAP_CAPACITY - 1, 2)
+        );
+
+        peer_map.insert(key(u8::MAX), peer(true));
+
+        assert_eq!(
+            num_seeders_leechers(&peer_map),
+            (SMALL_PEER_MAP_CAPACITY, 1)
+        );
+    }
+
+    #[test]
+    fn test_extract_prioritizing_reachable() {
+        use std::num::NonZeroU16;
+
+        let valid_until = ValidUntil::from_seconds(1000);
+
+        let peers = (1..=4u8)
+            .map(|i| {
+                let key = ResponsePeer {
+                    ip_address: Ipv4AddrBytes([10, 0, 0, i]),
+                    port: Port::new(NonZeroU16::new(1000).unwrap()),
+                };
+                let mut peer = Peer::new(PeerId([i; 20]), false, valid_until);
+
+                if i <= 2 {
+                    peer.set_unreachable();
+                }
+
+                (key, peer)
+            })
+            .collect::<Vec<_>>();
+
+        let keys = peers.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+
+        assert_eq!(
+            extract_prioritizing_reachable(peers.iter().map(|(k, p)| (k, p)), 3),
+            vec![keys[2], keys[3], keys[0]]
+        );
+    }
+
+    #[test]
+    fn test_response_peer_cache() {
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+
+        config.protocol.max_response_peers = 8;
+        config.protocol.announce_response_cache_ms = 60_000;
+
+        let mut cache = ResponsePeerCache::<Ipv4AddrBytes>::new(&config);
+
+        let peers = (1..=8u8)
+            .map(|i| ResponsePeer {
+                ip_address: Ipv4AddrBytes([10, 0, 0, i]),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            })
+            .collect::<Vec<_>>();
+
+        let info_hash = InfoHash([0; 20]);
+
+        let response_peers = cache.get_or_extract(info_hash, 3, peers[0], |n| {
+            assert_eq!(n, 5);
+
+            peers[1..=n].to_vec()
+        });
+
+        assert_eq!(response_peers, peers[1..4].to_vec());
+
+        // Cached peers are reused and announcing peer, which is among them,
+        // is filtered out without reducing the number of returned peers
+        let response_peers = cache.get_or_extract(info_hash, 4, peers[1], |_| {
+            panic!("peers extracted despite being cached");
+        });
+
+        assert_eq!(response_peers, peers[2..6].to_vec());
+
+        // Announcing peer not among cached peers
+        let response_peers = cache.get_or_extract(info_hash, 4, peers[7], |_| {
+            panic!("peers extracted despite being cached");
+        });
+
+        assert_eq!(response_peers, peers[1..5].to_vec());
+    }
+
+    #[test]
+    fn test_response_peer_cache_max_response_peers() {
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+
+        config.protocol.max_response_peers = 4;
+        config.protocol.announce_response_cache_ms = 60_000;
+
+        let mut cache = ResponsePeerCache::<Ipv4AddrBytes>::new(&config);
+
+        let peers = (1..=6u8)
+            .map(|i| ResponsePeer {
+                ip_address: Ipv4AddrBytes([10, 0, 0, i]),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            })
+            .collect::<Vec<_>>();
+
+        let info_hash = InfoHash([0; 20]);
+
+        let response_peers = cache.get_or_extract(info_hash, 4, peers[0], |n| {
+            assert_eq!(n, 5);
+
+            peers[1..=n].to_vec()
+        });
+
+        assert_eq!(response_peers, peers[1..5].to_vec());
+
+        // Full response even though announcing peer is in cached set
+        for announcing_peer in peers[1..].iter().copied() {
+            let response_peers = cache.get_or_extract(info_hash, 4, announcing_peer, |_| {
+                panic!("peers extracted despite being cached");
+            });
+
+            assert_eq!(response_peers.len(), 4);
+            assert!(!response_peers.contains(&announcing_peer));
+        }
+    }
+
+    #[test]
+    fn test_peer_churn_statistics() {
+        use std::num::NonZeroU16;
+
+        let config = Config::default();
+        let statistics = SocketWorkerStatistics::default();
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SwarmRng::seed_from_u64(&config, 0);
+        let mut cache = ResponsePeerCache::<Ipv4AddrBytes>::new(&config);
+        let torrent_maps = TorrentMapShards::<Ipv4AddrBytes>::new(1);
+
+        let mut announce = |peer: u8, event: AnnounceEvent, valid_until: ValidUntil| {
+            let request = AnnounceRequest {
+                connection_id: ConnectionId::new(0),
+                action_placeholder: Default::default(),
+                transaction_id: TransactionId::new(0),
+                info_hash: InfoHash([0; 20]),
+                peer_id: PeerId([peer; 20]),
+                bytes_downloaded: NumberOfBytes::new(0),
+                bytes_uploaded: NumberOfBytes::new(0),
+                bytes_left: NumberOfBytes::new(1),
+                event: event.into(),
+                ip_address: Ipv4AddrBytes([0; 4]),
+                key: PeerKey::new(0),
+                peers_wanted: NumberOfPeers::new(10),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            };
+
+            torrent_maps.announce(
+                &config,
+                &statistics,
+                &statistics_sender,
+                &mut rng,
+                &request,
+                Ipv4AddrBytes([10, 0, 0, peer]),
+                valid_until,
+                &mut cache,
+            );
+        };
+
+        let valid = ValidUntil::from_seconds(1000);
+        let expired = ValidUntil::from_seconds(0);
+
+        announce(1, AnnounceEvent::Started, valid);
+        announce(1, AnnounceEvent::None, valid);
+        announce(2, AnnounceEvent::Started, valid);
+        announce(3, AnnounceEvent::Started, expired);
+        announce(2, AnnounceEvent::Stopped, valid);
+        // Stopping peer that is not in swarm is not counted
+        announce(4, AnnounceEvent::Stopped, valid);
+
+        assert_eq!(statistics.peers_added.take(), 3);
+        assert_eq!(statistics.peers_stopped.take(), 1);
+
+        let (_, num_peers, _, peers_expired, _) = torrent_maps.clean_and_get_statistics(
+            &config,
+            &mut Vec::new(),
+            &mut create_access_list_cache(&Default::default()),
+            config.access_list.mode,
+            PeerExpiry::new(
+                ServerStartInstant::new().seconds_elapsed(),
+                config.cleaning.max_peer_age,
+                config.cleaning.max_seeder_age,
+            ),
+            false,
+            IpVersion::V4,
+            None,
+        );
+
+        assert_eq!(num_peers, 1);
+        assert_eq!(peers_expired, 1);
+    }
+
+    #[test]
+    fn test_scrape_merge_ip_versions() {
+        use std::net::SocketAddr;
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+        let statistics = IpVersionStatistics::<SocketWorkerStatistics>::default();
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SwarmRng::seed_from_u64(&config, 0);
+        let mut cache = AnnounceResponseCache::new(&config);
+        let torrent_maps = TorrentMaps::default();
+
+        let info_hash = InfoHash([1; 20]);
+        let ipv4_src = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+        let ipv6_src =
+            CanonicalSocketAddr::new(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 1000)));
+
+        // IPv4 seeder and IPv6 leecher
+        for (peer, src, bytes_left) in [(1, ipv4_src, 0), (2, ipv6_src, 1)] {
+            let request = AnnounceRequest {
+                connection_id: ConnectionId::new(0),
+                action_placeholder: Default::default(),
+                transaction_id: TransactionId::new(0),
+                info_hash,
+                peer_id: PeerId([peer; 20]),
+                bytes_downloaded: NumberOfBytes::new(0),
+                bytes_uploaded: NumberOfBytes::new(0),
+                bytes_left: NumberOfBytes::new(bytes_left),
+                event: AnnounceEvent::Started.into(),
+                ip_address: Ipv4AddrBytes([0; 4]),
+                key: PeerKey::new(0),
+                peers_wanted: NumberOfPeers::new(10),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            };
+
+            torrent_maps.announce(
+                &config,
+                &statistics,
+                &statistics_sender,
+                &mut rng,
+                &request,
+                src,
+                ValidUntil::from_seconds(1000),
+                &mut cache,
+            );
+        }
+
+        let scrape = |config: &Config, src| {
+            let request = ScrapeRequest {
+                connection_id: ConnectionId::new(0),
+                transaction_id: TransactionId::new(0),
+                info_hashes: vec![info_hash, InfoHash([2; 20])],
+            };
+
+            torrent_maps
+                .scrape(config, request, src)
+                .torrent_stats
+                .into_iter()
+                .map(|stats| {
+                    (
+                        stats.seeders.0.get(),
+                        stats.leechers.0.get(),
+                        stats.completed.0.get(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(scrape(&config, ipv4_src), vec![(1, 0, 0), (0, 0, 0)]);
+        assert_eq!(scrape(&config, ipv6_src), vec![(0, 1, 0), (0, 0, 0)]);
+
+        config.protocol.scrape_merge_ip_versions = true;
+
+        for src in [ipv4_src, ipv6_src] {
+            assert_eq!(scrape(&config, src), vec![(1, 1, 0), (0, 0, 0)]);
+        }
+    }
+
+    #[test]
+    fn test_idle_torrent_removal() {
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+
+        config.cleaning.max_torrent_idle = 60;
+
+        let torrent_maps = TorrentMapShards::<Ipv4AddrBytes>::new(1);
+
+        for i in 0..2 {
+            torrent_maps.insert_peer(
+                InfoHash([0; 20]),
+                ResponsePeer {
+                    ip_address: Ipv4AddrBytes([10, 0, 0, i]),
+                    port: Port::new(NonZeroU16::new(1000).unwrap()),
+                },
+                Peer::new(PeerId([i; 20]), false, ValidUntil::from_seconds(1000)),
+            );
+        }
+
+        let clean = |now: u32| {
+            torrent_maps.clean_and_get_statistics(
+                &config,
+                &mut Vec::new(),
+                &mut create_access_list_cache(&Default::default()),
+                config.access_list.mode,
+                PeerExpiry::new(
+                    SecondsSinceServerStart::from_seconds(now),
+                    config.cleaning.max_peer_age,
+                    config.cleaning.max_seeder_age,
+                ),
+                false,
+                IpVersion::V4,
+                None,
+            )
+        };
+
+        let (num_torrents, num_peers, _, peers_expired, _) = clean(59);
+
+        assert_eq!((num_torrents, num_peers, peers_expired), (1, 2, 0));
+
+        // Peers haven't expired, but torrent has been idle for too long
+        let (num_torrents, num_peers, _, peers_expired, _) = clean(60);
+
+        assert_eq!((num_torrents, num_peers, peers_expired), (0, 0, 2));
+    }
+
+    /// Peers inserted without announcing, e.g. when importing peer dumps,
+    /// get the same expiry time regardless of status, with cleaning keeping
+    /// seeders for max_seeder_age
+    #[test]
+    fn test_inserted_seeder_expiry() {
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+
+        config.cleaning.max_peer_age = 1200;
+        config.cleaning.max_seeder_age = 3600;
+
+        let torrent_maps = TorrentMapShards::<Ipv4AddrBytes>::new(1);
+
+        for (i, is_seeder) in [(0, false), (1, true)] {
+            torrent_maps.insert_peer(
+                InfoHash([0; 20]),
+                ResponsePeer {
+                    ip_address: Ipv4AddrBytes([10, 0, 0, i]),
+                    port: Port::new(NonZeroU16::new(1000).unwrap()),
+                },
+                Peer::new(
+                    PeerId([i; 20]),
+                    is_seeder,
+                    ValidUntil::from_seconds(config.cleaning.max_peer_age),
+                ),
+            );
+        }
+
+        let clean = |now: u32| {
+            torrent_maps.clean_and_get_statistics(
+                &config,
+                &mut Vec::new(),
+                &mut create_access_list_cache(&Default::default()),
+                config.access_list.mode,
+                PeerExpiry::new(
+                    SecondsSinceServerStart::from_seconds(now),
+                    config.cleaning.max_peer_age,
+                    config.cleaning.max_seeder_age,
+                ),
+                false,
+                IpVersion::V4,
+                None,
+            )
+        };
+
+        // Leecher expires after max_peer_age
+        let (_, num_peers, _, peers_expired, _) = clean(1200);
+
+        assert_eq!((num_peers, peers_expired), (1, 1));
+
+        let (_, num_peers, _, peers_expired, _) = clean(3599);
+
+        assert_eq!((num_peers, peers_expired), (1, 0));
+
+        // Seeder expires after max_seeder_age
+        let (_, num_peers, _, peers_expired, _) = clean(3600);
+
+        assert_eq!((num_peers, peers_expired), (0, 1));
+    }
+
+    #[test]
+    fn test_under_seeded_torrents() {
+        use std::num::NonZeroU16;
+
+        let mut config = Config::default();
+
+        config.snapshot.under_seeded_report_len = 2;
+        config.snapshot.under_seeded_min_leechers = 2;
+
+        let torrent_maps = TorrentMapShards::<Ipv4AddrBytes>::new(1);
+
+        // Seeder and leecher counts of torrents with info hashes 1, 2, 3 and 4
+        for (i, (seeders, leechers)) in [(1, 3), (0, 3), (0, 1), (4, 2)].into_iter().enumerate() {
+            for j in 0..(seeders + leechers) {
+                torrent_maps.insert_peer(
+                    InfoHash([i as u8 + 1; 20]),
+                    ResponsePeer {
+                        ip_address: Ipv4AddrBytes([10, 0, 0, j]),
+                        port: Port::new(NonZeroU16::new(1000).unwrap()),
+                    },
+                    Peer::new(PeerId([j; 20]), j < seeders, ValidUntil::from_seconds(1000)),
+                );
+            }
+        }
+
+        let clean = || {
+            let mut under_seeded = UnderSeededTorrents::new(&config);
+
+            torrent_maps.clean_and_get_statistics(
+                &config,
+                &mut Vec::new(),
+                &mut create_access_list_cache(&Default::default()),
+                config.access_list.mode,
+                PeerExpiry::new(
+                    SecondsSinceServerStart::from_seconds(0),
+                    config.cleaning.max_peer_age,
+                    config.cleaning.max_seeder_age,
+                ),
+                false,
+                IpVersion::V4,
+                Some(&mut under_seeded),
+            );
+
+            under_seeded
+                .into_sorted_vec()
+                .into_iter()
+                .map(|health| (health.info_hash.0[0], health.score))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(clean(), vec![(2, 0.0), (1, 1.0 / 3.0)]);
+
+        // Completions since previous cleaning pass count towards health
+        torrent_maps.0[0]
+            .read()
+            .get(&InfoHash([2; 20]))
+            .unwrap()
+            .completed
+            .fetch_add(3, Ordering::Relaxed);
+
+        assert_eq!(clean(), vec![(1, 1.0 / 3.0), (2, 1.0)]);
+        assert_eq!(clean(), vec![(2, 0.0), (1, 1.0 / 3.0)]);
+    }
+
+    #[test]
+    fn test_num_peers_to_take() {
+        let mut config = Config::default();
+
+        config.protocol.max_response_peers = 30;
+
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(-1)),
+            30
+        );
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(0)),
+            0
+        );
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(10)),
+            10
+        );
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(100)),
+            30
+        );
+
+        config.protocol.announce_only = true;
+
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(-1)),
+            0
+        );
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(10)),
+            0
+        );
+
+        config.protocol.announce_only = false;
+        config.protocol.max_response_peers = 100;
+        config.protocol.max_response_packet_bytes = 1200;
+
+        assert_eq!(
+            num_peers_to_take::<Ipv4AddrBytes>(&config, NumberOfPeers::new(-1)),
+            100
+        );
+        assert_eq!(
+            num_peers_to_take::<Ipv6AddrBytes>(&config, NumberOfPeers::new(-1)),
+            65
+        );
+    }
+
+    #[test]
+    fn test_should_shrink() {
+        let mut config = Config::default();
+
+        config.cleaning.shrink_threshold = 2.0;
+
+        assert!(should_shrink(&config, 20, 10));
+        assert!(should_shrink(&config, 20, 0));
+        assert!(!should_shrink(&config, 19, 10));
+        assert!(!should_shrink(&config, 0, 0));
+
+        config.cleaning.shrink_threshold = 1.0;
+
+        assert!(should_shrink(&config, 11, 10));
+        assert!(!should_shrink(&config, 10, 10));
+
+        config.cleaning.shrink_threshold = 0.0;
+
+        assert!(!should_shrink(&config, 20, 0));
+    }
 }
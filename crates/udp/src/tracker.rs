@@ -0,0 +1,587 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{available_parallelism, Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::access_list::{
+    spawn_access_list_updater, update_access_list, AccessListArcSwap,
+};
+use aquatic_common::bind::BindNotifier;
+use aquatic_common::ip_blocklist::update_ip_blocklist;
+use aquatic_common::maintenance::{spawn_maintenance_worker, Schedule};
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::shutdown::{join_workers, SHUTDOWN_TIMEOUT};
+use aquatic_common::{validate_ipv6_prefix_len, validate_max_scrape_torrents, WorkerType};
+use crossbeam_channel::{bounded, unbounded, Receiver};
+#[cfg(unix)]
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+
+use crate::common::{IpVersion, State, Statistics};
+use crate::config::{Config, SnapshotFormat};
+use crate::geoip::{AsnLookup, CountryLookup};
+use crate::swarm::{SwarmSummary, TorrentCleaner};
+use crate::workers;
+use crate::workers::socket::ConnectionValidator;
+
+/// UDP tracker running in the current process, e.g., embedded in another
+/// application
+///
+/// ```no_run
+/// use aquatic_udp::{config::Config, Tracker};
+///
+/// let mut config = Config::default();
+///
+/// config.network.address.set_port(0);
+///
+/// let tracker = Tracker::start(config)?;
+///
+/// println!("listening on {}", tracker.local_addr());
+///
+/// tracker.shutdown()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Tracker;
+
+impl Tracker {
+    /// Start all workers and wait until socket workers are ready to receive
+    /// requests
+    ///
+    /// The prometheus endpoint, if enabled, installs a process-wide metrics
+    /// recorder, so only one tracker with it enabled can be started per
+    /// process. No signal handlers are installed.
+    pub fn start(mut config: Config) -> anyhow::Result<TrackerHandle> {
+        validate_max_scrape_torrents(config.protocol.max_scrape_torrents)?;
+        validate_ipv6_prefix_len(config.network.ipv6_prefix_len)?;
+
+        // The kernel accepts at most UIO_MAXIOV messages per call
+        #[cfg(feature = "mmsg")]
+        if config.network.mmsg_batch_size > 1024 {
+            return Err(anyhow::anyhow!(
+                "network.mmsg_batch_size must be at most 1024"
+            ));
+        }
+
+        #[cfg(feature = "io-uring")]
+        if let Some(use_io_uring) = config.network.use_io_uring.take() {
+            ::log::warn!("network.use_io_uring is deprecated, use network.backend instead");
+
+            config.network.backend = if use_io_uring {
+                crate::config::SocketBackend::IoUring
+            } else {
+                crate::config::SocketBackend::Mio
+            };
+        }
+
+        // The error queue would only fill up, since the io_uring backend
+        // doesn't drain it
+        #[cfg(feature = "io-uring")]
+        if config.network.backend == crate::config::SocketBackend::IoUring
+            && config.network.socket_recv_errors
+        {
+            ::log::warn!(
+                "network.socket_recv_errors is not supported by the io_uring backend, ignoring it"
+            );
+
+            config.network.socket_recv_errors = false;
+        }
+
+        if config.socket_workers == 0 {
+            config.socket_workers = available_parallelism().map(Into::into).unwrap_or(1);
+        };
+
+        if config.debug.single_threaded {
+            ::log::warn!("Running in single-threaded debug mode, performance will be poor");
+
+            config.socket_workers = 1;
+            config.network.use_sender_threads = false;
+        }
+
+        #[cfg(target_os = "openbsd")]
+        {
+            if config.socket_workers > 1 {
+                ::log::warn!(
+                    "SO_REUSEPORT doesn't distribute packets among sockets on OpenBSD, so only one socket worker will receive requests"
+                );
+            }
+            if config.network.address.is_ipv6() && !config.network.only_ipv6 {
+                ::log::warn!("Dual-stack sockets are not supported on OpenBSD, so no IPv4 requests will be received");
+            }
+        }
+
+        // Sockets can only share a port for load balancing on unix-like platforms
+        #[cfg(not(unix))]
+        if config.socket_workers > 1 {
+            ::log::warn!("Multiple socket workers are not supported on this platform, using one");
+
+            config.socket_workers = 1;
+        }
+
+        let (reachability_probe_sender, opt_reachability_probe_receiver) =
+            if config.reachability.active() {
+                let (sender, receiver) = bounded(config.reachability.probes_per_second as usize);
+
+                (Some(sender), Some(receiver))
+            } else {
+                (None, None)
+            };
+
+        let state = State {
+            reachability_probe_sender,
+            country_lookup: CountryLookup::create(&config)?.map(Arc::new),
+            asn_lookup: AsnLookup::create(&config)?.map(Arc::new),
+            ..Default::default()
+        };
+        let statistics = Statistics::new(&config);
+        let opt_maintenance_schedule: Option<Schedule> = config
+            .maintenance
+            .active()
+            .then(|| config.maintenance.schedule.parse())
+            .transpose()
+            .context("parse maintenance.schedule")?;
+        let connection_validator = ConnectionValidator::new(&config)?;
+        let (bound_sender, bound_receiver) = bounded(1);
+        let bind_notifier = BindNotifier::new(
+            &mut config.network.address,
+            config.network.only_ipv6,
+            socket2::Type::DGRAM,
+            config.socket_workers,
+            Some(Box::new(move |addr| {
+                let _ = bound_sender.send(addr);
+            })),
+        )?;
+        let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers)
+            .with_bind_notifier(bind_notifier);
+        let (statistics_sender, statistics_receiver) = unbounded();
+
+        update_access_list(&config.access_list, &state.access_list)?;
+        update_ip_blocklist(&config.ip_blocklist, &state.ip_blocklist)?;
+        crate::import::import_peers(&config, &state)?;
+
+        #[cfg(feature = "cpu-pinning")]
+        aquatic_common::cpu_pinning::log_cpu_pinning_mapping(
+            &config.cpu_pinning,
+            config.socket_workers,
+            0,
+        );
+
+        // Created before spawning any workers, so that they are told to stop
+        // when it is dropped on error
+        let mut tracker = TrackerHandle {
+            config: config.clone(),
+            state: state.clone(),
+            local_addr: config.network.address,
+            join_handles: Vec::new(),
+        };
+
+        // Spawn combined socket and cleaning thread
+        if config.debug.single_threaded {
+            let state = state.clone();
+            let config = config.clone();
+            let connection_validator = connection_validator.clone();
+            let priv_dropper = priv_dropper.clone();
+            let statistics = statistics.clone();
+            let statistics_sender = statistics_sender.clone();
+
+            let handle = Builder::new()
+                .name("single-threaded".into())
+                .spawn(move || {
+                    workers::socket::run_single_threaded_worker(
+                        config,
+                        state,
+                        statistics,
+                        statistics_sender,
+                        connection_validator,
+                        priv_dropper,
+                    )
+                })
+                .with_context(|| "spawn single-threaded worker")?;
+
+            tracker.join_handles.push((WorkerType::Socket(0), handle));
+        }
+
+        // Spawn socket worker threads
+        for i in 0..config.socket_workers {
+            if config.debug.single_threaded {
+                break;
+            }
+
+            let state = state.clone();
+            let config = config.clone();
+            let connection_validator = connection_validator.clone();
+            let priv_dropper = priv_dropper.clone();
+            let statistics = statistics.socket[i].clone();
+            let statistics_sender = statistics_sender.clone();
+
+            let handle = Builder::new()
+                .name(format!("socket-{:02}", i + 1))
+                .spawn(move || {
+                    #[cfg(feature = "cpu-pinning")]
+                    aquatic_common::cpu_pinning::pin_current_if_configured_to(
+                        &config.cpu_pinning,
+                        config.socket_workers,
+                        0,
+                        aquatic_common::cpu_pinning::WorkerIndex::SocketWorker(i),
+                    );
+
+                    workers::socket::run_socket_worker(
+                        config,
+                        state,
+                        statistics,
+                        statistics_sender,
+                        connection_validator,
+                        priv_dropper,
+                        i,
+                    )
+                })
+                .with_context(|| "spawn socket worker")?;
+
+            tracker.join_handles.push((WorkerType::Socket(i), handle));
+        }
+
+        // Spawn cleaning thread
+        if !config.debug.single_threaded {
+            let state = state.clone();
+            let config = config.clone();
+            let mut cleaner =
+                TorrentCleaner::new(&config, statistics.swarm.clone(), statistics_sender.clone());
+
+            let handle = Builder::new()
+                .name("cleaning".into())
+                .spawn(move || {
+                    let interval = Duration::from_secs(config.cleaning.torrent_cleaning_interval);
+
+                    while !state.shutdown.sleep(interval) {
+                        cleaner.clean(&config, &state);
+                    }
+
+                    Ok(())
+                })
+                .with_context(|| "spawn cleaning worker")?;
+
+            tracker.join_handles.push((WorkerType::Cleaning, handle));
+        }
+
+        // Spawn reachability prober thread
+        if let Some(receiver) = opt_reachability_probe_receiver {
+            let state = state.clone();
+            let config = config.clone();
+
+            let handle = Builder::new()
+                .name("prober".into())
+                .spawn(move || workers::prober::run_reachability_prober(config, state, receiver))
+                .with_context(|| "spawn reachability prober")?;
+
+            tracker
+                .join_handles
+                .push((WorkerType::ReachabilityProber, handle));
+        }
+
+        // Spawn access list updater thread
+        if let Some(handle) = spawn_access_list_updater(
+            config.access_list.clone(),
+            state.access_list.clone(),
+            state.shutdown.clone(),
+        )? {
+            tracker
+                .join_handles
+                .push((WorkerType::AccessListUpdater, handle));
+        }
+
+        // Spawn IP blocklist updater thread
+        if config.ip_blocklist.enabled && config.ip_blocklist.update_interval != 0 {
+            let state = state.clone();
+            let config = config.clone();
+
+            let handle = Builder::new()
+                .name("ip-blocklist".into())
+                .spawn(move || {
+                    let interval = Duration::from_secs(config.ip_blocklist.update_interval);
+
+                    while !state.shutdown.sleep(interval) {
+                        let _ = update_ip_blocklist(&config.ip_blocklist, &state.ip_blocklist);
+                    }
+
+                    Ok(())
+                })
+                .with_context(|| "spawn ip blocklist updater")?;
+
+            tracker
+                .join_handles
+                .push((WorkerType::IpBlocklistUpdater, handle));
+        }
+
+        // Spawn snapshot endpoint thread
+        if config.snapshot.run_endpoint {
+            let handle = workers::snapshot::spawn_snapshot_endpoint(config.clone(), state.clone())?;
+
+            tracker
+                .join_handles
+                .push((WorkerType::SnapshotEndpoint, handle));
+        }
+
+        // Spawn snapshot file writer thread
+        if config.snapshot.file_interval != 0 {
+            let handle =
+                workers::snapshot::spawn_snapshot_file_writer(config.clone(), state.clone())?;
+
+            tracker
+                .join_handles
+                .push((WorkerType::SnapshotFileWriter, handle));
+        }
+
+        // Spawn maintenance thread
+        if let Some(schedule) = opt_maintenance_schedule {
+            let state = state.clone();
+            let config = config.clone();
+
+            let handle = spawn_maintenance_worker(schedule, state.shutdown.clone(), move || {
+                run_maintenance_tasks(&config, &state)
+            })?;
+
+            tracker.join_handles.push((WorkerType::Maintenance, handle));
+        }
+
+        // Spawn statistics thread
+        if config.statistics.active() {
+            let state = state.clone();
+            let config = config.clone();
+
+            let handle = Builder::new()
+                .name("statistics".into())
+                .spawn(move || {
+                    #[cfg(feature = "cpu-pinning")]
+                    aquatic_common::cpu_pinning::pin_current_if_configured_to(
+                        &config.cpu_pinning,
+                        config.socket_workers,
+                        0,
+                        aquatic_common::cpu_pinning::WorkerIndex::Util,
+                    );
+
+                    workers::statistics::run_statistics_worker(
+                        config,
+                        state,
+                        statistics,
+                        statistics_receiver,
+                    )
+                })
+                .with_context(|| "spawn statistics worker")?;
+
+            tracker.join_handles.push((WorkerType::Statistics, handle));
+        }
+
+        // Spawn prometheus endpoint thread
+        #[cfg(feature = "prometheus")]
+        if config.statistics.active() && config.statistics.run_prometheus_endpoint {
+            let handle = aquatic_common::spawn_prometheus_endpoint(
+                config.statistics.prometheus_endpoint_address,
+                Some(Duration::from_secs(
+                    config.cleaning.torrent_cleaning_interval * 2,
+                )),
+                None,
+                Some(aquatic_common::cli::BuildInfo::new(
+                    crate::APP_VERSION,
+                    &config,
+                )),
+                state.shutdown.clone(),
+            )?;
+
+            tracker.join_handles.push((WorkerType::Prometheus, handle));
+        }
+
+        tracker.wait_until_bound(bound_receiver)?;
+
+        Ok(tracker)
+    }
+}
+
+/// Handle to running tracker
+///
+/// Dropping the handle tells all workers to stop without waiting for them.
+pub struct TrackerHandle {
+    config: Config,
+    state: State,
+    local_addr: SocketAddr,
+    join_handles: Vec<(WorkerType, JoinHandle<anyhow::Result<()>>)>,
+}
+
+impl TrackerHandle {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Address that socket workers are bound to. If port 0 was configured,
+    /// this contains the port chosen by the operating system.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Count current torrents and peers
+    pub fn stats(&self) -> TrackerStatistics {
+        TrackerStatistics {
+            ipv4: self.state.torrent_maps.summary(IpVersion::V4),
+            ipv6: self.state.torrent_maps.summary(IpVersion::V6),
+        }
+    }
+
+    /// Access list used by workers. It can be replaced by storing a new
+    /// list in it. It is only consulted if `access_list.mode` isn't off.
+    pub fn access_list(&self) -> &Arc<AccessListArcSwap> {
+        &self.state.access_list
+    }
+
+    /// Reload access list and IP blocklist from the configured files
+    pub fn reload_lists(&self) -> anyhow::Result<()> {
+        update_access_list(&self.config.access_list, &self.state.access_list)?;
+        update_ip_blocklist(&self.config.ip_blocklist, &self.state.ip_blocklist)?;
+
+        Ok(())
+    }
+
+    /// Write snapshot of all torrents. See [`crate::config::SnapshotConfig`].
+    pub fn write_snapshot(&self, format: SnapshotFormat, writer: impl Write) -> anyhow::Result<()> {
+        workers::snapshot::write_snapshot(&self.config, &self.state, format, 0, writer)
+    }
+
+    /// Block until shutdown is triggered by a termination signal, in which
+    /// case workers are waited for as in [`Self::shutdown`], or until any
+    /// worker stops or panics, returning an error describing what happened
+    pub fn wait(mut self) -> anyhow::Result<()> {
+        loop {
+            if self.state.shutdown.sleep(Duration::from_secs(5)) {
+                return self.shutdown();
+            }
+
+            if let Err(err) = self.check_workers() {
+                // Worker might have stopped because shutdown was triggered
+                // after sleeping
+                if self.state.shutdown.is_triggered() {
+                    return self.shutdown();
+                }
+
+                return Err(err);
+            }
+        }
+    }
+
+    /// Tell all workers to stop and wait for them to do so, for at most ten
+    /// seconds
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.state.shutdown.trigger();
+
+        join_workers(::std::mem::take(&mut self.join_handles), SHUTDOWN_TIMEOUT)
+    }
+
+    /// Reload lists when SIGUSR1 is received, write snapshot file when
+    /// SIGUSR2 is and trigger shutdown when SIGTERM or SIGINT is
+    #[cfg(unix)]
+    pub(crate) fn spawn_signal_handler(&mut self, mut signals: Signals) -> anyhow::Result<()> {
+        let config = self.config.clone();
+        let state = self.state.clone();
+
+        let handle = Builder::new()
+            .name("signals".into())
+            .spawn(move || {
+                for signal in &mut signals {
+                    match signal {
+                        SIGUSR1 => {
+                            let _ = update_access_list(&config.access_list, &state.access_list);
+                            let _ = update_ip_blocklist(&config.ip_blocklist, &state.ip_blocklist);
+                        }
+                        SIGUSR2 => match workers::snapshot::write_snapshot_file(&config, &state) {
+                            Ok(()) => ::log::info!("wrote snapshot file"),
+                            Err(err) => ::log::error!("couldn't write snapshot file: {:#}", err),
+                        },
+                        SIGTERM | SIGINT => {
+                            ::log::info!("received signal {}, shutting down", signal);
+
+                            state.shutdown.trigger();
+
+                            break;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                Ok(())
+            })
+            .context("spawn signal worker")?;
+
+        self.join_handles.push((WorkerType::Signals, handle));
+
+        Ok(())
+    }
+
+    fn wait_until_bound(&mut self, receiver: Receiver<SocketAddr>) -> anyhow::Result<()> {
+        loop {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(addr) => {
+                    self.local_addr = addr;
+
+                    return Ok(());
+                }
+                Err(_) => self.check_workers()?,
+            }
+        }
+    }
+
+    /// Return error if any worker has stopped
+    fn check_workers(&mut self) -> anyhow::Result<()> {
+        let opt_index = self
+            .join_handles
+            .iter()
+            .position(|(_, handle)| handle.is_finished());
+
+        if let Some(index) = opt_index {
+            let (worker_type, handle) = self.join_handles.remove(index);
+
+            return match handle.join() {
+                Ok(Ok(())) => Err(anyhow::anyhow!("{} stopped", worker_type)),
+                Ok(Err(err)) => Err(err.context(format!("{} stopped", worker_type))),
+                Err(_) => Err(anyhow::anyhow!("{} panicked", worker_type)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TrackerHandle {
+    fn drop(&mut self) {
+        self.state.shutdown.trigger();
+    }
+}
+
+fn run_maintenance_tasks(config: &Config, state: &State) {
+    if config.maintenance.reload_lists {
+        let _ = update_access_list(&config.access_list, &state.access_list);
+        let _ = update_ip_blocklist(&config.ip_blocklist, &state.ip_blocklist);
+    }
+
+    if config.maintenance.write_snapshot_file {
+        match workers::snapshot::write_snapshot_file(config, state) {
+            Ok(()) => ::log::info!("wrote snapshot file"),
+            Err(err) => ::log::error!("couldn't write snapshot file: {:#}", err),
+        }
+    }
+
+    if config.maintenance.shrink_torrent_maps {
+        let reclaimed_bytes = state.torrent_maps.shrink_to_fit();
+
+        ::log::info!(
+            "shrank torrent maps, reclaiming approximately {} bytes",
+            reclaimed_bytes
+        );
+    }
+}
+
+/// Current torrent and peer counts, see [`TrackerHandle::stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrackerStatistics {
+    pub ipv4: SwarmSummary,
+    pub ipv6: SwarmSummary,
+}
@@ -1,2 +1,4 @@
+pub mod prober;
+pub mod snapshot;
 pub mod socket;
 pub mod statistics;
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, TryRecvError};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token};
+use slab::Slab;
+
+use crate::common::{ReachabilityProbeRequest, State};
+use crate::config::Config;
+
+const POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+struct Probe {
+    request: ReachabilityProbeRequest,
+    stream: TcpStream,
+    started_at: Instant,
+}
+
+/// Attempt TCP connections to sampled peer addresses and mark peers that
+/// can't be connected to as unreachable
+///
+/// Connection attempts are started at a steady rate of at most
+/// `reachability.probes_per_second`. Requests arriving while the channel is
+/// full are dropped by the socket workers, so only a sample of peers is
+/// probed.
+pub fn run_reachability_prober(
+    config: Config,
+    state: State,
+    request_receiver: Receiver<ReachabilityProbeRequest>,
+) -> anyhow::Result<()> {
+    let mut poll = Poll::new().context("create poll")?;
+    let mut events = Events::with_capacity(config.reachability.max_probes_in_flight.max(1));
+    let mut probes: Slab<Probe> = Slab::new();
+
+    let timeout = Duration::from_millis(config.reachability.timeout_ms);
+    let probe_interval = Duration::from_secs(1) / config.reachability.probes_per_second.max(1);
+    let mut next_probe_at = Instant::now();
+
+    while !state.shutdown.is_triggered() {
+        poll.poll(&mut events, Some(POLL_TIMEOUT)).context("poll")?;
+
+        for event in events.iter() {
+            let key = event.token().0;
+
+            if !probes.contains(key) {
+                continue;
+            }
+
+            let probe = probes.remove(key);
+
+            // Connection succeeded if peer address can be retrieved
+            let reachable =
+                matches!(probe.stream.take_error(), Ok(None)) && probe.stream.peer_addr().is_ok();
+
+            if !reachable {
+                mark_unreachable(&state, probe.request);
+            }
+        }
+
+        let now = Instant::now();
+
+        probes.retain(|_, probe| {
+            if now.duration_since(probe.started_at) >= timeout {
+                mark_unreachable(&state, probe.request);
+
+                false
+            } else {
+                true
+            }
+        });
+
+        while next_probe_at <= now && probes.len() < config.reachability.max_probes_in_flight {
+            let request = match request_receiver.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => {
+                    // Don't accumulate probe budget while idle
+                    next_probe_at = now;
+
+                    break;
+                }
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            };
+
+            next_probe_at += probe_interval;
+
+            let mut stream = match TcpStream::connect(request.addr.get()) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    ::log::debug!("couldn't start probe to {}: {:#}", request.addr.get(), err);
+
+                    mark_unreachable(&state, request);
+
+                    continue;
+                }
+            };
+
+            let entry = probes.vacant_entry();
+
+            poll.registry()
+                .register(&mut stream, Token(entry.key()), Interest::WRITABLE)
+                .context("register probe stream")?;
+
+            entry.insert(Probe {
+                request,
+                stream,
+                started_at: now,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn mark_unreachable(state: &State, request: ReachabilityProbeRequest) {
+    ::log::debug!("peer {} is unreachable", request.addr.get());
+
+    state
+        .torrent_maps
+        .mark_peer_unreachable(request.info_hash, request.addr);
+}
@@ -0,0 +1,429 @@
+use std::convert::Infallible;
+use std::fs::{rename, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::cli::BuildInfo;
+use aquatic_udp_protocol::InfoHash;
+use hashbrown::HashMap;
+
+use crate::common::{IpVersion, State};
+use crate::config::{Config, SnapshotFormat};
+use crate::swarm::{TorrentCounts, TorrentHealth};
+
+/// How often to check for new connections and shutdown
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Write snapshot of all torrents that haven't received announce requests
+/// for at least `min_idle_seconds`
+pub fn write_snapshot(
+    config: &Config,
+    state: &State,
+    format: SnapshotFormat,
+    min_idle_seconds: u32,
+    writer: impl Write,
+) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let now = state.server_start_instant.seconds_elapsed();
+    let pause = Duration::from_millis(config.snapshot.shard_pause_ms);
+
+    if let SnapshotFormat::Plain = format {
+        // Counts of both IP versions are combined, so all of them need to be
+        // collected before writing
+        let mut combined: HashMap<InfoHash, TorrentCounts> = HashMap::new();
+
+        state
+            .torrent_maps
+            .for_each_shard_counts(now, pause, |torrents| {
+                for counts in torrents {
+                    combined
+                        .entry(counts.info_hash)
+                        .and_modify(|entry| {
+                            entry.seeders += counts.seeders;
+                            entry.leechers += counts.leechers;
+                            entry.completed = entry.completed.saturating_add(counts.completed);
+                            entry.idle_seconds = entry.idle_seconds.min(counts.idle_seconds);
+                        })
+                        .or_insert(*counts);
+                }
+
+                Ok::<(), Infallible>(())
+            })?;
+
+        for counts in combined.values() {
+            if counts.idle_seconds >= min_idle_seconds {
+                write_plain_entry(&mut writer, counts)?;
+            }
+        }
+    } else {
+        if let SnapshotFormat::Csv = format {
+            writeln!(
+                writer,
+                "info_hash,ip_version,seeders,leechers,completed,idle_seconds"
+            )?;
+        }
+
+        state
+            .torrent_maps
+            .for_each_shard_counts(now, pause, |torrents| {
+                torrents
+                    .iter()
+                    .filter(|counts| counts.idle_seconds >= min_idle_seconds)
+                    .try_for_each(|counts| write_entry(&mut writer, format, counts))
+            })?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Write snapshot to `snapshot.file_path`, replacing any previous one once
+/// done
+pub fn write_snapshot_file(config: &Config, state: &State) -> anyhow::Result<()> {
+    let path = &config.snapshot.file_path;
+
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+
+    let file =
+        File::create(&tmp_path).with_context(|| format!("create snapshot file {:?}", tmp_path))?;
+
+    write_snapshot(config, state, config.snapshot.file_format, 0, file)?;
+
+    rename(&tmp_path, path).with_context(|| format!("move snapshot file to {:?}", path))?;
+
+    Ok(())
+}
+
+/// Write snapshot file every `snapshot.file_interval` seconds
+pub fn spawn_snapshot_file_writer(
+    config: Config,
+    state: State,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let handle = Builder::new()
+        .name("snapshot-file".into())
+        .spawn(move || {
+            let interval = Duration::from_secs(config.snapshot.file_interval);
+
+            while !state.shutdown.sleep(interval) {
+                if let Err(err) = write_snapshot_file(&config, &state) {
+                    ::log::error!("couldn't write snapshot file: {:#}", err);
+                }
+            }
+
+            Ok(())
+        })
+        .context("spawn snapshot file writer")?;
+
+    Ok(handle)
+}
+
+/// Serve snapshots on `GET /torrents.ndjson`, `GET /torrents.csv` and
+/// `GET /torrents.txt`, under-seeded torrent report on
+/// `GET /under_seeded.ndjson` if enabled and build information on
+/// `GET /build_info`
+///
+/// Only stale torrents are included in snapshots if query parameter
+/// `min_idle_seconds` is set. Requests are handled one at a time.
+pub fn spawn_snapshot_endpoint(
+    config: Config,
+    state: State,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let addr = config.snapshot.endpoint_address;
+
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("bind snapshot endpoint to {}", addr))?;
+
+    // Poll for connections, so that shutdown is noticed
+    listener.set_nonblocking(true)?;
+
+    let build_info = BuildInfo::new(crate::APP_VERSION, &config);
+
+    let handle = Builder::new()
+        .name("snapshot".into())
+        .spawn(move || loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = handle_request(&config, &state, &build_info, stream) {
+                        ::log::warn!("snapshot endpoint: {:#}", err);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if state.shutdown.sleep(ACCEPT_INTERVAL) {
+                        return Ok(());
+                    }
+                }
+                Err(err) => {
+                    ::log::warn!("snapshot endpoint: accept connection: {:#}", err);
+                }
+            }
+        })
+        .context("spawn snapshot endpoint")?;
+
+    Ok(handle)
+}
+
+fn handle_request(
+    config: &Config,
+    state: &State,
+    build_info: &BuildInfo,
+    mut stream: TcpStream,
+) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+
+    let mut buffer = [0u8; 1024];
+    let bytes_read = stream.read(&mut buffer)?;
+    let request = &buffer[..bytes_read];
+
+    let (path, query) = parse_request_target(request).unwrap_or_default();
+
+    if path == "/build_info" {
+        let body = serde_json::to_string_pretty(build_info).context("serialize build info")?;
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+
+        return Ok(());
+    }
+
+    if path == "/under_seeded.ndjson" && config.snapshot.under_seeded_report_active() {
+        // Don't hold lock while writing to stream
+        let report = state.under_seeded_torrents.lock().clone();
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n"
+        )?;
+
+        let mut writer = BufWriter::new(stream);
+
+        for health in report.iter() {
+            write_health_entry(&mut writer, health)?;
+        }
+
+        writer.flush()?;
+
+        return Ok(());
+    }
+
+    let opt_format = match path {
+        "/torrents.ndjson" => Some((SnapshotFormat::Ndjson, "application/x-ndjson")),
+        "/torrents.csv" => Some((SnapshotFormat::Csv, "text/csv")),
+        "/torrents.txt" => Some((SnapshotFormat::Plain, "text/plain")),
+        _ => None,
+    };
+
+    let min_idle_seconds = match parse_min_idle_seconds(query) {
+        Ok(min_idle_seconds) => min_idle_seconds,
+        Err(err) => {
+            write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+
+            return Err(err);
+        }
+    };
+
+    if let Some((format, content_type)) = opt_format {
+        // Body length isn't known in advance, so it is ended by closing the
+        // connection
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+            content_type
+        )?;
+
+        write_snapshot(config, state, format, min_idle_seconds, &mut stream)?;
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Extract path and query from GET request line
+fn parse_request_target(request: &[u8]) -> Option<(&str, &str)> {
+    let request = ::std::str::from_utf8(request).ok()?;
+    let target = request.strip_prefix("GET ")?.split(' ').next()?;
+
+    Some(target.split_once('?').unwrap_or((target, "")))
+}
+
+fn parse_min_idle_seconds(query: &str) -> anyhow::Result<u32> {
+    for pair in query.split('&') {
+        if let Some(value) = pair.strip_prefix("min_idle_seconds=") {
+            return value.parse().context("parse min_idle_seconds");
+        }
+    }
+
+    Ok(0)
+}
+
+fn write_entry(
+    writer: &mut impl Write,
+    format: SnapshotFormat,
+    counts: &TorrentCounts,
+) -> io::Result<()> {
+    let info_hash = hex::encode(counts.info_hash.0);
+    let ip_version = match counts.ip_version {
+        IpVersion::V4 => "ipv4",
+        IpVersion::V6 => "ipv6",
+    };
+
+    match format {
+        SnapshotFormat::Ndjson => writeln!(
+            writer,
+            r#"{{"info_hash":"{}","ip_version":"{}","seeders":{},"leechers":{},"completed":{},"idle_seconds":{}}}"#,
+            info_hash,
+            ip_version,
+            counts.seeders,
+            counts.leechers,
+            counts.completed,
+            counts.idle_seconds
+        ),
+        SnapshotFormat::Csv => writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            info_hash,
+            ip_version,
+            counts.seeders,
+            counts.leechers,
+            counts.completed,
+            counts.idle_seconds
+        ),
+        SnapshotFormat::Plain => unreachable!("plain entries are written separately"),
+    }
+}
+
+fn write_health_entry(writer: &mut impl Write, health: &TorrentHealth) -> io::Result<()> {
+    let ip_version = match health.ip_version {
+        IpVersion::V4 => "ipv4",
+        IpVersion::V6 => "ipv6",
+    };
+
+    writeln!(
+        writer,
+        r#"{{"info_hash":"{}","ip_version":"{}","seeders":{},"leechers":{},"recent_completions":{},"health_score":{}}}"#,
+        hex::encode(health.info_hash.0),
+        ip_version,
+        health.seeders,
+        health.leechers,
+        health.recent_completions,
+        health.score
+    )
+}
+
+fn write_plain_entry(writer: &mut impl Write, counts: &TorrentCounts) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{} {} {} {}",
+        hex::encode(counts.info_hash.0),
+        counts.seeders,
+        counts.leechers,
+        counts.completed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_entry() {
+        let counts = TorrentCounts {
+            info_hash: InfoHash([0xab; 20]),
+            ip_version: IpVersion::V6,
+            seeders: 2,
+            leechers: 3,
+            completed: 4,
+            idle_seconds: 60,
+        };
+
+        let mut ndjson = Vec::new();
+        let mut csv = Vec::new();
+
+        write_entry(&mut ndjson, SnapshotFormat::Ndjson, &counts).unwrap();
+        write_entry(&mut csv, SnapshotFormat::Csv, &counts).unwrap();
+
+        let info_hash = "ab".repeat(20);
+
+        assert_eq!(
+            String::from_utf8(ndjson).unwrap(),
+            format!(
+                "{{\"info_hash\":\"{}\",\"ip_version\":\"ipv6\",\"seeders\":2,\"leechers\":3,\"completed\":4,\"idle_seconds\":60}}\n",
+                info_hash
+            )
+        );
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!("{},ipv6,2,3,4,60\n", info_hash)
+        );
+
+        let mut plain = Vec::new();
+
+        write_plain_entry(&mut plain, &counts).unwrap();
+
+        assert_eq!(
+            String::from_utf8(plain).unwrap(),
+            format!("{} 2 3 4\n", info_hash)
+        );
+
+        let health = TorrentHealth {
+            info_hash: InfoHash([0xab; 20]),
+            ip_version: IpVersion::V4,
+            seeders: 1,
+            leechers: 4,
+            recent_completions: 1,
+            score: 0.5,
+        };
+
+        let mut health_ndjson = Vec::new();
+
+        write_health_entry(&mut health_ndjson, &health).unwrap();
+
+        assert_eq!(
+            String::from_utf8(health_ndjson).unwrap(),
+            format!(
+                "{{\"info_hash\":\"{}\",\"ip_version\":\"ipv4\",\"seeders\":1,\"leechers\":4,\"recent_completions\":1,\"health_score\":0.5}}\n",
+                info_hash
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request_target(b"GET /torrents.csv HTTP/1.1\r\n"),
+            Some(("/torrents.csv", ""))
+        );
+        assert_eq!(
+            parse_request_target(b"GET /torrents.csv?min_idle_seconds=60 HTTP/1.1\r\n"),
+            Some(("/torrents.csv", "min_idle_seconds=60"))
+        );
+        assert_eq!(
+            parse_request_target(b"POST /torrents.csv HTTP/1.1\r\n"),
+            None
+        );
+
+        assert_eq!(parse_min_idle_seconds("").unwrap(), 0);
+        assert_eq!(
+            parse_min_idle_seconds("a=b&min_idle_seconds=60").unwrap(),
+            60
+        );
+        assert!(parse_min_idle_seconds("min_idle_seconds=x").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::Instant;
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::config::Config;
+
+const NUM_COUNTERS: usize = 1 << 16;
+
+/// Byte counts are halved every this many milliseconds
+const HALF_LIFE_MS: f32 = 1000.0;
+
+#[derive(Clone, Copy, Default)]
+struct ByteCounts {
+    received: f32,
+    sent: f32,
+    /// Milliseconds since guard was created, wrapping
+    updated_at: u32,
+}
+
+impl ByteCounts {
+    fn decay(&mut self, now: u32) {
+        let elapsed = now.wrapping_sub(self.updated_at);
+
+        if elapsed != 0 {
+            let factor = 0.5f32.powf(elapsed as f32 / HALF_LIFE_MS);
+
+            self.received *= factor;
+            self.sent *= factor;
+            self.updated_at = now;
+        }
+    }
+}
+
+/// Limits amplification of traffic towards sources
+///
+/// Connect responses can be sent to spoofed addresses and error responses
+/// are cheap to trigger. This guard counts all bytes received from and sent
+/// to each source IP (IPv6: prefix of `network.ipv6_prefix_len` bits) and
+/// drops connect and error responses that would make the ratio of bytes
+/// sent to bytes received exceed a configured factor. Counts decay
+/// exponentially with a half-life of one second, so traffic during roughly
+/// the last few seconds is taken into account. Announce and scrape responses
+/// are never dropped, since they are only sent to sources that have proven
+/// that they can receive traffic, but they are counted.
+///
+/// Like in [`super::connect_filter::ConnectFilter`], counts are stored in a
+/// fixed size table indexed by a randomly keyed hash of the source.
+pub struct AmplificationGuard {
+    max_factor: f32,
+    ipv6_prefix_len: u8,
+    hasher_builder: RandomState,
+    counters: Box<[ByteCounts]>,
+    start_time: Instant,
+}
+
+impl AmplificationGuard {
+    /// Returns None if no maximum factor is configured
+    pub fn new(config: &Config) -> Option<Self> {
+        let max_factor = config.protocol.max_amplification_factor;
+
+        if max_factor == 0 {
+            return None;
+        }
+
+        Some(Self {
+            max_factor: max_factor.into(),
+            ipv6_prefix_len: config.network.ipv6_prefix_len,
+            hasher_builder: RandomState::new(),
+            counters: vec![ByteCounts::default(); NUM_COUNTERS].into_boxed_slice(),
+            start_time: Instant::now(),
+        })
+    }
+
+    pub fn add_received(&mut self, src: CanonicalSocketAddr, bytes: usize) {
+        let now = self.now();
+
+        self.add_received_at(src, bytes, now);
+    }
+
+    /// Returns None if response should be dropped
+    pub fn apply(
+        &mut self,
+        addr: CanonicalSocketAddr,
+        response: Response,
+    ) -> Option<(CanonicalSocketAddr, Response)> {
+        let now = self.now();
+
+        if self.allow_at(addr, &response, now) {
+            Some((addr, response))
+        } else {
+            None
+        }
+    }
+
+    fn now(&self) -> u32 {
+        self.start_time.elapsed().as_millis() as u32
+    }
+
+    fn add_received_at(&mut self, src: CanonicalSocketAddr, bytes: usize, now: u32) {
+        self.counts(src, now).received += bytes as f32;
+    }
+
+    fn allow_at(&mut self, addr: CanonicalSocketAddr, response: &Response, now: u32) -> bool {
+        let max_factor = self.max_factor;
+        let counts = self.counts(addr, now);
+        let sent = counts.sent + response.serialized_len() as f32;
+
+        if let Response::Connect(_) | Response::Error(_) = response {
+            if sent > counts.received * max_factor {
+                return false;
+            }
+        }
+
+        counts.sent = sent;
+
+        true
+    }
+
+    fn counts(&mut self, src: CanonicalSocketAddr, now: u32) -> &mut ByteCounts {
+        let mut hasher = self.hasher_builder.build_hasher();
+
+        src.ip_prefix(self.ipv6_prefix_len).hash(&mut hasher);
+
+        let counts = &mut self.counters[hasher.finish() as usize % NUM_COUNTERS];
+
+        counts.decay(now);
+
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::num::NonZeroU16;
+
+    use aquatic_udp_protocol::*;
+
+    use super::*;
+
+    #[test]
+    fn test_amplification_guard() {
+        let mut config = Config::default();
+
+        assert!(AmplificationGuard::new(&config).is_none());
+
+        config.protocol.max_amplification_factor = 2;
+
+        let mut guard = AmplificationGuard::new(&config).unwrap();
+
+        let addr = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+
+        let connect_response = Response::Connect(ConnectResponse {
+            connection_id: ConnectionId::new(0),
+            transaction_id: TransactionId::new(0),
+        });
+        let error_response = Response::Error(ErrorResponse {
+            transaction_id: TransactionId::new(0),
+            message: "Info hash not allowed".into(),
+        });
+
+        let mut announce_response = AnnounceResponse::<Ipv4AddrBytes>::empty();
+
+        announce_response.peers = vec![
+            ResponsePeer {
+                ip_address: Ipv4AddrBytes(Ipv4Addr::LOCALHOST.octets()),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            };
+            100
+        ];
+
+        let announce_response = Response::AnnounceIpv4(announce_response);
+
+        // Nothing received yet
+        assert!(!guard.allow_at(addr, &connect_response, 0));
+
+        // Connect request and response
+        guard.add_received_at(addr, 16, 0);
+
+        assert!(guard.allow_at(addr, &connect_response, 0));
+
+        // Announce request and response with 100 peers (620 bytes), which
+        // is never dropped but is counted
+        guard.add_received_at(addr, 98, 0);
+
+        assert!(guard.allow_at(addr, &announce_response, 0));
+
+        // 636 bytes have been sent and 114 received, so neither connect nor
+        // error responses are allowed, even after further requests
+        guard.add_received_at(addr, 16, 10);
+
+        assert!(!guard.allow_at(addr, &connect_response, 10));
+        assert!(!guard.allow_at(addr, &error_response, 10));
+
+        // Past traffic is still taken into account after a second has passed
+        guard.add_received_at(addr, 16, 1010);
+
+        assert!(!guard.allow_at(addr, &connect_response, 1010));
+
+        // Once counts have decayed, fresh requests outweigh them
+        guard.add_received_at(addr, 16, 10_000);
+
+        assert!(guard.allow_at(addr, &connect_response, 10_000));
+    }
+}
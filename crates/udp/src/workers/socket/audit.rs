@@ -0,0 +1,206 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::{AnnounceRequest, Response};
+
+use crate::config::Config;
+
+/// Number of hash bytes identifying peers
+const PEER_HASH_LEN: usize = 8;
+
+/// Records peers returned in every Nth announce response, see
+/// [`crate::config::DebugConfig::peer_selection_audit_interval`]
+pub struct PeerSelectionAuditor {
+    interval: u64,
+    num_announces: u64,
+    hasher: blake3::Hasher,
+    file: File,
+    buffer: String,
+}
+
+impl PeerSelectionAuditor {
+    pub fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        if config.debug.peer_selection_audit_interval == 0 {
+            return Ok(None);
+        }
+
+        let path = &config.debug.peer_selection_audit_file_path;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open peer selection audit file {:?}", path))?;
+
+        let mut hasher = blake3::Hasher::new();
+
+        hasher.update(config.debug.peer_selection_audit_salt.as_bytes());
+
+        Ok(Some(Self {
+            interval: config.debug.peer_selection_audit_interval,
+            num_announces: 0,
+            hasher,
+            file,
+            buffer: String::new(),
+        }))
+    }
+
+    pub fn record(
+        &mut self,
+        request: &AnnounceRequest,
+        src: CanonicalSocketAddr,
+        response: &Response,
+    ) {
+        self.num_announces += 1;
+
+        if self.num_announces % self.interval != 0 {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let returned: Vec<String> = match response {
+            Response::AnnounceIpv4(response) => response
+                .peers
+                .iter()
+                .map(|peer| self.hash_peer(&peer.ip_address.0, peer.port.0.get()))
+                .collect(),
+            Response::AnnounceIpv6(response) => response
+                .peers
+                .iter()
+                .map(|peer| self.hash_peer(&peer.ip_address.0, peer.port.0.get()))
+                .collect(),
+            _ => return,
+        };
+
+        let peer = match src.get().ip() {
+            IpAddr::V4(ip) => self.hash_peer(&ip.octets(), request.port.0.get()),
+            IpAddr::V6(ip) => self.hash_peer(&ip.octets(), request.port.0.get()),
+        };
+
+        self.buffer.clear();
+
+        let _ = write!(
+            self.buffer,
+            r#"{{"timestamp":{},"info_hash":"{}","peer":"{}","returned":["#,
+            timestamp,
+            hex::encode(request.info_hash.0),
+            peer
+        );
+
+        for (i, hash) in returned.iter().enumerate() {
+            if i != 0 {
+                self.buffer.push(',');
+            }
+
+            let _ = write!(self.buffer, r#""{}""#, hash);
+        }
+
+        self.buffer.push_str("]}\n");
+
+        // Write each line with a single call, since other socket workers
+        // append to the same file
+        if let Err(err) = self.file.write_all(self.buffer.as_bytes()) {
+            ::log::error!("Couldn't write peer selection audit line: {:#}", err);
+        }
+    }
+
+    /// Hex-encoded start of hash of salt, IP address octets and port (big
+    /// endian)
+    fn hash_peer(&self, ip_octets: &[u8], port: u16) -> String {
+        let mut hasher = self.hasher.clone();
+
+        hasher.update(ip_octets);
+        hasher.update(&port.to_be_bytes());
+
+        hex::encode(&hasher.finalize().as_bytes()[..PEER_HASH_LEN])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU16;
+
+    use aquatic_udp_protocol::*;
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = Config::default();
+
+        config.debug.peer_selection_audit_interval = 2;
+        config.debug.peer_selection_audit_file_path = dir.path().join("audit.jsonl");
+
+        let mut auditor = PeerSelectionAuditor::new(&config).unwrap().unwrap();
+
+        let port = Port::new(NonZeroU16::new(1000).unwrap());
+
+        let request = AnnounceRequest {
+            connection_id: ConnectionId::new(0),
+            action_placeholder: Default::default(),
+            transaction_id: TransactionId::new(0),
+            info_hash: InfoHash([0xab; 20]),
+            peer_id: PeerId([0; 20]),
+            bytes_downloaded: NumberOfBytes::new(0),
+            bytes_uploaded: NumberOfBytes::new(0),
+            bytes_left: NumberOfBytes::new(0),
+            event: AnnounceEvent::Started.into(),
+            ip_address: Ipv4AddrBytes([0; 4]),
+            key: PeerKey::new(0),
+            peers_wanted: NumberOfPeers::new(10),
+            port,
+        };
+        let response = Response::AnnounceIpv4(AnnounceResponse {
+            fixed: AnnounceResponseFixedData {
+                transaction_id: TransactionId::new(0),
+                announce_interval: AnnounceInterval::new(0),
+                leechers: NumberOfPeers::new(0),
+                seeders: NumberOfPeers::new(2),
+            },
+            peers: vec![
+                ResponsePeer {
+                    ip_address: Ipv4AddrBytes([10, 0, 0, 2]),
+                    port,
+                },
+                ResponsePeer {
+                    ip_address: Ipv4AddrBytes([10, 0, 0, 3]),
+                    port,
+                },
+            ],
+        });
+        let src = CanonicalSocketAddr::new("10.0.0.1:1000".parse().unwrap());
+
+        for _ in 0..3 {
+            auditor.record(&request, src, &response);
+        }
+
+        let contents =
+            ::std::fs::read_to_string(&config.debug.peer_selection_audit_file_path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 1);
+
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+
+        assert_eq!(line["info_hash"], "ab".repeat(20));
+        assert_eq!(line["peer"], auditor.hash_peer(&[10, 0, 0, 1], 1000));
+        assert_eq!(
+            line["returned"],
+            serde_json::json!([
+                auditor.hash_peer(&[10, 0, 0, 2], 1000),
+                auditor.hash_peer(&[10, 0, 0, 3], 1000),
+            ])
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::Instant;
+
+use aquatic_common::CanonicalSocketAddr;
+
+use crate::config::Config;
+
+const NUM_COUNTERS: usize = 1 << 16;
+
+/// Prefilter for connect requests
+///
+/// Since connection ids are validated without keeping state, the only
+/// remaining cost of spoofed connect requests is sending the responses. This
+/// filter drops connect requests in datagrams below a configured size and
+/// limits the number of connect requests handled per source IP (IPv6: prefix
+/// of `network.ipv6_prefix_len` bits) and second.
+///
+/// Request counts are stored in a fixed size table indexed by a randomly
+/// keyed hash of the source, so memory use doesn't depend on the number of
+/// sources. Unrelated sources will occasionally share a counter.
+pub struct ConnectFilter {
+    min_request_size: usize,
+    max_per_second: u16,
+    ipv6_prefix_len: u8,
+    hasher_builder: RandomState,
+    counters: Box<[u16]>,
+    start_time: Instant,
+    counters_second: u64,
+}
+
+impl ConnectFilter {
+    pub fn new(config: &Config) -> Self {
+        let max_per_second = config.protocol.max_connects_per_ip_per_second;
+
+        let counters = if max_per_second == 0 {
+            Vec::new()
+        } else {
+            vec![0; NUM_COUNTERS]
+        };
+
+        Self {
+            min_request_size: config.protocol.min_connect_request_size,
+            max_per_second,
+            ipv6_prefix_len: config.network.ipv6_prefix_len,
+            hasher_builder: RandomState::new(),
+            counters: counters.into_boxed_slice(),
+            start_time: Instant::now(),
+            counters_second: 0,
+        }
+    }
+
+    /// Returns false if connect request should be ignored
+    pub fn allow(&mut self, src: CanonicalSocketAddr, request_size: usize) -> bool {
+        if request_size < self.min_request_size {
+            return false;
+        }
+        if self.max_per_second == 0 {
+            return true;
+        }
+
+        let second = self.start_time.elapsed().as_secs();
+
+        self.allow_at_second(src, second)
+    }
+
+    fn allow_at_second(&mut self, src: CanonicalSocketAddr, second: u64) -> bool {
+        if second != self.counters_second {
+            self.counters.fill(0);
+            self.counters_second = second;
+        }
+
+        let mut hasher = self.hasher_builder.build_hasher();
+
+        src.ip_prefix(self.ipv6_prefix_len).hash(&mut hasher);
+
+        let counter = &mut self.counters[hasher.finish() as usize % NUM_COUNTERS];
+
+        if *counter >= self.max_per_second {
+            false
+        } else {
+            *counter += 1;
+
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn test_connect_filter() {
+        let mut config = Config::default();
+
+        config.protocol.min_connect_request_size = 20;
+        config.protocol.max_connects_per_ip_per_second = 2;
+
+        let mut filter = ConnectFilter::new(&config);
+
+        let ipv4 = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+        let ipv6_a =
+            CanonicalSocketAddr::new(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 1000)));
+        let ipv6_b =
+            CanonicalSocketAddr::new(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 2], 2000)));
+
+        assert!(!filter.allow(ipv4, 16));
+
+        assert!(filter.allow_at_second(ipv4, 0));
+        assert!(filter.allow_at_second(ipv4, 0));
+        assert!(!filter.allow_at_second(ipv4, 0));
+
+        // Addresses in same /64 prefix share counter
+        assert!(filter.allow_at_second(ipv6_a, 0));
+        assert!(filter.allow_at_second(ipv6_b, 0));
+        assert!(!filter.allow_at_second(ipv6_a, 0));
+
+        // Counters are reset each second
+        assert!(filter.allow_at_second(ipv4, 1));
+        assert!(filter.allow_at_second(ipv6_a, 1));
+    }
+
+    #[test]
+    fn test_connect_filter_disabled() {
+        let mut filter = ConnectFilter::new(&Config::default());
+
+        let addr = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+
+        for _ in 0..1000 {
+            assert!(filter.allow(addr, 16));
+        }
+    }
+}
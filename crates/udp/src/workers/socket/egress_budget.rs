@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::config::Config;
+
+const WINDOW: Duration = Duration::from_millis(100);
+const MAX_DEFERRED_RESPONSES: usize = 4096;
+
+/// Egress bandwidth budget for a single socket worker
+///
+/// Bytes sent are counted in 100 ms windows. If the budget was exceeded
+/// during a window, the number of peers in announce responses is halved,
+/// down to a minimum of one. When less than half of the budget is used, it
+/// is gradually increased again up to `max_response_peers`. Scrape responses
+/// are deferred while the budget for the current window is exhausted.
+pub struct EgressBudget {
+    bytes_per_window: usize,
+    window_start: Instant,
+    bytes_sent: usize,
+    config_max_response_peers: usize,
+    max_response_peers: usize,
+    deferred_responses: VecDeque<(CanonicalSocketAddr, Response)>,
+}
+
+impl EgressBudget {
+    /// Returns None if no budget is configured
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.network.egress_budget_mbits == 0 {
+            return None;
+        }
+
+        let bytes_per_second = config.network.egress_budget_mbits as f64 * 1_000_000.0 / 8.0;
+
+        Some(Self {
+            bytes_per_window: (bytes_per_second * WINDOW.as_secs_f64()) as usize,
+            window_start: Instant::now(),
+            bytes_sent: 0,
+            config_max_response_peers: config.protocol.max_response_peers,
+            max_response_peers: config.protocol.max_response_peers,
+            deferred_responses: Default::default(),
+        })
+    }
+
+    /// Start new window if current one has passed
+    pub fn update(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) < WINDOW {
+            return;
+        }
+
+        if self.bytes_sent > self.bytes_per_window {
+            self.max_response_peers = (self.max_response_peers / 2).max(1);
+
+            ::log::debug!(
+                "egress budget exceeded, reducing max response peers to {}",
+                self.max_response_peers
+            );
+        } else if self.bytes_sent < self.bytes_per_window / 2 {
+            let step = (self.config_max_response_peers / 10).max(1);
+
+            self.max_response_peers =
+                (self.max_response_peers + step).min(self.config_max_response_peers);
+        }
+
+        self.window_start = now;
+        self.bytes_sent = 0;
+    }
+
+    pub fn add_bytes_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes;
+    }
+
+    fn exhausted(&self) -> bool {
+        self.bytes_sent >= self.bytes_per_window
+    }
+
+    /// Limit number of peers in announce responses and defer scrape
+    /// responses if budget is exhausted
+    ///
+    /// Returns None if response was deferred or dropped.
+    pub fn apply(
+        &mut self,
+        addr: CanonicalSocketAddr,
+        mut response: Response,
+    ) -> Option<(CanonicalSocketAddr, Response)> {
+        match &mut response {
+            Response::AnnounceIpv4(r) => r.peers.truncate(self.max_response_peers),
+            Response::AnnounceIpv6(r) => r.peers.truncate(self.max_response_peers),
+            Response::Scrape(_) if self.exhausted() => {
+                if self.deferred_responses.len() < MAX_DEFERRED_RESPONSES {
+                    self.deferred_responses.push_back((addr, response));
+                } else {
+                    ::log::debug!("deferred response queue full, dropping scrape response");
+                }
+
+                return None;
+            }
+            _ => (),
+        }
+
+        Some((addr, response))
+    }
+
+    /// Take deferred response if budget allows it
+    pub fn pop_deferred(&mut self) -> Option<(CanonicalSocketAddr, Response)> {
+        if self.exhausted() {
+            None
+        } else {
+            self.deferred_responses.pop_front()
+        }
+    }
+
+    /// Take all deferred responses regardless of budget, e.g., on shutdown
+    pub fn take_deferred(&mut self) -> VecDeque<(CanonicalSocketAddr, Response)> {
+        ::std::mem::take(&mut self.deferred_responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::num::NonZeroU16;
+
+    use aquatic_udp_protocol::*;
+
+    use super::*;
+
+    #[test]
+    fn test_egress_budget() {
+        let mut config = Config::default();
+
+        config.network.egress_budget_mbits = 8;
+        config.protocol.max_response_peers = 100;
+
+        let mut budget = EgressBudget::new(&config).unwrap();
+
+        // 8 Mbit/s = 100 KB per 100 ms window
+        assert_eq!(budget.bytes_per_window, 100_000);
+
+        let addr = CanonicalSocketAddr::new(SocketAddr::from(([10, 0, 0, 1], 1000)));
+
+        let mut announce_response = AnnounceResponse::<Ipv4AddrBytes>::empty();
+
+        announce_response.peers = vec![
+            ResponsePeer {
+                ip_address: Ipv4AddrBytes(Ipv4Addr::LOCALHOST.octets()),
+                port: Port::new(NonZeroU16::new(1000).unwrap()),
+            };
+            100
+        ];
+
+        let scrape_response = Response::Scrape(ScrapeResponse {
+            transaction_id: TransactionId::new(0),
+            torrent_stats: Vec::new(),
+        });
+
+        budget.add_bytes_sent(200_000);
+        budget.update(budget.window_start + WINDOW);
+
+        assert_eq!(budget.max_response_peers, 50);
+
+        match budget.apply(addr, Response::AnnounceIpv4(announce_response)) {
+            Some((_, Response::AnnounceIpv4(r))) => assert_eq!(r.peers.len(), 50),
+            _ => panic!("announce response not returned"),
+        }
+
+        budget.add_bytes_sent(100_000);
+
+        assert!(budget.apply(addr, scrape_response).is_none());
+        assert!(budget.pop_deferred().is_none());
+
+        budget.update(budget.window_start + WINDOW);
+
+        assert_eq!(budget.max_response_peers, 50);
+        assert!(budget.pop_deferred().is_some());
+
+        budget.update(budget.window_start + WINDOW);
+
+        assert_eq!(budget.max_response_peers, 60);
+    }
+
+    #[test]
+    fn test_egress_budget_keeps_one_peer() {
+        let mut config = Config::default();
+
+        config.network.egress_budget_mbits = 8;
+        config.protocol.max_response_peers = 100;
+
+        let mut budget = EgressBudget::new(&config).unwrap();
+
+        for _ in 0..10 {
+            budget.add_bytes_sent(200_000);
+            budget.update(budget.window_start + WINDOW);
+        }
+
+        assert_eq!(budget.max_response_peers, 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Socket error queue reading for IP_RECVERR and IPV6_RECVERR
+//!
+//! With these options set, the kernel reports ICMP errors caused by sent
+//! datagrams, such as port unreachable or fragmentation needed, on
+//! unconnected sockets too. Each error is queued until it is read with
+//! MSG_ERRQUEUE, counting against the receive buffer in the meantime, so
+//! the queue needs to be drained regularly.
+
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use socket2::SockAddr;
+
+/// Control message buffer. u64 array for cmsghdr alignment.
+type ControlBuffer = [u64; 16];
+
+/// Error reported by the kernel for a previously sent datagram
+#[derive(Debug)]
+pub struct QueuedError {
+    /// Destination address of the datagram that caused the error
+    pub addr: Option<SocketAddr>,
+    pub error: io::Error,
+    /// One of the SO_EE_ORIGIN_* constants
+    pub origin: u8,
+    /// ICMP type, if the error originated from an ICMP message
+    pub icmp_type: u8,
+    /// ICMP code, if the error originated from an ICMP message
+    pub icmp_code: u8,
+}
+
+/// Read one error from the error queue of `socket` without blocking
+///
+/// Returns an error of kind `WouldBlock` when the queue is empty.
+pub fn recv_error(socket: &impl AsRawFd) -> io::Result<QueuedError> {
+    let mut addr: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut control: ControlBuffer = [0; 16];
+    // Only the error itself is of interest, so the returned payload of the
+    // original datagram is truncated
+    let mut payload = [0u8; 1];
+
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut header: libc::msghdr = unsafe { zeroed() };
+
+    header.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = size_of::<ControlBuffer>() as _;
+
+    let result = unsafe {
+        libc::recvmsg(
+            socket.as_raw_fd(),
+            &mut header,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let addr = if header.msg_namelen > 0 {
+        unsafe { SockAddr::new(addr, header.msg_namelen) }.as_socket()
+    } else {
+        None
+    };
+
+    let extended_err = unsafe { extended_error(&header) }.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "error queue message without extended error",
+        )
+    })?;
+
+    Ok(QueuedError {
+        addr,
+        error: io::Error::from_raw_os_error(extended_err.ee_errno as i32),
+        origin: extended_err.ee_origin,
+        icmp_type: extended_err.ee_type,
+        icmp_code: extended_err.ee_code,
+    })
+}
+
+/// Extended error from IP_RECVERR or IPV6_RECVERR control message
+unsafe fn extended_error(header: &libc::msghdr) -> Option<libc::sock_extended_err> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(header);
+
+    while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) => {
+                return Some(::std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err
+                ));
+            }
+            _ => (),
+        }
+
+        cmsg = libc::CMSG_NXTHDR(header, cmsg);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_recv_port_unreachable() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let enable: libc::c_int = 1;
+
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_RECVERR,
+                &enable as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        assert_eq!(result, 0);
+
+        assert_eq!(
+            recv_error(&socket).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Bind and drop socket to get a port that is most likely closed
+        let closed_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        socket.send_to(b"hello", closed_addr).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        let queued_error = loop {
+            match recv_error(&socket) {
+                Ok(queued_error) => break queued_error,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "no error was queued");
+
+                    ::std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("{:#}", err),
+            }
+        };
+
+        assert_eq!(queued_error.addr, Some(closed_addr));
+        assert_eq!(queued_error.error.raw_os_error(), Some(libc::ECONNREFUSED));
+        assert_eq!(queued_error.origin, libc::SO_EE_ORIGIN_ICMP);
+    }
+}
@@ -0,0 +1,84 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::{Config, FaultInjectionConfig};
+
+/// What to do with a response about to be passed on or sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendFault {
+    None,
+    /// Silently drop response
+    Drop,
+    /// Act as if socket send buffer was full
+    WouldBlock,
+}
+
+/// Injects artificial delays and failures into response sending, for testing
+pub struct FaultInjector {
+    config: FaultInjectionConfig,
+    rng: SmallRng,
+}
+
+impl FaultInjector {
+    /// Returns `None` if fault injection isn't configured
+    pub fn new(config: &Config) -> Option<Self> {
+        config.fault_injection.active().then(|| Self {
+            config: config.fault_injection.clone(),
+            rng: SmallRng::from_entropy(),
+        })
+    }
+
+    /// Sleep if configured and decide on fault to inject
+    ///
+    /// `WouldBlock` is only returned if `direct_send` is true, since it is
+    /// meaningless when passing responses on to a sender thread.
+    pub fn before_send(&mut self, direct_send: bool) -> SendFault {
+        if self.config.response_delay_ms != 0 {
+            sleep(Duration::from_millis(self.config.response_delay_ms));
+        }
+
+        if self
+            .rng
+            .gen_bool(self.config.response_drop_probability.clamp(0.0, 1.0))
+        {
+            SendFault::Drop
+        } else if direct_send
+            && self
+                .rng
+                .gen_bool(self.config.send_would_block_probability.clamp(0.0, 1.0))
+        {
+            SendFault::WouldBlock
+        } else {
+            SendFault::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault_injector() {
+        let mut config = Config::default();
+
+        assert!(FaultInjector::new(&config).is_none());
+
+        config.fault_injection.send_would_block_probability = 1.0;
+
+        let mut injector = FaultInjector::new(&config).unwrap();
+
+        assert_eq!(injector.before_send(true), SendFault::WouldBlock);
+        assert_eq!(injector.before_send(false), SendFault::None);
+
+        config.fault_injection.response_drop_probability = 1.0;
+
+        let mut injector = FaultInjector::new(&config).unwrap();
+
+        assert_eq!(injector.before_send(true), SendFault::Drop);
+        assert_eq!(injector.before_send(false), SendFault::Drop);
+    }
+}
@@ -0,0 +1,89 @@
+//! UDP generic segmentation offload (GSO) helpers for the mio backend
+//!
+//! With GSO, a buffer containing multiple equally sized datagrams (the last
+//! one may be shorter) can be handed to the kernel in a single `sendmsg`
+//! call. All datagrams must share the same destination address.
+
+use std::io;
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use socket2::SockAddr;
+
+/// Maximum number of segments the kernel accepts in a single GSO send
+pub const MAX_SEGMENTS: usize = 64;
+
+/// Maximum total payload size of a single GSO send
+///
+/// Maximum IPv4 packet size minus IPv4 and UDP headers
+pub const MAX_PAYLOAD_SIZE: usize = u16::MAX as usize - 20 - 8;
+
+/// Check if socket supports the UDP_SEGMENT option
+pub fn supported(socket: &impl AsRawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    result == 0
+}
+
+/// Send `payload` to `addr` as datagrams of `segment_size` bytes each
+///
+/// Returns number of bytes sent on success.
+pub fn send_segments(
+    socket: &impl AsRawFd,
+    addr: SocketAddr,
+    payload: &[u8],
+    segment_size: u16,
+) -> io::Result<usize> {
+    let addr = SockAddr::from(addr);
+
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    // u64 array for cmsghdr alignment
+    let mut control = [0u64; 8];
+
+    let control_len = unsafe { libc::CMSG_SPACE(size_of::<u16>() as u32) } as usize;
+
+    assert!(control_len <= size_of::<[u64; 8]>());
+
+    let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+
+    header.msg_name = addr.as_ptr() as *mut libc::c_void;
+    header.msg_namelen = addr.len();
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = control_len as _;
+
+    let result = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&header);
+
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+
+        ::std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+
+        libc::sendmsg(socket.as_raw_fd(), &header, 0)
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
@@ -1,9 +1,12 @@
-use std::io::{Cursor, ErrorKind};
-use std::sync::atomic::Ordering;
-use std::time::Duration;
+use std::io::{self, Cursor, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
+use aquatic_common::ip_blocklist::IpBlocklistCache;
 use crossbeam_channel::Sender;
 use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
@@ -13,14 +16,28 @@ use aquatic_common::{
     ValidUntil,
 };
 use aquatic_udp_protocol::*;
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
 
 use crate::common::*;
 use crate::config::Config;
+use crate::swarm::{AnnounceResponseCache, TorrentCleaner};
 
+use super::amplification_guard::AmplificationGuard;
+use super::audit::PeerSelectionAuditor;
+use super::connect_filter::ConnectFilter;
+use super::egress_budget::EgressBudget;
+#[cfg(feature = "fault-injection")]
+use super::fault_injection::{FaultInjector, SendFault};
+use super::handle_country_policy;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use super::mmsg::{RecvBatch, SendBatch};
+use super::sender::ResponseSender;
 use super::validator::ConnectionValidator;
-use super::{create_socket, EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
+use super::{
+    apply_amplification_guard, count_sent_response, create_opt_ip_blocklist_cache, create_socket,
+    destination_addr, handle_port_zero, is_blocked, record_invalid_length, record_unique_source,
+    sample_for_reachability_probe, send_buffer_full, strip_connect_padding, BusyPoll,
+    EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6,
+};
 
 pub struct SocketWorker {
     config: Config,
@@ -28,11 +45,40 @@ pub struct SocketWorker {
     statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     statistics_sender: Sender<StatisticsMessage>,
     access_list_cache: AccessListCache,
+    opt_ip_blocklist_cache: Option<IpBlocklistCache>,
     validator: ConnectionValidator,
+    connect_filter: ConnectFilter,
+    amplification_guard: Option<AmplificationGuard>,
+    egress_budget: Option<EgressBudget>,
+    announce_response_cache: AnnounceResponseCache,
     socket: UdpSocket,
+    opt_response_sender: Option<ResponseSender>,
     buffer: [u8; BUFFER_SIZE],
-    rng: SmallRng,
+    rng: SwarmRng,
+    opt_peer_selection_auditor: Option<PeerSelectionAuditor>,
     peer_valid_until: ValidUntil,
+    #[cfg(target_os = "linux")]
+    gso: Option<GsoState>,
+    /// Local address of request being handled, if
+    /// `network.pin_source_address` is set
+    #[cfg(target_os = "linux")]
+    opt_local_ip: Option<IpAddr>,
+    /// Set if datagrams are received in batches
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    recv_batch: Option<RecvBatch>,
+    /// Set if responses are sent in batches
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    send_batch: Option<SendBatch>,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<FaultInjector>,
+}
+
+/// Buffers used when flushing the resend buffer with GSO
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct GsoState {
+    payload: Vec<u8>,
+    responses: Vec<Response>,
 }
 
 impl SocketWorker {
@@ -43,31 +89,152 @@ impl SocketWorker {
         statistics_sender: Sender<StatisticsMessage>,
         validator: ConnectionValidator,
         priv_dropper: PrivilegeDropper,
+        worker_index: usize,
+    ) -> anyhow::Result<()> {
+        let mut worker = Self::new(
+            config,
+            shared_state,
+            statistics,
+            statistics_sender,
+            validator,
+            priv_dropper,
+            worker_index,
+        )?;
+
+        worker.run_inner(None)
+    }
+
+    pub fn run_single_threaded(
+        config: Config,
+        shared_state: State,
+        statistics: Statistics,
+        statistics_sender: Sender<StatisticsMessage>,
+        validator: ConnectionValidator,
+        priv_dropper: PrivilegeDropper,
     ) -> anyhow::Result<()> {
-        let socket = UdpSocket::from_std(create_socket(&config, priv_dropper)?);
+        let cleaner =
+            TorrentCleaner::new(&config, statistics.swarm.clone(), statistics_sender.clone());
+
+        let mut worker = Self::new(
+            config,
+            shared_state,
+            statistics.socket[0].clone(),
+            statistics_sender,
+            validator,
+            priv_dropper,
+            0,
+        )?;
+
+        worker.rng = SwarmRng::seed_from_u64(&worker.config, worker.config.debug.rng_seed);
+
+        worker.run_inner(Some(cleaner))
+    }
+
+    pub fn new(
+        config: Config,
+        shared_state: State,
+        statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+        statistics_sender: Sender<StatisticsMessage>,
+        validator: ConnectionValidator,
+        priv_dropper: PrivilegeDropper,
+        worker_index: usize,
+    ) -> anyhow::Result<Self> {
+        let socket = create_socket(&config, priv_dropper)?;
+
+        let opt_response_sender = if config.network.use_sender_threads {
+            let socket = socket
+                .try_clone()
+                .context("clone socket for sender thread")?;
+
+            Some(ResponseSender::spawn(
+                &config,
+                statistics.clone(),
+                socket,
+                worker_index,
+            )?)
+        } else {
+            None
+        };
+
+        let socket = UdpSocket::from_std(socket);
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let opt_ip_blocklist_cache = create_opt_ip_blocklist_cache(&config, &shared_state);
         let peer_valid_until = ValidUntil::new(
             shared_state.server_start_instant,
             config.cleaning.max_peer_age,
         );
 
-        let mut worker = Self {
+        #[cfg(target_os = "linux")]
+        let gso = if config.network.use_gso {
+            if super::gso::supported(&socket) {
+                Some(GsoState::default())
+            } else {
+                ::log::warn!("UDP GSO not supported by kernel, sending responses one by one");
+
+                None
+            }
+        } else {
+            None
+        };
+
+        // Batches don't carry local addresses
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        let (recv_batch, send_batch) =
+            if config.network.mmsg_batch_size > 1 && !config.network.pin_source_address {
+                let batch_size = config.network.mmsg_batch_size;
+
+                // Sender threads send responses themselves
+                let send_batch = opt_response_sender
+                    .is_none()
+                    .then(|| SendBatch::new(batch_size));
+
+                (Some(RecvBatch::new(batch_size)), send_batch)
+            } else {
+                (None, None)
+            };
+
+        let connect_filter = ConnectFilter::new(&config);
+        let amplification_guard = AmplificationGuard::new(&config);
+        let egress_budget = EgressBudget::new(&config);
+        let announce_response_cache = AnnounceResponseCache::new(&config);
+        let rng = SwarmRng::from_entropy(&config);
+        let opt_peer_selection_auditor = PeerSelectionAuditor::new(&config)?;
+        #[cfg(feature = "fault-injection")]
+        let fault_injector = FaultInjector::new(&config);
+
+        Ok(Self {
             config,
             shared_state,
             statistics,
             statistics_sender,
             validator,
+            connect_filter,
+            amplification_guard,
+            egress_budget,
+            announce_response_cache,
             access_list_cache,
+            opt_ip_blocklist_cache,
             socket,
+            opt_response_sender,
             buffer: [0; BUFFER_SIZE],
-            rng: SmallRng::from_entropy(),
+            rng,
+            opt_peer_selection_auditor,
             peer_valid_until,
-        };
-
-        worker.run_inner()
+            #[cfg(target_os = "linux")]
+            gso,
+            #[cfg(target_os = "linux")]
+            opt_local_ip: None,
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            recv_batch,
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            send_batch,
+            #[cfg(feature = "fault-injection")]
+            fault_injector,
+        })
     }
 
-    pub fn run_inner(&mut self) -> anyhow::Result<()> {
+    /// Run event loop, cleaning torrents in between if `opt_cleaner` is set
+    pub fn run_inner(&mut self, mut opt_cleaner: Option<TorrentCleaner>) -> anyhow::Result<()> {
         let mut opt_resend_buffer =
             (self.config.network.resend_buffer_max_len > 0).then_some(Vec::new());
         let mut events = Events::with_capacity(1);
@@ -78,119 +245,438 @@ impl SocketWorker {
             .context("register poll")?;
 
         let poll_timeout = Duration::from_millis(self.config.network.poll_timeout_ms);
+        let mut busy_poll = BusyPoll::new(&self.config);
+
+        let cleaning_interval = Duration::from_secs(self.config.cleaning.torrent_cleaning_interval);
+        let mut next_cleaning = Instant::now() + cleaning_interval;
 
         let mut iter_counter = 0u64;
 
-        loop {
-            poll.poll(&mut events, Some(poll_timeout)).context("poll")?;
+        while !self.shared_state.shutdown.is_triggered() {
+            poll.poll(&mut events, Some(busy_poll.timeout(poll_timeout)))
+                .context("poll")?;
+
+            busy_poll.register_poll_result(!events.is_empty());
 
             for event in events.iter() {
+                #[cfg(target_os = "linux")]
+                if event.is_error() && self.config.network.socket_recv_errors {
+                    self.drain_error_queue();
+                }
+
                 if event.is_readable() {
                     self.read_and_handle_requests(&mut opt_resend_buffer);
                 }
             }
 
-            // If resend buffer is enabled, send any responses in it
-            if let Some(resend_buffer) = opt_resend_buffer.as_mut() {
-                for (addr, response) in resend_buffer.drain(..) {
-                    self.send_response(&mut None, addr, response);
+            self.update_egress_budget(&mut opt_resend_buffer);
+            self.flush_resend_buffer(&mut opt_resend_buffer);
+
+            if let Some(cleaner) = opt_cleaner.as_mut() {
+                let now = Instant::now();
+
+                if now >= next_cleaning {
+                    cleaner.clean(&self.config, &self.shared_state);
+
+                    next_cleaning = now + cleaning_interval;
                 }
             }
 
             if iter_counter % 256 == 0 {
-                self.validator.update_elapsed();
-
-                self.peer_valid_until = ValidUntil::new(
-                    self.shared_state.server_start_instant,
-                    self.config.cleaning.max_peer_age,
-                );
+                self.update_timestamps();
+                self.check_response_sender()?;
             }
 
             iter_counter = iter_counter.wrapping_add(1);
         }
+
+        // Send responses deferred by the egress budget regardless of it
+        let deferred_responses = self
+            .egress_budget
+            .as_mut()
+            .map(EgressBudget::take_deferred)
+            .unwrap_or_default();
+
+        for (addr, response) in deferred_responses {
+            self.send_response(&mut opt_resend_buffer, addr, response);
+        }
+
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        self.flush_send_batch(&mut opt_resend_buffer);
+
+        // Make a final attempt at sending responses that couldn't be sent
+        // earlier because the socket send buffer was full
+        self.flush_resend_buffer(&mut opt_resend_buffer);
+
+        if let Some(response_sender) = self.opt_response_sender.take() {
+            response_sender.close();
+        }
+
+        Ok(())
+    }
+
+    /// Return error if sender thread has stopped
+    pub fn check_response_sender(&self) -> anyhow::Result<()> {
+        match self.opt_response_sender.as_ref() {
+            Some(response_sender) if response_sender.is_finished() => {
+                Err(anyhow::anyhow!("sender thread stopped"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "af-xdp")]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    #[cfg(feature = "af-xdp")]
+    pub fn shared_state(&self) -> &State {
+        &self.shared_state
+    }
+
+    #[cfg(feature = "af-xdp")]
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// If resend buffer is enabled, send any responses in it
+    pub fn flush_resend_buffer(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+    ) {
+        if let Some(resend_buffer) = opt_resend_buffer.as_mut() {
+            #[cfg(target_os = "linux")]
+            if let Some(gso) = self.gso.take() {
+                self.gso = self.send_responses_with_gso(gso, resend_buffer);
+            }
+
+            for (addr, response) in resend_buffer.drain(..) {
+                self.send_response(&mut None, addr, response);
+            }
+
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            self.flush_send_batch(&mut None);
+        }
     }
 
-    fn read_and_handle_requests(
+    /// Start new egress budget window if necessary and send any deferred
+    /// responses that fit in budget
+    pub fn update_egress_budget(
         &mut self,
         opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
     ) {
-        let max_scrape_torrents = self.config.protocol.max_scrape_torrents;
+        if let Some(egress_budget) = self.egress_budget.as_mut() {
+            if let Some(response_sender) = self.opt_response_sender.as_ref() {
+                egress_budget.add_bytes_sent(response_sender.take_bytes_sent());
+            }
+
+            egress_budget.update(Instant::now());
+        } else {
+            return;
+        }
+
+        while let Some((addr, response)) = self
+            .egress_budget
+            .as_mut()
+            .and_then(EgressBudget::pop_deferred)
+        {
+            self.send_response(opt_resend_buffer, addr, response);
+        }
+
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        self.flush_send_batch(opt_resend_buffer);
+    }
+
+    pub fn update_timestamps(&mut self) {
+        self.validator.update_elapsed();
+
+        self.peer_valid_until = ValidUntil::new(
+            self.shared_state.server_start_instant,
+            self.config.cleaning.max_peer_age,
+        );
+    }
+
+    pub fn read_and_handle_requests(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+    ) {
+        #[cfg(target_os = "linux")]
+        if self.config.network.pin_source_address {
+            self.read_and_handle_requests_with_local_ip(opt_resend_buffer);
+
+            return;
+        }
+
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        if let Some(recv_batch) = self.recv_batch.take() {
+            self.recv_batch =
+                Some(self.read_and_handle_request_batches(opt_resend_buffer, recv_batch));
+
+            return;
+        }
 
         loop {
             match self.socket.recv_from(&mut self.buffer[..]) {
                 Ok((bytes_read, src)) => {
-                    let src_port = src.port();
-                    let src = CanonicalSocketAddr::new(src);
+                    self.handle_datagram(opt_resend_buffer, bytes_read, src);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    self.handle_recv_error("recv_from", err);
+                }
+            }
+        }
+    }
 
-                    // Use canonical address for statistics
-                    let opt_statistics = if self.config.statistics.active() {
-                        if src.is_ipv4() {
-                            let statistics = &self.statistics.ipv4;
+    /// Log receive error
+    ///
+    /// When errors caused by sent datagrams are received, they are expected
+    /// to be reported by receive calls and are only logged at debug level.
+    /// The error queue is then drained, since it counts against the receive
+    /// buffer.
+    fn handle_recv_error(&self, syscall: &str, err: io::Error) {
+        #[cfg(target_os = "linux")]
+        if self.config.network.socket_recv_errors {
+            ::log::debug!("{} error: {:#}", syscall, err);
 
-                            statistics
-                                .bytes_received
-                                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+            self.drain_error_queue();
 
-                            Some(statistics)
-                        } else {
-                            let statistics = &self.statistics.ipv6;
+            return;
+        }
 
-                            statistics
-                                .bytes_received
-                                .fetch_add(bytes_read + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+        ::log::warn!("{} error: {:#}", syscall, err);
+    }
 
-                            Some(statistics)
-                        }
-                    } else {
-                        None
-                    };
+    /// Log errors caused by sent datagrams until socket error queue is empty
+    #[cfg(target_os = "linux")]
+    fn drain_error_queue(&self) {
+        loop {
+            match super::errqueue::recv_error(&self.socket) {
+                Ok(queued_error) => {
+                    ::log::debug!(
+                        "error caused by datagram sent to {:?}: {:#} (origin {}, type {}, code {})",
+                        queued_error.addr,
+                        queued_error.error,
+                        queued_error.origin,
+                        queued_error.icmp_type,
+                        queued_error.icmp_code,
+                    );
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    ::log::warn!("recvmsg error queue error: {:#}", err);
 
-                    if src_port == 0 {
-                        ::log::debug!("Ignored request because source port is zero");
+                    break;
+                }
+            }
+        }
+    }
 
-                        continue;
-                    }
+    /// Receive datagrams with recvmsg until none are left, so that responses
+    /// can be sent from the local address that each request was sent to
+    #[cfg(target_os = "linux")]
+    fn read_and_handle_requests_with_local_ip(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+    ) {
+        loop {
+            match super::pktinfo::recv_from(&self.socket, &mut self.buffer[..]) {
+                Ok((bytes_read, src, opt_local_ip)) => {
+                    self.opt_local_ip = opt_local_ip;
 
-                    match Request::parse_bytes(&self.buffer[..bytes_read], max_scrape_torrents) {
-                        Ok(request) => {
-                            if let Some(statistics) = opt_statistics {
-                                statistics.requests.fetch_add(1, Ordering::Relaxed);
-                            }
+                    self.handle_datagram(opt_resend_buffer, bytes_read, src);
 
-                            if let Some(response) = self.handle_request(request, src) {
-                                self.send_response(opt_resend_buffer, src, response);
-                            }
-                        }
-                        Err(RequestParseError::Sendable {
-                            connection_id,
-                            transaction_id,
-                            err,
-                        }) if self.validator.connection_id_valid(src, connection_id) => {
-                            let response = ErrorResponse {
-                                transaction_id,
-                                message: err.into(),
-                            };
-
-                            self.send_response(opt_resend_buffer, src, Response::Error(response));
-
-                            ::log::debug!("request parse error (sent error response): {:?}", err);
-                        }
-                        Err(err) => {
-                            ::log::debug!(
-                                "request parse error (didn't send error response): {:?}",
-                                err
-                            );
+                    self.opt_local_ip = None;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    self.handle_recv_error("recvmsg", err);
+                }
+            }
+        }
+    }
+
+    /// Receive datagrams in batches with recvmmsg until none are left,
+    /// sending responses to each batch together
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn read_and_handle_request_batches(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+        mut recv_batch: RecvBatch,
+    ) -> RecvBatch {
+        loop {
+            match recv_batch.recv(&self.socket) {
+                Ok(num_received) => {
+                    for i in 0..num_received {
+                        if let Some((payload, src)) = recv_batch.get(i) {
+                            let bytes_read = payload.len();
+
+                            self.buffer[..bytes_read].copy_from_slice(payload);
+                            self.handle_datagram(opt_resend_buffer, bytes_read, src);
                         }
-                    };
+                    }
+
+                    self.flush_send_batch(opt_resend_buffer);
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     break;
                 }
                 Err(err) => {
-                    ::log::warn!("recv_from error: {:#}", err);
+                    self.handle_recv_error("recvmmsg", err);
                 }
             }
         }
+
+        recv_batch
+    }
+
+    /// Handle datagram of `bytes_read` bytes located at start of buffer
+    pub fn handle_datagram(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+        bytes_read: usize,
+        src: SocketAddr,
+    ) {
+        let max_scrape_torrents = self.config.protocol.scrape_torrents_limit();
+
+        let src_port = src.port();
+        let src = CanonicalSocketAddr::new(src);
+
+        // Use canonical address for statistics
+        let opt_statistics = if self.config.statistics.active() {
+            if src.is_ipv4() {
+                let statistics = &self.statistics.ipv4;
+
+                statistics
+                    .bytes_received
+                    .increment(bytes_read + EXTRA_PACKET_SIZE_IPV4);
+
+                Some(statistics)
+            } else {
+                let statistics = &self.statistics.ipv6;
+
+                statistics
+                    .bytes_received
+                    .increment(bytes_read + EXTRA_PACKET_SIZE_IPV6);
+
+                Some(statistics)
+            }
+        } else {
+            None
+        };
+
+        if src_port == 0 {
+            ::log::debug!("Ignored request because source port is zero");
+
+            return;
+        }
+
+        if is_blocked(
+            &self.config,
+            &self.statistics,
+            &mut self.opt_ip_blocklist_cache,
+            src,
+        ) {
+            return;
+        }
+
+        let payload = strip_connect_padding(
+            self.config.protocol.min_connect_request_size,
+            &self.buffer[..bytes_read],
+        );
+
+        // Ignore malformed short packets before any further processing, so
+        // that they can't be used to trigger responses to spoofed addresses
+        if !Request::length_valid(payload) {
+            record_invalid_length(&self.config, &self.statistics, src);
+
+            return;
+        }
+
+        if let Some(amplification_guard) = self.amplification_guard.as_mut() {
+            amplification_guard.add_received(src, bytes_read);
+        }
+
+        match Request::parse_bytes(payload, max_scrape_torrents) {
+            Ok(request) => {
+                if let Some(statistics) = opt_statistics {
+                    statistics.requests.increment(1);
+
+                    record_unique_source(&self.config, &self.shared_state, statistics, src);
+                }
+
+                if let Request::Connect(_) = request {
+                    if !self.connect_filter.allow(src, bytes_read) {
+                        if let Some(statistics) = opt_statistics {
+                            statistics.connect_requests_throttled.increment(1);
+                        }
+
+                        return;
+                    }
+                }
+
+                let opt_response = self
+                    .handle_request(request, src)
+                    .and_then(|response| {
+                        apply_amplification_guard(
+                            &self.config,
+                            &self.statistics,
+                            &mut self.amplification_guard,
+                            src,
+                            response,
+                        )
+                    })
+                    .and_then(|(addr, response)| match self.egress_budget.as_mut() {
+                        Some(egress_budget) => egress_budget.apply(addr, response),
+                        None => Some((addr, response)),
+                    });
+
+                if let Some((addr, response)) = opt_response {
+                    self.send_response(opt_resend_buffer, addr, response);
+                }
+            }
+            Err(RequestParseError::Sendable {
+                connection_id,
+                transaction_id,
+                err,
+            }) if self.validator.connection_id_valid(src, connection_id) => {
+                let response = ErrorResponse {
+                    transaction_id,
+                    message: err.into(),
+                };
+
+                if let Some((addr, response)) = apply_amplification_guard(
+                    &self.config,
+                    &self.statistics,
+                    &mut self.amplification_guard,
+                    src,
+                    Response::Error(response),
+                ) {
+                    self.send_response(opt_resend_buffer, addr, response);
+                }
+
+                ::log::debug!("request parse error (sent error response): {:?}", err);
+            }
+            Err(err) => {
+                ::log::debug!(
+                    "request parse error (didn't send error response): {:?}",
+                    err
+                );
+            }
+        };
+    }
+
+    /// Buffer for handing over datagrams received by other means
+    #[cfg(feature = "af-xdp")]
+    pub fn buffer_mut(&mut self) -> &mut [u8; BUFFER_SIZE] {
+        &mut self.buffer
     }
 
     fn handle_request(&mut self, request: Request, src: CanonicalSocketAddr) -> Option<Response> {
@@ -208,6 +694,18 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
+                    if let Some(response) =
+                        handle_port_zero(&self.config, &self.statistics, &request, src)
+                    {
+                        return Some(response);
+                    }
+
+                    if let Some(response) =
+                        handle_country_policy(&self.config, &self.shared_state, &request, src)
+                    {
+                        return Some(response);
+                    }
+
                     if self
                         .access_list_cache
                         .load()
@@ -215,13 +713,21 @@ impl SocketWorker {
                     {
                         let response = self.shared_state.torrent_maps.announce(
                             &self.config,
+                            &self.statistics,
                             &self.statistics_sender,
                             &mut self.rng,
                             &request,
                             src,
                             self.peer_valid_until,
+                            &mut self.announce_response_cache,
                         );
 
+                        sample_for_reachability_probe(&self.shared_state, &request, src);
+
+                        if let Some(auditor) = self.opt_peer_selection_auditor.as_mut() {
+                            auditor.record(&request, src, &response);
+                        }
+
                         return Some(response);
                     } else {
                         return Some(Response::Error(ErrorResponse {
@@ -236,9 +742,11 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
-                    return Some(Response::Scrape(
-                        self.shared_state.torrent_maps.scrape(request, src),
-                    ));
+                    return Some(Response::Scrape(self.shared_state.torrent_maps.scrape(
+                        &self.config,
+                        request,
+                        src,
+                    )));
                 }
             }
         }
@@ -252,6 +760,51 @@ impl SocketWorker {
         canonical_addr: CanonicalSocketAddr,
         response: Response,
     ) {
+        #[cfg(feature = "fault-injection")]
+        let fault = self
+            .fault_injector
+            .as_mut()
+            .map(|injector| injector.before_send(self.opt_response_sender.is_none()))
+            .unwrap_or(SendFault::None);
+
+        #[cfg(feature = "fault-injection")]
+        if fault == SendFault::Drop {
+            ::log::debug!(
+                "Dropping response to {:?} (fault injection)",
+                canonical_addr
+            );
+
+            return;
+        }
+
+        if let Some(response_sender) = self.opt_response_sender.as_ref() {
+            response_sender.send(canonical_addr, response);
+
+            return;
+        }
+
+        let addr = self.destination_addr(canonical_addr);
+
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        if let Some(send_batch) = self.send_batch.as_mut() {
+            #[cfg(feature = "fault-injection")]
+            let batch = fault != SendFault::WouldBlock;
+            #[cfg(not(feature = "fault-injection"))]
+            let batch = true;
+
+            if batch {
+                if let Err(err) = send_batch.push(canonical_addr, addr, response) {
+                    ::log::error!("failed writing response to buffer: {:#}", err);
+                }
+
+                if send_batch.is_full() {
+                    self.flush_send_batch(opt_resend_buffer);
+                }
+
+                return;
+            }
+        }
+
         let mut buffer = Cursor::new(&mut self.buffer[..]);
 
         if let Err(err) = response.write_bytes(&mut buffer) {
@@ -262,58 +815,21 @@ impl SocketWorker {
 
         let bytes_written = buffer.position() as usize;
 
-        let addr = if self.config.network.address.is_ipv4() {
-            canonical_addr
-                .get_ipv4()
-                .expect("found peer ipv6 address while running bound to ipv4 address")
+        #[cfg(feature = "fault-injection")]
+        let result = if fault == SendFault::WouldBlock {
+            Err(ErrorKind::WouldBlock.into())
         } else {
-            canonical_addr.get_ipv6_mapped()
+            self.send_to(&self.buffer[..bytes_written], addr)
         };
+        #[cfg(not(feature = "fault-injection"))]
+        let result = self.send_to(&self.buffer[..bytes_written], addr);
 
-        match self
-            .socket
-            .send_to(&buffer.into_inner()[..bytes_written], addr)
-        {
-            Ok(bytes_sent) if self.config.statistics.active() => {
-                let stats = if canonical_addr.is_ipv4() {
-                    let stats = &self.statistics.ipv4;
-
-                    stats
-                        .bytes_sent
-                        .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
-
-                    stats
-                } else {
-                    let stats = &self.statistics.ipv6;
-
-                    stats
-                        .bytes_sent
-                        .fetch_add(bytes_sent + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
-
-                    stats
-                };
-
-                match response {
-                    Response::Connect(_) => {
-                        stats.responses_connect.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
-                        stats.responses_announce.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Scrape(_) => {
-                        stats.responses_scrape.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Error(_) => {
-                        stats.responses_error.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
+        match result {
+            Ok(bytes_sent) => {
+                self.on_response_sent(canonical_addr, bytes_sent, &response);
             }
-            Ok(_) => (),
             Err(err) => match opt_resend_buffer.as_mut() {
-                Some(resend_buffer)
-                    if (err.raw_os_error() == Some(libc::ENOBUFS))
-                        || (err.kind() == ErrorKind::WouldBlock) =>
-                {
+                Some(resend_buffer) if send_buffer_full(&err) => {
                     if resend_buffer.len() < self.config.network.resend_buffer_max_len {
                         ::log::debug!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
 
@@ -330,4 +846,218 @@ impl SocketWorker {
 
         ::log::debug!("send response fn finished");
     }
+
+    /// Send responses collected in send batch with sendmmsg
+    ///
+    /// If the socket send buffer is full, unsent responses are added to the
+    /// resend buffer if it is enabled.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub fn flush_send_batch(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+    ) {
+        let mut send_batch = match self.send_batch.take() {
+            Some(send_batch) => send_batch,
+            None => return,
+        };
+
+        let mut num_sent = 0;
+
+        while num_sent < send_batch.len() {
+            match send_batch.send(&self.socket, num_sent) {
+                Ok(n) => {
+                    for i in num_sent..num_sent + n {
+                        let (canonical_addr, bytes_sent, response) = send_batch.get(i);
+
+                        self.on_response_sent(canonical_addr, bytes_sent, response);
+                    }
+
+                    num_sent += n;
+                }
+                Err(err) if send_buffer_full(&err) && opt_resend_buffer.is_some() => {
+                    ::log::debug!(
+                        "Adding {} responses to resend queue, since sending them failed with: {:#}",
+                        send_batch.len() - num_sent,
+                        err
+                    );
+
+                    break;
+                }
+                Err(err) => {
+                    let (canonical_addr, _, _) = send_batch.get(num_sent);
+
+                    ::log::warn!(
+                        "Sending response to {} failed: {:#}",
+                        self.destination_addr(canonical_addr),
+                        err
+                    );
+
+                    num_sent += 1;
+                }
+            }
+        }
+
+        let max_len = self.config.network.resend_buffer_max_len;
+
+        for item in send_batch.drain(num_sent) {
+            match opt_resend_buffer.as_mut() {
+                Some(resend_buffer) if resend_buffer.len() < max_len => {
+                    resend_buffer.push(item);
+                }
+                _ => {
+                    ::log::warn!("Response resend buffer full, dropping response");
+                }
+            }
+        }
+
+        self.send_batch = Some(send_batch);
+    }
+
+    /// Send datagram, from local address of request being handled if it is
+    /// known
+    fn send_to(&self, payload: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        if let Some(local_ip) = self.opt_local_ip {
+            return super::pktinfo::send_to_from(&self.socket, payload, addr, local_ip);
+        }
+
+        self.socket.send_to(payload, addr)
+    }
+
+    fn destination_addr(&self, canonical_addr: CanonicalSocketAddr) -> SocketAddr {
+        destination_addr(&self.config, canonical_addr)
+    }
+
+    fn on_response_sent(
+        &mut self,
+        canonical_addr: CanonicalSocketAddr,
+        bytes_sent: usize,
+        response: &Response,
+    ) {
+        if let Some(egress_budget) = self.egress_budget.as_mut() {
+            let extra_bytes = if canonical_addr.is_ipv4() {
+                EXTRA_PACKET_SIZE_IPV4
+            } else {
+                EXTRA_PACKET_SIZE_IPV6
+            };
+
+            egress_budget.add_bytes_sent(bytes_sent + extra_bytes);
+        }
+
+        count_sent_response(
+            &self.config,
+            &self.statistics,
+            canonical_addr,
+            bytes_sent,
+            response,
+        );
+    }
+
+    /// Send responses with identical destinations in batches using GSO
+    ///
+    /// Responses that can't be batched are left in `responses`. Returns
+    /// `None` if GSO should not be used anymore.
+    #[cfg(target_os = "linux")]
+    fn send_responses_with_gso(
+        &mut self,
+        mut gso: GsoState,
+        responses: &mut Vec<(CanonicalSocketAddr, Response)>,
+    ) -> Option<GsoState> {
+        use super::gso::{send_segments, MAX_PAYLOAD_SIZE, MAX_SEGMENTS};
+
+        // Group responses by destination
+        responses.sort_by_key(|(addr, _)| addr.get());
+
+        let mut remaining = Vec::new();
+        let mut responses_iter = ::std::mem::take(responses).into_iter().peekable();
+
+        while let Some((canonical_addr, response)) = responses_iter.next() {
+            gso.payload.clear();
+            gso.responses.clear();
+
+            if let Err(err) = response.write_bytes(&mut gso.payload) {
+                ::log::error!("failed writing response to buffer: {:#}", err);
+
+                continue;
+            }
+
+            let segment_size = gso.payload.len();
+
+            gso.responses.push(response);
+
+            // All segments except the last one must have the same size
+            while gso.responses.len() < MAX_SEGMENTS
+                && gso.payload.len() == segment_size * gso.responses.len()
+            {
+                let response = match responses_iter.peek() {
+                    Some((addr, response)) if *addr == canonical_addr => response,
+                    _ => break,
+                };
+
+                let start = gso.payload.len();
+
+                if response.write_bytes(&mut gso.payload).is_err()
+                    || gso.payload.len() - start > segment_size
+                    || gso.payload.len() > MAX_PAYLOAD_SIZE
+                {
+                    gso.payload.truncate(start);
+
+                    break;
+                }
+
+                if let Some((_, response)) = responses_iter.next() {
+                    gso.responses.push(response);
+                }
+            }
+
+            if gso.responses.len() == 1 {
+                remaining.extend(gso.responses.drain(..).map(|r| (canonical_addr, r)));
+
+                continue;
+            }
+
+            let addr = self.destination_addr(canonical_addr);
+
+            match send_segments(&self.socket, addr, &gso.payload, segment_size as u16) {
+                Ok(_) => {
+                    ::log::debug!(
+                        "sent {} responses to {} with GSO",
+                        gso.responses.len(),
+                        addr
+                    );
+
+                    let mut bytes_left = gso.payload.len();
+
+                    for response in gso.responses.iter() {
+                        let bytes_sent = bytes_left.min(segment_size);
+
+                        self.on_response_sent(canonical_addr, bytes_sent, response);
+
+                        bytes_left -= bytes_sent;
+                    }
+                }
+                Err(err) if send_buffer_full(&err) => {
+                    ::log::debug!("GSO send to {} failed: {:#}", addr, err);
+
+                    remaining.extend(gso.responses.drain(..).map(|r| (canonical_addr, r)));
+                }
+                Err(err) => {
+                    // For instance, EIO is returned if the network device
+                    // doesn't support checksum offload
+                    ::log::warn!("GSO send to {} failed, disabling GSO: {:#}", addr, err);
+
+                    remaining.extend(gso.responses.drain(..).map(|r| (canonical_addr, r)));
+                    remaining.extend(responses_iter);
+
+                    *responses = remaining;
+
+                    return None;
+                }
+            }
+        }
+
+        *responses = remaining;
+
+        Some(gso)
+    }
 }
@@ -0,0 +1,311 @@
+//! Batched receiving and sending with `recvmmsg` and `sendmmsg` for the mio
+//! backend
+//!
+//! At high packet rates, the overhead of one system call per datagram
+//! dominates. These helpers move up to `network.mmsg_batch_size` datagrams
+//! per call instead.
+
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+use socket2::SockAddr;
+
+use crate::common::BUFFER_SIZE;
+
+/// Buffers for receiving a batch of datagrams
+pub struct RecvBatch {
+    /// One chunk of `BUFFER_SIZE` bytes per datagram
+    buffer: Box<[u8]>,
+    addrs: Box<[libc::sockaddr_storage]>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+    /// Number of datagrams received in last call
+    len: usize,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            buffer: vec![0; batch_size * BUFFER_SIZE].into_boxed_slice(),
+            addrs: (0..batch_size)
+                .map(|_| unsafe { zeroed() })
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+            iovecs: Vec::with_capacity(batch_size),
+            headers: Vec::with_capacity(batch_size),
+            len: 0,
+        }
+    }
+
+    /// Receive as many datagrams as are available, up to batch size
+    ///
+    /// Returns number of datagrams received. Fails with
+    /// [`io::ErrorKind::WouldBlock`] if none were available.
+    pub fn recv(&mut self, socket: &impl AsRawFd) -> io::Result<usize> {
+        self.len = 0;
+        self.iovecs.clear();
+        self.headers.clear();
+
+        for chunk in self.buffer.chunks_exact_mut(BUFFER_SIZE) {
+            self.iovecs.push(libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            });
+        }
+
+        for (iovec, addr) in self.iovecs.iter_mut().zip(self.addrs.iter_mut()) {
+            let mut header: libc::msghdr = unsafe { zeroed() };
+
+            header.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_iov = iovec;
+            header.msg_iovlen = 1;
+
+            self.headers.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                self.headers.as_mut_ptr(),
+                self.headers.len() as libc::c_uint,
+                0,
+                ::std::ptr::null_mut(),
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            self.len = result as usize;
+
+            Ok(self.len)
+        }
+    }
+
+    /// Payload and source address of datagram `index` of last call
+    ///
+    /// Returns None for datagrams with source addresses that are neither
+    /// IPv4 nor IPv6.
+    pub fn get(&self, index: usize) -> Option<(&[u8], SocketAddr)> {
+        if index >= self.len {
+            return None;
+        }
+
+        let header = &self.headers[index];
+
+        let addr =
+            unsafe { SockAddr::new(self.addrs[index], header.msg_hdr.msg_namelen) }.as_socket()?;
+
+        let start = index * BUFFER_SIZE;
+        let len = (header.msg_len as usize).min(BUFFER_SIZE);
+
+        Some((&self.buffer[start..start + len], addr))
+    }
+}
+
+/// Responses waiting to be sent in a single batch
+pub struct SendBatch {
+    batch_size: usize,
+    /// Serialized responses, one after the other
+    payload: Vec<u8>,
+    /// Byte range in `payload` and destination of each response
+    messages: Vec<(usize, usize, SockAddr)>,
+    responses: Vec<(CanonicalSocketAddr, Response)>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl SendBatch {
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            batch_size,
+            payload: Vec::with_capacity(batch_size * 128),
+            messages: Vec::with_capacity(batch_size),
+            responses: Vec::with_capacity(batch_size),
+            iovecs: Vec::with_capacity(batch_size),
+            headers: Vec::with_capacity(batch_size),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.responses.len() >= self.batch_size
+    }
+
+    /// Serialize response and add it to batch
+    pub fn push(
+        &mut self,
+        canonical_addr: CanonicalSocketAddr,
+        addr: SocketAddr,
+        response: Response,
+    ) -> io::Result<()> {
+        let start = self.payload.len();
+
+        if let Err(err) = response.write_bytes(&mut self.payload) {
+            self.payload.truncate(start);
+
+            return Err(err);
+        }
+
+        self.messages
+            .push((start, self.payload.len() - start, SockAddr::from(addr)));
+        self.responses.push((canonical_addr, response));
+
+        Ok(())
+    }
+
+    /// Destination, serialized length and response at `index`
+    pub fn get(&self, index: usize) -> (CanonicalSocketAddr, usize, &Response) {
+        let (canonical_addr, response) = &self.responses[index];
+
+        (*canonical_addr, self.messages[index].1, response)
+    }
+
+    /// Send responses starting at `start`
+    ///
+    /// Returns number of responses sent, which is at least one. Fails if
+    /// the response at `start` couldn't be sent.
+    pub fn send(&mut self, socket: &impl AsRawFd, start: usize) -> io::Result<usize> {
+        self.iovecs.clear();
+        self.headers.clear();
+
+        for (offset, len, _) in self.messages[start..].iter() {
+            self.iovecs.push(libc::iovec {
+                iov_base: self.payload[*offset..].as_ptr() as *mut libc::c_void,
+                iov_len: *len,
+            });
+        }
+
+        for (iovec, (_, _, addr)) in self.iovecs.iter_mut().zip(self.messages[start..].iter()) {
+            let mut header: libc::msghdr = unsafe { zeroed() };
+
+            header.msg_name = addr.as_ptr() as *mut libc::c_void;
+            header.msg_namelen = addr.len();
+            header.msg_iov = iovec;
+            header.msg_iovlen = 1;
+
+            self.headers.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                self.headers.as_mut_ptr(),
+                self.headers.len() as libc::c_uint,
+                0,
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    /// Remove all responses, returning those starting at `start`
+    pub fn drain(
+        &mut self,
+        start: usize,
+    ) -> impl Iterator<Item = (CanonicalSocketAddr, Response)> + '_ {
+        self.payload.clear();
+        self.messages.clear();
+
+        self.responses.drain(..).skip(start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use aquatic_udp_protocol::{ConnectResponse, ConnectionId, TransactionId};
+
+    use super::*;
+
+    #[test]
+    fn test_send_and_recv_batch() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+
+        let receiver_addr = receiver.local_addr().unwrap();
+        let canonical_addr = CanonicalSocketAddr::new(receiver_addr);
+
+        let mut send_batch = SendBatch::new(4);
+
+        for i in 0..4 {
+            let response = Response::Connect(ConnectResponse {
+                connection_id: ConnectionId::new(i),
+                transaction_id: TransactionId::new(i as i32),
+            });
+
+            send_batch
+                .push(canonical_addr, receiver_addr, response)
+                .unwrap();
+        }
+
+        assert!(send_batch.is_full());
+        assert_eq!(send_batch.get(1).1, 16);
+
+        let mut sent = 0;
+
+        while sent < send_batch.len() {
+            sent += send_batch.send(&sender, sent).unwrap();
+        }
+
+        assert_eq!(send_batch.drain(sent).count(), 0);
+        assert_eq!(send_batch.len(), 0);
+
+        let mut recv_batch = RecvBatch::new(8);
+        let mut received = Vec::new();
+
+        // Datagrams may not all have arrived yet
+        for _ in 0..100 {
+            match recv_batch.recv(&receiver) {
+                Ok(n) => {
+                    for i in 0..n {
+                        let (payload, addr) = recv_batch.get(i).unwrap();
+
+                        assert_eq!(addr, sender.local_addr().unwrap());
+
+                        received.push(payload.to_vec());
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+                }
+                Err(err) => panic!("recvmmsg failed: {:#}", err),
+            }
+
+            if received.len() == 4 {
+                break;
+            }
+        }
+
+        assert_eq!(received.len(), 4);
+
+        for (i, payload) in received.iter().enumerate() {
+            assert_eq!(payload.len(), 16);
+            assert_eq!(payload[..4], 0i32.to_be_bytes());
+            assert_eq!(payload[4..8], (i as i32).to_be_bytes());
+        }
+
+        assert!(recv_batch.get(4).is_none());
+    }
+}
@@ -1,25 +1,62 @@
+mod amplification_guard;
+mod audit;
+mod connect_filter;
+mod egress_budget;
+#[cfg(target_os = "linux")]
+mod errqueue;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
+#[cfg(target_os = "linux")]
+mod gso;
 mod mio;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
+#[cfg(target_os = "linux")]
+mod pktinfo;
+mod sender;
 #[cfg(all(target_os = "linux", feature = "io-uring"))]
 mod uring;
 mod validator;
+#[cfg(all(target_os = "linux", feature = "af-xdp"))]
+mod xdp;
+
+use std::net::SocketAddr;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
+use aquatic_common::ip_blocklist::{create_ip_blocklist_cache, IpBlocklistCache};
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::{CanonicalSocketAddr, PortZeroPolicy};
+use aquatic_udp_protocol::{
+    AnnounceEvent, AnnounceRequest, ErrorResponse, Response, CONNECT_REQUEST_LEN,
+};
 use crossbeam_channel::Sender;
 use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
     common::{
-        CachePaddedArc, IpVersionStatistics, SocketWorkerStatistics, State, StatisticsMessage,
+        CachePaddedArc, IpVersionStatistics, ReachabilityProbeRequest, SocketWorkerStatistics,
+        State, Statistics, StatisticsMessage,
     },
     config::Config,
 };
 
+use crate::config::CountryPolicy;
+
+use self::amplification_guard::AmplificationGuard;
+
 pub use self::validator::ConnectionValidator;
 
 #[cfg(all(not(target_os = "linux"), feature = "io-uring"))]
 compile_error!("io_uring feature is only supported on Linux");
 
+#[cfg(all(not(target_os = "linux"), feature = "af-xdp"))]
+compile_error!("af-xdp feature is only supported on Linux");
+
+#[cfg(all(not(target_os = "linux"), feature = "mmsg"))]
+compile_error!("mmsg feature is only supported on Linux");
+
 /// Bytes of data transmitted when sending an IPv4 UDP packet, in addition to payload size
 ///
 /// Consists of:
@@ -45,9 +82,23 @@ pub fn run_socket_worker(
     statistics_sender: Sender<StatisticsMessage>,
     validator: ConnectionValidator,
     priv_dropper: PrivilegeDropper,
+    worker_index: usize,
 ) -> anyhow::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "af-xdp"))]
+    if config.network.use_af_xdp {
+        return self::xdp::SocketWorker::run(
+            config,
+            shared_state,
+            statistics,
+            statistics_sender,
+            validator,
+            priv_dropper,
+            worker_index,
+        );
+    }
+
     #[cfg(all(target_os = "linux", feature = "io-uring"))]
-    if config.network.use_io_uring {
+    if config.network.backend == crate::config::SocketBackend::IoUring {
         self::uring::supported_on_current_kernel().context("check for io_uring compatibility")?;
 
         return self::uring::SocketWorker::run(
@@ -67,6 +118,27 @@ pub fn run_socket_worker(
         statistics_sender,
         validator,
         priv_dropper,
+        worker_index,
+    )
+}
+
+/// Run mio socket worker with torrent cleaning interleaved on the current
+/// thread, with deterministic peer selection
+pub fn run_single_threaded_worker(
+    config: Config,
+    shared_state: State,
+    statistics: Statistics,
+    statistics_sender: Sender<StatisticsMessage>,
+    validator: ConnectionValidator,
+    priv_dropper: PrivilegeDropper,
+) -> anyhow::Result<()> {
+    self::mio::SocketWorker::run_single_threaded(
+        config,
+        shared_state,
+        statistics,
+        statistics_sender,
+        validator,
+        priv_dropper,
     )
 }
 
@@ -86,10 +158,32 @@ fn create_socket(
             .with_context(|| "socket: set only ipv6")?;
     }
 
+    #[cfg(unix)]
     socket
         .set_reuse_port(true)
         .with_context(|| "socket: set reuse port")?;
 
+    // SO_REUSEPORT doesn't distribute incoming packets among sockets on
+    // FreeBSD
+    #[cfg(target_os = "freebsd")]
+    if config.network.use_reuse_port_lb {
+        socket
+            .set_reuse_port_lb(true)
+            .with_context(|| "socket: set reuse port lb")?;
+    }
+
+    if !config.network.bind_device.is_empty() {
+        #[cfg(target_os = "linux")]
+        socket
+            .bind_device(Some(config.network.bind_device.as_bytes()))
+            .with_context(|| format!("socket: bind to device {}", config.network.bind_device))?;
+
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "network.bind_device is only supported on Linux"
+        ));
+    }
+
     socket
         .set_nonblocking(true)
         .with_context(|| "socket: set nonblocking")?;
@@ -106,6 +200,43 @@ fn create_socket(
         }
     }
 
+    let send_buffer_size = config.network.socket_send_buffer_size;
+
+    if send_buffer_size != 0 {
+        if let Err(err) = socket.set_send_buffer_size(send_buffer_size) {
+            ::log::error!(
+                "socket: failed setting send buffer to {}: {:?}",
+                send_buffer_size,
+                err
+            );
+        }
+    }
+
+    static LOG_BUFFER_SIZES: Once = Once::new();
+
+    LOG_BUFFER_SIZES.call_once(|| log_buffer_sizes(config, &socket));
+
+    #[cfg(target_os = "linux")]
+    if config.network.socket_busy_poll_us != 0 {
+        let value = config
+            .network
+            .socket_busy_poll_us
+            .min(libc::c_int::MAX as u32) as libc::c_int;
+
+        if let Err(err) =
+            set_int_socket_option(&socket, libc::SOL_SOCKET, libc::SO_BUSY_POLL, value)
+        {
+            ::log::error!(
+                "socket: failed setting SO_BUSY_POLL to {}: {:#}",
+                config.network.socket_busy_poll_us,
+                err
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    set_ip_socket_options(config, &socket);
+
     socket
         .bind(&config.network.address.into())
         .with_context(|| format!("socket: bind to {}", config.network.address))?;
@@ -114,3 +245,545 @@ fn create_socket(
 
     Ok(socket.into())
 }
+
+/// Log buffer sizes granted by the kernel, warning if they are smaller than
+/// the configured ones
+fn log_buffer_sizes(config: &Config, socket: &Socket) {
+    // Linux doubles requested sizes to leave room for bookkeeping overhead
+    let granted = |effective: usize| {
+        if cfg!(target_os = "linux") {
+            effective / 2
+        } else {
+            effective
+        }
+    };
+
+    let buffers = [
+        (
+            "recv",
+            config.network.socket_recv_buffer_size,
+            socket.recv_buffer_size(),
+        ),
+        (
+            "send",
+            config.network.socket_send_buffer_size,
+            socket.send_buffer_size(),
+        ),
+    ];
+
+    for (name, configured, result) in buffers {
+        match result {
+            Ok(effective) if granted(effective) < configured => {
+                ::log::warn!(
+                    "socket: effective {} buffer size is {}, lower than configured {}. Check OS limits",
+                    name,
+                    effective,
+                    configured
+                );
+            }
+            Ok(effective) => {
+                ::log::info!("socket: effective {} buffer size is {}", name, effective);
+            }
+            Err(err) => {
+                ::log::error!("socket: failed getting {} buffer size: {:?}", name, err);
+            }
+        }
+    }
+}
+
+/// Set traffic class, path MTU discovery mode, checksum calculation and
+/// reception of local addresses of datagrams and of errors. Failures are
+/// logged.
+#[cfg(target_os = "linux")]
+fn set_ip_socket_options(config: &Config, socket: &Socket) {
+    use crate::config::MtuDiscovery;
+
+    let is_ipv6_socket = config.network.address.is_ipv6();
+    let mut options = Vec::new();
+
+    // SO_NO_CHECK applies to IPv4 traffic on dual-stack sockets too
+    if config.network.socket_skip_send_checksum {
+        if is_ipv6_socket {
+            options.push(("UDP_NO_CHECK6_TX", libc::SOL_UDP, libc::UDP_NO_CHECK6_TX, 1));
+        }
+        if config.network.ipv4_active() {
+            options.push(("SO_NO_CHECK", libc::SOL_SOCKET, libc::SO_NO_CHECK, 1));
+        }
+    }
+
+    if config.network.socket_recv_errors {
+        if is_ipv6_socket {
+            options.push(("IPV6_RECVERR", libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1));
+        }
+        if config.network.ipv4_active() {
+            options.push(("IP_RECVERR", libc::IPPROTO_IP, libc::IP_RECVERR, 1));
+        }
+    }
+
+    if config.network.socket_traffic_class != 0 {
+        let value = config.network.socket_traffic_class.into();
+
+        if is_ipv6_socket {
+            options.push(("IPV6_TCLASS", libc::IPPROTO_IPV6, libc::IPV6_TCLASS, value));
+        }
+        if config.network.ipv4_active() {
+            options.push(("IP_TOS", libc::IPPROTO_IP, libc::IP_TOS, value));
+        }
+    }
+
+    let mtu_discovery = match config.network.socket_mtu_discovery {
+        MtuDiscovery::OsDefault => None,
+        MtuDiscovery::Do => Some((libc::IP_PMTUDISC_DO, libc::IPV6_PMTUDISC_DO)),
+        MtuDiscovery::Probe => Some((libc::IP_PMTUDISC_PROBE, libc::IPV6_PMTUDISC_PROBE)),
+        MtuDiscovery::Dont => Some((libc::IP_PMTUDISC_DONT, libc::IPV6_PMTUDISC_DONT)),
+    };
+
+    if let Some((ipv4_value, ipv6_value)) = mtu_discovery {
+        if is_ipv6_socket {
+            options.push((
+                "IPV6_MTU_DISCOVER",
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                ipv6_value,
+            ));
+        }
+        if config.network.ipv4_active() {
+            options.push((
+                "IP_MTU_DISCOVER",
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                ipv4_value,
+            ));
+        }
+    }
+
+    // On dual-stack sockets, IPV6_PKTINFO is reported for IPv4 datagrams too
+    if config.network.pin_source_address {
+        if is_ipv6_socket {
+            options.push((
+                "IPV6_RECVPKTINFO",
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVPKTINFO,
+                1,
+            ));
+        } else {
+            options.push(("IP_PKTINFO", libc::IPPROTO_IP, libc::IP_PKTINFO, 1));
+        }
+    }
+
+    for (name, level, option, value) in options {
+        if let Err(err) = set_int_socket_option(socket, level, option, value) {
+            ::log::error!("socket: failed setting {} to {}: {:#}", name, value, err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_int_socket_option(
+    socket: &Socket,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> ::std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            ::std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(::std::io::Error::last_os_error())
+    }
+}
+
+/// Spin-then-park polling strategy
+///
+/// After events were received, the socket is polled without timeout for the
+/// configured duration before falling back to waiting with the regular poll
+/// timeout.
+struct BusyPoll {
+    duration: Duration,
+    last_event_at: Option<Instant>,
+}
+
+impl BusyPoll {
+    fn new(config: &Config) -> Self {
+        Self {
+            duration: Duration::from_micros(config.network.busy_poll_us),
+            last_event_at: None,
+        }
+    }
+
+    fn timeout(&self, poll_timeout: Duration) -> Duration {
+        match self.last_event_at {
+            Some(instant) if instant.elapsed() < self.duration => Duration::ZERO,
+            _ => poll_timeout,
+        }
+    }
+
+    fn register_poll_result(&mut self, received_events: bool) {
+        if received_events && !self.duration.is_zero() {
+            self.last_event_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Check if sending failed because socket send buffer is full or no
+/// buffer space is available, meaning that sending can be retried later
+fn send_buffer_full(err: &::std::io::Error) -> bool {
+    #[cfg(unix)]
+    let no_buffer_space = err.raw_os_error() == Some(libc::ENOBUFS);
+    // WSAENOBUFS
+    #[cfg(windows)]
+    let no_buffer_space = err.raw_os_error() == Some(10055);
+    #[cfg(not(any(unix, windows)))]
+    let no_buffer_space = false;
+
+    no_buffer_space || err.kind() == ::std::io::ErrorKind::WouldBlock
+}
+
+/// Count announce requests with port 0 and create error response for them
+/// if they should be rejected
+fn handle_port_zero(
+    config: &Config,
+    statistics: &IpVersionStatistics<SocketWorkerStatistics>,
+    request: &AnnounceRequest,
+    src: CanonicalSocketAddr,
+) -> Option<Response> {
+    if request.port.0.get() != 0 {
+        return None;
+    }
+
+    if config.statistics.active() {
+        let statistics = if src.is_ipv4() {
+            &statistics.ipv4
+        } else {
+            &statistics.ipv6
+        };
+
+        statistics.announce_requests_port_zero.increment(1);
+    }
+
+    match config.protocol.port_zero_policy {
+        PortZeroPolicy::Reject => Some(Response::Error(ErrorResponse {
+            transaction_id: request.transaction_id,
+            message: "Port can't be 0".into(),
+        })),
+        PortZeroPolicy::Hide => None,
+    }
+}
+
+/// Respond with error if announce request is from a country not served
+/// according to country policy
+fn handle_country_policy(
+    config: &Config,
+    state: &State,
+    request: &AnnounceRequest,
+    src: CanonicalSocketAddr,
+) -> Option<Response> {
+    let country_lookup = state.country_lookup.as_ref()?;
+
+    #[cfg(feature = "prometheus")]
+    let count = config.geoip.count_requests_by_country
+        && config.statistics.active()
+        && config.statistics.run_prometheus_endpoint;
+    #[cfg(not(feature = "prometheus"))]
+    let count = false;
+
+    if config.geoip.country_policy == CountryPolicy::Off && !count {
+        return None;
+    }
+
+    let country = country_lookup.lookup(src.get().ip());
+    let allowed = country_lookup.allows(country);
+
+    #[cfg(feature = "prometheus")]
+    if count {
+        let country = country
+            .map(|country| String::from_utf8_lossy(&country).into_owned())
+            .unwrap_or_else(|| "unknown".into());
+
+        ::metrics::counter!(
+            aquatic_metrics::names::ANNOUNCE_REQUESTS_BY_COUNTRY_TOTAL,
+            "country" => country,
+            "allowed" => if allowed { "true" } else { "false" },
+        )
+        .increment(1);
+    }
+
+    if allowed {
+        None
+    } else {
+        Some(Response::Error(ErrorResponse {
+            transaction_id: request.transaction_id,
+            message: config.geoip.denied_message.clone().into(),
+        }))
+    }
+}
+
+/// Pass address of peer that just started announcing on to reachability
+/// prober, if it is enabled and not busy
+fn sample_for_reachability_probe(
+    state: &State,
+    request: &AnnounceRequest,
+    src: CanonicalSocketAddr,
+) {
+    if let Some(sender) = state.reachability_probe_sender.as_ref() {
+        if request.port.0.get() != 0 && AnnounceEvent::from(request.event) == AnnounceEvent::Started
+        {
+            let addr = SocketAddr::new(src.get().ip(), request.port.0.get());
+
+            let _ = sender.try_send(ReachabilityProbeRequest {
+                info_hash: request.info_hash,
+                addr: CanonicalSocketAddr::new(addr),
+            });
+        }
+    }
+}
+
+fn create_opt_ip_blocklist_cache(config: &Config, state: &State) -> Option<IpBlocklistCache> {
+    config
+        .ip_blocklist
+        .enabled
+        .then(|| create_ip_blocklist_cache(&state.ip_blocklist))
+}
+
+/// Check if source address is in IP blocklist and count blocked requests
+fn is_blocked(
+    config: &Config,
+    statistics: &IpVersionStatistics<SocketWorkerStatistics>,
+    opt_ip_blocklist_cache: &mut Option<IpBlocklistCache>,
+    src: CanonicalSocketAddr,
+) -> bool {
+    let blocked = opt_ip_blocklist_cache
+        .as_mut()
+        .map(|cache| cache.load().contains(src.get().ip()))
+        .unwrap_or(false);
+
+    if blocked && config.statistics.active() {
+        let statistics = if src.is_ipv4() {
+            &statistics.ipv4
+        } else {
+            &statistics.ipv6
+        };
+
+        statistics.requests_blocked.increment(1);
+    }
+
+    blocked
+}
+
+/// Drop response if amplification guard doesn't allow it and count dropped
+/// responses
+fn apply_amplification_guard(
+    config: &Config,
+    statistics: &IpVersionStatistics<SocketWorkerStatistics>,
+    opt_amplification_guard: &mut Option<AmplificationGuard>,
+    addr: CanonicalSocketAddr,
+    response: Response,
+) -> Option<(CanonicalSocketAddr, Response)> {
+    let amplification_guard = match opt_amplification_guard.as_mut() {
+        Some(amplification_guard) => amplification_guard,
+        None => return Some((addr, response)),
+    };
+
+    let opt_response = amplification_guard.apply(addr, response);
+
+    if opt_response.is_none() {
+        if config.statistics.active() {
+            let statistics = if addr.is_ipv4() {
+                &statistics.ipv4
+            } else {
+                &statistics.ipv6
+            };
+
+            statistics.responses_amplification_dropped.increment(1);
+        }
+
+        ::log::debug!("Dropped response to {:?} due to amplification limit", addr);
+    }
+
+    opt_response
+}
+
+/// Add source of parsed request to estimates of distinct IPs and ASNs
+fn record_unique_source(
+    config: &Config,
+    state: &State,
+    statistics: &SocketWorkerStatistics,
+    src: CanonicalSocketAddr,
+) {
+    statistics
+        .unique_ips
+        .insert(&src.ip_prefix(config.network.ipv6_prefix_len));
+
+    if let Some(asn) = state
+        .asn_lookup
+        .as_ref()
+        .and_then(|asn_lookup| asn_lookup.lookup(src.get().ip()))
+    {
+        statistics.unique_asns.insert(&asn);
+    }
+}
+
+/// Strip padding from connect requests if `protocol.min_connect_request_size`
+/// is set, since clients are then expected to pad them beyond their regular
+/// length. The connect filter checks the size of the whole datagram.
+fn strip_connect_padding(min_connect_request_size: usize, payload: &[u8]) -> &[u8] {
+    let is_connect = payload.get(8..12) == Some(&[0, 0, 0, 0]);
+
+    if min_connect_request_size != 0 && is_connect && payload.len() > CONNECT_REQUEST_LEN {
+        &payload[..CONNECT_REQUEST_LEN]
+    } else {
+        payload
+    }
+}
+
+/// Count datagram ignored due to invalid length for its action
+fn record_invalid_length(
+    config: &Config,
+    statistics: &IpVersionStatistics<SocketWorkerStatistics>,
+    src: CanonicalSocketAddr,
+) {
+    if config.statistics.active() {
+        let statistics = if src.is_ipv4() {
+            &statistics.ipv4
+        } else {
+            &statistics.ipv6
+        };
+
+        statistics.requests_invalid_length.increment(1);
+    }
+
+    ::log::debug!("Ignored request with invalid length from {:?}", src);
+}
+
+fn destination_addr(config: &Config, canonical_addr: CanonicalSocketAddr) -> SocketAddr {
+    if config.network.address.is_ipv4() {
+        canonical_addr
+            .get_ipv4()
+            .expect("found peer ipv6 address while running bound to ipv4 address")
+    } else {
+        canonical_addr.get_ipv6_mapped()
+    }
+}
+
+/// Update statistics after sending response
+fn count_sent_response(
+    config: &Config,
+    statistics: &IpVersionStatistics<SocketWorkerStatistics>,
+    canonical_addr: CanonicalSocketAddr,
+    bytes_sent: usize,
+    response: &Response,
+) {
+    if !config.statistics.active() {
+        return;
+    }
+
+    let stats = if canonical_addr.is_ipv4() {
+        let stats = &statistics.ipv4;
+
+        stats
+            .bytes_sent
+            .increment(bytes_sent + EXTRA_PACKET_SIZE_IPV4);
+
+        stats
+    } else {
+        let stats = &statistics.ipv6;
+
+        stats
+            .bytes_sent
+            .increment(bytes_sent + EXTRA_PACKET_SIZE_IPV6);
+
+        stats
+    };
+
+    match response {
+        Response::Connect(_) => {
+            stats.responses_connect.increment(1);
+        }
+        Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
+            stats.responses_announce.increment(1);
+        }
+        Response::Scrape(_) => {
+            stats.responses_scrape.increment(1);
+        }
+        Response::Error(_) => {
+            stats.responses_error.increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aquatic_udp_protocol::{ConnectRequest, Request, TransactionId};
+
+    use super::*;
+
+    #[test]
+    fn test_strip_connect_padding() {
+        let mut payload = Vec::new();
+
+        Request::Connect(ConnectRequest {
+            transaction_id: TransactionId::new(1),
+        })
+        .write_bytes(&mut payload)
+        .unwrap();
+
+        payload.extend_from_slice(&[0; 8]);
+
+        assert!(!Request::length_valid(&payload));
+        assert_eq!(strip_connect_padding(0, &payload), &payload[..]);
+
+        let stripped = strip_connect_padding(20, &payload);
+
+        assert_eq!(stripped, &payload[..CONNECT_REQUEST_LEN]);
+        assert!(Request::length_valid(stripped));
+
+        // Other requests are left as they are
+        payload[11] = 2;
+
+        assert_eq!(strip_connect_padding(20, &payload), &payload[..]);
+    }
+
+    #[test]
+    fn test_busy_poll() {
+        let poll_timeout = Duration::from_millis(50);
+
+        let mut config = Config::default();
+
+        let mut busy_poll = BusyPoll::new(&config);
+
+        busy_poll.register_poll_result(true);
+
+        assert_eq!(busy_poll.timeout(poll_timeout), poll_timeout);
+
+        config.network.busy_poll_us = 1_000_000;
+
+        let mut busy_poll = BusyPoll::new(&config);
+
+        assert_eq!(busy_poll.timeout(poll_timeout), poll_timeout);
+
+        busy_poll.register_poll_result(false);
+
+        assert_eq!(busy_poll.timeout(poll_timeout), poll_timeout);
+
+        busy_poll.register_poll_result(true);
+
+        assert_eq!(busy_poll.timeout(poll_timeout), Duration::ZERO);
+
+        busy_poll.last_event_at = busy_poll
+            .last_event_at
+            .and_then(|instant| instant.checked_sub(Duration::from_secs(2)));
+
+        assert_eq!(busy_poll.timeout(poll_timeout), poll_timeout);
+    }
+}
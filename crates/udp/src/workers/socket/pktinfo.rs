@@ -0,0 +1,231 @@
+//! Source address pinning with IP_PKTINFO and IPV6_PKTINFO for the mio
+//! backend
+//!
+//! When the socket is bound to an unspecified address on a host with
+//! multiple addresses, the kernel picks the source address of each response
+//! by routing, which may differ from the address that the request was sent
+//! to. These helpers receive the local address of each request and send
+//! responses from it.
+
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+
+use socket2::SockAddr;
+
+/// Control message buffer. u64 array for cmsghdr alignment.
+type ControlBuffer = [u64; 16];
+
+/// Receive datagram into `buffer`
+///
+/// Returns number of bytes received, source address and local address that
+/// the datagram was sent to, if the kernel reported it.
+pub fn recv_from(
+    socket: &impl AsRawFd,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let mut addr: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+
+    let mut header: libc::msghdr = unsafe { zeroed() };
+
+    header.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = size_of::<ControlBuffer>() as _;
+
+    let result = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let src = unsafe { SockAddr::new(addr, header.msg_namelen) }
+        .as_socket()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "source address is neither IPv4 nor IPv6",
+            )
+        })?;
+
+    let opt_local_ip = unsafe { local_ip(&header) };
+
+    Ok((result as usize, src, opt_local_ip))
+}
+
+/// Send `payload` to `addr` with `local_ip` as source address
+///
+/// Returns number of bytes sent on success.
+pub fn send_to_from(
+    socket: &impl AsRawFd,
+    payload: &[u8],
+    addr: SocketAddr,
+    local_ip: IpAddr,
+) -> io::Result<usize> {
+    let addr = SockAddr::from(addr);
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut header: libc::msghdr = unsafe { zeroed() };
+
+    header.msg_name = addr.as_ptr() as *mut libc::c_void;
+    header.msg_namelen = addr.len();
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+    let result = unsafe {
+        match local_ip {
+            IpAddr::V4(ip) => {
+                let mut info: libc::in_pktinfo = zeroed();
+
+                info.ipi_spec_dst.s_addr = u32::from(ip).to_be();
+
+                write_control_message(&mut header, libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+            }
+            IpAddr::V6(ip) => {
+                let mut info: libc::in6_pktinfo = zeroed();
+
+                info.ipi6_addr.s6_addr = ip.octets();
+
+                write_control_message(&mut header, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+            }
+        }
+
+        libc::sendmsg(socket.as_raw_fd(), &header, 0)
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Local address from IP_PKTINFO or IPV6_PKTINFO control message
+///
+/// Dual-stack sockets report IPv4 addresses as IPv4-mapped IPv6 addresses,
+/// which are converted back.
+unsafe fn local_ip(header: &libc::msghdr) -> Option<IpAddr> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(header);
+
+    while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info =
+                    ::std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+
+                return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    info.ipi_addr.s_addr,
+                ))));
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info =
+                    ::std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo);
+                let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+
+                return Some(match ip.to_ipv4_mapped() {
+                    Some(ip) => IpAddr::V4(ip),
+                    None => IpAddr::V6(ip),
+                });
+            }
+            _ => (),
+        }
+
+        cmsg = libc::CMSG_NXTHDR(header, cmsg);
+    }
+
+    None
+}
+
+/// Write single control message to control buffer of `header`
+unsafe fn write_control_message<T>(
+    header: &mut libc::msghdr,
+    level: libc::c_int,
+    ty: libc::c_int,
+    data: T,
+) {
+    let control_len = libc::CMSG_SPACE(size_of::<T>() as u32) as usize;
+
+    assert!(control_len <= size_of::<ControlBuffer>());
+
+    header.msg_controllen = control_len as _;
+
+    let cmsg = libc::CMSG_FIRSTHDR(header);
+
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type = ty;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<T>() as u32) as _;
+
+    ::std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut T, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_send_from_local_ip() {
+        // Whole 127.0.0.0/8 network is assigned to loopback interface
+        let server = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let enable: libc::c_int = 1;
+
+        let result = unsafe {
+            libc::setsockopt(
+                server.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                &enable as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        assert_eq!(result, 0);
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let local_ip: IpAddr = "127.0.0.2".parse().unwrap();
+        let server_addr = SocketAddr::new(local_ip, server.local_addr().unwrap().port());
+
+        client.send_to(b"request", server_addr).unwrap();
+
+        let mut buffer = [0u8; 64];
+
+        let (bytes_read, src, opt_local_ip) = recv_from(&server, &mut buffer).unwrap();
+
+        assert_eq!(&buffer[..bytes_read], b"request");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(opt_local_ip, Some(local_ip));
+
+        send_to_from(&server, b"response", src, local_ip).unwrap();
+
+        let (bytes_read, response_src) = client.recv_from(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..bytes_read], b"response");
+        assert_eq!(response_src, server_addr);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+
+use crate::common::{CachePaddedArc, IpVersionStatistics, SocketWorkerStatistics, BUFFER_SIZE};
+use crate::config::Config;
+
+use super::{
+    count_sent_response, destination_addr, send_buffer_full, EXTRA_PACKET_SIZE_IPV4,
+    EXTRA_PACKET_SIZE_IPV6,
+};
+
+/// Wait this long before retrying sends that failed because the socket send
+/// buffer was full
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+/// Stop retrying failed sends this long after the channel has been closed
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Handle to sender thread paired with a socket worker
+pub struct ResponseSender {
+    sender: Sender<(CanonicalSocketAddr, Response)>,
+    bytes_sent: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl ResponseSender {
+    /// Spawn sender thread that sends responses through `socket`
+    pub fn spawn(
+        config: &Config,
+        statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+        socket: UdpSocket,
+        worker_index: usize,
+    ) -> anyhow::Result<Self> {
+        let (sender, receiver) = bounded(config.network.sender_channel_capacity);
+        let bytes_sent = Arc::new(AtomicUsize::new(0));
+
+        let mut worker = SenderWorker {
+            config: config.clone(),
+            statistics,
+            socket,
+            receiver,
+            bytes_sent: bytes_sent.clone(),
+            pending: VecDeque::new(),
+            buffer: [0; BUFFER_SIZE],
+        };
+
+        let handle = Builder::new()
+            .name(format!("sender-{:02}", worker_index + 1))
+            .spawn(move || worker.run())
+            .context("spawn sender thread")?;
+
+        Ok(Self {
+            sender,
+            bytes_sent,
+            handle,
+        })
+    }
+
+    /// Pass response on to sender thread, dropping it if channel is full
+    pub fn send(&self, addr: CanonicalSocketAddr, response: Response) {
+        match self.sender.try_send((addr, response)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                ::log::warn!("Sender thread channel full, dropping response");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                ::log::error!("Sender thread channel disconnected, dropping response");
+            }
+        }
+    }
+
+    /// Bytes sent since last call, including packet overhead
+    pub fn take_bytes_sent(&self) -> usize {
+        self.bytes_sent.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Close channel and wait for sender thread to send queued responses
+    pub fn close(self) {
+        drop(self.sender);
+
+        if self.handle.join().is_err() {
+            ::log::error!("sender thread panicked");
+        }
+    }
+}
+
+struct SenderWorker {
+    config: Config,
+    statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+    socket: UdpSocket,
+    receiver: Receiver<(CanonicalSocketAddr, Response)>,
+    bytes_sent: Arc<AtomicUsize>,
+    /// Responses that couldn't be sent because socket send buffer was full
+    pending: VecDeque<(CanonicalSocketAddr, Response)>,
+    buffer: [u8; BUFFER_SIZE],
+}
+
+impl SenderWorker {
+    fn run(&mut self) {
+        loop {
+            while let Some((addr, response)) = self.pending.pop_front() {
+                if let Some(item) = self.send_response(addr, response) {
+                    self.pending.push_front(item);
+
+                    break;
+                }
+            }
+
+            let result = if self.pending.is_empty() {
+                self.receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                self.receiver.recv_timeout(RETRY_INTERVAL)
+            };
+
+            match result {
+                Ok((addr, response)) => {
+                    // Preserve response order if earlier sends are waiting
+                    let opt_item = if self.pending.is_empty() {
+                        self.send_response(addr, response)
+                    } else {
+                        Some((addr, response))
+                    };
+
+                    if let Some(item) = opt_item {
+                        if self.pending.len() < self.config.network.sender_channel_capacity {
+                            self.pending.push_back(item);
+                        } else {
+                            ::log::warn!("Sender thread retry queue full, dropping response");
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    // All responses sent over channel have been received
+                    self.drain_pending();
+
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Retry sending pending responses until done or until timeout
+    fn drain_pending(&mut self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+
+        while let Some((addr, response)) = self.pending.pop_front() {
+            if let Some(item) = self.send_response(addr, response) {
+                if Instant::now() >= deadline {
+                    ::log::warn!(
+                        "Sender thread stopping, dropping {} responses",
+                        self.pending.len() + 1
+                    );
+
+                    return;
+                }
+
+                self.pending.push_front(item);
+
+                sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+
+    /// Returns response if sending should be retried later
+    fn send_response(
+        &mut self,
+        canonical_addr: CanonicalSocketAddr,
+        response: Response,
+    ) -> Option<(CanonicalSocketAddr, Response)> {
+        let addr = destination_addr(&self.config, canonical_addr);
+
+        let mut buffer = Cursor::new(&mut self.buffer[..]);
+
+        if let Err(err) = response.write_bytes(&mut buffer) {
+            ::log::error!("failed writing response to buffer: {:#}", err);
+
+            return None;
+        }
+
+        let bytes_written = buffer.position() as usize;
+
+        match self
+            .socket
+            .send_to(&buffer.into_inner()[..bytes_written], addr)
+        {
+            Ok(bytes_sent) => {
+                if self.config.network.egress_budget_mbits != 0 {
+                    let extra_bytes = if canonical_addr.is_ipv4() {
+                        EXTRA_PACKET_SIZE_IPV4
+                    } else {
+                        EXTRA_PACKET_SIZE_IPV6
+                    };
+
+                    self.bytes_sent
+                        .fetch_add(bytes_sent + extra_bytes, Ordering::Relaxed);
+                }
+
+                count_sent_response(
+                    &self.config,
+                    &self.statistics,
+                    canonical_addr,
+                    bytes_sent,
+                    &response,
+                );
+
+                None
+            }
+            Err(err) if send_buffer_full(&err) => {
+                ::log::debug!("Sending response to {} failed, retrying: {:#}", addr, err);
+
+                Some((canonical_addr, response))
+            }
+            Err(err) => {
+                ::log::warn!("Sending response to {} failed: {:#}", addr, err);
+
+                None
+            }
+        }
+    }
+}
@@ -7,10 +7,11 @@ use std::collections::VecDeque;
 use std::net::UdpSocket;
 use std::ops::DerefMut;
 use std::os::fd::AsRawFd;
-use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
+use aquatic_common::ip_blocklist::IpBlocklistCache;
 use crossbeam_channel::Sender;
 use io_uring::opcode::Timeout;
 use io_uring::types::{Fixed, Timespec};
@@ -21,18 +22,26 @@ use aquatic_common::{
     ValidUntil,
 };
 use aquatic_udp_protocol::*;
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
 
 use crate::common::*;
 use crate::config::Config;
+use crate::swarm::AnnounceResponseCache;
 
 use self::buf_ring::BufRing;
 use self::recv_helper::RecvHelper;
 use self::send_buffers::{ResponseType, SendBuffers};
 
+use super::amplification_guard::AmplificationGuard;
+use super::audit::PeerSelectionAuditor;
+use super::connect_filter::ConnectFilter;
+use super::egress_budget::EgressBudget;
+use super::handle_country_policy;
 use super::validator::ConnectionValidator;
-use super::{create_socket, EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
+use super::{
+    apply_amplification_guard, create_opt_ip_blocklist_cache, create_socket, handle_port_zero,
+    is_blocked, record_invalid_length, record_unique_source, sample_for_reachability_probe,
+    EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6,
+};
 
 /// Size of each request buffer
 ///
@@ -79,7 +88,12 @@ pub struct SocketWorker {
     statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
     statistics_sender: Sender<StatisticsMessage>,
     access_list_cache: AccessListCache,
+    opt_ip_blocklist_cache: Option<IpBlocklistCache>,
     validator: ConnectionValidator,
+    connect_filter: ConnectFilter,
+    amplification_guard: Option<AmplificationGuard>,
+    egress_budget: Option<EgressBudget>,
+    announce_response_cache: AnnounceResponseCache,
     #[allow(dead_code)]
     socket: UdpSocket,
     buf_ring: BufRing,
@@ -90,7 +104,8 @@ pub struct SocketWorker {
     recv_sqe: io_uring::squeue::Entry,
     pulse_timeout_sqe: io_uring::squeue::Entry,
     peer_valid_until: ValidUntil,
-    rng: SmallRng,
+    rng: SwarmRng,
+    opt_peer_selection_auditor: Option<PeerSelectionAuditor>,
 }
 
 impl SocketWorker {
@@ -108,6 +123,7 @@ impl SocketWorker {
 
         let socket = create_socket(&config, priv_dropper).expect("create socket");
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let opt_ip_blocklist_cache = create_opt_ip_blocklist_cache(&config, &shared_state);
 
         let send_buffers = SendBuffers::new(&config, send_buffer_entries as usize);
         let recv_helper = RecvHelper::new(&config);
@@ -151,13 +167,25 @@ impl SocketWorker {
             config.cleaning.max_peer_age,
         );
 
+        let connect_filter = ConnectFilter::new(&config);
+        let amplification_guard = AmplificationGuard::new(&config);
+        let egress_budget = EgressBudget::new(&config);
+        let announce_response_cache = AnnounceResponseCache::new(&config);
+        let rng = SwarmRng::from_entropy(&config);
+        let opt_peer_selection_auditor = PeerSelectionAuditor::new(&config)?;
+
         let mut worker = Self {
             config,
             shared_state,
             statistics,
             statistics_sender,
             validator,
+            connect_filter,
+            amplification_guard,
+            egress_budget,
+            announce_response_cache,
             access_list_cache,
+            opt_ip_blocklist_cache,
             send_buffers,
             recv_helper,
             local_responses: Default::default(),
@@ -167,7 +195,8 @@ impl SocketWorker {
             resubmittable_sqe_buf,
             socket,
             peer_valid_until,
-            rng: SmallRng::from_entropy(),
+            rng,
+            opt_peer_selection_auditor,
         };
 
         CurrentRing::with(|ring| worker.run_inner(ring));
@@ -176,51 +205,112 @@ impl SocketWorker {
     }
 
     fn run_inner(&mut self, ring: &mut IoUring) {
-        loop {
+        while !self.shared_state.shutdown.is_triggered() {
             for sqe in self.resubmittable_sqe_buf.drain(..) {
                 unsafe { ring.submission().push(&sqe).unwrap() };
             }
 
-            let sq_space = {
-                let sq = ring.submission();
+            if let Some(egress_budget) = self.egress_budget.as_mut() {
+                egress_budget.update(Instant::now());
+            }
 
-                sq.capacity() - sq.len()
-            };
+            let num_send_added = self.enqueue_responses(ring);
 
-            let mut num_send_added = 0;
+            // Wait for all sendmsg entries to complete. If none were added,
+            // wait for at least one recvmsg or timeout in order to avoid
+            // busy-polling if there is no incoming data.
+            ring.submitter()
+                .submit_and_wait(num_send_added.max(1))
+                .unwrap();
 
-            // Enqueue local responses
-            for _ in 0..sq_space {
-                if let Some((addr, response)) = self.local_responses.pop_front() {
-                    match self.send_buffers.prepare_entry(response, addr) {
-                        Ok(entry) => {
-                            unsafe { ring.submission().push(&entry).unwrap() };
+            for cqe in ring.completion() {
+                self.handle_cqe(cqe);
+            }
 
-                            num_send_added += 1;
-                        }
-                        Err(send_buffers::Error::NoBuffers(response)) => {
-                            self.local_responses.push_front((addr, response));
+            self.send_buffers.reset_likely_next_free_index();
+        }
 
-                            break;
-                        }
-                        Err(send_buffers::Error::SerializationFailed(err)) => {
-                            ::log::error!("Failed serializing response: {:#}", err);
+        self.flush_responses(ring);
+    }
+
+    /// Enqueue local responses, followed by deferred responses while the
+    /// egress budget allows it. Since sends only complete after this, bytes
+    /// are counted towards the budget when enqueued.
+    ///
+    /// Returns number of sendmsg entries added.
+    fn enqueue_responses(&mut self, ring: &mut IoUring) -> usize {
+        let sq_space = {
+            let sq = ring.submission();
+
+            sq.capacity() - sq.len()
+        };
+
+        let mut num_send_added = 0;
+
+        for _ in 0..sq_space {
+            let opt_response = self.local_responses.pop_front().or_else(|| {
+                self.egress_budget
+                    .as_mut()
+                    .and_then(|egress_budget| egress_budget.pop_deferred())
+            });
+
+            if let Some((addr, response)) = opt_response {
+                let bytes = response.serialized_len()
+                    + if addr.is_ipv4() {
+                        EXTRA_PACKET_SIZE_IPV4
+                    } else {
+                        EXTRA_PACKET_SIZE_IPV6
+                    };
+
+                match self.send_buffers.prepare_entry(response, addr) {
+                    Ok(entry) => {
+                        unsafe { ring.submission().push(&entry).unwrap() };
+
+                        num_send_added += 1;
+
+                        if let Some(egress_budget) = self.egress_budget.as_mut() {
+                            egress_budget.add_bytes_sent(bytes);
                         }
                     }
-                } else {
-                    break;
+                    Err(send_buffers::Error::NoBuffers(response)) => {
+                        self.local_responses.push_front((addr, response));
+
+                        break;
+                    }
+                    Err(send_buffers::Error::SerializationFailed(err)) => {
+                        ::log::error!("Failed serializing response: {:#}", err);
+                    }
                 }
+            } else {
+                break;
             }
+        }
 
-            // Wait for all sendmsg entries to complete. If none were added,
-            // wait for at least one recvmsg or timeout in order to avoid
-            // busy-polling if there is no incoming data.
+        num_send_added
+    }
+
+    /// Send remaining local responses and all deferred responses, regardless
+    /// of egress budget, and wait for all sends to complete. Requests
+    /// received in the meantime are ignored.
+    fn flush_responses(&mut self, ring: &mut IoUring) {
+        if let Some(egress_budget) = self.egress_budget.as_mut() {
+            self.local_responses.extend(egress_budget.take_deferred());
+        }
+
+        while !(self.local_responses.is_empty() && self.send_buffers.all_free()) {
+            let num_send_added = self.enqueue_responses(ring);
+
+            // If no entries could be added, wait for in-flight sends to
+            // complete and free their buffers
             ring.submitter()
                 .submit_and_wait(num_send_added.max(1))
                 .unwrap();
 
             for cqe in ring.completion() {
-                self.handle_cqe(cqe);
+                match cqe.user_data() {
+                    USER_DATA_RECV | USER_DATA_PULSE_TIMEOUT => (),
+                    _ => self.handle_cqe(cqe),
+                }
             }
 
             self.send_buffers.reset_likely_next_free_index();
@@ -231,7 +321,13 @@ impl SocketWorker {
         match cqe.user_data() {
             USER_DATA_RECV => {
                 if let Some((addr, response)) = self.handle_recv_cqe(&cqe) {
-                    self.local_responses.push_back((addr, response));
+                    if let Some(egress_budget) = self.egress_budget.as_mut() {
+                        if let Some(response) = egress_budget.apply(addr, response) {
+                            self.local_responses.push_back(response);
+                        }
+                    } else {
+                        self.local_responses.push_back((addr, response));
+                    }
                 }
 
                 if !io_uring::cqueue::more(cqe.flags()) {
@@ -271,7 +367,7 @@ impl SocketWorker {
 
                     statistics
                         .bytes_sent
-                        .fetch_add(result as usize + extra_bytes, Ordering::Relaxed);
+                        .increment(result as usize + extra_bytes);
 
                     let response_counter = match response_type {
                         ResponseType::Connect => &statistics.responses_connect,
@@ -280,7 +376,7 @@ impl SocketWorker {
                         ResponseType::Error => &statistics.responses_error,
                     };
 
-                    response_counter.fetch_add(1, Ordering::Relaxed);
+                    response_counter.increment(1);
                 }
 
                 // Safety: OK because cqe using buffer has been returned and
@@ -329,7 +425,7 @@ impl SocketWorker {
         };
 
         match self.recv_helper.parse(buffer.as_slice()) {
-            Ok((request, addr)) => {
+            Ok((request, addr, payload_len)) => {
                 if self.config.statistics.active() {
                     let (statistics, extra_bytes) = if addr.is_ipv4() {
                         (&self.statistics.ipv4, EXTRA_PACKET_SIZE_IPV4)
@@ -339,11 +435,50 @@ impl SocketWorker {
 
                     statistics
                         .bytes_received
-                        .fetch_add(buffer.len() + extra_bytes, Ordering::Relaxed);
-                    statistics.requests.fetch_add(1, Ordering::Relaxed);
+                        .increment(buffer.len() + extra_bytes);
+                    statistics.requests.increment(1);
+
+                    record_unique_source(&self.config, &self.shared_state, statistics, addr);
+                }
+
+                if is_blocked(
+                    &self.config,
+                    &self.statistics,
+                    &mut self.opt_ip_blocklist_cache,
+                    addr,
+                ) {
+                    return None;
+                }
+
+                if let Some(amplification_guard) = self.amplification_guard.as_mut() {
+                    amplification_guard.add_received(addr, payload_len);
                 }
 
-                return self.handle_request(request, addr);
+                if let Request::Connect(_) = request {
+                    if !self.connect_filter.allow(addr, payload_len) {
+                        if self.config.statistics.active() {
+                            let statistics = if addr.is_ipv4() {
+                                &self.statistics.ipv4
+                            } else {
+                                &self.statistics.ipv6
+                            };
+
+                            statistics.connect_requests_throttled.increment(1);
+                        }
+
+                        return None;
+                    }
+                }
+
+                let (addr, response) = self.handle_request(request, addr)?;
+
+                return apply_amplification_guard(
+                    &self.config,
+                    &self.statistics,
+                    &mut self.amplification_guard,
+                    addr,
+                    response,
+                );
             }
             Err(self::recv_helper::Error::RequestParseError(err, addr)) => {
                 if self.config.statistics.active() {
@@ -351,15 +486,28 @@ impl SocketWorker {
                         self.statistics
                             .ipv4
                             .bytes_received
-                            .fetch_add(buffer.len() + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+                            .increment(buffer.len() + EXTRA_PACKET_SIZE_IPV4);
                     } else {
                         self.statistics
                             .ipv6
                             .bytes_received
-                            .fetch_add(buffer.len() + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+                            .increment(buffer.len() + EXTRA_PACKET_SIZE_IPV6);
                     }
                 }
 
+                if is_blocked(
+                    &self.config,
+                    &self.statistics,
+                    &mut self.opt_ip_blocklist_cache,
+                    addr,
+                ) {
+                    return None;
+                }
+
+                if let Some(amplification_guard) = self.amplification_guard.as_mut() {
+                    amplification_guard.add_received(addr, buffer.len());
+                }
+
                 match err {
                     RequestParseError::Sendable {
                         connection_id,
@@ -374,7 +522,13 @@ impl SocketWorker {
                                 message: err.into(),
                             };
 
-                            return Some((addr, Response::Error(response)));
+                            return apply_amplification_guard(
+                                &self.config,
+                                &self.statistics,
+                                &mut self.amplification_guard,
+                                addr,
+                                Response::Error(response),
+                            );
                         }
                     }
                     RequestParseError::Unsendable { err } => {
@@ -382,6 +536,30 @@ impl SocketWorker {
                     }
                 }
             }
+            Err(self::recv_helper::Error::InvalidLength(addr)) => {
+                if self.config.statistics.active() {
+                    if addr.is_ipv4() {
+                        self.statistics
+                            .ipv4
+                            .bytes_received
+                            .increment(buffer.len() + EXTRA_PACKET_SIZE_IPV4);
+                    } else {
+                        self.statistics
+                            .ipv6
+                            .bytes_received
+                            .increment(buffer.len() + EXTRA_PACKET_SIZE_IPV6);
+                    }
+                }
+
+                if !is_blocked(
+                    &self.config,
+                    &self.statistics,
+                    &mut self.opt_ip_blocklist_cache,
+                    addr,
+                ) {
+                    record_invalid_length(&self.config, &self.statistics, addr);
+                }
+            }
             Err(self::recv_helper::Error::InvalidSocketAddress) => {
                 ::log::debug!("Ignored request claiming to be from port 0");
             }
@@ -417,6 +595,18 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
+                    if let Some(response) =
+                        handle_port_zero(&self.config, &self.statistics, &request, src)
+                    {
+                        return Some((src, response));
+                    }
+
+                    if let Some(response) =
+                        handle_country_policy(&self.config, &self.shared_state, &request, src)
+                    {
+                        return Some((src, response));
+                    }
+
                     if self
                         .access_list_cache
                         .load()
@@ -424,13 +614,21 @@ impl SocketWorker {
                     {
                         let response = self.shared_state.torrent_maps.announce(
                             &self.config,
+                            &self.statistics,
                             &self.statistics_sender,
                             &mut self.rng,
                             &request,
                             src,
                             self.peer_valid_until,
+                            &mut self.announce_response_cache,
                         );
 
+                        sample_for_reachability_probe(&self.shared_state, &request, src);
+
+                        if let Some(auditor) = self.opt_peer_selection_auditor.as_mut() {
+                            auditor.record(&request, src, &response);
+                        }
+
                         return Some((src, response));
                     } else {
                         let response = Response::Error(ErrorResponse {
@@ -447,8 +645,11 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
-                    let response =
-                        Response::Scrape(self.shared_state.torrent_maps.scrape(request, src));
+                    let response = Response::Scrape(self.shared_state.torrent_maps.scrape(
+                        &self.config,
+                        request,
+                        src,
+                    ));
 
                     return Some((src, response));
                 }
@@ -8,6 +8,7 @@ use aquatic_udp_protocol::{Request, RequestParseError};
 use io_uring::{opcode::RecvMsgMulti, types::RecvMsgOut};
 
 use crate::config::Config;
+use crate::workers::socket::strip_connect_padding;
 
 use super::{SOCKET_IDENTIFIER, USER_DATA_RECV};
 
@@ -16,12 +17,14 @@ pub enum Error {
     RecvMsgParseError,
     RecvMsgTruncated,
     RequestParseError(RequestParseError, CanonicalSocketAddr),
+    InvalidLength(CanonicalSocketAddr),
     InvalidSocketAddress,
 }
 
 pub struct RecvHelper {
     socket_is_ipv4: bool,
-    max_scrape_torrents: u8,
+    max_scrape_torrents: usize,
+    min_connect_request_size: usize,
     #[allow(dead_code)]
     name_v4: *const libc::sockaddr_in,
     msghdr_v4: *const libc::msghdr,
@@ -65,7 +68,8 @@ impl RecvHelper {
 
         Self {
             socket_is_ipv4: config.network.address.is_ipv4(),
-            max_scrape_torrents: config.protocol.max_scrape_torrents,
+            max_scrape_torrents: config.protocol.scrape_torrents_limit(),
+            min_connect_request_size: config.protocol.min_connect_request_size,
             name_v4,
             msghdr_v4,
             name_v6,
@@ -85,7 +89,8 @@ impl RecvHelper {
             .user_data(USER_DATA_RECV)
     }
 
-    pub fn parse(&self, buffer: &[u8]) -> Result<(Request, CanonicalSocketAddr), Error> {
+    /// Returns request, source address and payload length on success
+    pub fn parse(&self, buffer: &[u8]) -> Result<(Request, CanonicalSocketAddr, usize), Error> {
         let (msg, addr) = if self.socket_is_ipv4 {
             // Safe as long as kernel only reads from the pointer and doesn't
             // write to it. I think this is the case.
@@ -134,9 +139,16 @@ impl RecvHelper {
 
         let addr = CanonicalSocketAddr::new(addr);
 
-        let request = Request::parse_bytes(msg.payload_data(), self.max_scrape_torrents)
+        let payload_len = msg.payload_data().len();
+        let payload = strip_connect_padding(self.min_connect_request_size, msg.payload_data());
+
+        if !Request::length_valid(payload) {
+            return Err(Error::InvalidLength(addr));
+        }
+
+        let request = Request::parse_bytes(payload, self.max_scrape_torrents)
             .map_err(|err| Error::RequestParseError(err, addr))?;
 
-        Ok((request, addr))
+        Ok((request, addr, payload_len))
     }
 }
@@ -54,6 +54,11 @@ impl SendBuffers {
         self.buffers[index].0.free = true;
     }
 
+    /// Returns true if no buffer is referenced by in-flight sends
+    pub fn all_free(&self) -> bool {
+        self.buffers.iter().all(|(meta, _)| meta.free)
+    }
+
     /// Call after going through completion queue
     pub fn reset_likely_next_free_index(&mut self) {
         self.likely_next_free_index = 0;
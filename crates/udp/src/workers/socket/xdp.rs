@@ -0,0 +1,714 @@
+//! Experimental AF_XDP receive path
+//!
+//! Requests are read from an AF_XDP socket bound to a single network device
+//! queue, bypassing the kernel network stack. Responses are sent with a
+//! regular UDP socket, using the mio backend logic.
+//!
+//! An XDP program redirecting tracker traffic to the AF_XDP sockets must be
+//! loaded separately. It is expected to redirect packets into an XSKMAP
+//! (keyed by queue id) that is pinned at `network.af_xdp_xsks_map_path`.
+//! Packets not redirected by the program (e.g., because no AF_XDP socket is
+//! bound to the queue they arrive on) are read from the UDP socket as usual.
+
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::{privileges::PrivilegeDropper, CanonicalSocketAddr};
+use aquatic_udp_protocol::Response;
+use crossbeam_channel::Sender;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::mio::SocketWorker as MioSocketWorker;
+use super::validator::ConnectionValidator;
+use super::BusyPoll;
+
+const FRAME_SIZE: u32 = 2048;
+const NUM_FRAMES: u32 = 4096;
+const FILL_RING_SIZE: u32 = NUM_FRAMES;
+const COMPLETION_RING_SIZE: u32 = 64;
+
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+pub struct SocketWorker {
+    inner: MioSocketWorker,
+    xsk: XskSocket,
+    port: u16,
+    ipv4_active: bool,
+    ipv6_active: bool,
+}
+
+impl SocketWorker {
+    #[allow(clippy::too_many_arguments)]
+    pub fn run(
+        config: Config,
+        shared_state: State,
+        statistics: CachePaddedArc<IpVersionStatistics<SocketWorkerStatistics>>,
+        statistics_sender: Sender<StatisticsMessage>,
+        validator: ConnectionValidator,
+        priv_dropper: PrivilegeDropper,
+        worker_index: usize,
+    ) -> anyhow::Result<()> {
+        let queue_id = config.network.af_xdp_first_queue_id + worker_index as u32;
+
+        // Set up AF_XDP socket before privileges are dropped in UDP socket
+        // creation
+        let xsk = XskSocket::new(
+            &config.network.af_xdp_interface,
+            queue_id,
+            config.network.af_xdp_rx_ring_size,
+        )
+        .with_context(|| format!("create AF_XDP socket for queue {}", queue_id))?;
+
+        register_in_xsks_map(&config.network.af_xdp_xsks_map_path, queue_id, &xsk)
+            .context("register AF_XDP socket in XSKMAP")?;
+
+        let port = config.network.address.port();
+        let ipv4_active = config.network.ipv4_active();
+        let ipv6_active = config.network.ipv6_active();
+
+        let inner = MioSocketWorker::new(
+            config,
+            shared_state,
+            statistics,
+            statistics_sender,
+            validator,
+            priv_dropper,
+            worker_index,
+        )?;
+
+        let mut worker = Self {
+            inner,
+            xsk,
+            port,
+            ipv4_active,
+            ipv6_active,
+        };
+
+        worker.run_inner()
+    }
+
+    fn run_inner(&mut self) -> anyhow::Result<()> {
+        let config = self.inner.config().clone();
+
+        let mut opt_resend_buffer =
+            (config.network.resend_buffer_max_len > 0).then_some(Vec::new());
+        let mut frames_to_recycle = Vec::with_capacity(config.network.af_xdp_rx_ring_size as usize);
+
+        let poll_timeout = Duration::from_millis(config.network.poll_timeout_ms);
+        let mut busy_poll = BusyPoll::new(&config);
+
+        let mut iter_counter = 0u64;
+
+        while !self.inner.shared_state().shutdown.is_triggered() {
+            let mut poll_fds = [
+                libc::pollfd {
+                    fd: self.xsk.fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.inner.socket().as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            let poll_timeout_ms = busy_poll
+                .timeout(poll_timeout)
+                .as_millis()
+                .min(i32::MAX as u128) as i32;
+
+            let result = unsafe { libc::poll(poll_fds.as_mut_ptr(), 2, poll_timeout_ms) };
+
+            busy_poll.register_poll_result(result > 0);
+
+            if result < 0 {
+                let err = io::Error::last_os_error();
+
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err).context("poll");
+                }
+            }
+
+            if poll_fds[0].revents & libc::POLLIN != 0 {
+                self.read_and_handle_xsk_requests(&mut opt_resend_buffer, &mut frames_to_recycle);
+            }
+            if poll_fds[1].revents & libc::POLLIN != 0 {
+                self.inner.read_and_handle_requests(&mut opt_resend_buffer);
+            }
+
+            self.inner.update_egress_budget(&mut opt_resend_buffer);
+            self.inner.flush_resend_buffer(&mut opt_resend_buffer);
+
+            if iter_counter % 256 == 0 {
+                self.inner.update_timestamps();
+                self.inner.check_response_sender()?;
+            }
+
+            iter_counter = iter_counter.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+
+    fn read_and_handle_xsk_requests(
+        &mut self,
+        opt_resend_buffer: &mut Option<Vec<(CanonicalSocketAddr, Response)>>,
+        frames_to_recycle: &mut Vec<u64>,
+    ) {
+        let rx = &mut self.xsk.rx;
+
+        let producer = rx.producer().load(Ordering::Acquire);
+        let mut consumer = rx.consumer().load(Ordering::Relaxed);
+
+        while consumer != producer {
+            let desc = unsafe { rx.entry(consumer).read() };
+
+            consumer = consumer.wrapping_add(1);
+
+            frames_to_recycle.push(desc.addr - (desc.addr % u64::from(FRAME_SIZE)));
+
+            let frame = unsafe { self.xsk.umem.slice(desc.addr, desc.len) };
+
+            match parse_udp_packet(frame, self.port) {
+                Some((src, payload))
+                    if payload.len() <= BUFFER_SIZE
+                        && (if src.is_ipv4() {
+                            self.ipv4_active
+                        } else {
+                            self.ipv6_active
+                        }) =>
+                {
+                    let payload_len = payload.len();
+
+                    self.inner.buffer_mut()[..payload_len].copy_from_slice(payload);
+                    self.inner
+                        .handle_datagram(opt_resend_buffer, payload_len, src);
+                }
+                _ => {
+                    ::log::debug!("ignored packet received on AF_XDP socket");
+                }
+            }
+        }
+
+        rx.consumer().store(consumer, Ordering::Release);
+
+        #[cfg(feature = "mmsg")]
+        self.inner.flush_send_batch(opt_resend_buffer);
+
+        self.xsk.fill_frames(frames_to_recycle);
+    }
+}
+
+/// Parse ethernet frame, returning source address and payload if it contains
+/// an unfragmented UDP packet with destination port `port`
+fn parse_udp_packet(frame: &[u8], port: u16) -> Option<(SocketAddr, &[u8])> {
+    let mut ether_type = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+    let mut offset = 14;
+
+    // Skip 802.1Q VLAN tag
+    if ether_type == 0x8100 {
+        ether_type = u16::from_be_bytes(frame.get(16..18)?.try_into().ok()?);
+        offset += 4;
+    }
+
+    let (src_ip, udp_offset) = match ether_type {
+        0x0800 => {
+            let header = frame.get(offset..offset + 20)?;
+
+            if header[0] >> 4 != 4 || header[9] != libc::IPPROTO_UDP as u8 {
+                return None;
+            }
+            // Ignore fragments (more fragments flag or non-zero offset set)
+            if u16::from_be_bytes([header[6], header[7]]) & 0x3fff != 0 {
+                return None;
+            }
+
+            let header_len = usize::from(header[0] & 0x0f) * 4;
+
+            // Header is at least 20 bytes long, and options must fit in frame
+            if header_len < 20 || frame.len() < offset + header_len {
+                return None;
+            }
+
+            let ip = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+
+            (IpAddr::V4(ip), offset + header_len)
+        }
+        0x86dd => {
+            let header = frame.get(offset..offset + 40)?;
+
+            // Extension headers are not supported
+            if header[6] != libc::IPPROTO_UDP as u8 {
+                return None;
+            }
+
+            let ip: [u8; 16] = header[8..24].try_into().ok()?;
+
+            (IpAddr::V6(Ipv6Addr::from(ip)), offset + 40)
+        }
+        _ => return None,
+    };
+
+    let udp_header = frame.get(udp_offset..udp_offset + 8)?;
+
+    let src_port = u16::from_be_bytes([udp_header[0], udp_header[1]]);
+    let dst_port = u16::from_be_bytes([udp_header[2], udp_header[3]]);
+    let udp_len = usize::from(u16::from_be_bytes([udp_header[4], udp_header[5]]));
+
+    if dst_port != port || udp_len < 8 {
+        return None;
+    }
+
+    let payload = frame.get(udp_offset + 8..udp_offset + udp_len)?;
+
+    Some((SocketAddr::new(src_ip, src_port), payload))
+}
+
+fn register_in_xsks_map(
+    path: &std::path::Path,
+    queue_id: u32,
+    xsk: &XskSocket,
+) -> anyhow::Result<()> {
+    #[repr(C)]
+    struct ObjGetAttr {
+        pathname: u64,
+        bpf_fd: u32,
+        file_flags: u32,
+    }
+
+    #[repr(C)]
+    struct MapUpdateElemAttr {
+        map_fd: u32,
+        _pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+
+    let path = CString::new(path.as_os_str().as_bytes()).context("invalid XSKMAP path")?;
+
+    let attr = ObjGetAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: 0,
+        file_flags: 0,
+    };
+
+    let map_fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_OBJ_GET,
+            &attr as *const ObjGetAttr,
+            size_of::<ObjGetAttr>(),
+        )
+    };
+
+    if map_fd < 0 {
+        return Err(io::Error::last_os_error()).context("open pinned XSKMAP");
+    }
+
+    let map_fd = unsafe { OwnedFd::from_raw_fd(map_fd as i32) };
+    let socket_fd = xsk.fd.as_raw_fd();
+
+    let attr = MapUpdateElemAttr {
+        map_fd: map_fd.as_raw_fd() as u32,
+        _pad: 0,
+        key: &queue_id as *const u32 as u64,
+        value: &socket_fd as *const i32 as u64,
+        flags: 0,
+    };
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_UPDATE_ELEM,
+            &attr as *const MapUpdateElemAttr,
+            size_of::<MapUpdateElemAttr>(),
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error()).context("update XSKMAP");
+    }
+
+    Ok(())
+}
+
+struct XskSocket {
+    // Rings must be unmapped before socket is closed, so fd is declared
+    // last to be dropped last
+    rx: Ring<libc::xdp_desc>,
+    fill: Ring<u64>,
+    _completion: Ring<u64>,
+    umem: Umem,
+    fd: OwnedFd,
+}
+
+impl XskSocket {
+    fn new(interface: &str, queue_id: u32, rx_ring_size: u32) -> anyhow::Result<Self> {
+        if !rx_ring_size.is_power_of_two() {
+            return Err(anyhow::anyhow!(
+                "AF_XDP rx ring size must be a power of two"
+            ));
+        }
+
+        let interface_c = CString::new(interface).context("invalid interface name")?;
+        let ifindex = unsafe { libc::if_nametoindex(interface_c.as_ptr()) };
+
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("look up interface {}", interface));
+        }
+
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("create socket");
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let umem = Umem::new()?;
+
+        let reg = libc::xdp_umem_reg {
+            addr: umem.ptr.as_ptr() as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE,
+            headroom: 0,
+            flags: 0,
+        };
+
+        setsockopt(&fd, libc::XDP_UMEM_REG, &reg).context("register UMEM")?;
+        setsockopt(&fd, libc::XDP_UMEM_FILL_RING, &FILL_RING_SIZE).context("set fill ring size")?;
+        setsockopt(&fd, libc::XDP_UMEM_COMPLETION_RING, &COMPLETION_RING_SIZE)
+            .context("set completion ring size")?;
+        setsockopt(&fd, libc::XDP_RX_RING, &rx_ring_size).context("set rx ring size")?;
+
+        let mut offsets: libc::xdp_mmap_offsets = unsafe { std::mem::zeroed() };
+        let mut offsets_len = size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_XDP,
+                libc::XDP_MMAP_OFFSETS,
+                &mut offsets as *mut libc::xdp_mmap_offsets as *mut libc::c_void,
+                &mut offsets_len,
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("get ring mmap offsets");
+        }
+
+        let fill = Ring::map(
+            &fd,
+            &offsets.fr,
+            FILL_RING_SIZE,
+            libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+        )
+        .context("map fill ring")?;
+        let completion = Ring::map(
+            &fd,
+            &offsets.cr,
+            COMPLETION_RING_SIZE,
+            libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+        )
+        .context("map completion ring")?;
+        let rx = Ring::map(&fd, &offsets.rx, rx_ring_size, libc::XDP_PGOFF_RX_RING)
+            .context("map rx ring")?;
+
+        let addr = libc::sockaddr_xdp {
+            sxdp_family: libc::AF_XDP as u16,
+            sxdp_flags: 0,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue_id,
+            sxdp_shared_umem_fd: 0,
+        };
+
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_xdp as *const libc::sockaddr,
+                size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("bind socket");
+        }
+
+        let mut socket = Self {
+            rx,
+            fill,
+            _completion: completion,
+            umem,
+            fd,
+        };
+
+        let mut frames = (0..NUM_FRAMES)
+            .map(|i| u64::from(i * FRAME_SIZE))
+            .collect::<Vec<_>>();
+
+        socket.fill_frames(&mut frames);
+
+        Ok(socket)
+    }
+
+    /// Hand over frames to kernel for receiving packets into
+    fn fill_frames(&mut self, frames: &mut Vec<u64>) {
+        if frames.is_empty() {
+            return;
+        }
+
+        let producer = self.fill.producer().load(Ordering::Relaxed);
+        let num_frames = frames.len() as u32;
+
+        for (i, frame) in frames.drain(..).enumerate() {
+            unsafe {
+                self.fill
+                    .entry(producer.wrapping_add(i as u32))
+                    .write(frame);
+            }
+        }
+
+        self.fill
+            .producer()
+            .store(producer.wrapping_add(num_frames), Ordering::Release);
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Memory area that frames are received into
+struct Umem {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Umem {
+    fn new() -> anyhow::Result<Self> {
+        let len = (NUM_FRAMES * FRAME_SIZE) as usize;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).context("allocate UMEM");
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            len,
+        })
+    }
+
+    /// Safety: frame must not be owned by kernel
+    unsafe fn slice(&self, addr: u64, len: u32) -> &[u8] {
+        let start = (addr as usize).min(self.len);
+        let len = (len as usize).min(self.len - start);
+
+        std::slice::from_raw_parts(self.ptr.as_ptr().add(start), len)
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Single producer, single consumer ring shared with kernel
+struct Ring<T> {
+    map: NonNull<u8>,
+    map_len: usize,
+    producer: NonNull<AtomicU32>,
+    consumer: NonNull<AtomicU32>,
+    entries: NonNull<T>,
+    mask: u32,
+}
+
+impl<T> Ring<T> {
+    fn map(
+        fd: &OwnedFd,
+        offsets: &libc::xdp_ring_offset,
+        size: u32,
+        page_offset: libc::off_t,
+    ) -> anyhow::Result<Self> {
+        let map_len = offsets.desc as usize + size as usize * size_of::<T>();
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                page_offset,
+            )
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).context("mmap");
+        }
+
+        let map = map as *mut u8;
+
+        unsafe {
+            Ok(Self {
+                map: NonNull::new_unchecked(map),
+                map_len,
+                producer: NonNull::new_unchecked(map.add(offsets.producer as usize).cast()),
+                consumer: NonNull::new_unchecked(map.add(offsets.consumer as usize).cast()),
+                entries: NonNull::new_unchecked(map.add(offsets.desc as usize).cast()),
+                mask: size - 1,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { self.producer.as_ref() }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { self.consumer.as_ref() }
+    }
+
+    /// Safety: caller must own entry according to ring protocol
+    unsafe fn entry(&mut self, index: u32) -> *mut T {
+        self.entries.as_ptr().add((index & self.mask) as usize)
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map.as_ptr() as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_payload_offset(ip_header_len: usize) -> usize {
+        14 + ip_header_len + 8
+    }
+
+    fn ipv4_frame(dst_port: u16, flags_and_offset: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 14];
+
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+        let mut ip = [0u8; 20];
+
+        ip[0] = 0x45;
+        ip[6..8].copy_from_slice(&flags_and_offset.to_be_bytes());
+        ip[9] = libc::IPPROTO_UDP as u8;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&1234u16.to_be_bytes());
+        frame.extend_from_slice(&dst_port.to_be_bytes());
+        frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+
+        frame
+    }
+
+    #[test]
+    fn test_parse_ipv4_udp_packet() {
+        let frame = ipv4_frame(3000, 0, b"abc");
+
+        let (src, payload) = parse_udp_packet(&frame, 3000).unwrap();
+
+        assert_eq!(src, SocketAddr::from(([10, 0, 0, 1], 1234)));
+        assert_eq!(payload, &frame[udp_payload_offset(20)..]);
+
+        assert!(parse_udp_packet(&frame, 3001).is_none());
+        assert!(parse_udp_packet(&ipv4_frame(3000, 0x2000, b"abc"), 3000).is_none());
+        assert!(parse_udp_packet(&frame[..frame.len() - 1], 3000).is_none());
+    }
+
+    #[test]
+    fn test_parse_ipv4_udp_packet_invalid_header_len() {
+        for ihl in 0..5 {
+            let mut frame = ipv4_frame(3000, 0, b"abc");
+
+            frame[14] = 0x40 | ihl;
+
+            assert!(parse_udp_packet(&frame, 3000).is_none());
+        }
+
+        // Header length of 60 bytes exceeds frame length
+        let mut frame = ipv4_frame(3000, 0, b"abc");
+
+        frame[14] = 0x4f;
+
+        assert!(parse_udp_packet(&frame, 3000).is_none());
+    }
+
+    #[test]
+    fn test_parse_ipv6_udp_packet() {
+        let mut frame = vec![0u8; 14];
+
+        frame[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+
+        let mut ip = [0u8; 40];
+
+        ip[0] = 0x60;
+        ip[6] = libc::IPPROTO_UDP as u8;
+        ip[23] = 1;
+
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&1234u16.to_be_bytes());
+        frame.extend_from_slice(&3000u16.to_be_bytes());
+        frame.extend_from_slice(&11u16.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(b"abc");
+
+        let (src, payload) = parse_udp_packet(&frame, 3000).unwrap();
+
+        assert_eq!(src, SocketAddr::from((Ipv6Addr::LOCALHOST, 1234)));
+        assert_eq!(payload, b"abc");
+    }
+}
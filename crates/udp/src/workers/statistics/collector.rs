@@ -1,6 +1,6 @@
-use std::sync::atomic::Ordering;
 use std::time::Instant;
 
+use aquatic_metrics::HyperLogLogSnapshot;
 use hdrhistogram::Histogram;
 use num_format::{Locale, ToFormattedString};
 use serde::Serialize;
@@ -13,7 +13,7 @@ use super::{IpVersion, Statistics};
 macro_rules! set_peer_histogram_gauge {
     ($ip_version:expr, $data:expr, $type_label:expr) => {
         ::metrics::gauge!(
-            "aquatic_peers_per_torrent",
+            aquatic_metrics::names::PEERS_PER_TORRENT,
             "type" => $type_label,
             "ip_version" => $ip_version,
         )
@@ -51,8 +51,17 @@ impl StatisticsCollector {
         let mut responses_announce: usize = 0;
         let mut responses_scrape: usize = 0;
         let mut responses_error: usize = 0;
+        let mut connect_requests_throttled: usize = 0;
+        let mut responses_amplification_dropped: usize = 0;
+        let mut announce_requests_port_zero: usize = 0;
+        let mut requests_blocked: usize = 0;
+        let mut requests_invalid_length: usize = 0;
         let mut bytes_received: usize = 0;
         let mut bytes_sent: usize = 0;
+        let mut peers_added: usize = 0;
+        let mut peers_stopped: usize = 0;
+        let mut unique_ips = HyperLogLogSnapshot::default();
+        let mut unique_asns = HyperLogLogSnapshot::default();
 
         #[cfg(feature = "prometheus")]
         let ip_version_prometheus_str = self.ip_version.prometheus_str();
@@ -65,14 +74,14 @@ impl StatisticsCollector {
             .enumerate()
         {
             {
-                let n = statistics.requests.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.requests.take();
 
                 requests += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_requests_total",
+                        aquatic_metrics::names::REQUESTS_TOTAL,
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
                     )
@@ -80,14 +89,14 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics.responses_connect.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.responses_connect.take();
 
                 responses_connect += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_responses_total",
+                        aquatic_metrics::names::RESPONSES_TOTAL,
                         "type" => "connect",
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
@@ -96,16 +105,14 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics
-                    .responses_announce
-                    .fetch_and(0, Ordering::Relaxed);
+                let n = statistics.responses_announce.take();
 
                 responses_announce += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_responses_total",
+                        aquatic_metrics::names::RESPONSES_TOTAL,
                         "type" => "announce",
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
@@ -114,14 +121,14 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics.responses_scrape.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.responses_scrape.take();
 
                 responses_scrape += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_responses_total",
+                        aquatic_metrics::names::RESPONSES_TOTAL,
                         "type" => "scrape",
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
@@ -130,14 +137,14 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics.responses_error.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.responses_error.take();
 
                 responses_error += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_responses_total",
+                        aquatic_metrics::names::RESPONSES_TOTAL,
                         "type" => "error",
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
@@ -146,14 +153,89 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics.bytes_received.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.connect_requests_throttled.take();
+
+                connect_requests_throttled += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::CONNECT_REQUESTS_THROTTLED_TOTAL,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.responses_amplification_dropped.take();
+
+                responses_amplification_dropped += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::RESPONSES_AMPLIFICATION_DROPPED_TOTAL,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.announce_requests_port_zero.take();
+
+                announce_requests_port_zero += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::ANNOUNCE_REQUESTS_PORT_ZERO_TOTAL,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.requests_blocked.take();
+
+                requests_blocked += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::REQUESTS_BLOCKED_TOTAL,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.requests_invalid_length.take();
+
+                requests_invalid_length += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::REQUESTS_INVALID_LENGTH_TOTAL,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.bytes_received.take();
 
                 bytes_received += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_rx_bytes",
+                        aquatic_metrics::names::RX_BYTES,
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
                     )
@@ -161,31 +243,85 @@ impl StatisticsCollector {
                 }
             }
             {
-                let n = statistics.bytes_sent.fetch_and(0, Ordering::Relaxed);
+                let n = statistics.bytes_sent.take();
 
                 bytes_sent += n;
 
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::counter!(
-                        "aquatic_tx_bytes",
+                        aquatic_metrics::names::TX_BYTES,
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+            {
+                let n = statistics.peers_added.take();
+
+                peers_added += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::PEERS_ADDED_TOTAL,
                         "ip_version" => ip_version_prometheus_str,
                         "worker_index" => i.to_string(),
                     )
                     .increment(n.try_into().unwrap());
                 }
             }
+            {
+                let n = statistics.peers_stopped.take();
+
+                peers_stopped += n;
+
+                #[cfg(feature = "prometheus")]
+                if config.statistics.run_prometheus_endpoint {
+                    ::metrics::counter!(
+                        aquatic_metrics::names::PEERS_REMOVED_TOTAL,
+                        "reason" => "stopped",
+                        "ip_version" => ip_version_prometheus_str,
+                        "worker_index" => i.to_string(),
+                    )
+                    .increment(n.try_into().unwrap());
+                }
+            }
+
+            unique_ips.merge(&statistics.unique_ips.take());
+            unique_asns.merge(&statistics.unique_asns.take());
+        }
+
+        let unique_ips = unique_ips.estimate();
+        let unique_asns = unique_asns.estimate();
+
+        #[cfg(feature = "prometheus")]
+        if config.statistics.run_prometheus_endpoint {
+            ::metrics::gauge!(
+                aquatic_metrics::names::UNIQUE_IPS,
+                "ip_version" => ip_version_prometheus_str,
+            )
+            .set(unique_ips as f64);
+
+            if config.geoip.asn_active() {
+                ::metrics::gauge!(
+                    aquatic_metrics::names::UNIQUE_ASNS,
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .set(unique_asns as f64);
+            }
         }
 
         let swarm_statistics = &self.statistics.swarm.by_ip_version(self.ip_version);
 
         let num_torrents = {
-            let num_torrents = swarm_statistics.torrents.load(Ordering::Relaxed);
+            let num_torrents = swarm_statistics.torrents.get();
 
             #[cfg(feature = "prometheus")]
             if config.statistics.run_prometheus_endpoint {
                 ::metrics::gauge!(
-                    "aquatic_torrents",
+                    aquatic_metrics::names::TORRENTS,
                     "ip_version" => ip_version_prometheus_str,
                 )
                 .set(num_torrents as f64);
@@ -195,12 +331,12 @@ impl StatisticsCollector {
         };
 
         let num_peers = {
-            let num_peers = swarm_statistics.peers.load(Ordering::Relaxed);
+            let num_peers = swarm_statistics.peers.get();
 
             #[cfg(feature = "prometheus")]
             if config.statistics.run_prometheus_endpoint {
                 ::metrics::gauge!(
-                    "aquatic_peers",
+                    aquatic_metrics::names::PEERS,
                     "ip_version" => ip_version_prometheus_str,
                 )
                 .set(num_peers as f64);
@@ -209,6 +345,37 @@ impl StatisticsCollector {
             num_peers
         };
 
+        let reclaimed_bytes = {
+            let reclaimed_bytes = swarm_statistics.reclaimed_bytes.get();
+
+            #[cfg(feature = "prometheus")]
+            if config.statistics.run_prometheus_endpoint {
+                ::metrics::gauge!(
+                    aquatic_metrics::names::CLEANING_RECLAIMED_BYTES,
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .set(reclaimed_bytes as f64);
+            }
+
+            reclaimed_bytes
+        };
+
+        let peers_expired = {
+            let peers_expired = swarm_statistics.peers_expired.take();
+
+            #[cfg(feature = "prometheus")]
+            if config.statistics.run_prometheus_endpoint {
+                ::metrics::counter!(
+                    aquatic_metrics::names::PEERS_REMOVED_TOTAL,
+                    "reason" => "expired",
+                    "ip_version" => ip_version_prometheus_str,
+                )
+                .increment(peers_expired.try_into().unwrap());
+            }
+
+            peers_expired
+        };
+
         let elapsed = {
             let now = Instant::now();
 
@@ -230,8 +397,17 @@ impl StatisticsCollector {
         let responses_per_second_announce = responses_announce as f64 / elapsed;
         let responses_per_second_scrape = responses_scrape as f64 / elapsed;
         let responses_per_second_error = responses_error as f64 / elapsed;
+        let connect_requests_throttled_per_second = connect_requests_throttled as f64 / elapsed;
+        let responses_amplification_dropped_per_second =
+            responses_amplification_dropped as f64 / elapsed;
+        let announce_requests_port_zero_per_second = announce_requests_port_zero as f64 / elapsed;
+        let requests_blocked_per_second = requests_blocked as f64 / elapsed;
+        let requests_invalid_length_per_second = requests_invalid_length as f64 / elapsed;
         let bytes_received_per_second = bytes_received as f64 / elapsed;
         let bytes_sent_per_second = bytes_sent as f64 / elapsed;
+        let peers_added_per_second = peers_added as f64 / elapsed;
+        let peers_stopped_per_second = peers_stopped as f64 / elapsed;
+        let peers_expired_per_second = peers_expired as f64 / elapsed;
 
         let responses_per_second_total = responses_per_second_connect
             + responses_per_second_announce
@@ -250,11 +426,58 @@ impl StatisticsCollector {
                 .to_formatted_string(&Locale::en),
             responses_per_second_error: (responses_per_second_error as usize)
                 .to_formatted_string(&Locale::en),
+            connect_requests_throttled_per_second: (connect_requests_throttled_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            responses_amplification_dropped_per_second: (responses_amplification_dropped_per_second
+                as usize)
+                .to_formatted_string(&Locale::en),
+            announce_requests_port_zero_per_second: (announce_requests_port_zero_per_second
+                as usize)
+                .to_formatted_string(&Locale::en),
+            requests_blocked_per_second: (requests_blocked_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            requests_invalid_length_per_second: (requests_invalid_length_per_second as usize)
+                .to_formatted_string(&Locale::en),
             rx_mbits: format!("{:.2}", bytes_received_per_second * 8.0 / 1_000_000.0),
             tx_mbits: format!("{:.2}", bytes_sent_per_second * 8.0 / 1_000_000.0),
             num_torrents: num_torrents.to_formatted_string(&Locale::en),
             num_peers: num_peers.to_formatted_string(&Locale::en),
+            reclaimed_bytes: reclaimed_bytes.to_formatted_string(&Locale::en),
+            peers_added_per_second: (peers_added_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            peers_stopped_per_second: (peers_stopped_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            peers_expired_per_second: (peers_expired_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            unique_ips: unique_ips.to_formatted_string(&Locale::en),
+            unique_asns: unique_asns.to_formatted_string(&Locale::en),
             peer_histogram: self.last_complete_histogram.clone(),
+            raw: RawStatistics {
+                requests,
+                responses_connect,
+                responses_announce,
+                responses_scrape,
+                responses_error,
+                connect_requests_throttled,
+                responses_amplification_dropped,
+                announce_requests_port_zero,
+                requests_blocked,
+                requests_invalid_length,
+                bytes_received,
+                bytes_sent,
+                peers_added,
+                peers_stopped,
+                peers_expired,
+                unique_ips,
+                unique_asns,
+                requests_per_second,
+                responses_per_second: responses_per_second_total,
+                rx_mbits: bytes_received_per_second * 8.0 / 1_000_000.0,
+                tx_mbits: bytes_sent_per_second * 8.0 / 1_000_000.0,
+                torrents: num_torrents,
+                peers: num_peers,
+                reclaimed_bytes,
+            },
         }
     }
 }
@@ -267,11 +490,60 @@ pub struct CollectedStatistics {
     pub responses_per_second_announce: String,
     pub responses_per_second_scrape: String,
     pub responses_per_second_error: String,
+    pub connect_requests_throttled_per_second: String,
+    pub responses_amplification_dropped_per_second: String,
+    pub announce_requests_port_zero_per_second: String,
+    pub requests_blocked_per_second: String,
+    pub requests_invalid_length_per_second: String,
     pub rx_mbits: String,
     pub tx_mbits: String,
     pub num_torrents: String,
     pub num_peers: String,
+    pub reclaimed_bytes: String,
+    pub peers_added_per_second: String,
+    pub peers_stopped_per_second: String,
+    pub peers_expired_per_second: String,
+    pub unique_ips: String,
+    pub unique_asns: String,
     pub peer_histogram: PeerHistogramStatistics,
+    #[serde(skip)]
+    pub raw: RawStatistics,
+}
+
+/// Unformatted statistics for machine-readable output
+///
+/// Counts (`requests`, `responses_*`, `connect_requests_throttled`,
+/// `announce_requests_port_zero`, `requests_blocked`,
+/// `requests_invalid_length`, `bytes_*`, `peers_added`, `peers_stopped`,
+/// `peers_expired`) and estimates of distinct sources (`unique_ips`,
+/// `unique_asns`) cover the time since the previous collection.
+#[derive(Clone, Copy, Debug, Serialize, Default)]
+pub struct RawStatistics {
+    pub requests: usize,
+    pub responses_connect: usize,
+    pub responses_announce: usize,
+    pub responses_scrape: usize,
+    pub responses_error: usize,
+    pub connect_requests_throttled: usize,
+    pub responses_amplification_dropped: usize,
+    pub announce_requests_port_zero: usize,
+    pub requests_blocked: usize,
+    pub requests_invalid_length: usize,
+    pub bytes_received: usize,
+    pub bytes_sent: usize,
+    pub peers_added: usize,
+    pub peers_stopped: usize,
+    pub peers_expired: usize,
+    pub unique_ips: usize,
+    /// Zero unless an ASN database is configured
+    pub unique_asns: usize,
+    pub requests_per_second: f64,
+    pub responses_per_second: f64,
+    pub rx_mbits: f64,
+    pub tx_mbits: f64,
+    pub torrents: usize,
+    pub peers: usize,
+    pub reclaimed_bytes: usize,
 }
 
 #[derive(Clone, Debug, Serialize, Default)]
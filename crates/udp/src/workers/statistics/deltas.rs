@@ -0,0 +1,183 @@
+//! Per-torrent changes in swarm counts for incremental consumers
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::mem::swap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_udp_protocol::InfoHash;
+use hashbrown::HashMap;
+use serde::Serialize;
+
+use crate::common::{IpVersion, State};
+use crate::config::Config;
+use crate::swarm::TorrentCounts;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Counts {
+    seeders: usize,
+    leechers: usize,
+    completed: u32,
+}
+
+impl From<&TorrentCounts> for Counts {
+    fn from(counts: &TorrentCounts) -> Self {
+        Self {
+            seeders: counts.seeders,
+            leechers: counts.leechers,
+            completed: counts.completed,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct DeltaLine {
+    timestamp: u64,
+    info_hash: String,
+    ip_version: &'static str,
+    seeders: i64,
+    leechers: i64,
+    completed: u64,
+}
+
+/// Appends changes in torrent counts since previous call to a file
+pub struct SwarmDeltaWriter {
+    file: File,
+    previous: HashMap<(InfoHash, IpVersion), Counts>,
+    current: HashMap<(InfoHash, IpVersion), Counts>,
+}
+
+impl SwarmDeltaWriter {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.statistics.swarm_deltas_file_path)
+            .with_context(|| {
+                format!(
+                    "open swarm deltas file {}",
+                    config.statistics.swarm_deltas_file_path.to_string_lossy()
+                )
+            })?;
+
+        Ok(Self {
+            file,
+            previous: HashMap::new(),
+            current: HashMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, state: &State) -> anyhow::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let now = state.server_start_instant.seconds_elapsed();
+
+        let mut writer = BufWriter::new(&mut self.file);
+        let previous = &mut self.previous;
+        let current = &mut self.current;
+
+        state
+            .torrent_maps
+            .for_each_shard_counts(now, Duration::ZERO, |torrents| {
+                for counts in torrents {
+                    let key = (counts.info_hash, counts.ip_version);
+                    let new = Counts::from(counts);
+                    let old = previous.remove(&key).unwrap_or_default();
+
+                    if let Some(line) = delta(timestamp, key, old, new) {
+                        write_line(&mut writer, &line)?;
+                    }
+
+                    current.insert(key, new);
+                }
+
+                Ok::<(), anyhow::Error>(())
+            })?;
+
+        // Remaining torrents have been removed since previous call
+        for (key, old) in previous.drain() {
+            if let Some(line) = delta(timestamp, key, old, Counts::default()) {
+                write_line(&mut writer, &line)?;
+            }
+        }
+
+        swap(previous, current);
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn write_line(writer: &mut impl Write, line: &DeltaLine) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Returns None if counts haven't changed
+///
+/// Completed counts restart from zero when torrents are removed, so only
+/// increases are reported.
+fn delta(
+    timestamp: u64,
+    (info_hash, ip_version): (InfoHash, IpVersion),
+    old: Counts,
+    new: Counts,
+) -> Option<DeltaLine> {
+    let completed = new.completed.saturating_sub(old.completed);
+
+    if new.seeders == old.seeders && new.leechers == old.leechers && completed == 0 {
+        return None;
+    }
+
+    Some(DeltaLine {
+        timestamp,
+        info_hash: hex::encode(info_hash.0),
+        ip_version: match ip_version {
+            IpVersion::V4 => "ipv4",
+            IpVersion::V6 => "ipv6",
+        },
+        seeders: new.seeders as i64 - old.seeders as i64,
+        leechers: new.leechers as i64 - old.leechers as i64,
+        completed: completed.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let key = (InfoHash([0xab; 20]), IpVersion::V4);
+        let counts = |seeders, leechers, completed| Counts {
+            seeders,
+            leechers,
+            completed,
+        };
+
+        assert_eq!(delta(1, key, counts(1, 2, 3), counts(1, 2, 3)), None);
+
+        assert_eq!(
+            delta(1, key, counts(1, 2, 3), counts(2, 0, 4)),
+            Some(DeltaLine {
+                timestamp: 1,
+                info_hash: "ab".repeat(20),
+                ip_version: "ipv4",
+                seeders: 1,
+                leechers: -2,
+                completed: 1,
+            })
+        );
+
+        // Removed torrent
+        let line = delta(1, key, counts(1, 2, 3), Counts::default()).unwrap();
+
+        assert_eq!((line.seeders, line.leechers, line.completed), (-1, -2, 0));
+    }
+}
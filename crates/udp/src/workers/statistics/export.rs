@@ -0,0 +1,279 @@
+//! Machine-readable statistics output: JSON lines file, StatsD and Graphite
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::config::Config;
+
+use super::collector::RawStatistics;
+
+const GRAPHITE_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv4: Option<&'a RawStatistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6: Option<&'a RawStatistics>,
+}
+
+#[derive(Clone, Copy)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+pub struct StatisticsExporter {
+    opt_json_file: Option<File>,
+    opt_statsd_socket: Option<UdpSocket>,
+    buffer: String,
+}
+
+impl StatisticsExporter {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let opt_json_file = if config.statistics.write_json_to_file {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.statistics.json_file_path)
+                .with_context(|| {
+                    format!(
+                        "open statistics json file {}",
+                        config.statistics.json_file_path.to_string_lossy()
+                    )
+                })?;
+
+            Some(file)
+        } else {
+            None
+        };
+
+        let opt_statsd_socket = if config.statistics.push_to_statsd {
+            let bind_addr = if config.statistics.statsd_address.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+
+            let socket = UdpSocket::bind(bind_addr).context("bind statsd socket")?;
+
+            socket
+                .connect(config.statistics.statsd_address)
+                .context("connect statsd socket")?;
+
+            Some(socket)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            opt_json_file,
+            opt_statsd_socket,
+            buffer: String::new(),
+        })
+    }
+
+    pub fn export(
+        &mut self,
+        config: &Config,
+        ipv4: Option<&RawStatistics>,
+        ipv6: Option<&RawStatistics>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Some(file) = self.opt_json_file.as_mut() {
+            let line = JsonLine {
+                timestamp,
+                ipv4,
+                ipv6,
+            };
+
+            let result = serde_json::to_writer(&mut *file, &line)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| file.write_all(b"\n").map_err(anyhow::Error::from));
+
+            if let Err(err) = result {
+                ::log::error!("Couldn't write statistics to json file: {:#}", err);
+            }
+        }
+
+        let sources = [("ipv4", ipv4), ("ipv6", ipv6)];
+
+        if let Some(socket) = self.opt_statsd_socket.as_ref() {
+            // Send one datagram per IP version to stay below common MTUs
+            for (ip_version, statistics) in sources {
+                if let Some(statistics) = statistics {
+                    self.buffer.clear();
+
+                    for (name, kind, value) in metrics(statistics) {
+                        let kind = match kind {
+                            MetricKind::Counter => "c",
+                            MetricKind::Gauge => "g",
+                        };
+
+                        let _ = writeln!(
+                            self.buffer,
+                            "{}.{}.{}:{}|{}",
+                            config.statistics.push_prefix, ip_version, name, value, kind
+                        );
+                    }
+
+                    if let Err(err) = socket.send(self.buffer.as_bytes()) {
+                        ::log::error!("Couldn't push statistics to statsd: {:#}", err);
+                    }
+                }
+            }
+        }
+
+        if config.statistics.push_to_graphite {
+            self.buffer.clear();
+
+            for (ip_version, statistics) in sources {
+                if let Some(statistics) = statistics {
+                    for (name, _, value) in metrics(statistics) {
+                        let _ = writeln!(
+                            self.buffer,
+                            "{}.{}.{} {} {}",
+                            config.statistics.push_prefix, ip_version, name, value, timestamp
+                        );
+                    }
+                }
+            }
+
+            if let Err(err) = push_to_graphite(config, self.buffer.as_bytes()) {
+                ::log::error!("Couldn't push statistics to graphite: {:#}", err);
+            }
+        }
+    }
+}
+
+fn push_to_graphite(config: &Config, data: &[u8]) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect_timeout(
+        &config.statistics.graphite_address,
+        GRAPHITE_CONNECT_TIMEOUT,
+    )
+    .context("connect")?;
+
+    stream
+        .set_write_timeout(Some(GRAPHITE_CONNECT_TIMEOUT))
+        .context("set write timeout")?;
+    stream.write_all(data).context("write")?;
+
+    Ok(())
+}
+
+fn metrics(statistics: &RawStatistics) -> [(&'static str, MetricKind, f64); 22] {
+    use MetricKind::*;
+
+    [
+        ("requests", Counter, statistics.requests as f64),
+        (
+            "responses.connect",
+            Counter,
+            statistics.responses_connect as f64,
+        ),
+        (
+            "responses.announce",
+            Counter,
+            statistics.responses_announce as f64,
+        ),
+        (
+            "responses.scrape",
+            Counter,
+            statistics.responses_scrape as f64,
+        ),
+        (
+            "responses.error",
+            Counter,
+            statistics.responses_error as f64,
+        ),
+        (
+            "connect_requests_throttled",
+            Counter,
+            statistics.connect_requests_throttled as f64,
+        ),
+        (
+            "responses_amplification_dropped",
+            Counter,
+            statistics.responses_amplification_dropped as f64,
+        ),
+        (
+            "announce_requests_port_zero",
+            Counter,
+            statistics.announce_requests_port_zero as f64,
+        ),
+        (
+            "requests_blocked",
+            Counter,
+            statistics.requests_blocked as f64,
+        ),
+        (
+            "requests_invalid_length",
+            Counter,
+            statistics.requests_invalid_length as f64,
+        ),
+        ("rx_bytes", Counter, statistics.bytes_received as f64),
+        ("tx_bytes", Counter, statistics.bytes_sent as f64),
+        ("peers.added", Counter, statistics.peers_added as f64),
+        ("peers.stopped", Counter, statistics.peers_stopped as f64),
+        ("peers.expired", Counter, statistics.peers_expired as f64),
+        ("rx_mbits", Gauge, statistics.rx_mbits),
+        ("tx_mbits", Gauge, statistics.tx_mbits),
+        ("torrents", Gauge, statistics.torrents as f64),
+        ("peers", Gauge, statistics.peers as f64),
+        ("reclaimed_bytes", Gauge, statistics.reclaimed_bytes as f64),
+        ("unique_ips", Gauge, statistics.unique_ips as f64),
+        ("unique_asns", Gauge, statistics.unique_asns as f64),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = Config::default();
+
+        config.statistics.write_json_to_file = true;
+        config.statistics.json_file_path = dir.path().join("statistics.jsonl");
+
+        let mut exporter = StatisticsExporter::new(&config).unwrap();
+
+        let statistics = RawStatistics {
+            requests: 10,
+            torrents: 2,
+            ..Default::default()
+        };
+
+        exporter.export(&config, Some(&statistics), None);
+        exporter.export(&config, Some(&statistics), Some(&statistics));
+
+        let contents = ::std::fs::read_to_string(&config.statistics.json_file_path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+
+        assert_eq!(first["ipv4"]["requests"], 10);
+        assert_eq!(first["ipv4"]["torrents"], 2);
+        assert!(first.get("ipv6").is_none());
+
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+
+        assert!(second.get("ipv6").is_some());
+    }
+}
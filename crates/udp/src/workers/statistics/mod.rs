@@ -1,4 +1,6 @@
 mod collector;
+mod deltas;
+mod export;
 
 use std::fs::File;
 use std::io::Write;
@@ -16,6 +18,8 @@ use time::OffsetDateTime;
 use tinytemplate::TinyTemplate;
 
 use collector::{CollectedStatistics, StatisticsCollector};
+use deltas::SwarmDeltaWriter;
+use export::StatisticsExporter;
 
 use crate::common::*;
 use crate::config::Config;
@@ -34,6 +38,7 @@ struct TemplateData {
     ipv4_active: bool,
     ipv6_active: bool,
     extended_active: bool,
+    asn_active: bool,
     ipv4: CollectedStatistics,
     ipv6: CollectedStatistics,
     last_updated: String,
@@ -69,6 +74,21 @@ pub fn run_statistics_worker(
         None
     };
 
+    let mut opt_exporter = if config.statistics.write_json_to_file
+        | config.statistics.push_to_statsd
+        | config.statistics.push_to_graphite
+    {
+        Some(StatisticsExporter::new(&config).context("create statistics exporter")?)
+    } else {
+        None
+    };
+
+    let mut opt_delta_writer = if config.statistics.write_swarm_deltas_to_file {
+        Some(SwarmDeltaWriter::new(&config).context("create swarm delta writer")?)
+    } else {
+        None
+    };
+
     let mut ipv4_collector = StatisticsCollector::new(statistics.clone(), IpVersion::V4);
     let mut ipv6_collector = StatisticsCollector::new(statistics, IpVersion::V6);
 
@@ -140,7 +160,7 @@ pub fn run_statistics_worker(
             {
                 for (prefix, count) in prefixes {
                     ::metrics::gauge!(
-                        "aquatic_peer_id_prefixes",
+                        aquatic_metrics::names::PEER_ID_PREFIXES,
                         "prefix_hex" => prefix.to_string(),
                     )
                     .set(count as f64);
@@ -157,7 +177,7 @@ pub fn run_statistics_worker(
                 #[cfg(feature = "prometheus")]
                 if config.statistics.run_prometheus_endpoint {
                     ::metrics::gauge!(
-                        "aquatic_peer_clients",
+                        aquatic_metrics::names::PEER_CLIENTS,
                         "client" => client.to_string(),
                     )
                     .set(count as f64);
@@ -188,18 +208,36 @@ pub fn run_statistics_worker(
             println!();
         }
 
+        if let Some(exporter) = opt_exporter.as_mut() {
+            exporter.export(
+                &config,
+                config.network.ipv4_active().then_some(&statistics_ipv4.raw),
+                config.network.ipv6_active().then_some(&statistics_ipv6.raw),
+            );
+        }
+
+        if let Some(delta_writer) = opt_delta_writer.as_mut() {
+            if let Err(err) = delta_writer.write(&shared_state) {
+                ::log::error!("Couldn't write swarm deltas to file: {:#}", err);
+            }
+        }
+
         if let Some(tt) = opt_tt.as_ref() {
             let template_data = TemplateData {
                 stylesheet: STYLESHEET_CONTENTS.to_string(),
                 ipv4_active: config.network.ipv4_active(),
                 ipv6_active: config.network.ipv6_active(),
                 extended_active: config.statistics.torrent_peer_histograms,
+                asn_active: config.geoip.asn_active(),
                 ipv4: statistics_ipv4,
                 ipv6: statistics_ipv6,
                 last_updated: OffsetDateTime::now_utc()
                     .format(&Rfc2822)
                     .unwrap_or("(formatting error)".into()),
-                peer_update_interval: format!("{}", config.cleaning.torrent_cleaning_interval),
+                peer_update_interval: format!(
+                    "{}",
+                    config.statistics.torrent_count_interval(&config.cleaning)
+                ),
                 peer_clients,
             };
 
@@ -213,7 +251,9 @@ pub fn run_statistics_worker(
         if let Some(time_remaining) =
             Duration::from_secs(config.statistics.interval).checked_sub(start_time.elapsed())
         {
-            ::std::thread::sleep(time_remaining);
+            if shared_state.shutdown.sleep(time_remaining) {
+                return Ok(());
+            }
         } else {
             ::log::warn!(
                 "statistics interval not long enough to process all data, output may be misleading"
@@ -249,19 +289,69 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         "    error:         {:>10}",
         statistics.responses_per_second_error
     );
+    println!(
+        "  throttled connect requests/second: {:>10}",
+        statistics.connect_requests_throttled_per_second
+    );
+    println!(
+        "  amplification limited responses/second: {:>10}",
+        statistics.responses_amplification_dropped_per_second
+    );
+    println!(
+        "  announce requests with port 0/second: {:>10}",
+        statistics.announce_requests_port_zero_per_second
+    );
+    println!(
+        "  blocked requests/second: {:>10}",
+        statistics.requests_blocked_per_second
+    );
+    println!(
+        "  invalid length requests/second: {:>10}",
+        statistics.requests_invalid_length_per_second
+    );
     println!(
         "  torrents:        {:>10} (updated every {}s)",
-        statistics.num_torrents, config.cleaning.torrent_cleaning_interval
+        statistics.num_torrents,
+        config.statistics.torrent_count_interval(&config.cleaning)
     );
     println!(
         "  peers:           {:>10} (updated every {}s)",
-        statistics.num_peers, config.cleaning.torrent_cleaning_interval
+        statistics.num_peers,
+        config.statistics.torrent_count_interval(&config.cleaning)
     );
+    println!(
+        "  reclaimed bytes: {:>10} (in last cleaning pass)",
+        statistics.reclaimed_bytes
+    );
+    println!("  peer churn/second");
+    println!(
+        "    added:         {:>10}",
+        statistics.peers_added_per_second
+    );
+    println!(
+        "    stopped:       {:>10}",
+        statistics.peers_stopped_per_second
+    );
+    println!(
+        "    expired:       {:>10}",
+        statistics.peers_expired_per_second
+    );
+    println!(
+        "  unique IPs:      {:>10} (estimate, since last output)",
+        statistics.unique_ips
+    );
+
+    if config.geoip.asn_active() {
+        println!(
+            "  unique ASNs:     {:>10} (estimate, since last output)",
+            statistics.unique_asns
+        );
+    }
 
     if config.statistics.torrent_peer_histograms {
         println!(
             "  peers per torrent (updated every {}s)",
-            config.cleaning.torrent_cleaning_interval
+            config.statistics.torrent_count_interval(&config.cleaning)
         );
         println!("    min            {:>10}", statistics.peer_histogram.min);
         println!("    p10            {:>10}", statistics.peer_histogram.p10);
@@ -17,30 +17,25 @@ use aquatic_udp_protocol::{InfoHash, Response};
 
 #[test]
 fn test_access_list_deny() -> anyhow::Result<()> {
-    const TRACKER_PORT: u16 = 40_113;
-
     let deny = InfoHash([0; 20]);
     let allow = InfoHash([1; 20]);
 
-    test_access_list(TRACKER_PORT, allow, deny, deny, AccessListMode::Deny)?;
+    test_access_list(allow, deny, deny, AccessListMode::Deny)?;
 
     Ok(())
 }
 
 #[test]
 fn test_access_list_allow() -> anyhow::Result<()> {
-    const TRACKER_PORT: u16 = 40_114;
-
     let allow = InfoHash([0; 20]);
     let deny = InfoHash([1; 20]);
 
-    test_access_list(TRACKER_PORT, allow, deny, allow, AccessListMode::Allow)?;
+    test_access_list(allow, deny, allow, AccessListMode::Allow)?;
 
     Ok(())
 }
 
 fn test_access_list(
-    tracker_port: u16,
     info_hash_success: InfoHash,
     info_hash_fail: InfoHash,
     info_hash_in_list: InfoHash,
@@ -60,14 +55,10 @@ fn test_access_list(
 
     let mut config = Config::default();
 
-    config.network.address.set_port(tracker_port);
-
     config.access_list.mode = mode;
     config.access_list.path = access_list_path;
 
-    run_tracker(config);
-
-    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, tracker_port));
+    let tracker_addr = run_tracker(config);
     let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
 
     let socket = UdpSocket::bind(peer_addr)?;
@@ -0,0 +1,136 @@
+mod common;
+
+use common::*;
+
+use std::{
+    fs::File,
+    io::Write,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    num::NonZeroU16,
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::Context;
+use aquatic_common::access_list::AccessListMode;
+use aquatic_udp::config::Config;
+use aquatic_udp_protocol::{InfoHash, Response};
+
+const NUM_OTHER_PEERS: u8 = 100;
+
+#[test]
+fn test_amplification_guard() -> anyhow::Result<()> {
+    let info_hash = InfoHash([0; 20]);
+    let denied_info_hash = InfoHash([1; 20]);
+
+    let access_list_dir = tempfile::tempdir().with_context(|| "get temporary directory")?;
+    let access_list_path = access_list_dir.path().join("access-list.txt");
+
+    writeln!(
+        File::create(&access_list_path)?,
+        "{}",
+        hex::encode_upper(denied_info_hash.0)
+    )?;
+
+    let mut config = Config {
+        socket_workers: 1,
+        ..Default::default()
+    };
+
+    config.access_list.mode = AccessListMode::Deny;
+    config.access_list.path = access_list_path;
+    config.protocol.max_response_peers = NUM_OTHER_PEERS.into();
+    config.protocol.max_amplification_factor = 1;
+
+    let tracker_addr = run_tracker(config);
+
+    // Announce peers from other loopback addresses, so that responses to
+    // 127.0.0.1 contain many peers. These sources only ask for one peer, so
+    // they are sent fewer bytes than they send.
+    for i in 1..=NUM_OTHER_PEERS {
+        let socket = bind(Ipv4Addr::new(127, 0, 1, i))?;
+        let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+        announce(
+            &socket,
+            tracker_addr,
+            connection_id,
+            NonZeroU16::new(10_000 + u16::from(i)).unwrap(),
+            info_hash,
+            1,
+            true,
+        )
+        .with_context(|| "announce")?;
+    }
+
+    let socket = bind(Ipv4Addr::LOCALHOST)?;
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    // Responses to validated sources are never dropped, even though this one
+    // is several times larger than the request
+    match announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        NonZeroU16::new(20_000).unwrap(),
+        info_hash,
+        NUM_OTHER_PEERS.into(),
+        false,
+    )
+    .with_context(|| "announce")?
+    {
+        Response::AnnounceIpv4(response) => {
+            assert_eq!(response.peers.len(), usize::from(NUM_OTHER_PEERS))
+        }
+        response => panic!("not announce response: {:?}", response),
+    }
+
+    // Connect and error responses are dropped once more bytes have been sent
+    // to the source than received from it
+    assert!(connect(&socket, tracker_addr).is_err());
+    assert!(announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        NonZeroU16::new(20_000).unwrap(),
+        denied_info_hash,
+        1,
+        false,
+    )
+    .is_err());
+
+    // Byte counts decay gradually instead of being reset every second
+    sleep(Duration::from_millis(1100));
+
+    assert!(connect(&socket, tracker_addr).is_err());
+
+    // Other sources are unaffected
+    let socket = bind(Ipv4Addr::new(127, 0, 2, 1))?;
+    let connection_id =
+        connect(&socket, tracker_addr).with_context(|| "connect from other source")?;
+
+    match announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        NonZeroU16::new(20_001).unwrap(),
+        denied_info_hash,
+        1,
+        false,
+    )
+    .with_context(|| "announce from other source")?
+    {
+        Response::Error(_) => (),
+        response => panic!("not error response: {:?}", response),
+    }
+
+    Ok(())
+}
+
+fn bind(ip: Ipv4Addr) -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::from((ip, 0)))?;
+
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+    Ok(socket)
+}
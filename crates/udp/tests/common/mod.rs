@@ -1,28 +1,37 @@
 #![allow(dead_code)]
 
 use std::{
-    io::Cursor,
-    net::{SocketAddr, UdpSocket},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
     num::NonZeroU16,
     time::Duration,
 };
 
-use anyhow::Context;
-use aquatic_udp::{common::BUFFER_SIZE, config::Config};
+use aquatic_udp::config::Config;
 use aquatic_udp_protocol::{
     common::PeerId, AnnounceEvent, AnnounceRequest, ConnectRequest, ConnectionId, InfoHash,
     Ipv4AddrBytes, NumberOfBytes, NumberOfPeers, PeerKey, Port, Request, Response, ScrapeRequest,
     ScrapeResponse, TransactionId,
 };
 
-// FIXME: should ideally try different ports and use sync primitives to find
-// out if tracker was successfully started
-pub fn run_tracker(config: Config) {
+/// Start tracker on an ephemeral port and return its localhost address once
+/// it is ready to receive requests
+pub fn run_tracker(mut config: Config) -> SocketAddr {
+    let (sender, receiver) = ::std::sync::mpsc::channel();
+
+    config.network.address.set_port(0);
+
     ::std::thread::spawn(move || {
-        aquatic_udp::run(config).unwrap();
+        aquatic_udp::run_with_bound_callback(config, move |addr| {
+            let _ = sender.send(addr);
+        })
+        .unwrap();
     });
 
-    ::std::thread::sleep(Duration::from_secs(1));
+    let addr = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("tracker started");
+
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, addr.port()))
 }
 
 pub fn connect(socket: &UdpSocket, tracker_addr: SocketAddr) -> anyhow::Result<ConnectionId> {
@@ -99,27 +108,5 @@ pub fn request_and_response(
     tracker_addr: SocketAddr,
     request: Request,
 ) -> anyhow::Result<Response> {
-    let mut buffer = [0u8; BUFFER_SIZE];
-
-    {
-        let mut buffer = Cursor::new(&mut buffer[..]);
-
-        request
-            .write_bytes(&mut buffer)
-            .with_context(|| "write request")?;
-
-        let bytes_written = buffer.position() as usize;
-
-        socket
-            .send_to(&(buffer.into_inner())[..bytes_written], tracker_addr)
-            .with_context(|| "send request")?;
-    }
-
-    {
-        let (bytes_read, _) = socket
-            .recv_from(&mut buffer)
-            .with_context(|| "recv response")?;
-
-        Response::parse_bytes(&buffer[..bytes_read], true).with_context(|| "parse response")
-    }
+    aquatic_client::udp::send_request(socket, tracker_addr, request)
 }
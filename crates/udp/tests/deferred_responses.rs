@@ -0,0 +1,117 @@
+mod common;
+
+use common::*;
+
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::Context;
+use aquatic_udp::{
+    config::{Config, SocketBackend},
+    Tracker,
+};
+use aquatic_udp_protocol::{InfoHash, Request, Response, ScrapeRequest, TransactionId};
+
+const NUM_REQUESTS: i32 = 150;
+
+#[test]
+fn test_shutdown_sends_deferred_responses_mio() -> anyhow::Result<()> {
+    check_shutdown_sends_deferred_responses(SocketBackend::Mio)
+}
+
+#[cfg(feature = "io-uring")]
+#[test]
+fn test_shutdown_sends_deferred_responses_io_uring() -> anyhow::Result<()> {
+    check_shutdown_sends_deferred_responses(SocketBackend::IoUring)
+}
+
+/// Send scrape requests faster than the egress budget allows responding to
+/// them, then shut down the tracker before the budget has caught up. All
+/// responses should still be sent.
+fn check_shutdown_sends_deferred_responses(backend: SocketBackend) -> anyhow::Result<()> {
+    let mut config = Config {
+        socket_workers: 1,
+        ..Default::default()
+    };
+
+    config.network.address.set_port(0);
+    config.network.backend = backend;
+    // 12500 bytes per 100 ms window, enough for around 45 scrape responses
+    // with 20 info hashes each
+    config.network.egress_budget_mbits = 1;
+
+    let tracker = Tracker::start(config)?;
+
+    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::LOCALHOST,
+        tracker.local_addr().port(),
+    ));
+
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+
+    // Make room for all responses, since they are only read after shutdown
+    socket.set_recv_buffer_size(4 * 1024 * 1024)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())?;
+
+    let socket: UdpSocket = socket.into();
+
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    let mut buffer = Vec::new();
+
+    for i in 0..NUM_REQUESTS {
+        let request = Request::Scrape(ScrapeRequest {
+            connection_id,
+            transaction_id: TransactionId::new(i),
+            info_hashes: vec![InfoHash([0; 20]); 20],
+        });
+
+        buffer.clear();
+        request.write_bytes(&mut buffer)?;
+
+        socket.send_to(&buffer, tracker_addr)?;
+    }
+
+    // Give socket worker time to read requests, but not for the egress
+    // budget to allow sending all responses
+    sleep(Duration::from_millis(30));
+
+    tracker.shutdown()?;
+
+    socket.set_nonblocking(true)?;
+
+    let mut transaction_ids = HashSet::new();
+    let mut buffer = [0u8; 2048];
+
+    loop {
+        match socket.recv(&mut buffer) {
+            Ok(bytes_read) => match Response::parse_bytes(&buffer[..bytes_read], true)? {
+                Response::Scrape(response) => {
+                    transaction_ids.insert(response.transaction_id);
+                }
+                response => return Err(anyhow::anyhow!("not scrape response: {:?}", response)),
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let expected = (0..NUM_REQUESTS)
+        .map(TransactionId::new)
+        .collect::<HashSet<_>>();
+
+    assert_eq!(transaction_ids, expected);
+
+    Ok(())
+}
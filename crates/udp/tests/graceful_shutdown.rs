@@ -0,0 +1,65 @@
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    sync::mpsc::channel,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+#[test]
+fn test_exit_on_sigterm() -> anyhow::Result<()> {
+    let mut config_file = tempfile::NamedTempFile::new()?;
+
+    writeln!(
+        config_file,
+        "log_level = 'info'\nsocket_workers = 2\n[network]\naddress = '127.0.0.1:0'"
+    )?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aquatic_udp"))
+        .arg("-c")
+        .arg(config_file.path())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stderr = child.stderr.take().unwrap();
+    let (sender, receiver) = channel();
+
+    ::std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if line.contains("all socket workers bound") {
+                let _ = sender.send(());
+            }
+        }
+    });
+
+    // Signal handlers are installed before socket workers are started
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .with_context(|| "tracker didn't start")?;
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(15);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            assert!(status.success(), "exit status: {}", status);
+
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+
+            return Err(anyhow::anyhow!("tracker didn't exit after SIGTERM"));
+        }
+
+        sleep(Duration::from_millis(50));
+    }
+}
@@ -18,15 +18,9 @@ use aquatic_udp_protocol::{
 
 #[test]
 fn test_invalid_connection_id() -> anyhow::Result<()> {
-    const TRACKER_PORT: u16 = 40_112;
+    let config = Config::default();
 
-    let mut config = Config::default();
-
-    config.network.address.set_port(TRACKER_PORT);
-
-    run_tracker(config);
-
-    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, TRACKER_PORT));
+    let tracker_addr = run_tracker(config);
     let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
 
     let socket = UdpSocket::bind(peer_addr)?;
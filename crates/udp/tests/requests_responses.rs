@@ -15,17 +15,12 @@ use aquatic_udp_protocol::{InfoHash, Response};
 
 #[test]
 fn test_multiple_connect_announce_scrape() -> anyhow::Result<()> {
-    const TRACKER_PORT: u16 = 40_111;
     const PEER_PORT_START: u16 = 30_000;
     const PEERS_WANTED: usize = 10;
 
-    let mut config = Config::default();
-
-    config.network.address.set_port(TRACKER_PORT);
-
-    run_tracker(config);
+    let config = Config::default();
 
-    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, TRACKER_PORT));
+    let tracker_addr = run_tracker(config);
     let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
 
     let info_hash = InfoHash([0; 20]);
@@ -105,3 +100,102 @@ fn test_multiple_connect_announce_scrape() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_connect_scrape_with_sender_threads() -> anyhow::Result<()> {
+    let mut config = Config::default();
+    config.network.use_sender_threads = true;
+
+    let tracker_addr = run_tracker(config);
+    let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+    let socket = UdpSocket::bind(peer_addr)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    let scrape_response = scrape(
+        &socket,
+        tracker_addr,
+        connection_id,
+        vec![InfoHash([0; 20])],
+    )
+    .with_context(|| "scrape")?;
+
+    assert_eq!(scrape_response.torrent_stats.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_connect_announce_single_threaded() -> anyhow::Result<()> {
+    let mut config = Config::default();
+    config.debug.single_threaded = true;
+
+    let tracker_addr = run_tracker(config);
+    let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+    let socket = UdpSocket::bind(peer_addr)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    let response = announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        NonZeroU16::new(30_000).unwrap(),
+        InfoHash([0; 20]),
+        10,
+        true,
+    )
+    .with_context(|| "announce")?;
+
+    assert!(matches!(response, Response::AnnounceIpv4(_)));
+
+    Ok(())
+}
+
+#[test]
+fn test_client_announce_scrape() -> anyhow::Result<()> {
+    let config = Config::default();
+
+    let tracker_addr = run_tracker(config);
+
+    let tracker: aquatic_client::Tracker = format!("udp://{}", tracker_addr).parse()?;
+    let timeout = Duration::from_secs(1);
+
+    let info_hash = [1; 20];
+
+    let seeder = aquatic_client::AnnounceParams::new(info_hash, 30_000);
+    let leecher = aquatic_client::AnnounceParams {
+        bytes_left: 1,
+        ..aquatic_client::AnnounceParams::new(info_hash, 30_001)
+    };
+
+    tracker
+        .announce(&seeder, timeout)
+        .with_context(|| "announce")?;
+
+    let summary = tracker
+        .announce(&leecher, timeout)
+        .with_context(|| "announce")?;
+
+    assert_eq!(summary.seeders, 1);
+    assert_eq!(
+        summary.peers,
+        vec![SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            30_000
+        ))]
+    );
+
+    let summaries = tracker
+        .scrape(&[info_hash, [2; 20]], timeout)
+        .with_context(|| "scrape")?;
+
+    assert_eq!((summaries[0].seeders, summaries[0].leechers), (1, 1));
+    assert_eq!((summaries[1].seeders, summaries[1].leechers), (0, 0));
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use common::*;
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket},
+    num::NonZeroU16,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use aquatic_udp::{config::Config, Tracker};
+use aquatic_udp_protocol::InfoHash;
+
+#[test]
+fn test_tracker_handle() -> anyhow::Result<()> {
+    let mut config = Config::default();
+
+    config.network.address.set_port(0);
+
+    let tracker = Tracker::start(config)?;
+
+    assert_ne!(tracker.local_addr().port(), 0);
+
+    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::LOCALHOST,
+        tracker.local_addr().port(),
+    ));
+    let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+    let socket = UdpSocket::bind(peer_addr)?;
+
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        NonZeroU16::new(30_000).unwrap(),
+        InfoHash([0; 20]),
+        10,
+        true,
+    )
+    .with_context(|| "announce")?;
+
+    let stats = tracker.stats();
+
+    assert_eq!(stats.ipv4.torrents, 1);
+    assert_eq!(stats.ipv4.seeders, 1);
+    assert_eq!(stats.ipv4.leechers, 0);
+    assert_eq!(stats.ipv6.torrents, 0);
+
+    tracker.shutdown()?;
+
+    // Socket has been closed, so port can be bound again
+    bind_with_retries(tracker_addr, Duration::from_secs(5))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_build_info_endpoint() -> anyhow::Result<()> {
+    let mut config = Config::default();
+
+    config.network.address.set_port(0);
+    config.snapshot.run_endpoint = true;
+    config.snapshot.endpoint_address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let tracker = Tracker::start(config.clone())?;
+
+    let mut stream = TcpStream::connect(config.snapshot.endpoint_address)?;
+
+    stream.write_all(b"GET /build_info HTTP/1.1\r\n\r\n")?;
+
+    let mut response = String::new();
+
+    stream.read_to_string(&mut response)?;
+
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .with_context(|| "no body in response")?;
+    let build_info: serde_json::Value = serde_json::from_str(body)?;
+
+    assert_eq!(build_info["version"], aquatic_udp::APP_VERSION);
+    assert_eq!(
+        build_info["config_hash"],
+        aquatic_common::cli::config_hash(tracker.config())
+    );
+    assert!(build_info["features"].is_array());
+
+    tracker.shutdown()?;
+
+    Ok(())
+}
+
+/// Retry binding with backoff, since closing sockets on shutdown and
+/// binding ports in other tests running in parallel can race
+fn bind_with_retries(addr: SocketAddr, timeout: Duration) -> anyhow::Result<UdpSocket> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(10);
+
+    loop {
+        match UdpSocket::bind(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(_) if Instant::now() + backoff < deadline => {
+                sleep(backoff);
+
+                backoff = (backoff * 2).min(Duration::from_millis(500));
+            }
+            Err(err) => return Err(err).with_context(|| format!("bind {}", addr)),
+        }
+    }
+}
@@ -23,10 +23,14 @@ pub struct Peer {
     pub announce_info_hash_index: usize,
     pub announce_info_hash: InfoHash,
     pub announce_port: Port,
+    pub peer_id: PeerId,
+    pub key: PeerKey,
     pub scrape_info_hash_indices: Box<[usize]>,
     pub socket_index: u8,
 }
 
 pub enum StatisticsMessage {
     ResponsesPerInfoHash(IndexMap<usize, u64>),
+    /// Number of error responses by message
+    ErrorResponses(IndexMap<String, u64>),
 }
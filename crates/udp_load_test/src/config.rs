@@ -29,6 +29,7 @@ pub struct Config {
     pub extra_statistics: bool,
     pub network: NetworkConfig,
     pub requests: RequestConfig,
+    pub churn: ChurnConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: CpuPinningConfigDesc,
 }
@@ -44,6 +45,7 @@ impl Default for Config {
             extra_statistics: true,
             network: NetworkConfig::default(),
             requests: RequestConfig::default(),
+            churn: ChurnConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
         }
@@ -115,6 +117,18 @@ pub struct RequestConfig {
     pub weight_scrape: usize,
     /// Probability that a generated peer is a seeder
     pub peer_seeder_probability: f64,
+    /// Flood the tracker with large scrape requests
+    ///
+    /// Announce requests are replaced by scrape requests for
+    /// `scrape_flood_torrents` info hashes chosen uniformly at random, sent
+    /// from randomly chosen sockets. Requests are sent without waiting for
+    /// responses to catch up. Connect requests are still sent to keep
+    /// connection IDs valid.
+    pub scrape_flood: bool,
+    /// Number of info hashes in scrape requests in scrape flood mode
+    ///
+    /// Should usually match the tracker's `protocol.max_scrape_torrents`.
+    pub scrape_flood_torrents: usize,
 }
 
 impl Default for RequestConfig {
@@ -128,6 +142,37 @@ impl Default for RequestConfig {
             weight_announce: 50,
             weight_scrape: 1,
             peer_seeder_probability: 0.75,
+            scrape_flood: false,
+            scrape_flood_torrents: 70,
+        }
+    }
+}
+
+/// Changes to peer identity between announce requests
+///
+/// Real clients change peer_id on restart and key or port on reconfiguration,
+/// which static peers never do. Enabling churn exercises handling of peers
+/// replacing each other and of stale peers being cleaned.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChurnConfig {
+    /// Probability that a peer changes its peer_id before an announce request
+    pub peer_id_change_probability: f64,
+    /// Probability that a peer changes its key before an announce request
+    pub key_change_probability: f64,
+    /// Probability that a peer changes its port before an announce request
+    ///
+    /// The tracker then treats the peer as a new one, while the old one
+    /// remains until it is cleaned.
+    pub port_change_probability: f64,
+}
+
+impl Default for ChurnConfig {
+    fn default() -> Self {
+        Self {
+            peer_id_change_probability: 0.0,
+            key_change_probability: 0.0,
+            port_change_probability: 0.0,
         }
     }
 }
@@ -6,7 +6,7 @@ use std::thread::{self, Builder};
 use std::time::{Duration, Instant};
 
 use aquatic_common::IndexMap;
-use aquatic_udp_protocol::{InfoHash, Port};
+use aquatic_udp_protocol::{InfoHash, PeerId, PeerKey, Port};
 use crossbeam_channel::{unbounded, Receiver};
 use hdrhistogram::Histogram;
 use rand::rngs::SmallRng;
@@ -50,6 +50,13 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     // Start workers
 
+    #[cfg(feature = "cpu-pinning")]
+    aquatic_common::cpu_pinning::log_cpu_pinning_mapping(
+        &config.cpu_pinning,
+        config.workers as usize,
+        0,
+    );
+
     for (i, peers) in (0..config.workers).zip(peers_by_worker) {
         let ip = if config.server_address.is_ipv6() {
             Ipv6Addr::LOCALHOST.into()
@@ -65,6 +72,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let statistics_sender = statistics_sender.clone();
 
         Builder::new().name("load-test".into()).spawn(move || {
+            #[cfg(feature = "cpu-pinning")]
+            aquatic_common::cpu_pinning::pin_current_if_configured_to(
+                &config.cpu_pinning,
+                config.workers as usize,
+                0,
+                aquatic_common::cpu_pinning::WorkerIndex::SocketWorker(i as usize),
+            );
+
             Worker::run(config, state, statistics_sender, peers, addr)
         })?;
     }
@@ -83,6 +98,7 @@ fn monitor_statistics(
     let mut report_avg_announce: Vec<f64> = Vec::new();
     let mut report_avg_scrape: Vec<f64> = Vec::new();
     let mut report_avg_error: Vec<f64> = Vec::new();
+    let mut error_responses: IndexMap<String, u64> = Default::default();
 
     const INTERVAL: u64 = 5;
 
@@ -106,6 +122,11 @@ fn monitor_statistics(
                         }
                     }
                 }
+                StatisticsMessage::ErrorResponses(data) => {
+                    for (k, v) in data {
+                        *error_responses.entry(k).or_default() += v;
+                    }
+                }
             }
         }
 
@@ -208,6 +229,18 @@ fn monitor_statistics(
     println!("  - Announce responses: {:.2}", avg_announce);
     println!("  - Scrape responses:   {:.2}", avg_scrape);
     println!("  - Error responses:    {:.2}", avg_error);
+
+    if !error_responses.is_empty() {
+        error_responses.sort_by(|_, a, _, b| b.cmp(a));
+
+        println!();
+        println!("Error responses by message (whole run):");
+
+        for (message, count) in error_responses {
+            println!("  - {:?}: {}", message, count);
+        }
+    }
+
     println!();
     println!("Config: {:#?}", config);
     println!();
@@ -243,6 +276,8 @@ fn create_peers(config: &Config, info_hash_dist: &InfoHashDist) -> Vec<Box<[Peer
             announce_info_hash_index,
             announce_info_hash,
             announce_port: Port::new(rng.gen()),
+            peer_id: PeerId(rng.gen()),
+            key: PeerKey::new(rng.gen()),
             scrape_info_hash_indices,
             socket_index: rng.gen_range(0..config.network.sockets_per_worker),
         }
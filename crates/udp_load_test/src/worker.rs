@@ -30,6 +30,7 @@ pub struct Worker {
     statistics: LocalStatistics,
     statistics_sender: Sender<StatisticsMessage>,
     announce_responses_per_info_hash: IndexMap<usize, u64>,
+    error_responses: IndexMap<String, u64>,
 }
 
 impl Worker {
@@ -63,6 +64,7 @@ impl Worker {
             statistics,
             statistics_sender,
             announce_responses_per_info_hash: Default::default(),
+            error_responses: Default::default(),
         };
 
         instance.run_inner();
@@ -85,7 +87,11 @@ impl Worker {
         loop {
             let response_ratio = responses_received as f64 / requests_sent.max(1) as f64;
 
-            if response_ratio >= 0.90 || requests_sent == 0 || self.rng.gen::<u8>() == 0 {
+            if self.config.requests.scrape_flood
+                || response_ratio >= 0.90
+                || requests_sent == 0
+                || self.rng.gen::<u8>() == 0
+            {
                 for _ in 0..self.sockets.len() {
                     match self.request_type_dist.sample(&mut self.rng) {
                         RequestType::Connect => {
@@ -102,6 +108,9 @@ impl Worker {
 
                             peer_index = (peer_index + 1) % self.peers.len();
                         }
+                        RequestType::Scrape if self.config.requests.scrape_flood => {
+                            self.send_flood_scrape_request(&connection_ids);
+                        }
                         RequestType::Scrape => {
                             self.send_scrape_request(&connection_ids, peer_index);
 
@@ -206,7 +215,31 @@ impl Worker {
         }
     }
 
+    /// Randomly change peer identity according to churn config
+    fn churn_peer(&mut self, peer_index: usize) {
+        let churn = &self.config.churn;
+        let peer = self.peers.get_mut(peer_index).unwrap();
+
+        if churn.peer_id_change_probability > 0.0
+            && self.rng.gen_bool(churn.peer_id_change_probability.min(1.0))
+        {
+            peer.peer_id = PeerId(self.rng.gen());
+        }
+        if churn.key_change_probability > 0.0
+            && self.rng.gen_bool(churn.key_change_probability.min(1.0))
+        {
+            peer.key = PeerKey::new(self.rng.gen());
+        }
+        if churn.port_change_probability > 0.0
+            && self.rng.gen_bool(churn.port_change_probability.min(1.0))
+        {
+            peer.announce_port = Port::new(self.rng.gen());
+        }
+    }
+
     fn send_announce_request(&mut self, connection_ids: &[ConnectionId], peer_index: usize) {
+        self.churn_peer(peer_index);
+
         let peer = self.peers.get(peer_index).unwrap();
 
         let (event, bytes_left) = {
@@ -228,13 +261,13 @@ impl Worker {
             action_placeholder: Default::default(),
             transaction_id,
             info_hash: peer.announce_info_hash,
-            peer_id: PeerId([0; 20]),
+            peer_id: peer.peer_id,
             bytes_downloaded: NumberOfBytes::new(50),
             bytes_uploaded: NumberOfBytes::new(50),
             bytes_left,
             event: event.into(),
             ip_address: Ipv4AddrBytes([0; 4]),
-            key: PeerKey::new(0),
+            key: peer.key,
             peers_wanted: NumberOfPeers::new(self.config.requests.announce_peers_wanted),
             port: peer.announce_port,
         };
@@ -289,6 +322,37 @@ impl Worker {
         }
     }
 
+    /// Send scrape request for uniformly random info hashes from random socket
+    fn send_flood_scrape_request(&mut self, connection_ids: &[ConnectionId]) {
+        let socket_index = self.rng.gen_range(0..self.sockets.len());
+        let num_info_hashes = self.shared_state.info_hashes.len();
+
+        let info_hashes = (0..self.config.requests.scrape_flood_torrents)
+            .map(|_| self.shared_state.info_hashes[self.rng.gen_range(0..num_info_hashes)])
+            .collect();
+
+        let request = ScrapeRequest {
+            connection_id: connection_ids[socket_index],
+            transaction_id: TransactionId::new(self.rng.gen()),
+            info_hashes,
+        };
+
+        let mut cursor = Cursor::new(self.buffer);
+
+        request.write_bytes(&mut cursor).unwrap();
+
+        let position = cursor.position() as usize;
+
+        match self.sockets[socket_index].send(&cursor.get_ref()[..position]) {
+            Ok(_) => {
+                self.statistics.requests += 1;
+            }
+            Err(err) => {
+                eprintln!("Couldn't send packet: {:?}", err);
+            }
+        }
+    }
+
     fn handle_response(&mut self, response: Response) {
         match response {
             Response::Connect(_) => {
@@ -325,8 +389,13 @@ impl Worker {
             Response::Scrape(_) => {
                 self.statistics.responses_scrape += 1;
             }
-            Response::Error(_) => {
+            Response::Error(r) => {
                 self.statistics.responses_error += 1;
+
+                *self
+                    .error_responses
+                    .entry(r.message.into_owned())
+                    .or_default() += 1;
             }
         }
     }
@@ -361,6 +430,12 @@ impl Worker {
             self.statistics_sender.try_send(message).unwrap();
         }
 
+        if !self.error_responses.is_empty() {
+            let message = StatisticsMessage::ErrorResponses(self.error_responses.split_off(0));
+
+            self.statistics_sender.try_send(message).unwrap();
+        }
+
         self.statistics = LocalStatistics::default();
     }
 }
@@ -408,11 +483,19 @@ pub struct RequestTypeDist(WeightedIndex<usize>);
 
 impl RequestTypeDist {
     fn new(config: &Config) -> anyhow::Result<Self> {
-        let weights = [
-            config.requests.weight_announce,
-            config.requests.weight_connect,
-            config.requests.weight_scrape,
-        ];
+        let weights = if config.requests.scrape_flood {
+            [
+                0,
+                config.requests.weight_connect,
+                config.requests.weight_announce + config.requests.weight_scrape,
+            ]
+        } else {
+            [
+                config.requests.weight_announce,
+                config.requests.weight_connect,
+                config.requests.weight_scrape,
+            ]
+        };
 
         Ok(Self(WeightedIndex::new(weights)?))
     }
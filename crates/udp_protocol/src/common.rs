@@ -6,7 +6,10 @@ pub use aquatic_peer_id::{PeerClient, PeerId};
 use zerocopy::network_endian::{I32, I64, U16, U32};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-pub trait Ip: Clone + Copy + Debug + PartialEq + Eq + std::hash::Hash + AsBytes {}
+pub trait Ip: Clone + Copy + Debug + PartialEq + Eq + std::hash::Hash + AsBytes {
+    /// Length of IP header without options or extension headers
+    const IP_HEADER_LEN: usize;
+}
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, AsBytes, FromBytes, FromZeroes)]
 #[repr(transparent)]
@@ -103,7 +106,9 @@ pub struct ResponsePeer<I: Ip> {
 #[repr(transparent)]
 pub struct Ipv4AddrBytes(pub [u8; 4]);
 
-impl Ip for Ipv4AddrBytes {}
+impl Ip for Ipv4AddrBytes {
+    const IP_HEADER_LEN: usize = 20;
+}
 
 impl From<Ipv4AddrBytes> for Ipv4Addr {
     fn from(val: Ipv4AddrBytes) -> Self {
@@ -121,7 +126,9 @@ impl From<Ipv4Addr> for Ipv4AddrBytes {
 #[repr(transparent)]
 pub struct Ipv6AddrBytes(pub [u8; 16]);
 
-impl Ip for Ipv6AddrBytes {}
+impl Ip for Ipv6AddrBytes {
+    const IP_HEADER_LEN: usize = 40;
+}
 
 impl From<Ipv6AddrBytes> for Ipv6Addr {
     fn from(val: Ipv6AddrBytes) -> Self {
@@ -11,6 +11,17 @@ use super::common::*;
 
 const PROTOCOL_IDENTIFIER: i64 = 4_497_486_125_440;
 
+/// Length of connect requests
+pub const CONNECT_REQUEST_LEN: usize = 16;
+/// Minimum length of announce requests. Extensions may follow.
+pub const ANNOUNCE_REQUEST_MIN_LEN: usize = 98;
+/// Minimum length of scrape requests, which contain at least one info hash
+pub const SCRAPE_REQUEST_MIN_LEN: usize = 36;
+
+/// Error message in responses to scrape requests with more info hashes than
+/// allowed by the tracker. Same as `aquatic_common::TOO_MANY_SCRAPE_TORRENTS`.
+pub const TOO_MANY_SCRAPE_TORRENTS: &str = "Too many info hashes in scrape request";
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Request {
     Connect(ConnectRequest),
@@ -27,12 +38,43 @@ impl Request {
         }
     }
 
-    pub fn parse_bytes(bytes: &[u8], max_scrape_torrents: u8) -> Result<Self, RequestParseError> {
+    /// Check if datagram has the right length for its action
+    ///
+    /// Datagrams with unknown actions are not considered to have an invalid
+    /// length.
+    pub fn length_valid(bytes: &[u8]) -> bool {
+        let action = match bytes.get(8..12) {
+            Some(action_bytes) => I32::from_bytes(action_bytes.try_into().unwrap()),
+            None => return false,
+        };
+
+        match action.get() {
+            0 => bytes.len() == CONNECT_REQUEST_LEN,
+            1 => bytes.len() >= ANNOUNCE_REQUEST_MIN_LEN,
+            2 => bytes.len() >= SCRAPE_REQUEST_MIN_LEN,
+            _ => true,
+        }
+    }
+
+    /// Scrape requests with more than `max_scrape_torrents` info hashes are
+    /// rejected with a sendable error
+    ///
+    /// Datagrams with invalid length (see [`Request::length_valid`]) are
+    /// rejected with an unsendable error, so that short packets with spoofed
+    /// source addresses can't be used to trigger responses.
+    pub fn parse_bytes(
+        bytes: &[u8],
+        max_scrape_torrents: usize,
+    ) -> Result<Self, RequestParseError> {
         let action = bytes
             .get(8..12)
             .map(|bytes| I32::from_bytes(bytes.try_into().unwrap()))
             .ok_or_else(|| RequestParseError::unsendable_text("Couldn't parse action"))?;
 
+        if !Self::length_valid(bytes) {
+            return Err(RequestParseError::unsendable_text("Invalid request length"));
+        }
+
         match action.get() {
             // Connect
             0 => {
@@ -58,13 +100,7 @@ impl Request {
                 let request = AnnounceRequest::read_from_prefix(bytes)
                     .ok_or_else(|| RequestParseError::unsendable_text("invalid data"))?;
 
-                if request.port.0.get() == 0 {
-                    Err(RequestParseError::sendable_text(
-                        "Port can't be 0",
-                        request.connection_id,
-                        request.transaction_id,
-                    ))
-                } else if !matches!(request.event.0.get(), (0..=3)) {
+                if !matches!(request.event.0.get(), (0..=3)) {
                     // Make sure not to allow AnnounceEventBytes with invalid value
                     Err(RequestParseError::sendable_text(
                         "Invalid announce event",
@@ -91,18 +127,10 @@ impl Request {
                     let position = bytes.position() as usize;
                     let inner = bytes.into_inner();
 
-                    // Slice will be empty if position == inner.len()
+                    // Slice is not empty, since length has been checked
                     &inner[position..]
                 };
 
-                if remaining_bytes.is_empty() {
-                    return Err(RequestParseError::sendable_text(
-                        "Full scrapes are not allowed",
-                        connection_id,
-                        transaction_id,
-                    ));
-                }
-
                 let info_hashes = FromBytes::slice_from(remaining_bytes).ok_or_else(|| {
                     RequestParseError::sendable_text(
                         "Invalid info hash list",
@@ -111,9 +139,15 @@ impl Request {
                     )
                 })?;
 
-                let info_hashes = Vec::from(
-                    &info_hashes[..(max_scrape_torrents as usize).min(info_hashes.len())],
-                );
+                if info_hashes.len() > max_scrape_torrents {
+                    return Err(RequestParseError::sendable_text(
+                        TOO_MANY_SCRAPE_TORRENTS,
+                        connection_id,
+                        transaction_id,
+                    ));
+                }
+
+                let info_hashes = Vec::from(info_hashes);
 
                 Ok((ScrapeRequest {
                     connection_id,
@@ -352,7 +386,7 @@ mod tests {
         let mut buf = Vec::new();
 
         request.clone().write_bytes(&mut buf).unwrap();
-        let r2 = Request::parse_bytes(&buf[..], ::std::u8::MAX).unwrap();
+        let r2 = Request::parse_bytes(&buf[..], usize::MAX).unwrap();
 
         let success = request == r2;
 
@@ -409,6 +443,74 @@ mod tests {
         request_bytes.extend(2i32.to_be_bytes());
         request_bytes.extend(0i32.to_be_bytes());
 
-        Request::parse_bytes(&request_bytes, 1).unwrap_err();
+        assert!(!Request::length_valid(&request_bytes));
+        assert!(matches!(
+            Request::parse_bytes(&request_bytes, 1),
+            Err(RequestParseError::Unsendable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_length_valid() {
+        assert_eq!(
+            ::std::mem::size_of::<AnnounceRequest>(),
+            ANNOUNCE_REQUEST_MIN_LEN
+        );
+
+        let request_bytes = |action: i32, len: usize| {
+            let mut bytes = vec![0u8; len.max(12)];
+
+            bytes[8..12].copy_from_slice(&action.to_be_bytes());
+            bytes.truncate(len);
+
+            bytes
+        };
+
+        assert!(!Request::length_valid(&request_bytes(0, 11)));
+
+        assert!(!Request::length_valid(&request_bytes(0, 15)));
+        assert!(Request::length_valid(&request_bytes(0, 16)));
+        assert!(!Request::length_valid(&request_bytes(0, 17)));
+
+        assert!(!Request::length_valid(&request_bytes(1, 97)));
+        assert!(Request::length_valid(&request_bytes(1, 98)));
+        assert!(Request::length_valid(&request_bytes(1, 120)));
+
+        assert!(!Request::length_valid(&request_bytes(2, 35)));
+        assert!(Request::length_valid(&request_bytes(2, 36)));
+
+        // Connect request with trailing data must not get a response
+        let mut connect_bytes = Vec::new();
+
+        ConnectRequest {
+            transaction_id: TransactionId::new(1),
+        }
+        .write_bytes(&mut connect_bytes)
+        .unwrap();
+
+        assert!(Request::parse_bytes(&connect_bytes, 1).is_ok());
+
+        connect_bytes.push(0);
+
+        assert!(matches!(
+            Request::parse_bytes(&connect_bytes, 1),
+            Err(RequestParseError::Unsendable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_scrape_request_with_too_many_info_hashes() {
+        let mut request_bytes = Vec::new();
+
+        request_bytes.extend(0i64.to_be_bytes());
+        request_bytes.extend(2i32.to_be_bytes());
+        request_bytes.extend(0i32.to_be_bytes());
+        request_bytes.extend([0u8; 40]);
+
+        assert!(Request::parse_bytes(&request_bytes, 2).is_ok());
+        assert!(matches!(
+            Request::parse_bytes(&request_bytes, 1),
+            Err(RequestParseError::Sendable { .. })
+        ));
     }
 }
@@ -7,6 +7,8 @@ use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use super::common::*;
 
+const UDP_HEADER_LEN: usize = 8;
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Response {
     Connect(ConnectResponse),
@@ -28,6 +30,27 @@ impl Response {
         }
     }
 
+    /// Number of bytes written by [`Response::write_bytes`]
+    pub fn serialized_len(&self) -> usize {
+        // Action
+        4 + match self {
+            Response::Connect(_) => size_of::<ConnectResponse>(),
+            Response::AnnounceIpv4(r) => {
+                size_of::<AnnounceResponseFixedData>()
+                    + r.peers.len() * size_of::<ResponsePeer<Ipv4AddrBytes>>()
+            }
+            Response::AnnounceIpv6(r) => {
+                size_of::<AnnounceResponseFixedData>()
+                    + r.peers.len() * size_of::<ResponsePeer<Ipv6AddrBytes>>()
+            }
+            Response::Scrape(r) => {
+                size_of::<TransactionId>()
+                    + r.torrent_stats.len() * size_of::<TorrentScrapeStatistics>()
+            }
+            Response::Error(r) => size_of::<TransactionId>() + r.message.len(),
+        }
+    }
+
     #[inline]
     pub fn parse_bytes(mut bytes: &[u8], ipv4: bool) -> Result<Self, io::Error> {
         let action = read_i32_ne(&mut bytes)?;
@@ -152,6 +175,22 @@ pub struct AnnounceResponse<I: Ip> {
 }
 
 impl<I: Ip> AnnounceResponse<I> {
+    /// Maximum number of peers in a response that fits in an IP packet of
+    /// `mtu` bytes, including IP and UDP headers
+    ///
+    /// The IP version of the packet is assumed to match the peer address
+    /// type.
+    pub fn max_peers_for_mtu(mtu: usize) -> usize {
+        Self::max_peers_for_len(mtu.saturating_sub(I::IP_HEADER_LEN + UDP_HEADER_LEN))
+    }
+
+    /// Maximum number of peers in a response that serializes to at most
+    /// `len` bytes
+    pub fn max_peers_for_len(len: usize) -> usize {
+        len.saturating_sub(4 + size_of::<AnnounceResponseFixedData>())
+            / size_of::<ResponsePeer<I>>()
+    }
+
     pub fn empty() -> Self {
         Self {
             fixed: FromZeroes::new_zeroed(),
@@ -185,6 +224,12 @@ pub struct ScrapeResponse {
 }
 
 impl ScrapeResponse {
+    /// Maximum number of torrents in a response that serializes to at most
+    /// `len` bytes
+    pub fn max_torrents_for_len(len: usize) -> usize {
+        len.saturating_sub(4 + size_of::<TransactionId>()) / size_of::<TorrentScrapeStatistics>()
+    }
+
     #[inline]
     pub fn write_bytes(&self, bytes: &mut impl Write) -> Result<(), io::Error> {
         bytes.write_i32::<NetworkEndian>(2)?;
@@ -305,6 +350,9 @@ mod tests {
         let mut buf = Vec::new();
 
         response.clone().write_bytes(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), response.serialized_len());
+
         let r2 = Response::parse_bytes(&buf[..], ipv4).unwrap();
 
         let success = response == r2;
@@ -339,4 +387,51 @@ mod tests {
     fn test_scrape_response_convert_identity(response: ScrapeResponse) -> bool {
         same_after_conversion(response.into(), true)
     }
+
+    fn fits_in_mtu<I: Ip + FromZeroes>(mtu: u16) -> bool {
+        let mtu = mtu as usize;
+        let headers_len = I::IP_HEADER_LEN + UDP_HEADER_LEN;
+        let num_peers = AnnounceResponse::<I>::max_peers_for_mtu(mtu);
+
+        let mut response = AnnounceResponse::<I>::empty();
+
+        response.peers = vec![ResponsePeer::new_zeroed(); num_peers];
+
+        let mut buffer = Vec::new();
+
+        response.write_bytes(&mut buffer).unwrap();
+
+        let len = headers_len + buffer.len();
+
+        // Fits, and one more peer wouldn't fit
+        (len <= mtu || num_peers == 0) && len + size_of::<ResponsePeer<I>>() > mtu
+    }
+
+    #[quickcheck]
+    fn test_announce_response_ipv4_max_peers_for_mtu(mtu: u16) -> bool {
+        fits_in_mtu::<Ipv4AddrBytes>(mtu)
+    }
+
+    #[quickcheck]
+    fn test_announce_response_ipv6_max_peers_for_mtu(mtu: u16) -> bool {
+        fits_in_mtu::<Ipv6AddrBytes>(mtu)
+    }
+
+    #[quickcheck]
+    fn test_scrape_response_max_torrents_for_len(len: u16) -> bool {
+        let len = len as usize;
+        let num_torrents = ScrapeResponse::max_torrents_for_len(len);
+
+        let response = ScrapeResponse {
+            transaction_id: TransactionId::new(1),
+            torrent_stats: vec![TorrentScrapeStatistics::new_zeroed(); num_torrents],
+        };
+
+        let mut buffer = Vec::new();
+
+        response.write_bytes(&mut buffer).unwrap();
+
+        (buffer.len() <= len || num_torrents == 0)
+            && buffer.len() + size_of::<TorrentScrapeStatistics>() > len
+    }
 }
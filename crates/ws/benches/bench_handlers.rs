@@ -0,0 +1,201 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::SmallRng, SeedableRng};
+use std::time::Duration;
+
+use aquatic_common::ServerStartInstant;
+use aquatic_ws::bench_support::TorrentMaps;
+use aquatic_ws::common::{ConnectionId, ConsumerId, InMessageMeta, IpVersion};
+use aquatic_ws::config::Config;
+use aquatic_ws_protocol::{
+    common::*,
+    incoming::{
+        AnnounceEvent, AnnounceRequest, AnnounceRequestOffer, ScrapeRequest,
+        ScrapeRequestInfoHashes,
+    },
+};
+
+/// Insert `num_peers` peers into swarm of each info hash, returning
+/// synthetic connection meta of each peer
+fn setup(
+    config: &Config,
+    rng: &mut SmallRng,
+    torrent_maps: &mut TorrentMaps,
+    server_start_instant: ServerStartInstant,
+    info_hashes: &[InfoHash],
+    num_peers: usize,
+) -> Vec<(PeerId, InMessageMeta)> {
+    let mut connection_ids = slotmap::SlotMap::<ConnectionId, ()>::with_key();
+    let mut out_messages = Vec::new();
+
+    let peers = (0..num_peers)
+        .map(|i| {
+            let mut peer_id = PeerId([0; 20]);
+
+            peer_id.0[..8].copy_from_slice(&(i as u64).to_be_bytes());
+
+            let meta = InMessageMeta {
+                out_message_consumer_id: ConsumerId((i % 4) as u8),
+                connection_id: connection_ids.insert(()),
+                ip_version: IpVersion::V4,
+                pending_scrape_id: None,
+            };
+
+            (peer_id, meta)
+        })
+        .collect::<Vec<_>>();
+
+    for info_hash in info_hashes {
+        for (i, (peer_id, meta)) in peers.iter().enumerate() {
+            torrent_maps.handle_announce_request(
+                config,
+                rng,
+                &mut out_messages,
+                server_start_instant,
+                *meta,
+                AnnounceRequest {
+                    action: AnnounceAction::Announce,
+                    info_hash: *info_hash,
+                    peer_id: *peer_id,
+                    bytes_left: Some(i % 2),
+                    event: Some(AnnounceEvent::Started),
+                    offers: None,
+                    numwant: None,
+                    answer: None,
+                    answer_to_peer_id: None,
+                    answer_offer_id: None,
+                },
+            );
+
+            out_messages.clear();
+        }
+    }
+
+    peers
+}
+
+pub fn bench_announce_with_offers(c: &mut Criterion) {
+    let config = Config::default();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let server_start_instant = ServerStartInstant::new();
+    let mut torrent_maps = TorrentMaps::default();
+
+    let info_hash = InfoHash([1; 20]);
+
+    let peers = setup(
+        &config,
+        &mut rng,
+        &mut torrent_maps,
+        server_start_instant,
+        &[info_hash],
+        1000,
+    );
+
+    let offers = (0..config.protocol.max_offers)
+        .map(|i| AnnounceRequestOffer {
+            offer: RtcOffer {
+                t: RtcOfferType::Offer,
+                sdp: "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\n".repeat(8),
+            },
+            offer_id: OfferId([i as u8; 20]),
+        })
+        .collect::<Vec<_>>();
+
+    let mut out_messages = Vec::new();
+    let mut i = 0;
+
+    c.bench_function("handle-announce-request-with-offers", |b| {
+        b.iter(|| {
+            let (peer_id, meta) = peers[i % peers.len()];
+
+            i += 1;
+
+            torrent_maps.handle_announce_request(
+                &config,
+                &mut rng,
+                &mut out_messages,
+                server_start_instant,
+                meta,
+                black_box(AnnounceRequest {
+                    action: AnnounceAction::Announce,
+                    info_hash,
+                    peer_id,
+                    bytes_left: Some(1),
+                    event: None,
+                    offers: Some(offers.clone()),
+                    numwant: Some(offers.len()),
+                    answer: None,
+                    answer_to_peer_id: None,
+                    answer_offer_id: None,
+                }),
+            );
+
+            black_box(&out_messages);
+
+            out_messages.clear();
+        })
+    });
+}
+
+pub fn bench_scrape(c: &mut Criterion) {
+    let config = Config::default();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let server_start_instant = ServerStartInstant::new();
+    let mut torrent_maps = TorrentMaps::default();
+
+    let info_hashes = (0..1000u32)
+        .map(|i| {
+            let mut info_hash = InfoHash([0; 20]);
+
+            info_hash.0[..4].copy_from_slice(&i.to_be_bytes());
+
+            info_hash
+        })
+        .collect::<Vec<_>>();
+
+    let peers = setup(
+        &config,
+        &mut rng,
+        &mut torrent_maps,
+        server_start_instant,
+        &info_hashes,
+        10,
+    );
+
+    let mut out_messages = Vec::new();
+    let mut i = 0;
+
+    c.bench_function("handle-scrape-request", |b| {
+        b.iter(|| {
+            let (_, meta) = peers[i % peers.len()];
+
+            let request_info_hashes = (0..config.protocol.max_scrape_torrents)
+                .map(|j| info_hashes[(i + j * 7) % info_hashes.len()])
+                .collect();
+
+            i += 1;
+
+            torrent_maps.handle_scrape_request(
+                &config,
+                &mut out_messages,
+                meta,
+                black_box(ScrapeRequest {
+                    action: ScrapeAction::Scrape,
+                    info_hashes: Some(ScrapeRequestInfoHashes::Multiple(request_info_hashes)),
+                }),
+            );
+
+            black_box(&out_messages);
+
+            out_messages.clear();
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .measurement_time(Duration::from_secs(30))
+        .significance_level(0.01);
+    targets = bench_announce_with_offers, bench_scrape
+}
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+//! Entry points for benchmarks (see `benches/`). Not part of the public API.
+
+use aquatic_common::ServerStartInstant;
+use aquatic_ws_protocol::incoming::{AnnounceRequest, ScrapeRequest};
+use aquatic_ws_protocol::outgoing::OutMessage;
+use rand::rngs::SmallRng;
+
+use crate::common::{InMessageMeta, OutMessageMeta};
+use crate::config::Config;
+use crate::workers::swarm::storage;
+
+/// Torrent maps of a single swarm worker
+pub struct TorrentMaps(storage::TorrentMaps);
+
+impl Default for TorrentMaps {
+    fn default() -> Self {
+        Self(storage::TorrentMaps::new(0))
+    }
+}
+
+impl TorrentMaps {
+    pub fn handle_announce_request(
+        &mut self,
+        config: &Config,
+        rng: &mut SmallRng,
+        out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
+        server_start_instant: ServerStartInstant,
+        meta: InMessageMeta,
+        request: AnnounceRequest,
+    ) {
+        self.0.handle_announce_request(
+            config,
+            rng,
+            out_messages,
+            server_start_instant,
+            meta,
+            request,
+        )
+    }
+
+    pub fn handle_scrape_request(
+        &mut self,
+        config: &Config,
+        out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
+        meta: InMessageMeta,
+        request: ScrapeRequest,
+    ) {
+        self.0
+            .handle_scrape_request(config, out_messages, meta, request)
+    }
+}
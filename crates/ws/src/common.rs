@@ -1,10 +1,19 @@
-use std::{net::IpAddr, sync::Arc};
+use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::connection_table::ConnectionTables;
+use aquatic_common::observer::{AnnounceSubscribers, SwarmSummaries};
+use aquatic_common::pending_scrape::PendingScrapeKey;
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::user_agent::UserAgentStatistics;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_metrics::{names, Counter, Gauge, HyperLogLog, Labels, Registry};
 
 pub use aquatic_common::ValidUntil;
 use aquatic_ws_protocol::common::{InfoHash, PeerId};
 
+use crate::config::Config;
+
 #[derive(Copy, Clone, Debug)]
 pub enum IpVersion {
     V4,
@@ -12,24 +21,41 @@ pub enum IpVersion {
 }
 
 impl IpVersion {
-    pub fn canonical_from_ip(ip: IpAddr) -> IpVersion {
-        match ip {
-            IpAddr::V4(_) => Self::V4,
-            IpAddr::V6(addr) => match addr.octets() {
-                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, _, _, _, _] => Self::V4,
-                _ => Self::V6,
-            },
+    /// IPv4-mapped addresses count as IPv4 addresses
+    pub fn from_addr(addr: CanonicalSocketAddr) -> IpVersion {
+        if addr.is_ipv4() {
+            Self::V4
+        } else {
+            Self::V6
         }
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    /// Latest connection table snapshots of socket workers, for diagnostics
+    pub connection_tables: Arc<ConnectionTables>,
+    /// Updated by swarm workers after cleaning torrents
+    pub swarm_summaries: Arc<SwarmSummaries>,
+    pub announce_subscribers: AnnounceSubscribers,
+    pub shutdown: ShutdownSignal,
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            access_list: Default::default(),
+            connection_tables: Arc::new(ConnectionTables::new(config.socket_workers)),
+            swarm_summaries: Arc::new(SwarmSummaries::new(config.swarm_workers)),
+            announce_subscribers: Default::default(),
+            shutdown: Default::default(),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
-pub struct PendingScrapeId(pub u8);
+pub struct PendingScrapeId(pub PendingScrapeKey);
 
 #[derive(Copy, Clone, Debug)]
 pub struct ConsumerId(pub u8);
@@ -74,3 +100,83 @@ pub enum SwarmControlMessage {
         announced_info_hashes: Vec<(InfoHash, PeerId)>,
     },
 }
+
+/// Reason for a connection being closed, for statistics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionCloseReason {
+    /// Closed by connection cleaning, e.g., due to idleness
+    Idle,
+    /// Peer sent close frame or closed stream
+    PeerClose,
+    Error,
+}
+
+/// Connection lifecycle statistics of one socket worker
+///
+/// Counters are registered in the worker's registry, from which they are
+/// collected by the statistics reporter. Estimates of distinct values are
+/// registered in a registry shared by all socket workers.
+pub struct SocketWorkerStatistics {
+    pub tcp_accepts: Arc<Counter>,
+    pub tls_handshake_successes: Arc<Counter>,
+    pub tls_handshake_failures: Arc<Counter>,
+    pub ws_handshake_successes: Arc<Counter>,
+    pub ws_handshake_failures: Arc<Counter>,
+    /// Subset of handshake failures
+    pub ws_handshake_origin_rejections: Arc<Counter>,
+    /// Subset of handshake failures
+    pub ws_handshake_user_agent_rejections: Arc<Counter>,
+    pub active_connections: Arc<Gauge>,
+    pub connections_closed_idle: Arc<Counter>,
+    pub connections_closed_peer: Arc<Counter>,
+    pub connections_closed_error: Arc<Counter>,
+    /// Addresses of accepted connections (IPv6: prefixes of
+    /// `network.ipv6_prefix_len` bits)
+    pub unique_ips: Arc<HyperLogLog>,
+    pub user_agents: UserAgentStatistics,
+}
+
+impl SocketWorkerStatistics {
+    pub fn new(config: &Config, registry: &Registry, shared_registry: &Registry) -> Self {
+        let with_reason = |r: &str| Labels::new().with("reason", r);
+
+        Self {
+            tcp_accepts: registry.counter(names::TCP_ACCEPTS_TOTAL, Labels::new()),
+            tls_handshake_successes: registry
+                .counter(names::TLS_HANDSHAKE_SUCCESSES_TOTAL, Labels::new()),
+            tls_handshake_failures: registry
+                .counter(names::TLS_HANDSHAKE_FAILURES_TOTAL, Labels::new()),
+            ws_handshake_successes: registry
+                .counter(names::WS_HANDSHAKE_SUCCESSES_TOTAL, Labels::new()),
+            ws_handshake_failures: registry
+                .counter(names::WS_HANDSHAKE_FAILURES_TOTAL, Labels::new()),
+            ws_handshake_origin_rejections: registry
+                .counter(names::WS_HANDSHAKE_ORIGIN_REJECTIONS_TOTAL, Labels::new()),
+            ws_handshake_user_agent_rejections: registry.counter(
+                names::WS_HANDSHAKE_USER_AGENT_REJECTIONS_TOTAL,
+                Labels::new(),
+            ),
+            active_connections: registry.gauge(names::ACTIVE_CONNECTIONS, Labels::new()),
+            connections_closed_idle: registry
+                .counter(names::CONNECTIONS_CLOSED_TOTAL, with_reason("idle")),
+            connections_closed_peer: registry
+                .counter(names::CONNECTIONS_CLOSED_TOTAL, with_reason("peer_close")),
+            connections_closed_error: registry
+                .counter(names::CONNECTIONS_CLOSED_TOTAL, with_reason("error")),
+            unique_ips: shared_registry.hyperloglog(names::UNIQUE_IPS, Labels::new()),
+            user_agents: UserAgentStatistics::new(
+                &config.user_agent,
+                registry,
+                names::WS_HANDSHAKES_BY_USER_AGENT_TOTAL,
+            ),
+        }
+    }
+
+    pub fn connection_closed(&self, reason: ConnectionCloseReason) {
+        match reason {
+            ConnectionCloseReason::Idle => self.connections_closed_idle.increment(1),
+            ConnectionCloseReason::PeerClose => self.connections_closed_peer.increment(1),
+            ConnectionCloseReason::Error => self.connections_closed_error.increment(1),
+        }
+    }
+}
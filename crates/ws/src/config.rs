@@ -1,7 +1,10 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, privileges::PrivilegeConfig, rustls_config::SessionResumption,
+    user_agent::UserAgentConfig,
+};
 use serde::Deserialize;
 
 use aquatic_common::cli::LogLevel;
@@ -44,8 +47,16 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// User-Agent header filtering in WebSocket handshakes and per-agent
+    /// connection statistics
+    pub user_agent: UserAgentConfig,
+    pub statistics: StatisticsConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub maintenance: MaintenanceConfig,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
+    #[cfg(feature = "numa")]
+    pub numa: NumaConfig,
 }
 
 impl Default for Config {
@@ -59,8 +70,14 @@ impl Default for Config {
             cleaning: CleaningConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            user_agent: UserAgentConfig::default(),
+            statistics: StatisticsConfig::default(),
+            diagnostics: DiagnosticsConfig::default(),
+            maintenance: MaintenanceConfig::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
+            #[cfg(feature = "numa")]
+            numa: Default::default(),
         }
     }
 }
@@ -69,15 +86,53 @@ impl aquatic_common::cli::Config for Config {
     fn get_log_level(&self) -> Option<LogLevel> {
         Some(self.log_level)
     }
+
+    fn enabled_features() -> Vec<&'static str> {
+        let mut features = Vec::new();
+
+        if cfg!(feature = "metrics") {
+            features.push("metrics");
+        }
+        if cfg!(feature = "prometheus") {
+            features.push("prometheus");
+        }
+        if cfg!(feature = "mimalloc") {
+            features.push("mimalloc");
+        }
+        if cfg!(feature = "numa") {
+            features.push("numa");
+        }
+
+        features
+    }
+
+    fn handles_termination_signals() -> bool {
+        true
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
     /// Bind to this address
+    ///
+    /// With port 0, the operating system chooses a free port, which is
+    /// logged on startup
     pub address: SocketAddr,
     /// Only allow access over IPv6
     pub only_ipv6: bool,
+    /// Bind sockets to this network interface (SO_BINDTODEVICE), e.g.,
+    /// "eth1", so that only traffic over it is handled, regardless of
+    /// routing. Linux only. Requires CAP_NET_RAW on kernels older than 5.7.
+    ///
+    /// Leave empty to not bind to an interface.
+    pub bind_device: String,
+    /// Length of prefixes that IPv6 addresses are aggregated by in per-IP
+    /// accounting, i.e., when counting connections per source IP in the
+    /// connection table endpoint
+    ///
+    /// Hosts can often pick new addresses in a whole /64 network at will.
+    pub ipv6_prefix_len: u8,
     /// Maximum number of pending TCP connections
     pub tcp_backlog: i32,
 
@@ -93,6 +148,17 @@ pub struct NetworkConfig {
     pub tls_certificate_path: PathBuf,
     /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
     pub tls_private_key_path: PathBuf,
+    /// Number of TLS sessions to cache for resumption (0 = disable cache)
+    ///
+    /// Resuming sessions lets returning clients skip the expensive part of
+    /// the handshake. The cache is cleared when TLS files are reloaded.
+    pub tls_session_cache_size: usize,
+    /// Issue stateless TLS session tickets, so that clients can resume
+    /// sessions without taking up cache entries
+    ///
+    /// Ticket keys are rotated periodically and regenerated when TLS files
+    /// are reloaded. TLS 1.3 early data (0-RTT) is not accepted either way.
+    pub tls_session_tickets: bool,
 
     pub websocket_max_message_size: usize,
     pub websocket_max_frame_size: usize,
@@ -101,6 +167,34 @@ pub struct NetworkConfig {
     /// Return a HTTP 200 Ok response when receiving GET /health. Can not be
     /// combined with enable_tls.
     pub enable_http_health_checks: bool,
+
+    /// Origins (e.g., "https://example.com") of web pages allowed to open
+    /// WebSocket connections. Browsers set the Origin header, so handshakes
+    /// from other sites are rejected with HTTP 403 Forbidden. Requests
+    /// without an Origin header, e.g., from non-browser clients, are always
+    /// accepted. Leave empty to accept all origins that aren't denied.
+    ///
+    /// A leading wildcard label matches any subdomain, e.g.,
+    /// "https://*.example.com" matches "https://app.example.com" but not
+    /// "https://example.com".
+    pub allowed_origins: Vec<String>,
+    /// Origins of web pages not allowed to open WebSocket connections, with
+    /// the same syntax as allowed_origins. Takes precedence over
+    /// allowed_origins.
+    pub denied_origins: Vec<String>,
+    /// Value of Access-Control-Allow-Origin header to send in WebSocket
+    /// handshake and health check responses, e.g., "*". Leave empty to not
+    /// send the header.
+    pub access_control_allow_origin: String,
+}
+
+impl NetworkConfig {
+    pub fn tls_session_resumption(&self) -> SessionResumption {
+        SessionResumption {
+            cache_size: self.tls_session_cache_size,
+            tickets: self.tls_session_tickets,
+        }
+    }
 }
 
 impl Default for NetworkConfig {
@@ -108,17 +202,25 @@ impl Default for NetworkConfig {
         Self {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
             only_ipv6: false,
+            bind_device: String::new(),
+            ipv6_prefix_len: 64,
             tcp_backlog: 1024,
 
             enable_tls: false,
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_session_cache_size: 256,
+            tls_session_tickets: false,
 
             websocket_max_message_size: 64 * 1024,
             websocket_max_frame_size: 16 * 1024,
             websocket_write_buffer_size: 8 * 1024,
 
             enable_http_health_checks: false,
+
+            allowed_origins: Vec::new(),
+            denied_origins: Vec::new(),
+            access_control_allow_origin: String::new(),
         }
     }
 }
@@ -127,11 +229,33 @@ impl Default for NetworkConfig {
 #[serde(default, deny_unknown_fields)]
 pub struct ProtocolConfig {
     /// Maximum number of torrents to accept in scrape request
+    ///
+    /// Requests with more torrents get an error response.
     pub max_scrape_torrents: usize,
     /// Maximum number of offers to accept in announce request
     pub max_offers: usize,
+    /// Maximum number of peers to pass offers from one announce request on
+    /// to
+    ///
+    /// Lower it to reduce fan-out of offers in large swarms. Offers beyond
+    /// this number (or `max_offers`) are dropped.
+    pub max_peers_in_response: usize,
+    /// Maximum number of distinct info hashes that may be announced on a
+    /// single connection (0 = no limit)
+    ///
+    /// Announce requests for further info hashes get an error response.
+    /// Since offers and answers are only relayed between peers announced to
+    /// the same info hash, this limits how many swarms a single connection
+    /// can send signaling messages to.
+    pub max_info_hashes_per_connection: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Include non-standard `ipv4` and `ipv6` objects with per-IP-version
+    /// seeder and leecher counts in scrape response entries
+    pub scrape_ip_version_breakdown: bool,
+    /// Return combined IPv4 and IPv6 seeder and leecher counts in scrape
+    /// responses instead of only counts for the IP version of the client
+    pub scrape_merge_ip_versions: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -139,7 +263,11 @@ impl Default for ProtocolConfig {
         Self {
             max_scrape_torrents: 255,
             max_offers: 10,
+            max_peers_in_response: 10,
+            max_info_hashes_per_connection: 0,
             peer_announce_interval: 120,
+            scrape_ip_version_breakdown: false,
+            scrape_merge_ip_versions: false,
         }
     }
 }
@@ -151,6 +279,10 @@ pub struct CleaningConfig {
     pub torrent_cleaning_interval: u64,
     /// Remove peers that have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Remove seeders that have not announced for this long (seconds)
+    /// instead, since they often announce less frequently. 0 = use
+    /// `max_peer_age` for seeders too.
+    pub max_seeder_age: u32,
     /// Require that offers are answered to withing this period (seconds)
     pub max_offer_age: u32,
     // Clean connections this often (seconds)
@@ -169,6 +301,7 @@ impl Default for CleaningConfig {
         Self {
             torrent_cleaning_interval: 30,
             max_peer_age: 180,
+            max_seeder_age: 0,
             max_offer_age: 120,
             max_connection_idle: 180,
             connection_cleaning_interval: 30,
@@ -177,6 +310,100 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Collect and print/write statistics this often (seconds)
+    ///
+    /// Reported counts are totals since the tracker was started. Connection
+    /// lifecycle statistics include TCP accepts, TLS and WebSocket handshake
+    /// outcomes, active connections and closed connections by reason.
+    pub interval: u64,
+    /// Print statistics to standard output
+    pub print_to_stdout: bool,
+    /// Append statistics as a JSON object per line to a file
+    pub write_json_to_file: bool,
+    /// Path to append JSON lines to
+    pub json_file_path: PathBuf,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        self.interval != 0 && (self.print_to_stdout || self.write_json_to_file)
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            print_to_stdout: false,
+            write_json_to_file: false,
+            json_file_path: "tmp/statistics.jsonl".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    /// Serve summaries of socket worker connection tables as JSON on
+    /// `GET /connections`
+    ///
+    /// Summaries include connection counts by state, age and idle time
+    /// distributions and the source IPs with the most connections. They are
+    /// updated when connections are cleaned (see
+    /// `cleaning.connection_cleaning_interval`).
+    ///
+    /// Version, commit, enabled cargo features and configuration hash (as
+    /// printed with `--version --verbose`) are served on `GET /build_info`.
+    pub run_connection_table_endpoint: bool,
+    /// Address to run connection table endpoint on. Since source IPs are
+    /// exposed, it should not be reachable from the public internet.
+    pub connection_table_endpoint_address: SocketAddr,
+    /// Number of source IPs with the most connections to include
+    pub top_source_ips: usize,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self {
+            run_connection_table_endpoint: false,
+            connection_table_endpoint_address: SocketAddr::from(([127, 0, 0, 1], 9100)),
+            top_source_ips: 10,
+        }
+    }
+}
+
+/// Heavy maintenance tasks run on a schedule, e.g., during low-traffic hours
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Cron-like schedule in UTC with fields for minute, hour, day of month,
+    /// month and day of week, e.g. "30 3 * * *" for 03:30 every day or
+    /// "0 2-5 * * 6,7" for every hour from 02:00 to 05:00 on weekends
+    ///
+    /// Empty = disabled
+    pub schedule: String,
+    /// Reload access list
+    pub reload_access_list: bool,
+}
+
+impl MaintenanceConfig {
+    pub fn active(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            schedule: String::new(),
+            reload_access_list: true,
+        }
+    }
+}
+
 #[cfg(feature = "metrics")]
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -199,6 +426,21 @@ pub struct MetricsConfig {
     pub peer_id_prefixes: bool,
 }
 
+/// NUMA-aware worker placement (experimental)
+///
+/// Socket and swarm workers are distributed over NUMA nodes in round-robin
+/// order and restricted to the CPUs of their node. Since swarm workers create
+/// their torrent maps after being placed, the kernel's default first-touch
+/// policy allocates the maps from the worker's own node. Requests are still
+/// routed to swarm workers by info hash, since each torrent is handled by
+/// exactly one swarm worker.
+#[cfg(feature = "numa")]
+#[derive(Clone, Debug, Default, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NumaConfig {
+    pub active: bool,
+}
+
 #[cfg(feature = "metrics")]
 impl Default for MetricsConfig {
     fn default() -> Self {
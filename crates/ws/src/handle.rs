@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_common::observer::{AnnounceEvent, SwarmSummary};
+use aquatic_common::rustls_config::{create_rustls_config, RustlsConfig};
+use aquatic_common::shutdown::{join_workers, SHUTDOWN_TIMEOUT};
+use aquatic_common::WorkerType;
+use arc_swap::ArcSwap;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+
+use aquatic_common::access_list::update_access_list;
+
+use crate::common::State;
+use crate::config::Config;
+
+/// Handle to tracker started with [`crate::run_with_handle`]
+///
+/// Dropping the handle tells all workers to stop without waiting for them.
+pub struct TrackerHandle {
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    /// Contents of currently loaded TLS certificate file
+    opt_tls_cert_data: Option<Vec<u8>>,
+    local_addr: SocketAddr,
+    join_handles: Vec<(WorkerType, JoinHandle<anyhow::Result<()>>)>,
+}
+
+impl TrackerHandle {
+    pub(crate) fn new(
+        config: Config,
+        state: State,
+        opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+        opt_tls_cert_data: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            local_addr: config.network.address,
+            config,
+            state,
+            opt_tls_config,
+            opt_tls_cert_data,
+            join_handles: Vec::new(),
+        }
+    }
+
+    /// Address that socket workers are bound to. If port 0 was configured,
+    /// this contains the port chosen by the operating system.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Receive announce requests handled by swarm workers, buffering at
+    /// most `capacity` events. Events are dropped while the buffer is full.
+    pub fn subscribe_announces(&self, capacity: usize) -> Receiver<AnnounceEvent> {
+        self.state.announce_subscribers.subscribe(capacity)
+    }
+
+    /// Torrent and peer counts, as of the latest torrent
+    /// cleaning of each swarm worker
+    pub fn stats(&self) -> SwarmSummary {
+        self.state.swarm_summaries.total()
+    }
+
+    /// Block until shutdown is triggered by a termination signal, in which
+    /// case workers are waited for as in [`Self::shutdown`], or until any
+    /// worker stops or panics, returning an error describing what happened
+    pub fn wait(mut self) -> anyhow::Result<()> {
+        loop {
+            if self.state.shutdown.sleep(Duration::from_secs(5)) {
+                return self.shutdown();
+            }
+
+            if let Err(err) = self.check_workers() {
+                // Worker might have stopped because shutdown was triggered
+                // after sleeping
+                if self.state.shutdown.is_triggered() {
+                    return self.shutdown();
+                }
+
+                return Err(err);
+            }
+        }
+    }
+
+    /// Tell workers to stop and wait for them to do so, for at most ten
+    /// seconds
+    ///
+    /// Socket workers stop accepting connections and close existing ones,
+    /// after which swarm workers stop once they have handled remaining
+    /// messages. The statistics thread exports metrics a final time before
+    /// stopping.
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.state.shutdown.trigger();
+
+        join_workers(::std::mem::take(&mut self.join_handles), SHUTDOWN_TIMEOUT)
+    }
+
+    pub(crate) fn push_worker(
+        &mut self,
+        worker_type: WorkerType,
+        handle: JoinHandle<anyhow::Result<()>>,
+    ) {
+        self.join_handles.push((worker_type, handle));
+    }
+
+    /// Reload access list and TLS config when SIGUSR1 is received and
+    /// trigger shutdown when SIGTERM or SIGINT is
+    pub(crate) fn spawn_signal_handler(&mut self, mut signals: Signals) -> anyhow::Result<()> {
+        let config = self.config.clone();
+        let state = self.state.clone();
+        let opt_tls_config = self.opt_tls_config.clone();
+        let mut opt_tls_cert_data = self.opt_tls_cert_data.take();
+
+        let handle = Builder::new()
+            .name("signals".into())
+            .spawn(move || {
+                for signal in &mut signals {
+                    match signal {
+                        SIGUSR1 => {
+                            let _ = update_access_list(&config.access_list, &state.access_list);
+
+                            if let Some(tls_config) = opt_tls_config.as_ref() {
+                                match ::std::fs::read(&config.network.tls_certificate_path) {
+                                    Ok(data) if &data == opt_tls_cert_data.as_ref().unwrap() => {
+                                        ::log::info!("skipping tls config update: certificate identical to currently loaded");
+                                    }
+                                    Ok(data) => {
+                                        match create_rustls_config(
+                                            &config.network.tls_certificate_path,
+                                            &config.network.tls_private_key_path,
+                                            config.network.tls_session_resumption(),
+                                        ) {
+                                            Ok(config) => {
+                                                tls_config.store(Arc::new(config));
+                                                opt_tls_cert_data = Some(data);
+
+                                                ::log::info!("successfully updated tls config");
+                                            }
+                                            Err(err) => ::log::error!("could not update tls config: {:#}", err),
+                                        }
+                                    }
+                                    Err(err) => ::log::error!("couldn't read tls certificate file: {:#}", err),
+                                }
+                            }
+                        }
+                        SIGTERM | SIGINT => {
+                            ::log::info!("received signal {}, shutting down", signal);
+
+                            state.shutdown.trigger();
+
+                            break;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                Ok(())
+            })
+            .context("spawn signal worker")?;
+
+        self.push_worker(WorkerType::Signals, handle);
+
+        Ok(())
+    }
+
+    pub(crate) fn wait_until_bound(
+        &mut self,
+        receiver: mpsc::Receiver<SocketAddr>,
+    ) -> anyhow::Result<()> {
+        loop {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(addr) => {
+                    self.local_addr = addr;
+
+                    return Ok(());
+                }
+                Err(_) => self.check_workers()?,
+            }
+        }
+    }
+
+    /// Return error if any worker has stopped
+    fn check_workers(&mut self) -> anyhow::Result<()> {
+        let opt_index = self
+            .join_handles
+            .iter()
+            .position(|(_, handle)| handle.is_finished());
+
+        if let Some(index) = opt_index {
+            let (worker_type, handle) = self.join_handles.remove(index);
+
+            return match handle.join() {
+                Ok(Ok(())) => Err(anyhow::anyhow!("{} stopped", worker_type)),
+                Ok(Err(err)) => Err(err.context(format!("{} stopped", worker_type))),
+                Err(_) => Err(anyhow::anyhow!("{} panicked", worker_type)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TrackerHandle {
+    fn drop(&mut self) {
+        self.state.shutdown.trigger();
+    }
+}
@@ -1,23 +1,43 @@
+#[doc(hidden)]
+pub mod bench_support;
 pub mod common;
 pub mod config;
+mod handle;
+#[cfg(feature = "numa")]
+pub mod numa;
 pub mod workers;
 
-use std::sync::Arc;
-use std::thread::{sleep, Builder, JoinHandle};
+use std::net::SocketAddr;
+use std::sync::{mpsc::sync_channel, Arc};
+use std::thread::Builder;
 use std::time::Duration;
 
 use anyhow::Context;
+use aquatic_common::connection_table::spawn_connection_table_endpoint;
 use aquatic_common::rustls_config::create_rustls_config;
-use aquatic_common::{ServerStartInstant, WorkerType};
+use aquatic_common::{
+    validate_ipv6_prefix_len, validate_max_scrape_torrents, ServerStartInstant, WorkerType,
+};
+use aquatic_metrics::export::{run_reporter, Exporter, JsonFileExporter, StdoutExporter};
+use aquatic_metrics::{Labels, Registry};
 use arc_swap::ArcSwap;
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
-use signal_hook::{consts::SIGUSR1, iterator::Signals};
-
-use aquatic_common::access_list::update_access_list;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+
+use aquatic_common::access_list::{spawn_access_list_updater, update_access_list};
+use aquatic_common::bind::{BindNotifier, BoundCallback};
+use aquatic_common::maintenance::{spawn_maintenance_worker, Schedule};
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::user_agent::UserAgentFilter;
 
 use common::*;
 use config::Config;
+use workers::socket::OriginPolicy;
+
+pub use handle::TrackerHandle;
 
 pub const APP_NAME: &str = "aquatic_ws: WebTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -25,15 +45,68 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const SHARED_IN_CHANNEL_SIZE: usize = 1024;
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
+    run_inner(config, None)
+}
+
+/// Run tracker, calling `callback` with the bound address once all socket
+/// workers are ready to accept connections
+///
+/// This is useful together with port 0 in `network.address`, which makes
+/// the operating system choose a free port, e.g., in tests.
+pub fn run_with_bound_callback<F>(config: Config, callback: F) -> ::anyhow::Result<()>
+where
+    F: FnOnce(SocketAddr) + Send + 'static,
+{
+    run_inner(config, Some(Box::new(callback)))
+}
+
+fn run_inner(config: Config, opt_callback: Option<BoundCallback>) -> ::anyhow::Result<()> {
+    let signals = Signals::new([SIGUSR1, SIGTERM, SIGINT])?;
+
+    let mut tracker = run_with_handle(config)?;
+
+    if let Some(callback) = opt_callback {
+        callback(tracker.local_addr());
+    }
+
+    tracker.spawn_signal_handler(signals)?;
+
+    tracker.wait()
+}
+
+/// Start tracker and return once all socket workers are ready to accept
+/// connections
+///
+/// The returned handle can be used to subscribe to announce events and to
+/// query swarm statistics. No signal handlers are installed.
+pub fn run_with_handle(mut config: Config) -> ::anyhow::Result<TrackerHandle> {
     if config.network.enable_tls && config.network.enable_http_health_checks {
         return Err(anyhow::anyhow!(
             "configuration: network.enable_tls and network.enable_http_health_check can't both be set to true"
         ));
     }
 
-    let mut signals = Signals::new([SIGUSR1])?;
+    if let Err(err) =
+        tungstenite::http::HeaderValue::from_str(&config.network.access_control_allow_origin)
+    {
+        return Err(anyhow::anyhow!(
+            "configuration: network.access_control_allow_origin is not a valid header value: {:#}",
+            err
+        ));
+    }
 
-    let state = State::default();
+    OriginPolicy::new(&config.network).with_context(|| "configuration")?;
+    UserAgentFilter::new(&config.user_agent).with_context(|| "configuration")?;
+    validate_max_scrape_torrents(config.protocol.max_scrape_torrents)?;
+    validate_ipv6_prefix_len(config.network.ipv6_prefix_len)?;
+
+    let state = State::new(&config);
+    let opt_maintenance_schedule: Option<Schedule> = config
+        .maintenance
+        .active()
+        .then(|| config.maintenance.schedule.parse())
+        .transpose()
+        .context("parse maintenance.schedule")?;
 
     update_access_list(&config.access_list, &state.access_list)?;
 
@@ -43,20 +116,32 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let response_mesh_builder = MeshBuilder::partial(num_mesh_peers, SHARED_IN_CHANNEL_SIZE * 16);
     let control_mesh_builder = MeshBuilder::partial(num_mesh_peers, SHARED_IN_CHANNEL_SIZE * 16);
 
-    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
+    let (bound_sender, bound_receiver) = sync_channel(1);
+    let bind_notifier = BindNotifier::new(
+        &mut config.network.address,
+        config.network.only_ipv6,
+        socket2::Type::STREAM,
+        config.socket_workers,
+        Some(Box::new(move |addr| {
+            let _ = bound_sender.send(addr);
+        })),
+    )?;
+    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers)
+        .with_bind_notifier(bind_notifier);
 
     let opt_tls_config = if config.network.enable_tls {
         Some(Arc::new(ArcSwap::from_pointee(
             create_rustls_config(
                 &config.network.tls_certificate_path,
                 &config.network.tls_private_key_path,
+                config.network.tls_session_resumption(),
             )
             .with_context(|| "create rustls config")?,
         )))
     } else {
         None
     };
-    let mut opt_tls_cert_data = if config.network.enable_tls {
+    let opt_tls_cert_data = if config.network.enable_tls {
         Some(
             ::std::fs::read(&config.network.tls_certificate_path)
                 .with_context(|| "open tls certificate file")?,
@@ -67,9 +152,21 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     let server_start_instant = ServerStartInstant::new();
 
-    let mut join_handles = Vec::new();
+    let mut tracker = TrackerHandle::new(
+        config.clone(),
+        state.clone(),
+        opt_tls_config.clone(),
+        opt_tls_cert_data,
+    );
+    let shared_registry = Registry::new(Labels::new());
+    let mut registries = vec![shared_registry.clone()];
 
     for i in 0..(config.socket_workers) {
+        let registry = Registry::new(Labels::new().with("worker_index", i));
+        let statistics = SocketWorkerStatistics::new(&config, &registry, &shared_registry);
+
+        registries.push(registry);
+
         let config = config.clone();
         let state = state.clone();
         let opt_tls_config = opt_tls_config.clone();
@@ -81,7 +178,15 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let handle = Builder::new()
             .name(format!("socket-{:02}", i + 1))
             .spawn(move || {
-                LocalExecutorBuilder::default()
+                #[cfg(feature = "numa")]
+                let builder = LocalExecutorBuilder::new(numa::worker_placement(
+                    &config,
+                    numa::NumaWorker::Socket(i),
+                )?);
+                #[cfg(not(feature = "numa"))]
+                let builder = LocalExecutorBuilder::default();
+
+                builder
                     .make()
                     .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?
                     .run(workers::socket::run_socket_worker(
@@ -92,13 +197,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                         request_mesh_builder,
                         response_mesh_builder,
                         priv_dropper,
+                        statistics,
                         server_start_instant,
                         i,
                     ))
             })
             .context("spawn socket worker")?;
 
-        join_handles.push((WorkerType::Socket(i), handle));
+        tracker.push_worker(WorkerType::Socket(i), handle);
     }
 
     for i in 0..(config.swarm_workers) {
@@ -111,7 +217,15 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let handle = Builder::new()
             .name(format!("swarm-{:02}", i + 1))
             .spawn(move || {
-                LocalExecutorBuilder::default()
+                #[cfg(feature = "numa")]
+                let builder = LocalExecutorBuilder::new(numa::worker_placement(
+                    &config,
+                    numa::NumaWorker::Swarm(i),
+                )?);
+                #[cfg(not(feature = "numa"))]
+                let builder = LocalExecutorBuilder::default();
+
+                builder
                     .make()
                     .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?
                     .run(workers::swarm::run_swarm_worker(
@@ -126,7 +240,63 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             })
             .context("spawn swarm worker")?;
 
-        join_handles.push((WorkerType::Swarm(i), handle));
+        tracker.push_worker(WorkerType::Swarm(i), handle);
+    }
+
+    if let Some(handle) = spawn_access_list_updater(
+        config.access_list.clone(),
+        state.access_list.clone(),
+        state.shutdown.clone(),
+    )? {
+        tracker.push_worker(WorkerType::AccessListUpdater, handle);
+    }
+
+    if let Some(schedule) = opt_maintenance_schedule {
+        let state = state.clone();
+        let config = config.clone();
+
+        let handle = spawn_maintenance_worker(schedule, state.shutdown.clone(), move || {
+            if config.maintenance.reload_access_list {
+                let _ = update_access_list(&config.access_list, &state.access_list);
+            }
+        })?;
+
+        tracker.push_worker(WorkerType::Maintenance, handle);
+    }
+
+    if config.statistics.active() {
+        let mut exporters: Vec<Box<dyn Exporter>> = Vec::new();
+
+        if config.statistics.print_to_stdout {
+            exporters.push(Box::new(StdoutExporter));
+        }
+        if config.statistics.write_json_to_file {
+            exporters.push(Box::new(JsonFileExporter::new(
+                &config.statistics.json_file_path,
+            )?));
+        }
+
+        let shutdown = state.shutdown.clone();
+
+        let handle = run_reporter(
+            Duration::from_secs(config.statistics.interval),
+            registries,
+            exporters,
+            move |duration| shutdown.sleep(duration),
+        )?;
+
+        tracker.push_worker(WorkerType::Statistics, handle);
+    }
+
+    if config.diagnostics.run_connection_table_endpoint {
+        let handle = spawn_connection_table_endpoint(
+            config.diagnostics.connection_table_endpoint_address,
+            state.connection_tables.clone(),
+            aquatic_common::cli::BuildInfo::new(APP_VERSION, &config),
+            state.shutdown.clone(),
+        )?;
+
+        tracker.push_worker(WorkerType::Diagnostics, handle);
     }
 
     #[cfg(feature = "prometheus")]
@@ -142,74 +312,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             config.metrics.prometheus_endpoint_address,
             Some(Duration::from_secs(idle_timeout)),
             Some(metrics_util::MetricKindMask::GAUGE),
+            Some(aquatic_common::cli::BuildInfo::new(APP_VERSION, &config)),
+            state.shutdown.clone(),
         )?;
 
-        join_handles.push((WorkerType::Prometheus, handle));
-    }
-
-    // Spawn signal handler thread
-    {
-        let handle: JoinHandle<anyhow::Result<()>> = Builder::new()
-            .name("signals".into())
-            .spawn(move || {
-                for signal in &mut signals {
-                    match signal {
-                        SIGUSR1 => {
-                            let _ = update_access_list(&config.access_list, &state.access_list);
-
-                            if let Some(tls_config) = opt_tls_config.as_ref() {
-                                match ::std::fs::read(&config.network.tls_certificate_path) {
-                                    Ok(data) if &data == opt_tls_cert_data.as_ref().unwrap() => {
-                                        ::log::info!("skipping tls config update: certificate identical to currently loaded");
-                                    }
-                                    Ok(data) => {
-                                        match create_rustls_config(
-                                            &config.network.tls_certificate_path,
-                                            &config.network.tls_private_key_path,
-                                        ) {
-                                            Ok(config) => {
-                                                tls_config.store(Arc::new(config));
-                                                opt_tls_cert_data = Some(data);
-
-                                                ::log::info!("successfully updated tls config");
-                                            }
-                                            Err(err) => ::log::error!("could not update tls config: {:#}", err),
-                                        }
-                                    }
-                                    Err(err) => ::log::error!("couldn't read tls certificate file: {:#}", err),
-                                }
-                            }
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-
-                Ok(())
-            })
-            .context("spawn signal worker")?;
-
-        join_handles.push((WorkerType::Signals, handle));
+        tracker.push_worker(WorkerType::Prometheus, handle);
     }
 
-    loop {
-        for (i, (_, handle)) in join_handles.iter().enumerate() {
-            if handle.is_finished() {
-                let (worker_type, handle) = join_handles.remove(i);
-
-                match handle.join() {
-                    Ok(Ok(())) => {
-                        return Err(anyhow::anyhow!("{} stopped", worker_type));
-                    }
-                    Ok(Err(err)) => {
-                        return Err(err.context(format!("{} stopped", worker_type)));
-                    }
-                    Err(_) => {
-                        return Err(anyhow::anyhow!("{} panicked", worker_type));
-                    }
-                }
-            }
-        }
+    tracker.wait_until_bound(bound_receiver)?;
 
-        sleep(Duration::from_secs(5));
-    }
+    Ok(tracker)
 }
@@ -0,0 +1,45 @@
+//! NUMA-aware worker placement
+
+use glommio::{CpuSet, Placement};
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug)]
+pub enum NumaWorker {
+    Socket(usize),
+    Swarm(usize),
+}
+
+/// Get executor placement for worker
+///
+/// Workers of each type are assigned to NUMA nodes in round-robin order
+/// and restricted to the CPUs of that node.
+pub fn worker_placement(config: &Config, worker: NumaWorker) -> anyhow::Result<Placement> {
+    if !config.numa.active {
+        return Ok(Placement::Unbound);
+    }
+
+    let cpu_set = CpuSet::online().map_err(|err| anyhow::anyhow!("get cpu topology: {:#}", err))?;
+
+    let mut nodes = cpu_set
+        .iter()
+        .map(|location| location.numa_node)
+        .collect::<Vec<_>>();
+
+    nodes.sort_unstable();
+    nodes.dedup();
+
+    let worker_index = match worker {
+        NumaWorker::Socket(index) | NumaWorker::Swarm(index) => index,
+    };
+
+    let node = *nodes
+        .get(worker_index % nodes.len().max(1))
+        .ok_or_else(|| anyhow::anyhow!("no NUMA nodes found"))?;
+
+    ::log::info!("Placing {:?} on NUMA node {}", worker, node);
+
+    Ok(Placement::Fenced(
+        cpu_set.filter(|location| location.numa_node == node),
+    ))
+}
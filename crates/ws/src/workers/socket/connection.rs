@@ -1,14 +1,19 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::connection_table::ConnectionState;
+use aquatic_common::pending_scrape::{
+    OrderedScrapeStatistics, PendingScrapeResponse, PendingScrapeResponses,
+};
+use aquatic_common::routing::{group_by_swarm_worker, swarm_worker_index, WorkerSenders};
 use aquatic_common::rustls_config::RustlsConfig;
-use aquatic_common::ServerStartInstant;
+use aquatic_common::user_agent::UserAgentFilter;
+use aquatic_common::{ServerStartInstant, TOO_MANY_SCRAPE_TORRENTS};
 use aquatic_ws_protocol::common::{InfoHash, PeerId, ScrapeAction};
 use aquatic_ws_protocol::incoming::{
     AnnounceEvent, AnnounceRequest, InMessage, ScrapeRequest, ScrapeRequestInfoHashes,
@@ -22,25 +27,29 @@ use futures::stream::{SplitSink, SplitStream};
 use futures::{AsyncWriteExt, StreamExt};
 use futures_lite::future::race;
 use futures_rustls::TlsAcceptor;
-use glommio::channels::channel_mesh::Senders;
 use glommio::channels::local_channel::{LocalReceiver, LocalSender};
 use glommio::net::TcpStream;
 use glommio::timer::timeout;
 use glommio::{enclose, prelude::*};
 use hashbrown::hash_map::Entry;
 use hashbrown::HashMap;
-use slab::Slab;
+use tungstenite::handshake::server::{ErrorResponse as HandshakeErrorResponse, Request, Response};
+use tungstenite::http::{header, HeaderValue, StatusCode};
 
 #[cfg(feature = "metrics")]
 use metrics::{Counter, Gauge};
 
 use crate::common::*;
-use crate::config::Config;
-use crate::workers::socket::calculate_in_message_consumer_index;
+use crate::config::{Config, NetworkConfig};
+use crate::workers::socket::OriginPolicy;
 
 #[cfg(feature = "metrics")]
 use crate::workers::socket::{ip_version_to_metrics_str, WORKER_INDEX};
 
+/// Maximum number of scrape requests per connection that are waiting for
+/// responses from swarm workers
+const MAX_PENDING_SCRAPE_RESPONSES: usize = 256;
+
 /// Optional second tuple field is for peer id hex representation
 #[cfg(feature = "metrics")]
 type PeerClientGauge = (Gauge, Option<Gauge>);
@@ -48,7 +57,7 @@ type PeerClientGauge = (Gauge, Option<Gauge>);
 pub struct ConnectionRunner {
     pub config: Rc<Config>,
     pub access_list: Arc<AccessListArcSwap>,
-    pub in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
+    pub in_message_senders: WorkerSenders<(InMessageMeta, InMessage)>,
     pub connection_valid_until: Rc<RefCell<ValidUntil>>,
     pub out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     pub out_message_receiver: LocalReceiver<(OutMessageMeta, OutMessage)>,
@@ -57,12 +66,17 @@ pub struct ConnectionRunner {
     pub connection_id: ConnectionId,
     pub opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     pub ip_version: IpVersion,
+    /// Set to open after WebSocket handshake, for diagnostics
+    pub connection_state: Rc<Cell<ConnectionState>>,
+    pub statistics: Rc<SocketWorkerStatistics>,
+    pub origin_policy: Rc<OriginPolicy>,
+    pub user_agent_filter: Rc<UserAgentFilter>,
 }
 
 impl ConnectionRunner {
     pub async fn run(
         self,
-        control_message_senders: Rc<Senders<SwarmControlMessage>>,
+        control_message_senders: WorkerSenders<SwarmControlMessage>,
         close_conn_receiver: LocalReceiver<()>,
         stream: TcpStream,
     ) {
@@ -73,7 +87,7 @@ impl ConnectionRunner {
             opt_peer_client: Default::default(),
             #[cfg(feature = "metrics")]
             active_connections_gauge: ::metrics::gauge!(
-                "aquatic_active_connections",
+                aquatic_metrics::names::ACTIVE_CONNECTIONS,
                 "ip_version" => ip_version_to_metrics_str(self.ip_version),
                 "worker_index" => WORKER_INDEX.get().to_string(),
             ),
@@ -83,19 +97,32 @@ impl ConnectionRunner {
 
         let config = self.config.clone();
         let connection_id = self.connection_id;
+        let statistics = self.statistics.clone();
+
+        statistics.active_connections.increment(1);
 
-        race(
+        let close_reason = race(
             async {
-                if let Err(err) = self.run_inner(clean_up_data.clone(), stream).await {
-                    ::log::debug!("connection {:?} closed: {:#}", connection_id, err);
+                match self.run_inner(clean_up_data.clone(), stream).await {
+                    Ok(()) => ConnectionCloseReason::PeerClose,
+                    Err(err) => {
+                        ::log::debug!("connection {:?} closed: {:#}", connection_id, err);
+
+                        ConnectionCloseReason::Error
+                    }
                 }
             },
             async {
                 close_conn_receiver.recv().await;
+
+                ConnectionCloseReason::Idle
             },
         )
         .await;
 
+        statistics.active_connections.decrement(1);
+        statistics.connection_closed(close_reason);
+
         ::log::debug!("connection {:?} starting clean up", connection_id);
 
         clean_up_data
@@ -114,14 +141,24 @@ impl ConnectionRunner {
             let tls_config = tls_config.load_full();
             let tls_acceptor = TlsAcceptor::from(tls_config);
 
-            let stream = tls_acceptor.accept(stream).await?;
+            let stream = match tls_acceptor.accept(stream).await {
+                Ok(stream) => {
+                    self.statistics.tls_handshake_successes.increment(1);
+
+                    stream
+                }
+                Err(err) => {
+                    self.statistics.tls_handshake_failures.increment(1);
+
+                    return Err(anyhow::Error::from(err).context("tls accept"));
+                }
+            };
 
             self.run_inner_stream_agnostic(clean_up_data, stream).await
         } else {
             // Implementing this over TLS is too cumbersome, since the crate used
-            // for TLS streams doesn't support peek and tungstenite doesn't
-            // properly support sending a HTTP error response in accept_hdr
-            // callback.
+            // for TLS streams doesn't support peek and tungstenite only sends
+            // error responses, not 200 Ok, from the accept_hdr callback.
             if self.config.network.enable_http_health_checks {
                 let mut peek_buf = [0u8; 11];
 
@@ -131,12 +168,11 @@ impl ConnectionRunner {
                     .map_err(|err| anyhow::anyhow!("error peeking: {:#}", err))?;
 
                 if &peek_buf == b"GET /health" {
-                    stream
-                        .write_all(b"HTTP/1.1 200 Ok\r\nContent-Length: 2\r\n\r\nOk")
-                        .await
-                        .map_err(|err| {
-                            anyhow::anyhow!("error sending health check response: {:#}", err)
-                        })?;
+                    let response = health_check_response(&self.config.network);
+
+                    stream.write_all(&response).await.map_err(|err| {
+                        anyhow::anyhow!("error sending health check response: {:#}", err)
+                    })?;
                     stream.flush().await.map_err(|err| {
                         anyhow::anyhow!("error flushing health check response: {:#}", err)
                     })?;
@@ -166,21 +202,75 @@ impl ConnectionRunner {
             max_write_buffer_size: self.config.network.websocket_write_buffer_size * 3,
             ..Default::default()
         };
-        let stream = async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
+        let network_config = &self.config.network;
+        let origin_policy = &self.origin_policy;
+        let user_agent_filter = &self.user_agent_filter;
+        let statistics = &self.statistics;
+        // Error type is dictated by tungstenite
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| {
+            statistics.user_agents.record(
+                request
+                    .headers()
+                    .get(header::USER_AGENT)
+                    .map(|value| value.as_bytes()),
+            );
+
+            handle_handshake_request(
+                network_config,
+                origin_policy,
+                user_agent_filter,
+                request,
+                response,
+            )
+            .map_err(|rejection| {
+                match rejection {
+                    HandshakeRejection::Origin => {
+                        statistics.ws_handshake_origin_rejections.increment(1)
+                    }
+                    HandshakeRejection::UserAgent => {
+                        statistics.ws_handshake_user_agent_rejections.increment(1)
+                    }
+                }
+
+                rejection.error_response()
+            })
+        };
+
+        let stream = match async_tungstenite::accept_hdr_async_with_config(
+            stream,
+            callback,
+            Some(ws_config),
+        )
+        .await
+        {
+            Ok(stream) => {
+                self.statistics.ws_handshake_successes.increment(1);
+                self.connection_state.set(ConnectionState::Open);
+
+                stream
+            }
+            Err(err) => {
+                self.statistics.ws_handshake_failures.increment(1);
+
+                return Err(anyhow::Error::from(err).context("websocket handshake"));
+            }
+        };
         let (ws_out, ws_in) = futures::StreamExt::split(stream);
 
-        let pending_scrape_slab = Rc::new(RefCell::new(Slab::new()));
+        let pending_scrape_responses = Rc::new(RefCell::new(PendingScrapeResponses::default()));
         let access_list_cache = create_access_list_cache(&self.access_list);
 
         let config = self.config.clone();
 
-        let reader_future = enclose!((pending_scrape_slab, clean_up_data) async move {
+        let reader_future = enclose!((pending_scrape_responses, clean_up_data) async move {
             let mut reader = ConnectionReader {
                 config: self.config.clone(),
                 access_list_cache,
                 in_message_senders: self.in_message_senders,
                 out_message_sender: self.out_message_sender,
-                pending_scrape_slab,
+                pending_scrape_responses,
+                server_start_instant: self.server_start_instant,
                 out_message_consumer_id: self.out_message_consumer_id,
                 ws_in,
                 ip_version: self.ip_version,
@@ -188,14 +278,14 @@ impl ConnectionRunner {
                 clean_up_data: clean_up_data.clone(),
                 #[cfg(feature = "metrics")]
                 total_announce_requests_counter: ::metrics::counter!(
-                    "aquatic_requests_total",
+                    aquatic_metrics::names::REQUESTS_TOTAL,
                     "type" => "announce",
                     "ip_version" => ip_version_to_metrics_str(self.ip_version),
                     "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
                 ),
                 #[cfg(feature = "metrics")]
                 total_scrape_requests_counter: ::metrics::counter!(
-                    "aquatic_requests_total",
+                    aquatic_metrics::names::REQUESTS_TOTAL,
                     "type" => "scrape",
                     "ip_version" => ip_version_to_metrics_str(self.ip_version),
                     "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
@@ -211,7 +301,7 @@ impl ConnectionRunner {
                 out_message_receiver: self.out_message_receiver,
                 connection_valid_until: self.connection_valid_until,
                 ws_out,
-                pending_scrape_slab,
+                pending_scrape_responses,
                 server_start_instant: self.server_start_instant,
                 ip_version: self.ip_version,
                 clean_up_data,
@@ -227,9 +317,10 @@ impl ConnectionRunner {
 struct ConnectionReader<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
-    in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
+    in_message_senders: WorkerSenders<(InMessageMeta, InMessage)>,
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
-    pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
+    pending_scrape_responses: Rc<RefCell<PendingScrapeResponses<ScrapeStatisticsMap>>>,
+    server_start_instant: ServerStartInstant,
     out_message_consumer_id: ConsumerId,
     ws_in: SplitStream<WebSocketStream<S>>,
     ip_version: IpVersion,
@@ -244,11 +335,14 @@ struct ConnectionReader<S> {
 impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
     async fn run_in_message_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            let message = self
-                .ws_in
-                .next()
-                .await
-                .ok_or_else(|| anyhow::anyhow!("Stream ended"))??;
+            let message = match self.ws_in.next().await {
+                Some(message) => message?,
+                None => {
+                    ::log::debug!("Stream ended");
+
+                    break Ok(());
+                }
+            };
 
             match &message {
                 tungstenite::Message::Text(_) | tungstenite::Message::Binary(_) => {
@@ -303,6 +397,25 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         {
             let mut announced_info_hashes = self.clean_up_data.announced_info_hashes.borrow_mut();
 
+            let max_info_hashes = self.config.protocol.max_info_hashes_per_connection;
+
+            if max_info_hashes != 0
+                && announced_info_hashes.len() >= max_info_hashes
+                && !announced_info_hashes.contains_key(&request.info_hash)
+            {
+                // Drop Rc borrow before awaiting
+                drop(announced_info_hashes);
+
+                self.send_error_response(
+                    "Too many torrents announced on this connection".into(),
+                    Some(ErrorResponseAction::Announce),
+                    Some(info_hash),
+                )
+                .await?;
+
+                return Ok(());
+            }
+
             // Store peer id / check if stored peer id matches
             match announced_info_hashes.entry(request.info_hash) {
                 Entry::Occupied(entry) => {
@@ -334,7 +447,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                         let peer_id = aquatic_peer_id::PeerId(request.peer_id.0);
 
                         let peer_client_gauge = ::metrics::gauge!(
-                            "aquatic_peer_clients",
+                            aquatic_metrics::names::PEER_CLIENTS,
                             "client" => peer_id.client().to_string(),
                         );
 
@@ -343,7 +456,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                         let opt_peer_id_prefix_gauge =
                             self.config.metrics.peer_id_prefixes.then(|| {
                                 let g = ::metrics::gauge!(
-                                    "aquatic_peer_id_prefixes",
+                                    aquatic_metrics::names::PEER_ID_PREFIXES,
                                     "prefix_hex" => peer_id.first_8_bytes_hex().to_string(),
                                 );
 
@@ -367,7 +480,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
 
             let in_message = InMessage::AnnounceRequest(request);
 
-            let consumer_index = calculate_in_message_consumer_index(&self.config, info_hash);
+            let consumer_index = swarm_worker_index(&info_hash.0, self.config.swarm_workers);
 
             // Only fails when receiver is closed
             self.in_message_senders
@@ -394,7 +507,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         self.total_scrape_requests_counter.increment(1);
 
         let info_hashes = if let Some(info_hashes) = request.info_hashes {
-            info_hashes
+            info_hashes.as_vec()
         } else {
             // If request.info_hashes is empty, don't return scrape for all
             // torrents, even though reference server does it. It is too expensive.
@@ -408,44 +521,64 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
             return Ok(());
         };
 
-        let mut info_hashes_by_worker: BTreeMap<usize, Vec<InfoHash>> = BTreeMap::new();
-
-        for info_hash in info_hashes.as_vec() {
-            let info_hashes = info_hashes_by_worker
-                .entry(calculate_in_message_consumer_index(&self.config, info_hash))
-                .or_default();
+        if info_hashes.len() > self.config.protocol.max_scrape_torrents {
+            self.send_error_response(
+                TOO_MANY_SCRAPE_TORRENTS.into(),
+                Some(ErrorResponseAction::Scrape),
+                None,
+            )
+            .await?;
 
-            info_hashes.push(info_hash);
+            return Ok(());
         }
 
-        let pending_worker_out_messages = info_hashes_by_worker.len();
+        // Partial responses are put back into request order
+        let ordered_statistics = ScrapeStatisticsMap::new(info_hashes.clone());
 
-        let pending_scrape_response = PendingScrapeResponse {
-            pending_worker_out_messages,
-            stats: Default::default(),
-        };
+        let info_hashes_by_worker =
+            group_by_swarm_worker(info_hashes, self.config.swarm_workers, |info_hash| {
+                &info_hash.0
+            });
 
-        let pending_scrape_id: u8 = self
-            .pending_scrape_slab
-            .borrow_mut()
-            .insert(pending_scrape_response)
-            .try_into()
-            .with_context(|| "Reached 256 pending scrape responses")?;
+        let pending_scrape_response =
+            PendingScrapeResponse::new(info_hashes_by_worker.len(), ordered_statistics);
 
-        let meta = self.make_connection_meta(Some(PendingScrapeId(pending_scrape_id)));
+        let pending_scrape_key = {
+            let mut pending_responses = self.pending_scrape_responses.borrow_mut();
 
-        for (consumer_index, info_hashes) in info_hashes_by_worker {
-            let in_message = InMessage::ScrapeRequest(ScrapeRequest {
-                action: ScrapeAction::Scrape,
-                info_hashes: Some(ScrapeRequestInfoHashes::Multiple(info_hashes)),
-            });
+            // Responses to scrapes this old will not be arriving anymore
+            pending_responses.clean(self.server_start_instant.seconds_elapsed());
 
-            // Only fails when receiver is closed
-            self.in_message_senders
-                .send_to(consumer_index, (meta, in_message))
-                .await
-                .unwrap();
-        }
+            if pending_responses.len() >= MAX_PENDING_SCRAPE_RESPONSES {
+                return Err(anyhow::anyhow!(
+                    "Reached {} pending scrape responses",
+                    MAX_PENDING_SCRAPE_RESPONSES
+                ));
+            }
+
+            pending_responses.insert(
+                pending_scrape_response,
+                ValidUntil::new(
+                    self.server_start_instant,
+                    self.config.cleaning.max_connection_idle,
+                ),
+            )
+        };
+
+        let meta = self.make_connection_meta(Some(PendingScrapeId(pending_scrape_key)));
+
+        // Only fails when receiver is closed
+        self.in_message_senders
+            .scatter(info_hashes_by_worker, |info_hashes| {
+                let in_message = InMessage::ScrapeRequest(ScrapeRequest {
+                    action: ScrapeAction::Scrape,
+                    info_hashes: Some(ScrapeRequestInfoHashes::Multiple(info_hashes)),
+                });
+
+                (meta, in_message)
+            })
+            .await
+            .unwrap();
 
         Ok(())
     }
@@ -485,7 +618,7 @@ struct ConnectionWriter<S> {
     out_message_receiver: LocalReceiver<(OutMessageMeta, OutMessage)>,
     connection_valid_until: Rc<RefCell<ValidUntil>>,
     ws_out: SplitSink<WebSocketStream<S>, tungstenite::Message>,
-    pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
+    pending_scrape_responses: Rc<RefCell<PendingScrapeResponses<ScrapeStatisticsMap>>>,
     server_start_instant: ServerStartInstant,
     ip_version: IpVersion,
     clean_up_data: ConnectionCleanupData,
@@ -506,29 +639,27 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
                         .pending_scrape_id
                         .expect("meta.pending_scrape_id not set");
 
-                    let mut pending_responses = self.pending_scrape_slab.borrow_mut();
-
-                    let pending_response = pending_responses
-                        .get_mut(pending_scrape_id.0 as usize)
-                        .ok_or(anyhow::anyhow!("pending scrape not found in slab"))?;
-
-                    pending_response.stats.extend(out_message.files);
-                    pending_response.pending_worker_out_messages -= 1;
+                    let result = self
+                        .pending_scrape_responses
+                        .borrow_mut()
+                        .merge_part(pending_scrape_id.0, |files| files.extend(out_message.files));
 
-                    if pending_response.pending_worker_out_messages == 0 {
-                        let pending_response =
-                            pending_responses.remove(pending_scrape_id.0 as usize);
+                    // Partial responses to expired scrapes may still arrive
+                    let opt_files = match result {
+                        Ok(opt_files) => opt_files,
+                        Err(err) => {
+                            ::log::debug!("dropping partial scrape response: {:#}", err);
 
-                        pending_responses.shrink_to_fit();
+                            None
+                        }
+                    };
 
+                    if let Some(files) = opt_files {
                         let out_message = OutMessage::ScrapeResponse(ScrapeResponse {
                             action: ScrapeAction::Scrape,
-                            files: pending_response.stats,
+                            files: files.into_ordered().collect(),
                         });
 
-                        // Drop Rc borrow before awaiting
-                        drop(pending_responses);
-
                         self.send_out_message(&out_message).await?;
                     }
                 }
@@ -569,7 +700,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
             };
 
             ::metrics::counter!(
-                "aquatic_responses_total",
+                aquatic_metrics::names::RESPONSES_TOTAL,
                 "type" => out_message_type,
                 "ip_version" => ip_version_to_metrics_str(self.ip_version),
                 "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
@@ -613,30 +744,23 @@ impl ConnectionCleanupData {
     async fn after_close(
         &self,
         config: &Config,
-        control_message_senders: Rc<Senders<SwarmControlMessage>>,
+        control_message_senders: WorkerSenders<SwarmControlMessage>,
     ) {
-        let mut announced_info_hashes = HashMap::new();
-
-        for (info_hash, peer_id) in self.announced_info_hashes.take().into_iter() {
-            let consumer_index = calculate_in_message_consumer_index(config, info_hash);
-
-            announced_info_hashes
-                .entry(consumer_index)
-                .or_insert(Vec::new())
-                .push((info_hash, peer_id));
-        }
-
-        for (consumer_index, announced_info_hashes) in announced_info_hashes.into_iter() {
-            let message = SwarmControlMessage::ConnectionClosed {
-                ip_version: self.ip_version,
-                announced_info_hashes,
-            };
-
-            control_message_senders
-                .send_to(consumer_index, message)
-                .await
-                .expect("control message receiver open");
-        }
+        let announced_info_hashes = group_by_swarm_worker(
+            self.announced_info_hashes.take(),
+            config.swarm_workers,
+            |(info_hash, _)| &info_hash.0,
+        );
+
+        control_message_senders
+            .scatter(announced_info_hashes, |announced_info_hashes| {
+                SwarmControlMessage::ConnectionClosed {
+                    ip_version: self.ip_version,
+                    announced_info_hashes,
+                }
+            })
+            .await
+            .expect("control message receiver open");
 
         #[cfg(feature = "metrics")]
         self.active_connections_gauge.decrement(1.0);
@@ -652,7 +776,200 @@ impl ConnectionCleanupData {
     }
 }
 
-struct PendingScrapeResponse {
-    pending_worker_out_messages: usize,
-    stats: HashMap<InfoHash, ScrapeStatistics>,
+type ScrapeStatisticsMap = OrderedScrapeStatistics<InfoHash, ScrapeStatistics>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandshakeRejection {
+    Origin,
+    UserAgent,
+}
+
+impl HandshakeRejection {
+    fn error_response(self) -> HandshakeErrorResponse {
+        let body = match self {
+            Self::Origin => "Origin not allowed",
+            Self::UserAgent => "User agent not allowed",
+        };
+
+        let mut response = HandshakeErrorResponse::new(Some(body.into()));
+
+        *response.status_mut() = StatusCode::FORBIDDEN;
+
+        response
+    }
+}
+
+/// Reject handshakes from browsers on pages with unapproved origins and from
+/// denied user agents, and add configured CORS header to response
+fn handle_handshake_request(
+    config: &NetworkConfig,
+    origin_policy: &OriginPolicy,
+    user_agent_filter: &UserAgentFilter,
+    request: &Request,
+    mut response: Response,
+) -> Result<Response, HandshakeRejection> {
+    if let Some(origin) = request.headers().get(header::ORIGIN) {
+        if !origin_policy.is_allowed(origin.as_bytes()) {
+            ::log::debug!("rejected websocket handshake from origin {:?}", origin);
+
+            return Err(HandshakeRejection::Origin);
+        }
+    }
+    if let Some(user_agent) = request.headers().get(header::USER_AGENT) {
+        if user_agent_filter.is_denied(user_agent.as_bytes()) {
+            ::log::debug!(
+                "rejected websocket handshake from user agent {:?}",
+                user_agent
+            );
+
+            return Err(HandshakeRejection::UserAgent);
+        }
+    }
+
+    if !config.access_control_allow_origin.is_empty() {
+        // Validated at startup
+        if let Ok(value) = HeaderValue::from_str(&config.access_control_allow_origin) {
+            response
+                .headers_mut()
+                .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+    }
+
+    Ok(response)
+}
+
+fn health_check_response(config: &NetworkConfig) -> Vec<u8> {
+    let mut response = b"HTTP/1.1 200 Ok\r\nContent-Length: 2\r\n".to_vec();
+
+    if !config.access_control_allow_origin.is_empty() {
+        response.extend_from_slice(b"Access-Control-Allow-Origin: ");
+        response.extend_from_slice(config.access_control_allow_origin.as_bytes());
+        response.extend_from_slice(b"\r\n");
+    }
+
+    response.extend_from_slice(b"\r\nOk");
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use aquatic_common::user_agent::UserAgentConfig;
+
+    use super::*;
+
+    fn handshake_with_headers(
+        config: &NetworkConfig,
+        user_agent_filter: &UserAgentFilter,
+        headers: &[(header::HeaderName, &str)],
+    ) -> Result<Response, HandshakeRejection> {
+        let mut request = Request::builder().uri("/");
+
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+
+        let origin_policy = OriginPolicy::new(config).unwrap();
+
+        handle_handshake_request(
+            config,
+            &origin_policy,
+            user_agent_filter,
+            &request.body(()).unwrap(),
+            Response::default(),
+        )
+    }
+
+    fn handshake(config: &NetworkConfig, origin: Option<&str>) -> Result<Response, StatusCode> {
+        let headers = origin
+            .map(|origin| vec![(header::ORIGIN, origin)])
+            .unwrap_or_default();
+
+        handshake_with_headers(
+            config,
+            &UserAgentFilter::new(&Default::default()).unwrap(),
+            &headers,
+        )
+        .map_err(|rejection| rejection.error_response().status())
+    }
+
+    #[test]
+    fn test_handle_handshake_request() {
+        let mut config = NetworkConfig::default();
+
+        assert!(handshake(&config, Some("https://example.com")).is_ok());
+        assert!(handshake(&config, None).is_ok());
+
+        config.allowed_origins = vec![
+            "https://example.com/".into(),
+            "http://localhost:8080".into(),
+        ];
+        config.denied_origins = vec!["https://example.com".into()];
+
+        assert_eq!(
+            handshake(&config, Some("https://example.com")).unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        config.denied_origins.clear();
+
+        assert!(handshake(&config, Some("https://example.com")).is_ok());
+        assert!(handshake(&config, Some("http://localhost:8080")).is_ok());
+        assert!(handshake(&config, None).is_ok());
+        assert_eq!(
+            handshake(&config, Some("https://example.org")).unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            handshake(&config, Some("null")).unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        let response = handshake(&config, Some("https://example.com")).unwrap();
+
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        config.access_control_allow_origin = "*".into();
+
+        let response = handshake(&config, None).unwrap();
+
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            HeaderValue::from_static("*")
+        );
+        assert_eq!(
+            health_check_response(&config),
+            b"HTTP/1.1 200 Ok\r\nContent-Length: 2\r\nAccess-Control-Allow-Origin: *\r\n\r\nOk"
+        );
+    }
+
+    #[test]
+    fn test_handle_handshake_request_user_agent() {
+        let config = NetworkConfig::default();
+        let user_agent_filter = UserAgentFilter::new(&UserAgentConfig {
+            deny: vec!["^BadClient/".into()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(handshake_with_headers(&config, &user_agent_filter, &[]).is_ok());
+        assert!(handshake_with_headers(
+            &config,
+            &user_agent_filter,
+            &[(header::USER_AGENT, "GoodClient/1.0")]
+        )
+        .is_ok());
+        assert_eq!(
+            handshake_with_headers(
+                &config,
+                &user_agent_filter,
+                &[(header::USER_AGENT, "BadClient/1.0")]
+            )
+            .unwrap_err(),
+            HandshakeRejection::UserAgent
+        );
+    }
 }
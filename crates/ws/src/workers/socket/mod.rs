@@ -2,17 +2,23 @@ use std::cell::RefCell;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
+use aquatic_common::connection_table::{
+    ConnectionTableEntry, ConnectionTableSnapshot, ConnectionTables,
+};
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::routing::WorkerSenders;
 use aquatic_common::rustls_config::RustlsConfig;
-use aquatic_common::ServerStartInstant;
-use aquatic_ws_protocol::common::InfoHash;
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::user_agent::UserAgentFilter;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
 use aquatic_ws_protocol::incoming::InMessage;
 use aquatic_ws_protocol::outgoing::OutMessage;
 use arc_swap::ArcSwap;
 use futures::StreamExt;
+use futures_lite::future::race;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
 use glommio::channels::local_channel::{new_bounded, LocalSender};
 use glommio::channels::shared_channel::ConnectedReceiver;
@@ -27,10 +33,17 @@ use crate::common::*;
 use crate::workers::socket::connection::ConnectionRunner;
 
 mod connection;
+mod origin;
+
+pub use origin::OriginPolicy;
 
 type ConnectionHandles = HopSlotMap<ConnectionId, ConnectionHandle>;
 
 const LOCAL_CHANNEL_SIZE: usize = 16;
+/// How often to check if shutdown has been triggered
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for connections to close on shutdown
+const CONNECTION_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[cfg(feature = "metrics")]
 thread_local! { static WORKER_INDEX: ::std::cell::Cell<usize> = Default::default() }
@@ -46,6 +59,7 @@ struct ConnectionHandle {
     /// The TLS config used for this connection
     opt_tls_config: Option<Arc<RustlsConfig>>,
     valid_until_after_tls_update: Option<ValidUntil>,
+    table_entry: ConnectionTableEntry,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -55,8 +69,9 @@ pub async fn run_socket_worker(
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
     control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
     in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
-    out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
+    out_message_mesh_builder: MeshBuilder<Vec<(OutMessageMeta, OutMessage)>, Partial>,
     priv_dropper: PrivilegeDropper,
+    statistics: SocketWorkerStatistics,
     server_start_instant: ServerStartInstant,
     worker_index: usize,
 ) -> anyhow::Result<()> {
@@ -65,27 +80,25 @@ pub async fn run_socket_worker(
 
     let config = Rc::new(config);
     let access_list = state.access_list;
+    let connection_tables = state.connection_tables;
+    let shutdown = state.shutdown;
+    let statistics = Rc::new(statistics);
+    let origin_policy = Rc::new(OriginPolicy::new(&config.network)?);
+    let user_agent_filter = Rc::new(UserAgentFilter::new(&config.user_agent)?);
 
     let listener = create_tcp_listener(&config, priv_dropper).context("create tcp listener")?;
 
     ::log::info!("created tcp listener");
 
-    let (control_message_senders, _) = control_message_mesh_builder
-        .join(Role::Producer)
-        .await
-        .map_err(|err| anyhow::anyhow!("join control message mesh: {:#}", err))?;
-    let (in_message_senders, _) = in_message_mesh_builder
-        .join(Role::Producer)
-        .await
-        .map_err(|err| anyhow::anyhow!("join in message mesh: {:#}", err))?;
+    let control_message_senders =
+        WorkerSenders::join_mesh(control_message_mesh_builder, "control message").await?;
+    let in_message_senders =
+        WorkerSenders::join_mesh(in_message_mesh_builder, "in message").await?;
     let (_, mut out_message_receivers) = out_message_mesh_builder
         .join(Role::Consumer)
         .await
         .map_err(|err| anyhow::anyhow!("join out message mesh: {:#}", err))?;
 
-    let control_message_senders = Rc::new(control_message_senders);
-    let in_message_senders = Rc::new(in_message_senders);
-
     let out_message_consumer_id = ConsumerId(
         out_message_receivers
             .consumer_id()
@@ -112,8 +125,10 @@ pub async fn run_socket_worker(
             clean_connections(
                 config.clone(),
                 connection_handles.clone(),
+                connection_tables.clone(),
                 server_start_instant,
                 opt_tls_config.clone(),
+                worker_index,
             )
         }),
         tq_prioritized,
@@ -131,85 +146,135 @@ pub async fn run_socket_worker(
 
     let mut incoming = listener.incoming();
 
-    while let Some(stream) = incoming.next().await {
-        match stream {
-            Err(err) => {
-                ::log::error!("accept connection: {:#}", err);
-            }
-            Ok(stream) => {
-                let ip_version = match stream.peer_addr() {
-                    Ok(addr) => IpVersion::canonical_from_ip(addr.ip()),
-                    Err(err) => {
-                        ::log::info!("could not extract ip version (v4 or v6): {:#}", err);
-
-                        continue;
-                    }
-                };
-
-                let (out_message_sender, out_message_receiver) = new_bounded(LOCAL_CHANNEL_SIZE);
-                let out_message_sender = Rc::new(out_message_sender);
-
-                let (close_conn_sender, close_conn_receiver) = new_bounded(1);
-
-                let connection_valid_until = Rc::new(RefCell::new(ValidUntil::new(
-                    server_start_instant,
-                    config.cleaning.max_connection_idle,
-                )));
-
-                let connection_handle = ConnectionHandle {
-                    close_conn_sender,
-                    out_message_sender: out_message_sender.clone(),
-                    valid_until: connection_valid_until.clone(),
-                    opt_tls_config: opt_tls_config.as_ref().map(|c| c.load_full()),
-                    valid_until_after_tls_update: None,
-                };
-
-                let connection_id = connection_handles.borrow_mut().insert(connection_handle);
-
-                spawn_local_into(
-                    enclose!((
-                        config,
-                        access_list,
-                        in_message_senders,
-                        connection_valid_until,
-                        opt_tls_config,
-                        control_message_senders,
-                        connection_handles
-                    ) async move {
-                        let runner = ConnectionRunner {
+    let accept_connections = async {
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Err(err) => {
+                    ::log::error!("accept connection: {:#}", err);
+                }
+                Ok(stream) => {
+                    statistics.tcp_accepts.increment(1);
+
+                    let remote_addr = match stream.peer_addr() {
+                        Ok(addr) => CanonicalSocketAddr::new(addr),
+                        Err(err) => {
+                            ::log::info!("could not extract ip version (v4 or v6): {:#}", err);
+
+                            continue;
+                        }
+                    };
+
+                    statistics
+                        .unique_ips
+                        .insert(&remote_addr.ip_prefix(config.network.ipv6_prefix_len));
+
+                    let ip_version = IpVersion::from_addr(remote_addr);
+
+                    let (out_message_sender, out_message_receiver) =
+                        new_bounded(LOCAL_CHANNEL_SIZE);
+                    let out_message_sender = Rc::new(out_message_sender);
+
+                    let (close_conn_sender, close_conn_receiver) = new_bounded(1);
+
+                    let connection_valid_until = Rc::new(RefCell::new(ValidUntil::new(
+                        server_start_instant,
+                        config.cleaning.max_connection_idle,
+                    )));
+
+                    let connection_handle = ConnectionHandle {
+                        close_conn_sender,
+                        out_message_sender: out_message_sender.clone(),
+                        valid_until: connection_valid_until.clone(),
+                        opt_tls_config: opt_tls_config.as_ref().map(|c| c.load_full()),
+                        valid_until_after_tls_update: None,
+                        table_entry: ConnectionTableEntry::new(
+                            remote_addr.get().ip(),
+                            server_start_instant.seconds_elapsed(),
+                        ),
+                    };
+                    let connection_state = connection_handle.table_entry.state.clone();
+
+                    let connection_id = connection_handles.borrow_mut().insert(connection_handle);
+
+                    spawn_local_into(
+                        enclose!((
                             config,
                             access_list,
                             in_message_senders,
                             connection_valid_until,
-                            out_message_sender,
-                            out_message_receiver,
-                            server_start_instant,
-                            out_message_consumer_id,
-                            connection_id,
                             opt_tls_config,
-                            ip_version
-                        };
-
-                        runner.run(control_message_senders, close_conn_receiver, stream).await;
-
-                        connection_handles.borrow_mut().remove(connection_id);
-                    }),
-                    tq_regular,
-                )
-                .unwrap()
-                .detach();
+                            control_message_senders,
+                            connection_handles,
+                            statistics,
+                            origin_policy,
+                            user_agent_filter
+                        ) async move {
+                            let runner = ConnectionRunner {
+                                config,
+                                access_list,
+                                in_message_senders,
+                                connection_valid_until,
+                                out_message_sender,
+                                out_message_receiver,
+                                server_start_instant,
+                                out_message_consumer_id,
+                                connection_id,
+                                opt_tls_config,
+                                ip_version,
+                                connection_state,
+                                statistics,
+                                origin_policy,
+                                user_agent_filter,
+                            };
+
+                            runner.run(control_message_senders, close_conn_receiver, stream).await;
+
+                            connection_handles.borrow_mut().remove(connection_id);
+                        }),
+                        tq_regular,
+                    )
+                    .unwrap()
+                    .detach();
+                }
             }
         }
-    }
+    };
+
+    race(accept_connections, wait_for_shutdown(&shutdown)).await;
+
+    // Connections hold message senders, which need to be dropped for swarm
+    // workers to notice that message streams have ended
+    close_connections(&connection_handles).await;
 
     Ok(())
 }
 
+async fn wait_for_shutdown(shutdown: &ShutdownSignal) {
+    while !shutdown.is_triggered() {
+        glommio::timer::sleep(SHUTDOWN_CHECK_INTERVAL).await;
+    }
+}
+
+async fn close_connections(connection_handles: &RefCell<ConnectionHandles>) {
+    for handle in connection_handles.borrow().values() {
+        let _ = handle.close_conn_sender.try_send(());
+    }
+
+    let deadline = Instant::now() + CONNECTION_CLOSE_TIMEOUT;
+
+    // Connection tasks remove their handles when they finish
+    while !connection_handles.borrow().is_empty() && Instant::now() < deadline {
+        glommio::timer::sleep(Duration::from_millis(10)).await;
+    }
+}
+
 async fn clean_connections(
     config: Rc<Config>,
     connection_slab: Rc<RefCell<ConnectionHandles>>,
+    connection_tables: Arc<ConnectionTables>,
     server_start_instant: ServerStartInstant,
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    worker_index: usize,
 ) -> Option<Duration> {
     let now = server_start_instant.seconds_elapsed();
     let opt_current_tls_config = opt_tls_config.map(|c| c.load_full());
@@ -246,6 +311,23 @@ async fn clean_connections(
         }
     });
 
+    if config.diagnostics.run_connection_table_endpoint {
+        let snapshot = ConnectionTableSnapshot::new(
+            now,
+            connection_slab.borrow().values().map(|reference| {
+                reference.table_entry.info(
+                    now,
+                    *reference.valid_until.borrow(),
+                    config.cleaning.max_connection_idle,
+                )
+            }),
+            config.diagnostics.top_source_ips,
+            config.network.ipv6_prefix_len,
+        );
+
+        connection_tables.update(worker_index, snapshot);
+    }
+
     #[cfg(feature = "metrics")]
     {
         ::log::info!(
@@ -261,7 +343,7 @@ async fn clean_connections(
 
         if config.network.address.is_ipv4() || !config.network.only_ipv6 {
             ::metrics::gauge!(
-                "aquatic_active_connections",
+                aquatic_metrics::names::ACTIVE_CONNECTIONS,
                 "ip_version" => "4",
                 "worker_index" => worker_index.clone(),
             )
@@ -269,7 +351,7 @@ async fn clean_connections(
         }
         if config.network.address.is_ipv6() {
             ::metrics::gauge!(
-                "aquatic_active_connections",
+                aquatic_metrics::names::ACTIVE_CONNECTIONS,
                 "ip_version" => "6",
                 "worker_index" => worker_index,
             )
@@ -283,26 +365,30 @@ async fn clean_connections(
 }
 
 async fn receive_out_messages(
-    mut out_message_receiver: ConnectedReceiver<(OutMessageMeta, OutMessage)>,
+    mut out_message_receiver: ConnectedReceiver<Vec<(OutMessageMeta, OutMessage)>>,
     connection_references: Rc<RefCell<ConnectionHandles>>,
 ) {
     let connection_references = &connection_references;
 
-    while let Some((meta, out_message)) = out_message_receiver.next().await {
-        if let Some(reference) = connection_references.borrow().get(meta.connection_id) {
-            match reference.out_message_sender.try_send((meta, out_message)) {
-                Ok(()) => {}
-                Err(GlommioError::Closed(_)) => {}
-                Err(GlommioError::WouldBlock(_)) => {
-                    ::log::debug!(
-                        "couldn't send OutMessage over local channel to Connection, channel full"
-                    );
-                }
-                Err(err) => {
-                    ::log::debug!(
-                        "couldn't send OutMessage over local channel to Connection: {:?}",
-                        err
-                    );
+    while let Some(batch) = out_message_receiver.next().await {
+        let connection_references = connection_references.borrow();
+
+        for (meta, out_message) in batch {
+            if let Some(reference) = connection_references.get(meta.connection_id) {
+                match reference.out_message_sender.try_send((meta, out_message)) {
+                    Ok(()) => {}
+                    Err(GlommioError::Closed(_)) => {}
+                    Err(GlommioError::WouldBlock(_)) => {
+                        ::log::debug!(
+                            "couldn't send OutMessage over local channel to Connection, channel full"
+                        );
+                    }
+                    Err(err) => {
+                        ::log::debug!(
+                            "couldn't send OutMessage over local channel to Connection: {:?}",
+                            err
+                        );
+                    }
                 }
             }
         }
@@ -338,6 +424,20 @@ fn create_tcp_listener(
         .set_reuse_port(true)
         .with_context(|| "socket: set reuse port")?;
 
+    if !config.network.bind_device.is_empty() {
+        ::log::info!("binding socket to device..");
+
+        #[cfg(target_os = "linux")]
+        socket
+            .bind_device(Some(config.network.bind_device.as_bytes()))
+            .with_context(|| format!("socket: bind to device {}", config.network.bind_device))?;
+
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "network.bind_device is only supported on Linux"
+        ));
+    }
+
     ::log::info!("binding socket..");
 
     socket
@@ -366,7 +466,3 @@ fn ip_version_to_metrics_str(ip_version: IpVersion) -> &'static str {
         IpVersion::V6 => "6",
     }
 }
-
-fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
-    (info_hash.0[0] as usize) % config.swarm_workers
-}
@@ -0,0 +1,173 @@
+use anyhow::Context;
+
+use crate::config::NetworkConfig;
+
+/// Decides which web page origins may open WebSocket connections, based on
+/// the Origin header that browsers send in the handshake request
+#[derive(Clone, Debug)]
+pub struct OriginPolicy {
+    allowed: Vec<OriginPattern>,
+    denied: Vec<OriginPattern>,
+}
+
+impl OriginPolicy {
+    pub fn new(config: &NetworkConfig) -> anyhow::Result<Self> {
+        let parse = |patterns: &[String], field: &str| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    OriginPattern::parse(pattern)
+                        .with_context(|| format!("network.{}: parse {:?}", field, pattern))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            allowed: parse(&config.allowed_origins, "allowed_origins")?,
+            denied: parse(&config.denied_origins, "denied_origins")?,
+        })
+    }
+
+    /// Denied origins take precedence over allowed ones. An empty allowlist
+    /// allows all origins that aren't denied.
+    pub fn is_allowed(&self, origin: &[u8]) -> bool {
+        let origin = origin.to_ascii_lowercase();
+
+        if self.denied.iter().any(|pattern| pattern.matches(&origin)) {
+            return false;
+        }
+
+        self.allowed.is_empty() || self.allowed.iter().any(|pattern| pattern.matches(&origin))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OriginPattern {
+    /// Exact origin, e.g., "https://example.com" or "null"
+    Exact(Vec<u8>),
+    /// Any subdomain of host, e.g., "https://*.example.com"
+    Subdomains {
+        /// Scheme including "://"
+        scheme: Vec<u8>,
+        /// Host and optional port, including leading dot
+        suffix: Vec<u8>,
+    },
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+
+        if pattern == "null" {
+            return Ok(Self::Exact(pattern.into_bytes()));
+        }
+
+        let (scheme, host) = pattern
+            .split_once("://")
+            .with_context(|| "expected scheme, e.g., https://example.com")?;
+
+        if scheme.is_empty() || host.is_empty() {
+            return Err(anyhow::anyhow!("expected scheme and host"));
+        }
+        if host.contains('/') {
+            return Err(anyhow::anyhow!("origins can't contain paths"));
+        }
+
+        if let Some(suffix) = host.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 || suffix.contains('*') {
+                return Err(anyhow::anyhow!(
+                    "wildcard is only supported as leading label, e.g., https://*.example.com"
+                ));
+            }
+
+            Ok(Self::Subdomains {
+                scheme: format!("{}://", scheme).into_bytes(),
+                suffix: suffix.as_bytes().to_vec(),
+            })
+        } else if host.contains('*') {
+            Err(anyhow::anyhow!(
+                "wildcard is only supported as leading label, e.g., https://*.example.com"
+            ))
+        } else {
+            Ok(Self::Exact(pattern.into_bytes()))
+        }
+    }
+
+    /// Origin must be lowercase
+    fn matches(&self, origin: &[u8]) -> bool {
+        match self {
+            Self::Exact(pattern) => origin == pattern.as_slice(),
+            Self::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_slice())
+                .and_then(|host| host.strip_suffix(suffix.as_slice()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty() && !subdomain.iter().any(|b| matches!(b, b':' | b'/'))
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed: &[&str], denied: &[&str]) -> OriginPolicy {
+        let config = NetworkConfig {
+            allowed_origins: allowed.iter().map(|s| s.to_string()).collect(),
+            denied_origins: denied.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+
+        OriginPolicy::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_origin_policy() {
+        let all = policy(&[], &[]);
+
+        assert!(all.is_allowed(b"https://example.com"));
+        assert!(all.is_allowed(b"null"));
+
+        let allowlist = policy(&["https://example.com/", "https://*.example.org:8443"], &[]);
+
+        assert!(allowlist.is_allowed(b"https://example.com"));
+        assert!(allowlist.is_allowed(b"https://EXAMPLE.com"));
+        assert!(allowlist.is_allowed(b"https://a.example.org:8443"));
+        assert!(allowlist.is_allowed(b"https://a.b.example.org:8443"));
+        assert!(!allowlist.is_allowed(b"http://example.com"));
+        assert!(!allowlist.is_allowed(b"https://a.example.com"));
+        assert!(!allowlist.is_allowed(b"https://example.org:8443"));
+        assert!(!allowlist.is_allowed(b"https://a.example.org"));
+        assert!(!allowlist.is_allowed(b"https://evil.com/.example.org:8443"));
+        assert!(!allowlist.is_allowed(b"https://evilexample.org:8443"));
+        assert!(!allowlist.is_allowed(b"null"));
+
+        let denylist = policy(&[], &["https://*.example.com", "null"]);
+
+        assert!(denylist.is_allowed(b"https://example.com"));
+        assert!(!denylist.is_allowed(b"https://a.example.com"));
+        assert!(!denylist.is_allowed(b"null"));
+
+        let both = policy(&["https://*.example.com"], &["https://bad.example.com"]);
+
+        assert!(both.is_allowed(b"https://good.example.com"));
+        assert!(!both.is_allowed(b"https://bad.example.com"));
+    }
+
+    #[test]
+    fn test_origin_pattern_invalid() {
+        for pattern in [
+            "example.com",
+            "https://",
+            "://example.com",
+            "https://example.com/path",
+            "https://*",
+            "https://*.",
+            "https://*example.com",
+            "https://a.*.example.com",
+            "https://*.*.example.com",
+        ] {
+            assert!(OriginPattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
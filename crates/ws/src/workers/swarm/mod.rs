@@ -1,18 +1,20 @@
-mod storage;
+pub(crate) mod storage;
 
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::time::Duration;
 
-use aquatic_ws_protocol::incoming::InMessage;
+use aquatic_ws_protocol::incoming::{AnnounceEvent as RequestEvent, AnnounceRequest, InMessage};
 use aquatic_ws_protocol::outgoing::OutMessage;
 use futures::StreamExt;
-use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
+use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
 use glommio::enclose;
 use glommio::prelude::*;
 use glommio::timer::TimerActionRepeat;
 use rand::{rngs::SmallRng, SeedableRng};
 
+use aquatic_common::observer::{AnnounceEvent, AnnounceKind, AnnounceSubscribers};
+use aquatic_common::routing::WorkerSenders;
 use aquatic_common::ServerStartInstant;
 
 use crate::common::*;
@@ -26,7 +28,7 @@ pub async fn run_swarm_worker(
     state: State,
     control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
     in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
-    out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
+    out_message_mesh_builder: MeshBuilder<Vec<(OutMessageMeta, OutMessage)>, Partial>,
     server_start_instant: ServerStartInstant,
     worker_index: usize,
 ) -> anyhow::Result<()> {
@@ -38,24 +40,30 @@ pub async fn run_swarm_worker(
         .join(Role::Consumer)
         .await
         .map_err(|err| anyhow::anyhow!("join in message mesh: {:#}", err))?;
-    let (out_message_senders, _) = out_message_mesh_builder
-        .join(Role::Producer)
-        .await
-        .map_err(|err| anyhow::anyhow!("join out message mesh: {:#}", err))?;
-
-    let out_message_senders = Rc::new(out_message_senders);
+    let out_message_senders =
+        WorkerSenders::join_mesh(out_message_mesh_builder, "out message").await?;
 
+    // Maps are created on worker thread, so that their memory is allocated
+    // on the worker's NUMA node when NUMA-aware placement is active
     let torrents = Rc::new(RefCell::new(TorrentMaps::new(worker_index)));
     let access_list = state.access_list;
+    let swarm_summaries = state.swarm_summaries;
+    let announce_subscribers = state.announce_subscribers;
 
     // Periodically clean torrents
-    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
-        enclose!((config, torrents, access_list) move || async move {
-            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+    TimerActionRepeat::repeat(
+        enclose!((config, torrents, access_list, swarm_summaries) move || {
+            enclose!((config, torrents, access_list, swarm_summaries) move || async move {
+                let mut torrents = torrents.borrow_mut();
 
-            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
-        })()
-    }));
+                torrents.clean(&config, &access_list, server_start_instant);
+
+                swarm_summaries.update(worker_index, torrents.summary());
+
+                Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            })()
+        }),
+    );
 
     // Periodically update torrent count metrics
     #[cfg(feature = "metrics")]
@@ -82,6 +90,7 @@ pub async fn run_swarm_worker(
             torrents.clone(),
             server_start_instant,
             out_message_senders.clone(),
+            announce_subscribers.clone(),
             receiver,
         ))
         .detach();
@@ -120,7 +129,8 @@ async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
     server_start_instant: ServerStartInstant,
-    out_message_senders: Rc<Senders<(OutMessageMeta, OutMessage)>>,
+    out_message_senders: WorkerSenders<Vec<(OutMessageMeta, OutMessage)>>,
+    announce_subscribers: AnnounceSubscribers,
     stream: S,
 ) where
     S: futures_lite::Stream<Item = (InMessageMeta, InMessage)> + ::std::marker::Unpin,
@@ -130,6 +140,7 @@ async fn handle_request_stream<S>(
     let torrents = &torrents;
     let rng = &rng;
     let out_message_senders = &out_message_senders;
+    let announce_subscribers = &announce_subscribers;
 
     stream
         .for_each_concurrent(
@@ -139,6 +150,8 @@ async fn handle_request_stream<S>(
 
                 match in_message {
                     InMessage::AnnounceRequest(request) => {
+                        announce_subscribers.publish(|| announce_event(&request));
+
                         torrents.borrow_mut().handle_announce_request(
                             config,
                             &mut rng.borrow_mut(),
@@ -153,15 +166,56 @@ async fn handle_request_stream<S>(
                         .handle_scrape_request(config, &mut out_messages, meta, request),
                 };
 
-                for (meta, out_message) in out_messages {
-                    out_message_senders
-                        .send_to(meta.out_message_consumer_id.0 as usize, (meta, out_message))
+                // Send all messages for a socket worker as a single batch to
+                // reduce channel synchronization overhead. Sort is stable, so
+                // message order per socket worker is preserved.
+                out_messages.sort_by_key(|(meta, _)| meta.out_message_consumer_id.0);
+
+                let mut out_messages = out_messages.into_iter().peekable();
+
+                while let Some((meta, out_message)) = out_messages.next() {
+                    let consumer_id = meta.out_message_consumer_id.0;
+
+                    let mut batch = vec![(meta, out_message)];
+
+                    while let Some(item) = out_messages
+                        .next_if(|(meta, _)| meta.out_message_consumer_id.0 == consumer_id)
+                    {
+                        batch.push(item);
+                    }
+
+                    // Fails if socket worker has stopped during shutdown
+                    if let Err(err) = out_message_senders
+                        .send_to(consumer_id as usize, batch)
                         .await
-                        .expect("failed sending out_message to socket worker");
+                    {
+                        ::log::debug!("couldn't send OutMessage batch to socket worker: {:#}", err);
 
-                    ::log::debug!("swarm worker sent OutMessage to socket worker");
+                        continue;
+                    }
+
+                    ::log::debug!("swarm worker sent OutMessage batch to socket worker");
                 }
             },
         )
         .await;
 }
+
+fn announce_event(request: &AnnounceRequest) -> AnnounceEvent {
+    let kind = match request.event.unwrap_or_default() {
+        RequestEvent::Started => AnnounceKind::Started,
+        RequestEvent::Stopped => AnnounceKind::Stopped,
+        RequestEvent::Completed => AnnounceKind::Completed,
+        RequestEvent::Update => AnnounceKind::Update,
+    };
+
+    // WebTorrent peers are reached through the tracker, so their addresses
+    // are not relevant
+    AnnounceEvent {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        peer_addr: None,
+        kind,
+        bytes_left: request.bytes_left.map(|bytes_left| bytes_left as u64),
+    }
+}
@@ -1,17 +1,18 @@
 use std::sync::Arc;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::observer::SwarmSummary;
 use aquatic_ws_protocol::incoming::{
     AnnounceEvent, AnnounceRequest, AnnounceRequestOffer, ScrapeRequest,
 };
 use aquatic_ws_protocol::outgoing::{
-    AnnounceResponse, AnswerOutMessage, ErrorResponse, ErrorResponseAction, OfferOutMessage,
-    OutMessage, ScrapeResponse, ScrapeStatistics,
+    AnnounceResponse, AnswerOutMessage, IpVersionScrapeStatistics, OfferOutMessage, OutMessage,
+    ScrapeResponse, ScrapeStatistics,
 };
-use hashbrown::HashMap;
 use rand::rngs::SmallRng;
+use slab::Slab;
 
-use aquatic_common::{IndexMap, SecondsSinceServerStart, ServerStartInstant};
+use aquatic_common::{IndexMap, PeerExpiry, SecondsSinceServerStart, ServerStartInstant};
 use aquatic_ws_protocol::common::*;
 use rand::Rng;
 
@@ -59,9 +60,80 @@ impl TorrentMaps {
         meta: InMessageMeta,
         request: ScrapeRequest,
     ) {
-        let torrent_map = self.get_torrent_map_by_ip_version(meta.ip_version);
+        let info_hashes = if let Some(info_hashes) = request.info_hashes {
+            info_hashes.as_vec()
+        } else {
+            return;
+        };
+
+        let num_to_take = info_hashes.len().min(config.protocol.max_scrape_torrents);
+
+        let mut out_message = ScrapeResponse {
+            action: ScrapeAction::Scrape,
+            files: ::indexmap::IndexMap::with_capacity(num_to_take),
+        };
+
+        let torrent_map = match meta.ip_version {
+            IpVersion::V4 => &self.ipv4,
+            IpVersion::V6 => &self.ipv6,
+        };
+
+        for info_hash in info_hashes.into_iter().take(num_to_take) {
+            let opt_stats = if config.protocol.scrape_merge_ip_versions {
+                match (
+                    self.ipv4.scrape_statistics(&info_hash),
+                    self.ipv6.scrape_statistics(&info_hash),
+                ) {
+                    (None, None) => None,
+                    (opt_ipv4, opt_ipv6) => {
+                        let ipv4 = opt_ipv4.unwrap_or_default();
+                        let ipv6 = opt_ipv6.unwrap_or_default();
+
+                        Some(IpVersionScrapeStatistics {
+                            complete: ipv4.complete + ipv6.complete,
+                            incomplete: ipv4.incomplete + ipv6.incomplete,
+                        })
+                    }
+                }
+            } else {
+                torrent_map.scrape_statistics(&info_hash)
+            };
+
+            if config.protocol.scrape_ip_version_breakdown {
+                let opt_ipv4 = self.ipv4.scrape_statistics(&info_hash);
+                let opt_ipv6 = self.ipv6.scrape_statistics(&info_hash);
+
+                if opt_ipv4.is_none() && opt_ipv6.is_none() {
+                    continue;
+                }
+
+                let stats = opt_stats.unwrap_or_default();
 
-        torrent_map.handle_scrape_request(config, out_messages, meta, request);
+                out_message.files.insert(
+                    info_hash,
+                    ScrapeStatistics {
+                        complete: stats.complete,
+                        downloaded: 0, // No implementation planned
+                        incomplete: stats.incomplete,
+                        ipv4: Some(opt_ipv4.unwrap_or_default()),
+                        ipv6: Some(opt_ipv6.unwrap_or_default()),
+                    },
+                );
+            } else if let Some(stats) = opt_stats {
+                out_message.files.insert(
+                    info_hash,
+                    ScrapeStatistics {
+                        complete: stats.complete,
+                        downloaded: 0, // No implementation planned
+                        incomplete: stats.incomplete,
+                        ipv4: None,
+                        ipv6: None,
+                    },
+                );
+            }
+        }
+
+        out_messages.push((meta.into(), OutMessage::ScrapeResponse(out_message)));
     }
 
     pub fn clean(
@@ -71,10 +143,24 @@ impl TorrentMaps {
         server_start_instant: ServerStartInstant,
     ) {
         let mut access_list_cache = create_access_list_cache(access_list);
-        let now = server_start_instant.seconds_elapsed();
+        let expiry = PeerExpiry::new(
+            server_start_instant.seconds_elapsed(),
+            config.cleaning.max_peer_age,
+            config.cleaning.max_seeder_age,
+        );
 
-        self.ipv4.clean(config, &mut access_list_cache, now);
-        self.ipv6.clean(config, &mut access_list_cache, now);
+        self.ipv4.clean(config, &mut access_list_cache, expiry);
+        self.ipv6.clean(config, &mut access_list_cache, expiry);
+    }
+
+    /// Count torrents and peers of both IP versions
+    pub fn summary(&self) -> SwarmSummary {
+        let mut summary = SwarmSummary::default();
+
+        self.ipv4.add_to_summary(&mut summary);
+        self.ipv6.add_to_summary(&mut summary);
+
+        summary
     }
 
     #[cfg(feature = "metrics")]
@@ -104,10 +190,20 @@ impl TorrentMaps {
 
 struct TorrentMap {
     torrents: IndexMap<InfoHash, TorrentData>,
+    /// Peer records of all torrents, referenced by key from torrent peer
+    /// maps. Keeping them in a single slab reduces allocator pressure when
+    /// peers come and go.
+    peers: Slab<Peer>,
     #[cfg(feature = "metrics")]
     torrent_gauge: ::metrics::Gauge,
     #[cfg(feature = "metrics")]
     peer_gauge: ::metrics::Gauge,
+    #[cfg(feature = "metrics")]
+    answers_dropped_unknown_counter: ::metrics::Counter,
+    #[cfg(feature = "metrics")]
+    answers_dropped_unsolicited_counter: ::metrics::Counter,
+    #[cfg(feature = "metrics")]
+    answers_dropped_expired_counter: ::metrics::Counter,
 }
 
 impl TorrentMap {
@@ -115,12 +211,12 @@ impl TorrentMap {
         #[cfg(feature = "metrics")]
         let peer_gauge = match ip_version {
             IpVersion::V4 => ::metrics::gauge!(
-                "aquatic_peers",
+                aquatic_metrics::names::PEERS,
                 "ip_version" => "4",
                 "worker_index" => worker_index.to_string(),
             ),
             IpVersion::V6 => ::metrics::gauge!(
-                "aquatic_peers",
+                aquatic_metrics::names::PEERS,
                 "ip_version" => "6",
                 "worker_index" => worker_index.to_string(),
             ),
@@ -128,23 +224,51 @@ impl TorrentMap {
         #[cfg(feature = "metrics")]
         let torrent_gauge = match ip_version {
             IpVersion::V4 => ::metrics::gauge!(
-                "aquatic_torrents",
+                aquatic_metrics::names::TORRENTS,
                 "ip_version" => "4",
                 "worker_index" => worker_index.to_string(),
             ),
             IpVersion::V6 => ::metrics::gauge!(
-                "aquatic_torrents",
+                aquatic_metrics::names::TORRENTS,
                 "ip_version" => "6",
                 "worker_index" => worker_index.to_string(),
             ),
         };
 
+        #[cfg(feature = "metrics")]
+        let ip_version_str = match ip_version {
+            IpVersion::V4 => "4",
+            IpVersion::V6 => "6",
+        };
+
         Self {
             torrents: Default::default(),
+            peers: Default::default(),
             #[cfg(feature = "metrics")]
             peer_gauge,
             #[cfg(feature = "metrics")]
             torrent_gauge,
+            #[cfg(feature = "metrics")]
+            answers_dropped_unknown_counter: ::metrics::counter!(
+                aquatic_metrics::names::ANSWERS_DROPPED_TOTAL,
+                "reason" => "unknown",
+                "ip_version" => ip_version_str,
+                "worker_index" => worker_index.to_string(),
+            ),
+            #[cfg(feature = "metrics")]
+            answers_dropped_unsolicited_counter: ::metrics::counter!(
+                aquatic_metrics::names::ANSWERS_DROPPED_TOTAL,
+                "reason" => "unsolicited",
+                "ip_version" => ip_version_str,
+                "worker_index" => worker_index.to_string(),
+            ),
+            #[cfg(feature = "metrics")]
+            answers_dropped_expired_counter: ::metrics::counter!(
+                aquatic_metrics::names::ANSWERS_DROPPED_TOTAL,
+                "reason" => "expired",
+                "ip_version" => ip_version_str,
+                "worker_index" => worker_index.to_string(),
+            ),
         }
     }
 
@@ -163,8 +287,8 @@ impl TorrentMap {
         // is same as that of request sender. Otherwise, ignore request. Since
         // peers have access to each others peer_id's, they could send requests
         // using them, causing all sorts of issues.
-        if let Some(previous_peer) = torrent_data.peers.get(&request.peer_id) {
-            if request_sender_meta.connection_id != previous_peer.connection_id {
+        if let Some(previous_peer_key) = torrent_data.peers.get(&request.peer_id) {
+            if request_sender_meta.connection_id != self.peers[*previous_peer_key].connection_id {
                 return;
             }
         }
@@ -172,6 +296,7 @@ impl TorrentMap {
         ::log::trace!("received request from {:?}", request_sender_meta);
 
         let peer_status = torrent_data.insert_or_update_peer(
+            &mut self.peers,
             config,
             server_start_instant,
             request_sender_meta,
@@ -183,6 +308,7 @@ impl TorrentMap {
         if peer_status != PeerStatus::Stopped {
             if let Some(offers) = request.offers {
                 torrent_data.handle_offers(
+                    &mut self.peers,
                     config,
                     rng,
                     server_start_instant,
@@ -198,8 +324,10 @@ impl TorrentMap {
                 request.answer_to_peer_id,
                 request.answer_offer_id,
             ) {
-                let opt_out_message = torrent_data.handle_answer(
+                let result = torrent_data.handle_answer(
+                    &mut self.peers,
                     request_sender_meta,
+                    server_start_instant.seconds_elapsed(),
                     request.info_hash,
                     request.peer_id,
                     answer_receiver_id,
@@ -207,8 +335,28 @@ impl TorrentMap {
                     answer,
                 );
 
-                if let Some(out_message) = opt_out_message {
-                    out_messages.push(out_message);
+                match result {
+                    Ok(out_message) => out_messages.push(out_message),
+                    Err(reason) => {
+                        ::log::trace!(
+                            "dropped answer from {:?}: {:?}",
+                            request_sender_meta,
+                            reason
+                        );
+
+                        #[cfg(feature = "metrics")]
+                        match reason {
+                            AnswerDropReason::Unknown => {
+                                self.answers_dropped_unknown_counter.increment(1)
+                            }
+                            AnswerDropReason::Unsolicited => {
+                                self.answers_dropped_unsolicited_counter.increment(1)
+                            }
+                            AnswerDropReason::Expired => {
+                                self.answers_dropped_expired_counter.increment(1)
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -224,44 +372,19 @@ impl TorrentMap {
         out_messages.push((request_sender_meta.into(), response));
     }
 
-    pub fn handle_scrape_request(
-        &mut self,
-        config: &Config,
-        out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
-        meta: InMessageMeta,
-        request: ScrapeRequest,
-    ) {
-        let info_hashes = if let Some(info_hashes) = request.info_hashes {
-            info_hashes.as_vec()
-        } else {
-            return;
-        };
-
-        let num_to_take = info_hashes.len().min(config.protocol.max_scrape_torrents);
-
-        let mut out_message = ScrapeResponse {
-            action: ScrapeAction::Scrape,
-            files: HashMap::with_capacity(num_to_take),
-        };
-
-        for info_hash in info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = self.torrents.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers(),
-                };
-
-                out_message.files.insert(info_hash, stats);
-            }
-        }
-
-        out_messages.push((meta.into(), OutMessage::ScrapeResponse(out_message)));
+    fn scrape_statistics(&self, info_hash: &InfoHash) -> Option<IpVersionScrapeStatistics> {
+        self.torrents
+            .get(info_hash)
+            .map(|torrent_data| IpVersionScrapeStatistics {
+                complete: torrent_data.num_seeders,
+                incomplete: torrent_data.num_leechers(),
+            })
     }
 
     pub fn handle_connection_closed(&mut self, info_hash: InfoHash, peer_id: PeerId) {
         if let Some(torrent_data) = self.torrents.get_mut(&info_hash) {
             torrent_data.handle_connection_closed(
+                &mut self.peers,
                 peer_id,
                 #[cfg(feature = "metrics")]
                 &self.peer_gauge,
@@ -269,6 +392,15 @@ impl TorrentMap {
         }
     }
 
+    fn add_to_summary(&self, summary: &mut SwarmSummary) {
+        summary.torrents += self.torrents.len();
+
+        for torrent_data in self.torrents.values() {
+            summary.seeders += torrent_data.num_seeders;
+            summary.leechers += torrent_data.num_leechers();
+        }
+    }
+
     #[cfg(feature = "metrics")]
     pub fn update_torrent_gauge(&self) {
         self.torrent_gauge.set(self.torrents.len() as f64);
@@ -278,19 +410,25 @@ impl TorrentMap {
         &mut self,
         config: &Config,
         access_list_cache: &mut AccessListCache,
-        now: SecondsSinceServerStart,
+        expiry: PeerExpiry,
     ) {
         let mut total_num_peers = 0u64;
 
+        let peers = &mut self.peers;
+
         self.torrents.retain(|info_hash, torrent_data| {
             if !access_list_cache
                 .load()
                 .allows(config.access_list.mode, &info_hash.0)
             {
+                for peer_key in torrent_data.peers.values() {
+                    peers.remove(*peer_key);
+                }
+
                 return false;
             }
 
-            let num_peers = torrent_data.clean_and_get_num_peers(now);
+            let num_peers = torrent_data.clean_and_get_num_peers(peers, expiry);
 
             total_num_peers += num_peers as u64;
 
@@ -298,6 +436,7 @@ impl TorrentMap {
         });
 
         self.torrents.shrink_to_fit();
+        self.peers.shrink_to_fit();
 
         #[cfg(feature = "metrics")]
         self.peer_gauge.set(total_num_peers as f64);
@@ -309,7 +448,8 @@ impl TorrentMap {
 
 #[derive(Default)]
 struct TorrentData {
-    peers: IndexMap<PeerId, Peer>,
+    /// Keys into peer slab of torrent map
+    peers: IndexMap<PeerId, usize>,
     num_seeders: usize,
 }
 
@@ -320,6 +460,7 @@ impl TorrentData {
 
     pub fn insert_or_update_peer(
         &mut self,
+        peers: &mut Slab<Peer>,
         config: &Config,
         server_start_instant: ServerStartInstant,
         request_sender_meta: InMessageMeta,
@@ -334,9 +475,9 @@ impl TorrentData {
         );
 
         match self.peers.entry(request.peer_id) {
-            ::indexmap::map::Entry::Occupied(mut entry) => match peer_status {
+            ::indexmap::map::Entry::Occupied(entry) => match peer_status {
                 PeerStatus::Leeching => {
-                    let peer = entry.get_mut();
+                    let peer = &mut peers[*entry.get()];
 
                     if peer.seeder {
                         self.num_seeders -= 1;
@@ -346,7 +487,7 @@ impl TorrentData {
                     peer.valid_until = valid_until;
                 }
                 PeerStatus::Seeding => {
-                    let peer = entry.get_mut();
+                    let peer = &mut peers[*entry.get()];
 
                     if !peer.seeder {
                         self.num_seeders += 1;
@@ -356,7 +497,7 @@ impl TorrentData {
                     peer.valid_until = valid_until;
                 }
                 PeerStatus::Stopped => {
-                    let peer = entry.swap_remove();
+                    let peer = peers.remove(entry.swap_remove());
 
                     if peer.seeder {
                         self.num_seeders -= 1;
@@ -376,7 +517,7 @@ impl TorrentData {
                         expecting_answers: Default::default(),
                     };
 
-                    entry.insert(peer);
+                    entry.insert(peers.insert(peer));
 
                     #[cfg(feature = "metrics")]
                     peer_gauge.increment(1.0)
@@ -392,7 +533,7 @@ impl TorrentData {
                         expecting_answers: Default::default(),
                     };
 
-                    entry.insert(peer);
+                    entry.insert(peers.insert(peer));
 
                     #[cfg(feature = "metrics")]
                     peer_gauge.increment(1.0);
@@ -408,6 +549,7 @@ impl TorrentData {
     #[allow(clippy::too_many_arguments)]
     pub fn handle_offers(
         &mut self,
+        peers: &mut Slab<Peer>,
         config: &Config,
         rng: &mut SmallRng,
         server_start_instant: ServerStartInstant,
@@ -416,17 +558,26 @@ impl TorrentData {
         offers: Vec<AnnounceRequestOffer>,
         out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
     ) {
-        let max_num_peers_to_take = offers.len().min(config.protocol.max_offers);
+        let max_num_peers_to_take = offers
+            .len()
+            .min(config.protocol.max_offers)
+            .min(config.protocol.max_peers_in_response);
 
         let offer_receivers: Vec<(PeerId, ConnectionId, ConsumerId)> = extract_response_peers(
             rng,
             &self.peers,
             max_num_peers_to_take,
             sender_peer_id,
-            |peer_id, peer| (*peer_id, peer.connection_id, peer.consumer_id),
+            |peer_id, peer_key| {
+                let peer = &peers[*peer_key];
+
+                (*peer_id, peer.connection_id, peer.consumer_id)
+            },
         );
 
-        if let Some(peer) = self.peers.get_mut(&sender_peer_id) {
+        if let Some(peer_key) = self.peers.get(&sender_peer_id) {
+            let peer = &mut peers[*peer_key];
+
             for (
                 offer,
                 (offer_receiver_peer_id, offer_receiver_connection_id, offer_receiver_consumer_id),
@@ -437,7 +588,14 @@ impl TorrentData {
                         from_peer_id: offer_receiver_peer_id,
                         regarding_offer_id: offer.offer_id,
                     },
-                    ValidUntil::new(server_start_instant, config.cleaning.max_offer_age),
+                    OfferRecipient {
+                        consumer_id: offer_receiver_consumer_id,
+                        connection_id: offer_receiver_connection_id,
+                        valid_until: ValidUntil::new(
+                            server_start_instant,
+                            config.cleaning.max_offer_age,
+                        ),
+                    },
                 );
 
                 let offer_out_message = OfferOutMessage {
@@ -459,67 +617,82 @@ impl TorrentData {
         }
     }
 
-    /// Pass on answer to relevant peer
+    /// Pass on answer to relevant peer if it is expecting it
+    ///
+    /// Answers to unknown or expired offers and answers from other
+    /// connections than the one the offer was relayed to are dropped without
+    /// notifying the sender, so that answers can't be used to probe for peer
+    /// ids or offer ids, or to send unsolicited answers.
+    #[allow(clippy::too_many_arguments)]
     fn handle_answer(
         &mut self,
+        peers: &mut Slab<Peer>,
         request_sender_meta: InMessageMeta,
+        now: SecondsSinceServerStart,
         info_hash: InfoHash,
         peer_id: PeerId,
         answer_receiver_id: PeerId,
         offer_id: OfferId,
         answer: RtcAnswer,
-    ) -> Option<(OutMessageMeta, OutMessage)> {
-        if let Some(answer_receiver) = self.peers.get_mut(&answer_receiver_id) {
-            let expecting_answer = ExpectingAnswer {
-                from_peer_id: peer_id,
-                regarding_offer_id: offer_id,
-            };
+    ) -> Result<(OutMessageMeta, OutMessage), AnswerDropReason> {
+        let answer_receiver_key = self
+            .peers
+            .get(&answer_receiver_id)
+            .ok_or(AnswerDropReason::Unknown)?;
+        let answer_receiver = &mut peers[*answer_receiver_key];
+
+        let expecting_answer = ExpectingAnswer {
+            from_peer_id: peer_id,
+            regarding_offer_id: offer_id,
+        };
 
-            if answer_receiver
-                .expecting_answers
-                .swap_remove(&expecting_answer)
-                .is_some()
-            {
-                let answer_out_message = AnswerOutMessage {
-                    action: AnnounceAction::Announce,
-                    peer_id,
-                    info_hash,
-                    answer,
-                    offer_id,
-                };
+        let recipient = answer_receiver
+            .expecting_answers
+            .get(&expecting_answer)
+            .ok_or(AnswerDropReason::Unknown)?;
 
-                let meta = OutMessageMeta {
-                    out_message_consumer_id: answer_receiver.consumer_id,
-                    connection_id: answer_receiver.connection_id,
-                    pending_scrape_id: None,
-                };
+        // Leave offer in place, so that legitimate answer can still arrive
+        if !recipient.matches(request_sender_meta) {
+            return Err(AnswerDropReason::Unsolicited);
+        }
 
-                Some((meta, OutMessage::AnswerOutMessage(answer_out_message)))
-            } else {
-                let error_message = ErrorResponse {
-                    action: Some(ErrorResponseAction::Announce),
-                    info_hash: Some(info_hash),
-                    failure_reason:
-                        "Could not find the offer corresponding to your answer. It may have expired."
-                            .into(),
-                };
+        let recipient = answer_receiver
+            .expecting_answers
+            .swap_remove(&expecting_answer)
+            .expect("offer recipient was just found");
 
-                Some((
-                    request_sender_meta.into(),
-                    OutMessage::ErrorResponse(error_message),
-                ))
-            }
-        } else {
-            None
+        if !recipient.valid_until.valid(now) {
+            return Err(AnswerDropReason::Expired);
         }
+
+        let answer_out_message = AnswerOutMessage {
+            action: AnnounceAction::Announce,
+            peer_id,
+            info_hash,
+            answer,
+            offer_id,
+        };
+
+        let meta = OutMessageMeta {
+            out_message_consumer_id: answer_receiver.consumer_id,
+            connection_id: answer_receiver.connection_id,
+            pending_scrape_id: None,
+        };
+
+        Ok((meta, OutMessage::AnswerOutMessage(answer_out_message)))
     }
 
     pub fn handle_connection_closed(
         &mut self,
+        peers: &mut Slab<Peer>,
         peer_id: PeerId,
         #[cfg(feature = "metrics")] peer_gauge: &::metrics::Gauge,
     ) {
-        if let Some(peer) = self.peers.swap_remove(&peer_id) {
+        if let Some(peer) = self
+            .peers
+            .swap_remove(&peer_id)
+            .map(|key| peers.remove(key))
+        {
             if peer.seeder {
                 self.num_seeders -= 1;
             }
@@ -529,16 +702,22 @@ impl TorrentData {
         }
     }
 
-    fn clean_and_get_num_peers(&mut self, now: SecondsSinceServerStart) -> usize {
-        self.peers.retain(|_, peer| {
+    fn clean_and_get_num_peers(&mut self, peers: &mut Slab<Peer>, expiry: PeerExpiry) -> usize {
+        self.peers.retain(|_, peer_key| {
+            let peer = &mut peers[*peer_key];
+
             peer.expecting_answers
-                .retain(|_, valid_until| valid_until.valid(now));
+                .retain(|_, recipient| recipient.valid_until.valid(expiry.now()));
             peer.expecting_answers.shrink_to_fit();
 
-            let keep = peer.valid_until.valid(now);
+            let keep = expiry.keep(peer.valid_until, peer.seeder);
 
-            if (!keep) & peer.seeder {
-                self.num_seeders -= 1;
+            if !keep {
+                if peer.seeder {
+                    self.num_seeders -= 1;
+                }
+
+                peers.remove(*peer_key);
             }
 
             keep
@@ -556,7 +735,35 @@ struct Peer {
     pub connection_id: ConnectionId,
     pub seeder: bool,
     pub valid_until: ValidUntil,
-    pub expecting_answers: IndexMap<ExpectingAnswer, ValidUntil>,
+    pub expecting_answers: IndexMap<ExpectingAnswer, OfferRecipient>,
+}
+
+/// Connection that an offer was relayed to. Answers are only accepted from
+/// it, so that a peer id can't be taken over (e.g., after its connection has
+/// been closed) to answer offers sent to someone else.
+#[derive(Clone, Copy, Debug)]
+struct OfferRecipient {
+    consumer_id: ConsumerId,
+    connection_id: ConnectionId,
+    valid_until: ValidUntil,
+}
+
+impl OfferRecipient {
+    fn matches(&self, meta: InMessageMeta) -> bool {
+        self.consumer_id.0 == meta.out_message_consumer_id.0
+            && self.connection_id == meta.connection_id
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AnswerDropReason {
+    /// No such peer or no such offer sent to the answering peer
+    Unknown,
+    /// Offer was sent to a peer with the answering peer id, but over a
+    /// different connection
+    Unsolicited,
+    /// Offer was sent, but more than `cleaning.max_offer_age` ago
+    Expired,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -723,4 +930,219 @@ mod tests {
 
         assert_eq!(response_peers.len(), unique.len(),);
     }
+
+    #[test]
+    fn test_handle_answer() {
+        let receiver_peer_id = PeerId([1; 20]);
+        let sender_peer_id = PeerId([2; 20]);
+        let valid_offer_id = OfferId([1; 20]);
+        let expired_offer_id = OfferId([2; 20]);
+
+        let now = ServerStartInstant::new().seconds_elapsed();
+
+        let mut peers = Slab::new();
+        let mut torrent_data = TorrentData::default();
+
+        let mut receiver = Peer {
+            consumer_id: ConsumerId(0),
+            connection_id: ConnectionId::default(),
+            seeder: false,
+            valid_until: ValidUntil::new_with_now(now, 60),
+            expecting_answers: Default::default(),
+        };
+
+        let mut connection_ids = slotmap::SlotMap::<ConnectionId, ()>::with_key();
+
+        let answerer_meta = InMessageMeta {
+            out_message_consumer_id: ConsumerId(0),
+            connection_id: connection_ids.insert(()),
+            ip_version: IpVersion::V4,
+            pending_scrape_id: None,
+        };
+        let other_meta = InMessageMeta {
+            connection_id: connection_ids.insert(()),
+            ..answerer_meta
+        };
+
+        for (offer_id, valid_until) in [
+            (valid_offer_id, ValidUntil::new_with_now(now, 60)),
+            (expired_offer_id, ValidUntil::from_seconds(0)),
+        ] {
+            receiver.expecting_answers.insert(
+                ExpectingAnswer {
+                    from_peer_id: sender_peer_id,
+                    regarding_offer_id: offer_id,
+                },
+                OfferRecipient {
+                    consumer_id: answerer_meta.out_message_consumer_id,
+                    connection_id: answerer_meta.connection_id,
+                    valid_until,
+                },
+            );
+        }
+
+        torrent_data
+            .peers
+            .insert(receiver_peer_id, peers.insert(receiver));
+
+        let mut handle_answer = |meta, answer_receiver_id, offer_id| {
+            torrent_data
+                .handle_answer(
+                    &mut peers,
+                    meta,
+                    now,
+                    InfoHash([0; 20]),
+                    sender_peer_id,
+                    answer_receiver_id,
+                    offer_id,
+                    RtcAnswer {
+                        t: RtcAnswerType::Answer,
+                        sdp: "test".into(),
+                    },
+                )
+                .map(|_| ())
+        };
+
+        assert_eq!(
+            handle_answer(answerer_meta, PeerId([3; 20]), valid_offer_id),
+            Err(AnswerDropReason::Unknown)
+        );
+        assert_eq!(
+            handle_answer(answerer_meta, receiver_peer_id, OfferId([3; 20])),
+            Err(AnswerDropReason::Unknown)
+        );
+        assert_eq!(
+            handle_answer(answerer_meta, receiver_peer_id, expired_offer_id),
+            Err(AnswerDropReason::Expired)
+        );
+        assert_eq!(
+            handle_answer(other_meta, receiver_peer_id, valid_offer_id),
+            Err(AnswerDropReason::Unsolicited)
+        );
+        assert_eq!(
+            handle_answer(answerer_meta, receiver_peer_id, valid_offer_id),
+            Ok(())
+        );
+        // Offers can only be answered once
+        assert_eq!(
+            handle_answer(answerer_meta, receiver_peer_id, valid_offer_id),
+            Err(AnswerDropReason::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_no_relays_between_info_hashes() {
+        let config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let server_start_instant = ServerStartInstant::new();
+
+        let mut torrent_map = TorrentMap::new(0, IpVersion::V4);
+        let mut connection_ids = slotmap::SlotMap::<ConnectionId, ()>::with_key();
+
+        let info_hash_a = InfoHash([1; 20]);
+        let info_hash_b = InfoHash([2; 20]);
+        let peer_id_a = PeerId([1; 20]);
+        let peer_id_b = PeerId([2; 20]);
+        let offer_id = OfferId([1; 20]);
+
+        let meta_a = InMessageMeta {
+            out_message_consumer_id: ConsumerId(0),
+            connection_id: connection_ids.insert(()),
+            ip_version: IpVersion::V4,
+            pending_scrape_id: None,
+        };
+        let meta_b = InMessageMeta {
+            connection_id: connection_ids.insert(()),
+            ..meta_a
+        };
+        let meta_c = InMessageMeta {
+            connection_id: connection_ids.insert(()),
+            ..meta_a
+        };
+
+        let request = |info_hash, peer_id| AnnounceRequest {
+            action: AnnounceAction::Announce,
+            info_hash,
+            peer_id,
+            bytes_left: Some(1),
+            event: None,
+            offers: None,
+            numwant: None,
+            answer: None,
+            answer_to_peer_id: None,
+            answer_offer_id: None,
+        };
+
+        let mut out_messages = Vec::new();
+
+        torrent_map.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut out_messages,
+            server_start_instant,
+            meta_b,
+            request(info_hash_b, peer_id_b),
+        );
+        torrent_map.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut out_messages,
+            server_start_instant,
+            meta_c,
+            request(info_hash_a, PeerId([3; 20])),
+        );
+
+        out_messages.clear();
+
+        // Offers are relayed to peer c in same swarm, but not to peer b
+
+        torrent_map.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut out_messages,
+            server_start_instant,
+            meta_a,
+            AnnounceRequest {
+                offers: Some(vec![AnnounceRequestOffer {
+                    offer: RtcOffer {
+                        t: RtcOfferType::Offer,
+                        sdp: "test".into(),
+                    },
+                    offer_id,
+                }]),
+                ..request(info_hash_a, peer_id_a)
+            },
+        );
+
+        let offer_recipients = out_messages
+            .iter()
+            .filter(|(_, message)| matches!(message, OutMessage::OfferOutMessage(_)))
+            .map(|(meta, _)| meta.connection_id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(offer_recipients, vec![meta_c.connection_id]);
+
+        // Answers to peer in other swarm are not relayed to peer a
+
+        torrent_map.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut out_messages,
+            server_start_instant,
+            meta_b,
+            AnnounceRequest {
+                answer: Some(RtcAnswer {
+                    t: RtcAnswerType::Answer,
+                    sdp: "test".into(),
+                }),
+                answer_to_peer_id: Some(peer_id_a),
+                answer_offer_id: Some(offer_id),
+                ..request(info_hash_b, peer_id_b)
+            },
+        );
+
+        assert!(!out_messages
+            .iter()
+            .any(|(_, message)| matches!(message, OutMessage::AnswerOutMessage(_))));
+    }
 }
@@ -0,0 +1,119 @@
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener},
+    process::{Command, Stdio},
+    sync::mpsc::channel,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use aquatic_ws::config::Config;
+
+/// Statistics, diagnostics and prometheus endpoint threads are running too,
+/// so the tracker only exits if they stop on shutdown as well
+#[test]
+fn test_exit_on_sigterm() -> anyhow::Result<()> {
+    let mut config_file = tempfile::NamedTempFile::new()?;
+
+    writeln!(
+        config_file,
+        "log_level = 'info'\nsocket_workers = 2\nswarm_workers = 1\n\
+        [network]\naddress = '127.0.0.1:0'\n\
+        [statistics]\ninterval = 1\nprint_to_stdout = true\n\
+        [diagnostics]\nrun_connection_table_endpoint = true\n\
+        connection_table_endpoint_address = '127.0.0.1:0'"
+    )?;
+
+    if cfg!(feature = "prometheus") {
+        writeln!(
+            config_file,
+            "[metrics]\nrun_prometheus_endpoint = true\n\
+            prometheus_endpoint_address = '127.0.0.1:0'"
+        )?;
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aquatic_ws"))
+        .arg("-c")
+        .arg(config_file.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stderr = child.stderr.take().unwrap();
+    let (sender, receiver) = channel();
+
+    ::std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if line.contains("all socket workers bound") {
+                let _ = sender.send(());
+            }
+        }
+    });
+
+    // Signal handlers are installed before socket workers are started
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .with_context(|| "tracker didn't start")?;
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(15);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            assert!(status.success(), "exit status: {}", status);
+
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+
+            return Err(anyhow::anyhow!("tracker didn't exit after SIGTERM"));
+        }
+
+        sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_shutdown_closes_endpoints() -> anyhow::Result<()> {
+    let statistics_dir = tempfile::tempdir()?;
+    let mut config = Config::default();
+
+    config.network.address = "127.0.0.1:0".parse()?;
+    config.statistics.interval = 1;
+    config.statistics.write_json_to_file = true;
+    config.statistics.json_file_path = statistics_dir.path().join("statistics.jsonl");
+    config.diagnostics.run_connection_table_endpoint = true;
+    config.diagnostics.connection_table_endpoint_address = free_addr()?;
+
+    #[cfg(feature = "prometheus")]
+    {
+        config.metrics.run_prometheus_endpoint = true;
+        config.metrics.prometheus_endpoint_address = free_addr()?;
+    }
+
+    let tracker = aquatic_ws::run_with_handle(config.clone())?;
+
+    tracker.shutdown()?;
+
+    // Listeners are closed once their threads have stopped
+    TcpListener::bind(config.diagnostics.connection_table_endpoint_address)
+        .with_context(|| "bind connection table endpoint address")?;
+
+    #[cfg(feature = "prometheus")]
+    TcpListener::bind(config.metrics.prometheus_endpoint_address)
+        .with_context(|| "bind prometheus endpoint address")?;
+
+    Ok(())
+}
+
+fn free_addr() -> anyhow::Result<SocketAddr> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?)
+}
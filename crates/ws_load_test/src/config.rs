@@ -15,6 +15,12 @@ pub struct Config {
     pub connection_creation_interval_ms: u64,
     pub duration: usize,
     pub measure_after_max_connections_reached: bool,
+    /// Resume TLS sessions when opening new connections, if the server
+    /// supports it
+    pub tls_session_resumption: bool,
+    /// Send first request as TLS 1.3 early data (0-RTT) when resuming
+    /// sessions, if the server accepts it
+    pub tls_early_data: bool,
     pub torrents: TorrentConfig,
 }
 
@@ -34,6 +40,8 @@ impl Default for Config {
             connection_creation_interval_ms: 10,
             duration: 0,
             measure_after_max_connections_reached: true,
+            tls_session_resumption: true,
+            tls_early_data: false,
             torrents: TorrentConfig::default(),
         }
     }
@@ -55,7 +55,7 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         gamma: Arc::new(gamma),
     };
 
-    let tls_config = create_tls_config().unwrap();
+    let tls_config = create_tls_config(&config).unwrap();
 
     for _ in 0..config.num_workers {
         let config = config.clone();
@@ -120,16 +120,22 @@ impl rustls::client::danger::ServerCertVerifier for FakeCertificateVerifier {
     }
 }
 
-fn create_tls_config() -> anyhow::Result<Arc<rustls::ClientConfig>> {
-    let mut config = rustls::ClientConfig::builder()
+fn create_tls_config(config: &Config) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let mut tls_config = rustls::ClientConfig::builder()
         .with_root_certificates(rustls::RootCertStore::empty())
         .with_no_client_auth();
 
-    config
+    tls_config
         .dangerous()
         .set_certificate_verifier(Arc::new(FakeCertificateVerifier));
 
-    Ok(Arc::new(config))
+    if !config.tls_session_resumption {
+        tls_config.resumption = rustls::client::Resumption::disabled();
+    }
+
+    tls_config.enable_early_data = config.tls_early_data;
+
+    Ok(Arc::new(tls_config))
 }
 
 fn monitor_statistics(state: LoadTestState, config: &Config) {
@@ -0,0 +1,17 @@
+#![no_main]
+
+use aquatic_ws_protocol::incoming::InMessage;
+use libfuzzer_sys::fuzz_target;
+use tungstenite::Message;
+
+fuzz_target!(|data: &[u8]| {
+    // Text and binary messages take different paths to the deserializer
+    if let Ok(text) = ::std::str::from_utf8(data) {
+        if let Ok(message) = InMessage::from_ws_message(Message::Text(text.to_owned())) {
+            // Serialize parsed message, as done by the load tester
+            let _ = message.to_ws_message();
+        }
+    }
+
+    let _ = InMessage::from_ws_message(Message::Binary(data.to_vec()));
+});
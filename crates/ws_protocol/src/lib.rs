@@ -26,8 +26,8 @@ mod tests {
             ScrapeRequestInfoHashes,
         },
         outgoing::{
-            AnnounceResponse, AnswerOutMessage, OfferOutMessage, OutMessage, ScrapeResponse,
-            ScrapeStatistics,
+            AnnounceResponse, AnswerOutMessage, IpVersionScrapeStatistics, OfferOutMessage,
+            OutMessage, ScrapeResponse, ScrapeStatistics,
         },
     };
 
@@ -191,6 +191,17 @@ mod tests {
                 complete: Arbitrary::arbitrary(g),
                 incomplete: Arbitrary::arbitrary(g),
                 downloaded: Arbitrary::arbitrary(g),
+                ipv4: Arbitrary::arbitrary(g),
+                ipv6: Arbitrary::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for IpVersionScrapeStatistics {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                complete: Arbitrary::arbitrary(g),
+                incomplete: Arbitrary::arbitrary(g),
             }
         }
     }
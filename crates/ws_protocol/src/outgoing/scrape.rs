@@ -1,4 +1,4 @@
-use hashbrown::HashMap;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
 use crate::common::*;
@@ -6,7 +6,8 @@ use crate::common::*;
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ScrapeResponse {
     pub action: ScrapeAction,
-    pub files: HashMap<InfoHash, ScrapeStatistics>,
+    /// Statistics in order of info hashes in request
+    pub files: IndexMap<InfoHash, ScrapeStatistics>,
     // It looks like `flags` field is ignored in reference client
     // pub flags: HashMap<String, usize>,
 }
@@ -16,4 +17,16 @@ pub struct ScrapeStatistics {
     pub complete: usize,
     pub incomplete: usize,
     pub downloaded: usize,
+    /// Non-standard breakdown of IPv4 peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<IpVersionScrapeStatistics>,
+    /// Non-standard breakdown of IPv6 peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<IpVersionScrapeStatistics>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpVersionScrapeStatistics {
+    pub complete: usize,
+    pub incomplete: usize,
 }